@@ -0,0 +1,93 @@
+//! Benchmarks of the batch decode kernels, `cargo bench --bench decode`
+//!
+//! `*_scalar` decodes one row at a time, `*_kernel` uses the SIMD kernel
+//! when the CPU has it, see `dtf::kernels`.
+
+#![feature(test)]
+
+extern crate dtf;
+extern crate test;
+
+use dtf::{BatchMetadata, Update};
+use dtf::kernels::{self, ROW_LEN};
+use test::Bencher;
+
+/// rows of one full batch
+const ROWS : usize = 65_535;
+
+static REF_TS : u64 = 1_505_177_459_000;
+
+fn rows() -> Vec<u8> {
+    let mut buf = Vec::with_capacity(ROWS * ROW_LEN);
+    for i in 0..ROWS {
+        let price = (0.0523 + (i % 100) as f32 * 1e-5).to_bits();
+        let size = (i % 37) as f32 * 0.25;
+        buf.extend(&[(i >> 8) as u8, i as u8, (i % 3) as u8, (i % 4) as u8]);
+        buf.extend(&[(price >> 24) as u8, (price >> 16) as u8, (price >> 8) as u8, price as u8]);
+        let size = size.to_bits();
+        buf.extend(&[(size >> 24) as u8, (size >> 16) as u8, (size >> 8) as u8, size as u8]);
+    }
+    buf
+}
+
+fn meta() -> BatchMetadata {
+    BatchMetadata { ref_ts: REF_TS, ref_seq: 0, count: ROWS as u16, user_flags: false }
+}
+
+#[bench]
+fn decode_batch_scalar(b: &mut Bencher) {
+    let (buf, meta) = (rows(), meta());
+    b.bytes = buf.len() as u64;
+    b.iter(|| {
+        let mut out : Vec<Update> = Vec::new();
+        kernels::decode_rows_scalar(&buf, &meta, 0, u64::max_value(), &mut out).unwrap();
+        out
+    });
+}
+
+#[bench]
+fn decode_batch_kernel(b: &mut Bencher) {
+    let (buf, meta) = (rows(), meta());
+    b.bytes = buf.len() as u64;
+    b.iter(|| {
+        let mut out : Vec<Update> = Vec::new();
+        kernels::decode_rows(&buf, &meta, 0, u64::max_value(), &mut out).unwrap();
+        out
+    });
+}
+
+/// a tenth of the rows are within the range
+#[bench]
+fn range_batch_scalar(b: &mut Bencher) {
+    let (buf, meta) = (rows(), meta());
+    b.bytes = buf.len() as u64;
+    b.iter(|| {
+        let mut out : Vec<Update> = Vec::new();
+        kernels::decode_rows_scalar(&buf, &meta, REF_TS + 30_000, REF_TS + 36_553, &mut out).unwrap();
+        out
+    });
+}
+
+#[bench]
+fn range_batch_kernel(b: &mut Bencher) {
+    let (buf, meta) = (rows(), meta());
+    b.bytes = buf.len() as u64;
+    b.iter(|| {
+        let mut out : Vec<Update> = Vec::new();
+        kernels::decode_rows(&buf, &meta, REF_TS + 30_000, REF_TS + 36_553, &mut out).unwrap();
+        out
+    });
+}
+
+/// a whole file in memory, like a `DOWNLOAD` or an `UPLOAD`
+#[bench]
+fn decode_buffer(b: &mut Bencher) {
+    let ups : Vec<Update> = (0..200_000u64).map(|i| Update {
+        ts: REF_TS + i * 7, seq: i as u32, is_trade: i % 5 == 0, is_bid: i % 2 == 0,
+        price: 0.0523 + (i % 100) as f32 * 1e-5, size: (i % 37) as f32 * 0.25,
+        flags: 0,
+    }).collect();
+    let buf = dtf::encode_buffer("bnc_btc_eth", &ups);
+    b.bytes = buf.len() as u64;
+    b.iter(|| dtf::decode_buffer(&buf).unwrap());
+}
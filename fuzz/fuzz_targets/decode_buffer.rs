@@ -0,0 +1,8 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate dtf;
+
+// whole files: header checks and every batch
+fuzz_target!(|data: &[u8]| {
+    let _ = dtf::decode_buffer(data);
+});
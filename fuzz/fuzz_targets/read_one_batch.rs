@@ -0,0 +1,15 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate dtf;
+
+use std::io::Cursor;
+
+// the batch stream of a GET response, as the clients read it
+fuzz_target!(|data: &[u8]| {
+    let mut rdr = Cursor::new(data);
+    while let Ok(batch) = dtf::try_read_one_batch(&mut rdr) {
+        if batch.is_empty() {
+            break;
+        }
+    }
+});
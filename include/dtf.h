@@ -0,0 +1,61 @@
+/* C interface of the dtf library, built as a cdylib (libdtf.so), see src/lib/ffi.rs */
+
+#ifndef DTF_H
+#define DTF_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define DTF_SCAN_CHUNK 2048
+
+/* longest symbol of a dtf file, in bytes */
+#define DTF_SYMBOL_LEN 20
+
+typedef struct {
+    uint64_t ts;      /* ms since epoch */
+    uint32_t seq;
+    uint8_t is_trade; /* 0 or 1 */
+    uint8_t is_bid;   /* 0 or 1 */
+    uint8_t flags;    /* user defined flags, 0 to 63 */
+    float price;
+    float size;
+} DtfRow;
+
+/* write len rows, in ts order, to a new dtf file, returns len or -1 if the rows are
+   out of order, a flag is out of range or it cannot be written. path is replaced only once
+   the file is written */
+int64_t dtf_encode(const char *path, const char *symbol, const DtfRow *rows, size_t len);
+
+/* rows with min_ts <= ts <= max_ts into *rows, to be freed with dtf_rows_free,
+   returns their number or -1 if path is not a readable dtf file or is corrupt */
+int64_t dtf_decode_range(const char *path, uint64_t min_ts, uint64_t max_ts, DtfRow **rows);
+
+void dtf_rows_free(DtfRow *rows, size_t len);
+
+/* one array of len values per column, valid until the next dtf_scan_next or dtf_scan_close */
+typedef struct {
+    size_t len;
+    const uint64_t *ts;      /* ms since epoch */
+    const uint32_t *seq;
+    const uint8_t *is_trade; /* 0 or 1 */
+    const uint8_t *is_bid;   /* 0 or 1 */
+    const float *price;
+    const float *size;
+    const uint8_t *flags;    /* user defined flags, 0 to 63 */
+} DtfChunk;
+
+typedef struct DtfScan DtfScan;
+
+/* rows with min_ts <= ts <= max_ts, NULL if path is not a readable dtf file */
+DtfScan *dtf_scan_open(const char *path, uint64_t min_ts, uint64_t max_ts);
+
+/* rows in the next chunk of at most DTF_SCAN_CHUNK rows, 0 once done, -1 on a NULL argument
+   or a corrupt file */
+int64_t dtf_scan_next(DtfScan *scan, DtfChunk *chunk);
+
+void dtf_scan_close(DtfScan *scan);
+
+/* row count in the header, -1 if path is not a readable dtf file */
+int64_t dtf_row_count(const char *path);
+
+#endif
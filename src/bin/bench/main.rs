@@ -0,0 +1,265 @@
+extern crate clap;
+extern crate dtf;
+
+use clap::{Arg, App};
+use dtf::Update;
+use dtf::client::Client;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+///
+/// Drives a tectonic-server with a synthetic tick workload and reports
+/// insert and query latencies.
+///
+/// Every symbol gets its own connection and store `bench_[n]`. Each
+/// operation is either a write of `batch` rows or a query of the latest
+/// `query` rows, picked at random with probability `reads` for queries.
+///
+fn main() {
+    let matches = App::new("tectonic-bench")
+                        .version("1.0.0")
+                        .author("Ricky Han <tectonic@rickyhan.com>")
+                        .about("load generator for tectonic-server")
+                        .arg(Arg::with_name("host")
+                            .short("h")
+                            .value_name("HOST")
+                            .help("host to connect to (default 0.0.0.0)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("port")
+                            .short("p")
+                            .value_name("PORT")
+                            .help("port to connect to (default 9001)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("symbols")
+                            .short("s")
+                            .long("symbols")
+                            .value_name("SYMBOLS")
+                            .help("number of symbols, one connection each (default 4)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("rate")
+                            .short("r")
+                            .long("rate")
+                            .value_name("RATE")
+                            .help("rows per second per symbol, 0 is as fast as possible (default 0)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("batch")
+                            .short("b")
+                            .long("batch")
+                            .value_name("BATCH")
+                            .help("rows per write (default 10)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("reads")
+                            .short("R")
+                            .long("reads")
+                            .value_name("READS")
+                            .help("fraction of operations that are queries (default 0.1)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("query")
+                            .short("q")
+                            .long("query")
+                            .value_name("QUERY")
+                            .help("rows per query (default 100)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("duration")
+                            .short("d")
+                            .long("duration")
+                            .value_name("SECONDS")
+                            .help("how long to run (default 10)")
+                            .takes_value(true))
+                        .get_matches();
+
+    let host = matches.value_of("host").unwrap_or("0.0.0.0");
+    let port = matches.value_of("port").unwrap_or("9001");
+    let addr = format!("{}:{}", host, port);
+    let workload = Workload {
+        symbols: matches.value_of("symbols").unwrap_or("4").parse().unwrap(),
+        rate: matches.value_of("rate").unwrap_or("0").parse().unwrap(),
+        batch: matches.value_of("batch").unwrap_or("10").parse().unwrap(),
+        reads: matches.value_of("reads").unwrap_or("0.1").parse().unwrap(),
+        query: matches.value_of("query").unwrap_or("100").parse().unwrap(),
+        duration: Duration::from_secs(matches.value_of("duration").unwrap_or("10").parse().unwrap()),
+    };
+
+    let handles : Vec<_> = (0..workload.symbols).map(|n| {
+        let addr = addr.clone();
+        let workload = workload.clone();
+        thread::spawn(move || run_symbol(&addr, n, &workload))
+    }).collect();
+
+    let mut total = Latencies::default();
+    for handle in handles {
+        let latencies = handle.join().expect("bench thread panicked");
+        total.inserts.extend(latencies.inserts);
+        total.queries.extend(latencies.queries);
+        total.errors += latencies.errors;
+    }
+
+    let secs = workload.duration.as_secs() as f64;
+    println!("symbols: {}, batch: {}, reads: {}, duration: {}s",
+        workload.symbols, workload.batch, workload.reads, secs);
+    println!("inserts: {} rows/s", (total.inserts.len() * workload.batch) as f64 / secs);
+    report("insert", &mut total.inserts);
+    report("query", &mut total.queries);
+    println!("errors: {}", total.errors);
+}
+
+#[derive(Clone)]
+struct Workload {
+    symbols: usize,
+    rate: u64,
+    batch: usize,
+    reads: f64,
+    query: u32,
+    duration: Duration,
+}
+
+#[derive(Default)]
+struct Latencies {
+    /// in microseconds
+    inserts: Vec<u64>,
+    queries: Vec<u64>,
+    errors: u64,
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + u64::from(d.subsec_nanos()) / 1000
+}
+
+fn report(name: &str, latencies: &mut [u64]) {
+    if latencies.is_empty() {
+        println!("{}: no samples", name);
+        return;
+    }
+    latencies.sort();
+    println!("{}: n={} p50={}us p99={}us max={}us",
+        name, latencies.len(), percentile(latencies, 0.5), percentile(latencies, 0.99), latencies[latencies.len() - 1]);
+}
+
+/// the `p` percentile of sorted latencies, rounded down to a sample
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    sorted[((sorted.len() - 1) as f64 * p) as usize]
+}
+
+/// xorshift, good enough to pick operations and walk prices
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// a random walk of order book updates with occasional trades
+struct Ticks {
+    rng: Rng,
+    ts: u64,
+    seq: u32,
+    price: f32,
+}
+
+impl Iterator for Ticks {
+    type Item = Update;
+    fn next(&mut self) -> Option<Update> {
+        self.ts += 1 + self.rng.next() % 5;
+        self.seq += 1;
+        self.price *= 1. + ((self.rng.next_f64() - 0.5) * 0.001) as f32;
+        let is_bid = self.rng.next() % 2 == 0;
+        let spread = if is_bid { 0.9995 } else { 1.0005 };
+        Some(Update {
+            ts: self.ts,
+            seq: self.seq,
+            is_trade: self.rng.next() % 10 == 0,
+            is_bid,
+            price: self.price * spread,
+            size: (self.rng.next_f64() * 10.) as f32,
+            flags: 0,
+        })
+    }
+}
+
+fn run_symbol(addr: &str, n: usize, workload: &Workload) -> Latencies {
+    let store = format!("bench_{}", n);
+    let mut client = Client::new(addr);
+    let mut latencies = Latencies::default();
+    if let Err(e) = client.cmd(&format!("CREATE {}", store)).and_then(|_| client.cmd(&format!("USE {}", store))) {
+        eprintln!("{}: {}", store, e);
+        latencies.errors += 1;
+        return latencies;
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d ^ (n as u64 + 1));
+    let mut ticks = Ticks { rng: Rng(rng.next()), ts: now.as_secs() * 1000, seq: 0, price: 100. };
+    let query = format!("GET ALL AS JSON LIMIT {}", workload.query);
+
+    // time a batch should take to hold the rate
+    let pace = if workload.rate == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(workload.batch as u64) / workload.rate as u32)
+    };
+
+    let start = Instant::now();
+    while start.elapsed() < workload.duration {
+        let op_start = Instant::now();
+        if rng.next_f64() < workload.reads {
+            match client.cmd(&query) {
+                Ok(_) => latencies.queries.push(micros(op_start.elapsed())),
+                Err(_) => latencies.errors += 1
+            }
+            continue;
+        }
+
+        let mut ok = true;
+        for up in ticks.by_ref().take(workload.batch) {
+            ok &= client.insert(&up, &store).is_ok();
+        }
+        if ok {
+            latencies.inserts.push(micros(op_start.elapsed()));
+        } else {
+            latencies.errors += 1;
+        }
+
+        if let Some(pace) = pace {
+            let elapsed = op_start.elapsed();
+            if elapsed < pace {
+                thread::sleep(pace - elapsed);
+            }
+        }
+    }
+    latencies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_pick_percentiles_of_samples() {
+        let sorted : Vec<u64> = (1..101).collect();
+        assert_eq!(percentile(&sorted, 0.5), 50);
+        assert_eq!(percentile(&sorted, 0.99), 99);
+        assert_eq!(percentile(&sorted, 1.), 100);
+        assert_eq!(percentile(&[7], 0.99), 7);
+    }
+
+    #[test]
+    fn should_generate_ordered_ticks_around_the_price() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        assert!((0..1000).map(|_| rng.next_f64()).all(|x| x >= 0. && x < 1.));
+
+        let ticks : Vec<Update> = Ticks { rng: Rng(1), ts: 1_505_177_459_000, seq: 0, price: 100. }.take(1000).collect();
+        assert!(ticks.windows(2).all(|pair| pair[0].ts < pair[1].ts && pair[0].seq + 1 == pair[1].seq));
+        assert!(ticks.iter().all(|up| up.price > 90. && up.price < 110. && up.size >= 0. && up.size < 10.));
+        assert!(ticks.iter().any(|up| up.is_trade) && ticks.iter().any(|up| !up.is_trade));
+        assert!(ticks.iter().any(|up| up.is_bid) && ticks.iter().any(|up| !up.is_bid));
+    }
+}
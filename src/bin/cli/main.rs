@@ -5,11 +5,14 @@ extern crate dtf;
 use clap::{Arg, App};
 use std::net::TcpStream;
 use std::str;
-use byteorder::{BigEndian, /*WriteBytesExt, */ ReadBytesExt};
+use byteorder::{BigEndian, LittleEndian, WriteBytesExt, ReadBytesExt};
+use std::fs::File;
 use std::io::{self, Read, Write};
 
 struct Cxn {
     stream : TcpStream,
+    /// responses use the flat encoding (`FORMAT FLAT`)
+    flat: bool,
     // addr: String
 }
 
@@ -17,13 +20,67 @@ impl Cxn {
     fn cmd(&mut self, command : &str) -> String {
         let _ = self.stream.write(command.as_bytes());
         let success = self.stream.read_u8().unwrap() == 0x1;
-        if command.starts_with("GET") && !command.contains("AS JSON") && success {
-            let vecs = dtf::read_one_batch(&mut self.stream);
+        if command.starts_with("REPLAY") && success {
+            // print rows as they arrive
+            loop {
+                let chunk = dtf::read_one_batch(&mut self.stream);
+                if chunk.is_empty() { break; }
+                println!("[{}]", dtf::update_vec_to_json(&chunk));
+            }
+            String::new()
+        } else if command.starts_with("SUBSCRIBE") && success {
+            // print frames until the server goes away
+            while let Ok(size) = self.stream.read_u64::<BigEndian>() {
+                let mut buf = vec![0; size as usize];
+                if self.stream.read_exact(&mut buf).is_err() { break; }
+                print!("{}", str::from_utf8(&buf).unwrap());
+            }
+            String::new()
+        } else if command.starts_with("DOWNLOAD") && success {
+            // save the dtf file as [db].dtf
+            let size = self.stream.read_u64::<BigEndian>().unwrap();
+            let mut buf = vec![0; size as usize];
+            let _ = self.stream.read_exact(&mut buf);
+            let fname = format!("{}.dtf", command.split_whitespace().nth(1).unwrap_or("download"));
+            match File::create(&fname).and_then(|mut file| file.write_all(&buf)) {
+                Ok(()) => format!("Saved {} bytes to {}\n", size, fname),
+                Err(e) => format!("Unable to save {}: {}\n", fname, e),
+            }
+        } else if command.starts_with("GET") && command.contains(" STREAM") && success {
+            // read chunks until the empty end-of-stream batch
+            let mut vecs = Vec::new();
+            loop {
+                let chunk = dtf::read_one_batch(&mut self.stream);
+                if chunk.is_empty() { break; }
+                vecs.extend(chunk);
+            }
             format!("[{}]\n", dtf::update_vec_to_json(&vecs))
+        } else if command.starts_with("GET") && !command.contains("AS JSON")
+                && !command.contains(" LIMIT ") && self.flat && success {
+            let len = self.stream.read_u64::<LittleEndian>().unwrap() as usize;
+            let mut buf = vec![0; dtf::FLAT_HEADER_LEN + len * dtf::FLAT_RECORD_LEN];
+            let _ = (&mut buf[..dtf::FLAT_HEADER_LEN]).write_u64::<LittleEndian>(len as u64);
+            let _ = self.stream.read_exact(&mut buf[dtf::FLAT_HEADER_LEN..]);
+            let view = dtf::FlatView::new(&buf).unwrap();
+            format!("[{}]\n", dtf::update_vec_to_json(&view.to_vec()))
+        } else if command.starts_with("GET") && !command.contains("AS JSON") && success {
+            let cursor = if command.contains(" LIMIT ") {
+                Some(self.stream.read_u64::<BigEndian>().unwrap())
+            } else {
+                None
+            };
+            let vecs = dtf::read_one_batch(&mut self.stream);
+            match cursor {
+                Some(0) | None => format!("[{}]\n", dtf::update_vec_to_json(&vecs)),
+                Some(c) => format!("[{}]\nCURSOR {}\n", dtf::update_vec_to_json(&vecs), c),
+            }
         } else {
             let size = self.stream.read_u64::<BigEndian>().unwrap();
             let mut buf = vec![0; size as usize];
             let _ = self.stream.read_exact(&mut buf);
+            if command.starts_with("FORMAT") && success {
+                self.flat = command.trim() == "FORMAT FLAT";
+            }
             str::from_utf8(&buf).unwrap().to_owned()
         }
     }
@@ -78,6 +135,7 @@ fn connect(host : &str, port : &str, verbosity : u64) -> Cxn {
 
     let cxn = Cxn{
         stream : TcpStream::connect(&addr).unwrap(),
+        flat: false,
         // addr
     };
 
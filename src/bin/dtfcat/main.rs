@@ -24,20 +24,61 @@ fn main() {
                                .short("c")
                                .long("csv")
                                .help("output csv (default is JSON)"))
+                          .arg(Arg::with_name("jsonl")
+                               .short("l")
+                               .long("jsonl")
+                               .help("output JSON Lines, one row per line"))
+                          .arg(Arg::with_name("kdb")
+                               .short("k")
+                               .long("kdb")
+                               .value_name("DIR")
+                               .help("write a kdb+ splayed table into DIR instead")
+                               .takes_value(true))
+                          .arg(Arg::with_name("from")
+                               .short("f")
+                               .long("from")
+                               .value_name("FROM")
+                               .help("only rows at or after FROM (ts in seconds)")
+                               .takes_value(true))
+                          .arg(Arg::with_name("to")
+                               .short("t")
+                               .long("to")
+                               .value_name("TO")
+                               .help("only rows at or before TO (ts in seconds)")
+                               .takes_value(true))
                           .get_matches();
 
     let input = matches.value_of("input").unwrap();
     let metadata = matches.is_present("metadata");
     let csv = matches.is_present("csv");
+    let jsonl = matches.is_present("jsonl");
+    let from = matches.value_of("from").map(|ts| ts.parse::<f64>().expect("FROM is not a timestamp"));
+    let to = matches.value_of("to").map(|ts| ts.parse::<f64>().expect("TO is not a timestamp"));
 
     if metadata {
         println!("{}", dtf::read_meta(input));
         return;
-    } else if csv{
-        println!("{}", dtf::update_vec_to_csv(&dtf::decode(input, None)));
-        return;
+    }
+
+    let ups = dtf::decode(input, None);
+    let ups = if from.is_some() || to.is_some() {
+        let from = (from.unwrap_or(0.) * 1000.) as u64;
+        let to = to.map_or(u64::max_value(), |to| (to * 1000.) as u64);
+        ups.into_iter().filter(|up| up.ts >= from && up.ts <= to).collect()
     } else {
-        println!("[{}]", dtf::update_vec_to_json(&dtf::decode(input, None)));
-        return;
+        ups
+    };
+
+    if let Some(dir) = matches.value_of("kdb") {
+        dtf::write_splayed(dir, &ups).expect("Unable to write kdb+ table");
+        eprintln!("Wrote {} rows to {}", ups.len(), dir);
+    } else if csv {
+        println!("{}", dtf::update_vec_to_csv(&ups));
+    } else if jsonl {
+        for up in ups.iter() {
+            println!("{}", up.to_json());
+        }
+    } else {
+        println!("[{}]", dtf::update_vec_to_json(&ups));
     }
 }
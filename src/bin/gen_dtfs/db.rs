@@ -20,6 +20,7 @@ pub fn run(cnx_str : &str, query : &str) -> Vec<Update> {
             is_bid: row.get(3),
             price: price as f32,
             size: size as f32,
+            flags: 0,
         };
 
         v.push(up);
@@ -0,0 +1,102 @@
+extern crate clap;
+extern crate dtf;
+
+use clap::{Arg, App};
+use dtf::client::JournalEntry;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+///
+/// Sends a journal recorded by `tectonic-server --journal` back to a server.
+///
+/// Every peer of the journal gets its own connection, and commands are sent
+/// in their original order, paced by their original inter-arrival times
+/// divided by `speed`. Responses are read and discarded.
+///
+fn main() {
+    let matches = App::new("tectonic-replay")
+                        .version("1.0.0")
+                        .author("Ricky Han <tectonic@rickyhan.com>")
+                        .about("replay a tectonic-server journal")
+                        .arg(Arg::with_name("input")
+                            .short("i")
+                            .long("input")
+                            .value_name("JOURNAL")
+                            .help("journal to replay")
+                            .required(true)
+                            .takes_value(true))
+                        .arg(Arg::with_name("host")
+                            .short("h")
+                            .value_name("HOST")
+                            .help("host to connect to (default 0.0.0.0)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("port")
+                            .short("p")
+                            .value_name("PORT")
+                            .help("port to connect to (default 9001)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("speed")
+                            .short("s")
+                            .long("speed")
+                            .value_name("SPEED")
+                            .help("speed up factor, 0 is as fast as possible (default 1)")
+                            .takes_value(true))
+                        .get_matches();
+
+    let input = matches.value_of("input").unwrap();
+    let host = matches.value_of("host").unwrap_or("0.0.0.0");
+    let port = matches.value_of("port").unwrap_or("9001");
+    let speed = matches.value_of("speed").unwrap_or("1").parse::<f64>().unwrap();
+    let addr = format!("{}:{}", host, port);
+
+    let file = File::open(input).expect("OPENING JOURNAL");
+    let entries : Vec<JournalEntry> = BufReader::new(file).lines()
+        .filter_map(|line| line.ok())
+        .filter_map(|line| JournalEntry::parse(&line))
+        .collect();
+    if entries.is_empty() {
+        println!("Nothing to replay.");
+        return;
+    }
+
+    let mut cxns : HashMap<String, TcpStream> = HashMap::new();
+    let first_ts = entries[0].ts;
+    let start = Instant::now();
+    for entry in entries.iter() {
+        if speed > 0. {
+            let offset = (entry.ts.saturating_sub(first_ts) as f64 / speed) as u64;
+            let due = Duration::from_millis(offset);
+            let elapsed = start.elapsed();
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+        }
+
+        if !cxns.contains_key(&entry.peer) {
+            cxns.insert(entry.peer.clone(), connect(&addr).expect("CONNECTING"));
+        }
+        let stream = cxns.get_mut(&entry.peer).unwrap();
+        if let Err(e) = stream.write_all(format!("{}\n", entry.command).as_bytes()) {
+            eprintln!("{}: {}", entry.peer, e);
+        }
+    }
+
+    let elapsed = start.elapsed();
+    println!("Replayed {} commands of {} connections in {}.{:03}s",
+        entries.len(), cxns.len(), elapsed.as_secs(), elapsed.subsec_nanos() / 1_000_000);
+}
+
+/// a connection whose responses are drained in the background
+fn connect(addr: &str) -> Result<TcpStream, io::Error> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_nodelay(true)?;
+    let mut rdr = stream.try_clone()?;
+    thread::spawn(move || {
+        let _ = io::copy(&mut rdr, &mut io::sink());
+    });
+    Ok(stream)
+}
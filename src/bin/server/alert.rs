@@ -0,0 +1,140 @@
+/// Alerts
+///
+/// Alerts are logged as warnings and, with `--webhook [url]`, POSTed as
+///
+///     {"alert": "silent", "store": "bnc_btc_eth", "message": "...", "ts": 1505177459}
+///
+/// to the webhook. Only plain `http://` webhooks are supported.
+///
+/// Alert rules are added with the `ALERT` commands and kept in `[dtf_folder]/alerts`,
+/// one command per line. A rule with `INTO [db]` also inserts a row into that store
+/// when it fires: ts is the time, seq the rule number, price the measured value
+/// and size the threshold.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use parser;
+use utils;
+
+/// seconds a webhook has to answer
+static WEBHOOK_TIMEOUT : u64 = 10;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    /// no rows inserted into the store for ms
+    Silent(String, u64),
+    /// the files in dtf_folder take more than bytes
+    DiskAbove(u64),
+    /// a flush failed
+    FlushErrors,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub condition: Condition,
+    /// store the alert is also inserted into
+    pub into: Option<String>,
+}
+
+impl Rule {
+    /// name of the alert raised by the rule
+    pub fn alert(&self) -> &'static str {
+        match self.condition {
+            Condition::Silent(..) => "silent",
+            Condition::DiskAbove(_) => "disk",
+            Condition::FlushErrors => "flush_errors",
+        }
+    }
+}
+
+/// the command that adds the rule
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.condition {
+            Condition::Silent(ref store_name, ms) => write!(f, "ALERT SILENT {} AFTER {}s", store_name, ms / 1000)?,
+            Condition::DiskAbove(bytes) => write!(f, "ALERT DISK ABOVE {}MB", bytes / 1024 / 1024)?,
+            Condition::FlushErrors => write!(f, "ALERT FLUSH ERRORS")?,
+        }
+        if let Some(ref into) = self.into {
+            write!(f, " INTO {}", into)?;
+        }
+        Ok(())
+    }
+}
+
+fn rules_fname(dtf_folder: &str) -> String {
+    utils::join(dtf_folder, "alerts")
+}
+
+/// the rules kept in the folder
+pub fn read_rules(dtf_folder: &str) -> Vec<Rule> {
+    let file = match File::open(rules_fname(dtf_folder)) {
+        Ok(f) => f,
+        Err(_) => return Vec::new()
+    };
+    BufReader::new(file).lines()
+        .filter_map(|line| line.ok())
+        .filter_map(|line| parser::parse_alert(&line))
+        .collect()
+}
+
+pub fn write_rules(dtf_folder: &str, rules: &[Rule]) -> Result<(), io::Error> {
+    let mut file = File::create(rules_fname(dtf_folder))?;
+    for rule in rules {
+        writeln!(file, "{}", rule)?;
+    }
+    Ok(())
+}
+
+pub fn raise(webhook: Option<&str>, alert: &str, store_name: &str, message: &str) {
+    warn!("ALERT {} {}: {}", alert, store_name, message);
+
+    let webhook = match webhook {
+        Some(webhook) => webhook.to_owned(),
+        None => return
+    };
+    let body = format!(r#"{{"alert": "{}", "store": "{}", "message": "{}", "ts": {}}}"#,
+        alert,
+        store_name,
+        message.replace('\\', "\\\\").replace('"', "\\\""),
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs());
+    // a slow webhook must not hold up the caller
+    thread::spawn(move || {
+        if let Err(e) = post(&webhook, &body) {
+            error!("Unable to send alert to {}: {}", webhook, e);
+        }
+    });
+}
+
+/// POST `body` as JSON to an `http://host[:port][/path]` url
+fn post(url: &str, body: &str) -> Result<(), String> {
+    if !url.starts_with("http://") {
+        return Err("only http:// webhooks are supported".to_owned());
+    }
+    let rest = &url[7..];
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/")
+    };
+    let addr = if host.contains(':') { host.to_owned() } else { format!("{}:80", host) };
+
+    let mut stream = TcpStream::connect(&addr).map_err(|e| format!("{:?}", e))?;
+    let timeout = Some(Duration::from_secs(WEBHOOK_TIMEOUT));
+    let _ = stream.set_read_timeout(timeout);
+    let _ = stream.set_write_timeout(timeout);
+    let request = format!("POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path, host, body.len(), body);
+    stream.write_all(request.as_bytes()).map_err(|e| format!("{:?}", e))?;
+
+    let mut status = [0; 12];
+    stream.read_exact(&mut status).map_err(|e| format!("{:?}", e))?;
+    // "HTTP/1.1 2xx"
+    if status[9] != b'2' {
+        return Err(format!("webhook answered {}", String::from_utf8_lossy(&status[9..])));
+    }
+    Ok(())
+}
@@ -0,0 +1,41 @@
+/// Aliases
+///
+/// `ALIAS [alias] FOR [db]` makes a store reachable under a second name,
+/// e.g. the old symbol of a rebranded market. Aliases resolve to the
+/// store itself, never to another alias.
+///
+/// Aliases are kept in `[dtf_folder]/aliases`, one `alias <TAB> db` line each.
+/// A later line for the same alias wins.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use utils;
+
+/// alias -> store name
+pub type Aliases = HashMap<String, String>;
+
+fn aliases_fname(dtf_folder: &str) -> String {
+    utils::join(dtf_folder, "aliases")
+}
+
+/// all aliases kept in the folder
+pub fn read(dtf_folder: &str) -> Aliases {
+    let mut aliases = HashMap::new();
+    let file = match File::open(aliases_fname(dtf_folder)) {
+        Ok(f) => f,
+        Err(_) => return aliases
+    };
+    for line in BufReader::new(file).lines().filter_map(|line| line.ok()) {
+        let parts : Vec<&str> = line.split('\t').collect();
+        if parts.len() == 2 {
+            aliases.insert(parts[0].to_owned(), parts[1].to_owned());
+        }
+    }
+    aliases
+}
+
+pub fn record(dtf_folder: &str, alias: &str, store_name: &str) -> Result<(), io::Error> {
+    let mut file = OpenOptions::new().create(true).append(true).open(aliases_fname(dtf_folder))?;
+    writeln!(file, "{}\t{}", alias, store_name)
+}
@@ -0,0 +1,180 @@
+/// Approximate queries
+///
+/// `APPROX COUNT [db] [FROM ts TO ts] [ERROR 1%]` and `APPROX VWAP ...`
+/// answer from the sidecars of the dtf files instead of reading their rows,
+/// for exploring month-long ranges where a scan takes minutes:
+///
+///     {"estimate": 1843200, "error": 0.0021, "exact": false}
+///
+/// `error` is the half width of the 95% confidence interval relative to the
+/// estimate. A count adds up the rows per day of the stats of the files for
+/// the days within the range and estimates the days at its ends from the
+/// sample of the file, see `filestats`. A VWAP (of the trades) is estimated
+/// from the samples only. Rows in memory are always counted exactly. With
+/// ERROR, an estimate less precise than asked for is replaced by the exact
+/// answer of a scan. Deleted or corrected rows that are not compacted yet
+/// are estimated as they were inserted.
+
+use dtf::Update;
+use dtf::summary::Summary;
+use dtf::sample::Reservoir;
+
+/// ms in a day, the granularity of `Summary::rows_per_day`
+static DAY : u64 = 86_400_000;
+
+/// of a two-sided 95% confidence interval
+static Z : f64 = 1.96;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    Count,
+    Vwap,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Estimate {
+    /// None for the VWAP of no trades
+    pub value: Option<f64>,
+    /// relative half width of the 95% confidence interval
+    pub error: f64,
+    pub exact: bool,
+}
+
+impl Estimate {
+    fn new(value: Option<f64>, variance: f64) -> Estimate {
+        let error = match value {
+            Some(value) if value != 0. => Z * variance.sqrt() / value.abs(),
+            _ => 0.
+        };
+        Estimate { value, error, exact: variance == 0. }
+    }
+
+    pub fn to_json(&self) -> String {
+        let value = self.value.map_or("null".to_owned(), |value| value.to_string());
+        format!(r#"{{"estimate": {}, "error": {}, "exact": {}}}"#, value, self.error, self.exact)
+    }
+}
+
+/// What the sidecars of a dtf file tell of its rows
+pub struct FileSample {
+    pub summary: Summary,
+    pub sample: Reservoir,
+}
+
+impl FileSample {
+    /// the sample holds every row of the file
+    fn is_complete(&self) -> bool {
+        self.sample.seen == self.sample.rows.len() as u64
+    }
+}
+
+fn in_range(up: &Update, min_ts: u64, max_ts: u64) -> bool {
+    up.ts >= min_ts && up.ts <= max_ts
+}
+
+/// rows within [min_ts, max_ts] of the files and of the rows in memory
+pub fn count(files: &[FileSample], memory: &[Update], min_ts: u64, max_ts: u64) -> Estimate {
+    let mut value = memory.iter().filter(|up| in_range(up, min_ts, max_ts)).count() as f64;
+    let mut variance = 0.;
+    for file in files {
+        let (first, last) = match (file.summary.min_ts, file.summary.max_ts) {
+            (Some(first), Some(last)) => (first, last),
+            _ => continue
+        };
+        if last < min_ts || first > max_ts {
+            continue;
+        }
+        if first >= min_ts && last <= max_ts {
+            value += file.summary.count as f64;
+            continue;
+        }
+        if file.is_complete() {
+            value += file.sample.rows.iter().filter(|up| in_range(up, min_ts, max_ts)).count() as f64;
+            continue;
+        }
+        for (&day, &rows) in file.summary.rows_per_day.iter() {
+            let (start, end) = (day * 1000, day * 1000 + DAY - 1);
+            if end < min_ts || start > max_ts {
+                continue;
+            }
+            let rows = rows as f64;
+            if start >= min_ts && end <= max_ts {
+                value += rows;
+                continue;
+            }
+            let sampled : Vec<&Update> = file.sample.rows.iter().filter(|up| in_range(up, start, end)).collect();
+            if sampled.is_empty() {
+                // as if the rows of the day were spread evenly, at worst all in or all out
+                let covered = (end.min(max_ts) - start.max(min_ts) + 1) as f64 / DAY as f64;
+                value += rows * covered;
+                variance += (rows * covered.max(1. - covered) / Z).powi(2);
+                continue;
+            }
+            let n = sampled.len() as f64;
+            let k = sampled.iter().filter(|up| in_range(up, min_ts, max_ts)).count() as f64;
+            value += rows * k / n;
+            // smoothed, a sample with none or all of the rows in range is not certain of it
+            let p = (k + 1.) / (n + 2.);
+            variance += rows * rows * p * (1. - p) / n;
+        }
+    }
+    Estimate::new(Some(value), variance)
+}
+
+/// volume weighted average price of the trades within [min_ts, max_ts]
+/// of the files and of the rows in memory
+pub fn vwap(files: &[FileSample], memory: &[Update], min_ts: u64, max_ts: u64) -> Estimate {
+    let is_counted = |up: &&Update| up.is_trade && in_range(up, min_ts, max_ts);
+    let (mut notional, mut volume) = (0., 0.);
+    for up in memory.iter().filter(&is_counted) {
+        notional += f64::from(up.price) * f64::from(up.size);
+        volume += f64::from(up.size);
+    }
+    let files : Vec<&FileSample> = files.iter()
+        .filter(|file| match (file.summary.min_ts, file.summary.max_ts) {
+            (Some(first), Some(last)) => last >= min_ts && first <= max_ts,
+            _ => false
+        })
+        .collect();
+    for file in files.iter() {
+        let weight = file.sample.weight();
+        for up in file.sample.rows.iter().filter(&is_counted) {
+            notional += weight * f64::from(up.price) * f64::from(up.size);
+            volume += weight * f64::from(up.size);
+        }
+    }
+    if volume == 0. {
+        return Estimate::new(None, 0.);
+    }
+    let vwap = notional / volume;
+
+    // linearized variance of the ratio, the sample of a file is a simple random sample of its rows
+    let mut variance = 0.;
+    for file in files.iter().filter(|file| !file.is_complete()) {
+        let (n, seen) = (file.sample.rows.len() as f64, file.sample.seen as f64);
+        if n < 2. {
+            continue;
+        }
+        let z : Vec<f64> = file.sample.rows.iter()
+            .map(|up| if is_counted(&up) { f64::from(up.size) * (f64::from(up.price) - vwap) } else { 0. })
+            .collect();
+        let mean = z.iter().sum::<f64>() / n;
+        let z_variance = z.iter().map(|z| (z - mean).powi(2)).sum::<f64>() / (n - 1.);
+        variance += seen * seen * (1. - n / seen) * z_variance / n;
+    }
+    Estimate::new(Some(vwap), variance / (volume * volume))
+}
+
+/// the exact answer from every row within the range
+pub fn exact(aggregate: Aggregate, rows: &[Update]) -> Estimate {
+    let value = match aggregate {
+        Aggregate::Count => Some(rows.len() as f64),
+        Aggregate::Vwap => {
+            let trades = rows.iter().filter(|up| up.is_trade);
+            let (notional, volume) = trades.fold((0., 0.), |(notional, volume), up|
+                (notional + f64::from(up.price) * f64::from(up.size), volume + f64::from(up.size)));
+            if volume == 0. { None } else { Some(notional / volume) }
+        }
+    };
+    Estimate { value, error: 0., exact: true }
+}
@@ -0,0 +1,47 @@
+/// In-memory backend, for tests of the paths through `StorageBackend`
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use dtf::Update;
+use backend::StorageBackend;
+
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    stores: Mutex<HashMap<String, Vec<Update>>>,
+}
+
+impl StorageBackend for MemoryBackend {
+    fn append(&self, store: &str, ups: &[Update]) -> Result<(), String> {
+        let mut stores = self.stores.lock().unwrap();
+        let rows = stores.entry(store.to_owned()).or_insert_with(Vec::new);
+        for up in ups {
+            match rows.binary_search_by_key(&(up.ts, up.seq), |row| (row.ts, row.seq)) {
+                Ok(i) => rows[i] = up.clone(),
+                Err(i) => rows.insert(i, up.clone())
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&self, store: &str, range: Option<(u64, u64)>) -> Result<Vec<Update>, String> {
+        let (min_ts, max_ts) = range.unwrap_or((0, u64::max_value()));
+        Ok(self.stores.lock().unwrap().get(store)
+            .map(|rows| rows.iter().filter(|up| up.ts >= min_ts && up.ts <= max_ts).cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn replace(&self, store: &str, ups: &[Update]) -> Result<(), String> {
+        self.stores.lock().unwrap().insert(store.to_owned(), ups.to_vec());
+        Ok(())
+    }
+
+    fn count(&self, store: &str) -> u64 {
+        self.stores.lock().unwrap().get(store).map_or(0, |rows| rows.len() as u64)
+    }
+
+    fn stores(&self) -> Vec<String> {
+        let mut stores : Vec<String> = self.stores.lock().unwrap().keys().cloned().collect();
+        stores.sort();
+        stores
+    }
+}
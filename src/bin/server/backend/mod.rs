@@ -0,0 +1,89 @@
+/// Storage backends
+///
+/// By default flushed rows go to one dtf file per store in `dtf_folder`.
+/// A backend replaces the files as the place where `FLUSH` writes and
+/// `LOAD` reads, see `--storage`.
+
+use std::fmt;
+use std::sync::Arc;
+use dtf::Update;
+use dtf::summary::Summary;
+use settings::{Settings, Storage};
+#[cfg(feature = "rocksdb")]
+use utils;
+
+#[cfg(feature = "rocksdb")]
+pub mod rocks;
+
+#[cfg(any(feature = "s3", test))]
+pub mod object;
+#[cfg(feature = "s3")]
+pub mod s3;
+
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub mod uring;
+
+#[cfg(test)]
+pub mod memory;
+
+pub trait StorageBackend: fmt::Debug + Send + Sync {
+    /// persist rows of a store, a row replaces the one of the same (ts, seq)
+    fn append(&self, store: &str, ups: &[Update]) -> Result<(), String>;
+
+    /// rows of a store in order, optionally within [min_ts, max_ts] in ms
+    fn read(&self, store: &str, range: Option<(u64, u64)>) -> Result<Vec<Update>, String>;
+
+    /// replace every row of a store with `ups`, e.g. once compacted
+    fn replace(&self, store: &str, ups: &[Update]) -> Result<(), String>;
+
+    /// number of rows of a store
+    fn count(&self, store: &str) -> u64;
+
+    /// statistics of the rows of a store
+    fn summary(&self, store: &str) -> Result<Summary, String> {
+        let mut summary = Summary::new();
+        summary.add(&self.read(store, None)?);
+        Ok(summary)
+    }
+
+    /// every store with rows in the backend
+    fn stores(&self) -> Vec<String>;
+}
+
+/// open the backend selected in settings, None for dtf files
+pub fn open(settings: &Settings) -> Option<Arc<StorageBackend>> {
+    match settings.storage {
+        Storage::DTF => None,
+        #[cfg(feature = "rocksdb")]
+        Storage::RocksDB => {
+            let path = utils::join(&settings.dtf_folder, "rocksdb");
+            match rocks::RocksBackend::open(&path) {
+                Ok(backend) => Some(Arc::new(backend)),
+                Err(e) => panic!("Unable to open RocksDB at {}: {}", path, e)
+            }
+        },
+        #[cfg(not(feature = "rocksdb"))]
+        Storage::RocksDB => panic!("tectonic-server was built without the `rocksdb` feature"),
+        #[cfg(feature = "s3")]
+        Storage::S3(ref bucket) => {
+            let store = s3::S3Store::new(bucket);
+            Some(Arc::new(object::ObjectBackend::new(store, &settings.dtf_folder, object::CACHE_BYTES)))
+        },
+        #[cfg(not(feature = "s3"))]
+        Storage::S3(_) => panic!("tectonic-server was built without the `s3` feature"),
+        // the same dtf files either way, so a kernel without io_uring gets the standard path
+        #[cfg(all(feature = "uring", target_os = "linux"))]
+        Storage::Uring => match uring::UringBackend::open(&settings.dtf_folder) {
+            Ok(backend) => Some(Arc::new(backend)),
+            Err(e) => {
+                warn!("io_uring is unavailable ({}), using dtf files", e);
+                None
+            }
+        },
+        #[cfg(not(all(feature = "uring", target_os = "linux")))]
+        Storage::Uring => {
+            warn!("tectonic-server was built without the `uring` feature, using dtf files");
+            None
+        },
+    }
+}
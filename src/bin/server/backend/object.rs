@@ -0,0 +1,435 @@
+/// Object store backend
+///
+/// Rows are buffered in a local dtf file per store (the write-ahead buffer)
+/// until it holds `PARTITION_ROWS` rows. The partition is then closed:
+/// uploaded as one object and removed locally. Objects are named
+///
+///     [store]/[min_ts]-[max_ts]-[count].dtf
+///
+/// so reads and counts only need a listing. Fetched objects are kept in a
+/// local read-through cache of at most `CACHE_BYTES`, the least recently read
+/// evicted first, so the server only needs a small local disk.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use dtf::{self, Update};
+use backend::StorageBackend;
+use utils;
+
+/// rows per closed partition
+pub const PARTITION_ROWS: u64 = 100_000;
+/// bytes of fetched partitions kept in the cache
+#[cfg(feature = "s3")]
+pub const CACHE_BYTES: u64 = 4 << 30;
+
+pub trait ObjectStore: fmt::Debug + Send + Sync {
+    fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), String>;
+    fn get(&self, key: &str) -> Result<Vec<u8>, String>;
+    fn delete(&self, key: &str) -> Result<(), String>;
+    /// keys starting with `prefix`
+    fn list(&self, prefix: &str) -> Result<Vec<String>, String>;
+}
+
+/// a closed partition
+struct Partition {
+    key: String,
+    min_ts: u64,
+    max_ts: u64,
+    count: u64,
+}
+
+impl Partition {
+    fn from_key(key: &str) -> Option<Partition> {
+        let name = Path::new(key).file_stem()?.to_str()?;
+        let parts : Vec<&str> = name.split('-').collect();
+        if parts.len() != 3 {
+            return None;
+        }
+        Some(Partition {
+            key: key.to_owned(),
+            min_ts: parts[0].parse().ok()?,
+            max_ts: parts[1].parse().ok()?,
+            count: parts[2].parse().ok()?,
+        })
+    }
+}
+
+/// the partitions in the cache folder, by file name
+#[derive(Debug, Default)]
+struct Cache {
+    bytes: u64,
+    /// file -> (bytes, last read)
+    files: HashMap<String, (u64, u64)>,
+    /// last read -> file, least recent first
+    reads: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl Cache {
+    /// whether `fname` is cached, it is then the most recently read
+    fn touch(&mut self, fname: &str) -> bool {
+        let len = match self.files.get(fname) {
+            Some(&(len, _)) => len,
+            None => return false
+        };
+        self.insert(fname, len);
+        true
+    }
+
+    fn insert(&mut self, fname: &str, len: u64) {
+        self.remove(fname);
+        self.clock += 1;
+        self.files.insert(fname.to_owned(), (len, self.clock));
+        self.reads.insert(self.clock, fname.to_owned());
+        self.bytes += len;
+    }
+
+    fn remove(&mut self, fname: &str) {
+        if let Some((len, read)) = self.files.remove(fname) {
+            self.reads.remove(&read);
+            self.bytes -= len;
+        }
+    }
+
+    /// remove the least recently read files until at most `capacity` bytes
+    /// are left, the most recent one is kept either way
+    fn evict(&mut self, capacity: u64) {
+        while self.bytes > capacity && self.files.len() > 1 {
+            let fname = match self.reads.values().next() {
+                Some(fname) => fname.to_owned(),
+                None => break
+            };
+            self.remove(&fname);
+            let _ = fs::remove_file(&fname);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ObjectBackend<S: ObjectStore> {
+    store: S,
+    wal_folder: String,
+    cache_folder: String,
+    cache: Mutex<Cache>,
+    cache_bytes: u64,
+}
+
+impl<S: ObjectStore> ObjectBackend<S> {
+    /// keeps at most `cache_bytes` of fetched partitions in `folder`/cache
+    pub fn new(store: S, folder: &str, cache_bytes: u64) -> ObjectBackend<S> {
+        let wal_folder = utils::join(folder, "wal");
+        let cache_folder = utils::join(folder, "cache");
+        utils::create_dir_if_not_exist(folder);
+        utils::create_dir_if_not_exist(&wal_folder);
+        utils::create_dir_if_not_exist(&cache_folder);
+        let backend = ObjectBackend { store, wal_folder, cache_folder, cache: Mutex::new(Cache::default()), cache_bytes };
+        backend.scan_cache();
+        backend
+    }
+
+    /// the partitions cached before a restart, least recently modified
+    /// first, and a fetch cut short removed
+    fn scan_cache(&self) {
+        let mut files : Vec<(SystemTime, String, u64)> = match fs::read_dir(&self.cache_folder) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let meta = entry.metadata().ok()?;
+                    let name = entry.file_name().to_str()?.to_owned();
+                    Some((meta.modified().ok()?, name, meta.len()))
+                })
+                .collect(),
+            Err(e) => { error!("Unable to list {}: {}", self.cache_folder, e); Vec::new() }
+        };
+        files.sort();
+        let mut cache = self.cache.lock().unwrap();
+        for (_, name, len) in files {
+            let fname = utils::join(&self.cache_folder, &name);
+            if name.ends_with(".part") {
+                let _ = fs::remove_file(&fname);
+            } else {
+                cache.insert(&fname, len);
+            }
+        }
+        cache.evict(self.cache_bytes);
+    }
+
+    fn wal_fname(&self, store: &str) -> String {
+        utils::dtf_fname(&self.wal_folder, store)
+    }
+
+    fn partitions(&self, store: &str) -> Result<Vec<Partition>, String> {
+        let mut partitions : Vec<Partition> = self.store.list(&format!("{}/", store))?
+            .iter()
+            .filter_map(|key| Partition::from_key(key))
+            .collect();
+        partitions.sort_by_key(|p| p.min_ts);
+        Ok(partitions)
+    }
+
+    /// upload the write-ahead buffer of a store as a partition
+    fn close_partition(&self, store: &str) -> Result<(), String> {
+        let fname = self.wal_fname(store);
+        let meta = dtf::read_meta(&fname);
+        let key = format!("{}/{}-{}-{}.dtf", store, meta.min_ts, meta.max_ts, meta.nums);
+
+        let mut bytes = Vec::new();
+        File::open(&fname)
+            .and_then(|mut f| f.read_to_end(&mut bytes))
+            .map_err(|e| e.to_string())?;
+        self.store.put(&key, bytes)?;
+        utils::remove_file(&fname).map_err(|e| e.to_string())
+    }
+
+    fn cache_fname(&self, key: &str) -> String {
+        utils::join(&self.cache_folder, &key.replace('/', "--"))
+    }
+
+    /// rows of a partition, from the cache or fetched into it
+    fn fetch(&self, key: &str) -> Result<Vec<Update>, String> {
+        let fname = self.cache_fname(key);
+        // opened under the lock, an eviction meanwhile leaves the file readable
+        let cached = {
+            let mut cache = self.cache.lock().unwrap();
+            if cache.touch(&fname) { File::open(&fname).ok() } else { None }
+        };
+        let bytes = match cached {
+            Some(mut file) => {
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes).map_err(|e| format!("{}: {}", fname, e))?;
+                bytes
+            },
+            None => {
+                let bytes = self.store.get(key)?;
+                let tmp = format!("{}.part", fname);
+                File::create(&tmp)
+                    .and_then(|mut f| f.write_all(&bytes))
+                    .map_err(|e| e.to_string())?;
+                let mut cache = self.cache.lock().unwrap();
+                utils::replace_file(&tmp, &fname).map_err(|e| e.to_string())?;
+                cache.insert(&fname, bytes.len() as u64);
+                cache.evict(self.cache_bytes);
+                bytes
+            }
+        };
+        dtf::decode_buffer(&bytes).map_err(|e| format!("{}: {}", key, e))
+    }
+
+    /// drop a partition from the cache
+    fn evict(&self, key: &str) {
+        let fname = self.cache_fname(key);
+        let mut cache = self.cache.lock().unwrap();
+        cache.remove(&fname);
+        let _ = fs::remove_file(&fname);
+    }
+}
+
+impl<S: ObjectStore> StorageBackend for ObjectBackend<S> {
+    fn append(&self, store: &str, ups: &[Update]) -> Result<(), String> {
+        if ups.is_empty() {
+            return Ok(());
+        }
+        let fname = self.wal_fname(store);
+        if Path::new(&fname).exists() {
+            dtf::append(&fname, &ups.to_vec());
+        } else {
+            dtf::encode(&fname, store, &ups.to_vec());
+        }
+        if dtf::get_size(&fname) >= PARTITION_ROWS {
+            self.close_partition(store)?;
+        }
+        Ok(())
+    }
+
+    fn read(&self, store: &str, range: Option<(u64, u64)>) -> Result<Vec<Update>, String> {
+        let (min_ts, max_ts) = range.unwrap_or((0, u64::max_value()));
+        let mut ups = Vec::new();
+        for partition in self.partitions(store)? {
+            if partition.max_ts < min_ts || partition.min_ts > max_ts {
+                continue;
+            }
+            ups.extend(self.fetch(&partition.key)?.into_iter()
+                .filter(|up| up.ts >= min_ts && up.ts <= max_ts));
+        }
+
+        let wal = self.wal_fname(store);
+        if Path::new(&wal).exists() {
+            ups.extend(dtf::decode(&wal, None).into_iter()
+                .filter(|up| up.ts >= min_ts && up.ts <= max_ts));
+        }
+        Ok(ups)
+    }
+
+    /// The new partitions are uploaded before the old ones are deleted, so a
+    /// failure part way leaves rows twice rather than losing them.
+    fn replace(&self, store: &str, ups: &[Update]) -> Result<(), String> {
+        let old = self.partitions(store)?;
+        let mut keys = Vec::new();
+        for chunk in ups.chunks(PARTITION_ROWS as usize) {
+            let key = format!("{}/{}-{}-{}.dtf", store, chunk[0].ts, chunk[chunk.len() - 1].ts, chunk.len());
+            self.store.put(&key, dtf::encode_buffer(store, chunk))?;
+            // a partition of the same name may be cached with the rows before
+            self.evict(&key);
+            keys.push(key);
+        }
+        for partition in old.iter().filter(|p| !keys.contains(&p.key)) {
+            self.store.delete(&partition.key)?;
+            self.evict(&partition.key);
+        }
+        let wal = self.wal_fname(store);
+        if Path::new(&wal).exists() {
+            utils::remove_file(&wal).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn count(&self, store: &str) -> u64 {
+        let closed : u64 = match self.partitions(store) {
+            Ok(partitions) => partitions.iter().map(|p| p.count).sum(),
+            Err(e) => { error!("Unable to list partitions of {}: {}", store, e); 0 }
+        };
+        let wal = self.wal_fname(store);
+        let buffered = if Path::new(&wal).exists() { dtf::get_size(&wal) } else { 0 };
+        closed + buffered
+    }
+
+    fn stores(&self) -> Vec<String> {
+        let mut stores : Vec<String> = match self.store.list("") {
+            Ok(keys) => keys.iter()
+                .filter_map(|key| key.split('/').next())
+                .map(|store| store.to_owned())
+                .collect(),
+            Err(e) => { error!("Unable to list objects: {}", e); Vec::new() }
+        };
+        if let Ok(entries) = fs::read_dir(&self.wal_folder) {
+            stores.extend(entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| entry.file_name().to_str().map(|s| s.to_owned()))
+                .filter(|name| name.ends_with(".dtf"))
+                .map(|name| name[..(name.len() - 4)].to_owned()));
+        }
+        stores.sort();
+        stores.dedup();
+        stores
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use uuid::Uuid;
+
+    /// objects in memory, counting the fetches
+    #[derive(Debug, Default)]
+    struct MemoryStore {
+        objects: Mutex<HashMap<String, Vec<u8>>>,
+        gets: AtomicUsize,
+    }
+
+    impl ObjectStore for MemoryStore {
+        fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), String> {
+            self.objects.lock().unwrap().insert(key.to_owned(), bytes);
+            Ok(())
+        }
+
+        fn get(&self, key: &str) -> Result<Vec<u8>, String> {
+            self.gets.fetch_add(1, Ordering::SeqCst);
+            self.objects.lock().unwrap().get(key).cloned().ok_or_else(|| format!("No object {}", key))
+        }
+
+        fn delete(&self, key: &str) -> Result<(), String> {
+            self.objects.lock().unwrap().remove(key);
+            Ok(())
+        }
+
+        fn list(&self, prefix: &str) -> Result<Vec<String>, String> {
+            Ok(self.objects.lock().unwrap().keys().filter(|key| key.starts_with(prefix)).cloned().collect())
+        }
+    }
+
+    fn rows(from: u64, n: u64) -> Vec<Update> {
+        (from..(from + n)).map(|i| Update {
+            ts: 1000 * i, seq: i as u32, is_trade: true, is_bid: false, price: 1.0, size: 1.0, flags: 0
+        }).collect()
+    }
+
+    /// a backend on a fresh folder with partitions of 10 rows from 0, 100 and 200,
+    /// caching `partitions` of them
+    fn backend(partitions: u64) -> (ObjectBackend<MemoryStore>, String) {
+        let folder = format!("{}/tectonic_object_{}", env::temp_dir().display(), Uuid::new_v4());
+        let store = MemoryStore::default();
+        let mut len = 0;
+        for &from in &[0, 100, 200] {
+            let ups = rows(from, 10);
+            let bytes = dtf::encode_buffer("btc", &ups);
+            len = bytes.len() as u64;
+            store.put(&format!("btc/{}-{}-10.dtf", from * 1000, (from + 9) * 1000), bytes).unwrap();
+        }
+        (ObjectBackend::new(store, &folder, partitions * len), folder)
+    }
+
+    fn read(backend: &ObjectBackend<MemoryStore>, from: u64) -> usize {
+        backend.read("btc", Some((from * 1000, (from + 9) * 1000))).unwrap().len()
+    }
+
+    fn gets(backend: &ObjectBackend<MemoryStore>) -> usize {
+        backend.store.gets.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn should_evict_the_least_recently_read_partition() {
+        let (backend, folder) = backend(2);
+        assert_eq!(backend.count("btc"), 30);
+        assert_eq!(read(&backend, 0), 10);
+        assert_eq!(read(&backend, 100), 10);
+        assert_eq!(read(&backend, 0), 10);
+        assert_eq!(gets(&backend), 2);
+
+        // the partition from 100 was read last the longest ago
+        assert_eq!(read(&backend, 200), 10);
+        assert_eq!(fs::read_dir(&backend.cache_folder).unwrap().count(), 2);
+        assert_eq!(read(&backend, 0), 10);
+        assert_eq!(gets(&backend), 3);
+        assert_eq!(read(&backend, 100), 10);
+        assert_eq!(gets(&backend), 4);
+
+        let _ = fs::remove_dir_all(&folder);
+    }
+
+    #[test]
+    fn should_keep_the_cache_bounded_across_restarts() {
+        let (backend, folder) = backend(3);
+        assert_eq!(backend.read("btc", None).unwrap().len(), 30);
+        assert_eq!(fs::read_dir(&backend.cache_folder).unwrap().count(), 3);
+
+        let len = backend.cache.lock().unwrap().bytes / 3;
+        let backend = ObjectBackend::new(backend.store, &folder, len);
+        assert_eq!(fs::read_dir(&backend.cache_folder).unwrap().count(), 1);
+        assert_eq!(backend.cache.lock().unwrap().bytes, len);
+
+        let _ = fs::remove_dir_all(&folder);
+    }
+
+    #[test]
+    fn should_replace_partitions_and_their_cached_rows() {
+        let (backend, folder) = backend(3);
+        backend.append("btc", &rows(300, 5)).unwrap();
+        assert_eq!(backend.count("btc"), 35);
+        assert_eq!(backend.read("btc", None).unwrap().len(), 35);
+
+        let kept : Vec<Update> = backend.read("btc", None).unwrap().into_iter().filter(|up| up.seq % 2 == 0).collect();
+        backend.replace("btc", &kept).unwrap();
+        assert_eq!(backend.count("btc"), 18);
+        assert_eq!(backend.read("btc", None).unwrap(), kept);
+        assert!(!Path::new(&backend.wal_fname("btc")).exists());
+
+        let _ = fs::remove_dir_all(&folder);
+    }
+}
@@ -0,0 +1,149 @@
+/// RocksDB backend
+///
+/// Rows are keyed by (store, ts, seq) so a time range of a store is a
+/// single forward scan, and many small stores share one database.
+///
+/// key:   'd' store 0x0 ts(u64 BE) seq(u32 BE)
+/// value: flags(u8) price(f32 BE) size(f32 BE)
+///
+/// The row count of each store is kept under 's' store.
+
+use std::fmt;
+use std::io::Cursor;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rocksdb::{DB, Direction, IteratorMode, WriteBatch};
+use dtf::Update;
+use backend::StorageBackend;
+
+pub struct RocksBackend {
+    db: DB,
+}
+
+impl fmt::Debug for RocksBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RocksBackend")
+    }
+}
+
+fn store_prefix(store: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(store.len() + 14);
+    key.push(b'd');
+    key.extend_from_slice(store.as_bytes());
+    key.push(0x0);
+    key
+}
+
+fn count_key(store: &str) -> Vec<u8> {
+    let mut key = vec![b's'];
+    key.extend_from_slice(store.as_bytes());
+    key
+}
+
+fn row_key(store: &str, ts: u64, seq: u32) -> Vec<u8> {
+    let mut key = store_prefix(store);
+    let _ = key.write_u64::<BigEndian>(ts);
+    let _ = key.write_u32::<BigEndian>(seq);
+    key
+}
+
+fn encode_row(up: &Update) -> Vec<u8> {
+    let mut value = Vec::with_capacity(9);
+    let _ = value.write_u8(up.flags_byte());
+    let _ = value.write_f32::<BigEndian>(up.price);
+    let _ = value.write_f32::<BigEndian>(up.size);
+    value
+}
+
+fn decode_row(key: &[u8], value: &[u8]) -> Option<Update> {
+    let mut key = Cursor::new(&key[(key.len() - 12)..]);
+    let mut value = Cursor::new(value);
+    let flags = value.read_u8().ok()?;
+    Some(Update {
+        ts: key.read_u64::<BigEndian>().ok()?,
+        seq: key.read_u32::<BigEndian>().ok()?,
+        is_bid: false,
+        is_trade: false,
+        price: value.read_f32::<BigEndian>().ok()?,
+        size: value.read_f32::<BigEndian>().ok()?,
+        flags: 0,
+    }.with_flags_byte(flags))
+}
+
+impl RocksBackend {
+    pub fn open(path: &str) -> Result<RocksBackend, String> {
+        let db = DB::open_default(path).map_err(|e| e.to_string())?;
+        Ok(RocksBackend { db })
+    }
+}
+
+impl StorageBackend for RocksBackend {
+    fn append(&self, store: &str, ups: &[Update]) -> Result<(), String> {
+        let mut batch = WriteBatch::default();
+        let mut count = self.count(store);
+        for up in ups {
+            let key = row_key(store, up.ts, up.seq);
+            if self.db.get(&key).map_err(|e| e.to_string())?.is_none() {
+                count += 1;
+            }
+            batch.put(&key, &encode_row(up)).map_err(|e| e.to_string())?;
+        }
+        let mut value = Vec::with_capacity(8);
+        let _ = value.write_u64::<BigEndian>(count);
+        batch.put(&count_key(store), &value).map_err(|e| e.to_string())?;
+        self.db.write(batch).map_err(|e| e.to_string())
+    }
+
+    fn read(&self, store: &str, range: Option<(u64, u64)>) -> Result<Vec<Update>, String> {
+        let prefix = store_prefix(store);
+        let (start, max_ts) = match range {
+            Some((min_ts, max_ts)) => (row_key(store, min_ts, 0), max_ts),
+            None => (prefix.clone(), u64::max_value())
+        };
+
+        let mut ups = Vec::new();
+        for (key, value) in self.db.iterator(IteratorMode::From(&start, Direction::Forward)) {
+            if !key.starts_with(&prefix) || key.len() != prefix.len() + 12 {
+                break;
+            }
+            match decode_row(&key, &value) {
+                Some(ref up) if up.ts > max_ts => break,
+                Some(up) => ups.push(up),
+                None => return Err(format!("Corrupted row in `{}`", store))
+            }
+        }
+        Ok(ups)
+    }
+
+    fn replace(&self, store: &str, ups: &[Update]) -> Result<(), String> {
+        let prefix = store_prefix(store);
+        let mut batch = WriteBatch::default();
+        for (key, _) in self.db.iterator(IteratorMode::From(&prefix, Direction::Forward)) {
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            batch.delete(&key).map_err(|e| e.to_string())?;
+        }
+        for up in ups {
+            batch.put(&row_key(store, up.ts, up.seq), &encode_row(up)).map_err(|e| e.to_string())?;
+        }
+        let mut value = Vec::with_capacity(8);
+        let _ = value.write_u64::<BigEndian>(ups.len() as u64);
+        batch.put(&count_key(store), &value).map_err(|e| e.to_string())?;
+        // one batch, so the store is never seen half replaced
+        self.db.write(batch).map_err(|e| e.to_string())
+    }
+
+    fn count(&self, store: &str) -> u64 {
+        match self.db.get(&count_key(store)) {
+            Ok(Some(value)) => Cursor::new(&value[..]).read_u64::<BigEndian>().unwrap_or(0),
+            _ => 0
+        }
+    }
+
+    fn stores(&self) -> Vec<String> {
+        self.db.iterator(IteratorMode::From(b"s", Direction::Forward))
+            .take_while(|&(ref key, _)| key.starts_with(b"s"))
+            .filter_map(|(key, _)| String::from_utf8(key[1..].to_vec()).ok())
+            .collect()
+    }
+}
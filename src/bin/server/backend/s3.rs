@@ -0,0 +1,89 @@
+/// Amazon S3 as an `ObjectStore`
+///
+/// Credentials and region are read from the environment like the aws cli,
+/// e.g. AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and AWS_DEFAULT_REGION.
+
+use std::fmt;
+use futures::{Future, Stream};
+use rusoto_core::Region;
+use rusoto_s3::{S3, S3Client, DeleteObjectRequest, GetObjectRequest, ListObjectsV2Request, PutObjectRequest};
+use backend::object::ObjectStore;
+
+pub struct S3Store {
+    client: S3Client,
+    bucket: String,
+}
+
+impl fmt::Debug for S3Store {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "S3Store {{ bucket: {} }}", self.bucket)
+    }
+}
+
+impl S3Store {
+    pub fn new(bucket: &str) -> S3Store {
+        S3Store {
+            client: S3Client::simple(Region::default()),
+            bucket: bucket.to_owned(),
+        }
+    }
+}
+
+impl ObjectStore for S3Store {
+    fn put(&self, key: &str, bytes: Vec<u8>) -> Result<(), String> {
+        let req = PutObjectRequest {
+            bucket: self.bucket.to_owned(),
+            key: key.to_owned(),
+            body: Some(bytes),
+            ..Default::default()
+        };
+        self.client.put_object(&req).sync()
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, String> {
+        let req = GetObjectRequest {
+            bucket: self.bucket.to_owned(),
+            key: key.to_owned(),
+            ..Default::default()
+        };
+        let output = self.client.get_object(&req).sync().map_err(|e| e.to_string())?;
+        match output.body {
+            Some(body) => body.concat2().wait().map_err(|e| e.to_string()),
+            None => Ok(Vec::new())
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<(), String> {
+        let req = DeleteObjectRequest {
+            bucket: self.bucket.to_owned(),
+            key: key.to_owned(),
+            ..Default::default()
+        };
+        self.client.delete_object(&req).sync()
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>, String> {
+        let mut keys = Vec::new();
+        let mut token = None;
+        loop {
+            let req = ListObjectsV2Request {
+                bucket: self.bucket.to_owned(),
+                prefix: Some(prefix.to_owned()),
+                continuation_token: token,
+                ..Default::default()
+            };
+            let output = self.client.list_objects_v2(&req).sync().map_err(|e| e.to_string())?;
+            keys.extend(output.contents.unwrap_or_default()
+                .into_iter()
+                .filter_map(|object| object.key));
+            token = output.next_continuation_token;
+            if token.is_none() {
+                return Ok(keys);
+            }
+        }
+    }
+}
@@ -0,0 +1,378 @@
+/// io_uring backend (Linux)
+///
+/// The rows stay in the dtf files of `--storage dtf`, one per store in
+/// dtf_folder, but are read and appended through an io_uring instead of one
+/// blocking syscall at a time. A read submits every chunk of a file at once,
+/// so an NVMe drive serves them in parallel. An append submits the new
+/// batches and the new header, linked so the header is written after the
+/// batches. Ops the kernel completes short or cancels are finished with
+/// plain `pread`/`pwrite`, and a kernel without io_uring (or a seccomp
+/// profile denying it) gets the files through the standard path, see
+/// `backend::open`.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use io_uring::{opcode, squeue, types, IoUring};
+use libc;
+use dtf::{self, Update};
+use backend::StorageBackend;
+use utils;
+
+/// entries of the ring, at most this many ops are in flight
+const QUEUE_DEPTH : u32 = 64;
+/// bytes of one read or write op
+const CHUNK_LEN : usize = 1 << 20;
+/// ms waited for the ops in flight once the ring fails, before aborting
+const DRAIN_ATTEMPTS : u32 = 10_000;
+
+pub struct UringBackend {
+    ring: Mutex<Ring>,
+    dtf_folder: String,
+}
+
+struct Ring {
+    ring: IoUring,
+    /// bumped by every `run`, the high half of the `user_data` of its ops
+    generation: u32,
+}
+
+impl fmt::Debug for UringBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UringBackend")
+    }
+}
+
+/// one op: `len` bytes of a buffer at `ptr` to or from `offset` of the file
+struct Op {
+    offset: u64,
+    ptr: *mut u8,
+    len: usize,
+}
+
+/// the ops of the `len` bytes at `ptr` to or from `offset`, CHUNK_LEN bytes each
+fn chunks(ptr: *mut u8, len: usize, offset: u64) -> Vec<Op> {
+    (0..len).step_by(CHUNK_LEN)
+        .map(|start| Op {
+            offset: offset + start as u64,
+            ptr: unsafe { ptr.offset(start as isize) },
+            len: (len - start).min(CHUNK_LEN),
+        })
+        .collect()
+}
+
+/// whether an op that failed with `errno` is worth retrying with a plain syscall
+fn is_retryable(errno: i32) -> bool {
+    errno == libc::ECANCELED || errno == libc::EINTR || errno == libc::EAGAIN
+}
+
+/// `user_data` of the op at `index` of the ops of `generation`
+fn user_data(generation: u32, index: usize) -> u64 {
+    u64::from(generation) << 32 | index as u64
+}
+
+/// Submit the ops pushed and wait until `pending` ops of `generation` are
+/// completed, recording their results. The ops point at the buffers of the
+/// caller, so they are all waited for even when submitting fails; should the
+/// ring stay broken, the process aborts rather than free buffers the kernel
+/// may still write to. Completions of other generations are dropped.
+fn complete(ring: &mut IoUring, generation: u32, mut pending: usize, results: &mut [i32]) -> io::Result<()> {
+    let mut error = None;
+    let mut fruitless = 0;
+    while pending > 0 {
+        match ring.submit_and_wait(pending) {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => if error.is_none() { error = Some(e) }
+        }
+        let mut progress = false;
+        for cqe in ring.completion() {
+            let (cqe_generation, index) = ((cqe.user_data() >> 32) as u32, (cqe.user_data() & 0xFFFF_FFFF) as usize);
+            if cqe_generation != generation || index >= results.len() {
+                continue;
+            }
+            results[index] = cqe.result();
+            pending -= 1;
+            progress = true;
+        }
+        if progress || error.is_none() {
+            fruitless = 0;
+            continue;
+        }
+        fruitless += 1;
+        if fruitless > DRAIN_ATTEMPTS {
+            error!("io_uring fails with {:?} and {} ops are in flight, aborting", error, pending);
+            process::abort();
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    match error {
+        Some(e) => Err(e),
+        None => Ok(())
+    }
+}
+
+impl UringBackend {
+    /// Fails if the kernel has no io_uring, e.g. before Linux 5.1
+    pub fn open(dtf_folder: &str) -> Result<UringBackend, String> {
+        let ring = IoUring::new(QUEUE_DEPTH).map_err(|e| e.to_string())?;
+        Ok(UringBackend { ring: Mutex::new(Ring { ring, generation: 0 }), dtf_folder: dtf_folder.to_owned() })
+    }
+
+    /// Run `ops` on `file`, QUEUE_DEPTH at a time, and return the result of
+    /// each: the bytes transferred or a negated errno. When `linked`, the ops
+    /// submitted together run one after the other and an op after a failed
+    /// or short one is cancelled.
+    fn run(&self, file: &File, ops: &[Op], write: bool, linked: bool) -> io::Result<Vec<i32>> {
+        let fd = types::Fd(file.as_raw_fd());
+        let mut results = vec![0; ops.len()];
+        let mut ring = self.ring.lock().unwrap();
+        ring.generation = ring.generation.wrapping_add(1);
+        let generation = ring.generation;
+        for (window, ops) in ops.chunks(QUEUE_DEPTH as usize).enumerate() {
+            let mut pushed = 0;
+            let mut pushing = Ok(());
+            {
+                let mut sq = ring.ring.submission();
+                for (i, op) in ops.iter().enumerate() {
+                    let entry = if write {
+                        opcode::Write::new(fd, op.ptr, op.len as u32).offset(op.offset).build()
+                    } else {
+                        opcode::Read::new(fd, op.ptr, op.len as u32).offset(op.offset).build()
+                    };
+                    let mut entry = entry.user_data(user_data(generation, window * QUEUE_DEPTH as usize + i));
+                    if linked && i + 1 < ops.len() {
+                        entry = entry.flags(squeue::Flags::IO_LINK);
+                    }
+                    // the buffers outlive the ops: every op pushed is waited for, even after an error
+                    if unsafe { sq.push(&entry) }.is_err() {
+                        pushing = Err(io::Error::new(io::ErrorKind::Other, "submission queue is full"));
+                        break;
+                    }
+                    pushed += 1;
+                }
+            }
+            let completed = complete(&mut ring.ring, generation, pushed, &mut results);
+            pushing?;
+            completed?;
+        }
+        Ok(results)
+    }
+
+    /// fill `buf` from `offset` of `file`
+    fn read_at(&self, file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let ops = chunks(buf.as_mut_ptr(), buf.len(), offset);
+        let results = self.run(file, &ops, false, false)?;
+        for (op, &result) in ops.iter().zip(&results) {
+            let done = match result {
+                n if n >= 0 => n as usize,
+                errno if is_retryable(-errno) => 0,
+                errno => return Err(io::Error::from_raw_os_error(-errno))
+            };
+            if done < op.len {
+                let start = (op.offset - offset) as usize;
+                file.read_exact_at(&mut buf[(start + done)..(start + op.len)], op.offset + done as u64)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// write each (bytes, offset) of `parts` to `file`, in order
+    fn write_at(&self, file: &File, parts: &[(&[u8], u64)]) -> io::Result<()> {
+        let ops : Vec<Op> = parts.iter().flat_map(|&(bytes, offset)| chunks(bytes.as_ptr() as *mut u8, bytes.len(), offset)).collect();
+        let results = self.run(file, &ops, true, true)?;
+        // a short or failed op cancels the ops linked after it, which are redone in order
+        for (op, &result) in ops.iter().zip(&results) {
+            let done = match result {
+                n if n >= 0 => n as usize,
+                errno if is_retryable(-errno) => 0,
+                errno => return Err(io::Error::from_raw_os_error(-errno))
+            };
+            if done < op.len {
+                let bytes = unsafe { ::std::slice::from_raw_parts(op.ptr.offset(done as isize), op.len - done) };
+                file.write_all_at(bytes, op.offset + done as u64)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// the header of a dtf file
+    fn read_header(&self, file: &File) -> io::Result<Vec<u8>> {
+        let len = file.metadata()?.len().min(dtf::MAIN_OFFSET) as usize;
+        let mut header = vec![0; len];
+        self.read_at(file, &mut header, 0)?;
+        Ok(header)
+    }
+}
+
+impl StorageBackend for UringBackend {
+    fn append(&self, store: &str, ups: &[Update]) -> Result<(), String> {
+        if ups.is_empty() {
+            return Ok(());
+        }
+        let fname = utils::dtf_fname(&self.dtf_folder, store);
+        if !Path::new(&fname).exists() {
+            let buf = dtf::encode_buffer(store, ups);
+            let file = File::create(&fname).map_err(|e| e.to_string())?;
+            self.write_at(&file, &[(&buf, 0)]).map_err(|e| format!("{}: {}", fname, e))?;
+            dtf::batch_index_written(&fname, ups);
+            return Ok(());
+        }
+
+        let file = OpenOptions::new().read(true).write(true).open(&fname).map_err(|e| e.to_string())?;
+        let header = self.read_header(&file).map_err(|e| format!("{}: {}", fname, e))?;
+        let plan = match dtf::plan_append(&header, ups).map_err(|e| format!("{}: {}", fname, e))? {
+            Some(plan) => plan,
+            None => return Ok(())
+        };
+        let old_len = file.metadata().map_err(|e| e.to_string())?.len();
+        let offset = if plan.at_main_offset { dtf::MAIN_OFFSET } else { old_len };
+        self.write_at(&file, &[(&plan.batches, offset), (&plan.header, 0)])
+            .map_err(|e| format!("{}: {}", fname, e))?;
+        dtf::batch_index_appended(&fname, old_len, &plan);
+        Ok(())
+    }
+
+    fn read(&self, store: &str, range: Option<(u64, u64)>) -> Result<Vec<Update>, String> {
+        let fname = utils::dtf_fname(&self.dtf_folder, store);
+        if !Path::new(&fname).exists() {
+            return Ok(Vec::new());
+        }
+        let file = File::open(&fname).map_err(|e| e.to_string())?;
+        let len = file.metadata().map_err(|e| e.to_string())?.len() as usize;
+        let mut buf = vec![0; len];
+        self.read_at(&file, &mut buf, 0).map_err(|e| format!("{}: {}", fname, e))?;
+        let ups = dtf::decode_buffer(&buf).map_err(|e| format!("{}: {}", fname, e))?;
+        Ok(match range {
+            Some((min_ts, max_ts)) => ups.into_iter().filter(|up| up.ts >= min_ts && up.ts <= max_ts).collect(),
+            None => ups
+        })
+    }
+
+    fn replace(&self, store: &str, ups: &[Update]) -> Result<(), String> {
+        let fname = utils::dtf_fname(&self.dtf_folder, store);
+        let tmp = format!("{}.replace", fname);
+        let buf = dtf::encode_buffer(store, ups);
+        {
+            let file = File::create(&tmp).map_err(|e| e.to_string())?;
+            if let Err(e) = self.write_at(&file, &[(&buf, 0)]) {
+                let _ = fs::remove_file(&tmp);
+                return Err(format!("{}: {}", tmp, e));
+            }
+        }
+        dtf::batch_index_written(&tmp, ups);
+        utils::replace_file(&tmp, &fname).map_err(|e| e.to_string())
+    }
+
+    fn count(&self, store: &str) -> u64 {
+        let fname = utils::dtf_fname(&self.dtf_folder, store);
+        if !Path::new(&fname).exists() {
+            return 0;
+        }
+        let header = File::open(&fname).and_then(|file| self.read_header(&file));
+        match header.and_then(|header| dtf::header_len(&header)) {
+            Ok(len) => len,
+            Err(e) => { error!("Unable to read the header of {}: {}", fname, e); 0 }
+        }
+    }
+
+    fn stores(&self) -> Vec<String> {
+        let mut stores : Vec<String> = match fs::read_dir(&self.dtf_folder) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| entry.file_name().to_str().map(|s| s.to_owned()))
+                .filter(|name| name.ends_with(".dtf"))
+                .map(|name| name[..(name.len() - 4)].to_owned())
+                .collect(),
+            Err(e) => { error!("Unable to list {}: {}", self.dtf_folder, e); Vec::new() }
+        };
+        stores.sort();
+        stores
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use uuid::Uuid;
+
+    fn rows(from: u64, n: u64) -> Vec<Update> {
+        (from..(from + n)).map(|i| Update {
+            ts: 1000 * i, seq: i as u32, is_trade: i % 2 == 0, is_bid: i % 3 == 0, price: i as f32, size: 1.0, flags: 0
+        }).collect()
+    }
+
+    /// a backend on a fresh folder, None on a kernel without io_uring
+    fn backend() -> Option<(UringBackend, String)> {
+        let folder = format!("{}/tectonic_uring_{}", env::temp_dir().display(), Uuid::new_v4());
+        utils::create_dir_if_not_exist(&folder);
+        match UringBackend::open(&folder) {
+            Ok(backend) => Some((backend, folder)),
+            Err(e) => {
+                eprintln!("io_uring is unavailable ({}), skipped", e);
+                let _ = fs::remove_dir_all(&folder);
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn should_write_dtf_files_through_the_ring() {
+        let (backend, folder) = match backend() {
+            Some(backend) => backend,
+            None => return
+        };
+        let fname = utils::dtf_fname(&folder, "btc");
+        // more than one chunk, so reads and writes take several ops
+        let ups = rows(0, 200_000);
+        backend.append("btc", &ups[..150_000]).unwrap();
+        backend.append("btc", &ups[150_000..]).unwrap();
+        // rows already written are skipped
+        backend.append("btc", &ups[199_990..]).unwrap();
+        assert!(dtf::get_size(&fname) > 0 && fs::metadata(&fname).unwrap().len() > CHUNK_LEN as u64);
+
+        assert_eq!(backend.count("btc"), 200_000);
+        assert_eq!(backend.read("btc", None).unwrap(), ups);
+        assert_eq!(dtf::decode(&fname, None), ups);
+        assert_eq!(backend.read("btc", Some((10_000, 19_000))).unwrap(), &ups[10..20]);
+        assert_eq!(backend.stores(), vec!["btc".to_owned()]);
+
+        let kept : Vec<Update> = ups.iter().filter(|up| up.seq % 2 == 0).cloned().collect();
+        backend.replace("btc", &kept).unwrap();
+        assert_eq!(backend.count("btc"), 100_000);
+        assert_eq!(dtf::decode(&fname, None), kept);
+        assert!(!Path::new(&format!("{}.replace", fname)).exists());
+
+        let _ = fs::remove_dir_all(&folder);
+    }
+
+    #[test]
+    fn should_drop_completions_of_earlier_calls() {
+        let (backend, folder) = match backend() {
+            Some(backend) => backend,
+            None => return
+        };
+        let ups = rows(0, 1000);
+        backend.append("btc", &ups).unwrap();
+        {
+            // completions left in the ring, e.g. by a call that failed
+            let mut ring = backend.ring.lock().unwrap();
+            for &data in &[user_data(0, 0), user_data(0, 10_000)] {
+                let nop = opcode::Nop::new().build().user_data(data);
+                unsafe { ring.ring.submission().push(&nop) }.unwrap();
+            }
+            ring.ring.submit().unwrap();
+        }
+        assert_eq!(backend.read("btc", None).unwrap(), ups);
+
+        let _ = fs::remove_dir_all(&folder);
+    }
+}
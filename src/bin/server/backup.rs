@@ -0,0 +1,231 @@
+/// Incremental backups
+///
+/// `BACKUP TO [folder]` copies every file of dtf_folder to a new numbered
+/// backup, `[folder]/[n]/`, listed in `[folder]/[n]/manifest`. With
+/// `BACKUP TO [folder] INCREMENTAL` only the files created or changed since
+/// the last manifest are copied, and the manifest points the others to the
+/// earlier backup holding them. Sealed partitions never change, so the hourly
+/// backup of an archive partitioned with `--max_partition_bytes` only copies
+/// the new partitions and the files still appended to, and the new backup
+/// folder is all an offsite sync has to upload. The journal of `--journal`
+/// only grows, its bytes written since the last backup are copied as a
+/// `journal/[offset]` segment.
+///
+/// A manifest line is `path <TAB> bytes <TAB> mtime <TAB> backup`, the path
+/// relative to dtf_folder. Restoring backup n copies every path of its
+/// manifest from `[folder]/[backup]/[path]` to an empty dtf_folder, the
+/// journal segments concatenated by offset. Rows are backed up once flushed,
+/// a file written while it is copied is copied again.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use utils;
+
+/// folder of the journal segments in a backup
+static JOURNAL : &str = "journal";
+
+/// attempts to copy a file that keeps being written
+const COPY_ATTEMPTS : usize = 3;
+
+/// suffixes of the files being written by a flush, compaction or upload
+static TEMPORARY : &[&str] = &[".tmp", ".part", ".upload", ".compact", ".retention", ".tiering"];
+
+#[derive(Clone, Debug, PartialEq)]
+struct Entry {
+    len: u64,
+    /// ns since epoch of the last modification
+    mtime: u64,
+    /// backup holding the copy
+    backup: u32,
+}
+
+/// path relative to dtf_folder -> entry
+type Manifest = BTreeMap<String, Entry>;
+
+/// What a backup copied
+#[derive(Debug, Default)]
+pub struct Report {
+    pub backup: u32,
+    pub copied: usize,
+    pub bytes: u64,
+    pub unchanged: usize,
+}
+
+fn manifest_fname(folder: &str, backup: u32) -> String {
+    utils::join(&utils::join(folder, &backup.to_string()), "manifest")
+}
+
+/// the newest backup in `folder` with a manifest
+fn last(folder: &str) -> Option<(u32, Manifest)> {
+    let backup = fs::read_dir(folder).ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter_map(|name| name.parse::<u32>().ok())
+        .filter(|&n| Path::new(&manifest_fname(folder, n)).exists())
+        .max()?;
+    Some((backup, read_manifest(&manifest_fname(folder, backup)).ok()?))
+}
+
+fn read_manifest(fname: &str) -> Result<Manifest, io::Error> {
+    let mut manifest = BTreeMap::new();
+    for line in BufReader::new(File::open(fname)?).lines() {
+        let line = line?;
+        let parts : Vec<&str> = line.split('\t').collect();
+        if parts.len() != 4 {
+            continue;
+        }
+        if let (Ok(len), Ok(mtime), Ok(backup)) = (parts[1].parse(), parts[2].parse(), parts[3].parse()) {
+            manifest.insert(parts[0].to_owned(), Entry { len, mtime, backup });
+        }
+    }
+    Ok(manifest)
+}
+
+/// written last, a backup without its manifest is ignored
+fn write_manifest(fname: &str, manifest: &Manifest) -> Result<(), io::Error> {
+    let tmp = format!("{}.tmp", fname);
+    {
+        let mut file = File::create(&tmp)?;
+        for (path, entry) in manifest {
+            writeln!(file, "{}\t{}\t{}\t{}", path, entry.len, entry.mtime, entry.backup)?;
+        }
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, fname)
+}
+
+/// (len, mtime in ns) of a file
+fn stat(path: &Path) -> Result<(u64, u64), io::Error> {
+    let metadata = fs::metadata(path)?;
+    let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos()))
+        .unwrap_or(0);
+    Ok((metadata.len(), mtime))
+}
+
+/// paths of the files under `dir`, relative to `root`
+fn walk(root: &Path, dir: &Path, paths: &mut Vec<PathBuf>) -> Result<(), io::Error> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            walk(root, &path, paths)?;
+        } else if let Ok(relative) = path.strip_prefix(root) {
+            paths.push(relative.to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+/// Copy `src` to `dst`, again if it was written meanwhile. Returns its (len, mtime).
+fn copy_stable(src: &Path, dst: &Path) -> Result<(u64, u64), io::Error> {
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut before = stat(src)?;
+    for _ in 0..COPY_ATTEMPTS {
+        fs::copy(src, dst)?;
+        let after = stat(src)?;
+        if after == before {
+            return Ok(after);
+        }
+        before = after;
+    }
+    Err(io::Error::new(io::ErrorKind::Other, format!("{} kept changing while copied", src.display())))
+}
+
+/// Copy the bytes of `src` from `offset` to `dst`, returns how many
+fn copy_from(src: &Path, offset: u64, dst: &Path) -> Result<u64, io::Error> {
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = File::open(src)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut out = OpenOptions::new().create(true).write(true).truncate(true).open(dst)?;
+    io::copy(&mut file, &mut out)
+}
+
+/// Back up `dtf_folder` and the `journal` to a new backup in `folder`,
+/// only what changed since the last one if `incremental`
+pub fn run(dtf_folder: &str, journal: Option<&str>, folder: &str, incremental: bool) -> Result<Report, io::Error> {
+    let root = fs::canonicalize(dtf_folder)?;
+    fs::create_dir_all(folder)?;
+    if fs::canonicalize(folder)?.starts_with(&root) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the backup folder is in dtf_folder"));
+    }
+    let journal = journal.and_then(|journal| fs::canonicalize(journal).ok());
+    let (previous, old) = match last(folder) {
+        Some((n, manifest)) => (Some(n), manifest),
+        None => (None, BTreeMap::new())
+    };
+    let old = if incremental { old } else { BTreeMap::new() };
+    let mut report = Report { backup: previous.map_or(0, |n| n + 1), ..Report::default() };
+    let target = utils::join(folder, &report.backup.to_string());
+    if Path::new(&target).exists() {
+        // left by a backup that failed before its manifest
+        fs::remove_dir_all(&target)?;
+    }
+    let mut manifest = BTreeMap::new();
+
+    let mut paths = Vec::new();
+    walk(&root, &root, &mut paths)?;
+    paths.sort();
+    for relative in paths {
+        let path = root.join(&relative);
+        let name = match relative.to_str() {
+            Some(name) => name.to_owned(),
+            None => continue
+        };
+        if TEMPORARY.iter().any(|suffix| name.ends_with(suffix)) || Some(&path) == journal.as_ref() {
+            continue;
+        }
+        let (len, mtime) = match stat(&path) {
+            Ok(stat) => stat,
+            // removed by a compaction meanwhile
+            Err(_) => continue
+        };
+        match old.get(&name) {
+            Some(entry) if entry.len == len && entry.mtime == mtime => {
+                manifest.insert(name, entry.clone());
+                report.unchanged += 1;
+            }
+            _ => {
+                let (len, mtime) = match copy_stable(&path, &Path::new(&target).join(&relative)) {
+                    Ok(stat) => stat,
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e)
+                };
+                manifest.insert(name, Entry { len, mtime, backup: report.backup });
+                report.copied += 1;
+                report.bytes += len;
+            }
+        }
+    }
+
+    if let Some(journal) = journal {
+        // the segments in the manifest hold the journal up to `end`
+        let mut segments : Vec<(String, Entry)> = old.iter()
+            .filter(|&(path, _)| path.starts_with(&format!("{}/", JOURNAL)))
+            .map(|(path, entry)| (path.clone(), entry.clone()))
+            .collect();
+        let end : u64 = segments.iter().map(|&(_, ref entry)| entry.len).sum();
+        let (len, mtime) = stat(&journal)?;
+        // a journal shorter than its segments was replaced, it starts over
+        let end = if len < end { segments.clear(); 0 } else { end };
+        report.unchanged += segments.len();
+        manifest.extend(segments);
+        if len > end {
+            let path = format!("{}/{:020}", JOURNAL, end);
+            let copied = copy_from(&journal, end, &Path::new(&target).join(&path))?;
+            manifest.insert(path, Entry { len: copied, mtime, backup: report.backup });
+            report.copied += 1;
+            report.bytes += copied;
+        }
+    }
+
+    fs::create_dir_all(&target)?;
+    write_manifest(&manifest_fname(folder, report.backup), &manifest)?;
+    Ok(report)
+}
@@ -0,0 +1,247 @@
+/// Result cache
+///
+/// Dashboards refresh the same queries, e.g. the last hour of 1-minute
+/// candles, every few seconds. The responses of `GET` and `GET ... ASOF
+/// EPOCH` are kept in an LRU cache of `--result_cache` MB shared by the
+/// connections, keyed by store, range, query (count, format, precision and
+/// encoding) and epoch, and dropped when a write touches their range: an
+/// insert, `DELETE` or correction drops the entries of the store whose range
+/// holds its ts, a flush, `CLEAR`, `COMPACT`, an upload, retention or tiering
+/// drops every entry of the store. `INFO` shows the hits and misses.
+///
+/// Each store has a write generation, bumped by every invalidation. A read
+/// records it when it starts, and its response is only kept if no write
+/// touched the store meanwhile. The cache has its own lock, so a `GET`
+/// does not wait for the writers of the shared state.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Key {
+    pub store: String,
+    /// (min, max) ts in ms, both included
+    pub range: Option<(u64, u64)>,
+    /// e.g. `All JSON(None) DTF Some(2)`
+    pub query: String,
+    /// epoch of `ASOF EPOCH`, None for the rows in memory
+    pub epoch: Option<u32>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Response {
+    String(String),
+    Bytes(Vec<u8>),
+}
+
+impl Response {
+    fn len(&self) -> usize {
+        match *self {
+            Response::String(ref s) => s.len(),
+            Response::Bytes(ref b) => b.len(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    key: Key,
+    response: Response,
+    /// tick of the last hit, the smallest is evicted first
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct ResultCache {
+    entries: Mutex<Entries>,
+}
+
+impl ResultCache {
+    /// the cached response, or the write generation of the store to pass to `insert`
+    pub fn get(&self, key: &Key) -> Result<Response, u64> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(response) => Ok(response),
+            None => Err(entries.generation(&key.store))
+        }
+    }
+
+    /// keep the response of a read started at `generation` of its store,
+    /// unless a write touched the store since
+    pub fn insert(&self, key: Key, response: Response, capacity: usize, generation: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.generation(&key.store) == generation {
+            entries.insert(key, response, capacity);
+        }
+    }
+
+    /// drop the entries of `store` overlapping `range` (in ms), all of them if None
+    pub fn invalidate(&self, store: &str, range: Option<(u64, u64)>) {
+        let mut entries = self.entries.lock().unwrap();
+        *entries.generations.entry(store.to_owned()).or_insert(0) += 1;
+        entries.invalidate(store, range);
+    }
+
+    /// {"entries": 12, "bytes": 48213, "hits": 1502, "misses": 37}
+    pub fn to_json(&self) -> String {
+        self.entries.lock().unwrap().to_json()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    /// store -> cached responses
+    stores: HashMap<String, Vec<Entry>>,
+    /// store -> invalidations so far
+    generations: HashMap<String, u64>,
+    bytes: usize,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl Entries {
+    fn generation(&self, store: &str) -> u64 {
+        self.generations.get(store).cloned().unwrap_or(0)
+    }
+
+    fn get(&mut self, key: &Key) -> Option<Response> {
+        self.tick += 1;
+        let tick = self.tick;
+        let found = self.stores.get_mut(&key.store)
+            .and_then(|entries| entries.iter_mut().find(|entry| entry.key == *key))
+            .map(|entry| {
+                entry.used = tick;
+                entry.response.clone()
+            });
+        if found.is_some() {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        found
+    }
+
+    /// keep a response, evicting the least recently used ones beyond `capacity` bytes
+    fn insert(&mut self, key: Key, response: Response, capacity: usize) {
+        if response.len() > capacity {
+            return;
+        }
+        self.tick += 1;
+        self.bytes += response.len();
+        let store = key.store.clone();
+        let entries = self.stores.entry(store).or_insert_with(Vec::new);
+        if let Some(i) = entries.iter().position(|entry| entry.key == key) {
+            self.bytes -= entries.remove(i).response.len();
+        }
+        entries.push(Entry { key, response, used: self.tick });
+        while self.bytes > capacity {
+            self.evict();
+        }
+    }
+
+    fn evict(&mut self) {
+        let oldest = self.stores.iter()
+            .flat_map(|(store, entries)| entries.iter().enumerate().map(move |(i, entry)| (entry.used, store, i)))
+            .min()
+            .map(|(_, store, i)| (store.clone(), i));
+        if let Some((store, i)) = oldest {
+            let entries = self.stores.get_mut(&store).unwrap();
+            self.bytes -= entries.remove(i).response.len();
+            if entries.is_empty() {
+                self.stores.remove(&store);
+            }
+        }
+    }
+
+    fn invalidate(&mut self, store: &str, range: Option<(u64, u64)>) {
+        let emptied = match self.stores.get_mut(store) {
+            Some(entries) => {
+                let before : usize = entries.iter().map(|entry| entry.response.len()).sum();
+                entries.retain(|entry| match (range, entry.key.range) {
+                    (Some((min, max)), Some((entry_min, entry_max))) => max < entry_min || min > entry_max,
+                    _ => false
+                });
+                let after : usize = entries.iter().map(|entry| entry.response.len()).sum();
+                self.bytes -= before - after;
+                entries.is_empty()
+            }
+            None => return
+        };
+        if emptied {
+            self.stores.remove(store);
+        }
+    }
+
+    fn to_json(&self) -> String {
+        format!(r#"{{"entries": {}, "bytes": {}, "hits": {}, "misses": {}}}"#,
+            self.stores.values().map(|entries| entries.len()).sum::<usize>(), self.bytes, self.hits, self.misses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use state::tests::{add, connect, global, run};
+
+    fn key(store: &str, range: Option<(u64, u64)>) -> Key {
+        Key { store: store.to_owned(), range, query: "All JSON(None)".to_owned(), epoch: None }
+    }
+
+    #[test]
+    fn should_evict_the_least_recently_used_response() {
+        let cache = ResultCache::default();
+        cache.insert(key("a", None), Response::String("aaaa".to_owned()), 8, 0);
+        cache.insert(key("b", None), Response::Bytes(vec![0; 4]), 8, 0);
+        assert!(cache.get(&key("a", None)).is_ok());
+        cache.insert(key("c", None), Response::String("cccc".to_owned()), 8, 0);
+        assert!(cache.get(&key("b", None)).is_err());
+        assert!(cache.get(&key("a", None)).is_ok());
+        assert_eq!(cache.entries.lock().unwrap().bytes, 8);
+        // a response larger than the cache is not kept
+        cache.insert(key("d", None), Response::String("d".repeat(9)), 8, 0);
+        assert!(cache.get(&key("d", None)).is_err());
+        assert_eq!(cache.to_json(), r#"{"entries": 2, "bytes": 8, "hits": 2, "misses": 2}"#);
+    }
+
+    #[test]
+    fn should_not_keep_a_response_read_before_a_write() {
+        let cache = ResultCache::default();
+        let generation = cache.get(&key("a", Some((0, 10)))).unwrap_err();
+
+        // a write outside of the range of the read still bumps the generation
+        cache.invalidate("a", Some((20, 30)));
+        cache.insert(key("a", Some((0, 10))), Response::String("stale".to_owned()), 8, generation);
+        let generation = cache.get(&key("a", Some((0, 10)))).unwrap_err();
+
+        cache.insert(key("a", Some((0, 10))), Response::String("fresh".to_owned()), 8, generation);
+        match cache.get(&key("a", Some((0, 10)))) {
+            Ok(Response::String(s)) => assert_eq!(s, "fresh"),
+            other => panic!("{:?}", other)
+        }
+    }
+
+    #[test]
+    fn should_drop_the_responses_a_write_touches() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "USE btc_usdt");
+        add(&mut state, 0);
+        add(&mut state, 1);
+        let get = "GET ALL FROM 1505177459 TO 1505177460 AS JSON";
+        let first = run(&mut state, get);
+        assert_eq!(run(&mut state, get), first);
+        assert_eq!(global.read().unwrap().result_cache.entries.lock().unwrap().hits, 1);
+
+        // a row outside of the range keeps the response
+        add(&mut state, 5);
+        assert_eq!(run(&mut state, get), first);
+        assert_eq!(global.read().unwrap().result_cache.entries.lock().unwrap().hits, 2);
+
+        // a row in the range drops it
+        run(&mut state, "ADD 1505177459.900, 139020, f, t, 0.0703620, 7.65064240;");
+        assert_ne!(run(&mut state, get), first);
+        assert_eq!(global.read().unwrap().result_cache.entries.lock().unwrap().hits, 2);
+    }
+}
@@ -0,0 +1,93 @@
+/// Store catalog
+///
+/// A store only had a dtf file once it was flushed, so a store created
+/// but still empty when the server stopped was gone after the restart and
+/// the collectors inserting into it got errors until they created it again.
+/// Every created store is now kept in `[dtf_folder]/catalog`, one
+/// `store <TAB> file name` line each, `<TAB> memory` added for the stores
+/// created with `CREATE [db] MEMORY`. A later line for the same store wins.
+///
+/// The file name is the one of the dtf file the store is flushed to, so
+/// every connection flushes a store to the same file.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use utils;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    /// name of the dtf file in dtf_folder, without `.dtf`
+    pub fname: String,
+    /// rows are only kept in memory
+    pub memory: bool,
+}
+
+/// store name -> entry
+pub type Catalog = HashMap<String, Entry>;
+
+fn catalog_fname(dtf_folder: &str) -> String {
+    utils::join(dtf_folder, "catalog")
+}
+
+/// all stores kept in the folder
+pub fn read(dtf_folder: &str) -> Catalog {
+    let mut catalog = HashMap::new();
+    let file = match File::open(catalog_fname(dtf_folder)) {
+        Ok(f) => f,
+        Err(_) => return catalog
+    };
+    for line in BufReader::new(file).lines().filter_map(|line| line.ok()) {
+        let parts : Vec<&str> = line.split('\t').collect();
+        if parts.len() >= 2 && !parts[1].is_empty() {
+            let memory = parts.get(2) == Some(&"memory");
+            catalog.insert(parts[0].to_owned(), Entry { fname: parts[1].to_owned(), memory });
+        }
+    }
+    catalog
+}
+
+pub fn record(dtf_folder: &str, store_name: &str, entry: &Entry) -> Result<(), io::Error> {
+    utils::create_dir_if_not_exist(dtf_folder);
+    let mut file = OpenOptions::new().create(true).append(true).open(catalog_fname(dtf_folder))?;
+    if entry.memory {
+        writeln!(file, "{}\t{}\tmemory", store_name, entry.fname)
+    } else {
+        writeln!(file, "{}\t{}", store_name, entry.fname)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use state::tests::{connect, global, restart, run};
+
+    #[test]
+    fn should_keep_the_last_line_of_a_store() {
+        let dtf_folder = global().read().unwrap().settings.dtf_folder.clone();
+        assert!(read(&dtf_folder).is_empty());
+        let entry = Entry { fname: "a--btc_usdt".to_owned(), memory: false };
+        record(&dtf_folder, "btc_usdt", &entry).unwrap();
+        record(&dtf_folder, "scratch", &Entry { fname: "b--scratch".to_owned(), memory: true }).unwrap();
+        record(&dtf_folder, "btc_usdt", &Entry { fname: "c--btc_usdt".to_owned(), memory: false }).unwrap();
+        let catalog = read(&dtf_folder);
+        assert_eq!(catalog.len(), 2);
+        assert_eq!(catalog["btc_usdt"].fname, "c--btc_usdt");
+        assert!(catalog["scratch"].memory);
+    }
+
+    #[test]
+    fn should_keep_empty_stores_across_a_restart() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        let fname = state.store["btc_usdt"].fname.clone();
+        // another connection flushes the store to the same file
+        assert_eq!(connect(&global).store["btc_usdt"].fname, fname);
+
+        let global = restart(&global);
+        let mut state = connect(&global);
+        assert_eq!(run(&mut state, "EXISTS btc_usdt"), "1\n");
+        assert_eq!(state.store["btc_usdt"].fname, fname);
+    }
+}
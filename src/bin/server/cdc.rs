@@ -0,0 +1,211 @@
+/// Change data capture
+///
+/// `SUBSCRIBE __cdc__ [FROM OFFSET n]` streams the changes to the stores of
+/// the server, for indexers and data catalogs mirroring it, in frames of
+/// `offset,json` lines:
+///
+///     1,{"event": "create", "store": "bnc_btc_eth", "ts": 1505177459658}
+///     2,{"event": "flush", "store": "bnc_btc_eth", "epoch": 1, "count": 1000, "ts": 1505177460012}
+///     3,{"event": "delete", "store": "bnc_btc_eth", "from": 1505177459000, "to": 1505177459999, "ts": ...}
+///     4,{"event": "compact", "store": "bnc_btc_eth", "removed": 12, "ts": ...}
+///     5,{"event": "prune", "store": "bnc_btc_eth", "before": 1505091060000, "removed": 1000, "ts": ...}
+///     6,{"event": "upload", "store": "bnc_btc_eth", "added": 500, "ts": ...}
+///
+/// `count` is the rows in the dtf file at the flush epoch, ts the time of the
+/// event in ms. Events have offsets of their own, the last `--replay_window`
+/// are kept in `[dtf_folder]/cdc`, one `offset <TAB> json` line each, so a
+/// subscriber resumes after a restart of the server too.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::cmp;
+use std::time::{SystemTime, UNIX_EPOCH};
+use utils;
+
+/// name subscribed to for the events
+pub static STORE : &str = "__cdc__";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Create { store: String },
+    /// a flush recorded `epoch`, the file then held `count` rows
+    Flush { store: String, epoch: u32, count: u64 },
+    /// rows within [from, to] were deleted, see `DELETE`
+    Delete { store: String, from: u64, to: u64 },
+    Compact { store: String, removed: u64 },
+    /// rows before `before` were pruned by the retention worker
+    Prune { store: String, before: u64, removed: u64 },
+    /// rows of a dtf file were merged into the store, see `UPLOAD`
+    Upload { store: String, added: u64 },
+}
+
+impl Event {
+    pub fn to_json(&self, ts: u64) -> String {
+        match *self {
+            Event::Create { ref store } =>
+                format!(r#"{{"event": "create", "store": "{}", "ts": {}}}"#, store, ts),
+            Event::Flush { ref store, epoch, count } =>
+                format!(r#"{{"event": "flush", "store": "{}", "epoch": {}, "count": {}, "ts": {}}}"#, store, epoch, count, ts),
+            Event::Delete { ref store, from, to } =>
+                format!(r#"{{"event": "delete", "store": "{}", "from": {}, "to": {}, "ts": {}}}"#, store, from, to, ts),
+            Event::Compact { ref store, removed } =>
+                format!(r#"{{"event": "compact", "store": "{}", "removed": {}, "ts": {}}}"#, store, removed, ts),
+            Event::Prune { ref store, before, removed } =>
+                format!(r#"{{"event": "prune", "store": "{}", "before": {}, "removed": {}, "ts": {}}}"#, store, before, removed, ts),
+            Event::Upload { ref store, added } =>
+                format!(r#"{{"event": "upload", "store": "{}", "added": {}, "ts": {}}}"#, store, added, ts),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Cdc {
+    dtf_folder: String,
+    fname: String,
+    /// (offset, json), oldest first
+    events: VecDeque<(u64, String)>,
+    window: usize,
+    /// lines in the file, it is rewritten once it holds two windows
+    on_disk: usize,
+    /// offset of the last event
+    pub offset: u64,
+}
+
+fn cdc_fname(dtf_folder: &str) -> String {
+    utils::join(dtf_folder, "cdc")
+}
+
+impl Cdc {
+    /// Open the events kept before a restart
+    pub fn open(dtf_folder: &str, window: usize) -> Cdc {
+        let window = cmp::max(window, 1);
+        let fname = cdc_fname(dtf_folder);
+        let mut events = VecDeque::new();
+        let mut on_disk = 0;
+        if let Ok(file) = File::open(&fname) {
+            for line in BufReader::new(file).lines().filter_map(|line| line.ok()) {
+                let mut parts = line.splitn(2, '\t');
+                // a torn last line is dropped
+                let event = match (parts.next().and_then(|offset| offset.parse().ok()), parts.next()) {
+                    (Some(offset), Some(json)) if json.ends_with('}') => (offset, json.to_owned()),
+                    _ => continue
+                };
+                if events.len() == window {
+                    events.pop_front();
+                }
+                events.push_back(event);
+                on_disk += 1;
+            }
+        }
+        let offset = events.back().map_or(0, |&(offset, _)| offset);
+        Cdc { dtf_folder: dtf_folder.to_owned(), fname, events, window, on_disk, offset }
+    }
+
+    pub fn push(&mut self, event: &Event) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let ts = now.as_secs() * 1000 + u64::from(now.subsec_nanos()) / 1_000_000;
+        self.offset += 1;
+        if self.events.len() == self.window {
+            self.events.pop_front();
+        }
+        let json = event.to_json(ts);
+        let line = format!("{}\t{}\n", self.offset, json);
+        self.events.push_back((self.offset, json));
+
+        utils::create_dir_if_not_exist(&self.dtf_folder);
+        let written = OpenOptions::new().create(true).append(true).open(&self.fname)
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+        if let Err(e) = written {
+            error!("Unable to write to {}: {:?}", self.fname, e);
+        }
+        self.on_disk += 1;
+        if self.on_disk >= 2 * self.window {
+            if let Err(e) = self.rewrite() {
+                error!("Unable to rewrite {}: {:?}", self.fname, e);
+            }
+        }
+    }
+
+    /// the file only keeps the events in the window
+    fn rewrite(&mut self) -> Result<(), io::Error> {
+        let tmp = format!("{}.tmp", self.fname);
+        {
+            let mut wtr = BufWriter::new(File::create(&tmp)?);
+            for &(offset, ref json) in self.events.iter() {
+                writeln!(wtr, "{}\t{}", offset, json)?;
+            }
+            wtr.flush()?;
+        }
+        utils::replace_file(&tmp, &self.fname)?;
+        self.on_disk = self.events.len();
+        Ok(())
+    }
+
+    /// events up to this offset are no longer kept
+    pub fn gap_upto(&self) -> u64 {
+        self.events.front().map_or(self.offset, |&(offset, _)| offset - 1)
+    }
+
+    /// events after `offset`
+    pub fn since(&self, offset: u64) -> Vec<(u64, String)> {
+        let n = self.events.iter().rev().take_while(|&&(o, _)| o > offset).count();
+        self.events.iter().skip(self.events.len() - n).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use handler::{self, ReturnType};
+    use serde_json::{self, Value};
+    use state::tests::{add, connect, global, restart, run, run_err};
+
+    fn events(since: &[(u64, String)]) -> Vec<(u64, String)> {
+        since.iter()
+            .map(|&(offset, ref json)| (offset, serde_json::from_str::<Value>(json).unwrap()["event"].as_str().unwrap().to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn should_stream_the_changes_to_the_stores() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "USE btc_usdt");
+        add(&mut state, 0);
+        add(&mut state, 1);
+        run(&mut state, "FLUSH");
+        run(&mut state, "DELETE FROM btc_usdt WHERE ts BETWEEN 1505177459 AND 1505177459");
+        run(&mut state, "COMPACT btc_usdt");
+        let since = state.cdc_since(0);
+        assert_eq!(events(&since), vec![
+            (1, "create".to_owned()), (2, "flush".to_owned()), (3, "delete".to_owned()), (4, "compact".to_owned())]);
+        let flush : Value = serde_json::from_str(&since[1].1).unwrap();
+        assert_eq!((&flush["store"], &flush["epoch"], &flush["count"]), (&Value::from("btc_usdt"), &Value::from(1), &Value::from(2)));
+
+        match handler::gen_response("SUBSCRIBE __cdc__ FROM OFFSET 2", &mut state) {
+            ReturnType::Cdc(offset) => assert_eq!(offset, 2),
+            _ => panic!("SUBSCRIBE __cdc__ failed")
+        }
+        assert!(run_err(&mut state, "SUBSCRIBE __cdc__ FROM OFFSET 5").starts_with("Offset 5 is ahead"));
+        assert!(run_err(&mut state, "SUBSCRIBE __cdc__ WHERE is_trade = t").starts_with("SUBSCRIBE __cdc__ takes no WHERE."));
+    }
+
+    #[test]
+    fn should_keep_the_last_events_across_a_restart() {
+        let global = global();
+        global.write().unwrap().settings.replay_window = 3;
+        let global = restart(&global);
+        let mut state = connect(&global);
+        for store in &["a", "b", "c", "d", "e", "f", "g"] {
+            run(&mut state, &format!("CREATE {}", store));
+        }
+        let global = restart(&global);
+        let state = connect(&global);
+        assert_eq!(state.cdc_since(0).iter().map(|&(offset, _)| offset).collect::<Vec<_>>(), vec![5, 6, 7]);
+        assert_eq!(global.read().unwrap().cdc.gap_upto(), 4);
+        assert!(state.subscribe_cdc(Some(3)).unwrap_err().starts_with("Offset 3 is older than the replay window"));
+        // the file was rewritten to the window once it held two
+        assert_eq!(global.read().unwrap().cdc.on_disk, 4);
+    }
+}
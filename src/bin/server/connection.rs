@@ -0,0 +1,86 @@
+/// Connections and their running commands
+///
+/// Every session is registered with an id, `CONNECTIONS` lists them with the
+/// command each one is running. `CANCEL [id]` interrupts that command, so
+/// does a timeout: `TIMEOUT [ms]` for a connection, `--query_timeout` for
+/// all. Reads check for an interruption every CHECK_EVERY rows and between
+/// chunks, the client then gets `ERR: Query cancelled.` or `ERR: Query timed
+/// out.` instead of the response, and a `GET ... STREAM` ends early. Writes
+/// are never interrupted.
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Instant;
+
+/// rows read between two checks for an interruption
+pub const CHECK_EVERY : usize = 4096;
+
+/// longest command shown by CONNECTIONS, longer ones are cut
+const MAX_COMMAND_LEN : usize = 200;
+
+#[derive(Debug)]
+pub struct Connection {
+    pub id: u64,
+    pub peer: String,
+    cancelled: AtomicBool,
+    /// version of the protocol agreed on with `HELLO`, see `hello`
+    protocol: AtomicU32,
+    /// command being run and since when
+    running: Mutex<Option<(String, Instant)>>,
+}
+
+impl Connection {
+    pub fn new(id: u64, peer: &str) -> Connection {
+        Connection {
+            id,
+            peer: peer.to_owned(),
+            cancelled: AtomicBool::new(false),
+            protocol: AtomicU32::new(1),
+            running: Mutex::new(None),
+        }
+    }
+
+    pub fn start(&self, command: &str) {
+        self.cancelled.store(false, Ordering::SeqCst);
+        *self.running.lock().unwrap() = Some((command.chars().take(MAX_COMMAND_LEN).collect(), Instant::now()));
+    }
+
+    pub fn finish(&self) {
+        *self.running.lock().unwrap() = None;
+    }
+
+    /// interrupt the running command, false if there is none
+    pub fn cancel(&self) -> bool {
+        let running = self.running.lock().unwrap();
+        if running.is_some() {
+            self.cancelled.store(true, Ordering::SeqCst);
+        }
+        running.is_some()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn protocol(&self) -> u32 {
+        self.protocol.load(Ordering::SeqCst)
+    }
+
+    pub fn set_protocol(&self, protocol: u32) {
+        self.protocol.store(protocol, Ordering::SeqCst);
+    }
+
+    /// {"id": 3, "peer": "10.0.0.2:51234", "protocol": 1, "command": "GET ALL AS JSON", "running_ms": 1520}
+    pub fn to_json(&self) -> String {
+        let (command, running_ms) = match *self.running.lock().unwrap() {
+            Some((ref command, started)) => {
+                let elapsed = started.elapsed();
+                let ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_nanos() / 1_000_000);
+                (format!("\"{}\"", command.replace('\\', "\\\\").replace('"', "\\\"")), ms.to_string())
+            }
+            None => ("null".to_owned(), "null".to_owned())
+        };
+        format!(r#"{{"id": {}, "peer": "{}", "protocol": {}, "command": {}, "running_ms": {}}}"#,
+            self.id, self.peer, self.protocol(), command, running_ms)
+    }
+}
@@ -0,0 +1,64 @@
+/// Corrections
+///
+/// `ADD CORRECTION [row]` supersedes the row of the same (ts, seq) without
+/// touching the dtf file. Corrections are applied to every read until
+/// COMPACT rewrites the file with them.
+///
+/// Corrections are recorded next to the dtf file in `[fname].dtf.corrections`,
+/// one `ts <TAB> seq <TAB> is_trade <TAB> is_bid <TAB> price <TAB> size` line
+/// each. A later correction of the same row wins.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use dtf::Update;
+
+/// (ts, seq) -> corrected row
+pub type Corrections = HashMap<(u64, u32), Update>;
+
+fn corrections_fname(dtf_fname: &str) -> String {
+    format!("{}.corrections", dtf_fname)
+}
+
+/// all corrections of a dtf file
+pub fn read(dtf_fname: &str) -> Corrections {
+    let mut corrections = HashMap::new();
+    let file = match File::open(corrections_fname(dtf_fname)) {
+        Ok(f) => f,
+        Err(_) => return corrections
+    };
+    let rows = BufReader::new(file).lines()
+        .filter_map(|line| line.ok())
+        .filter_map(|line| {
+            let parts : Vec<&str> = line.split('\t').collect();
+            if parts.len() != 6 { return None; }
+            Some(Update {
+                ts: parts[0].parse().ok()?,
+                seq: parts[1].parse().ok()?,
+                is_trade: parts[2].parse().ok()?,
+                is_bid: parts[3].parse().ok()?,
+                price: parts[4].parse().ok()?,
+                size: parts[5].parse().ok()?,
+                flags: 0,
+            })
+        });
+    for up in rows {
+        corrections.insert((up.ts, up.seq), up);
+    }
+    corrections
+}
+
+pub fn record(dtf_fname: &str, up: &Update) -> Result<(), io::Error> {
+    let mut file = OpenOptions::new().create(true).append(true).open(corrections_fname(dtf_fname))?;
+    writeln!(file, "{}\t{}\t{}\t{}\t{}\t{}", up.ts, up.seq, up.is_trade, up.is_bid, up.price, up.size)
+}
+
+/// remove every correction, once they are merged into the file
+pub fn clear(dtf_fname: &str) -> Result<(), io::Error> {
+    let fname = corrections_fname(dtf_fname);
+    if Path::new(&fname).exists() {
+        fs::remove_file(fname)?;
+    }
+    Ok(())
+}
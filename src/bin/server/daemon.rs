@@ -0,0 +1,126 @@
+/// Daemon mode
+///
+/// With `--daemon` the server tells its service manager, e.g. systemd, when
+/// it can accept ticks: after the socket is bound and the startup integrity
+/// scan is done, not when the process starts.
+///
+///     [Service]
+///     Type=notify
+///     ExecStart=/usr/bin/tectonic-server --daemon --pid_file /run/tectonic.pid -f /var/lib/tectonic
+///
+/// With a matching `.socket` unit, the server listens on the sockets passed by
+/// the service manager (`LISTEN_FDS`), in the order of `--listen`, instead of
+/// binding its own, so clients connecting during a restart wait instead of
+/// being refused.
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::TcpListener;
+use std::process;
+use settings::Settings;
+
+/// first file descriptor passed by socket activation
+#[cfg(unix)]
+const LISTEN_FDS_START : i32 = 3;
+
+/// the listening sockets passed by the service manager, if any
+#[cfg(unix)]
+pub fn activated_listeners() -> Vec<TcpListener> {
+    use std::os::unix::io::FromRawFd;
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+    let fds = env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse::<i32>().ok()).unwrap_or(0);
+    // the variables are meant for this process, not its children
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if pid != Some(process::id()) {
+        return Vec::new();
+    }
+    (LISTEN_FDS_START..(LISTEN_FDS_START + fds))
+        .map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn activated_listeners() -> Vec<TcpListener> {
+    Vec::new()
+}
+
+/// send `state`, e.g. `READY=1`, to the service manager, see sd_notify(3)
+#[cfg(unix)]
+fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+    let socket = match env::var("NOTIFY_SOCKET") {
+        Ok(socket) => socket,
+        Err(_) => {
+            warn!("NOTIFY_SOCKET is not set, not started by a service manager?");
+            return;
+        }
+    };
+    if socket.starts_with('@') {
+        error!("Unable to notify the service manager: abstract socket `{}` is not supported", socket);
+        return;
+    }
+    if let Err(e) = UnixDatagram::unbound().and_then(|s| s.send_to(state.as_bytes(), &socket)) {
+        error!("Unable to notify the service manager: {}", e);
+    }
+}
+
+#[cfg(not(unix))]
+fn notify(_state: &str) {
+    warn!("--daemon only notifies service managers on Unix");
+}
+
+fn write_pid_file(fname: &str) -> Result<(), io::Error> {
+    let mut file = File::create(fname)?;
+    writeln!(file, "{}", process::id())
+}
+
+/// Called once the server can accept ticks, writes `--pid_file` and
+/// notifies the service manager with `--daemon`
+pub fn ready(settings: &Settings, addrs: &str) {
+    if let Some(ref fname) = settings.pid_file {
+        if let Err(e) = write_pid_file(fname) {
+            error!("Unable to write the pid file {}: {}", fname, e);
+        }
+    }
+    if settings.daemon {
+        notify(&format!("READY=1\nMAINPID={}\nSTATUS=Accepting ticks on {}", process::id(), addrs));
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::net::UnixDatagram;
+    use state::tests::global;
+
+    #[test]
+    fn should_write_the_pid_file_and_notify_readiness() {
+        let mut settings = global().read().unwrap().settings.clone();
+        let socket_fname = format!("{}/notify.sock", settings.dtf_folder);
+        let pid_fname = format!("{}/tectonic.pid", settings.dtf_folder);
+        let socket = UnixDatagram::bind(&socket_fname).unwrap();
+        env::set_var("NOTIFY_SOCKET", &socket_fname);
+        settings.daemon = true;
+        settings.pid_file = Some(pid_fname.clone());
+
+        ready(&settings, "0.0.0.0:9001");
+        env::remove_var("NOTIFY_SOCKET");
+        assert_eq!(fs::read_to_string(&pid_fname).unwrap(), format!("{}\n", process::id()));
+        let mut buf = [0; 256];
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], format!("READY=1\nMAINPID={}\nSTATUS=Accepting ticks on 0.0.0.0:9001", process::id()).as_bytes());
+    }
+
+    #[test]
+    fn should_ignore_sockets_passed_to_another_process() {
+        env::set_var("LISTEN_PID", (process::id() + 1).to_string());
+        env::set_var("LISTEN_FDS", "1");
+        assert!(activated_listeners().is_empty());
+        assert!(env::var("LISTEN_PID").is_err());
+        assert!(env::var("LISTEN_FDS").is_err());
+    }
+}
@@ -0,0 +1,312 @@
+/// Virtual stores
+///
+/// `CREATE VIRTUAL [db] AS [expression] [INTERVAL 1s] [MATERIALIZED]` derives
+/// a store from the rows inserted into other stores, e.g. the spread between
+/// two markets resampled to 1s:
+///
+///     CREATE VIRTUAL btc_spread AS mid(bnc_btc_usdt) - mid(okx_btc_usdt) INTERVAL 1s
+///
+/// An expression combines numbers and the `mid`, `bid`, `ask` (best price of
+/// the book built from the quotes of a store, size 0 removes a level) and
+/// `last` (price of the last trade) of stores with `+ - * /` and parentheses.
+///
+/// Every row inserted into a source updates the value. Without INTERVAL a
+/// row `ts, n, f, f, value, 0` is added each time the value changes, with it
+/// one row per interval with the value at its end, added once a source row
+/// of a later interval comes in. Rows are added like inserted ones, so the
+/// store is read with `GET` and followed with `SUBSCRIBE`. A virtual store is
+/// kept in memory like a MEMORY store, a MATERIALIZED one is flushed to its
+/// dtf file like any store. Rows of the sources in memory are folded in when
+/// the store is created, after a restart it continues from the next rows.
+///
+/// Definitions are kept in `[dtf_folder]/virtual`, one `db <TAB> expression
+/// <TAB> interval in ms, 0 for none <TAB> materialized` line each.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use dtf::Update;
+use parser;
+use utils;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Mid,
+    Bid,
+    Ask,
+    Last,
+}
+
+impl Field {
+    pub fn parse(name: &str) -> Option<Field> {
+        match name {
+            "mid" => Some(Field::Mid),
+            "bid" => Some(Field::Bid),
+            "ask" => Some(Field::Ask),
+            "last" => Some(Field::Last),
+            _ => None
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Field::Mid => "mid",
+            Field::Bid => "bid",
+            Field::Ask => "ask",
+            Field::Last => "last",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl Op {
+    fn symbol(self) -> &'static str {
+        match self {
+            Op::Add => "+",
+            Op::Sub => "-",
+            Op::Mul => "*",
+            Op::Div => "/",
+        }
+    }
+
+    fn precedence(self) -> u8 {
+        match self {
+            Op::Add | Op::Sub => 0,
+            Op::Mul | Op::Div => 1,
+        }
+    }
+}
+
+/// An expression over stores, see `parser::parse_expr`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Num(f32),
+    Store(Field, String),
+    Neg(Box<Expr>),
+    Bin(Box<Expr>, Op, Box<Expr>),
+}
+
+impl Expr {
+    /// the stores it reads, each once
+    pub fn stores(&self) -> Vec<String> {
+        let mut stores = Vec::new();
+        self.collect_stores(&mut stores);
+        stores
+    }
+
+    fn collect_stores(&self, stores: &mut Vec<String>) {
+        match *self {
+            Expr::Num(_) => (),
+            Expr::Store(_, ref store_name) => if !stores.contains(store_name) {
+                stores.push(store_name.clone());
+            },
+            Expr::Neg(ref expr) => expr.collect_stores(stores),
+            Expr::Bin(ref lhs, _, ref rhs) => {
+                lhs.collect_stores(stores);
+                rhs.collect_stores(stores);
+            }
+        }
+    }
+
+    /// rename the stores it reads, e.g. to follow aliases
+    pub fn rename<F: Fn(&str) -> String>(&mut self, rename: &F) {
+        match *self {
+            Expr::Num(_) => (),
+            Expr::Store(_, ref mut store_name) => *store_name = rename(store_name),
+            Expr::Neg(ref mut expr) => expr.rename(rename),
+            Expr::Bin(ref mut lhs, _, ref mut rhs) => {
+                lhs.rename(rename);
+                rhs.rename(rename);
+            }
+        }
+    }
+
+    /// None while a value it reads is unknown, or if it is not a number
+    pub fn eval<F: Fn(Field, &str) -> Option<f32>>(&self, value: &F) -> Option<f32> {
+        let ret = match *self {
+            Expr::Num(n) => n,
+            Expr::Store(field, ref store_name) => value(field, store_name)?,
+            Expr::Neg(ref expr) => -expr.eval(value)?,
+            Expr::Bin(ref lhs, op, ref rhs) => {
+                let (lhs, rhs) = (lhs.eval(value)?, rhs.eval(value)?);
+                match op {
+                    Op::Add => lhs + rhs,
+                    Op::Sub => lhs - rhs,
+                    Op::Mul => lhs * rhs,
+                    Op::Div => lhs / rhs,
+                }
+            }
+        };
+        if ret.is_finite() { Some(ret) } else { None }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expr::Num(n) => write!(f, "{}", n),
+            Expr::Store(field, ref store_name) => write!(f, "{}({})", field.name(), store_name),
+            Expr::Neg(ref expr) => match **expr {
+                Expr::Bin(..) => write!(f, "-({})", expr),
+                _ => write!(f, "-{}", expr),
+            },
+            Expr::Bin(ref lhs, op, ref rhs) => {
+                // parentheses only where the order of operations needs them
+                let lhs_parens = match **lhs {
+                    Expr::Bin(_, lhs_op, _) => lhs_op.precedence() < op.precedence(),
+                    _ => false
+                };
+                let rhs_parens = match **rhs {
+                    Expr::Bin(_, rhs_op, _) => rhs_op.precedence() <= op.precedence(),
+                    _ => false
+                };
+                if lhs_parens { write!(f, "({})", lhs)? } else { write!(f, "{}", lhs)? }
+                write!(f, " {} ", op.symbol())?;
+                if rhs_parens { write!(f, "({})", rhs) } else { write!(f, "{}", rhs) }
+            }
+        }
+    }
+}
+
+/// price (as bits, which sort like positive prices) -> size
+type BookSide = BTreeMap<u32, f32>;
+
+/// What the expression reads of a source, also the book of `TOPN ... BY spread`
+#[derive(Debug, Default)]
+pub struct Top {
+    bids: BookSide,
+    asks: BookSide,
+    last: Option<f32>,
+}
+
+impl Top {
+    pub fn add(&mut self, up: &Update) {
+        if up.is_trade {
+            self.last = Some(up.price);
+            return;
+        }
+        let side = if up.is_bid { &mut self.bids } else { &mut self.asks };
+        if up.size == 0. {
+            side.remove(&up.price.to_bits());
+        } else {
+            side.insert(up.price.to_bits(), up.size);
+        }
+    }
+
+    pub fn get(&self, field: Field) -> Option<f32> {
+        let bid = || self.bids.keys().next_back().map(|&price| f32::from_bits(price));
+        let ask = || self.asks.keys().next().map(|&price| f32::from_bits(price));
+        match field {
+            Field::Bid => bid(),
+            Field::Ask => ask(),
+            Field::Mid => Some((bid()? + ask()?) / 2.),
+            Field::Last => self.last,
+        }
+    }
+}
+
+/// A virtual store and what it has seen of its sources
+#[derive(Debug)]
+pub struct Derived {
+    pub expr: Expr,
+    /// ms, None for a row on every change
+    pub interval: Option<u64>,
+    pub materialized: bool,
+    tops: HashMap<String, Top>,
+    /// start of the current interval and the value so far
+    pending: Option<(u64, f32)>,
+    /// value of the last row
+    last: Option<f32>,
+    rows: u32,
+}
+
+impl Derived {
+    pub fn new(expr: Expr, interval: Option<u64>, materialized: bool) -> Derived {
+        let tops = expr.stores().into_iter().map(|store_name| (store_name, Top::default())).collect();
+        Derived { expr, interval, materialized, tops, pending: None, last: None, rows: 0 }
+    }
+
+    /// fold in a row inserted into `source`, returns the row to add, if any
+    pub fn add(&mut self, source: &str, up: &Update) -> Option<Update> {
+        match self.tops.get_mut(source) {
+            Some(top) => top.add(up),
+            None => return None
+        }
+        let value = {
+            let tops = &self.tops;
+            self.expr.eval(&|field, store_name| tops.get(store_name)?.get(field))
+        };
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return match value {
+                Some(value) if self.last != Some(value) => Some(self.row(up.ts, value)),
+                _ => None
+            }
+        };
+        let start = up.ts / interval * interval;
+        let mut ret = None;
+        if let Some((pending_start, pending_value)) = self.pending {
+            if start < pending_start {
+                // a late row changes the books but not the past
+                return None;
+            }
+            if start > pending_start {
+                ret = Some(self.row(pending_start, pending_value));
+                self.pending = None;
+            }
+        }
+        if let Some(value) = value {
+            self.pending = Some((start, value));
+        }
+        ret
+    }
+
+    fn row(&mut self, ts: u64, value: f32) -> Update {
+        self.last = Some(value);
+        self.rows = self.rows.wrapping_add(1);
+        Update { ts, seq: self.rows, is_trade: false, is_bid: false, price: value, size: 0., flags: 0 }
+    }
+}
+
+/// store name -> virtual store
+pub type Virtuals = HashMap<String, Derived>;
+
+fn virtual_fname(dtf_folder: &str) -> String {
+    utils::join(dtf_folder, "virtual")
+}
+
+/// all virtual stores kept in the folder
+pub fn read(dtf_folder: &str) -> Virtuals {
+    let mut virtuals = HashMap::new();
+    let file = match File::open(virtual_fname(dtf_folder)) {
+        Ok(f) => f,
+        Err(_) => return virtuals
+    };
+    for line in BufReader::new(file).lines().filter_map(|line| line.ok()) {
+        let parts : Vec<&str> = line.split('\t').collect();
+        if parts.len() != 4 {
+            continue;
+        }
+        let (expr, interval) = match (parser::parse_expr(parts[1]), parts[2].parse::<u64>()) {
+            (Some(expr), Ok(interval)) => (expr, interval),
+            _ => continue
+        };
+        let interval = if interval == 0 { None } else { Some(interval) };
+        virtuals.insert(parts[0].to_owned(), Derived::new(expr, interval, parts[3] == "materialized"));
+    }
+    virtuals
+}
+
+pub fn record(dtf_folder: &str, store_name: &str, derived: &Derived) -> Result<(), io::Error> {
+    let mut file = OpenOptions::new().create(true).append(true).open(virtual_fname(dtf_folder))?;
+    writeln!(file, "{}\t{}\t{}\t{}", store_name, derived.expr, derived.interval.unwrap_or(0),
+        if derived.materialized { "materialized" } else { "memory" })
+}
@@ -0,0 +1,222 @@
+/// Direct IO
+///
+/// A flush or compaction of a busy store writes megabytes that live queries
+/// won't read soon, and through the page cache these pages evict the files
+/// the queries do read. With `--direct_io MB`, writes of at least MB open the
+/// file with O_DIRECT and go straight to the drive. O_DIRECT only writes
+/// whole blocks at block offsets, so the bytes are copied to an aligned
+/// buffer padded to a block and the file is truncated to its length
+/// afterwards. An append rewrites the partial block at the end of the file
+/// along with the new batches. The zone map, stats and mirror read the new
+/// rows back, so `evict` drops them from the page cache once they are done.
+/// Smaller writes, filesystems refusing O_DIRECT (e.g. tmpfs before Linux
+/// 6.6) and other platforms get the buffered writes of `dtf::encode` and
+/// `dtf::append`. Errors once the file is opened are returned, the flush or
+/// compaction then leaves the rows where they were.
+
+use std::io;
+use dtf::{self, Update};
+use dtf::kernels::ROW_LEN;
+
+/// whether writing `rows` rows is large enough to bypass the page cache
+fn is_large(rows: usize, min_bytes: u64) -> bool {
+    min_bytes > 0 && (rows * ROW_LEN) as u64 >= min_bytes
+}
+
+/// `dtf::encode`, with O_DIRECT from `min_bytes`
+pub fn encode(fname: &str, symbol: &str, ups: &[Update], min_bytes: u64) -> io::Result<()> {
+    if is_large(ups.len(), min_bytes) {
+        match linux::encode(fname, symbol, ups)? {
+            Ok(()) => return Ok(()),
+            Err(e) => warn!("Unable to write {} with O_DIRECT ({}), writing it buffered", fname, e)
+        }
+    }
+    dtf::encode(fname, symbol, ups);
+    Ok(())
+}
+
+/// `dtf::append`, with O_DIRECT from `min_bytes`
+pub fn append(fname: &str, ups: &[Update], min_bytes: u64) -> io::Result<Vec<Update>> {
+    if is_large(ups.len(), min_bytes) {
+        match linux::append(fname, ups)? {
+            Ok(appended) => return Ok(appended),
+            Err(e) => warn!("Unable to append to {} with O_DIRECT ({}), appending buffered", fname, e)
+        }
+    }
+    Ok(dtf::append(fname, ups))
+}
+
+/// drop the bytes of `fname` from `offset` from the page cache after a write of `rows` rows
+pub fn evict(fname: &str, offset: u64, rows: usize, min_bytes: u64) {
+    if is_large(rows, min_bytes) {
+        if let Err(e) = linux::evict(fname, offset) {
+            warn!("Unable to drop {} from the page cache: {}", fname, e);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read};
+    use std::os::unix::fs::{FileExt, OpenOptionsExt};
+    use std::os::unix::io::AsRawFd;
+    use dtf::{self, Update};
+    use libc;
+
+    /// alignment of O_DIRECT offsets, lengths and buffers, a multiple of the
+    /// logical block size of any drive
+    const BLOCK : u64 = 4096;
+
+    fn open_direct(fname: &str, create: bool) -> io::Result<File> {
+        OpenOptions::new()
+            .write(true)
+            .create(create)
+            .truncate(create)
+            .custom_flags(libc::O_DIRECT)
+            .open(fname)
+    }
+
+    /// Write `bytes` at `offset`, a multiple of BLOCK, of `file` and make
+    /// them the end of the file
+    pub(super) fn write_blocks(file: &File, bytes: &[u8], offset: u64) -> io::Result<()> {
+        let len = ((bytes.len() as u64 + BLOCK - 1) / BLOCK * BLOCK) as usize;
+        let mut buf = vec![0u8; len + BLOCK as usize];
+        let start = buf.as_ptr().align_offset(BLOCK as usize);
+        let aligned = &mut buf[start..(start + len)];
+        aligned[..bytes.len()].copy_from_slice(bytes);
+        file.write_all_at(aligned, offset)?;
+        file.set_len(offset + bytes.len() as u64)
+    }
+
+    /// The inner Err if the file can't be opened with O_DIRECT, nothing was written then
+    pub fn encode(fname: &str, symbol: &str, ups: &[Update]) -> io::Result<io::Result<()>> {
+        dtf::remove_batch_index(fname);
+        let file = match open_direct(fname, true) {
+            Ok(file) => file,
+            Err(e) => return Ok(Err(e))
+        };
+        write_blocks(&file, &dtf::encode_buffer(symbol, ups), 0)?;
+        dtf::batch_index_written(fname, ups);
+        Ok(Ok(()))
+    }
+
+    /// The inner Err if the file can't be opened with O_DIRECT, nothing was written then
+    pub fn append(fname: &str, ups: &[Update]) -> io::Result<io::Result<Vec<Update>>> {
+        let file = File::open(fname)?;
+        let mut header = Vec::new();
+        (&file).take(dtf::MAIN_OFFSET).read_to_end(&mut header)?;
+        let plan = match dtf::plan_append(&header, ups)? {
+            Some(plan) => plan,
+            None => return Ok(Ok(Vec::new()))
+        };
+        let old_len = file.metadata()?.len();
+        let end = if plan.at_main_offset { dtf::MAIN_OFFSET } else { old_len };
+        let wtr = match open_direct(fname, false) {
+            Ok(wtr) => wtr,
+            Err(e) => return Ok(Err(e))
+        };
+
+        // the block the file ends in is written again, followed by the new batches
+        let start = end / BLOCK * BLOCK;
+        let mut bytes = vec![0u8; (end - start) as usize];
+        let present = (old_len.min(end) - start) as usize;
+        file.read_exact_at(&mut bytes[..present], start)?;
+        if start == 0 {
+            bytes[..plan.header.len()].copy_from_slice(&plan.header);
+        }
+        bytes.extend_from_slice(&plan.batches);
+        write_blocks(&wtr, &bytes, start)?;
+
+        // the header is in a block of its own, written after the batches
+        if start > 0 {
+            OpenOptions::new().write(true).open(fname)?.write_all_at(&plan.header, 0)?;
+        }
+        dtf::batch_index_appended(fname, old_len, &plan);
+        Ok(Ok(plan.ups))
+    }
+
+    pub fn evict(fname: &str, offset: u64) -> io::Result<()> {
+        let file = File::open(fname)?;
+        match unsafe { libc::posix_fadvise(file.as_raw_fd(), offset as libc::off_t, 0, libc::POSIX_FADV_DONTNEED) } {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(errno))
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod linux {
+    use std::io;
+    use dtf::Update;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Other, "O_DIRECT is Linux only")
+    }
+
+    pub fn encode(_fname: &str, _symbol: &str, _ups: &[Update]) -> io::Result<io::Result<()>> {
+        Ok(Err(unsupported()))
+    }
+
+    pub fn append(_fname: &str, _ups: &[Update]) -> io::Result<io::Result<Vec<Update>>> {
+        Ok(Err(unsupported()))
+    }
+
+    pub fn evict(_fname: &str, _offset: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use utils;
+    use uuid::Uuid;
+
+    fn fname() -> String {
+        format!("{}/tectonic_direct_{}.dtf", env::temp_dir().display(), Uuid::new_v4())
+    }
+
+    fn ups(from: u64, n: u64) -> Vec<Update> {
+        (from..(from + n)).map(|i| Update {
+            ts: 1505177459685 + i,
+            seq: 139010 + i as u32,
+            is_trade: i % 2 == 0,
+            is_bid: i % 3 == 0,
+            price: 0.0703620,
+            size: 7.65064240,
+            flags: 0,
+        }).collect()
+    }
+
+    #[test]
+    fn should_encode_and_append_large_writes() {
+        let fname = fname();
+        encode(&fname, "btc_usdt", &ups(0, 1000), 1).unwrap();
+        assert_eq!(append(&fname, &ups(1000, 1000), 1).unwrap().len(), 1000);
+        // an append of rows already in the file writes nothing
+        assert!(append(&fname, &ups(0, 10), 1).unwrap().is_empty());
+        assert_eq!(dtf::decode(&fname, None), ups(0, 2000));
+        utils::remove_file(&fname).unwrap();
+    }
+
+    #[test]
+    fn should_return_errors_instead_of_panicking() {
+        let fname = fname();
+        fs::write(&fname, b"not a dtf file").unwrap();
+        assert!(append(&fname, &ups(0, 1000), 1).is_err());
+        utils::remove_file(&fname).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn should_return_errors_of_block_writes() {
+        let fname = fname();
+        fs::write(&fname, b"").unwrap();
+        let file = fs::File::open(&fname).unwrap();
+        assert!(linux::write_blocks(&file, &[0u8; 10], 0).is_err());
+        utils::remove_file(&fname).unwrap();
+    }
+}
@@ -0,0 +1,243 @@
+/// Storage engine
+///
+/// The catalog of stores, inserts, flushes and queries, without the TCP
+/// front-end. `server` is a thin wrapper that runs one `Session` per
+/// connection; a process embedding the engine, e.g. a collector, opens
+/// sessions of its own:
+///
+/// let engine = Engine::open(&settings, Extensions::new());
+/// let mut session = engine.session("collector");
+/// session.insert(up, "bnc_btc_eth")?;
+/// let rows = session.query("bnc_btc_eth", None)?;
+/// session.execute("FLUSH ALL");
+///
+/// Sessions of one engine share the stores, like the connections of a server.
+
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use dtf::Update;
+use extension::Extensions;
+use handler::{self, ReturnType};
+use latency::Latencies;
+use slowlog::{self, Entry, Profile};
+use connection::Connection;
+use parser;
+use partition;
+use plugins::run_plugins;
+use settings::Settings;
+use state::{SharedState, State};
+use utils;
+
+pub struct Engine {
+    global: Arc<RwLock<SharedState>>,
+}
+
+impl Engine {
+    /// Open the stores in `settings.dtf_folder` and start the background plugins
+    pub fn open(settings: &Settings, extensions: Extensions) -> Engine {
+        utils::create_dir_if_not_exist(&settings.dtf_folder);
+        partition::restore(&settings.dtf_folder);
+        let mut shared_state = SharedState::new(settings.clone());
+        shared_state.extensions = Arc::new(extensions);
+        let global = Arc::new(RwLock::new(shared_state));
+        run_plugins(global.clone());
+        Engine { global }
+    }
+
+    /// A session of `peer`, with its own current store and settings like `FORMAT`
+    pub fn session(&self, peer: &str) -> Session {
+        let mut state = State::new(&self.global);
+        state.connection = {
+            let mut wtr = self.global.write().unwrap();
+            wtr.last_connection_id += 1;
+            let connection = Arc::new(Connection::new(wtr.last_connection_id, peer));
+            wtr.connections.insert(connection.id, connection.clone());
+            connection
+        };
+        utils::init_dbs(&mut state);
+        state.use_default_store(peer);
+        Session { state, peer: peer.to_owned() }
+    }
+
+    pub fn global(&self) -> &Arc<RwLock<SharedState>> {
+        &self.global
+    }
+}
+
+pub struct Session {
+    pub state: State,
+    /// recorded with each command in the journal, see `--journal`
+    peer: String,
+}
+
+impl Session {
+    /// A session over the state of a connection, e.g. one of a test
+    #[cfg(test)]
+    pub fn new(state: State, peer: &str) -> Session {
+        Session { state, peer: peer.to_owned() }
+    }
+
+    /// Run one command of the text protocol
+    pub fn execute(&mut self, command: &str) -> ReturnType {
+        self.journal(command);
+        let command_type = Latencies::command_type(command, self.state.is_adding);
+        *self.state.profile.borrow_mut() = Profile::default();
+        let start = Instant::now();
+        self.state.start_command(command);
+        // every row of a BULKADD and its end are fenced, the primary may have lost the lease since it began
+        let refused = if self.state.is_adding {
+            let fenced = self.state.fenced(command);
+            if fenced.is_some() && command == "DDAKLUB" {
+                self.state.abort_bulkadd();
+            }
+            fenced
+        } else {
+            self.state.fenced(command).or_else(|| self.state.shed(command))
+        };
+        let resp = match refused {
+            Some(e) => ReturnType::Error(e),
+            None => match handler::gen_response(command, &mut self.state) {
+                // a read was interrupted, its partial response is never sent
+                ReturnType::String(_) | ReturnType::Bytes(_) if self.state.interruption().is_some() =>
+                    ReturnType::Error(self.state.interruption().unwrap().to_owned()),
+                resp => resp
+            }
+        };
+        self.record_timing(command, command_type, start.elapsed());
+        match resp {
+            // still running while the rows are sent
+            ReturnType::Stream(_) | ReturnType::Replay(_) | ReturnType::Subscribe(_) | ReturnType::Cdc(_) => (),
+            _ => self.state.connection.finish()
+        }
+        resp
+    }
+
+    /// Run `UPLOAD INTO [db] [bytes]` with the dtf file sent after it.
+    /// Only the command is journaled.
+    pub fn upload(&mut self, command: &str, file: &[u8]) -> ReturnType {
+        self.journal(command);
+        *self.state.profile.borrow_mut() = Profile::default();
+        let start = Instant::now();
+        let resp = self.upload_file(command, file);
+        self.record_timing(command, "UPLOAD", start.elapsed());
+        resp
+    }
+
+    fn upload_file(&mut self, command: &str, file: &[u8]) -> ReturnType {
+        if let Some(e) = self.state.fenced(command) {
+            return ReturnType::Error(e);
+        }
+        let store_name = match parser::parse_upload(command) {
+            Some((store_name, _)) => store_name,
+            None => return ReturnType::Error("Unable to parse UPLOAD.".to_owned())
+        };
+        let store_name = match self.state.new_store_name(&store_name) {
+            Ok(store_name) => store_name,
+            Err(e) => return ReturnType::Error(e)
+        };
+        match self.state.upload(&store_name, file) {
+            Ok(added) => ReturnType::String(format!("{}\n", added)),
+            Err(e) => ReturnType::Error(e)
+        }
+    }
+
+    /// record the latency of a command, and log it if it was slow
+    fn record_timing(&self, command: &str, command_type: &'static str, elapsed: Duration) {
+        let slow = {
+            let mut wtr = self.state.global.write().unwrap();
+            wtr.latencies.record(command_type, elapsed);
+            wtr.ingest_lag.record(command_type, elapsed);
+            if let Some(ref store_name) = self.state.profile.borrow().store {
+                wtr.record_read(store_name);
+            }
+            let threshold = wtr.settings.slowlog_threshold;
+            if threshold > 0 && elapsed >= Duration::from_millis(threshold) {
+                let entry = Entry::new(&self.peer, command, &self.state.current_store_name,
+                    &self.state.profile.borrow(), elapsed);
+                Some((wtr.slowlog.record(entry), wtr.settings.slowlog_file.clone()))
+            } else {
+                None
+            }
+        };
+        if let Some((line, fname)) = slow {
+            if let Err(e) = slowlog::append(&fname, &line) {
+                error!("Unable to write to the slow log {}: {}", fname, e);
+            }
+        }
+    }
+
+    fn journal(&self, command: &str) {
+        if let Some(ref journal) = self.state.global.read().unwrap().journal {
+            if let Err(e) = journal.record(&self.peer, command) {
+                error!("Unable to record to journal: {:?}", e);
+            }
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.state.global.write().unwrap().connections.remove(&self.state.connection.id);
+    }
+}
+
+// typed calls for embedding code, the server only uses `execute`
+#[allow(dead_code)]
+impl Session {
+    /// Insert a row into `store_name` like `ADD ... INTO`, returns the commit offset
+    pub fn insert(&mut self, up: Update, store_name: &str) -> Result<u64, String> {
+        let store_name = self.state.resolve(store_name);
+        if self.state.over_budget(&store_name) {
+            return Err(format!("Memory budget exceeded, `{}` cannot be flushed.", store_name));
+        }
+        self.state.ensure_store(&store_name)?;
+        self.state.insert(up, &store_name);
+        Ok(self.state.offset())
+    }
+
+    /// Rows of `store_name` in `range` (in seconds), in memory and on disk
+    pub fn query(&mut self, store_name: &str, range: Option<(u32, u32)>) -> Result<Vec<Update>, String> {
+        let store_name = self.state.resolve(store_name);
+        self.state.read_store(&store_name, range)
+    }
+
+    /// Write the rows of every store to disk
+    pub fn flush_all(&mut self) {
+        self.state.flushall();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use state::tests::global;
+
+    fn up(i: u64) -> Update {
+        Update::new(1_505_177_459_685 + i * 1000, 139_010 + i as u32, false, true, 0.070_362, 7.650_642)
+    }
+
+    #[test]
+    fn should_insert_and_query_without_the_front_end() {
+        let settings = global().read().unwrap().settings.clone();
+        let engine = Engine::open(&settings, Extensions::new());
+        let mut session = engine.session("collector");
+        assert_eq!(session.insert(up(0), "btc_usdt"), Err("No db named `btc_usdt`".to_owned()));
+        match session.execute("CREATE btc_usdt") {
+            ReturnType::String(_) => (),
+            _ => panic!("CREATE failed")
+        }
+        session.insert(up(0), "btc_usdt").unwrap();
+        session.insert(up(1), "btc_usdt").unwrap();
+
+        // the sessions of an engine share the stores
+        let mut other = engine.session("reader");
+        assert_eq!(other.query("btc_usdt", None).unwrap(), vec![up(0), up(1)]);
+        assert_eq!(other.query("btc_usdt", Some((1_505_177_460, 1_505_177_461))).unwrap(), vec![up(1)]);
+
+        // flushed rows are there once the engine is opened again
+        session.flush_all();
+        let engine = Engine::open(&settings, Extensions::new());
+        let mut session = engine.session("collector");
+        assert_eq!(session.query("btc_usdt", None).unwrap(), vec![up(0), up(1)]);
+    }
+}
@@ -0,0 +1,89 @@
+/// Flush epochs
+///
+/// Every flush of a store is tagged with an epoch, starting at 1. Since
+/// flushes only ever append to a dtf file, the store as it was at epoch n
+/// is the prefix of the file that existed after the nth flush.
+///
+/// Epochs are recorded next to the dtf file in `[fname].dtf.epochs`, one
+/// line per flush:
+///
+///     epoch <TAB> flush time (s) <TAB> file length (bytes) <TAB> row count
+///
+/// VACUUM removes the record. Anything that rewrites a dtf file instead of
+/// appending to it must vacuum, since the recorded prefixes no longer apply.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use dtf;
+
+#[derive(Debug, Clone)]
+pub struct Epoch {
+    pub epoch: u32,
+    pub flushed_at: u64,
+    pub len: u64,
+    pub count: u64,
+}
+
+impl Epoch {
+    pub fn to_json(&self) -> String {
+        format!(r#"{{"epoch": {}, "flushed_at": {}, "count": {}}}"#,
+            self.epoch, self.flushed_at, self.count)
+    }
+}
+
+fn epochs_fname(dtf_fname: &str) -> String {
+    format!("{}.epochs", dtf_fname)
+}
+
+/// all recorded epochs of a dtf file, oldest first
+pub fn read(dtf_fname: &str) -> Vec<Epoch> {
+    let file = match File::open(epochs_fname(dtf_fname)) {
+        Ok(f) => f,
+        Err(_) => return Vec::new()
+    };
+    BufReader::new(file).lines()
+        .filter_map(|line| line.ok())
+        .filter_map(|line| {
+            let parts : Vec<&str> = line.split('\t').collect();
+            if parts.len() != 4 { return None; }
+            Some(Epoch {
+                epoch: parts[0].parse().ok()?,
+                flushed_at: parts[1].parse().ok()?,
+                len: parts[2].parse().ok()?,
+                count: parts[3].parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+/// tag the current state of a freshly flushed dtf file with the next epoch
+pub fn record(dtf_fname: &str) -> Result<Epoch, io::Error> {
+    let epoch = read(dtf_fname).last().map(|e| e.epoch).unwrap_or(0) + 1;
+    let len = fs::metadata(dtf_fname)?.len();
+    let count = dtf::get_size(dtf_fname);
+    let flushed_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+
+    let mut file = OpenOptions::new().create(true).append(true).open(epochs_fname(dtf_fname))?;
+    writeln!(file, "{}\t{}\t{}\t{}", epoch, flushed_at, len, count)?;
+    Ok(Epoch { epoch, flushed_at, len, count })
+}
+
+/// the store as it was at `epoch`
+pub fn read_asof(dtf_fname: &str, epoch: u32) -> Option<Vec<dtf::Update>> {
+    let found = read(dtf_fname).into_iter().find(|e| e.epoch == epoch)?;
+    Some(dtf::decode_prefix(dtf_fname, found.len))
+}
+
+/// forget every epoch
+pub fn vacuum(dtf_fname: &str) -> Result<(), io::Error> {
+    let fname = epochs_fname(dtf_fname);
+    if Path::new(&fname).exists() {
+        fs::remove_file(fname)?;
+    }
+    Ok(())
+}
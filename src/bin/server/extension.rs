@@ -0,0 +1,129 @@
+/// Command extensions
+///
+/// Code embedding the server adds commands of its own, e.g. proprietary
+/// analytics, by implementing `Extension` and passing the registered
+/// extensions to `server::run_server`:
+///
+/// let mut extensions = Extensions::new();
+/// extensions.register(Box::new(Vwap));
+/// server::run_server(&host, &port, &settings, extensions);
+///
+/// A command is handed to the extension whose keyword is its first word,
+/// but only if no built-in command matched it, so an extension cannot
+/// change the meaning of a built-in command.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use state::State;
+
+pub trait Extension: Send + Sync {
+    /// first word of the commands handled, e.g. `VWAP`
+    fn keyword(&self) -> &str;
+
+    /// usage shown by HELP, e.g. `VWAP [db] [FROM ts TO ts]`
+    fn help(&self) -> &str;
+
+    /// response to `command` sent on the connection of `state`, or an error message
+    fn handle(&self, command: &str, state: &mut State) -> Result<String, String>;
+}
+
+#[derive(Default)]
+pub struct Extensions {
+    /// keyword -> extension
+    by_keyword: BTreeMap<String, Box<Extension>>,
+}
+
+impl Extensions {
+    pub fn new() -> Extensions {
+        Extensions { by_keyword: BTreeMap::new() }
+    }
+
+    /// add an extension, replacing the one registered for the same keyword
+    #[allow(dead_code)] // called by embedding code
+    pub fn register(&mut self, extension: Box<Extension>) {
+        let keyword = extension.keyword().to_owned();
+        if self.by_keyword.insert(keyword.clone(), extension).is_some() {
+            warn!("Replaced the extension of `{}`", keyword);
+        }
+    }
+
+    /// the extension handling `command`
+    pub fn find(&self, command: &str) -> Option<&Extension> {
+        let keyword = command.split(' ').next().unwrap_or("");
+        self.by_keyword.get(keyword).map(|extension| &**extension)
+    }
+
+    /// (keyword, usage) of the registered commands
+    pub fn usages(&self) -> Vec<(&str, &str)> {
+        self.by_keyword.iter()
+            .map(|(keyword, extension)| (keyword.as_str(), extension.help()))
+            .collect()
+    }
+
+    /// usage of the registered commands, one per line
+    pub fn help(&self) -> String {
+        self.by_keyword.values()
+            .map(|extension| format!("{}\n", extension.help()))
+            .collect()
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.by_keyword.keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use state::tests::{add, connect, global, run, run_err};
+
+    /// `COUNT [db]`: rows of a store
+    struct Count;
+
+    impl Extension for Count {
+        fn keyword(&self) -> &str { "COUNT" }
+        fn help(&self) -> &str { "COUNT [db]" }
+        fn handle(&self, command: &str, state: &mut State) -> Result<String, String> {
+            let store = command.split(' ').nth(1).ok_or("Missing db.")?;
+            if !state.exists(store) {
+                return Err(format!("No db named `{}`", store));
+            }
+            let count = state.global.read().unwrap().vec_store[store].1;
+            Ok(count.to_string())
+        }
+    }
+
+    /// `INFO`: shadowed by the built-in command
+    struct Info;
+
+    impl Extension for Info {
+        fn keyword(&self) -> &str { "INFO" }
+        fn help(&self) -> &str { "INFO" }
+        fn handle(&self, _command: &str, _state: &mut State) -> Result<String, String> {
+            Ok("shadowed".to_owned())
+        }
+    }
+
+    #[test]
+    fn should_run_commands_no_built_in_matched() {
+        let global = global();
+        let mut extensions = Extensions::new();
+        extensions.register(Box::new(Count));
+        extensions.register(Box::new(Info));
+        global.write().unwrap().extensions = Arc::new(extensions);
+
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "USE btc_usdt");
+        add(&mut state, 0);
+        add(&mut state, 1);
+        assert_eq!(run(&mut state, "COUNT btc_usdt"), "2\n");
+        assert!(run_err(&mut state, "COUNT nope").starts_with("No db named `nope`"));
+        assert!(run(&mut state, "INFO").starts_with("{"));
+        assert!(run_err(&mut state, "VWAP btc_usdt").starts_with("Unknown command."));
+        assert!(run(&mut state, "HELP").contains("\nCOUNT [db]\nINFO\n"));
+    }
+}
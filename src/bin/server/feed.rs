@@ -0,0 +1,161 @@
+/// Subscription feeds
+///
+/// From its first `SUBSCRIBE`, a store keeps the last `--replay_window` rows
+/// inserted into it with their commit offsets, so a subscriber that reconnects
+/// with `SUBSCRIBE [db] FROM OFFSET [n]` receives every row it missed.
+///
+/// The feed of a store is kept in `[dtf_folder]/feeds/[db]`, one
+///
+///     offset: u64 | ts: u64 | seq: u32 | flags: u8 | price: f32 | size: f32
+///
+/// record per row in big endian, and outlives restarts. The file is appended
+/// to through the shared `FileHandles`.
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use dtf::update::Update;
+use handles::FileHandles;
+use utils;
+
+/// bytes of a record
+static RECORD_LEN : usize = 29;
+
+#[derive(Debug)]
+pub struct Feed {
+    pub fname: String,
+    /// (offset, row), oldest first
+    rows: VecDeque<(u64, Update)>,
+    window: usize,
+    /// records in the file, it is rewritten once it holds two windows
+    on_disk: usize,
+    /// rows of the store up to this offset are not in the feed
+    pub gap_upto: u64,
+}
+
+fn feeds_folder(dtf_folder: &str) -> String {
+    utils::join(dtf_folder, "feeds")
+}
+
+fn write_record(wtr: &mut Write, offset: u64, up: &Update) -> Result<(), io::Error> {
+    wtr.write_u64::<BigEndian>(offset)?;
+    wtr.write_u64::<BigEndian>(up.ts)?;
+    wtr.write_u32::<BigEndian>(up.seq)?;
+    wtr.write_u8(up.flags_byte())?;
+    wtr.write_f32::<BigEndian>(up.price)?;
+    wtr.write_f32::<BigEndian>(up.size)
+}
+
+fn read_record(rdr: &mut Read) -> Result<(u64, Update), io::Error> {
+    let offset = rdr.read_u64::<BigEndian>()?;
+    let ts = rdr.read_u64::<BigEndian>()?;
+    let seq = rdr.read_u32::<BigEndian>()?;
+    let flags = rdr.read_u8()?;
+    let price = rdr.read_f32::<BigEndian>()?;
+    let size = rdr.read_f32::<BigEndian>()?;
+    Ok((offset, Update { ts, seq, is_trade: false, is_bid: false, price, size, flags: 0 }.with_flags_byte(flags)))
+}
+
+impl Feed {
+    /// Open the feed of `store_name`, with the rows kept before a restart
+    pub fn open(dtf_folder: &str, store_name: &str, window: usize) -> Result<Feed, io::Error> {
+        let window = cmp::max(window, 1);
+        fs::create_dir_all(feeds_folder(dtf_folder))?;
+        let fname = utils::join(&feeds_folder(dtf_folder), store_name);
+
+        let mut rows = VecDeque::new();
+        let mut on_disk = 0;
+        if let Ok(file) = File::open(&fname) {
+            let mut rdr = BufReader::new(file);
+            // a torn last record is dropped
+            while let Ok(row) = read_record(&mut rdr) {
+                if rows.len() == window {
+                    rows.pop_front();
+                }
+                rows.push_back(row);
+                on_disk += 1;
+            }
+        }
+        // rows before a full window may have left it
+        let gap_upto = if rows.len() == window {
+            rows.front().map_or(0, |&(offset, _)| offset - 1)
+        } else {
+            0
+        };
+        let len = fs::metadata(&fname).map(|m| m.len()).unwrap_or(0);
+        let mut feed = Feed { fname, rows, window, on_disk, gap_upto };
+        if feed.on_disk as u64 * RECORD_LEN as u64 != len {
+            feed.rewrite()?;
+        }
+        Ok(feed)
+    }
+
+    pub fn push(&mut self, offset: u64, up: &Update, handles: &mut FileHandles) {
+        if self.rows.len() == self.window {
+            if let Some((evicted, _)) = self.rows.pop_front() {
+                self.gap_upto = evicted;
+            }
+        }
+        self.rows.push_back((offset, up.clone()));
+
+        if let Err(e) = handles.append(&self.fname).and_then(|file| write_record(file, offset, up)) {
+            error!("Unable to write to feed {}: {:?}", self.fname, e);
+        }
+        self.on_disk += 1;
+        if self.on_disk >= 2 * self.window {
+            // the open handle would keep appending to the old file
+            handles.close(&self.fname);
+            if let Err(e) = self.rewrite() {
+                error!("Unable to rewrite feed {}: {:?}", self.fname, e);
+            }
+        }
+    }
+
+    /// the file only keeps the rows in the window
+    fn rewrite(&mut self) -> Result<(), io::Error> {
+        let tmp = format!("{}.tmp", self.fname);
+        {
+            let mut wtr = BufWriter::new(File::create(&tmp)?);
+            for &(offset, ref up) in self.rows.iter() {
+                write_record(&mut wtr, offset, up)?;
+            }
+            wtr.flush()?;
+        }
+        utils::replace_file(&tmp, &self.fname)?;
+        self.on_disk = self.rows.len();
+        Ok(())
+    }
+
+    pub fn newest(&self) -> Option<u64> {
+        self.rows.back().map(|&(offset, _)| offset)
+    }
+
+    /// rows inserted after commit `offset`
+    pub fn since(&self, offset: u64) -> Vec<(u64, Update)> {
+        // offsets only grow, the new rows are at the back
+        let n = self.rows.iter().rev().take_while(|&&(o, _)| o > offset).count();
+        self.rows.iter().skip(self.rows.len() - n).cloned().collect()
+    }
+}
+
+/// the feeds of every store that has one
+pub fn open_all(dtf_folder: &str, window: usize) -> HashMap<String, Feed> {
+    let mut feeds = HashMap::new();
+    let entries = match fs::read_dir(feeds_folder(dtf_folder)) {
+        Ok(entries) => entries,
+        Err(_) => return feeds
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = match entry.file_name().into_string() {
+            Ok(ref name) if !name.ends_with(".tmp") => name.clone(),
+            _ => continue
+        };
+        match Feed::open(dtf_folder, &name, window) {
+            Ok(feed) => { feeds.insert(name, feed); }
+            Err(e) => error!("Unable to open feed of {}: {:?}", name, e)
+        }
+    }
+    feeds
+}
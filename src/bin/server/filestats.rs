@@ -0,0 +1,185 @@
+/// File statistics
+///
+/// Every write of a dtf file leaves `[fname].dtf.stats` next to it:
+///
+///     len <file length (bytes)>
+///     checksum <CRC-32 of the rows>
+///     count <rows>
+///     ...
+///
+/// followed by the rest of the summary of the rows (time and price range,
+/// volume, rows per day, ...), so STATS and COUNT of a store are answered
+/// without reading the file. The checksum is over the rows in flat layout,
+/// in file order, and is continued on every append.
+///
+/// It also leaves `[fname].dtf.sample`, a uniform sample of the rows of the
+/// file continued on every append, which `APPROX` estimates from:
+///
+///     len <file length (bytes)>
+///     <capacity> <rows seen> <random state>
+///     <ts> <seq> <is_trade> <is_bid> <price> <size> <flags>
+///     ...
+///
+/// A sidecar whose length is not the length of the file was left by an older
+/// server or a crash, it is rebuilt from the rows.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use dtf::{self, Update};
+use dtf::summary::Summary;
+use dtf::sample::Reservoir;
+use dtf::utils::crc32_update;
+use utils;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileStats {
+    /// length of the dtf file described
+    pub len: u64,
+    pub checksum: u32,
+    pub summary: Summary,
+}
+
+impl FileStats {
+    fn add(&mut self, rows: &[Update]) {
+        self.checksum = checksum(self.checksum, rows);
+        self.summary.add(rows);
+    }
+}
+
+/// rows kept in the sample of a file
+static SAMPLE_ROWS : usize = 2048;
+
+fn stats_fname(dtf_fname: &str) -> String {
+    format!("{}.stats", dtf_fname)
+}
+
+fn file_len(dtf_fname: &str) -> u64 {
+    fs::metadata(dtf_fname).map(|m| m.len()).unwrap_or(0)
+}
+
+/// CRC-32 of `rows` continued from `crc`
+pub fn checksum(crc: u32, rows: &[Update]) -> u32 {
+    crc32_update(crc, &dtf::encode_flat(rows)[dtf::FLAT_HEADER_LEN..])
+}
+
+/// the sidecar of a dtf file, unless it is missing or stale
+pub fn read(dtf_fname: &str) -> Option<FileStats> {
+    read_at(dtf_fname, file_len(dtf_fname))
+}
+
+/// the sidecar, if it describes the file when it was `len` bytes long
+fn read_at(dtf_fname: &str, len: u64) -> Option<FileStats> {
+    let mut text = String::new();
+    File::open(stats_fname(dtf_fname)).ok()?.read_to_string(&mut text).ok()?;
+    let mut lines = text.splitn(3, '\n');
+    let mut value = |key: &str| lines.next()
+        .and_then(|line| if line.starts_with(key) { Some(line[key.len()..].to_owned()) } else { None });
+    let stats = FileStats {
+        len: value("len ")?.parse().ok()?,
+        checksum: u32::from_str_radix(&value("checksum ")?, 16).ok()?,
+        summary: Summary::parse(&value("")?)?,
+    };
+    if stats.len == len { Some(stats) } else { None }
+}
+
+fn write(dtf_fname: &str, stats: &FileStats) -> Result<(), io::Error> {
+    let tmp = format!("{}.tmp", stats_fname(dtf_fname));
+    {
+        let mut file = File::create(&tmp)?;
+        write!(file, "len {}\nchecksum {:08x}\n{}", stats.len, stats.checksum, stats.summary.serialize())?;
+    }
+    utils::replace_file(&tmp, &stats_fname(dtf_fname))
+}
+
+fn save(dtf_fname: &str, stats: &FileStats) {
+    if let Err(e) = write(dtf_fname, stats) {
+        error!("Unable to write stats of {}: {:?}", dtf_fname, e);
+    }
+}
+
+/// statistics of the rows of a dtf file, from the sidecar or rebuilt from the rows
+pub fn get(dtf_fname: &str) -> FileStats {
+    match read(dtf_fname) {
+        Some(stats) => stats,
+        None => rebuild(dtf_fname)
+    }
+}
+
+pub fn rebuild(dtf_fname: &str) -> FileStats {
+    let mut stats = FileStats::default();
+    stats.add(&dtf::decode(dtf_fname, None));
+    stats.len = file_len(dtf_fname);
+    save(dtf_fname, &stats);
+    stats
+}
+
+fn sample_fname(dtf_fname: &str) -> String {
+    format!("{}.sample", dtf_fname)
+}
+
+/// the sample sidecar, if it describes the file when it was `len` bytes long
+fn read_sample_at(dtf_fname: &str, len: u64) -> Option<Reservoir> {
+    let mut text = String::new();
+    File::open(sample_fname(dtf_fname)).ok()?.read_to_string(&mut text).ok()?;
+    let mut parts = text.splitn(2, '\n');
+    let sampled_len = parts.next()?;
+    if !sampled_len.starts_with("len ") || sampled_len[4..].parse::<u64>().ok()? != len {
+        return None;
+    }
+    Reservoir::parse(parts.next()?)
+}
+
+fn save_sample(dtf_fname: &str, reservoir: &Reservoir) {
+    let tmp = format!("{}.tmp", sample_fname(dtf_fname));
+    let written = File::create(&tmp)
+        .and_then(|mut file| write!(file, "len {}\n{}", file_len(dtf_fname), reservoir.serialize()))
+        .and_then(|_| utils::replace_file(&tmp, &sample_fname(dtf_fname)));
+    if let Err(e) = written {
+        error!("Unable to write the sample of {}: {:?}", dtf_fname, e);
+    }
+}
+
+/// a sample of the rows of a dtf file, from the sidecar or rebuilt from the rows
+pub fn sample(dtf_fname: &str) -> Reservoir {
+    match read_sample_at(dtf_fname, file_len(dtf_fname)) {
+        Some(reservoir) => reservoir,
+        None => rebuild_sample(dtf_fname)
+    }
+}
+
+fn rebuild_sample(dtf_fname: &str) -> Reservoir {
+    let mut reservoir = Reservoir::new(SAMPLE_ROWS);
+    reservoir.add(&dtf::decode(dtf_fname, None));
+    save_sample(dtf_fname, &reservoir);
+    reservoir
+}
+
+/// After the dtf file was (re)written with `rows`
+pub fn written(dtf_fname: &str, rows: &[Update]) {
+    let mut stats = FileStats::default();
+    stats.add(rows);
+    stats.len = file_len(dtf_fname);
+    save(dtf_fname, &stats);
+    let mut reservoir = Reservoir::new(SAMPLE_ROWS);
+    reservoir.add(rows);
+    save_sample(dtf_fname, &reservoir);
+}
+
+/// After `rows` were appended to the dtf file, which was `old_len` bytes long
+pub fn appended(dtf_fname: &str, old_len: u64, rows: &[Update]) {
+    match read_at(dtf_fname, old_len) {
+        Some(mut stats) => {
+            stats.add(rows);
+            stats.len = file_len(dtf_fname);
+            save(dtf_fname, &stats);
+        }
+        None => { rebuild(dtf_fname); }
+    }
+    match read_sample_at(dtf_fname, old_len) {
+        Some(mut reservoir) => {
+            reservoir.add(rows);
+            save_sample(dtf_fname, &reservoir);
+        }
+        None => { rebuild_sample(dtf_fname); }
+    }
+}
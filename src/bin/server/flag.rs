@@ -0,0 +1,82 @@
+/// Row flags
+///
+/// A row carries up to 6 user defined flags, e.g. rows filled in by a gap
+/// filler, corrected rows or the rows of a snapshot, as a 7th field of its
+/// `ADD` line, from 0 to 63:
+///
+///     1505177459.658, 139010, t, f, 0.0703629, 7.65064249, 5;
+///
+/// `FLAGS [db] synthetic corrected snapshot` names the bits of a store from
+/// the lowest, so 5 is a synthetic snapshot row, and `WHERE snapshot = f`
+/// filters on them. `FLAGS [db]` returns the names as JSON, `FLAGS [db] OFF`
+/// forgets them, the flags of the rows are kept.
+///
+/// Names are kept in `[dtf_folder]/flags`, one `db <TAB> name,name,...` line
+/// each. A later line for the same store wins, an empty list drops the names.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use dtf::update::MAX_USER_FLAGS;
+use utils;
+
+/// names a flag can't have, the fields of a row and the keywords of WHERE
+pub static RESERVED : &[&str] = &["ts", "seq", "is_trade", "is_bid", "price", "size", "flags", "and"];
+
+/// store name -> names of its flags, from the lowest bit
+pub type Names = HashMap<String, Vec<String>>;
+
+fn flags_fname(dtf_folder: &str) -> String {
+    utils::join(dtf_folder, "flags")
+}
+
+/// all names kept in the folder
+pub fn read(dtf_folder: &str) -> Names {
+    let mut names = HashMap::new();
+    let file = match File::open(flags_fname(dtf_folder)) {
+        Ok(f) => f,
+        Err(_) => return names
+    };
+    for line in BufReader::new(file).lines().filter_map(|line| line.ok()) {
+        let parts : Vec<&str> = line.split('\t').collect();
+        if parts.len() != 2 {
+            continue;
+        }
+        if parts[1].is_empty() {
+            names.remove(parts[0]);
+        } else {
+            names.insert(parts[0].to_owned(), parts[1].split(',').map(|name| name.to_owned()).collect());
+        }
+    }
+    names
+}
+
+pub fn record(dtf_folder: &str, store_name: &str, names: &[String]) -> Result<(), io::Error> {
+    let mut file = OpenOptions::new().create(true).append(true).open(flags_fname(dtf_folder))?;
+    writeln!(file, "{}\t{}", store_name, names.join(","))
+}
+
+/// at most 6 distinct lowercase names that aren't the fields of a row
+pub fn validate(names: &[String]) -> Result<(), String> {
+    let max = MAX_USER_FLAGS.count_ones() as usize;
+    if names.len() > max {
+        return Err(format!("A store has at most {} flags.", max));
+    }
+    for (i, name) in names.iter().enumerate() {
+        let valid = name.starts_with(|c: char| c.is_ascii_lowercase())
+            && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !valid || RESERVED.contains(&name.as_str()) {
+            return Err(format!("Invalid flag name `{}`, use lowercase letters, digits and `_`.", name));
+        }
+        if names[..i].contains(name) {
+            return Err(format!("Flag `{}` is named twice.", name));
+        }
+    }
+    Ok(())
+}
+
+/// ["synthetic", "corrected", "snapshot"]
+pub fn to_json(names: &[String]) -> String {
+    let names : Vec<String> = names.iter().map(|name| format!("\"{}\"", name)).collect();
+    format!("[{}]", names.join(", "))
+}
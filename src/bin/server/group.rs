@@ -0,0 +1,47 @@
+/// Store groups
+///
+/// `GROUP [name] = [pattern]` names the stores matching a pattern (`*`
+/// matches any characters), e.g. `GROUP binance = binance_*` for the
+/// hundreds of symbols of an exchange. `FLUSH GROUP`, `CLEAR GROUP`,
+/// `RETAIN GROUP` and `SUBSCRIBE GROUP` then act on all of them at once,
+/// under a single lock. The stores of a group are matched when a command
+/// runs, so a store created later joins its groups.
+///
+/// Groups are kept in `[dtf_folder]/groups`, one `group <TAB> pattern` line
+/// each. A later line for the same group wins, an empty pattern drops it.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use utils;
+
+/// group -> store name pattern
+pub type Groups = HashMap<String, String>;
+
+fn groups_fname(dtf_folder: &str) -> String {
+    utils::join(dtf_folder, "groups")
+}
+
+/// all groups kept in the folder
+pub fn read(dtf_folder: &str) -> Groups {
+    let mut groups = HashMap::new();
+    let file = match File::open(groups_fname(dtf_folder)) {
+        Ok(f) => f,
+        Err(_) => return groups
+    };
+    for line in BufReader::new(file).lines().filter_map(|line| line.ok()) {
+        let parts : Vec<&str> = line.split('\t').collect();
+        if parts.len() == 2 && parts[1].is_empty() {
+            groups.remove(parts[0]);
+        } else if parts.len() == 2 {
+            groups.insert(parts[0].to_owned(), parts[1].to_owned());
+        }
+    }
+    groups
+}
+
+/// Keep `group`, or drop it if `pattern` is empty
+pub fn record(dtf_folder: &str, group: &str, pattern: &str) -> Result<(), io::Error> {
+    let mut file = OpenOptions::new().create(true).append(true).open(groups_fname(dtf_folder))?;
+    writeln!(file, "{}\t{}", group, pattern)
+}
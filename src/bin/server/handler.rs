@@ -1,14 +1,65 @@
 use state::*;
 use parser;
+use derived::Expr;
+use approx::Aggregate;
+use topn::By;
+use hist;
+use job;
+use hello::{self, Hello};
+use dtf;
 use dtf::Update;
+use alert;
+use precision;
+use cache;
+use cdc;
 
 #[derive(Debug)]
 pub enum ReturnType {
     String(String),
     Bytes(Vec<u8>),
+    Stream(StreamQuery),
+    Replay(ReplayQuery),
+    Subscribe(SubscribeQuery),
+    /// changes to the stores after event offset, written to the socket as
+    /// they happen until the subscriber disconnects, see `cdc`
+    Cdc(u64),
     Error(String)
 }
 
+/// Rows of `stores` inserted after commit `offset` that match `filter`, written to the socket
+/// as they arrive until the subscriber disconnects
+#[derive(Debug)]
+pub struct SubscribeQuery {
+    pub stores: Vec<String>,
+    /// rows name their store, for `SUBSCRIBE GROUP`
+    pub group: bool,
+    pub offset: u64,
+    pub filter: parser::RowFilter,
+}
+
+/// Historical rows written to the socket paced by their original
+/// inter-arrival times divided by `speed`.
+#[derive(Debug)]
+pub struct ReplayQuery {
+    pub ups: Vec<Update>,
+    pub speed: f64,
+}
+
+/// A binary GET that is written to the socket in chunks of `window` rows
+/// instead of being buffered into a single response.
+#[derive(Debug)]
+pub struct StreamQuery {
+    pub count: Option<u32>,
+    pub range: Option<(u32, u32)>,
+    pub window: u32,
+}
+
+/// default number of rows per chunk for `GET ... STREAM`
+static DEFAULT_STREAM_WINDOW : u32 = 10_000;
+
+/// default ms `GET ... AFTER` waits for the commit offset
+static DEFAULT_AFTER_TIMEOUT : u64 = 5_000;
+
 #[derive(Debug)]
 enum ReqCount {
     All,
@@ -17,12 +68,20 @@ enum ReqCount {
 
 #[derive(Debug)]
 enum GetFormat {
-    JSON,
-    DTF
+    /// with price and size rounded to n decimal places, see `PRECISION`
+    JSON(Option<usize>),
+    /// JSON Lines, one update per line
+    JSONL(Option<usize>),
+    DTF,
+    /// DTF batches streamed in chunks of n rows
+    Stream(u32)
 }
 
 type DbName = String;
 
+/// (limit, cursor), see `parser::parse_paging`
+type Paging = (u32, Option<(u64, u32)>);
+
 #[derive(Debug)]
 enum Command {
     Nothing,
@@ -30,30 +89,195 @@ enum Command {
     Help,
     Info,
     Perf,
-    BulkAdd,
-    BulkAddInto(DbName),
+    PerfLatency(bool),
+    SlowLogGet(usize),
+    SlowLogReset,
+    Connections,
+    Cancel(u64),
+    StartJob(job::Kind),
+    JobStatus(u64),
+    JobCancel(u64),
+    Jobs,
+    Timeout(u64),
+    Explain(Option<u32>, Option<(u32, u32)>, Option<u32>),
+    ExplainCopy(DbName, Option<(u32, u32)>, parser::RowFilter),
+    BulkAdd(Option<DbName>, Option<parser::BulkDeclaration>),
     BulkAddEnd,
-    Get(ReqCount, GetFormat, Option<(u32,u32)>),
+    Begin,
+    Commit,
+    Rollback,
+    Get(ReqCount, GetFormat, Option<(u32,u32)>, Option<Paging>),
+    GetAsOf(ReqCount, GetFormat, Option<(u32,u32)>, u32),
     Count(ReqCount),
     Clear(ReqCount),
     Flush(ReqCount),
     Insert(Option<Update>, Option<DbName>),
     Create(DbName),
+    CreateIfNotExists(DbName),
+    CreateEphemeral(DbName),
+    CreateTtl(DbName, u64),
+    CreateLike(DbName, String),
+    Copy(DbName, DbName, Option<(u32,u32)>, parser::RowFilter),
+    Replay(DbName, Option<(u32,u32)>, f64),
+    Download(DbName, Option<(u32,u32)>),
+    Sync(String, String, Option<u32>, bool),
+    Checksums(DbName, Option<(u32,u32)>),
+    Subscribe(DbName, Option<u64>, parser::RowFilter),
+    SubscribeCdc(Option<u64>),
+    SubscribeGroup(String, Option<u64>, parser::RowFilter),
+    Join(DbName, DbName, Option<(u32,u32)>, Option<u64>),
+    Series(DbName, Option<(u32,u32)>, u64),
+    Approx(Aggregate, DbName, Option<(u32,u32)>, Option<f64>),
+    TopN(DbName, usize, By, Option<(u32,u32)>),
+    Hist(DbName, hist::Field, usize, Option<(u32,u32)>, parser::RowFilter),
+    Alias(DbName, DbName),
+    Group(String, String),
+    GroupDrop(String),
+    Groups,
+    FlushGroup(String),
+    ClearGroup(String),
+    Retain(DbName, Option<u64>),
+    RetainGroup(String, Option<u64>),
+    Tier(DbName, String, u64),
+    Backup(String, bool),
+    Ha,
+    Topology,
+    Schema,
+    Multicast(DbName, bool),
+    Precision(DbName, Option<usize>),
+    Flags(DbName, Option<Vec<String>>),
+    Meta(DbName, Vec<(String, String)>),
+    Find(Vec<(String, String)>),
+    Alert(alert::Rule),
+    AlertDrop(usize),
+    Alerts,
+    ConfigGet(Option<String>),
+    ConfigSet(String, String),
+    CreateMaterialized(DbName, DbName, u16),
+    CreateVirtual(DbName, Expr, Option<u64>, bool),
     Use(DbName),
     Exists(DbName),
+    ExistsAsJson(DbName),
+    Format(Encoding),
+    Hello(Hello),
+    Latest(DbName),
+    Stats(DbName),
+    Epochs(DbName),
+    Vacuum(DbName),
+    Delete(DbName, u64, u64),
+    Compact(DbName),
+    Close(DbName),
+    Correct(DbName, Update),
+    Strict(bool),
+    Validate(String),
     Unknown
 }
 
-static HELP_STR : &str = "PING, INFO, USE [db], CREATE [db],
-ADD [ts],[seq],[is_trade],[is_bid],[price],[size];
-BULKADD ...; DDAKLUB
-FLUSH, FLUSHALL, GETALL, GET [count], CLEAR
+static HELP_STR : &str = "PING, INFO, SCHEMA, USE [db], CREATE [db], CREATE [db] MEMORY,
+CREATE IF NOT EXISTS [db], EXISTS [db] [AS JSON]
+CREATE [db] TTL [30s|15m|24h|7d], CREATE [db] LIKE [db|template]
+CREATE MATERIALIZED [db] FROM [db] INTERVAL [1m|1h|1d]
+CREATE VIRTUAL [db] AS [mid(db) - mid(db) ...] [INTERVAL 1s] [MATERIALIZED]
+ADD [ts],[seq],[is_trade],[is_bid],[price],[size][,flags];
+ADD CORRECTION [ts],[seq],[is_trade],[is_bid],[price],[size]; [INTO db]
+BULKADD [INTO db] [COUNT n [CRC crc32]] ...; DDAKLUB
+BEGIN, ADD ..., COMMIT|ROLLBACK
+VALIDATE [row], STRICT [ON|OFF]
+FLUSH, FLUSHALL, GETALL, GET [count], CLEAR, CLOSE [db]
+GET [count|ALL] [FROM ts TO ts] [AS JSON|JSONL [PRECISION n]] [LIMIT n [CURSOR token]]
+GET [count|ALL] [FROM ts TO ts] STREAM [WINDOW n]
+GET [count|ALL] [FROM ts TO ts] [AS JSON|JSONL] ASOF EPOCH [n], EPOCHS [db], VACUUM [db]
+GET ... AFTER [offset] [TIMEOUT ms]
+EXPLAIN GET ..., EXPLAIN COPY ...
+FORMAT [DTF|FLAT|MSGPACK]
+HELLO [protocol=n] [store=db] [format=binary|flat|msgpack]
+LATEST [db], STATS [db], PERF, PERF LATENCY [RESET], SLOWLOG GET [n], SLOWLOG RESET
+CONNECTIONS, CANCEL [id], TIMEOUT [ms]
+FLUSH ALL ASYNC, CLEAR ALL ASYNC, JOB STATUS [id], JOB CANCEL [id], JOBS
+JOB COMPACT [db], JOB PRUNE, JOB INTEGRITY fast|deep, JOB BACKUP TO [folder] [INCREMENTAL]
+DELETE FROM [db] WHERE ts BETWEEN [ts] AND [ts], COMPACT [db]
+COPY [db] TO [db] [FROM ts TO ts] [WHERE is_trade = t|f [AND is_bid = t|f] [AND price|size <|<=|=|>=|> n]]
+REPLAY [db] [FROM ts TO ts] [SPEED 10x]
+DOWNLOAD [db] [FROM ts TO ts], UPLOAD INTO [db] [bytes]
+SYNC FROM [host:port] STORES [pattern] [SINCE ts] [VERIFY], CHECKSUMS [db] [FROM ts TO ts]
+JOIN [trades db] ASOF [quotes|series db] [FROM ts TO ts] [TOLERANCE ms]
+CREATE [db] LIKE [funding|open_interest], SERIES [db] [FROM ts TO ts] INTERVAL [1h|8h|1d]
+APPROX [COUNT|VWAP] [db] [FROM ts TO ts] [ERROR 1%]
+TOPN [db] [n] BY [size|notional|spread] [FROM ts TO ts]
+HIST [db] FIELD [price|size|notional] BUCKETS [n] [FROM ts TO ts] [WHERE ...]
+SUBSCRIBE [db] [FROM OFFSET n] [WHERE is_trade = t|f [AND size > n ...]]
+SUBSCRIBE __cdc__ [FROM OFFSET n]
+ALIAS [alias] FOR [db]
+GROUP [name] = [pattern], GROUP DROP [name], GROUPS
+FLUSH GROUP [name], CLEAR GROUP [name], SUBSCRIBE GROUP [name] [FROM OFFSET n] [WHERE ...]
+RETAIN [db] [30s|15m|24h|7d|OFF], RETAIN GROUP [name] [30s|15m|24h|7d|OFF]
+TIER [db] COLD [folder] AFTER [30s|15m|24h|7d]
+BACKUP TO [folder] [INCREMENTAL], HA, TOPOLOGY
+MULTICAST [db] [OFF]
+PRECISION [db] [n|OFF]
+FLAGS [db] [name ...|OFF], WHERE [name] = t|f
+META [db] [tag=value ...], FIND [tag=value|pattern ...]
+ALERT SILENT [db] AFTER [30s|15m|24h|7d] [INTO db], ALERT DISK ABOVE [500MB|2GB] [INTO db]
+ALERT FLUSH ERRORS [INTO db], ALERTS, ALERT DROP [n]
+CONFIG GET [setting], CONFIG SET [setting] [value]
 ";
 
+/// error of ADD and BULKADD without INTO, see `--default_store none`
+static NO_DB_IN_USE : &str = "No db in use, `USE [db]` first or add `INTO [db]`.";
+
+/// parse `ADD [row]` or `ADD [row] INTO [db]`
+///
+/// In strict mode a malformed row is reported as an error right away.
+fn parse_add(string: &str, state: &State) -> Result<(Option<Update>, Option<DbName>), String> {
+    let dbname = if string.contains(" INTO ") {
+        parser::parse_dbname(string).1.to_owned()
+    } else if state.require_use {
+        return Err(NO_DB_IN_USE.to_owned());
+    } else {
+        state.current_store_name.to_owned()
+    };
+    if state.strict {
+        return match parser::parse_line_strict(parser::parse_payload(string)) {
+            Ok(up) => Ok((Some(up), Some(dbname))),
+            Err(diagnostic) => Err(diagnostic.to_string())
+        };
+    }
+    Ok(if string.contains(" INTO ") {
+        parser::parse_add_into(string)
+    } else {
+        let data_string : &str = &string[3..];
+        match parser::parse_line(data_string) {
+            Some(up) => (Some(up), Some(dbname)),
+            None => (None, None)
+        }
+    })
+}
+
 /// sometimes returns string, sometimes bytes, error string
 // pub type Response = (Option<String>, Option<Vec<u8>>, Option<String>);
 
 pub fn gen_response (string : &str, state: &mut State) -> ReturnType {
+    // inserts replayed from a client spool carry an idempotency key
+    if string.starts_with("ADD ") {
+        if let (string, Some(key)) = parser::parse_idempotency_key(string) {
+            if state.has_idempotency_key(key) {
+                // already inserted
+                return return_string("");
+            }
+            // the key is only taken by an insert that succeeded, a replay of
+            // one that failed is tried again
+            let resp = gen_command_response(string, state);
+            match resp {
+                ReturnType::Error(_) => (),
+                _ => state.record_idempotency_key(key),
+            }
+            return resp;
+        }
+    }
+    gen_command_response(string, state)
+}
+
+fn gen_command_response(string : &str, state: &mut State) -> ReturnType {
     use self::Command::*;
 
     let command: Command = match string {
@@ -62,123 +286,705 @@ pub fn gen_response (string : &str, state: &mut State) -> ReturnType {
         "HELP" => Help,
         "INFO" => Info,
         "PERF" => Perf,
-        "BULKADD" => BulkAdd,
+        "HA" => Ha,
+        "TOPOLOGY" => Topology,
+        "SCHEMA" => Schema,
+        "PERF LATENCY" => PerfLatency(false),
+        "PERF LATENCY RESET" => PerfLatency(true),
+        "SLOWLOG GET" => SlowLogGet(10),
+        "SLOWLOG RESET" => SlowLogReset,
+        "CONNECTIONS" => Connections,
+        "ALERTS" => Alerts,
+        "GROUPS" => Groups,
+        "CONFIG GET" => ConfigGet(None),
+        "BULKADD" => BulkAdd(None, None),
         "DDAKLUB" => BulkAddEnd,
+        "BEGIN" => Begin,
+        "COMMIT" => Commit,
+        "ROLLBACK" => Rollback,
         "COUNT" => Count(ReqCount::Count(1)), 
         "COUNT ALL" => Count(ReqCount::All),
         "CLEAR" => Clear(ReqCount::Count(1)),
         "CLEAR ALL" => Clear(ReqCount::All),
-        "GET ALL AS JSON" => Get(ReqCount::All, GetFormat::JSON, None),
-        "GET ALL" => Get(ReqCount::All, GetFormat::DTF, None),
+        "GET ALL AS JSON" => Get(ReqCount::All, GetFormat::JSON(None), None, None),
+        "GET ALL AS JSONL" => Get(ReqCount::All, GetFormat::JSONL(None), None, None),
+        "GET ALL" => Get(ReqCount::All, GetFormat::DTF, None, None),
         "FLUSH" => Flush(ReqCount::Count(1)),
         "FLUSH ALL" => Flush(ReqCount::All),
+        "FLUSH ALL ASYNC" => StartJob(job::Kind::FlushAll),
+        "CLEAR ALL ASYNC" => StartJob(job::Kind::ClearAll),
+        "JOBS" => Jobs,
+        "HELLO" => Hello(hello::Hello::default()),
+        "FORMAT DTF" => Format(Encoding::DTF),
+        "FORMAT FLAT" => Format(Encoding::Flat),
+        "FORMAT MSGPACK" => Format(Encoding::Msgpack),
+        "STRICT ON" => Strict(true),
+        "STRICT OFF" => Strict(false),
         _ => {
             // is in bulkadd
             if state.is_adding {
-                let parsed = parser::parse_line(string);
-                let current_db = state.bulkadd_db.clone();
-                let dbname = current_db.unwrap();
-                Insert(parsed, Some(dbname))
+                let parsed = if state.encoding == Encoding::Msgpack {
+                    parser::parse_msgpack_line(string)
+                } else if state.strict {
+                    parser::parse_line_strict(string).map_err(|diagnostic| diagnostic.to_string())
+                } else {
+                    parser::parse_line(string).ok_or_else(|| "Unable to parse line".to_owned())
+                };
+                // declared rows are held back until DDAKLUB
+                if let Some(ref mut pending) = state.bulkadd_pending {
+                    pending.push(string, parsed.clone().ok());
+                    return match parsed {
+                        Ok(_) => return_string(""),
+                        Err(e) => return_err(&e)
+                    };
+                }
+                match parsed {
+                    Ok(up) => Insert(Some(up), state.bulkadd_db.clone()),
+                    Err(e) => return return_err(&e)
+                }
             } else
 
-            if string.starts_with("BULKADD INTO ") {
-                let (_index, dbname) = parser::parse_dbname(string);
-                BulkAddInto(dbname.to_owned())
+            if string.starts_with("BULKADD ") {
+                match parser::parse_bulkadd(string) {
+                    Some((Some(dbname), declaration)) => match state.new_store_name(&dbname) {
+                        Ok(dbname) => BulkAdd(Some(dbname), declaration),
+                        Err(e) => return return_err(&e)
+                    },
+                    Some((None, declaration)) => BulkAdd(None, declaration),
+                    None => return return_err("Unable to parse BULKADD.")
+                }
             } else 
 
+            if string.starts_with("CREATE VIRTUAL ") {
+                match parser::parse_create_virtual(string) {
+                    Some((name, expr, interval, materialized)) => match state.new_store_name(&name) {
+                        Ok(name) => CreateVirtual(name, expr, interval, materialized),
+                        Err(e) => return return_err(&e)
+                    },
+                    None => return return_err("Unable to parse CREATE VIRTUAL.")
+                }
+            } else
+
+            if string.starts_with("CREATE MATERIALIZED ") {
+                match parser::parse_create_materialized(string) {
+                    Some((name, source, minutes)) => match state.new_store_name(&name) {
+                        Ok(name) => CreateMaterialized(name, state.resolve(&source), minutes),
+                        Err(e) => return return_err(&e)
+                    },
+                    None => return return_err("Unable to parse CREATE MATERIALIZED.")
+                }
+            } else
+
+            if string.starts_with("CREATE IF NOT EXISTS ") {
+                match parser::parse_store_between(string, &["CREATE", "IF", "NOT", "EXISTS"], &[]) {
+                    Some(dbname) => CreateIfNotExists(match state.new_store_name(&dbname) {
+                        Ok(dbname) => dbname,
+                        Err(e) => return return_err(&e)
+                    }),
+                    None => return return_err("Unable to parse CREATE IF NOT EXISTS.")
+                }
+            } else
+
+            if string.starts_with("CREATE ") && string.ends_with(" MEMORY") {
+                match parser::parse_store_between(string, &["CREATE"], &["MEMORY"]) {
+                    Some(dbname) => CreateEphemeral(match state.new_store_name(&dbname) {
+                        Ok(dbname) => dbname,
+                        Err(e) => return return_err(&e)
+                    }),
+                    None => return return_err("Unable to parse CREATE MEMORY.")
+                }
+            } else
+
+            if string.starts_with("CREATE ") && string.contains(" LIKE ") {
+                match parser::parse_create_like(string) {
+                    Some((dbname, like)) => match state.new_store_name(&dbname) {
+                        Ok(dbname) => CreateLike(dbname, like),
+                        Err(e) => return return_err(&e)
+                    },
+                    None => return return_err("Unable to parse CREATE LIKE.")
+                }
+            } else
+
+            if string.starts_with("CREATE ") && string.contains(" TTL ") {
+                match parser::parse_create_ttl(string) {
+                    Some((dbname, ttl)) => match state.new_store_name(&dbname) {
+                        Ok(dbname) => CreateTtl(dbname, ttl),
+                        Err(e) => return return_err(&e)
+                    },
+                    None => return return_err("Unable to parse CREATE TTL.")
+                }
+            } else
+
             if string.starts_with("CREATE ") {
                 let dbname : &str = &string[7..];
-                Create(dbname.to_owned())
+                Create(match state.new_store_name(dbname) {
+                    Ok(dbname) => dbname,
+                    Err(e) => return return_err(&e)
+                })
+            } else
+
+            if string.starts_with("HELLO ") {
+                match parser::parse_hello(&string[6..]) {
+                    Some(hello) => Hello(hello),
+                    None => return return_err("Unable to parse HELLO.")
+                }
             } else
 
             if string.starts_with("USE ") {
                 let dbname : &str = &string[4..];
-                Use(dbname.to_owned())
+                Use(state.resolve(dbname))
+            } else
+
+            if string.starts_with("LATEST ") {
+                let dbname : &str = &string[7..];
+                Latest(state.resolve(dbname))
+            } else
+
+            if string.starts_with("STATS ") {
+                let dbname : &str = &string[6..];
+                Stats(state.resolve(dbname))
+            } else
+
+            if string.starts_with("EPOCHS ") {
+                let dbname : &str = &string[7..];
+                Epochs(state.resolve(dbname))
+            } else
+
+            if string.starts_with("VACUUM ") {
+                let dbname : &str = &string[7..];
+                Vacuum(dbname.to_owned())
+            } else
+
+            if string.starts_with("DELETE ") {
+                match parser::parse_delete(string) {
+                    Some((dbname, min_ts, max_ts)) => Delete(dbname, min_ts, max_ts),
+                    None => return return_err("Unable to parse DELETE.")
+                }
+            } else
+
+            if string.starts_with("REPLAY ") {
+                match parser::parse_replay(string) {
+                    Some((dbname, range, speed)) => Replay(state.resolve(&dbname), range, speed),
+                    None => return return_err("Unable to parse REPLAY.")
+                }
+            } else
+
+            if string.starts_with("UPLOAD ") {
+                // the server reads the file following the command, see `Session::upload`
+                return return_err("UPLOAD needs the dtf file after the command.")
+            } else
+
+            if string.starts_with("SYNC ") {
+                match parser::parse_sync(string) {
+                    Some((addr, pattern, since, verify)) => Sync(addr, pattern, since, verify),
+                    None => return return_err("Unable to parse SYNC.")
+                }
+            } else
+
+            if string.starts_with("CHECKSUMS ") {
+                match parser::parse_download(string) {
+                    Some((dbname, range)) => Checksums(state.resolve(&dbname), range),
+                    None => return return_err("Unable to parse CHECKSUMS.")
+                }
+            } else
+
+            if string.starts_with("DOWNLOAD ") {
+                match parser::parse_download(string) {
+                    Some((dbname, range)) => Download(state.resolve(&dbname), range),
+                    None => return return_err("Unable to parse DOWNLOAD.")
+                }
+            } else
+
+            if string.starts_with("JOIN ") {
+                match parser::parse_join(string) {
+                    Some((trades, quotes, range, tolerance)) => Join(state.resolve(&trades), state.resolve(&quotes), range, tolerance),
+                    None => return return_err("Unable to parse JOIN.")
+                }
+            } else
+
+            if string.starts_with("SERIES ") {
+                match parser::parse_series(string) {
+                    Some((dbname, range, interval)) => Series(state.resolve(&dbname), range, interval),
+                    None => return return_err("Unable to parse SERIES.")
+                }
+            } else
+
+            if string.starts_with("APPROX ") {
+                match parser::parse_approx(string) {
+                    Some((aggregate, dbname, range, error)) => Approx(aggregate, state.resolve(&dbname), range, error),
+                    None => return return_err("Unable to parse APPROX.")
+                }
+            } else
+
+            if string.starts_with("TOPN ") {
+                match parser::parse_topn(string) {
+                    Some((dbname, n, by, range)) => TopN(state.resolve(&dbname), n, by, range),
+                    None => return return_err("Unable to parse TOPN.")
+                }
+            } else
+
+            if string.starts_with("HIST ") {
+                match parser::parse_hist(string) {
+                    Some((dbname, field, buckets, range, filter)) => Hist(state.resolve(&dbname), field, buckets, range, filter),
+                    None => return return_err("Unable to parse HIST.")
+                }
+            } else
+
+            if string.starts_with("SUBSCRIBE GROUP ") {
+                let subscribe = format!("SUBSCRIBE {}", &string[16..]);
+                match parser::parse_subscribe(&subscribe) {
+                    Some((group_name, from, filter)) => SubscribeGroup(group_name, from, filter),
+                    None => return return_err("Unable to parse SUBSCRIBE GROUP.")
+                }
+            } else
+
+            if string.starts_with("SUBSCRIBE ") {
+                match parser::parse_subscribe(string) {
+                    Some((ref dbname, from, ref filter)) if dbname == cdc::STORE && *filter == parser::RowFilter::default() =>
+                        SubscribeCdc(from),
+                    Some((ref dbname, _, _)) if dbname == cdc::STORE =>
+                        return return_err("SUBSCRIBE __cdc__ takes no WHERE."),
+                    Some((dbname, from, filter)) => Subscribe(state.resolve(&dbname), from, filter),
+                    None => return return_err("Unable to parse SUBSCRIBE.")
+                }
+            } else
+
+            if string.starts_with("COPY ") {
+                match parser::parse_copy(string) {
+                    Some((src, dst, range, filter)) => Copy(state.resolve(&src), state.resolve(&dst), range, filter),
+                    None => return return_err("Unable to parse COPY.")
+                }
+            } else
+
+            if string.starts_with("TIER ") {
+                match parser::parse_tier(string) {
+                    Some((dbname, cold_folder, ms)) => Tier(state.resolve(&dbname), cold_folder, ms),
+                    None => return return_err("Unable to parse TIER.")
+                }
+            } else
+
+            if string.starts_with("BACKUP ") {
+                match parser::parse_backup(string) {
+                    Some((folder, incremental)) => Backup(folder, incremental),
+                    None => return return_err("Unable to parse BACKUP.")
+                }
+            } else
+
+            if string.starts_with("CONFIG ") {
+                match parser::parse_config(string) {
+                    Some((key, Some(value))) => ConfigSet(key, value),
+                    Some((key, None)) => ConfigGet(Some(key)),
+                    None => return return_err("Unable to parse CONFIG.")
+                }
+            } else
+
+            if string.starts_with("EXPLAIN COPY ") {
+                match parser::parse_copy(&string[8..]) {
+                    Some((src, _, range, filter)) => ExplainCopy(state.resolve(&src), range, filter),
+                    None => return return_err("Unable to parse COPY.")
+                }
+            } else
+
+            if string.starts_with("EXPLAIN ") {
+                match parser::parse_explain(string) {
+                    Some((count, range, limit)) => Explain(count, range, limit),
+                    None => return return_err("Unable to parse EXPLAIN, only GET and COPY can be explained.")
+                }
+            } else
+
+            if string.starts_with("CANCEL ") {
+                match string[7..].parse::<u64>() {
+                    Ok(id) => Cancel(id),
+                    Err(_) => return return_err("Unable to parse CANCEL.")
+                }
+            } else
+
+            if string.starts_with("JOB STATUS ") {
+                match string[11..].parse::<u64>() {
+                    Ok(id) => JobStatus(id),
+                    Err(_) => return return_err("Unable to parse JOB STATUS.")
+                }
+            } else
+
+            if string.starts_with("JOB CANCEL ") {
+                match string[11..].parse::<u64>() {
+                    Ok(id) => JobCancel(id),
+                    Err(_) => return return_err("Unable to parse JOB CANCEL.")
+                }
+            } else
+
+            if string.starts_with("JOB ") {
+                match parser::parse_job(&string[4..]) {
+                    Some(job::Kind::Compact(dbname)) => StartJob(job::Kind::Compact(state.resolve(&dbname))),
+                    Some(kind) => StartJob(kind),
+                    None => return return_err("Unable to parse JOB.")
+                }
+            } else
+
+            if string.starts_with("TIMEOUT ") {
+                match string[8..].parse::<u64>() {
+                    Ok(ms) => Timeout(ms),
+                    Err(_) => return return_err("Unable to parse TIMEOUT.")
+                }
+            } else
+
+            if string.starts_with("SLOWLOG GET ") {
+                match string[12..].parse::<usize>() {
+                    Ok(n) => SlowLogGet(n),
+                    Err(_) => return return_err("Unable to parse SLOWLOG GET.")
+                }
+            } else
+
+            if string.starts_with("ALERT DROP ") {
+                match string[11..].parse::<usize>() {
+                    Ok(n) => AlertDrop(n),
+                    Err(_) => return return_err("Unable to parse ALERT DROP.")
+                }
+            } else
+
+            if string.starts_with("ALERT ") {
+                match parser::parse_alert(string) {
+                    Some(rule) => Alert(rule),
+                    None => return return_err("Unable to parse ALERT.")
+                }
+            } else
+
+            if string.starts_with("MULTICAST ") {
+                match parser::parse_multicast(string) {
+                    Some((dbname, on)) => Multicast(state.resolve(&dbname), on),
+                    None => return return_err("Unable to parse MULTICAST.")
+                }
+            } else
+
+            if string.starts_with("PRECISION ") {
+                match parser::parse_precision(string) {
+                    Some((_, Some(decimals))) if decimals > precision::MAX_DECIMALS =>
+                        return return_err(&format!("PRECISION must be 0 to {}.", precision::MAX_DECIMALS)),
+                    Some((dbname, decimals)) => Precision(state.resolve(&dbname), decimals),
+                    None => return return_err("Unable to parse PRECISION.")
+                }
+            } else
+
+            if string.starts_with("FLAGS ") {
+                match parser::parse_flags(string) {
+                    Some((dbname, names)) => Flags(state.resolve(&dbname), names),
+                    None => return return_err("Unable to parse FLAGS.")
+                }
+            } else
+
+            if string.starts_with("META ") {
+                match parser::parse_meta(string) {
+                    Some((dbname, tags)) => Meta(state.resolve(&dbname), tags),
+                    None => return return_err("Unable to parse META.")
+                }
+            } else
+
+            if string.starts_with("FIND ") {
+                match parser::parse_find(string) {
+                    Some(tags) => Find(tags),
+                    None => return return_err("Unable to parse FIND.")
+                }
+            } else
+
+            if string.starts_with("GROUP DROP ") {
+                GroupDrop(string[11..].to_owned())
+            } else
+
+            if string.starts_with("GROUP ") {
+                match parser::parse_group(string) {
+                    Some((group_name, pattern)) => Group(group_name, pattern),
+                    None => return return_err("Unable to parse GROUP.")
+                }
+            } else
+
+            if string.starts_with("FLUSH GROUP ") {
+                FlushGroup(string[12..].to_owned())
+            } else
+
+            if string.starts_with("CLEAR GROUP ") {
+                ClearGroup(string[12..].to_owned())
+            } else
+
+            if string.starts_with("RETAIN GROUP ") {
+                let retain = format!("RETAIN {}", &string[13..]);
+                match parser::parse_retain(&retain) {
+                    Some((group_name, ttl)) => RetainGroup(group_name, ttl),
+                    None => return return_err("Unable to parse RETAIN GROUP.")
+                }
+            } else
+
+            if string.starts_with("RETAIN ") {
+                match parser::parse_retain(string) {
+                    Some((dbname, ttl)) => Retain(state.resolve(&dbname), ttl),
+                    None => return return_err("Unable to parse RETAIN.")
+                }
+            } else
+
+            if string.starts_with("ALIAS ") {
+                match parser::parse_alias(string) {
+                    Some((alias, dbname)) => Alias(alias, dbname),
+                    None => return return_err("Unable to parse ALIAS.")
+                }
+            } else
+
+            if string.starts_with("COMPACT ") {
+                let dbname : &str = &string[8..];
+                Compact(dbname.to_owned())
+            } else
+
+            if string.starts_with("CLOSE ") {
+                let dbname : &str = &string[6..];
+                Close(state.resolve(dbname))
+            } else
+
+            if string.starts_with("EXISTS ") && string.ends_with(" AS JSON") {
+                match parser::parse_store_between(string, &["EXISTS"], &["AS", "JSON"]) {
+                    Some(dbname) => ExistsAsJson(state.resolve(&dbname)),
+                    None => return return_err("Unable to parse EXISTS AS JSON.")
+                }
             } else
 
             if string.starts_with("EXISTS ") {
                 let dbname : &str = &string[7..];
-                Exists(dbname.to_owned())
+                Exists(state.resolve(dbname))
+            } else
+
+            if string.starts_with("ADD CORRECTION ") {
+                let add = format!("ADD {}", &string[15..]);
+                match parse_add(&add, state) {
+                    Ok((Some(up), Some(dbname))) => Correct(state.resolve(&dbname), up),
+                    Err(e) => return return_err(&e),
+                    _ => return return_err("Unable to parse correction.")
+                }
             } else
 
             if string.starts_with("ADD ") {
-                let parsed = if string.contains(" INTO ") {
-                        parser::parse_add_into(&string)
-                    } else {
-                        let data_string : &str = &string[3..];
-                        match parser::parse_line(&data_string) {
-                            Some(up) => (Some(up), Some(state.current_store_name.to_owned())),
-                            None => (None, None)
-                        }
-                    };
-                Insert(parsed.0, parsed.1)
+                match parse_add(string, state) {
+                    Ok((up, Some(dbname))) => match state.new_store_name(&dbname) {
+                        Ok(dbname) => Insert(up, Some(dbname)),
+                        Err(e) => return return_err(&e)
+                    },
+                    Ok((up, None)) => Insert(up, None),
+                    Err(e) => return return_err(&e)
+                }
+            } else
+
+            if string.starts_with("VALIDATE ") {
+                Validate(string[9..].to_owned())
             } else
 
 
             // get
             if string.starts_with("GET ") {
+                // read your own writes: wait until the commit offset is applied
+                if string.contains(" AFTER ") {
+                    let offset = match parser::parse_keyword::<u64>(string, " AFTER ") {
+                        Some(offset) => offset,
+                        None => return return_err("Unable to parse offset.")
+                    };
+                    let timeout = parser::parse_keyword::<u64>(string, " TIMEOUT ").unwrap_or(DEFAULT_AFTER_TIMEOUT);
+                    if let Err(e) = state.wait_for_offset(offset, timeout) {
+                        return return_err(&e);
+                    }
+                }
+
                 // how many records from memory we want...
                 let count : &str = &string.clone()[4..];
                 let count : Vec<&str> = count.split(" ").collect();
-                let count = count[0].parse::<u32>().unwrap();
+                let count = match count[0] {
+                    "ALL" => ReqCount::All,
+                    n => match n.parse::<u32>() {
+                        Ok(n) => ReqCount::Count(n),
+                        Err(_) => return return_err("Unable to parse count.")
+                    }
+                };
 
                 let ranged = string.contains(" FROM ");
                 let range = if ranged {
                         // range to query
-                        let from_epoch = string.clone()[(string.find(" FROM ").unwrap()+6)..]
-                                        .split(" ")
-                                        .collect::<Vec<&str>>()
-                                        [0]
-                                        .parse::<u32>()
-                                        .unwrap();
-                        let to_epoch = string.clone()[(string.find(" TO ").unwrap()+4)..]
-                                        .split(" ")
-                                        .collect::<Vec<&str>>()
-                                        [0]
-                                        .parse::<u32>()
-                                        .unwrap();
-                        Some((from_epoch, to_epoch))
+                        let from_epoch = parser::parse_keyword::<u32>(string, " FROM ");
+                        let to_epoch = parser::parse_keyword::<u32>(string, " TO ");
+                        match (from_epoch, to_epoch) {
+                            (Some(from_epoch), Some(to_epoch)) => Some((from_epoch, to_epoch)),
+                            _ => return return_err("Unable to parse range.")
+                        }
                     } else {
                         None
                     };
 
-                // test if json
-                let format =  if string.contains(" AS JSON") { GetFormat::JSON } else { GetFormat::DTF };
+                // test if paged
+                let paging = if string.contains(" LIMIT ") {
+                        match parser::parse_paging(string) {
+                            Some(paging) => Some(paging),
+                            None => return return_err("Unable to parse LIMIT/CURSOR.")
+                        }
+                    } else {
+                        None
+                    };
 
-                Get(ReqCount::Count(count), format, range)
+                // rounding of json
+                let decimals = if string.contains(" PRECISION ") {
+                        match parser::parse_keyword::<usize>(string, " PRECISION ") {
+                            Some(decimals) if decimals <= precision::MAX_DECIMALS => Some(decimals),
+                            _ => return return_err(&format!("PRECISION must be 0 to {}.", precision::MAX_DECIMALS))
+                        }
+                    } else {
+                        None
+                    };
+
+                // test if json or streamed
+                let format = if string.contains(" AS JSONL") {
+                        GetFormat::JSONL(decimals)
+                    } else if string.contains(" AS JSON") {
+                        GetFormat::JSON(decimals)
+                    } else if string.contains(" STREAM") {
+                        match parser::parse_keyword::<u32>(string, " WINDOW ") {
+                            Some(0) => return return_err("WINDOW must be positive."),
+                            Some(window) => GetFormat::Stream(window),
+                            None => GetFormat::Stream(DEFAULT_STREAM_WINDOW)
+                        }
+                    } else {
+                        GetFormat::DTF
+                    };
+
+                // test if time travel
+                if string.contains(" ASOF EPOCH ") {
+                    let asof = match parser::parse_keyword::<u32>(string, " ASOF EPOCH ") {
+                        Some(asof) => asof,
+                        None => return return_err("Unable to parse epoch.")
+                    };
+                    match (format, paging) {
+                        (GetFormat::Stream(_), _) | (_, Some(_)) =>
+                            return return_err("ASOF cannot be combined with STREAM or LIMIT."),
+                        (format, None) => GetAsOf(count, format, range, asof)
+                    }
+                } else {
+                    Get(count, format, range, paging)
+                }
             } else
 
             { Unknown }
         }
     };
 
-    match command {
+    // a repeated read is answered from the result cache
+    let cache_key = cache_key(&command, state);
+    let mut generation = 0;
+    if let Some(ref key) = cache_key {
+        match state.cached(key) {
+            Ok(cache::Response::String(s)) => return ReturnType::String(s),
+            Ok(cache::Response::Bytes(b)) => return ReturnType::Bytes(b),
+            Err(read_at) => generation = read_at
+        }
+    }
+
+    let resp = match command {
         Nothing =>
             return_string(""),
         Ping =>
             return_string("PONG"),
         Help =>
-            return_string(HELP_STR),
+            {
+                let extensions = state.global.read().unwrap().extensions.clone();
+                return_string(&(HELP_STR.to_owned() + &extensions.help()))
+            },
         Info =>
             return_string(&state.info()),
         Perf =>
             return_string(&state.perf()),
-        BulkAdd => 
+        Ha =>
+            ReturnType::String(state.ha()),
+        Topology =>
+            ReturnType::String(state.topology()),
+        Schema =>
+            return_string(&state.schema()),
+        PerfLatency(reset) =>
+            return_string(&state.perf_latency(reset)),
+        Explain(count, range, limit) =>
+            return_string(&state.explain_get(count, range, limit)),
+        ExplainCopy(src, range, filter) =>
+            match state.resolve_flags(&[src.clone()], filter).and_then(|filter| state.explain_copy(&src, range, &filter)) {
+                Ok(json) => return_string(&json),
+                Err(e) => return_err(&e)
+            },
+        SlowLogGet(n) =>
+            return_string(&state.slowlog_get(n)),
+        SlowLogReset =>
             {
-                state.is_adding = true;
-                return_string("")
+                state.slowlog_reset();
+                return_string("1")
+            },
+        Connections =>
+            return_string(&state.connections()),
+        Cancel(id) =>
+            match state.cancel(id) {
+                Ok(()) => return_string("1"),
+                Err(e) => return_err(&e)
             },
-        BulkAddInto(dbname) =>
+        StartJob(kind) =>
+            return_string(&format!(r#"{{"job": {}}}"#, state.start_job(kind))),
+        JobStatus(id) =>
+            match state.job_status(id) {
+                Ok(json) => return_string(&json),
+                Err(e) => return_err(&e)
+            },
+        JobCancel(id) =>
+            match state.job_cancel(id) {
+                Ok(()) => return_string("1"),
+                Err(e) => return_err(&e)
+            },
+        Jobs => return_string(&state.jobs()),
+        Timeout(ms) =>
             {
-                state.bulkadd_db = Some(dbname);
+                state.timeout = Some(ms);
+                return_string("1")
+            },
+        BulkAdd(None, _) if state.require_use =>
+            return_err(NO_DB_IN_USE),
+        BulkAdd(dbname, declaration) =>
+            {
+                state.bulkadd_db = dbname;
+                state.bulkadd_pending = declaration.map(PendingBulkAdd::new);
                 state.is_adding = true;
                 return_string("")
             },
         BulkAddEnd => 
             {
                 state.is_adding = false;
-                state.bulkadd_db = None;
-                return_string("1")
+                let dbname = state.bulkadd_db.take();
+                match state.bulkadd_pending.take() {
+                    Some(pending) => {
+                        let dbname = dbname.unwrap_or_else(|| state.current_store_name.clone());
+                        match state.commit_bulkadd(pending, &dbname) {
+                            // applied with the transaction
+                            Ok(_) if state.transaction.is_some() => return_string("1"),
+                            Ok(_) => return_string(&format!("{}", state.offset())),
+                            Err(e) => return_err(&e)
+                        }
+                    },
+                    None => return_string("1")
+                }
+            },
+        Begin =>
+            {
+                match state.begin() {
+                    Ok(()) => return_string(""),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Commit =>
+            {
+                match state.commit() {
+                    Ok(offset) => return_string(&format!("{}", offset)),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Rollback =>
+            {
+                match state.rollback() {
+                    Ok(n) => return_string(&format!("{}", n)),
+                    Err(e) => return_err(&e)
+                }
             },
         Count(ReqCount::Count(_)) => 
             return_string(&format!("{}", state.count())),
@@ -196,8 +1002,10 @@ pub fn gen_response (string : &str, state: &mut State) -> ReturnType {
             },
         Flush(ReqCount::Count(_)) =>
             {
-                state.flush();
-                return_string("1")
+                match state.flush() {
+                    Ok(()) => return_string("1"),
+                    Err(e) => return_err(&e)
+                }
             },
         Flush(ReqCount::All) =>
             {
@@ -206,15 +1014,33 @@ pub fn gen_response (string : &str, state: &mut State) -> ReturnType {
             },
 
         // update, dbname
+        Insert(Some(up), dbname) if state.transaction.is_some() =>
+            {
+                let dbname = dbname.unwrap_or_else(|| state.current_store_name.clone());
+                if !state.exists(&dbname) && !state.global.read().unwrap().settings.autocreate_stores {
+                    return return_err(&format!("No db named `{}`", dbname));
+                }
+                state.transaction.as_mut().unwrap().push((dbname, up));
+                return_string("")
+            },
+        Insert(Some(_), Some(ref dbname)) if state.over_budget(dbname) =>
+            return_err(&format!("Memory budget exceeded, `{}` cannot be flushed.", dbname)),
         Insert(Some(up), Some(dbname)) =>
             {
+                if let Err(e) = state.ensure_store(&dbname) {
+                    return return_err(&e);
+                }
                 state.insert(up, &dbname);
-                return_string("")
+                // the ack of every insert, formatted once
+                ReturnType::String(format!("{}\n", state.offset()))
             },
         Insert(Some(up), None) =>
             {
+                if state.over_budget(&state.current_store_name) {
+                    return return_err(&format!("Memory budget exceeded, `{}` cannot be flushed.", state.current_store_name));
+                }
                 state.add(up);
-                return_string("")
+                ReturnType::String(format!("{}\n", state.offset()))
             },
         Insert(None, _) => 
             return_err("Unable to parse line"),
@@ -225,6 +1051,41 @@ pub fn gen_response (string : &str, state: &mut State) -> ReturnType {
                 state.create(&dbname); 
                 return_string(&format!("Created DB `{}`.", &dbname))
             },
+        CreateIfNotExists(dbname) =>
+            return_string(&state.create_if_not_exists(&dbname)),
+        CreateEphemeral(dbname) =>
+            {
+                state.create_ephemeral(&dbname);
+                return_string(&format!("Created DB `{}` in memory.", &dbname))
+            },
+        CreateTtl(dbname, ttl) =>
+            {
+                match state.create_ttl(&dbname, ttl) {
+                    Ok(()) => return_string(&format!("Created DB `{}`.", &dbname)),
+                    Err(e) => return_err(&e)
+                }
+            },
+        CreateLike(dbname, like) =>
+            {
+                match state.create_like(&dbname, &like) {
+                    Ok(()) => return_string(&format!("Created DB `{}`.", &dbname)),
+                    Err(e) => return_err(&e)
+                }
+            },
+        CreateMaterialized(name, source, minutes) =>
+            {
+                match state.create_materialized(&name, &source, minutes) {
+                    Ok(()) => return_string(&format!("Created DB `{}`.", &name)),
+                    Err(e) => return_err(&e)
+                }
+            },
+        CreateVirtual(name, expr, interval, materialized) =>
+            {
+                match state.create_virtual(&name, expr, interval, materialized) {
+                    Ok(()) => return_string(&format!("Created DB `{}`.", &name)),
+                    Err(e) => return_err(&e)
+                }
+            },
         Use(dbname) => 
             {
                 match state.use_db(&dbname) {
@@ -240,55 +1101,458 @@ pub fn gen_response (string : &str, state: &mut State) -> ReturnType {
                     return_err(&format!("No db named `{}`", dbname))
                 }
             },
+        ExistsAsJson(dbname) =>
+            return_string(&state.describe(&dbname, false)),
+
+        Latest(dbname) =>
+            {
+                match state.latest(&dbname) {
+                    Some(json) => return_string(&json),
+                    None => return_err(&format!("No db named `{}`", dbname))
+                }
+            },
+        Stats(dbname) =>
+            {
+                match state.stats(&dbname) {
+                    Some(json) => return_string(&json),
+                    None => return_err(&format!("No db named `{}`", dbname))
+                }
+            },
+        Epochs(dbname) =>
+            {
+                match state.epochs(&dbname) {
+                    Some(json) => return_string(&json),
+                    None => return_err(&format!("No db named `{}`", dbname))
+                }
+            },
+        Vacuum(dbname) =>
+            {
+                match state.vacuum(&dbname) {
+                    Some(Ok(())) => return_string("1"),
+                    Some(Err(e)) => return_err(&e),
+                    None => return_err(&format!("No db named `{}`", dbname))
+                }
+            },
+        Delete(dbname, min_ts, max_ts) =>
+            {
+                match state.delete(&dbname, min_ts, max_ts) {
+                    Ok(()) => return_string("1"),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Compact(dbname) =>
+            {
+                match state.compact(&dbname) {
+                    Ok(removed) => return_string(&format!("{}", removed)),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Close(dbname) =>
+            {
+                match state.close(&dbname) {
+                    Ok(()) => return_string("1"),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Strict(strict) =>
+            {
+                state.strict = strict;
+                return_string(&format!("STRICT {}", if strict { "ON" } else { "OFF" }))
+            },
+        Validate(payload) =>
+            {
+                match parser::parse_line_strict(parser::parse_payload(&payload)) {
+                    Ok(up) => return_string(&format!("[{}]\n", up.to_json())),
+                    Err(diagnostic) => return_err(&diagnostic.to_string())
+                }
+            },
+        Replay(dbname, range, speed) =>
+            {
+                match state.read_store(&dbname, range) {
+                    Ok(ups) => ReturnType::Replay(ReplayQuery { ups, speed }),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Sync(addr, pattern, since, verify) =>
+            {
+                match state.sync(&addr, &pattern, since, verify) {
+                    Ok(json) => ReturnType::String(json),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Checksums(dbname, range) =>
+            {
+                match state.checksums(&dbname, range) {
+                    Ok(json) => ReturnType::String(json),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Download(dbname, range) =>
+            {
+                match state.download(&dbname, range) {
+                    Ok(bytes) => ReturnType::Bytes(bytes),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Join(trades, quotes, range, tolerance) =>
+            {
+                match state.join_quotes(&trades, &quotes, range, tolerance) {
+                    Ok(json) => ReturnType::String(json),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Series(dbname, range, interval) =>
+            {
+                match state.aggregate_series(&dbname, range, interval) {
+                    Ok(json) => ReturnType::String(json),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Approx(aggregate, dbname, range, error) =>
+            {
+                match state.approx(aggregate, &dbname, range, error) {
+                    Ok(json) => ReturnType::String(json),
+                    Err(e) => return_err(&e)
+                }
+            },
+        TopN(dbname, n, by, range) =>
+            {
+                match state.topn(&dbname, n, by, range) {
+                    Ok(json) => ReturnType::String(json),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Hist(dbname, field, buckets, range, filter) =>
+            {
+                let filter = match state.resolve_flags(&[dbname.clone()], filter) {
+                    Ok(filter) => filter,
+                    Err(e) => return return_err(&e)
+                };
+                match state.hist(&dbname, field, buckets, range, filter) {
+                    Ok(json) => ReturnType::String(json),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Subscribe(dbname, from, filter) =>
+            {
+                let stores = vec![dbname];
+                let filter = match state.resolve_flags(&stores, filter) {
+                    Ok(filter) => filter,
+                    Err(e) => return return_err(&e)
+                };
+                match state.subscribe(&stores, from) {
+                    Ok(offset) => ReturnType::Subscribe(SubscribeQuery { stores, group: false, offset, filter }),
+                    Err(e) => return_err(&e)
+                }
+            },
+        SubscribeCdc(from) =>
+            {
+                match state.subscribe_cdc(from) {
+                    Ok(offset) => ReturnType::Cdc(offset),
+                    Err(e) => return_err(&e)
+                }
+            },
+        SubscribeGroup(group_name, from, filter) =>
+            {
+                let stores = match state.group_stores(&group_name) {
+                    Ok(stores) => stores,
+                    Err(e) => return return_err(&e)
+                };
+                let filter = match state.resolve_flags(&stores, filter) {
+                    Ok(filter) => filter,
+                    Err(e) => return return_err(&e)
+                };
+                match state.subscribe(&stores, from) {
+                    Ok(offset) => ReturnType::Subscribe(SubscribeQuery { stores, group: true, offset, filter }),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Tier(dbname, cold_folder, ms) =>
+            {
+                match state.set_tier(&dbname, &cold_folder, ms) {
+                    Ok(()) => return_string("1"),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Backup(folder, incremental) =>
+            {
+                match state.backup(&folder, incremental) {
+                    Ok(report) => return_string(&report),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Alert(rule) =>
+            {
+                match state.add_alert(rule) {
+                    Ok(n) => return_string(&n.to_string()),
+                    Err(e) => return_err(&e)
+                }
+            },
+        AlertDrop(n) =>
+            {
+                match state.drop_alert(n) {
+                    Ok(()) => return_string("1"),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Alerts => ReturnType::String(state.alerts()),
+        ConfigGet(key) =>
+            {
+                match state.config_get(key.as_ref().map(|k| k.as_str())) {
+                    Ok(config) => ReturnType::String(config),
+                    Err(e) => return_err(&e)
+                }
+            },
+        ConfigSet(key, value) =>
+            {
+                match state.config_set(&key, &value) {
+                    Ok(()) => return_string("1"),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Multicast(dbname, on) =>
+            {
+                match state.set_multicast(&dbname, on) {
+                    Ok(()) => return_string("1"),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Precision(dbname, decimals) =>
+            {
+                match state.set_precision(&dbname, decimals) {
+                    Ok(()) => return_string("1"),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Flags(dbname, names) =>
+            {
+                match state.set_flags(&dbname, names) {
+                    Ok(json) => ReturnType::String(json),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Meta(dbname, tags) =>
+            {
+                match state.set_tags(&dbname, &tags) {
+                    Ok(json) => ReturnType::String(json),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Find(tags) =>
+            ReturnType::String(state.find(&tags)),
+        Group(group_name, pattern) =>
+            {
+                match state.create_group(&group_name, &pattern) {
+                    Ok(()) => return_string("1"),
+                    Err(e) => return_err(&e)
+                }
+            },
+        GroupDrop(group_name) =>
+            {
+                match state.drop_group(&group_name) {
+                    Ok(()) => return_string("1"),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Groups => ReturnType::String(state.groups()),
+        FlushGroup(group_name) =>
+            {
+                match state.flush_group(&group_name) {
+                    Ok(n) => return_string(&format!("{}", n)),
+                    Err(e) => return_err(&e)
+                }
+            },
+        ClearGroup(group_name) =>
+            {
+                match state.clear_group(&group_name) {
+                    Ok(n) => return_string(&format!("{}", n)),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Retain(dbname, ttl) =>
+            {
+                match state.retain(&[dbname], ttl) {
+                    Ok(()) => return_string("1"),
+                    Err(e) => return_err(&e)
+                }
+            },
+        RetainGroup(group_name, ttl) =>
+            {
+                let stores = match state.group_stores(&group_name) {
+                    Ok(stores) => stores,
+                    Err(e) => return return_err(&e)
+                };
+                match state.retain(&stores, ttl) {
+                    Ok(()) => return_string(&format!("{}", stores.len())),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Alias(alias, dbname) =>
+            {
+                match state.create_alias(&alias, &dbname) {
+                    Ok(()) => return_string(&format!("`{}` is now an alias of `{}`.", alias, state.resolve(&alias))),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Copy(src, dst, range, filter) =>
+            {
+                let filter = match state.resolve_flags(&[src.clone()], filter) {
+                    Ok(filter) => filter,
+                    Err(e) => return return_err(&e)
+                };
+                match state.copy(&src, &dst, range, &filter) {
+                    Ok(copied) => return_string(&format!("{}", copied)),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Correct(dbname, up) =>
+            {
+                match state.correct(&dbname, up) {
+                    Ok(()) => return_string("1"),
+                    Err(e) => return_err(&e)
+                }
+            },
+        Format(encoding) =>
+            {
+                state.encoding = encoding;
+                return_string(&format!("FORMAT {:?}", encoding))
+            },
+        Hello(hello) =>
+            {
+                match state.hello(hello) {
+                    Ok(json) => return_string(&json),
+                    Err(e) => return_err(&e)
+                }
+            },
 
         // get
-        Get(ReqCount::All, GetFormat::JSON, _) => 
+        Get(count, GetFormat::JSON(decimals), range, Some((limit, cursor))) =>
             {
-                match state.get_n_as_json(None) {
+                let count = match count { ReqCount::All => None, ReqCount::Count(c) => Some(c) };
+                return_string(&state.get_page_as_json(count, range, limit, cursor, decimals))
+            },
+        Get(count, GetFormat::JSONL(decimals), range, Some((limit, cursor))) =>
+            {
+                let count = match count { ReqCount::All => None, ReqCount::Count(c) => Some(c) };
+                ReturnType::String(state.get_page_as_jsonl(count, range, limit, cursor, decimals))
+            },
+        Get(count, GetFormat::DTF, range, Some((limit, cursor))) =>
+            {
+                let count = match count { ReqCount::All => None, ReqCount::Count(c) => Some(c) };
+                return_bytes(state.get_page_as_bytes(count, range, limit, cursor))
+            },
+        GetAsOf(count, format, range, asof) =>
+            {
+                let count = match count { ReqCount::All => None, ReqCount::Count(c) => Some(c) };
+                match (state.get_asof(count, range, asof), format) {
+                    (Err(e), _) => return_err(&e),
+                    (Ok(ups), GetFormat::JSON(decimals)) =>
+                        return_string(&format!("[{}]\n", dtf::update_vec_to_json_with(&ups, state.decimals(decimals)))),
+                    (Ok(ups), GetFormat::JSONL(decimals)) =>
+                        return_string(&dtf::update_vec_to_jsonl_with(&ups, state.decimals(decimals))),
+                    (Ok(ref ups), _) if state.encoding == Encoding::Msgpack =>
+                        return_bytes(dtf::encode_msgpack(ups)),
+                    (Ok(ref ups), _) if ups.is_empty() =>
+                        return_err("No items to return."),
+                    (Ok(ups), _) =>
+                        {
+                            let mut bytes : Vec<u8> = Vec::new();
+                            dtf::write_batches(&mut bytes, &ups);
+                            return_bytes(bytes)
+                        }
+                }
+            },
+        Get(_, GetFormat::Stream(_), _, Some(_)) =>
+            return_err("STREAM cannot be combined with LIMIT."),
+        Get(count, GetFormat::Stream(window), range, None) =>
+            {
+                let count = match count { ReqCount::All => None, ReqCount::Count(c) => Some(c) };
+                ReturnType::Stream(StreamQuery { count, range, window })
+            },
+        Get(ReqCount::All, GetFormat::JSON(decimals), range, None) => 
+            {
+                match state.get_n_as_json(None, range, decimals) {
                     Some(json) => return_string(&json),
                     None => return_err("Not enough items to return."),
                 }
             },
-        Get(ReqCount::All, GetFormat::DTF, _) => 
+        Get(ReqCount::All, GetFormat::JSONL(decimals), range, None) =>
+            {
+                match state.get_n_as_jsonl(None, range, decimals) {
+                    Some(jsonl) => ReturnType::String(jsonl),
+                    None => return_err("Not enough items to return."),
+                }
+            },
+        Get(ReqCount::All, GetFormat::DTF, range, None) => 
             {
-                match state.get(None) {
+                match state.get(None, range) {
                     Some(bytes) => return_bytes(bytes),
                     None => return_err("Failed to GET ALL.")
                 }
             },
-        Get(ReqCount::Count(count), GetFormat::JSON, range) => 
+        Get(ReqCount::Count(count), GetFormat::JSON(decimals), range, None) => 
             {
-                match range {
-                    Some((min, max)) => unimplemented!(),
-                    None => {
-                        match state.get_n_as_json(Some(count)) {
-                            Some(json) => return_string(&json),
-                            None => return_err(&format!("Requested {} items. Too many.", count))
-                        }
-                    }
+                match state.get_n_as_json(Some(count), range, decimals) {
+                    Some(json) => return_string(&json),
+                    None => return_err(&format!("Requested {} items. Too many.", count))
                 }
             }
 
-        Get(ReqCount::Count(count), GetFormat::DTF, range) => 
+        Get(ReqCount::Count(count), GetFormat::JSONL(decimals), range, None) =>
             {
-                match range {
-                    Some((min, max)) => unimplemented!(),
-                    None => {
-                        match state.get(Some(count)) {
-                            Some(bytes) => return_bytes(bytes),
-                            None => return_string(&format!("Failed to get {}.", count))
-                        }
-                    }
+                match state.get_n_as_jsonl(Some(count), range, decimals) {
+                    Some(jsonl) => ReturnType::String(jsonl),
+                    None => return_err(&format!("Requested {} items. Too many.", count))
+                }
+            }
+
+        Get(ReqCount::Count(count), GetFormat::DTF, range, None) => 
+            {
+                match state.get(Some(count), range) {
+                    Some(bytes) => return_bytes(bytes),
+                    None => return_string(&format!("Failed to get {}.", count))
                 }
             }
 
-        Unknown => 
-            return_err("Unknown command.")
+        Unknown =>
+            {
+                let extensions = state.global.read().unwrap().extensions.clone();
+                match extensions.find(string).map(|extension| extension.handle(string, state)) {
+                    Some(Ok(resp)) => return_string(&resp),
+                    Some(Err(e)) => return_err(&e),
+                    None => return_err("Unknown command.")
+                }
+            }
+    };
+
+    if let Some(key) = cache_key {
+        match resp {
+            ReturnType::String(ref s) => state.cache(key, generation, cache::Response::String(s.clone())),
+            ReturnType::Bytes(ref b) => state.cache(key, generation, cache::Response::Bytes(b.clone())),
+            _ => ()
+        }
     }
+    resp
+}
+
+/// key of the responses kept in the result cache, None for the commands not cached
+fn cache_key(command: &Command, state: &State) -> Option<cache::Key> {
+    let (count, format, range, epoch) = match *command {
+        Command::Get(_, GetFormat::Stream(_), _, _) => return None,
+        Command::Get(ref count, ref format, range, None) => (count, format, range, None),
+        Command::GetAsOf(ref count, ref format, range, asof) => (count, format, range, Some(asof)),
+        _ => return None
+    };
+    Some(cache::Key {
+        store: state.current_store_name.clone(),
+        range: range.map(|(min, max)| (u64::from(min) * 1000, u64::from(max) * 1000)),
+        query: format!("{:?} {:?} {:?} {:?}", count, format, state.encoding, state.decimals(None)),
+        epoch,
+    })
 }
 
 fn return_string(string: &str) -> ReturnType {
-    let mut ret = String::new();
+    let mut ret = String::with_capacity(string.len() + 1);
     ret.push_str(string);
     ret.push_str("\n");
     ReturnType::String(ret)
@@ -299,7 +1563,7 @@ fn return_bytes(bytes: Vec<u8>) -> ReturnType {
 }
 
 fn return_err(err: &str) -> ReturnType {
-    let mut ret = String::new();
+    let mut ret = String::with_capacity(err.len() + 1);
     ret.push_str(err);
     ret.push_str("\n");
     ReturnType::Error(ret)
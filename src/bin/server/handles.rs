@@ -0,0 +1,101 @@
+/// File handles
+///
+/// Files the server appends to over and over, like the subscription feeds,
+/// are kept open between writes. With thousands of stores that would exhaust
+/// the file descriptors, so at most `--max_open_files` handles are kept open
+/// and the least recently used one is closed to open another.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io;
+
+#[derive(Debug)]
+pub struct FileHandles {
+    cap: usize,
+    /// fname -> (handle, last use)
+    open: HashMap<String, (File, u64)>,
+    /// last use -> fname
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl FileHandles {
+    pub fn new(cap: usize) -> FileHandles {
+        FileHandles {
+            cap,
+            open: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// handle to append to `fname`, created if needed
+    pub fn append(&mut self, fname: &str) -> Result<&mut File, io::Error> {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(&mut (_, ref mut last_use)) = self.open.get_mut(fname) {
+            self.lru.remove(last_use);
+            *last_use = tick;
+        } else {
+            let file = OpenOptions::new().create(true).append(true).open(fname)?;
+            while !self.open.is_empty() && self.open.len() >= self.cap {
+                self.close_lru();
+            }
+            self.open.insert(fname.to_owned(), (file, tick));
+        }
+        self.lru.insert(tick, fname.to_owned());
+        Ok(&mut self.open.get_mut(fname).unwrap().0)
+    }
+
+    fn close_lru(&mut self) {
+        let oldest = self.lru.keys().next().cloned();
+        if let Some(fname) = oldest.and_then(|tick| self.lru.remove(&tick)) {
+            debug!("Closing {}, {} files are open", fname, self.open.len());
+            self.open.remove(&fname);
+        }
+    }
+
+    pub fn close(&mut self, fname: &str) {
+        if let Some((_, last_use)) = self.open.remove(fname) {
+            self.lru.remove(&last_use);
+        }
+    }
+
+    /// number of open handles
+    pub fn count(&self) -> usize {
+        self.open.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn should_close_the_least_recently_used_handle() {
+        let fnames = ["test-handles-a.txt", "test-handles-b.txt", "test-handles-c.txt"];
+        let mut handles = FileHandles::new(2);
+        handles.append(fnames[0]).unwrap().write_all(b"a").unwrap();
+        handles.append(fnames[1]).unwrap().write_all(b"b").unwrap();
+        // `a` is used again, `b` is the least recently used
+        handles.append(fnames[0]).unwrap().write_all(b"a").unwrap();
+        handles.append(fnames[2]).unwrap().write_all(b"c").unwrap();
+        assert_eq!(handles.count(), 2);
+        assert!(handles.open.contains_key(fnames[0]));
+        assert!(!handles.open.contains_key(fnames[1]));
+
+        // a closed file is appended to, not truncated, when opened again
+        handles.append(fnames[1]).unwrap().write_all(b"b").unwrap();
+        assert_eq!(handles.count(), 2);
+        handles.close(fnames[1]);
+        assert_eq!(handles.count(), 1);
+        assert_eq!(fs::read_to_string(fnames[0]).unwrap(), "aa");
+        assert_eq!(fs::read_to_string(fnames[1]).unwrap(), "bb");
+
+        for fname in &fnames {
+            let _ = fs::remove_file(fname);
+        }
+    }
+}
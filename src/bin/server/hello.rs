@@ -0,0 +1,127 @@
+/// Connection handshake
+///
+/// Connections of a pool or behind a proxy are reused by other clients,
+/// which find the store of the last `USE` and the encoding of the last
+/// `FORMAT`. A client sends the settings of its connection in a `HELLO`
+/// first instead:
+///
+///     HELLO protocol=1 store=binance_btc_usdt format=binary
+///
+/// `store` is the store `ADD`, `BULKADD` and the reads of the current store
+/// use, as after `USE`, created if `--autocreate_stores` is on. `format` is
+/// the encoding of binary responses and BULKADD rows, as after `FORMAT`:
+/// `binary` (the dtf batches, also `dtf`), `flat` or `msgpack`. Settings left
+/// out are kept, a `HELLO` that fails changes none.
+///
+/// `protocol` is the newest version of the protocol the client speaks. The
+/// server answers with the version both speak, the lower of the two, and
+/// keeps answering a client of an older version as that version did. A
+/// client that never sends `HELLO` gets version 1. `CONNECTIONS` shows the
+/// version of every connection. The response is the
+/// settings of the connection with what the server supports:
+///
+///     {"version": "0.1.7", "protocol": 1, "store": "binance_btc_usdt", "format": "binary",
+///      "formats": ["binary", "flat", "msgpack"],
+///      "features": {"compression": false, "subscribe": true, "tls": false, ...},
+///      "limits": {"max_name_len": 64, "max_upload_bytes": 1073741824, ...}}
+///
+/// with `"store": null` until a store is chosen under `--default_store none`.
+/// Clients ignore the keys they do not know, a feature or limit is added
+/// without a new version, a change to a command or response is a new version.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use serde_json;
+use dtf::update::MAX_USER_FLAGS;
+use parser::MAX_NAME_LEN;
+use schema::VERSION;
+use server::MAX_UPLOAD_BYTES;
+use settings::Settings;
+use state::Encoding;
+
+/// newest version of the protocol
+pub static PROTOCOL_VERSION : u32 = 1;
+
+/// The settings of a `HELLO`, None for those left out
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Hello {
+    pub protocol: Option<u32>,
+    pub store: Option<String>,
+    pub format: Option<Encoding>,
+}
+
+/// `format=` of an encoding
+pub fn parse_format(name: &str) -> Option<Encoding> {
+    match name {
+        "binary" | "dtf" => Some(Encoding::DTF),
+        "flat" => Some(Encoding::Flat),
+        "msgpack" => Some(Encoding::Msgpack),
+        _ => None
+    }
+}
+
+fn format_name(encoding: Encoding) -> &'static str {
+    match encoding {
+        Encoding::DTF => "binary",
+        Encoding::Flat => "flat",
+        Encoding::Msgpack => "msgpack",
+    }
+}
+
+/// version of the protocol spoken with a client speaking up to `protocol`
+pub fn negotiate(protocol: u32) -> u32 {
+    cmp::min(protocol, PROTOCOL_VERSION)
+}
+
+#[derive(Serialize)]
+struct Welcome<'a> {
+    version: &'static str,
+    protocol: u32,
+    store: Option<&'a str>,
+    format: &'static str,
+    formats: Vec<&'static str>,
+    features: BTreeMap<&'static str, bool>,
+    limits: BTreeMap<&'static str, u64>,
+}
+
+/// features a client can check before using them, `tls` if a listener accepts TLS
+fn features(settings: &Settings) -> BTreeMap<&'static str, bool> {
+    let mut features = BTreeMap::new();
+    // responses are never compressed
+    features.insert("compression", false);
+    features.insert("subscribe", true);
+    features.insert("cdc", true);
+    features.insert("stream", true);
+    features.insert("transactions", true);
+    features.insert("idempotency", true);
+    features.insert("upload", true);
+    features.insert("jobs", true);
+    features.insert("tls", settings.listeners.iter().any(|listen| listen.tls.is_some()));
+    features
+}
+
+/// limits of the server, 0 is unlimited
+fn limits(settings: &Settings) -> BTreeMap<&'static str, u64> {
+    let mut limits = BTreeMap::new();
+    limits.insert("max_name_len", MAX_NAME_LEN as u64);
+    limits.insert("max_upload_bytes", MAX_UPLOAD_BYTES);
+    // number of user flag bits
+    limits.insert("max_flags", u64::from(MAX_USER_FLAGS.count_ones()));
+    limits.insert("max_memory", settings.max_memory);
+    limits.insert("query_timeout", settings.query_timeout);
+    limits
+}
+
+/// the response to a `HELLO`
+pub fn to_json(protocol: u32, store: Option<&str>, encoding: Encoding, settings: &Settings) -> String {
+    let welcome = Welcome {
+        version: VERSION,
+        protocol,
+        store,
+        format: format_name(encoding),
+        formats: vec!["binary", "flat", "msgpack"],
+        features: features(settings),
+        limits: limits(settings),
+    };
+    serde_json::to_string(&welcome).unwrap_or_default()
+}
@@ -0,0 +1,53 @@
+/// Histograms
+///
+/// `HIST [db] FIELD [price|size|notional] BUCKETS [n] [FROM ts TO ts] [WHERE ...]`
+/// counts the rows of a range by value on the server, in `n` buckets of
+/// equal width from the smallest to the largest value, at most 10000:
+///
+///     HIST btc_usdt FIELD size BUCKETS 50 WHERE is_trade = t
+///     [{"lower":0.001,"upper":0.2,"count":18238}, ...]
+///
+/// The buckets hold [lower, upper), the last one also `upper`. Every worker
+/// of the scan (see `scan::fold`) counts the rows of each value, the counts
+/// are bucketed once merged.
+
+use dtf::Update;
+use dtf::distribution::Bin;
+
+/// most buckets of a histogram
+pub static MAX_BUCKETS : usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Price,
+    Size,
+    /// price * size
+    Notional,
+}
+
+impl Field {
+    pub fn parse(name: &str) -> Option<Field> {
+        match name {
+            "price" => Some(Field::Price),
+            "size" => Some(Field::Size),
+            "notional" => Some(Field::Notional),
+            _ => None
+        }
+    }
+
+    pub fn value(self, up: &Update) -> f32 {
+        match self {
+            Field::Price => up.price,
+            Field::Size => up.size,
+            Field::Notional => up.price * up.size,
+        }
+    }
+}
+
+/// the buckets of `HIST` as a JSON array
+pub fn to_json(bins: &[Bin]) -> String {
+    let rows : Vec<String> = bins.iter()
+        .map(|bin| format!(r#"{{"lower":{},"upper":{},"count":{}}}"#, bin.lower as f32, bin.upper as f32, bin.count))
+        .collect();
+    format!("[{}]\n", rows.join(","))
+}
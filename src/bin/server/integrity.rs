@@ -0,0 +1,117 @@
+/// Startup integrity scan
+///
+/// With `--integrity_scan fast|deep` every dtf file in dtf_folder is checked
+/// before the server accepts connections, see `IntegrityScan`. A corrupt file
+/// is moved with its sidecars (`[fname].dtf.*`) to `[dtf_folder]/corrupt`, so
+/// the server starts without it instead of failing on every read of the store.
+///
+/// Findings are logged and reported under `integrity` by INFO. The scan of
+/// `JOB INTEGRITY` runs while the files are in use, so it only reports them.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use dtf;
+use filestats;
+use settings::IntegrityScan;
+use utils;
+
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub fname: String,
+    pub problem: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub mode: IntegrityScan,
+    pub scanned: usize,
+    pub corrupt: Vec<Finding>,
+}
+
+impl Report {
+    pub fn to_json(&self) -> String {
+        let corrupt : Vec<String> = self.corrupt.iter()
+            .map(|finding| format!(r#"{{"file": "{}", "problem": "{}"}}"#,
+                finding.fname, finding.problem.replace('"', "'")))
+            .collect();
+        format!(r#"{{"mode": "{}", "scanned": {}, "corrupt": [{}]}}"#,
+            format!("{:?}", self.mode).to_lowercase(), self.scanned, corrupt.join(", "))
+    }
+}
+
+fn corrupt_folder(dtf_folder: &str) -> String {
+    utils::join(dtf_folder, "corrupt")
+}
+
+/// check the dtf files of dtf_folder, with `quarantine` moving the corrupt ones aside
+pub fn scan(dtf_folder: &str, mode: IntegrityScan, quarantine: bool) -> Report {
+    let mut report = Report { mode, scanned: 0, corrupt: Vec::new() };
+    if mode == IntegrityScan::Off {
+        return report;
+    }
+    let entries = match fs::read_dir(dtf_folder) {
+        Ok(entries) => entries,
+        Err(_) => return report
+    };
+    let mut fnames : Vec<String> = entries.filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.ends_with(".dtf"))
+        .collect();
+    fnames.sort();
+
+    for name in fnames {
+        let fname = utils::join(dtf_folder, &name);
+        report.scanned += 1;
+        let problem = match check(&fname, mode) {
+            Ok(()) => continue,
+            Err(problem) => problem
+        };
+        error!("{} is corrupt: {}", fname, problem);
+        if quarantine {
+            match move_aside(dtf_folder, &name) {
+                Ok(()) => warn!("Moved {} to {}", name, corrupt_folder(dtf_folder)),
+                Err(e) => error!("Unable to move {} to {}: {:?}", name, corrupt_folder(dtf_folder), e)
+            }
+        }
+        report.corrupt.push(Finding { fname: name, problem });
+    }
+    info!("Integrity scan ({:?}) of {} files, {} corrupt", mode, report.scanned, report.corrupt.len());
+    report
+}
+
+fn check(fname: &str, mode: IntegrityScan) -> Result<(), String> {
+    dtf::verify(fname)?;
+    if mode != IntegrityScan::Deep {
+        return Ok(());
+    }
+    let mut buf = Vec::new();
+    File::open(fname)
+        .and_then(|mut file| file.read_to_end(&mut buf))
+        .map_err(|e| e.to_string())?;
+    let rows = dtf::decode_buffer(&buf).map_err(|e| e.to_string())?;
+    if let Some(stats) = filestats::read(fname) {
+        let checksum = filestats::checksum(0, &rows);
+        if checksum != stats.checksum {
+            return Err(format!("checksum {:08x} of the rows, {:08x} in the stats sidecar", checksum, stats.checksum));
+        }
+    }
+    Ok(())
+}
+
+/// move a dtf file and its sidecars to the corrupt folder
+fn move_aside(dtf_folder: &str, name: &str) -> Result<(), io::Error> {
+    let folder = corrupt_folder(dtf_folder);
+    fs::create_dir_all(&folder)?;
+    let sidecar_prefix = format!("{}.", name);
+    for entry in fs::read_dir(dtf_folder)? {
+        let entry_name = match entry?.file_name().into_string() {
+            Ok(entry_name) => entry_name,
+            Err(_) => continue
+        };
+        if entry_name == name || entry_name.starts_with(&sidecar_prefix) {
+            fs::rename(Path::new(dtf_folder).join(&entry_name), Path::new(&folder).join(&entry_name))?;
+        }
+    }
+    Ok(())
+}
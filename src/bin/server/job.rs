@@ -0,0 +1,369 @@
+/// Admin jobs
+///
+/// Long-running maintenance runs as a job in a thread of its own, so neither
+/// the connection nor the shared state is held while it runs. `JOB [command]`
+/// starts one and returns `{"job": 3}` at once:
+///
+///     JOB COMPACT btc_usdt
+///     JOB PRUNE
+///     JOB INTEGRITY fast|deep
+///     JOB BACKUP TO /mnt/backups/tectonic [INCREMENTAL]
+///
+/// as do `FLUSH ALL ASYNC` and `CLEAR ALL ASYNC`, which flush or clear the
+/// stores JOB_CHUNK stores at a time. `PRUNE` is a pass of the retention
+/// worker, `INTEGRITY` checks the dtf files like `--integrity_scan` but only
+/// reports the corrupt ones under `integrity` of INFO, they are quarantined
+/// on the next start with the scan. At most `--max_jobs` jobs run at once,
+/// the others are queued. `JOB STATUS [id]` shows a job:
+///
+///     {"id": 3, "command": "FLUSH ALL", "status": "running", "done": 1280, "total": 4096, "queued_ms": 0, "running_ms": 5210}
+///
+/// with its `"result"` or `"error"` once finished, and `JOBS` every job kept,
+/// the newest first. `JOB CANCEL [id]` drops a queued job and stops a flush
+/// or clear between two chunks, the stores done stay done. The last
+/// MAX_FINISHED finished jobs are kept. The runs, failures and time taken
+/// per command are reported under `jobs` by INFO.
+///
+/// Jobs are also started on a schedule, see `Cron` and the scheduler plugin.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use settings::IntegrityScan;
+
+/// stores flushed or cleared under one lock of the shared state
+pub static JOB_CHUNK : usize = 64;
+
+/// finished jobs kept for `JOB STATUS` and `JOBS`
+static MAX_FINISHED : usize = 100;
+
+/// how often a queued job checks whether it was cancelled or `--max_jobs` changed
+static QUEUE_POLL : u64 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kind {
+    FlushAll,
+    ClearAll,
+    Compact(String),
+    Prune,
+    Integrity(IntegrityScan),
+    /// folder, incremental
+    Backup(String, bool),
+}
+
+impl Kind {
+    /// the command without its arguments, metrics are kept per name
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Kind::FlushAll => "FLUSH ALL",
+            Kind::ClearAll => "CLEAR ALL",
+            Kind::Compact(_) => "COMPACT",
+            Kind::Prune => "PRUNE",
+            Kind::Integrity(_) => "INTEGRITY",
+            Kind::Backup(..) => "BACKUP",
+        }
+    }
+
+    /// goes through the stores in chunks and can be stopped between two
+    pub fn is_chunked(&self) -> bool {
+        *self == Kind::FlushAll || *self == Kind::ClearAll
+    }
+}
+
+/// the command as `JOB [command]` reads it
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Kind::Compact(ref store) => write!(f, "COMPACT {}", store),
+            Kind::Integrity(mode) => write!(f, "INTEGRITY {}", format!("{:?}", mode).to_lowercase()),
+            Kind::Backup(ref folder, incremental) =>
+                write!(f, "BACKUP TO {}{}", folder, if incremental { " INCREMENTAL" } else { "" }),
+            _ => write!(f, "{}", self.name())
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl Status {
+    fn name(&self) -> &'static str {
+        match *self {
+            Status::Queued => "queued",
+            Status::Running => "running",
+            Status::Done => "done",
+            Status::Failed => "failed",
+            Status::Cancelled => "cancelled",
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        *self != Status::Queued && *self != Status::Running
+    }
+}
+
+#[derive(Debug)]
+struct Progress {
+    status: Status,
+    queued: Instant,
+    started: Option<Instant>,
+    /// once finished
+    running_ms: Option<u64>,
+    /// message or error once finished
+    result: Option<Result<String, String>>,
+}
+
+#[derive(Debug)]
+pub struct Job {
+    pub id: u64,
+    pub kind: Kind,
+    /// stores or steps to go through
+    pub total: usize,
+    /// stores or steps gone through
+    done: AtomicUsize,
+    cancelled: AtomicBool,
+    progress: Mutex<Progress>,
+}
+
+impl Job {
+    pub fn new(id: u64, kind: Kind, total: usize) -> Job {
+        Job {
+            id,
+            kind,
+            total,
+            done: AtomicUsize::new(0),
+            cancelled: AtomicBool::new(false),
+            progress: Mutex::new(Progress {
+                status: Status::Queued,
+                queued: Instant::now(),
+                started: None,
+                running_ms: None,
+                result: None,
+            }),
+        }
+    }
+
+    /// `n` more stores or steps were gone through
+    pub fn progress(&self, n: usize) {
+        self.done.fetch_add(n, Ordering::SeqCst);
+    }
+
+    /// drop the job if queued, stop it between two chunks if running
+    pub fn cancel(&self) -> Result<(), String> {
+        let status = self.progress.lock().unwrap().status.clone();
+        match status {
+            Status::Queued => {},
+            Status::Running if self.kind.is_chunked() => {},
+            Status::Running => return Err(format!("Job {} cannot be stopped once running.", self.id)),
+            _ => return Err(format!("Job {} is not running.", self.id))
+        }
+        self.cancelled.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn done(&self) -> usize {
+        self.done.load(Ordering::SeqCst)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn begin(&self) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.status = Status::Running;
+        progress.started = Some(Instant::now());
+    }
+
+    pub fn finish(&self, result: Result<String, String>) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.status = match result {
+            _ if self.is_cancelled() => Status::Cancelled,
+            Ok(_) => Status::Done,
+            Err(_) => Status::Failed,
+        };
+        progress.running_ms = Some(progress.started.map_or(0, elapsed_ms));
+        progress.result = Some(result);
+    }
+
+    pub fn status(&self) -> Status {
+        self.progress.lock().unwrap().status.clone()
+    }
+
+    /// {"id": 3, "command": "COMPACT btc_usdt", "status": "done", "done": 1, "total": 1, "queued_ms": 0, "running_ms": 840, "result": "Removed 12 rows."}
+    pub fn to_json(&self) -> String {
+        let progress = self.progress.lock().unwrap();
+        let queued_ms = match progress.started {
+            Some(started) => duration_ms(started.duration_since(progress.queued)),
+            None if progress.status == Status::Queued => elapsed_ms(progress.queued),
+            None => 0
+        };
+        let running_ms = progress.running_ms.unwrap_or_else(|| progress.started.map_or(0, elapsed_ms));
+        let result = match progress.result {
+            Some(Ok(ref message)) if !message.is_empty() => format!(r#", "result": "{}""#, escape(message)),
+            Some(Err(ref e)) => format!(r#", "error": "{}""#, escape(e)),
+            _ => String::new()
+        };
+        format!(r#"{{"id": {}, "command": "{}", "status": "{}", "done": {}, "total": {}, "queued_ms": {}, "running_ms": {}{}}}"#,
+            self.id, escape(&self.kind.to_string()), progress.status.name(), self.done(),
+            self.total, queued_ms, running_ms, result)
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn duration_ms(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+fn elapsed_ms(since: Instant) -> u64 {
+    duration_ms(since.elapsed())
+}
+
+/// The jobs running, of at most `--max_jobs`
+#[derive(Debug, Default)]
+pub struct Slots {
+    running: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl Slots {
+    /// wait until fewer than `max_jobs()` jobs run, false if `job` was cancelled meanwhile
+    pub fn acquire<F: Fn() -> usize>(&self, job: &Job, max_jobs: F) -> bool {
+        let mut running = self.running.lock().unwrap();
+        loop {
+            if job.is_cancelled() {
+                return false;
+            }
+            if *running < max_jobs() {
+                *running += 1;
+                return true;
+            }
+            running = self.freed.wait_timeout(running, Duration::from_secs(QUEUE_POLL)).unwrap().0;
+        }
+    }
+
+    pub fn release(&self) {
+        *self.running.lock().unwrap() -= 1;
+        self.freed.notify_one();
+    }
+}
+
+/// Runs of a command
+#[derive(Debug, Clone, Default)]
+struct Metric {
+    done: u64,
+    failed: u64,
+    cancelled: u64,
+    total_ms: u64,
+}
+
+/// The queued, running and recently finished jobs
+#[derive(Debug, Default)]
+pub struct Jobs {
+    last_id: u64,
+    jobs: BTreeMap<u64, Arc<Job>>,
+    pub slots: Arc<Slots>,
+    metrics: BTreeMap<&'static str, Metric>,
+}
+
+impl Jobs {
+    /// register a job over `total` stores or steps
+    pub fn start(&mut self, kind: Kind, total: usize) -> Arc<Job> {
+        let finished : Vec<u64> = self.jobs.values().filter(|job| job.status().is_finished()).map(|job| job.id).collect();
+        for id in finished.iter().take(finished.len().saturating_sub(MAX_FINISHED - 1)) {
+            self.jobs.remove(id);
+        }
+        self.last_id += 1;
+        let job = Arc::new(Job::new(self.last_id, kind, total));
+        self.jobs.insert(job.id, job.clone());
+        job
+    }
+
+    /// count a finished job in the metrics
+    pub fn finished(&mut self, job: &Job) {
+        let progress = job.progress.lock().unwrap();
+        let metric = self.metrics.entry(job.kind.name()).or_default();
+        match progress.status {
+            Status::Done => metric.done += 1,
+            Status::Failed => metric.failed += 1,
+            Status::Cancelled => metric.cancelled += 1,
+            _ => {}
+        }
+        metric.total_ms += progress.running_ms.unwrap_or(0);
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Arc<Job>> {
+        self.jobs.get(&id)
+    }
+
+    /// a job of the same command is queued or running
+    pub fn is_pending(&self, kind: &Kind) -> bool {
+        self.jobs.values().any(|job| job.kind == *kind && !job.status().is_finished())
+    }
+
+    /// every job kept, the newest first
+    pub fn to_json(&self) -> String {
+        let jobs : Vec<String> = self.jobs.values().rev().map(|job| job.to_json()).collect();
+        format!("[{}]\n", jobs.join(", "))
+    }
+
+    /// {"queued": 0, "running": 1, "commands": {"COMPACT": {"done": 4, "failed": 0, "cancelled": 0, "total_ms": 1520}}}
+    pub fn metrics(&self) -> String {
+        let count = |status: Status| self.jobs.values().filter(|job| job.status() == status).count();
+        let commands : Vec<String> = self.metrics.iter()
+            .map(|(name, metric)| format!(r#""{}": {{"done": {}, "failed": {}, "cancelled": {}, "total_ms": {}}}"#,
+                name, metric.done, metric.failed, metric.cancelled, metric.total_ms))
+            .collect();
+        format!(r#"{{"queued": {}, "running": {}, "commands": {{{}}}}}"#,
+            count(Status::Queued), count(Status::Running), commands.join(", "))
+    }
+}
+
+/// When a scheduled job runs, the five fields of a crontab line in UTC:
+/// minute, hour, day of the month, month and day of the week (0 is
+/// Sunday), each `*`, a number, a range `a-b`, a step `*/n` or `a-b/n`, or
+/// a comma separated list of those. As in cron, if both days are restricted
+/// a day matching either runs the job.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    /// the fields as written
+    pub spec: String,
+    pub minutes: u64,
+    pub hours: u32,
+    pub days: u32,
+    pub months: u16,
+    pub weekdays: u8,
+    /// day of the month and day of the week are not `*`
+    pub both_days: bool,
+}
+
+impl Cron {
+    /// whether the job runs in the minute of `secs` since epoch
+    pub fn matches(&self, secs: u64) -> bool {
+        let time = NaiveDateTime::from_timestamp(secs as i64, 0);
+        let day = self.days & (1 << time.day()) != 0;
+        let weekday = self.weekdays & (1 << time.weekday().num_days_from_sunday()) != 0;
+        self.minutes & (1 << time.minute()) != 0
+            && self.hours & (1 << time.hour()) != 0
+            && self.months & (1 << time.month()) != 0
+            && if self.both_days { day || weekday } else { day && weekday }
+    }
+}
+
+impl fmt::Display for Cron {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.spec)
+    }
+}
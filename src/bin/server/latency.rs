@@ -0,0 +1,189 @@
+/// Command latencies
+///
+/// The time the handler takes for each command, from parsing to the
+/// response being ready, is recorded into a histogram per command type.
+/// Sending the response is not included, so slow clients do not skew it.
+/// `PERF LATENCY` shows the histograms, `PERF LATENCY RESET` starts over,
+/// e.g. to compare before and after a config change.
+///
+/// The latency of inserts is also averaged into the ingest lag, from which
+/// `--shed_reads_above` refuses full scans.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// command types recorded on their own, others are recorded as `OTHER`
+static COMMANDS : &[&str] = &[
+    "ADD", "ALERT", "ALERTS", "ALIAS", "APPROX", "BACKUP", "BEGIN", "BULKADD", "CANCEL",
+    "CHECKSUMS", "CLEAR", "CLOSE", "COMMIT", "COMPACT", "CONFIG", "CONNECTIONS", "COPY", "COUNT",
+    "CREATE", "DDAKLUB", "DELETE", "DOWNLOAD", "EPOCHS", "EXISTS", "EXPLAIN", "FIND", "FLUSH",
+    "FORMAT", "GET", "GROUP", "GROUPS", "HA", "HELLO", "HELP", "HIST", "INFO", "JOB", "JOBS",
+    "JOIN", "LATEST", "META", "MULTICAST", "PERF", "PING", "PRECISION", "REPLAY", "RETAIN",
+    "ROLLBACK", "SCHEMA", "SERIES", "SLOWLOG", "STATS", "STRICT", "SUBSCRIBE", "SYNC", "TIER",
+    "TIMEOUT", "TOPN", "TOPOLOGY", "UPLOAD", "USE", "VACUUM", "VALIDATE",
+];
+
+/// command types of inserts, averaged into the ingest lag
+static WRITES : &[&str] = &["ADD", "BULKADD", "COMMIT", "DDAKLUB"];
+
+/// ms without inserts after which ingest no longer lags
+const IDLE_MS : u64 = 1000;
+
+/// bucket `i` counts latencies below 2^i us, the last one up to ~35 minutes
+const BUCKETS : usize = 32;
+
+#[derive(Clone, Debug, Default)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    total_us: u64,
+    max_us: u64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, elapsed: Duration) {
+        let us = elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_nanos() / 1000);
+        let bucket = (64 - us.leading_zeros() as usize).min(BUCKETS - 1);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total_us += us;
+        self.max_us = self.max_us.max(us);
+    }
+
+    /// upper bound in us of the `q` quantile
+    pub fn quantile(&self, q: f64) -> u64 {
+        let rank = (q * self.count as f64).ceil() as u64;
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if n > 0 && seen >= rank {
+                return (1u64 << i).min(self.max_us);
+            }
+        }
+        self.max_us
+    }
+
+    fn to_json(&self) -> String {
+        let buckets : Vec<String> = self.buckets.iter().enumerate()
+            .filter(|&(_, &n)| n > 0)
+            .map(|(i, n)| format!("\"{}\":{}", 1u64 << i, n))
+            .collect();
+        format!(r#"{{"count":{},"mean_us":{},"p50_us":{},"p90_us":{},"p99_us":{},"max_us":{},"buckets":{{{}}}}}"#,
+            self.count,
+            if self.count == 0 { 0 } else { self.total_us / self.count },
+            self.quantile(0.5), self.quantile(0.9), self.quantile(0.99), self.max_us,
+            buckets.join(","))
+    }
+}
+
+/// command type -> histogram
+#[derive(Debug, Default)]
+pub struct Latencies {
+    by_command: BTreeMap<&'static str, Histogram>,
+}
+
+impl Latencies {
+    /// the command type of `command`, rows sent after BULKADD are of `BULKADD`
+    pub fn command_type(command: &str, is_adding: bool) -> &'static str {
+        if is_adding && command != "DDAKLUB" {
+            return "BULKADD";
+        }
+        let keyword = command.split(' ').next().unwrap_or("");
+        COMMANDS.iter().find(|&&c| c == keyword).cloned().unwrap_or("OTHER")
+    }
+
+    pub fn record(&mut self, command_type: &'static str, elapsed: Duration) {
+        self.by_command.entry(command_type).or_insert_with(Histogram::default).record(elapsed);
+    }
+
+    pub fn reset(&mut self) {
+        self.by_command.clear();
+    }
+
+    /// {"ADD": {"count": 2, "mean_us": 3, "p50_us": 4, ..., "buckets": {"4": 2}}, ...},
+    /// a bucket counts the latencies below its key in us
+    pub fn to_json(&self) -> String {
+        let commands : Vec<String> = self.by_command.iter()
+            .map(|(command, histogram)| format!("\"{}\":{}", command, histogram.to_json()))
+            .collect();
+        format!("{{{}}}\n", commands.join(","))
+    }
+}
+
+/// Moving average of the latency of inserts
+#[derive(Debug, Default)]
+pub struct IngestLag {
+    mean_us: u64,
+    last: Option<Instant>,
+}
+
+impl IngestLag {
+    pub fn record(&mut self, command_type: &str, elapsed: Duration) {
+        if !WRITES.contains(&command_type) {
+            return;
+        }
+        let us = elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_nanos() / 1000);
+        // the last 8 or so inserts weigh the most
+        self.mean_us = (self.mean_us * 7 + us) / 8;
+        self.last = Some(Instant::now());
+    }
+
+    /// 0 once no insert was made for IDLE_MS
+    pub fn ms(&self) -> u64 {
+        match self.last {
+            Some(last) if last.elapsed() < Duration::from_millis(IDLE_MS) => self.mean_us / 1000,
+            _ => 0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use engine::Session;
+    use handler::ReturnType;
+    use serde_json::{self, Value};
+    use state::tests::{connect, global};
+
+    #[test]
+    fn should_bucket_latencies_by_power_of_two() {
+        let mut histogram = Histogram::default();
+        for us in &[3, 3, 3, 100, 5000] {
+            histogram.record(Duration::from_micros(*us));
+        }
+        assert_eq!(histogram.quantile(0.5), 4);
+        assert_eq!(histogram.quantile(0.8), 128);
+        // the last bucket is capped by the slowest command
+        assert_eq!(histogram.quantile(0.99), 5000);
+        let json : Value = serde_json::from_str(&histogram.to_json()).unwrap();
+        assert_eq!(json["count"], 5);
+        assert_eq!(json["mean_us"], 1021);
+        assert_eq!(json["buckets"], serde_json::from_str::<Value>(r#"{"4": 3, "128": 1, "8192": 1}"#).unwrap());
+    }
+
+    #[test]
+    fn should_record_the_latency_of_each_command_type() {
+        assert_eq!(Latencies::command_type("GET ALL AS JSON", false), "GET");
+        assert_eq!(Latencies::command_type("1505177459.685, 139010, t, f, 0.07, 7.6;", true), "BULKADD");
+        assert_eq!(Latencies::command_type("DDAKLUB", true), "DDAKLUB");
+        assert_eq!(Latencies::command_type("FROBNICATE", false), "OTHER");
+
+        let global = global();
+        let mut session = Session::new(connect(&global), "test");
+        session.execute("PING");
+        session.execute("PING");
+        session.execute("FROBNICATE");
+        let latencies = match session.execute("PERF LATENCY") {
+            ReturnType::String(json) => serde_json::from_str::<Value>(&json).unwrap(),
+            _ => panic!("PERF LATENCY failed")
+        };
+        assert_eq!(latencies["PING"]["count"], 2);
+        assert_eq!(latencies["OTHER"]["count"], 1);
+
+        // the reset itself is recorded
+        session.execute("PERF LATENCY RESET");
+        let latencies : Value = serde_json::from_str(&global.read().unwrap().latencies.to_json()).unwrap();
+        assert_eq!(latencies.as_object().unwrap().keys().collect::<Vec<_>>(), vec!["PERF"]);
+        assert_eq!(latencies["PERF"]["count"], 1);
+    }
+}
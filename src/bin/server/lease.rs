@@ -0,0 +1,248 @@
+/// Lease of the primary of an HA pair
+///
+/// With `--ha_lease [file]`, two servers, each with its own dtf_folder,
+/// share a lease file, e.g. on NFS. The primary holds the lease as one
+/// `node <TAB> expiry (ms since epoch) <TAB> term` line and renews it every
+/// third of `--ha_lease_secs`. The standby takes the lease with the next
+/// term once it has expired, and starts accepting writes. A primary that
+/// could not renew it stops accepting writes a fifth of the lease before it
+/// expires, so the two never accept writes at once as long as their clocks
+/// are closer than that. A standby serves reads, `SYNC`, e.g. `SYNC ...
+/// VERIFY` from the primary to stay current, and `HA`.
+///
+/// Both nodes may find an expired lease at once, so a node taking it, or
+/// retaking its own, reads it back after CONFIRM_MS and only the node whose
+/// write is there becomes the primary.
+///
+/// Every node also keeps `[lease].[node].member`, one `addr <TAB> seen (ms
+/// since epoch)` line, renewed along with the lease, so `TOPOLOGY` can tell
+/// clients where the primary and its standbys are.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// ms between taking the lease and reading it back
+const CONFIRM_MS : u64 = 500;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lease {
+    pub node: String,
+    /// ms since epoch
+    pub expires: u64,
+    /// incremented by every change of primary
+    pub term: u64,
+}
+
+/// A node of the HA pair, see `announce`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Member {
+    pub node: String,
+    /// host:port clients connect to
+    pub addr: String,
+    /// ms since epoch
+    pub seen: u64,
+}
+
+/// Role of this server, see `--ha_lease`
+#[derive(Debug, Clone)]
+pub enum Role {
+    /// not in an HA pair
+    Single,
+    /// accepting writes until `until`, unless the lease is renewed
+    Primary { term: u64, until: Instant },
+    /// `primary` holds the lease, empty if unknown yet
+    Standby { term: u64, primary: String },
+}
+
+impl Role {
+    pub fn accepts_writes(&self) -> bool {
+        match *self {
+            Role::Single => true,
+            Role::Primary { until, .. } => Instant::now() < until,
+            Role::Standby { .. } => false,
+        }
+    }
+
+    /// {"role": "primary", "term": 2, "primary": "node-a"}
+    pub fn to_json(&self, node: &str) -> String {
+        match *self {
+            Role::Single => r#"{"role": "single"}"#.to_owned(),
+            Role::Primary { term, .. } if self.accepts_writes() =>
+                format!(r#"{{"role": "primary", "term": {}, "primary": "{}"}}"#, term, node),
+            Role::Primary { term, .. } =>
+                format!(r#"{{"role": "fenced", "term": {}, "primary": "{}"}}"#, term, node),
+            Role::Standby { term, ref primary } =>
+                format!(r#"{{"role": "standby", "term": {}, "primary": "{}"}}"#, term, primary),
+        }
+    }
+}
+
+pub fn now_ms() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    now.as_secs() * 1000 + u64::from(now.subsec_nanos()) / 1_000_000
+}
+
+pub fn read(fname: &str) -> Option<Lease> {
+    let mut s = String::new();
+    File::open(fname).ok()?.read_to_string(&mut s).ok()?;
+    let parts : Vec<&str> = s.trim().split('\t').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+    Some(Lease { node: parts[0].to_owned(), expires: parts[1].parse().ok()?, term: parts[2].parse().ok()? })
+}
+
+/// replaced at once, a reader never sees half a line
+fn write(fname: &str, node: &str, line: &str) -> Result<(), io::Error> {
+    let tmp = format!("{}.{}.tmp", fname, node);
+    {
+        let mut file = File::create(&tmp)?;
+        writeln!(file, "{}", line)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, fname)
+}
+
+/// Renew the lease of `node`, or take it if it expired, for `secs`.
+/// Returns the lease held afterwards, by `node` or by the other node.
+pub fn acquire(fname: &str, node: &str, secs: u64) -> Result<Lease, io::Error> {
+    let now = now_ms();
+    let current = read(fname);
+    let term = match current {
+        Some(ref lease) if lease.node != node && lease.expires > now => return Ok(lease.clone()),
+        Some(ref lease) if lease.node == node => lease.term,
+        Some(ref lease) => lease.term + 1,
+        None => 1
+    };
+    let lease = Lease { node: node.to_owned(), expires: now + secs * 1000, term };
+    write(fname, node, &format!("{}\t{}\t{}", lease.node, lease.expires, lease.term))?;
+    // an expired lease of its own may be taken by the other node just as well
+    if current.map_or(true, |current| current.node != node || current.expires <= now) {
+        thread::sleep(Duration::from_millis(CONFIRM_MS));
+        return read(fname).ok_or_else(||
+            io::Error::new(io::ErrorKind::Other, format!("Unable to read back the lease {}", fname)));
+    }
+    Ok(lease)
+}
+
+fn member_fname(fname: &str, node: &str) -> String {
+    format!("{}.{}.member", fname, node)
+}
+
+/// Record that `node` is reachable at `addr`
+pub fn announce(fname: &str, node: &str, addr: &str) -> Result<(), io::Error> {
+    write(&member_fname(fname, node), node, &format!("{}\t{}", addr, now_ms()))
+}
+
+/// The nodes that announced themselves next to the lease `fname`
+pub fn members(fname: &str) -> Vec<Member> {
+    let path = Path::new(fname);
+    let (dir, prefix) = match (path.parent(), path.file_name().and_then(|name| name.to_str())) {
+        (Some(dir), Some(name)) => (dir, format!("{}.", name)),
+        _ => return Vec::new()
+    };
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new()
+    };
+    let mut members : Vec<Member> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.starts_with(&prefix) && name.ends_with(".member"))
+        .filter_map(|name| {
+            let node = name[prefix.len()..name.len() - ".member".len()].to_owned();
+            let mut s = String::new();
+            File::open(dir.join(&name)).ok()?.read_to_string(&mut s).ok()?;
+            let parts : Vec<&str> = s.trim().split('\t').collect();
+            if parts.len() != 2 {
+                return None;
+            }
+            Some(Member { node, addr: parts[0].to_owned(), seen: parts[1].parse().ok()? })
+        })
+        .collect();
+    members.sort_by(|a, b| a.node.cmp(&b.node));
+    members
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remove(fname: &str) {
+        let _ = fs::remove_file(fname);
+    }
+
+    #[test]
+    fn should_acquire_and_renew_the_lease() {
+        let fname = "test-lease-renew.lease";
+        remove(fname);
+
+        let lease = acquire(fname, "node-a", 10).unwrap();
+        assert_eq!((lease.node.as_str(), lease.term), ("node-a", 1));
+        assert!(lease.expires > now_ms());
+        assert_eq!(read(fname), Some(lease.clone()));
+
+        // renewing keeps the term and does not wait to confirm
+        thread::sleep(Duration::from_millis(10));
+        let start = Instant::now();
+        let renewed = acquire(fname, "node-a", 10).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(CONFIRM_MS));
+        assert_eq!((renewed.node.as_str(), renewed.term), ("node-a", 1));
+        assert!(renewed.expires > lease.expires);
+
+        // the other node gets the lease held by the primary
+        assert_eq!(acquire(fname, "node-b", 10).unwrap(), renewed);
+        assert_eq!(read(fname), Some(renewed));
+
+        remove(fname);
+    }
+
+    #[test]
+    fn should_take_over_an_expired_lease() {
+        let fname = "test-lease-expired.lease";
+        remove(fname);
+
+        write(fname, "node-a", &format!("node-a\t{}\t3", now_ms() - 1)).unwrap();
+        let lease = acquire(fname, "node-b", 10).unwrap();
+        assert_eq!((lease.node.as_str(), lease.term), ("node-b", 4));
+        assert_eq!(read(fname), Some(lease.clone()));
+
+        // the old primary finds the lease taken
+        assert_eq!(acquire(fname, "node-a", 10).unwrap(), lease);
+
+        remove(fname);
+    }
+
+    #[test]
+    fn should_confirm_the_retake_of_an_expired_own_lease() {
+        let fname = "test-lease-retake.lease";
+        remove(fname);
+
+        // both nodes find the expired lease of node-a, node-a writes first
+        write(fname, "node-a", &format!("node-a\t{}\t3", now_ms() - 1)).unwrap();
+        let node_a = thread::spawn(move || acquire(fname, "node-a", 10).unwrap());
+        thread::sleep(Duration::from_millis(CONFIRM_MS / 5));
+        let taken = Lease { node: "node-b".to_owned(), expires: now_ms() + 10_000, term: 4 };
+        write(fname, "node-b", &format!("node-b\t{}\t4", taken.expires)).unwrap();
+
+        // node-a reads back the write of node-b and stays standby
+        assert_eq!(node_a.join().unwrap(), taken);
+        assert_eq!(read(fname), Some(taken));
+
+        remove(fname);
+    }
+
+    #[test]
+    fn should_fence_an_expired_primary() {
+        assert!(Role::Single.accepts_writes());
+        assert!(Role::Primary { term: 1, until: Instant::now() + Duration::from_secs(10) }.accepts_writes());
+        let fenced = Role::Primary { term: 1, until: Instant::now() };
+        assert!(!fenced.accepts_writes());
+        assert!(fenced.to_json("node-a").contains("fenced"));
+        assert!(!Role::Standby { term: 1, primary: "node-a".to_owned() }.accepts_writes());
+    }
+}
@@ -5,21 +5,81 @@ extern crate clap;
 extern crate byteorder;
 extern crate chrono;
 #[macro_use] extern crate serde_derive;
+extern crate serde_json;
+extern crate libc;
 
 #[macro_use] extern crate log;
 extern crate fern;
 
 extern crate uuid;
+#[cfg(feature = "rocksdb")]
+extern crate rocksdb;
+#[cfg(feature = "s3")]
+extern crate rusoto_core;
+#[cfg(feature = "s3")]
+extern crate rusoto_s3;
+#[cfg(feature = "s3")]
+extern crate futures;
+#[cfg(feature = "tls")]
+extern crate native_tls;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+extern crate io_uring;
+
 
 mod plugins;
 
 mod server;
+mod engine;
 mod state;
 mod utils;
 mod parser;
 mod handler;
 mod settings;
 mod threadpool;
+mod epoch;
+mod filestats;
+mod zonemap;
+mod scan;
+mod direct;
+mod partition;
+mod tombstone;
+mod correction;
+mod alias;
+mod group;
+mod tag;
+mod flag;
+mod series;
+mod derived;
+mod materialized;
+mod approx;
+mod topn;
+mod hist;
+mod job;
+mod throttle;
+mod hello;
+mod schema;
+mod catalog;
+mod backup;
+mod lease;
+mod precision;
+mod extension;
+mod alert;
+mod feed;
+mod cdc;
+mod handles;
+mod integrity;
+mod latency;
+mod slowlog;
+mod connection;
+mod cache;
+mod pool;
+mod multicast;
+mod daemon;
+mod tls;
+mod service;
+mod mirror;
+mod sync;
+mod backend;
 
 use clap::{Arg, App, ArgMatches};
 
@@ -36,19 +96,107 @@ fn main() {
     let flush_interval = matches.value_of("flush_interval").unwrap_or("1000");
     let hist_granularity = matches.value_of("hist_granularity").unwrap_or("30");
     let threads = matches.value_of("threads").unwrap_or("100");
+    let max_memory = matches.value_of("max_memory").unwrap_or("0");
+    let group_commit = matches.value_of("group_commit").unwrap_or("0");
+    let silence_alert = matches.value_of("silence_alert").unwrap_or("0");
+    let replay_window = matches.value_of("replay_window").unwrap_or("100000");
+    let max_open_files = matches.value_of("max_open_files").unwrap_or("1024");
+    let storage = match matches.value_of("storage").unwrap_or("dtf") {
+        "dtf" => settings::Storage::DTF,
+        "rocksdb" => settings::Storage::RocksDB,
+        "uring" => settings::Storage::Uring,
+        s3 if s3.starts_with("s3://") => settings::Storage::S3(s3[5..].to_owned()),
+        other => panic!("Unknown storage `{}`", other)
+    };
+    let integrity_scan = match matches.value_of("integrity_scan").unwrap_or("off") {
+        "off" => settings::IntegrityScan::Off,
+        "fast" => settings::IntegrityScan::Fast,
+        "deep" => settings::IntegrityScan::Deep,
+        other => panic!("Unknown integrity scan `{}`", other)
+    };
 
     let log_file = matches.value_of("log_file").unwrap_or("tectonic.log");
 
-    let settings = settings::Settings {
+    let mut settings = settings::Settings {
         autoflush: autoflush,
         dtf_folder: dtf_folder.to_owned(),
+        dtf_folder_mirror: matches.value_of("dtf_folder_mirror").map(|m| m.to_owned()),
         flush_interval: flush_interval.parse::<u32>().unwrap(),
         threads: threads.parse::<usize>().unwrap(),
         hist_granularity: hist_granularity.parse::<u64>().unwrap(),
+        storage: storage,
+        max_memory: max_memory.parse::<u64>().unwrap() * 1024 * 1024,
+        flush_memory: matches.value_of("flush_memory").unwrap_or("0").parse::<u64>().unwrap() * 1024 * 1024,
+        journal: matches.value_of("journal").map(|j| j.to_owned()),
+        normalize_names: matches.is_present("normalize_names"),
+        autocreate_stores: matches.is_present("autocreate_stores"),
+        default_store: match matches.value_of("default_store").unwrap_or("default") {
+            "none" => None,
+            name => Some(name.to_owned())
+        },
+        group_commit: group_commit.parse::<u64>().unwrap(),
+        multicast: matches.value_of("multicast").map(|m| m.to_owned()),
+        silence_alert: silence_alert.parse::<u64>().unwrap(),
+        webhook: matches.value_of("webhook").map(|w| w.to_owned()),
+        replay_window: replay_window.parse::<usize>().unwrap(),
+        max_open_files: max_open_files.parse::<usize>().unwrap(),
+        integrity_scan,
+        daemon: matches.is_present("daemon"),
+        pid_file: matches.value_of("pid_file").map(|p| p.to_owned()),
+        listeners: matches.values_of("listen").map(|specs| specs.map(|spec| match parser::parse_listen(spec) {
+            Some(listen) => listen,
+            None => panic!("Unable to parse --listen `{}`", spec)
+        }).collect()).unwrap_or_default(),
+        allow_ips: parse_cidrs(matches.value_of("allow_ips")),
+        deny_ips: parse_cidrs(matches.value_of("deny_ips")),
+        slowlog_threshold: matches.value_of("slowlog_threshold").unwrap_or("0").parse::<u64>().unwrap(),
+        slowlog_file: matches.value_of("slowlog_file").unwrap_or("slow.log").to_owned(),
+        query_timeout: matches.value_of("query_timeout").unwrap_or("0").parse::<u64>().unwrap(),
+        shed_reads_above: matches.value_of("shed_reads_above").unwrap_or("0").parse::<u64>().unwrap(),
+        result_cache: matches.value_of("result_cache").unwrap_or("64").parse::<u64>().unwrap() * 1024 * 1024,
+        scan_threads: matches.value_of("scan_threads").unwrap_or("4").parse::<usize>().unwrap(),
+        direct_io: matches.value_of("direct_io").unwrap_or("0").parse::<u64>().unwrap() * 1024 * 1024,
+        compaction_throttle: matches.value_of("compaction_throttle").unwrap_or("1").parse::<u64>().unwrap(),
+        max_partition_bytes: matches.value_of("max_partition_bytes").unwrap_or("0").parse::<u64>().unwrap(),
+        unload_idle: matches.value_of("unload_idle").unwrap_or("0").parse::<u64>().unwrap(),
+        ha_lease: matches.value_of("ha_lease").map(|fname| fname.to_owned()),
+        ha_lease_secs: matches.value_of("ha_lease_secs").unwrap_or("10").parse::<u64>().unwrap(),
+        ha_node: matches.value_of("ha_node").map(|node| node.to_owned()).unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+        ha_addr: matches.value_of("ha_addr").map(|addr| addr.to_owned()).unwrap_or_else(|| format!("{}:{}", host, port)),
+        templates: matches.values_of("template").map(|specs| specs.map(|spec| {
+            let mut parts = spec.splitn(2, '=');
+            match (parts.next(), parts.next().and_then(parser::parse_template)) {
+                (Some(name), Some(template)) if !name.is_empty() => (name.to_owned(), template),
+                _ => panic!("Unable to parse --template `{}`", spec)
+            }
+        }).collect()).unwrap_or_default(),
+        max_jobs: matches.value_of("max_jobs").unwrap_or("2").parse::<usize>().unwrap(),
+        schedules: matches.values_of("schedule").map(|specs| specs.map(|spec| {
+            let mut parts = spec.splitn(2, '=');
+            match (parts.next(), parts.next().and_then(parser::parse_schedule)) {
+                (Some(name), Some(schedule)) if !name.is_empty() => (name.to_owned(), schedule),
+                _ => panic!("Unable to parse --schedule `{}`", spec)
+            }
+        }).collect()).unwrap_or_default(),
     };
 
     prepare_logger(verbosity, &log_file);
-    server::run_server(&host, &port, &settings);
+    settings.load_config();
+    if let Err(e) = settings.check() {
+        panic!("{}", e);
+    }
+    match matches.value_of("service") {
+        Some(name) => service::run(name, &host, &port, &settings, extension::Extensions::new()),
+        None => server::run_server(&host, &port, &settings, extension::Extensions::new())
+    }
+}
+
+fn parse_cidrs(cidrs: Option<&str>) -> Vec<settings::Cidr> {
+    match cidrs.map(parser::parse_cidrs) {
+        Some(Some(cidrs)) => cidrs,
+        Some(None) => panic!("Unable to parse the networks `{}`", cidrs.unwrap()),
+        None => Vec::new()
+    }
 }
 
 fn prepare_logger(verbosity: u8, log_file: &str) {
@@ -99,6 +247,12 @@ fn get_matches<'a>() -> ArgMatches<'a> {
         .value_name("FOLDER")
         .help("Sets the folder to serve dtf files")
         .takes_value(true))
+    .arg(Arg::with_name("dtf_folder_mirror")
+        .short("b")
+        .long("dtf_folder_mirror")
+        .value_name("FOLDER")
+        .help("Copies every flushed dtf file to FOLDER, e.g. on another disk, and verifies the copy by checksum")
+        .takes_value(true))
     .arg(Arg::with_name("v")
         .short("v")
         .multiple(true)
@@ -126,5 +280,202 @@ fn get_matches<'a>() -> ArgMatches<'a> {
         .long("log_file")
         .value_name("LOG_FILE")
         .help("Sets the log file to write to"))
+    .arg(Arg::with_name("max_memory")
+        .short("m")
+        .long("max_memory")
+        .value_name("MB")
+        .help("Sets the memory budget of rows in memory, MEMORY stores refuse inserts beyond it. (default 0, unlimited)")
+        .takes_value(true))
+    .arg(Arg::with_name("flush_memory")
+        .long("flush_memory")
+        .value_name("MB")
+        .help("Flushes the stores with the most unflushed rows once the unflushed rows of all stores take MB (default 0, off)")
+        .takes_value(true))
+    .arg(Arg::with_name("normalize_names")
+        .short("n")
+        .long("normalize_names")
+        .help("Lowercases store names and replaces `-` with `_` (default is false)"))
+    .arg(Arg::with_name("autocreate_stores")
+        .short("c")
+        .long("autocreate_stores")
+        .help("Creates the store named by ADD INTO if it does not exist (default is false)"))
+    .arg(Arg::with_name("default_store")
+        .long("default_store")
+        .value_name("NAME")
+        .help("Names the store a connection adds to until USE, `{peer}` is replaced by the client address, `none` refuses ADD without INTO until USE (default `default`)")
+        .takes_value(true))
+    .arg(Arg::with_name("group_commit")
+        .short("d")
+        .long("group_commit")
+        .value_name("MS")
+        .help("Sets the max delay of autoflushes, which are then written and synced to disk together. (default 0, flush right away)")
+        .takes_value(true))
+    .arg(Arg::with_name("multicast")
+        .short("u")
+        .long("multicast")
+        .value_name("GROUP:PORT")
+        .help("Publishes the rows of stores selected with MULTICAST to a UDP multicast group")
+        .takes_value(true))
+    .arg(Arg::with_name("silence_alert")
+        .short("q")
+        .long("silence_alert")
+        .value_name("SECS")
+        .help("Raises an alert when a store that received rows gets none for SECS. (default 0, off)")
+        .takes_value(true))
+    .arg(Arg::with_name("webhook")
+        .short("w")
+        .long("webhook")
+        .value_name("URL")
+        .help("Sets the http:// url alerts are POSTed to as JSON")
+        .takes_value(true))
+    .arg(Arg::with_name("replay_window")
+        .short("r")
+        .long("replay_window")
+        .value_name("ROWS")
+        .help("Sets the rows a subscribed store keeps on disk for subscribers resuming with SUBSCRIBE ... FROM OFFSET. (default 100000)")
+        .takes_value(true))
+    .arg(Arg::with_name("max_open_files")
+        .short("o")
+        .long("max_open_files")
+        .value_name("FILES")
+        .help("Sets how many files are kept open between writes, the least recently used is closed first. (default 1024)")
+        .takes_value(true))
+    .arg(Arg::with_name("journal")
+        .short("j")
+        .long("journal")
+        .value_name("JOURNAL")
+        .help("Records every received command to a file that tectonic-replay can send back")
+        .takes_value(true))
+    .arg(Arg::with_name("storage")
+        .short("s")
+        .long("storage")
+        .value_name("STORAGE")
+        .help("Sets where flushed rows are stored: dtf, uring, rocksdb or s3://[bucket] (default dtf)")
+        .takes_value(true))
+    .arg(Arg::with_name("integrity_scan")
+        .short("k")
+        .long("integrity_scan")
+        .value_name("MODE")
+        .help("Checks the dtf files on startup: off, fast (headers only) or deep (every row). Corrupt files are moved to [dtf_folder]/corrupt (default off)")
+        .takes_value(true))
+    .arg(Arg::with_name("listen")
+        .long("listen")
+        .value_name("ADDR[,tls=IDENTITY][,allow=read]")
+        .help("Accepts connections on ADDR instead of --host:--port, once per listener. tls= requires TLS with a PKCS#12 identity, allow=read only accepts commands that read")
+        .multiple(true)
+        .number_of_values(1)
+        .takes_value(true))
+    .arg(Arg::with_name("allow_ips")
+        .long("allow_ips")
+        .value_name("CIDRS")
+        .help("Only accepts connections from these networks, e.g. 10.0.0.0/8,127.0.0.1")
+        .takes_value(true))
+    .arg(Arg::with_name("deny_ips")
+        .long("deny_ips")
+        .value_name("CIDRS")
+        .help("Refuses connections from these networks, even if allowed")
+        .takes_value(true))
+    .arg(Arg::with_name("slowlog_threshold")
+        .long("slowlog_threshold")
+        .value_name("MS")
+        .help("Records commands taking longer than MS in the slow log, see SLOWLOG GET (default 0, off)")
+        .takes_value(true))
+    .arg(Arg::with_name("slowlog_file")
+        .long("slowlog_file")
+        .value_name("FILE")
+        .help("Sets the file the slow log is appended to (default slow.log)")
+        .takes_value(true))
+    .arg(Arg::with_name("query_timeout")
+        .long("query_timeout")
+        .value_name("MS")
+        .help("Interrupts reads running longer than MS, see TIMEOUT (default 0, never)")
+        .takes_value(true))
+    .arg(Arg::with_name("shed_reads_above")
+        .long("shed_reads_above")
+        .value_name("MS")
+        .help("Refuses full scans while inserts take longer than MS on average (default 0, off)")
+        .takes_value(true))
+    .arg(Arg::with_name("result_cache")
+        .long("result_cache")
+        .value_name("MB")
+        .help("Keeps the responses of repeated GETs in MB of memory until a write changes them (default 64, 0 is off)")
+        .takes_value(true))
+    .arg(Arg::with_name("scan_threads")
+        .long("scan_threads")
+        .value_name("THREADS")
+        .help("Decodes the batches of a range read on up to THREADS threads (default 4)")
+        .takes_value(true))
+    .arg(Arg::with_name("direct_io")
+        .long("direct_io")
+        .value_name("MB")
+        .help("Writes flushes and compactions of at least MB with O_DIRECT, bypassing the page cache (default 0, off)")
+        .takes_value(true))
+    .arg(Arg::with_name("compaction_throttle")
+        .long("compaction_throttle")
+        .value_name("MS")
+        .help("Delays inserts into a store being compacted by up to MS a row as its new rows pile up (default 1, 0 is off)")
+        .takes_value(true))
+    .arg(Arg::with_name("max_partition_bytes")
+        .long("max_partition_bytes")
+        .value_name("BYTES")
+        .help("Seals the file of a store as a partition once it reaches BYTES and starts a new one (default 0, off)")
+        .takes_value(true))
+    .arg(Arg::with_name("unload_idle")
+        .long("unload_idle")
+        .value_name("MINUTES")
+        .help("Drops the flushed rows of a store from memory after MINUTES without reads or inserts (default 0, off)")
+        .takes_value(true))
+    .arg(Arg::with_name("ha_lease")
+        .long("ha_lease")
+        .value_name("FILE")
+        .help("Runs as one of an HA pair sharing the lease FILE, accepting writes only while holding it")
+        .takes_value(true))
+    .arg(Arg::with_name("ha_lease_secs")
+        .long("ha_lease_secs")
+        .value_name("SECS")
+        .help("Sets how long the lease of the primary lasts without renewal (default 10)")
+        .takes_value(true))
+    .arg(Arg::with_name("ha_node")
+        .long("ha_node")
+        .value_name("NAME")
+        .help("Names this server in the lease (default a random id)")
+        .takes_value(true))
+    .arg(Arg::with_name("ha_addr")
+        .long("ha_addr")
+        .value_name("ADDR")
+        .help("Sets the host:port clients reach this server at, listed by TOPOLOGY (default --host:--port)")
+        .takes_value(true))
+    .arg(Arg::with_name("template")
+        .long("template")
+        .value_name("NAME=SPEC")
+        .help("Names the settings of the stores created with CREATE [db] LIKE NAME, e.g. binance=precision=8,ttl=30d, once per template")
+        .multiple(true)
+        .number_of_values(1)
+        .takes_value(true))
+    .arg(Arg::with_name("max_jobs")
+        .long("max_jobs")
+        .value_name("JOBS")
+        .help("Sets how many admin jobs run at once, the others are queued (default 2)")
+        .takes_value(true))
+    .arg(Arg::with_name("schedule")
+        .long("schedule")
+        .value_name("NAME=SPEC")
+        .help("Starts an admin job on a crontab schedule in UTC, e.g. nightly=\"0 3 * * * COMPACT btc_usdt\", once per schedule")
+        .multiple(true)
+        .number_of_values(1)
+        .takes_value(true))
+    .arg(Arg::with_name("daemon")
+        .long("daemon")
+        .help("Notifies the service manager (NOTIFY_SOCKET) once ready and listens on the socket it passes (LISTEN_FDS)"))
+    .arg(Arg::with_name("pid_file")
+        .long("pid_file")
+        .value_name("FILE")
+        .help("Writes the pid to FILE once ready")
+        .takes_value(true))
+    .arg(Arg::with_name("service")
+        .long("service")
+        .value_name("NAME")
+        .help("Runs as the Windows service NAME, stopping the service flushes every store")
+        .takes_value(true))
     .get_matches()
 }
\ No newline at end of file
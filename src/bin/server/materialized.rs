@@ -0,0 +1,46 @@
+/// Materialized stores
+///
+/// `CREATE MATERIALIZED [db] FROM [db] INTERVAL [1m|1h|1d]` keeps a store of
+/// candles built from the trades inserted into another store. The candles
+/// are rewritten to the dtf file of the store on every flush.
+///
+/// Definitions are kept in `[dtf_folder]/materialized`, one `db <TAB> source
+/// <TAB> minutes` line each. On startup the candles are read back from the
+/// dtf file, so trades inserted after a restart update them instead of
+/// starting over.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use utils;
+
+/// store name -> (source, minutes of a candle)
+pub type Definitions = HashMap<String, (String, u16)>;
+
+fn materialized_fname(dtf_folder: &str) -> String {
+    utils::join(dtf_folder, "materialized")
+}
+
+/// all materialized stores kept in the folder
+pub fn read(dtf_folder: &str) -> Definitions {
+    let mut definitions = HashMap::new();
+    let file = match File::open(materialized_fname(dtf_folder)) {
+        Ok(f) => f,
+        Err(_) => return definitions
+    };
+    for line in BufReader::new(file).lines().filter_map(|line| line.ok()) {
+        let parts : Vec<&str> = line.split('\t').collect();
+        if parts.len() != 3 {
+            continue;
+        }
+        if let Ok(minutes) = parts[2].parse::<u16>() {
+            definitions.insert(parts[0].to_owned(), (parts[1].to_owned(), minutes));
+        }
+    }
+    definitions
+}
+
+pub fn record(dtf_folder: &str, store_name: &str, source: &str, minutes: u16) -> Result<(), io::Error> {
+    let mut file = OpenOptions::new().create(true).append(true).open(materialized_fname(dtf_folder))?;
+    writeln!(file, "{}\t{}\t{}", store_name, source, minutes)
+}
@@ -0,0 +1,107 @@
+/// Mirrored flushes
+///
+/// With `--dtf_folder_mirror [FOLDER]` every dtf file written to dtf_folder
+/// is copied to the file of the same name in FOLDER, e.g. on another disk or
+/// on NFS, so the loss of one disk loses no flushed rows.
+///
+/// An append only copies the header and the appended bytes, a rewrite of the
+/// file copies it whole. The copied bytes are read back and compared with
+/// the file by CRC-32; a mirror that does not match is copied whole again.
+
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use dtf;
+use dtf::utils::crc32_update;
+use utils;
+
+/// bytes read at once while comparing
+static CHUNK_LEN : u64 = 1 << 16;
+
+/// the copy of `dtf_fname` in `mirror_folder`
+pub fn mirror_fname(mirror_folder: &str, dtf_fname: &str) -> String {
+    let name = Path::new(dtf_fname).file_name().and_then(|name| name.to_str()).unwrap_or(dtf_fname);
+    utils::join(mirror_folder, name)
+}
+
+fn file_len(fname: &str) -> u64 {
+    fs::metadata(fname).map(|m| m.len()).unwrap_or(0)
+}
+
+/// CRC-32 of the bytes of `file` in `start..end`
+fn checksum(file: &mut File, start: u64, end: u64) -> Result<u32, io::Error> {
+    file.seek(SeekFrom::Start(start))?;
+    let mut crc = 0;
+    let mut buf = vec![0; CHUNK_LEN as usize];
+    let mut pos = start;
+    while pos < end {
+        let n = cmp::min(CHUNK_LEN, end - pos) as usize;
+        file.read_exact(&mut buf[..n])?;
+        crc = crc32_update(crc, &buf[..n]);
+        pos += n as u64;
+    }
+    Ok(crc)
+}
+
+/// copy `ranges` of `src` into `dst` and compare them by checksum
+fn copy_ranges(src: &str, dst: &mut File, ranges: &[(u64, u64)]) -> Result<bool, io::Error> {
+    let mut src = File::open(src)?;
+    for &(start, end) in ranges {
+        src.seek(SeekFrom::Start(start))?;
+        dst.seek(SeekFrom::Start(start))?;
+        io::copy(&mut (&mut src).take(end - start), dst)?;
+    }
+    dst.sync_all()?;
+    for &(start, end) in ranges {
+        if checksum(&mut src, start, end)? != checksum(dst, start, end)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// After the dtf file was written or rewritten
+pub fn written(dtf_fname: &str, mirror_folder: &str) -> Result<(), io::Error> {
+    let mirror = mirror_fname(mirror_folder, dtf_fname);
+    let tmp = format!("{}.tmp", mirror);
+    let len = file_len(dtf_fname);
+    {
+        let mut dst = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&tmp)?;
+        if !copy_ranges(dtf_fname, &mut dst, &[(0, len)])? {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} does not match {}", tmp, dtf_fname)));
+        }
+    }
+    utils::replace_file(&tmp, &mirror)
+}
+
+/// After rows were appended to the dtf file, which was `old_len` bytes long
+pub fn appended(dtf_fname: &str, old_len: u64, mirror_folder: &str) -> Result<(), io::Error> {
+    let mirror = mirror_fname(mirror_folder, dtf_fname);
+    // a mirror of another length missed a write
+    if file_len(&mirror) != old_len {
+        return written(dtf_fname, mirror_folder);
+    }
+    let len = file_len(dtf_fname);
+    let header = cmp::min(dtf::MAIN_OFFSET, len);
+    let ranges = [(0, header), (cmp::max(old_len, header), len)];
+    let matches = {
+        let mut dst = OpenOptions::new().write(true).read(true).open(&mirror)?;
+        copy_ranges(dtf_fname, &mut dst, &ranges)?
+    };
+    if matches {
+        Ok(())
+    } else {
+        warn!("Mirror {} does not match {}, copying it whole", mirror, dtf_fname);
+        written(dtf_fname, mirror_folder)
+    }
+}
+
+/// After the dtf file was removed
+pub fn removed(dtf_fname: &str, mirror_folder: &str) -> Result<(), io::Error> {
+    let mirror = mirror_fname(mirror_folder, dtf_fname);
+    if Path::new(&mirror).exists() {
+        utils::remove_file(&mirror)?;
+    }
+    Ok(())
+}
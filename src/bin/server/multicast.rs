@@ -0,0 +1,124 @@
+/// UDP multicast fan-out
+///
+/// With `--multicast [group:port]`, rows inserted into stores selected with
+/// `MULTICAST [db]` are published to the group as one datagram each, so any
+/// number of subscribers in the datacenter get the live feed from a single send.
+///
+/// A datagram is
+///
+///     seq: u64 | name_len: u8 | name | ts: u64 | seq: u32 | flags: u8 | price: f32 | size: f32
+///
+/// in big endian. The first `seq` counts datagrams across all stores, a subscriber
+/// that sees it skip asks for the missing ones by sending `from: u64 | to: u64`
+/// to the source address of the datagrams. The last GAPFILL_BUFFER datagrams are
+/// sent again to it, older rows have to be read with GET.
+
+use std::collections::{HashSet, VecDeque};
+use std::io::Cursor;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use dtf::update::Update;
+
+/// number of sent datagrams kept for gap-fill requests
+static GAPFILL_BUFFER : usize = 100_000;
+
+/// multicast hops, datagrams stay in the local network
+static MULTICAST_TTL : u32 = 1;
+
+type Sent = Arc<Mutex<VecDeque<(u64, Vec<u8>)>>>;
+
+#[derive(Debug)]
+pub struct Multicast {
+    socket: UdpSocket,
+    group: SocketAddr,
+    /// stores whose rows are published
+    pub stores: HashSet<String>,
+    /// seq of the last datagram
+    seq: u64,
+    sent: Sent,
+}
+
+impl Multicast {
+    /// Publish to `group` ("239.0.0.1:9002") and answer gap-fill requests
+    pub fn open(group: &str) -> Result<Multicast, String> {
+        let group : SocketAddr = group.parse().map_err(|_| format!("Invalid multicast group `{}`", group))?;
+        let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| format!("{:?}", e))?;
+        socket.set_multicast_ttl_v4(MULTICAST_TTL).map_err(|e| format!("{:?}", e))?;
+
+        let sent : Sent = Arc::new(Mutex::new(VecDeque::new()));
+        let gapfill_socket = socket.try_clone().map_err(|e| format!("{:?}", e))?;
+        let gapfill_sent = sent.clone();
+        thread::spawn(move || gapfill(&gapfill_socket, &gapfill_sent));
+
+        Ok(Multicast {
+            socket,
+            group,
+            stores: HashSet::new(),
+            seq: 0,
+            sent,
+        })
+    }
+
+    /// send `up` if `store_name` is selected
+    pub fn publish(&mut self, store_name: &str, up: &Update) {
+        if !self.stores.contains(store_name) {
+            return;
+        }
+        self.seq += 1;
+        let datagram = encode(self.seq, store_name, up);
+        if let Err(e) = self.socket.send_to(&datagram, self.group) {
+            error!("Unable to multicast {}: {:?}", self.seq, e);
+        }
+
+        let mut sent = self.sent.lock().unwrap();
+        if sent.len() == GAPFILL_BUFFER {
+            sent.pop_front();
+        }
+        sent.push_back((self.seq, datagram));
+    }
+}
+
+fn encode(seq: u64, store_name: &str, up: &Update) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(30 + store_name.len());
+    let _ = buf.write_u64::<BigEndian>(seq);
+    let _ = buf.write_u8(store_name.len() as u8);
+    buf.extend_from_slice(store_name.as_bytes());
+
+    let _ = buf.write_u64::<BigEndian>(up.ts);
+    let _ = buf.write_u32::<BigEndian>(up.seq);
+    let _ = buf.write_u8(up.flags_byte());
+    let _ = buf.write_f32::<BigEndian>(up.price);
+    let _ = buf.write_f32::<BigEndian>(up.size);
+    buf
+}
+
+/// resend the datagrams a subscriber missed
+fn gapfill(socket: &UdpSocket, sent: &Sent) {
+    let mut req = [0; 16];
+    loop {
+        let (len, src) = match socket.recv_from(&mut req) {
+            Ok(received) => received,
+            Err(e) => { error!("Gap-fill request failed: {:?}", e); continue; }
+        };
+        if len != req.len() {
+            continue;
+        }
+        let mut rdr = Cursor::new(&req[..]);
+        let from = rdr.read_u64::<BigEndian>().unwrap();
+        let to = rdr.read_u64::<BigEndian>().unwrap();
+
+        let missed : Vec<Vec<u8>> = sent.lock().unwrap().iter()
+            .filter(|&&(seq, _)| seq >= from && seq <= to)
+            .map(|&(_, ref datagram)| datagram.clone())
+            .collect();
+        debug!("Gap-fill of {} datagrams to {}", missed.len(), src);
+        for datagram in missed {
+            if let Err(e) = socket.send_to(&datagram, src) {
+                error!("Unable to gap-fill {}: {:?}", src, e);
+                break;
+            }
+        }
+    }
+}
@@ -1,14 +1,29 @@
 use dtf;
-use dtf::update::Update;
+use dtf::update::{Update, MAX_USER_FLAGS};
+use std::str::FromStr;
+use std::fmt;
+use alert::{Condition, Rule};
+use settings::{Allow, Cidr, IntegrityScan, Listen, Schedule, Template};
+use job::{self, Cron};
+use hello::{self, Hello};
+use precision;
+use flag;
+use series;
+use derived::{Expr, Field, Op};
+use approx::Aggregate;
+use topn::By;
+use hist;
+use std::net::IpAddr;
 
 /// Parses a line that looks like 
 /// 
 /// 1505177459.658, 139010, t, t, 0.0703629, 7.65064249;
+/// 1505177459.658, 139010, t, t, 0.0703629, 7.65064249, 5;
 /// 
-/// into an `Update` struct.
+/// into an `Update` struct, the 7th field is the flags, see `flag`.
 /// 
 pub fn parse_line(string : &str) -> Option<Update> {
-    let mut u = Update { ts : 0, seq : 0, is_bid : false, is_trade : false, price : -0.1, size : -0.1 };
+    let mut u = Update { ts : 0, seq : 0, is_bid : false, is_trade : false, price : 0., size : 0., flags: 0 };
     let mut buf : String = String::new();
     let mut count = 0;
     let mut most_current_bool = false;
@@ -20,6 +35,9 @@ pub fn parse_line(string : &str) -> Option<Update> {
             buf.push(ch);
         } else if ch.is_digit(10) {
             buf.push(ch);
+        } else if ch == '-' && count == 4 {
+            // a price may be negative, e.g. a funding rate, see `series`
+            buf.push(ch);
         } else if ch == 't' || ch == 'f' {
             most_current_bool = ch == 't';
         } else if ch == ',' || ch == ';' {
@@ -30,20 +48,148 @@ pub fn parse_line(string : &str) -> Option<Update> {
                 3 => { u.is_bid   = most_current_bool; },
                 4 => { u.price    = match buf.parse::<f32>() {Ok(price) => price, Err(_) => return None} },
                 5 => { u.size     = match buf.parse::<f32>() {Ok(size) => size, Err(_) => return None}},
-                _ => panic!("IMPOSSIBLE")
+                6 => { u.flags    = match buf.parse::<u8>() {Ok(flags) if flags <= MAX_USER_FLAGS => flags, _ => return None}},
+                _ => return None
             }
             count += 1;
             buf.clear();
         }
     }
 
-    if u.price < 0. || u.size < 0. {
+    if count < 6 {
         None
     } else {
         Some(u)
     }
 }
 
+/// Why a row was rejected by `parse_line_strict`
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic {
+    pub field: &'static str,
+    pub expected: &'static str,
+    pub got: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "field `{}`: expected {}, got `{}`", self.field, self.expected, self.got)
+    }
+}
+
+static FIELDS : [&str; 7] = ["ts", "seq", "is_trade", "is_bid", "price", "size", "flags"];
+
+/// Parses the same rows as `parse_line` but rejects anything it would
+/// silently skip over, and says which field is wrong.
+pub fn parse_line_strict(string : &str) -> Result<Update, Diagnostic> {
+    let string = string.trim();
+    if !string.ends_with(';') {
+        return Err(Diagnostic { field: "row", expected: "`;` at the end", got: string.to_owned() });
+    }
+    let fields : Vec<&str> = string[..(string.len() - 1)].split(',').map(|f| f.trim()).collect();
+    if fields.len() != FIELDS.len() - 1 && fields.len() != FIELDS.len() {
+        return Err(Diagnostic { field: "row", expected: "6 or 7 fields", got: format!("{} fields", fields.len()) });
+    }
+
+    let err = |i: usize, expected: &'static str| Diagnostic { field: FIELDS[i], expected, got: fields[i].to_owned() };
+    let parse_bool = |i: usize| match fields[i] {
+        "t" | "true" => Ok(true),
+        "f" | "false" => Ok(false),
+        _ => Err(err(i, "t or f"))
+    };
+    let parse_size = |i: usize| match fields[i].parse::<f32>() {
+        Ok(v) if v >= 0. => Ok(v),
+        _ => Err(err(i, "non-negative f32"))
+    };
+    let parse_price = |i: usize| match fields[i].parse::<f32>() {
+        Ok(v) if v.is_finite() => Ok(v),
+        _ => Err(err(i, "f32"))
+    };
+
+    if fields[0].is_empty() || fields[0].matches('.').count() > 1
+        || !fields[0].chars().all(|ch| ch.is_digit(10) || ch == '.') {
+        return Err(err(0, "timestamp"));
+    }
+    let ts = match fields[0].replace('.', "").parse::<u64>() {
+        Ok(ts) => dtf::fill_digits(ts),
+        Err(_) => return Err(err(0, "timestamp"))
+    };
+    let seq = match fields[1].parse::<u32>() {
+        Ok(seq) => seq,
+        Err(_) => return Err(err(1, "u32"))
+    };
+
+    let flags = match fields.get(6).map(|flags| flags.parse::<u8>()) {
+        None => 0,
+        Some(Ok(flags)) if flags <= MAX_USER_FLAGS => flags,
+        Some(_) => return Err(err(6, "integer from 0 to 63"))
+    };
+
+    Ok(Update {
+        ts,
+        seq,
+        is_trade: parse_bool(2)?,
+        is_bid: parse_bool(3)?,
+        price: parse_price(4)?,
+        size: parse_size(5)?,
+        flags,
+    })
+}
+
+/// Decodes standard base64, padding optional
+pub fn parse_base64(string: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(string.len() * 3 / 4);
+    let mut acc : u32 = 0;
+    let mut bits = 0;
+    for ch in string.trim().bytes() {
+        let value = match ch {
+            b'A'..=b'Z' => ch - b'A',
+            b'a'..=b'z' => ch - b'a' + 26,
+            b'0'..=b'9' => ch - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => continue,
+            _ => return None
+        };
+        acc = (acc << 6) | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+/// Parses a BULKADD row of a `FORMAT MSGPACK` connection, a base64 encoded
+/// MessagePack map or array, see `dtf::msgpack`
+pub fn parse_msgpack_line(string: &str) -> Result<Update, String> {
+    match parse_base64(string) {
+        Some(buf) => dtf::decode_msgpack_row(&buf),
+        None => Err("Row is not base64.".to_owned())
+    }
+}
+
+/// Strips the command around the row of
+///
+/// ADD [row] INTO [db]
+///
+/// so it can be validated on its own.
+pub fn parse_payload(string: &str) -> &str {
+    let string = if string.starts_with("ADD CORRECTION ") {
+        &string[15..]
+    } else if string.starts_with("ADD ") {
+        &string[4..]
+    } else {
+        string
+    };
+    match string.find(" INTO ") {
+        Some(index) => &string[..index],
+        None => string
+    }
+}
+
 pub fn parse_dbname(string: &str) -> (usize, &str) {
     let into_indices : Vec<_> = string.match_indices(" INTO ").collect();
     let (index, _) = into_indices[0];
@@ -61,10 +207,1270 @@ pub fn parse_add_into(string: &str) -> (Option<Update>, Option<String>) {
     }
 }
 
+/// returns the word following `keyword`, parsed into `T`
+///
+/// parse_keyword::<u32>("GET 10 FROM 1 TO 2", " FROM ") => Some(1)
+pub fn parse_keyword<T: FromStr>(string: &str, keyword: &str) -> Option<T> {
+    let index = string.find(keyword)?;
+    string[(index + keyword.len())..]
+        .split(' ')
+        .next()?
+        .parse::<T>()
+        .ok()
+}
+
+/// Parses an interval like `1m`, `4h` or `1d` into minutes
+pub fn parse_interval(string: &str) -> Option<u16> {
+    if string.len() < 2 {
+        return None;
+    }
+    let (n, unit) = string.split_at(string.len() - 1);
+    let n = n.parse::<u16>().ok()?;
+    let minutes = match unit {
+        "m" => Some(n),
+        "h" => n.checked_mul(60),
+        "d" => n.checked_mul(60 * 24),
+        _ => None
+    }?;
+    if minutes == 0 { None } else { Some(minutes) }
+}
+
+/// Parses
+///
+/// CREATE MATERIALIZED btc_usdt@1m FROM btc_usdt INTERVAL 1m
+///
+/// into (name, source, minutes)
+pub fn parse_create_materialized(string: &str) -> Option<(String, String, u16)> {
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts.len() != 7
+        || parts[0] != "CREATE" || parts[1] != "MATERIALIZED"
+        || parts[3] != "FROM" || parts[5] != "INTERVAL" {
+        return None;
+    }
+    Some((parts[2].to_owned(), parts[4].to_owned(), parse_interval(parts[6])?))
+}
+
+/// Parses a duration like `30s`, `15m`, `24h` or `7d` into ms
+pub fn parse_duration(string: &str) -> Option<u64> {
+    if string.len() < 2 {
+        return None;
+    }
+    let (n, unit) = string.split_at(string.len() - 1);
+    let n = n.parse::<u64>().ok()?;
+    let ms = match unit {
+        "s" => n.checked_mul(1000),
+        "m" => n.checked_mul(60 * 1000),
+        "h" => n.checked_mul(60 * 60 * 1000),
+        "d" => n.checked_mul(24 * 60 * 60 * 1000),
+        _ => None
+    }?;
+    if ms == 0 { None } else { Some(ms) }
+}
+
+/// longest store name, it is also part of the dtf file name
+pub static MAX_NAME_LEN : usize = 64;
+
+/// `BTC-USDT` -> `btc_usdt`
+pub fn normalize_name(name: &str) -> String {
+    name.to_lowercase().replace('-', "_")
+}
+
+/// Store names are 1 to 64 ASCII letters, digits, `_` or `-`
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(format!("DB name `{}` must be 1 to {} characters", name, MAX_NAME_LEN));
+    }
+    match name.chars().find(|&c| !(c.is_ascii_alphanumeric() || c == '_' || c == '-')) {
+        Some(c) => Err(format!("DB name `{}` contains `{}`, only letters, digits, `_` and `-` are allowed", name, c)),
+        None => Ok(())
+    }
+}
+
+/// Rows a client announces for `BULKADD ... COUNT n [CRC x]`
+#[derive(Debug, PartialEq, Clone)]
+pub struct BulkDeclaration {
+    pub count: u64,
+    /// CRC-32 of the row lines, each followed by `\n`
+    pub crc: Option<u32>,
+}
+
+/// Parses
+///
+/// BULKADD INTO btc_usdt COUNT 1000 CRC 9ae0daaf
+///
+/// into (store, declaration). Both are optional, the CRC is hex.
+pub fn parse_bulkadd(string: &str) -> Option<(Option<String>, Option<BulkDeclaration>)> {
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts[0] != "BULKADD" {
+        return None;
+    }
+    let mut i = 1;
+
+    let dbname = if parts.get(i) == Some(&"INTO") {
+        let dbname = parts.get(i + 1)?.to_string();
+        i += 2;
+        Some(dbname)
+    } else {
+        None
+    };
+
+    let declaration = if parts.get(i) == Some(&"COUNT") {
+        let count = parts.get(i + 1)?.parse::<u64>().ok()?;
+        i += 2;
+        let crc = if parts.get(i) == Some(&"CRC") {
+            let crc = u32::from_str_radix(parts.get(i + 1)?, 16).ok()?;
+            i += 2;
+            Some(crc)
+        } else {
+            None
+        };
+        Some(BulkDeclaration { count, crc })
+    } else {
+        None
+    };
+
+    if i != parts.len() {
+        return None;
+    }
+    Some((dbname, declaration))
+}
+
+/// Parses
+///
+/// TIER btc_usdt COLD /mnt/hdd/db AFTER 7d
+///
+/// into (store, cold folder, age in ms)
+pub fn parse_tier(string: &str) -> Option<(String, String, u64)> {
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts.len() != 6 || parts[0] != "TIER" || parts[2] != "COLD" || parts[4] != "AFTER" {
+        return None;
+    }
+    Some((parts[1].to_owned(), parts[3].to_owned(), parse_duration(parts[5])?))
+}
+
+/// Parses
+///
+/// BACKUP TO /mnt/backups/tectonic
+/// BACKUP TO /mnt/backups/tectonic INCREMENTAL
+///
+/// into (folder, incremental)
+pub fn parse_backup(string: &str) -> Option<(String, bool)> {
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts.len() < 3 || parts.len() > 4 || parts[0] != "BACKUP" || parts[1] != "TO" || parts[2].is_empty() {
+        return None;
+    }
+    match parts.get(3) {
+        None => Some((parts[2].to_owned(), false)),
+        Some(&"INCREMENTAL") => Some((parts[2].to_owned(), true)),
+        Some(_) => None
+    }
+}
+
+/// Parses
+///
+/// ALERT SILENT btc_usdt AFTER 30s
+/// ALERT DISK ABOVE 500MB
+/// ALERT FLUSH ERRORS INTO alerts
+///
+/// into an alert rule, sizes are in MB or GB
+pub fn parse_alert(string: &str) -> Option<Rule> {
+    let mut parts : Vec<&str> = string.split(' ').collect();
+    let into = if parts.len() > 2 && parts[parts.len() - 2] == "INTO" {
+        let into = parts.pop()?.to_owned();
+        parts.pop();
+        Some(into)
+    } else {
+        None
+    };
+    if parts[0] != "ALERT" {
+        return None;
+    }
+    let condition = match parts.len() {
+        5 if parts[1] == "SILENT" && parts[3] == "AFTER" =>
+            Condition::Silent(parts[2].to_owned(), parse_duration(parts[4])?),
+        4 if parts[1] == "DISK" && parts[2] == "ABOVE" => {
+            let size = parts[3];
+            if size.len() < 3 {
+                return None;
+            }
+            let (n, unit) = size.split_at(size.len() - 2);
+            let mb = match unit {
+                "MB" => n.parse::<u64>().ok()?,
+                "GB" => n.parse::<u64>().ok()?.checked_mul(1024)?,
+                _ => return None
+            };
+            Condition::DiskAbove(mb.checked_mul(1024 * 1024)?)
+        }
+        3 if parts[1] == "FLUSH" && parts[2] == "ERRORS" => Condition::FlushErrors,
+        _ => return None
+    };
+    Some(Rule { condition, into })
+}
+
+/// Parses
+///
+/// JOIN btc_usdt_trades ASOF btc_usdt_quotes FROM 1505177459 TO 1505177460 TOLERANCE 500
+///
+/// into (trades store, quotes store, range in seconds, tolerance in ms).
+/// FROM..TO and TOLERANCE are optional.
+pub fn parse_join(string: &str) -> Option<(String, String, Option<(u32, u32)>, Option<u64>)> {
+    let mut parts : Vec<&str> = string.split(' ').collect();
+    if parts.len() < 4 || parts[0] != "JOIN" || parts[2] != "ASOF" {
+        return None;
+    }
+    let tolerance = if parts[parts.len() - 2] == "TOLERANCE" {
+        let tolerance = parts.pop()?.parse::<u64>().ok()?;
+        parts.pop();
+        Some(tolerance)
+    } else {
+        None
+    };
+    let range = match parts.len() {
+        4 => None,
+        8 if parts[4] == "FROM" && parts[6] == "TO" =>
+            Some((parts[5].parse::<u32>().ok()?, parts[7].parse::<u32>().ok()?)),
+        _ => return None
+    };
+    Some((parts[1].to_owned(), parts[3].to_owned(), range, tolerance))
+}
+
+/// Parses
+///
+/// SERIES btc_funding FROM 1505177459 TO 1505177460 INTERVAL 8h
+///
+/// into (store, range in seconds, interval in ms). FROM..TO is optional.
+pub fn parse_series(string: &str) -> Option<(String, Option<(u32, u32)>, u64)> {
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts.len() < 4 || parts[0] != "SERIES" || parts[parts.len() - 2] != "INTERVAL" {
+        return None;
+    }
+    let interval = parse_duration(parts[parts.len() - 1])?;
+    let range = match parts.len() {
+        4 => None,
+        8 if parts[2] == "FROM" && parts[4] == "TO" =>
+            Some((parts[3].parse::<u32>().ok()?, parts[5].parse::<u32>().ok()?)),
+        _ => return None
+    };
+    Some((parts[1].to_owned(), range, interval))
+}
+
+/// Parses
+///
+/// APPROX COUNT btc_usdt FROM 1505177459 TO 1505177460 ERROR 1%
+/// APPROX VWAP btc_usdt
+///
+/// into (aggregate, store, range in seconds, largest relative error).
+/// FROM..TO and ERROR are optional.
+pub fn parse_approx(string: &str) -> Option<(Aggregate, String, Option<(u32, u32)>, Option<f64>)> {
+    let mut parts : Vec<&str> = string.split(' ').collect();
+    if parts.len() < 3 || parts[0] != "APPROX" {
+        return None;
+    }
+    let aggregate = match parts[1] {
+        "COUNT" => Aggregate::Count,
+        "VWAP" => Aggregate::Vwap,
+        _ => return None
+    };
+    let mut error = None;
+    if parts.len() >= 5 && parts[parts.len() - 2] == "ERROR" {
+        let percent = parts[parts.len() - 1];
+        if !percent.ends_with('%') {
+            return None;
+        }
+        let percent = percent[..percent.len() - 1].parse::<f64>().ok()?;
+        if !percent.is_finite() || percent <= 0. {
+            return None;
+        }
+        error = Some(percent / 100.);
+        let len = parts.len() - 2;
+        parts.truncate(len);
+    }
+    let range = match parts.len() {
+        3 => None,
+        7 if parts[3] == "FROM" && parts[5] == "TO" =>
+            Some((parts[4].parse::<u32>().ok()?, parts[6].parse::<u32>().ok()?)),
+        _ => return None
+    };
+    Some((aggregate, parts[2].to_owned(), range, error))
+}
+
+/// Parses
+///
+/// TOPN btc_usdt 10 BY size FROM 1505177459 TO 1505177460
+///
+/// into (store, n, ranked by, range in seconds). FROM..TO is optional.
+pub fn parse_topn(string: &str) -> Option<(String, usize, By, Option<(u32, u32)>)> {
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts.len() < 5 || parts[0] != "TOPN" || parts[3] != "BY" {
+        return None;
+    }
+    let n = parts[2].parse::<usize>().ok()?;
+    if n == 0 {
+        return None;
+    }
+    let by = By::parse(parts[4])?;
+    let range = match parts.len() {
+        5 => None,
+        9 if parts[5] == "FROM" && parts[7] == "TO" =>
+            Some((parts[6].parse::<u32>().ok()?, parts[8].parse::<u32>().ok()?)),
+        _ => return None
+    };
+    Some((parts[1].to_owned(), n, by, range))
+}
+
+/// Parses
+///
+/// HIST btc_usdt FIELD size BUCKETS 50 FROM 1505177459 TO 1505177460 WHERE is_trade = t
+///
+/// into (store, field, buckets, range in seconds, filter). FROM..TO and WHERE are optional.
+pub fn parse_hist(string: &str) -> Option<(String, hist::Field, usize, Option<(u32, u32)>, RowFilter)> {
+    let (string, filter) = split_where(string)?;
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts.len() < 6 || parts[0] != "HIST" || parts[2] != "FIELD" || parts[4] != "BUCKETS" {
+        return None;
+    }
+    let field = hist::Field::parse(parts[3])?;
+    let buckets = parts[5].parse::<usize>().ok()?;
+    if buckets == 0 || buckets > hist::MAX_BUCKETS {
+        return None;
+    }
+    let range = match parts.len() {
+        6 => None,
+        10 if parts[6] == "FROM" && parts[8] == "TO" =>
+            Some((parts[7].parse::<u32>().ok()?, parts[9].parse::<u32>().ok()?)),
+        _ => return None
+    };
+    Some((parts[1].to_owned(), field, buckets, range, filter))
+}
+
+/// Parses
+///
+/// SUBSCRIBE btc_usdt
+/// SUBSCRIBE btc_usdt FROM OFFSET 1200 WHERE is_trade=1 AND size > 1.0
+///
+/// into (store, offset to resume after, filter). FROM OFFSET and WHERE are optional.
+pub fn parse_subscribe(string: &str) -> Option<(String, Option<u64>, RowFilter)> {
+    let (string, filter) = split_where(string)?;
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts[0] != "SUBSCRIBE" {
+        return None;
+    }
+    match parts.len() {
+        2 => Some((parts[1].to_owned(), None, filter)),
+        5 if parts[2] == "FROM" && parts[3] == "OFFSET" => Some((parts[1].to_owned(), Some(parts[4].parse().ok()?), filter)),
+        _ => None
+    }
+}
+
+/// Parses
+///
+/// CONFIG GET flush_interval
+/// CONFIG SET flush_interval 100000
+///
+/// into (key, value to set)
+pub fn parse_config(string: &str) -> Option<(String, Option<String>)> {
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts[0] != "CONFIG" {
+        return None;
+    }
+    match parts.len() {
+        3 if parts[1] == "GET" => Some((parts[2].to_owned(), None)),
+        4 if parts[1] == "SET" => Some((parts[2].to_owned(), Some(parts[3].to_owned()))),
+        _ => None
+    }
+}
+
+/// Parses
+///
+/// MULTICAST btc_usdt
+/// MULTICAST btc_usdt OFF
+///
+/// into (store, whether it is published)
+pub fn parse_multicast(string: &str) -> Option<(String, bool)> {
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts[0] != "MULTICAST" {
+        return None;
+    }
+    match parts.len() {
+        2 => Some((parts[1].to_owned(), true)),
+        3 if parts[2] == "OFF" => Some((parts[1].to_owned(), false)),
+        _ => None
+    }
+}
+
+/// Parses
+///
+/// PRECISION btc 4
+/// PRECISION btc OFF
+///
+/// into (store, decimal places)
+pub fn parse_precision(string: &str) -> Option<(String, Option<usize>)> {
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts.len() != 3 || parts[0] != "PRECISION" {
+        return None;
+    }
+    match parts[2] {
+        "OFF" => Some((parts[1].to_owned(), None)),
+        decimals => Some((parts[1].to_owned(), Some(decimals.parse::<usize>().ok()?)))
+    }
+}
+
+/// Parses
+///
+/// FLAGS btc synthetic corrected snapshot
+/// FLAGS btc OFF
+/// FLAGS btc
+///
+/// into (store, names of the flags), no names for OFF and None to return them
+pub fn parse_flags(string: &str) -> Option<(String, Option<Vec<String>>)> {
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts.len() < 2 || parts[0] != "FLAGS" {
+        return None;
+    }
+    let names = match &parts[2..] {
+        [] => None,
+        ["OFF"] => Some(Vec::new()),
+        names => Some(names.iter().map(|name| (*name).to_owned()).collect())
+    };
+    Some((parts[1].to_owned(), names))
+}
+
+/// Parses
+///
+/// ALIAS bcc_usdt FOR bch_usdt
+///
+/// into (alias, store)
+pub fn parse_alias(string: &str) -> Option<(String, String)> {
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts.len() != 4 || parts[0] != "ALIAS" || parts[2] != "FOR" {
+        return None;
+    }
+    Some((parts[1].to_owned(), parts[3].to_owned()))
+}
+
+/// Parses
+///
+/// GROUP binance = binance_*
+///
+/// into (group, store name pattern)
+pub fn parse_group(string: &str) -> Option<(String, String)> {
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts.len() != 4 || parts[0] != "GROUP" || parts[2] != "=" || parts[3].is_empty() {
+        return None;
+    }
+    Some((parts[1].to_owned(), parts[3].to_owned()))
+}
+
+/// `tag=value` pairs, without quotes so they stay valid in JSON
+fn parse_tags(parts: &[&str]) -> Option<Vec<(String, String)>> {
+    parts.iter().map(|part| {
+        let mut kv = part.splitn(2, '=');
+        let (tag, value) = (kv.next()?, kv.next()?);
+        if tag.is_empty() || part.contains('"') || part.contains('\\') {
+            return None;
+        }
+        Some((tag.to_owned(), value.to_owned()))
+    }).collect()
+}
+
+/// Parses
+///
+/// META btc exchange=binance asset_class=spot
+/// META btc asset_class=
+/// META btc
+///
+/// into (store, tags to set, an empty value removes a tag)
+pub fn parse_meta(string: &str) -> Option<(String, Vec<(String, String)>)> {
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts.len() < 2 || parts[0] != "META" || parts[1].is_empty() {
+        return None;
+    }
+    Some((parts[1].to_owned(), parse_tags(&parts[2..])?))
+}
+
+/// Parses
+///
+/// FIND exchange=binance asset_class=*
+///
+/// into (tag, value pattern) pairs a store must all have
+pub fn parse_find(string: &str) -> Option<Vec<(String, String)>> {
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts.len() < 2 || parts[0] != "FIND" {
+        return None;
+    }
+    let tags = parse_tags(&parts[1..])?;
+    if tags.iter().any(|&(_, ref value)| value.is_empty()) {
+        return None;
+    }
+    Some(tags)
+}
+
+/// Parses
+///
+/// RETAIN btc_usdt 7d
+/// RETAIN btc_usdt OFF
+///
+/// into (store, ttl in ms)
+pub fn parse_retain(string: &str) -> Option<(String, Option<u64>)> {
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts.len() != 3 || parts[0] != "RETAIN" {
+        return None;
+    }
+    match parts[2] {
+        "OFF" => Some((parts[1].to_owned(), None)),
+        ttl => Some((parts[1].to_owned(), Some(parse_duration(ttl)?)))
+    }
+}
+
+/// Parses
+///
+/// CREATE btc_usdt TTL 24h
+///
+/// into (store, ttl in ms)
+pub fn parse_create_ttl(string: &str) -> Option<(String, u64)> {
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts.len() != 4 || parts[0] != "CREATE" || parts[2] != "TTL" {
+        return None;
+    }
+    Some((parts[1].to_owned(), parse_duration(parts[3])?))
+}
+
+/// Comparison of a WHERE clause
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cmp {
+    Lt,
+    Le,
+    Eq,
+    Ge,
+    Gt,
+}
+
+impl Cmp {
+    fn parse(op: &str) -> Option<Cmp> {
+        Some(match op {
+            "<" => Cmp::Lt,
+            "<=" => Cmp::Le,
+            "=" => Cmp::Eq,
+            ">=" => Cmp::Ge,
+            ">" => Cmp::Gt,
+            _ => return None
+        })
+    }
+
+    fn holds(self, lhs: f32, rhs: f32) -> bool {
+        match self {
+            Cmp::Lt => lhs < rhs,
+            Cmp::Le => lhs <= rhs,
+            Cmp::Eq => lhs == rhs,
+            Cmp::Ge => lhs >= rhs,
+            Cmp::Gt => lhs > rhs,
+        }
+    }
+
+    /// whether it holds for some value within [min, max]
+    fn may_hold(self, min: f32, max: f32, rhs: f32) -> bool {
+        match self {
+            Cmp::Lt => min < rhs,
+            Cmp::Le => min <= rhs,
+            Cmp::Eq => min <= rhs && rhs <= max,
+            Cmp::Ge => max >= rhs,
+            Cmp::Gt => max > rhs,
+        }
+    }
+}
+
+/// Row predicate of a WHERE clause, `None` matches both values
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RowFilter {
+    pub is_trade: Option<bool>,
+    pub is_bid: Option<bool>,
+    pub price: Vec<(Cmp, f32)>,
+    pub size: Vec<(Cmp, f32)>,
+    /// (name, value) of the flags named by the store, see `flag`
+    pub flags: Vec<(String, bool)>,
+    /// bits of `flags` once resolved with `resolve_flags`, and their values
+    flag_mask: u8,
+    flag_bits: u8,
+}
+
+impl RowFilter {
+    pub fn matches(&self, up: &Update) -> bool {
+        self.is_trade.map_or(true, |is_trade| up.is_trade == is_trade)
+            && self.is_bid.map_or(true, |is_bid| up.is_bid == is_bid)
+            && self.price.iter().all(|&(cmp, value)| cmp.holds(up.price, value))
+            && self.size.iter().all(|&(cmp, value)| cmp.holds(up.size, value))
+            && up.flags & self.flag_mask == self.flag_bits
+    }
+
+    /// Find the bits of the flags tested, `names` are the flags of the store
+    pub fn resolve_flags(&mut self, names: &[String]) -> Result<(), String> {
+        self.flag_mask = 0;
+        self.flag_bits = 0;
+        for &(ref name, value) in self.flags.iter() {
+            let bit = match names.iter().position(|n| n == name) {
+                Some(i) => 1 << i,
+                None => return Err(format!("No flag named `{}`, see FLAGS.", name))
+            };
+            self.flag_mask |= bit;
+            if value {
+                self.flag_bits |= bit;
+            }
+        }
+        Ok(())
+    }
+
+    /// whether any row of a batch can match, from its zone maps, see `zonemap`
+    pub fn may_match(&self, zone: &dtf::BatchZone) -> bool {
+        self.price.iter().all(|&(cmp, value)| cmp.may_hold(zone.min_price, zone.max_price, value))
+            && self.size.iter().all(|&(cmp, value)| cmp.may_hold(zone.min_size, zone.max_size, value))
+    }
+
+    /// whether it tests price or size, so zone maps can skip batches
+    pub fn has_ranges(&self) -> bool {
+        !self.price.is_empty() || !self.size.is_empty()
+    }
+}
+
+/// `size>1.0` -> `size`, `>`, `1.0`
+fn tokenize_where(clause: &str) -> Vec<String> {
+    let mut tokens : Vec<String> = Vec::new();
+    let mut token = String::new();
+    let mut in_op = false;
+    for ch in clause.chars() {
+        let is_op = ch == '<' || ch == '>' || ch == '=';
+        if ch.is_whitespace() || is_op != in_op {
+            if !token.is_empty() {
+                tokens.push(token.clone());
+                token.clear();
+            }
+        }
+        if !ch.is_whitespace() {
+            token.push(ch);
+            in_op = is_op;
+        }
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    tokens
+}
+
+/// Parses the clause after WHERE, like
+///
+/// is_trade = t AND is_bid = f
+/// is_trade=1 AND size > 1.0
+///
+/// synthetic = f
+///
+/// into a `RowFilter`. Flags compare with `=` to t/f/1/0, price and size
+/// with `<`, `<=`, `=`, `>=` or `>`. Other names are the flags of the store,
+/// see `RowFilter::resolve_flags`.
+pub fn parse_where(clause: &str) -> Option<RowFilter> {
+    let tokens = tokenize_where(clause);
+    let mut filter = RowFilter::default();
+    let mut i = 0;
+    loop {
+        if tokens.len() < i + 3 {
+            return None;
+        }
+        let cmp = Cmp::parse(&tokens[i + 1])?;
+        let value = &tokens[i + 2];
+        let flag = || match value.as_str() {
+            "t" | "true" | "1" if cmp == Cmp::Eq => Some(true),
+            "f" | "false" | "0" if cmp == Cmp::Eq => Some(false),
+            _ => None
+        };
+        match tokens[i].as_str() {
+            "is_trade" => filter.is_trade = Some(flag()?),
+            "is_bid" => filter.is_bid = Some(flag()?),
+            "price" => filter.price.push((cmp, value.parse().ok()?)),
+            "size" => filter.size.push((cmp, value.parse().ok()?)),
+            name if !flag::RESERVED.contains(&name) && name.starts_with(|c: char| c.is_ascii_lowercase()) =>
+                filter.flags.push((name.to_owned(), flag()?)),
+            _ => return None
+        }
+        i += 3;
+        if tokens.get(i).map(|t| t.as_str()) == Some("AND") {
+            i += 1;
+        } else {
+            break;
+        }
+    }
+    if i != tokens.len() {
+        return None;
+    }
+    Some(filter)
+}
+
+/// `[head] WHERE [clause]` -> (head, filter of the clause)
+fn split_where(string: &str) -> Option<(&str, RowFilter)> {
+    match string.find(" WHERE ") {
+        Some(i) => Some((&string[..i], parse_where(&string[i + 7..])?)),
+        None => Some((string, RowFilter::default()))
+    }
+}
+
+/// Parses
+///
+/// COPY btc_usdt TO btc_usdt_trades FROM 1505177459 TO 1505177460 WHERE is_trade = t AND is_bid = f
+///
+/// into (src, dst, range in seconds, filter). FROM..TO and WHERE are optional.
+pub fn parse_copy(string: &str) -> Option<(String, String, Option<(u32, u32)>, RowFilter)> {
+    let (string, filter) = split_where(string)?;
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts.len() < 4 || parts[0] != "COPY" || parts[2] != "TO" {
+        return None;
+    }
+    let range = match parts.len() {
+        4 => None,
+        8 if parts[4] == "FROM" && parts[6] == "TO" =>
+            Some((parts[5].parse::<u32>().ok()?, parts[7].parse::<u32>().ok()?)),
+        _ => return None
+    };
+    Some((parts[1].to_owned(), parts[3].to_owned(), range, filter))
+}
+
+/// Parses
+///
+/// REPLAY btc_usdt FROM 1505177459 TO 1505177460 SPEED 10x
+///
+/// into (store, range in seconds, speed). Range and speed (default 1x) are optional.
+pub fn parse_replay(string: &str) -> Option<(String, Option<(u32, u32)>, f64)> {
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts.len() < 2 || parts[0] != "REPLAY" {
+        return None;
+    }
+    let mut i = 2;
+
+    let range = if parts.get(i) == Some(&"FROM") {
+        if parts.len() < i + 4 || parts[i + 2] != "TO" {
+            return None;
+        }
+        let range = (parts[i + 1].parse::<u32>().ok()?, parts[i + 3].parse::<u32>().ok()?);
+        i += 4;
+        Some(range)
+    } else {
+        None
+    };
+
+    let speed = if parts.get(i) == Some(&"SPEED") {
+        let speed = parts.get(i + 1)?;
+        i += 2;
+        speed.trim_right_matches('x').parse::<f64>().ok()?
+    } else {
+        1.
+    };
+
+    if i != parts.len() || !(speed > 0.) {
+        return None;
+    }
+    Some((parts[1].to_owned(), range, speed))
+}
+
+/// Parses
+///
+/// SYNC FROM 10.0.0.2:9001 STORES bnc_* SINCE 1505177459 VERIFY
+///
+/// into (address, store name pattern, since in seconds, verify). Since and
+/// VERIFY are optional.
+pub fn parse_sync(string: &str) -> Option<(String, String, Option<u32>, bool)> {
+    let mut parts : Vec<&str> = string.split(' ').collect();
+    let verify = parts.last() == Some(&"VERIFY");
+    if verify {
+        parts.pop();
+    }
+    if parts.len() < 5 || parts[0] != "SYNC" || parts[1] != "FROM" || parts[3] != "STORES" {
+        return None;
+    }
+    let since = match parts.len() {
+        5 => None,
+        7 if parts[5] == "SINCE" => Some(parts[6].parse::<u32>().ok()?),
+        _ => return None
+    };
+    Some((parts[2].to_owned(), parts[4].to_owned(), since, verify))
+}
+
+/// whether a store name matches a pattern, `*` matches any characters
+pub fn matches_pattern(pattern: &str, name: &str) -> bool {
+    match pattern.find('*') {
+        None => pattern == name,
+        Some(i) => {
+            if !name.starts_with(&pattern[..i]) {
+                return false;
+            }
+            let (rest, name) = (&pattern[(i + 1)..], &name[i..]);
+            name.char_indices()
+                .map(|(j, _)| j)
+                .chain(Some(name.len()))
+                .any(|j| matches_pattern(rest, &name[j..]))
+        }
+    }
+}
+
+/// Parses
+///
+/// 10.0.0.0/8,192.168.1.7,2001:db8::/32
+///
+/// into networks, an address without prefix is a network of one. `none` is no network.
+pub fn parse_cidrs(string: &str) -> Option<Vec<Cidr>> {
+    if string == "none" {
+        return Some(Vec::new());
+    }
+    string.split(',').map(|cidr| {
+        let mut parts = cidr.trim().splitn(2, '/');
+        let addr = parts.next()?.parse::<IpAddr>().ok()?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|&prefix| prefix <= max_prefix)?,
+            None => max_prefix
+        };
+        Some(Cidr { addr, prefix })
+    }).collect()
+}
+
+/// Parses
+///
+/// EXPLAIN GET 100 FROM 1505177459 TO 1505177460 AS JSON LIMIT 10
+///
+/// into (count, range in seconds, page size) of the GET. The count is None
+/// for ALL, the range and page size are optional.
+pub fn parse_explain(string: &str) -> Option<(Option<u32>, Option<(u32, u32)>, Option<u32>)> {
+    if !string.starts_with("EXPLAIN GET ") {
+        return None;
+    }
+    let get = &string[8..];
+    let count = match get[4..].split(' ').next()? {
+        "ALL" => None,
+        n => Some(n.parse::<u32>().ok()?)
+    };
+    let range = if get.contains(" FROM ") {
+        Some((parse_keyword::<u32>(get, " FROM ")?, parse_keyword::<u32>(get, " TO ")?))
+    } else {
+        None
+    };
+    let limit = if get.contains(" LIMIT ") {
+        Some(parse_paging(get)?.0)
+    } else {
+        None
+    };
+    Some((count, range, limit))
+}
+
+/// Parses the socket of `--listen`
+///
+/// 0.0.0.0:9443,tls=/etc/tectonic/identity.p12,allow=read
+///
+/// `allow` is `all` (the default), `read` for READ_COMMANDS, or keywords
+/// separated by `+`, e.g. `allow=PING+GET+CONFIG GET`.
+pub fn parse_listen(string: &str) -> Option<Listen> {
+    let mut parts = string.split(',');
+    let addr = parts.next()?.trim();
+    if !addr.contains(':') {
+        return None;
+    }
+    let mut listen = Listen { addr: addr.to_owned(), tls: None, allow: Allow::All };
+    for part in parts {
+        let mut option = part.splitn(2, '=');
+        match (option.next(), option.next()) {
+            (Some("tls"), Some(fname)) if !fname.is_empty() => listen.tls = Some(fname.to_owned()),
+            (Some("allow"), Some("all")) => listen.allow = Allow::All,
+            (Some("allow"), Some("read")) => listen.allow = Allow::read_only(),
+            (Some("allow"), Some(keywords)) if !keywords.is_empty() =>
+                listen.allow = Allow::Only(keywords.split('+').map(|keyword| keyword.to_uppercase()).collect()),
+            _ => return None
+        }
+    }
+    Some(listen)
+}
+
+/// Parses a template, see `settings::Template`
+///
+/// memory,ttl=7d,tier=/mnt/hdd:30d,precision=4,multicast,series=funding
+pub fn parse_template(string: &str) -> Option<Template> {
+    let mut template = Template::default();
+    if string == "default" {
+        return Some(template);
+    }
+    for part in string.split(',') {
+        let mut option = part.trim().splitn(2, '=');
+        match (option.next(), option.next()) {
+            (Some("memory"), None) => template.memory = true,
+            (Some("multicast"), None) => template.multicast = true,
+            (Some("ttl"), Some(ttl)) => template.ttl = Some(parse_duration(ttl)?),
+            (Some("tier"), Some(tier)) => {
+                // the folder may contain `:`, e.g. on Windows
+                let mut tier = tier.rsplitn(2, ':');
+                let ms = parse_duration(tier.next()?)?;
+                let cold_folder = tier.next().filter(|folder| !folder.is_empty())?;
+                template.tier = Some((cold_folder.to_owned(), ms));
+            },
+            (Some("precision"), Some(decimals)) =>
+                template.precision = Some(decimals.parse::<usize>().ok().filter(|&decimals| decimals <= precision::MAX_DECIMALS)?),
+            (Some("series"), Some(kind)) => template.series = Some(series::Kind::parse(kind)?),
+            _ => return None
+        }
+    }
+    Some(template)
+}
+
+/// Parses `protocol=1 store=binance_btc_usdt format=binary`, the settings of a `HELLO`
+pub fn parse_hello(string: &str) -> Option<Hello> {
+    let mut hello = Hello::default();
+    for part in string.split_whitespace() {
+        let mut setting = part.splitn(2, '=');
+        match (setting.next(), setting.next()) {
+            (Some("protocol"), Some(protocol)) =>
+                hello.protocol = Some(protocol.parse::<u32>().ok().filter(|&protocol| protocol > 0)?),
+            (Some("store"), Some(store)) if !store.is_empty() => hello.store = Some(store.to_owned()),
+            (Some("format"), Some(format)) => hello.format = Some(hello::parse_format(format)?),
+            _ => return None
+        }
+    }
+    Some(hello)
+}
+
+/// Parses
+///
+/// COMPACT btc_usdt
+/// PRUNE
+/// INTEGRITY deep
+/// BACKUP TO /mnt/backups/tectonic INCREMENTAL
+/// FLUSH ALL
+///
+/// into a job, see `job`
+pub fn parse_job(string: &str) -> Option<job::Kind> {
+    let parts : Vec<&str> = string.split(' ').collect();
+    match parts.as_slice() {
+        ["FLUSH", "ALL"] => Some(job::Kind::FlushAll),
+        ["CLEAR", "ALL"] => Some(job::Kind::ClearAll),
+        ["COMPACT", store] if !store.is_empty() => Some(job::Kind::Compact((*store).to_owned())),
+        ["PRUNE"] => Some(job::Kind::Prune),
+        ["INTEGRITY", "fast"] => Some(job::Kind::Integrity(IntegrityScan::Fast)),
+        ["INTEGRITY", "deep"] => Some(job::Kind::Integrity(IntegrityScan::Deep)),
+        ["BACKUP", ..] => parse_backup(string).map(|(folder, incremental)| job::Kind::Backup(folder, incremental)),
+        _ => None
+    }
+}
+
+/// Parses a schedule, see `settings::Schedule`
+///
+/// 30 */6 * * 1-5 BACKUP TO /mnt/backups/tectonic INCREMENTAL
+pub fn parse_schedule(string: &str) -> Option<Schedule> {
+    let parts : Vec<&str> = string.trim().splitn(6, ' ').collect();
+    if parts.len() < 6 {
+        return None;
+    }
+    let minutes = parse_cron_field(parts[0], 0, 59)?;
+    let hours = parse_cron_field(parts[1], 0, 23)?;
+    let days = parse_cron_field(parts[2], 1, 31)?;
+    let months = parse_cron_field(parts[3], 1, 12)?;
+    // 7 is Sunday too
+    let weekdays = parse_cron_field(parts[4], 0, 7)?;
+    let cron = Cron {
+        spec: parts[..5].join(" "),
+        minutes,
+        hours: hours as u32,
+        days: days as u32,
+        months: months as u16,
+        weekdays: ((weekdays | weekdays >> 7) & 0x7f) as u8,
+        both_days: !parts[2].starts_with('*') && !parts[4].starts_with('*'),
+    };
+    Some(Schedule { cron, job: parse_job(parts[5])? })
+}
+
+/// the bits of the values of a crontab field within [min, max]
+fn parse_cron_field(field: &str, min: u32, max: u32) -> Option<u64> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let mut step = part.splitn(2, '/');
+        let range = step.next()?;
+        let step = match step.next() {
+            Some(step) => step.parse::<u32>().ok().filter(|&step| step > 0)?,
+            None => 1
+        };
+        let (first, last) = if range == "*" {
+            (min, max)
+        } else {
+            let mut bounds = range.splitn(2, '-');
+            let first = bounds.next()?.parse::<u32>().ok()?;
+            let last = match bounds.next() {
+                Some(last) => last.parse::<u32>().ok()?,
+                None => first
+            };
+            (first, last)
+        };
+        if first < min || last > max || first > last {
+            return None;
+        }
+        for value in (first..=last).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Some(bits)
+}
+
+/// Parses an expression over stores, see `derived`
+///
+/// mid(bnc_btc_usdt) - mid(okx_btc_usdt) * 2
+pub fn parse_expr(string: &str) -> Option<Expr> {
+    let tokens = tokenize_expr(string)?;
+    let mut pos = 0;
+    let expr = parse_sum(&tokens, &mut pos)?;
+    if pos == tokens.len() { Some(expr) } else { None }
+}
+
+/// numbers, names and the single characters `+-*/()`
+fn tokenize_expr(string: &str) -> Option<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut chars = string.chars().peekable();
+    while let Some(&ch) = chars.peek() {
+        if ch.is_whitespace() {
+            chars.next();
+        } else if "+-*/()".contains(ch) {
+            tokens.push(ch.to_string());
+            chars.next();
+        } else if ch.is_alphanumeric() || ch == '_' || ch == '.' {
+            let mut token = String::new();
+            while let Some(&ch) = chars.peek() {
+                if !(ch.is_alphanumeric() || ch == '_' || ch == '.' || ch == '@') {
+                    break;
+                }
+                token.push(ch);
+                chars.next();
+            }
+            tokens.push(token);
+        } else {
+            return None;
+        }
+    }
+    Some(tokens)
+}
+
+/// terms joined by `+` and `-`
+fn parse_sum(tokens: &[String], pos: &mut usize) -> Option<Expr> {
+    let mut expr = parse_product(tokens, pos)?;
+    while let Some(op) = tokens.get(*pos).and_then(|token| match token.as_str() {
+        "+" => Some(Op::Add),
+        "-" => Some(Op::Sub),
+        _ => None
+    }) {
+        *pos += 1;
+        expr = Expr::Bin(Box::new(expr), op, Box::new(parse_product(tokens, pos)?));
+    }
+    Some(expr)
+}
+
+/// factors joined by `*` and `/`
+fn parse_product(tokens: &[String], pos: &mut usize) -> Option<Expr> {
+    let mut expr = parse_factor(tokens, pos)?;
+    while let Some(op) = tokens.get(*pos).and_then(|token| match token.as_str() {
+        "*" => Some(Op::Mul),
+        "/" => Some(Op::Div),
+        _ => None
+    }) {
+        *pos += 1;
+        expr = Expr::Bin(Box::new(expr), op, Box::new(parse_factor(tokens, pos)?));
+    }
+    Some(expr)
+}
+
+/// a number, `mid(db)`, `-factor` or `(expression)`
+fn parse_factor(tokens: &[String], pos: &mut usize) -> Option<Expr> {
+    let token = tokens.get(*pos)?;
+    *pos += 1;
+    match token.as_str() {
+        "-" => Some(Expr::Neg(Box::new(parse_factor(tokens, pos)?))),
+        "(" => {
+            let expr = parse_sum(tokens, pos)?;
+            if tokens.get(*pos).map(|token| token.as_str()) != Some(")") {
+                return None;
+            }
+            *pos += 1;
+            Some(expr)
+        },
+        token => match token.parse::<f32>() {
+            Ok(n) => Some(Expr::Num(n)),
+            Err(_) => {
+                let field = Field::parse(token)?;
+                let store_name = match (tokens.get(*pos), tokens.get(*pos + 1), tokens.get(*pos + 2)) {
+                    (Some(open), Some(store_name), Some(close)) if open == "(" && close == ")" => store_name.clone(),
+                    _ => return None
+                };
+                *pos += 3;
+                Some(Expr::Store(field, store_name))
+            }
+        }
+    }
+}
+
+/// Parses
+///
+/// CREATE VIRTUAL btc_spread AS mid(bnc_btc_usdt) - mid(okx_btc_usdt) INTERVAL 1s MATERIALIZED
+///
+/// into (store, expression, interval in ms, materialized). INTERVAL and MATERIALIZED are optional.
+pub fn parse_create_virtual(string: &str) -> Option<(String, Expr, Option<u64>, bool)> {
+    if !string.starts_with("CREATE VIRTUAL ") {
+        return None;
+    }
+    let mut string = &string[15..];
+    let materialized = string.ends_with(" MATERIALIZED");
+    if materialized {
+        string = &string[..(string.len() - 13)];
+    }
+    let interval = match string.rfind(" INTERVAL ") {
+        Some(i) => {
+            let interval = parse_duration(&string[(i + 10)..])?;
+            string = &string[..i];
+            Some(interval)
+        },
+        None => None
+    };
+    let mut parts = string.splitn(2, " AS ");
+    let (name, expr) = (parts.next()?, parts.next()?);
+    if name.is_empty() || name.contains(' ') {
+        return None;
+    }
+    Some((name.to_owned(), parse_expr(expr)?, interval, materialized))
+}
+
+/// Parses
+///
+/// CREATE btc_usdt LIKE binance
+///
+/// into (store, store or template to copy the settings of)
+pub fn parse_create_like(string: &str) -> Option<(String, String)> {
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts.len() != 4 || parts[0] != "CREATE" || parts[2] != "LIKE" {
+        return None;
+    }
+    Some((parts[1].to_owned(), parts[3].to_owned()))
+}
+
+/// Parses the store name between the words of a command, e.g.
+///
+/// EXISTS btc_usdt AS JSON
+///
+/// with `before` ["EXISTS"] and `after` ["AS", "JSON"] into btc_usdt.
+pub fn parse_store_between(string: &str, before: &[&str], after: &[&str]) -> Option<String> {
+    let parts : Vec<&str> = string.split_whitespace().collect();
+    if parts.len() != before.len() + 1 + after.len()
+        || parts[..before.len()] != *before
+        || parts[(before.len() + 1)..] != *after {
+        return None;
+    }
+    Some(parts[before.len()].to_owned())
+}
+
+/// Parses
+///
+/// UPLOAD INTO btc_usdt 1024
+///
+/// into (store, length in bytes of the dtf file following the command).
+pub fn parse_upload(string: &str) -> Option<(String, u64)> {
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts.len() != 4 || parts[0] != "UPLOAD" || parts[1] != "INTO" {
+        return None;
+    }
+    Some((parts[2].to_owned(), parts[3].parse::<u64>().ok()?))
+}
+
+/// Parses
+///
+/// DOWNLOAD btc_usdt FROM 1505177459 TO 1505177460
+/// CHECKSUMS btc_usdt FROM 1505177459 TO 1505177460
+///
+/// into (store, range in seconds). The range is optional.
+pub fn parse_download(string: &str) -> Option<(String, Option<(u32, u32)>)> {
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts.len() < 2 || (parts[0] != "DOWNLOAD" && parts[0] != "CHECKSUMS") {
+        return None;
+    }
+    let range = match parts.len() {
+        2 => None,
+        6 if parts[2] == "FROM" && parts[4] == "TO" =>
+            Some((parts[3].parse::<u32>().ok()?, parts[5].parse::<u32>().ok()?)),
+        _ => return None
+    };
+    Some((parts[1].to_owned(), range))
+}
+
+/// Parses
+///
+/// DELETE FROM btc_usdt WHERE ts BETWEEN 1505177459.658 AND 1505177460
+///
+/// into (store, min_ts, max_ts) with timestamps in ms
+pub fn parse_delete(string: &str) -> Option<(String, u64, u64)> {
+    let parts : Vec<&str> = string.split(' ').collect();
+    if parts.len() != 9
+        || parts[0] != "DELETE" || parts[1] != "FROM" || parts[3] != "WHERE"
+        || parts[4] != "ts" || parts[5] != "BETWEEN" || parts[7] != "AND" {
+        return None;
+    }
+    let min_ts = parts[6].parse::<f64>().ok()?;
+    let max_ts = parts[8].parse::<f64>().ok()?;
+    if min_ts < 0. || min_ts > max_ts {
+        return None;
+    }
+    Some((parts[2].to_owned(), (min_ts * 1000.).round() as u64, (max_ts * 1000.).round() as u64))
+}
+
+/// Splits off the idempotency key a client appends when replaying its spool
+///
+/// ADD 1505177459.658, 139010, t, f, 0.07, 7.6; INTO db KEY spool:12
+///
+/// returns ("ADD ...; INTO db", Some("spool:12"))
+pub fn parse_idempotency_key(string: &str) -> (&str, Option<&str>) {
+    match string.rfind(" KEY ") {
+        Some(index) => (&string[..index], Some(&string[(index + 5)..])),
+        None => (string, None)
+    }
+}
+
+/// Parses the paging clause of a GET command
+///
+/// GET ALL AS JSON LIMIT 100 CURSOR 1505177459685:139010
+///
+/// into `(limit, cursor)`, the cursor being the (ts, seq) of the last row of
+/// the previous page. None for the first page.
+pub fn parse_paging(string: &str) -> Option<(u32, Option<(u64, u32)>)> {
+    let limit = parse_keyword::<u32>(string, " LIMIT ")?;
+    let cursor = if string.contains(" CURSOR ") {
+        Some(parse_cursor(&parse_keyword::<String>(string, " CURSOR ")?)?)
+    } else {
+        None
+    };
+
+    if limit == 0 {
+        None
+    } else {
+        Some((limit, cursor))
+    }
+}
+
+/// Parses a paging cursor `ts:seq`, see `parse_paging`
+pub fn parse_cursor(token: &str) -> Option<(u64, u32)> {
+    let mut parts = token.splitn(2, ':');
+    let ts = parts.next()?.parse::<u64>().ok()?;
+    let seq = parts.next()?.parse::<u32>().ok()?;
+    Some((ts, seq))
+}
+
+/// Whether a read scans a whole store, see `--shed_reads_above`
+///
+/// GET ALL AS JSON
+///
+/// reads every row, GET ALL FROM 1505177459 TO 1505177460 or GET ALL LIMIT 100
+/// only a range or a page of it.
+pub fn is_full_scan(string: &str) -> bool {
+    let bounded = [" FROM ", " LIMIT ", " AFTER "].iter().any(|keyword| string.contains(keyword));
+    let scan = ["GET ALL", "CHECKSUMS ", "COPY ", "DOWNLOAD ", "JOIN "].iter().any(|keyword| string.starts_with(keyword));
+    scan && !bounded
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use state::Encoding;
     #[test]
     fn should_parse_string_not_okay() {
         let string = "1505177459.658, 139010,,, f, t, 0.0703629, 7.65064249;";
@@ -84,7 +1490,8 @@ mod tests {
             is_trade: false,
             is_bid: true,
             price: 0.0703629,
-            size: 7.65064249
+            size: 7.65064249,
+            flags: 0
         };
         assert_eq!(target, parse_line(&string).unwrap());
 
@@ -96,9 +1503,16 @@ mod tests {
             is_trade: true,
             is_bid: false,
             price: 0.0703620,
-            size: 7.65064240
+            size: 7.65064240,
+            flags: 0
         };
         assert_eq!(target1, parse_line(&string1).unwrap());
+
+        let string2 = "1505177459.65, 139010, t, f, 0.0703620, 7.65064240, 5;";
+        assert_eq!(Update { flags: 5, ..target1 }, parse_line(&string2).unwrap());
+        assert!(parse_line("1505177459.65, 139010, t, f, 0.0703620, 7.65064240, 64;").is_none());
+        assert!(parse_line("1505177459.65, 139010, t, f, 0.0703620, 7.65064240, 5, 5;").is_none());
+        assert_eq!(parse_line("1505177459.65, 0, f, f, -0.0001, 0;").unwrap().price, -0.0001);
     }
 
     #[test]
@@ -119,9 +1533,606 @@ mod tests {
             is_trade: true,
             is_bid: false,
             price: 0.0703620,
-            size: 7.65064240
+            size: 7.65064240,
+            flags: 0
         };
         assert_eq!((Some(target), Some("dbname".to_owned())),
                     parse_add_into(cmd));
     }
+
+    #[test]
+    fn should_parse_keyword_ok() {
+        assert_eq!(parse_keyword::<u32>("GET 10 FROM 1 TO 2", " FROM "), Some(1));
+        assert_eq!(parse_keyword::<u32>("GET 10 FROM 1 TO 2", " TO "), Some(2));
+        assert_eq!(parse_keyword::<u32>("GET 10 FROM x TO 2", " FROM "), None);
+        assert_eq!(parse_keyword::<u32>("GET 10", " FROM "), None);
+    }
+
+    #[test]
+    fn should_parse_idempotency_key_ok() {
+        assert_eq!(parse_idempotency_key("ADD 1, 1, t, f, 1, 1; INTO db KEY spool:12"),
+                    ("ADD 1, 1, t, f, 1, 1; INTO db", Some("spool:12")));
+        assert_eq!(parse_idempotency_key("ADD 1, 1, t, f, 1, 1; INTO db"),
+                    ("ADD 1, 1, t, f, 1, 1; INTO db", None));
+    }
+
+    #[test]
+    fn should_parse_interval_ok() {
+        assert_eq!(parse_interval("1m"), Some(1));
+        assert_eq!(parse_interval("4h"), Some(240));
+        assert_eq!(parse_interval("1d"), Some(1440));
+        assert_eq!(parse_interval("0m"), None);
+        assert_eq!(parse_interval("1s"), None);
+        assert_eq!(parse_interval("m"), None);
+    }
+
+    #[test]
+    fn should_normalize_and_validate_names() {
+        assert_eq!(normalize_name("BTC-USDT"), "btc_usdt");
+        assert!(validate_name("btc_usdt").is_ok());
+        assert!(validate_name("BTC-usdt2").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("btc usdt").is_err());
+        assert!(validate_name("../btc").is_err());
+        assert!(validate_name(&"a".repeat(65)).is_err());
+    }
+
+    #[test]
+    fn should_parse_bulkadd_ok() {
+        assert_eq!(parse_bulkadd("BULKADD"), Some((None, None)));
+        assert_eq!(parse_bulkadd("BULKADD INTO btc"), Some((Some("btc".to_owned()), None)));
+        assert_eq!(parse_bulkadd("BULKADD INTO btc COUNT 3 CRC 9AE0DAAF"),
+            Some((Some("btc".to_owned()), Some(BulkDeclaration { count: 3, crc: Some(0x9ae0_daaf) }))));
+        assert_eq!(parse_bulkadd("BULKADD COUNT 3"),
+            Some((None, Some(BulkDeclaration { count: 3, crc: None }))));
+        assert_eq!(parse_bulkadd("BULKADD INTO btc CRC 9ae0daaf"), None);
+        assert_eq!(parse_bulkadd("BULKADD COUNT x"), None);
+        assert_eq!(parse_bulkadd("BULKADD INTO"), None);
+    }
+
+    #[test]
+    fn should_parse_tier_ok() {
+        assert_eq!(parse_tier("TIER btc COLD /mnt/hdd AFTER 7d"),
+            Some(("btc".to_owned(), "/mnt/hdd".to_owned(), 7 * 24 * 60 * 60 * 1000)));
+        assert_eq!(parse_tier("TIER btc COLD /mnt/hdd"), None);
+        assert_eq!(parse_tier("TIER btc COLD /mnt/hdd AFTER 7x"), None);
+    }
+
+    #[test]
+    fn should_parse_backup_ok() {
+        assert_eq!(parse_backup("BACKUP TO /mnt/b"), Some(("/mnt/b".to_owned(), false)));
+        assert_eq!(parse_backup("BACKUP TO /mnt/b INCREMENTAL"), Some(("/mnt/b".to_owned(), true)));
+        assert_eq!(parse_backup("BACKUP TO /mnt/b FULL"), None);
+        assert_eq!(parse_backup("BACKUP /mnt/b"), None);
+    }
+
+    #[test]
+    fn should_parse_alias_ok() {
+        assert_eq!(parse_alias("ALIAS bcc FOR bch"), Some(("bcc".to_owned(), "bch".to_owned())));
+        assert_eq!(parse_alias("ALIAS bcc bch"), None);
+        assert_eq!(parse_alias("ALIAS bcc FOR bch FOR btc"), None);
+    }
+
+    #[test]
+    fn should_parse_group_ok() {
+        assert_eq!(parse_group("GROUP bnc = bnc_*"), Some(("bnc".to_owned(), "bnc_*".to_owned())));
+        assert_eq!(parse_group("GROUP bnc bnc_*"), None);
+        assert_eq!(parse_group("GROUP bnc = "), None);
+    }
+
+    #[test]
+    fn should_parse_meta_ok() {
+        let tags = vec![("exchange".to_owned(), "binance".to_owned()), ("asset_class".to_owned(), "".to_owned())];
+        assert_eq!(parse_meta("META btc exchange=binance asset_class="), Some(("btc".to_owned(), tags)));
+        assert_eq!(parse_meta("META btc"), Some(("btc".to_owned(), vec![])));
+        assert_eq!(parse_meta("META btc exchange"), None);
+        assert_eq!(parse_meta("META btc =binance"), None);
+        assert_eq!(parse_meta("META btc exchange=\"binance\""), None);
+        assert_eq!(parse_find("FIND exchange=binance asset_class=*"),
+            Some(vec![("exchange".to_owned(), "binance".to_owned()), ("asset_class".to_owned(), "*".to_owned())]));
+        assert_eq!(parse_find("FIND exchange="), None);
+        assert_eq!(parse_find("FIND"), None);
+    }
+
+    #[test]
+    fn should_parse_retain_ok() {
+        assert_eq!(parse_retain("RETAIN btc 7d"), Some(("btc".to_owned(), Some(7 * 24 * 60 * 60 * 1000))));
+        assert_eq!(parse_retain("RETAIN btc OFF"), Some(("btc".to_owned(), None)));
+        assert_eq!(parse_retain("RETAIN btc 7x"), None);
+        assert_eq!(parse_retain("RETAIN btc"), None);
+    }
+
+    #[test]
+    fn should_parse_alert_ok() {
+        let silent = parse_alert("ALERT SILENT btc AFTER 30s").unwrap();
+        assert_eq!(silent, Rule { condition: Condition::Silent("btc".to_owned(), 30_000), into: None });
+        let disk = parse_alert("ALERT DISK ABOVE 2GB INTO alerts").unwrap();
+        assert_eq!(disk, Rule { condition: Condition::DiskAbove(2 * 1024 * 1024 * 1024), into: Some("alerts".to_owned()) });
+        assert_eq!(parse_alert("ALERT FLUSH ERRORS").unwrap().condition, Condition::FlushErrors);
+        assert_eq!(parse_alert("ALERT DISK ABOVE 2TB"), None);
+        assert_eq!(parse_alert("ALERT SILENT btc"), None);
+        assert_eq!(parse_alert("ALERT FLUSH ERRORS INTO"), None);
+
+        // rules are kept as the command that adds them
+        for rule in &[silent, disk] {
+            assert_eq!(parse_alert(&rule.to_string()).as_ref(), Some(rule));
+        }
+    }
+
+    #[test]
+    fn should_parse_join_ok() {
+        assert_eq!(parse_join("JOIN t ASOF q"), Some(("t".to_owned(), "q".to_owned(), None, None)));
+        assert_eq!(parse_join("JOIN t ASOF q FROM 1 TO 2"), Some(("t".to_owned(), "q".to_owned(), Some((1, 2)), None)));
+        assert_eq!(parse_join("JOIN t ASOF q TOLERANCE 500"), Some(("t".to_owned(), "q".to_owned(), None, Some(500))));
+        assert_eq!(parse_join("JOIN t ASOF q TOLERANCE x"), None);
+        assert_eq!(parse_join("JOIN t ASOF q FROM 1"), None);
+        assert_eq!(parse_join("JOIN t q"), None);
+    }
+
+    #[test]
+    fn should_parse_expr_ok() {
+        let expr = parse_expr("mid(a) - mid(b@1m) * 2").unwrap();
+        assert_eq!(expr.stores(), vec!["a".to_owned(), "b@1m".to_owned()]);
+        assert_eq!(expr.to_string(), "mid(a) - mid(b@1m) * 2");
+        let value = |field: Field, store_name: &str| match (field, store_name) {
+            (Field::Mid, "a") => Some(10.),
+            (Field::Mid, "b@1m") => Some(3.),
+            _ => None
+        };
+        assert_eq!(expr.eval(&value), Some(4.));
+        assert_eq!(parse_expr("(mid(a) - mid(b@1m)) * 2").unwrap().eval(&value), Some(14.));
+        assert_eq!(parse_expr("(mid(a) - mid(b@1m)) * 2").unwrap().to_string(), "(mid(a) - mid(b@1m)) * 2");
+        assert_eq!(parse_expr("-mid(a) / (2 - 2)").unwrap().eval(&value), None);
+        assert_eq!(parse_expr("last(a)").unwrap().eval(&value), None);
+        assert_eq!(parse_expr("mid(a) -"), None);
+        assert_eq!(parse_expr("avg(a)"), None);
+        assert_eq!(parse_expr("(mid(a)"), None);
+        assert_eq!(parse_expr("mid(a) % 2"), None);
+    }
+
+    #[test]
+    fn should_parse_create_virtual_ok() {
+        let (name, expr, interval, materialized) =
+            parse_create_virtual("CREATE VIRTUAL s AS mid(a) - mid(b) INTERVAL 1s MATERIALIZED").unwrap();
+        assert_eq!((name.as_str(), expr.to_string(), interval, materialized), ("s", "mid(a) - mid(b)".to_owned(), Some(1000), true));
+        let (_, _, interval, materialized) = parse_create_virtual("CREATE VIRTUAL s AS last(a)").unwrap();
+        assert_eq!((interval, materialized), (None, false));
+        assert_eq!(parse_create_virtual("CREATE VIRTUAL s AS last(a) INTERVAL 1x"), None);
+        assert_eq!(parse_create_virtual("CREATE VIRTUAL s last(a)"), None);
+        assert_eq!(parse_create_virtual("CREATE VIRTUAL AS last(a)"), None);
+    }
+
+    #[test]
+    fn should_parse_series_ok() {
+        assert_eq!(parse_series("SERIES f INTERVAL 8h"), Some(("f".to_owned(), None, 8 * 60 * 60 * 1000)));
+        assert_eq!(parse_series("SERIES f FROM 1 TO 2 INTERVAL 1d"), Some(("f".to_owned(), Some((1, 2)), 24 * 60 * 60 * 1000)));
+        assert_eq!(parse_series("SERIES f INTERVAL 0h"), None);
+        assert_eq!(parse_series("SERIES f FROM 1 INTERVAL 1h"), None);
+        assert_eq!(parse_series("SERIES f"), None);
+    }
+
+    #[test]
+    fn should_parse_approx_ok() {
+        assert_eq!(parse_approx("APPROX COUNT btc"), Some((Aggregate::Count, "btc".to_owned(), None, None)));
+        assert_eq!(parse_approx("APPROX VWAP btc FROM 1 TO 2 ERROR 1%"), Some((Aggregate::Vwap, "btc".to_owned(), Some((1, 2)), Some(0.01))));
+        assert_eq!(parse_approx("APPROX COUNT btc ERROR 0.5%"), Some((Aggregate::Count, "btc".to_owned(), None, Some(0.005))));
+        assert_eq!(parse_approx("APPROX COUNT btc ERROR 1"), None);
+        assert_eq!(parse_approx("APPROX COUNT btc ERROR 0%"), None);
+        assert_eq!(parse_approx("APPROX MEAN btc"), None);
+        assert_eq!(parse_approx("APPROX COUNT btc FROM 1"), None);
+    }
+
+    #[test]
+    fn should_parse_topn_ok() {
+        assert_eq!(parse_topn("TOPN btc 10 BY size"), Some(("btc".to_owned(), 10, By::Size, None)));
+        assert_eq!(parse_topn("TOPN btc 3 BY spread FROM 1 TO 2"), Some(("btc".to_owned(), 3, By::Spread, Some((1, 2)))));
+        assert_eq!(parse_topn("TOPN btc 0 BY size"), None);
+        assert_eq!(parse_topn("TOPN btc 10 BY price"), None);
+        assert_eq!(parse_topn("TOPN btc BY size"), None);
+        assert_eq!(parse_topn("TOPN btc 10 BY size FROM 1"), None);
+    }
+
+    #[test]
+    fn should_parse_hist_ok() {
+        assert_eq!(parse_hist("HIST btc FIELD size BUCKETS 50"), Some(("btc".to_owned(), hist::Field::Size, 50, None, RowFilter::default())));
+        let (_, field, buckets, range, filter) = parse_hist("HIST btc FIELD price BUCKETS 2 FROM 1 TO 2 WHERE is_trade = t").unwrap();
+        assert_eq!((field, buckets, range), (hist::Field::Price, 2, Some((1, 2))));
+        assert_eq!(filter, RowFilter { is_trade: Some(true), ..RowFilter::default() });
+        assert_eq!(parse_hist("HIST btc FIELD size BUCKETS 0"), None);
+        assert_eq!(parse_hist("HIST btc FIELD size BUCKETS 100000"), None);
+        assert_eq!(parse_hist("HIST btc FIELD ts BUCKETS 5"), None);
+        assert_eq!(parse_hist("HIST btc FIELD size"), None);
+    }
+
+    #[test]
+    fn should_parse_job() {
+        assert_eq!(parse_job("COMPACT btc_usdt"), Some(job::Kind::Compact("btc_usdt".to_owned())));
+        assert_eq!(parse_job("INTEGRITY deep"), Some(job::Kind::Integrity(IntegrityScan::Deep)));
+        assert_eq!(parse_job("BACKUP TO /mnt/backups INCREMENTAL"), Some(job::Kind::Backup("/mnt/backups".to_owned(), true)));
+        assert_eq!(parse_job("FLUSH ALL"), Some(job::Kind::FlushAll));
+        assert_eq!(parse_job("PRUNE"), Some(job::Kind::Prune));
+        assert_eq!(parse_job("INTEGRITY off"), None);
+        assert_eq!(parse_job("COMPACT"), None);
+        assert_eq!(parse_job("BACKUP /mnt/backups"), None);
+    }
+
+    #[test]
+    fn should_parse_schedule() {
+        let schedule = parse_schedule("30 */6 * * 1-5 BACKUP TO /mnt/backups INCREMENTAL").unwrap();
+        assert_eq!(schedule.to_string(), "30 */6 * * 1-5 BACKUP TO /mnt/backups INCREMENTAL");
+        assert_eq!(schedule.cron.hours, 1 << 0 | 1 << 6 | 1 << 12 | 1 << 18);
+        // Monday 2017-09-11 18:30 UTC, and the Sunday before
+        assert!(schedule.cron.matches(1505154600));
+        assert!(!schedule.cron.matches(1505154600 + 60));
+        assert!(!schedule.cron.matches(1505154600 - 86_400));
+
+        // the 1st of the month or a Sunday
+        let cron = parse_schedule("0 0 1 * 7 PRUNE").unwrap().cron;
+        assert_eq!(cron.weekdays, 1);
+        assert!(cron.matches(1505001600));
+        assert!(cron.matches(1509494400));
+        assert!(!cron.matches(1505088000));
+
+        assert!(parse_schedule("60 * * * * PRUNE").is_none());
+        assert!(parse_schedule("* * 0 * * PRUNE").is_none());
+        assert!(parse_schedule("*/0 * * * * PRUNE").is_none());
+        assert!(parse_schedule("5-1 * * * * PRUNE").is_none());
+        assert!(parse_schedule("* * * * PRUNE").is_none());
+        assert!(parse_schedule("* * * * * VACUUM").is_none());
+    }
+
+    #[test]
+    fn should_parse_hello() {
+        assert_eq!(parse_hello(""), Some(Hello::default()));
+        assert_eq!(parse_hello("protocol=1 store=binance_btc_usdt format=binary"), Some(Hello {
+            protocol: Some(1),
+            store: Some("binance_btc_usdt".to_owned()),
+            format: Some(Encoding::DTF),
+        }));
+        assert_eq!(parse_hello("format=msgpack"), Some(Hello { format: Some(Encoding::Msgpack), ..Hello::default() }));
+        assert_eq!(parse_hello("protocol=7"), Some(Hello { protocol: Some(7), ..Hello::default() }));
+        assert!(parse_hello("protocol=0").is_none());
+        assert!(parse_hello("protocol=v2").is_none());
+        assert!(parse_hello("store=").is_none());
+        assert!(parse_hello("format=csv").is_none());
+        assert!(parse_hello("user=bob").is_none());
+    }
+
+    #[test]
+    fn should_parse_subscribe_ok() {
+        assert_eq!(parse_subscribe("SUBSCRIBE btc"), Some(("btc".to_owned(), None, RowFilter::default())));
+        assert_eq!(parse_subscribe("SUBSCRIBE btc FROM OFFSET 12"), Some(("btc".to_owned(), Some(12), RowFilter::default())));
+        assert_eq!(parse_subscribe("SUBSCRIBE btc FROM 12"), None);
+        assert_eq!(parse_subscribe("SUBSCRIBE btc FROM OFFSET x"), None);
+
+        let (_, from, filter) = parse_subscribe("SUBSCRIBE btc FROM OFFSET 12 WHERE is_trade=1 AND size > 1.0").unwrap();
+        assert_eq!(from, Some(12));
+        assert_eq!(filter, RowFilter { is_trade: Some(true), size: vec![(Cmp::Gt, 1.)], ..RowFilter::default() });
+        assert_eq!(parse_subscribe("SUBSCRIBE btc WHERE size >> 1"), None);
+    }
+
+    #[test]
+    fn should_filter_rows() {
+        let filter = parse_where("is_trade = t AND price>=2 AND price<3 AND size=1").unwrap();
+        let up = Update { ts: 0, seq: 0, is_trade: true, is_bid: false, price: 2., size: 1., flags: 0 };
+        assert!(filter.matches(&up));
+        assert!(!filter.matches(&Update { price: 3., ..up.clone() }));
+        assert!(!filter.matches(&Update { is_trade: false, ..up.clone() }));
+        assert_eq!(parse_where("is_bid > 0"), None);
+        assert_eq!(parse_where("price = x"), None);
+    }
+
+    #[test]
+    fn should_parse_flags_ok() {
+        let names = vec!["synthetic".to_owned(), "snapshot".to_owned()];
+        assert_eq!(parse_flags("FLAGS btc synthetic snapshot"), Some(("btc".to_owned(), Some(names))));
+        assert_eq!(parse_flags("FLAGS btc OFF"), Some(("btc".to_owned(), Some(vec![]))));
+        assert_eq!(parse_flags("FLAGS btc"), Some(("btc".to_owned(), None)));
+        assert_eq!(parse_flags("FLAGS"), None);
+    }
+
+    #[test]
+    fn should_filter_flags() {
+        let names = vec!["synthetic".to_owned(), "corrected".to_owned(), "snapshot".to_owned()];
+        let mut filter = parse_where("snapshot = t AND synthetic = f AND is_trade = t").unwrap();
+        assert_eq!(filter.flags, vec![("snapshot".to_owned(), true), ("synthetic".to_owned(), false)]);
+        assert!(filter.resolve_flags(&names).is_ok());
+        let up = Update { ts: 0, seq: 0, is_trade: true, is_bid: false, price: 2., size: 1., flags: 0b110 };
+        assert!(filter.matches(&up));
+        assert!(!filter.matches(&Update { flags: 0b101, ..up.clone() }));
+        assert!(!filter.matches(&Update { flags: 0, ..up.clone() }));
+        assert!(filter.resolve_flags(&names[..1]).is_err());
+        assert_eq!(parse_where("snapshot > 0"), None);
+    }
+
+    #[test]
+    fn should_skip_zones() {
+        let batch = dtf::BatchRange { offset: 80, min_ts: 0, max_ts: 10, count: 2 };
+        let zone = dtf::BatchZone { batch, min_price: 2., max_price: 4., min_size: 1., max_size: 1. };
+        let may_match = |clause: &str| parse_where(clause).unwrap().may_match(&zone);
+        assert!(may_match("price > 3.5 AND size = 1"));
+        assert!(may_match("price <= 2"));
+        assert!(may_match("is_bid = t"));
+        assert!(!may_match("price > 4"));
+        assert!(!may_match("price < 2"));
+        assert!(!may_match("price = 5"));
+        assert!(!may_match("price >= 3 AND size > 1"));
+    }
+
+    #[test]
+    fn should_parse_config_ok() {
+        assert_eq!(parse_config("CONFIG GET autoflush"), Some(("autoflush".to_owned(), None)));
+        assert_eq!(parse_config("CONFIG SET autoflush true"), Some(("autoflush".to_owned(), Some("true".to_owned()))));
+        assert_eq!(parse_config("CONFIG SET autoflush"), None);
+        assert_eq!(parse_config("CONFIG GET autoflush true"), None);
+    }
+
+    #[test]
+    fn should_parse_multicast_ok() {
+        assert_eq!(parse_multicast("MULTICAST btc"), Some(("btc".to_owned(), true)));
+        assert_eq!(parse_multicast("MULTICAST btc OFF"), Some(("btc".to_owned(), false)));
+        assert_eq!(parse_multicast("MULTICAST btc ON"), None);
+        assert_eq!(parse_multicast("MULTICAST"), None);
+    }
+
+    #[test]
+    fn should_parse_precision_ok() {
+        assert_eq!(parse_precision("PRECISION btc 4"), Some(("btc".to_owned(), Some(4))));
+        assert_eq!(parse_precision("PRECISION btc OFF"), Some(("btc".to_owned(), None)));
+        assert_eq!(parse_precision("PRECISION btc -1"), None);
+        assert_eq!(parse_precision("PRECISION btc"), None);
+    }
+
+    #[test]
+    fn should_parse_replay_ok() {
+        assert_eq!(parse_replay("REPLAY btc"), Some(("btc".to_owned(), None, 1.)));
+        assert_eq!(parse_replay("REPLAY btc FROM 1 TO 2 SPEED 10x"), Some(("btc".to_owned(), Some((1, 2)), 10.)));
+        assert_eq!(parse_replay("REPLAY btc SPEED 0.5"), Some(("btc".to_owned(), None, 0.5)));
+        assert_eq!(parse_replay("REPLAY btc SPEED 0x"), None);
+        assert_eq!(parse_replay("REPLAY btc SPEED"), None);
+        assert_eq!(parse_replay("REPLAY btc FROM 1"), None);
+    }
+
+    #[test]
+    fn should_parse_sync_ok() {
+        assert_eq!(parse_sync("SYNC FROM 10.0.0.2:9001 STORES bnc_*"),
+            Some(("10.0.0.2:9001".to_owned(), "bnc_*".to_owned(), None, false)));
+        assert_eq!(parse_sync("SYNC FROM 10.0.0.2:9001 STORES * SINCE 1505177459"),
+            Some(("10.0.0.2:9001".to_owned(), "*".to_owned(), Some(1505177459), false)));
+        assert_eq!(parse_sync("SYNC FROM 10.0.0.2:9001 STORES bnc_* VERIFY"),
+            Some(("10.0.0.2:9001".to_owned(), "bnc_*".to_owned(), None, true)));
+        assert_eq!(parse_sync("SYNC FROM 10.0.0.2:9001 STORES * SINCE 1505177459 VERIFY"),
+            Some(("10.0.0.2:9001".to_owned(), "*".to_owned(), Some(1505177459), true)));
+        assert_eq!(parse_sync("SYNC FROM 10.0.0.2:9001 STORES * SINCE"), None);
+        assert_eq!(parse_sync("SYNC FROM 10.0.0.2:9001 VERIFY"), None);
+        assert_eq!(parse_sync("SYNC FROM 10.0.0.2:9001"), None);
+    }
+
+    #[test]
+    fn should_match_patterns() {
+        assert!(matches_pattern("*", "bnc_btc_eth"));
+        assert!(matches_pattern("bnc_*", "bnc_btc_eth"));
+        assert!(matches_pattern("*_eth", "bnc_btc_eth"));
+        assert!(matches_pattern("bnc_*_eth", "bnc_btc_eth"));
+        assert!(matches_pattern("bnc_btc_eth", "bnc_btc_eth"));
+        assert!(!matches_pattern("bnc_*", "gdax_btc_eth"));
+        assert!(!matches_pattern("bnc_*_usd", "bnc_btc_eth"));
+        assert!(!matches_pattern("bnc", "bnc_btc_eth"));
+    }
+
+    #[test]
+    fn should_parse_upload_ok() {
+        assert_eq!(parse_upload("UPLOAD INTO btc 1024"), Some(("btc".to_owned(), 1024)));
+        assert_eq!(parse_upload("UPLOAD INTO btc"), None);
+        assert_eq!(parse_upload("UPLOAD btc 1024"), None);
+        assert_eq!(parse_upload("UPLOAD INTO btc -1"), None);
+    }
+
+    #[test]
+    fn should_parse_download_ok() {
+        assert_eq!(parse_download("DOWNLOAD btc"), Some(("btc".to_owned(), None)));
+        assert_eq!(parse_download("DOWNLOAD btc FROM 1 TO 2"), Some(("btc".to_owned(), Some((1, 2)))));
+        assert_eq!(parse_download("DOWNLOAD btc FROM 1"), None);
+        assert_eq!(parse_download("DOWNLOAD btc FROM a TO 2"), None);
+        assert_eq!(parse_download("DOWNLOAD"), None);
+        assert_eq!(parse_download("CHECKSUMS btc FROM 1 TO 2"), Some(("btc".to_owned(), Some((1, 2)))));
+    }
+
+    #[test]
+    fn should_parse_copy_ok() {
+        assert_eq!(parse_copy("COPY btc TO btc2"),
+            Some(("btc".to_owned(), "btc2".to_owned(), None, RowFilter::default())));
+        assert_eq!(parse_copy("COPY btc TO btc2 FROM 1 TO 2 WHERE is_trade = t AND is_bid = f"),
+            Some(("btc".to_owned(), "btc2".to_owned(), Some((1, 2)),
+                RowFilter { is_trade: Some(true), is_bid: Some(false), ..RowFilter::default() })));
+        assert_eq!(parse_copy("COPY btc TO btc2 WHERE is_trade = t").unwrap().3,
+            RowFilter { is_trade: Some(true), is_bid: None, ..RowFilter::default() });
+        assert_eq!(parse_copy("COPY btc TO btc2 FROM 1"), None);
+        assert_eq!(parse_copy("COPY btc TO btc2 WHERE price = 1").unwrap().3,
+            RowFilter { price: vec![(Cmp::Eq, 1.)], ..RowFilter::default() });
+        assert_eq!(parse_copy("COPY btc TO btc2 WHERE ts = 1"), None);
+        assert_eq!(parse_copy("COPY btc TO btc2 WHERE is_trade = t AND"), None);
+        assert_eq!(parse_copy("COPY btc btc2"), None);
+    }
+
+    #[test]
+    fn should_parse_create_ttl_ok() {
+        assert_eq!(parse_create_ttl("CREATE btc TTL 24h"), Some(("btc".to_owned(), 24 * 60 * 60 * 1000)));
+        assert_eq!(parse_create_ttl("CREATE btc TTL 30s"), Some(("btc".to_owned(), 30 * 1000)));
+        assert_eq!(parse_create_ttl("CREATE btc TTL 0d"), None);
+        assert_eq!(parse_create_ttl("CREATE btc TTL 1y"), None);
+        assert_eq!(parse_create_ttl("CREATE btc"), None);
+    }
+
+    #[test]
+    fn should_parse_create_materialized_ok() {
+        assert_eq!(parse_create_materialized("CREATE MATERIALIZED btc@5m FROM btc INTERVAL 5m"),
+                    Some(("btc@5m".to_owned(), "btc".to_owned(), 5)));
+        assert_eq!(parse_create_materialized("CREATE MATERIALIZED btc@5m FROM btc"), None);
+    }
+
+    #[test]
+    fn should_parse_delete_ok() {
+        assert_eq!(parse_delete("DELETE FROM btc WHERE ts BETWEEN 1505177459.658 AND 1505177460"),
+                    Some(("btc".to_owned(), 1505177459658, 1505177460000)));
+        assert_eq!(parse_delete("DELETE FROM btc WHERE ts BETWEEN 2 AND 1"), None);
+        assert_eq!(parse_delete("DELETE FROM btc"), None);
+    }
+
+    #[test]
+    fn should_parse_paging_ok() {
+        assert_eq!(parse_paging("GET ALL AS JSON LIMIT 100"), Some((100, None)));
+        assert_eq!(parse_paging("GET ALL LIMIT 100 CURSOR 1505177459685:139010"), Some((100, Some((1505177459685, 139010)))));
+        assert_eq!(parse_paging("GET ALL LIMIT 0"), None);
+        assert_eq!(parse_paging("GET ALL LIMIT 10 CURSOR abc"), None);
+        assert_eq!(parse_paging("GET ALL LIMIT 10 CURSOR 300"), None);
+        assert_eq!(parse_paging("GET ALL"), None);
+    }
+
+    #[test]
+    fn should_tell_full_scans() {
+        assert!(is_full_scan("GET ALL AS JSON"));
+        assert!(is_full_scan("DOWNLOAD btc"));
+        assert!(is_full_scan("JOIN trades ASOF quotes"));
+        assert!(!is_full_scan("GET ALL FROM 1505177459 TO 1505177460"));
+        assert!(!is_full_scan("GET ALL AS JSON LIMIT 100"));
+        assert!(!is_full_scan("GET ALL AFTER 10"));
+        assert!(!is_full_scan("GET 10"));
+        assert!(!is_full_scan("COUNT ALL"));
+    }
+
+    #[test]
+    fn should_parse_base64() {
+        assert_eq!(parse_base64("YWI="), Some(b"ab".to_vec()));
+        assert_eq!(parse_base64("YWI"), Some(b"ab".to_vec()));
+        assert_eq!(parse_base64(""), Some(vec![]));
+        assert_eq!(parse_base64("YW I"), None);
+    }
+
+    #[test]
+    fn should_parse_msgpack_line() {
+        // [1505177459658, 139010, false, true, 0.5, 7.25]
+        let target = Update { ts: 1505177459658, seq: 139010, is_trade: false, is_bid: true, price: 0.5, size: 7.25, flags: 0 };
+        assert_eq!(parse_msgpack_line("ls8AAAFec5Fbys4AAh8CwsPKPwAAAMpA6AAA"), Ok(target));
+        assert!(parse_msgpack_line("ls8AAAFec5Fbys4AAh8CwsPKPwAAAMpA").is_err());
+        assert!(parse_msgpack_line("1505177459.658, 139010, f, t, 0.5, 7.25;").is_err());
+    }
+
+    #[test]
+    fn should_parse_line_strict() {
+        let target = parse_line("1505177459.658, 139010, f, t, 0.0703629, 7.65064249;");
+        assert_eq!(parse_line_strict(" 1505177459.658, 139010, f, t, 0.0703629, 7.65064249;").ok(), target);
+
+        let diagnostic = parse_line_strict("1505177459.658, 139010, x, t, 0.0703629, 7.65064249;").unwrap_err();
+        assert_eq!(diagnostic, Diagnostic { field: "is_trade", expected: "t or f", got: "x".to_owned() });
+        assert_eq!(diagnostic.to_string(), "field `is_trade`: expected t or f, got `x`");
+
+        assert_eq!(parse_line_strict("1505177459.658, -1, f, t, 0.07, 7.6;").unwrap_err().field, "seq");
+        assert_eq!(parse_line_strict("1505177459.658, 1, f, t, abc, 7.6;").unwrap_err().field, "price");
+        assert_eq!(parse_line_strict("1505177459.658, 1, f, t, 0.07, -7.6;").unwrap_err().field, "size");
+        assert_eq!(parse_line_strict("1505177459.658, 1, f, f, -0.0001, 0;").unwrap().price, -0.0001);
+        assert_eq!(parse_line_strict("1505177459.658, 1, f, f, inf, 0;").unwrap_err().field, "price");
+        assert_eq!(parse_line_strict("1505177459.6.5, 1, f, t, 0.07, 7.6;").unwrap_err().field, "ts");
+        assert_eq!(parse_line_strict("1505177459.658, 1, f, t, 0.07;").unwrap_err().got, "5 fields");
+        assert_eq!(parse_line_strict("1505177459.658, 1, f, t, 0.07, 7.6, 3;").unwrap().flags, 3);
+        assert_eq!(parse_line_strict("1505177459.658, 1, f, t, 0.07, 7.6, 64;").unwrap_err().field, "flags");
+        assert_eq!(parse_line_strict("1505177459.658, 1, f, t, 0.07, 7.6").unwrap_err().field, "row");
+    }
+
+    #[test]
+    fn should_parse_payload_ok() {
+        assert_eq!(parse_payload("ADD 1, 2, t, f, 0.1, 1; INTO btc"), "1, 2, t, f, 0.1, 1;");
+        assert_eq!(parse_payload("ADD CORRECTION 1, 2, t, f, 0.1, 1;"), "1, 2, t, f, 0.1, 1;");
+        assert_eq!(parse_payload("1, 2, t, f, 0.1, 1;"), "1, 2, t, f, 0.1, 1;");
+    }
+
+    #[test]
+    fn should_parse_listen() {
+        let listen = parse_listen("0.0.0.0:9443,tls=identity.p12,allow=read").unwrap();
+        assert_eq!(listen.addr, "0.0.0.0:9443");
+        assert_eq!(listen.tls, Some("identity.p12".to_owned()));
+        assert!(listen.allow.permits("GET ALL AS JSON"));
+        assert!(listen.allow.permits("CONFIG GET"));
+        assert!(!listen.allow.permits("CONFIG SET autoflush true"));
+        assert!(!listen.allow.permits("ADD 1, 2, t, f, 0.1, 1;"));
+        assert!(!listen.allow.permits("ALERT DROP 1"));
+
+        let listen = parse_listen("127.0.0.1:9001").unwrap();
+        assert_eq!(listen.allow, Allow::All);
+        assert_eq!(listen.tls, None);
+        let listen = parse_listen("127.0.0.1:9001,allow=ping+add").unwrap();
+        assert!(listen.allow.permits("ADD 1, 2, t, f, 0.1, 1;"));
+        assert!(!listen.allow.permits("ADDX"));
+
+        assert!(parse_listen("9001").is_none());
+        assert!(parse_listen("127.0.0.1:9001,tls=").is_none());
+        assert!(parse_listen("127.0.0.1:9001,readonly").is_none());
+    }
+
+    #[test]
+    fn should_parse_template() {
+        let template = parse_template("memory,ttl=7d,tier=C:\\cold:30d,precision=4").unwrap();
+        assert!(template.memory);
+        assert_eq!(template.ttl, Some(7 * 24 * 60 * 60 * 1000));
+        assert_eq!(template.tier, Some(("C:\\cold".to_owned(), 30 * 24 * 60 * 60 * 1000)));
+        assert_eq!(template.precision, Some(4));
+        assert!(!template.multicast);
+        assert_eq!(template.to_string(), "memory,ttl=7d,tier=C:\\cold:30d,precision=4");
+        assert_eq!(parse_template("ttl=90m").unwrap().to_string(), "ttl=90m");
+        assert_eq!(parse_template("series=open_interest").unwrap().series, Some(series::Kind::OpenInterest));
+        assert_eq!(parse_template("precision=8,series=funding").unwrap().to_string(), "precision=8,series=funding");
+        assert_eq!(parse_template("default"), Some(Template::default()));
+
+        assert!(parse_template("precision=18").is_none());
+        assert!(parse_template("tier=30d").is_none());
+        assert!(parse_template("ttl").is_none());
+        assert!(parse_template("series=basis").is_none());
+        assert!(parse_template("").is_none());
+    }
+
+    #[test]
+    fn should_parse_store_between_ok() {
+        assert_eq!(parse_store_between("EXISTS btc AS JSON", &["EXISTS"], &["AS", "JSON"]), Some("btc".to_owned()));
+        assert_eq!(parse_store_between("EXISTS AS JSON", &["EXISTS"], &["AS", "JSON"]), None);
+        assert_eq!(parse_store_between("CREATE btc MEMORY", &["CREATE"], &["MEMORY"]), Some("btc".to_owned()));
+        assert_eq!(parse_store_between("CREATE MEMORY", &["CREATE"], &["MEMORY"]), None);
+        assert_eq!(parse_store_between("CREATE IF NOT EXISTS btc", &["CREATE", "IF", "NOT", "EXISTS"], &[]), Some("btc".to_owned()));
+        assert_eq!(parse_store_between("CREATE IF NOT EXISTS", &["CREATE", "IF", "NOT", "EXISTS"], &[]), None);
+    }
+
+    #[test]
+    fn should_parse_create_like_ok() {
+        assert_eq!(parse_create_like("CREATE bnc_eth LIKE bnc_btc"), Some(("bnc_eth".to_owned(), "bnc_btc".to_owned())));
+        assert_eq!(parse_create_like("CREATE bnc_eth LIKE"), None);
+    }
+
+    #[test]
+    fn should_parse_cidrs() {
+        let cidrs = parse_cidrs("10.0.0.0/8, 192.168.1.7,2001:db8::/32").unwrap();
+        assert_eq!(cidrs.len(), 3);
+        assert_eq!(cidrs[1].to_string(), "192.168.1.7/32");
+        assert!(cidrs[0].contains("10.20.30.40".parse().unwrap()));
+        assert!(!cidrs[0].contains("11.0.0.1".parse().unwrap()));
+        assert!(cidrs[0].contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(cidrs[2].contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!cidrs[2].contains("10.0.0.1".parse().unwrap()));
+        let cidr = parse_cidrs("172.16.0.0/12").unwrap()[0];
+        assert!(cidr.contains("172.31.255.255".parse().unwrap()));
+        assert!(!cidr.contains("172.32.0.0".parse().unwrap()));
+
+        assert_eq!(parse_cidrs("none"), Some(Vec::new()));
+        assert!(parse_cidrs("10.0.0.0/33").is_none());
+        assert!(parse_cidrs("10.0.0/8").is_none());
+        assert!(parse_cidrs("").is_none());
+    }
+
+    #[test]
+    fn should_parse_explain() {
+        assert_eq!(parse_explain("EXPLAIN GET ALL"), Some((None, None, None)));
+        assert_eq!(parse_explain("EXPLAIN GET 100 FROM 1505177459 TO 1505177460 AS JSON LIMIT 10"),
+            Some((Some(100), Some((1505177459, 1505177460)), Some(10))));
+        assert_eq!(parse_explain("EXPLAIN GET ALL FROM 1505177459"), None);
+        assert_eq!(parse_explain("EXPLAIN GET MANY"), None);
+        assert_eq!(parse_explain("EXPLAIN COPY a TO b"), None);
+    }
 }
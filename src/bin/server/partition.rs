@@ -0,0 +1,151 @@
+/// Partitions
+///
+/// With `--max_partition_bytes`, the dtf file of a store that has grown past
+/// the limit is sealed by its next flush: the file is moved, with its stats,
+/// zone map and batch index sidecars, to
+///
+///     [dtf_folder]/partitions/[file name]/[n].dtf
+///
+/// and the flush starts a new file. Sealed partitions are only rewritten by
+/// `COMPACT` and the retention worker, so appends and the indexes they
+/// update stay the size of the newest partition, and a backup of a
+/// firehose symbol only copies the partitions it doesn't have. Reads, counts
+/// and stats cover the partitions of a store, oldest first, then its file.
+/// Tiering only moves the rows of the file, and the flush epochs of `ASOF`
+/// start over with every partition. The mirror of `--dtf_folder_mirror`
+/// follows the partitions to `[mirror]/partitions/[file name]/[n].dtf`.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use dtf::{self, Update};
+use epoch;
+use mirror;
+use utils;
+
+/// sidecars that describe the rows of a dtf file, moved along with it
+pub static SIDECARS : &[&str] = &["stats", "sample", "zones", "batches"];
+
+/// folder of the partitions of the store whose file is `dtf_fname`
+fn folder(dtf_fname: &str) -> String {
+    let path = Path::new(dtf_fname);
+    let parent = path.parent().and_then(|parent| parent.to_str()).unwrap_or(".");
+    let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or(dtf_fname);
+    utils::join(&utils::join(parent, "partitions"), stem)
+}
+
+/// the partitions of a store by number, oldest first
+fn numbered(dtf_fname: &str) -> Vec<(u32, String)> {
+    let folder = folder(dtf_fname);
+    let entries = match fs::read_dir(&folder) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new()
+    };
+    let mut partitions : Vec<(u32, String)> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.ends_with(".dtf"))
+        .filter_map(|name| name[..(name.len() - 4)].parse().ok().map(|n| (n, utils::join(&folder, &name))))
+        .collect();
+    partitions.sort();
+    partitions
+}
+
+/// the sealed partitions of the store whose file is `dtf_fname`, oldest first
+pub fn list(dtf_fname: &str) -> Vec<String> {
+    numbered(dtf_fname).into_iter().map(|(_, fname)| fname).collect()
+}
+
+/// rows in the partitions of a store
+pub fn count(dtf_fname: &str) -> u64 {
+    list(dtf_fname).iter().map(|fname| dtf::get_size(fname)).sum()
+}
+
+/// (ts, seq) of the newest row in the partitions of a store
+pub fn newest(dtf_fname: &str) -> Option<(u64, u32)> {
+    list(dtf_fname).iter().rev().filter_map(|fname| dtf::read_newest(fname)).next()
+}
+
+/// the rows of a loaded store that are newer than its partitions
+pub fn unsealed(dtf_fname: &str, ups: &[Update]) -> Option<Vec<Update>> {
+    let newest = newest(dtf_fname)?;
+    Some(ups.iter().filter(|up| (up.ts, up.seq) > newest).cloned().collect())
+}
+
+/// whether the file of a store is over `max_bytes`, 0 never is
+pub fn is_full(dtf_fname: &str, max_bytes: u64) -> bool {
+    max_bytes > 0 && fs::metadata(dtf_fname).map(|m| m.len() >= max_bytes).unwrap_or(false)
+}
+
+/// Move the file of a store to a new partition and return its path
+pub fn seal(dtf_fname: &str) -> io::Result<String> {
+    let folder = folder(dtf_fname);
+    fs::create_dir_all(&folder)?;
+    let n = numbered(dtf_fname).last().map_or(0, |&(n, _)| n + 1);
+    let partition = utils::join(&folder, &format!("{:06}.dtf", n));
+    fs::rename(dtf_fname, &partition)?;
+    for sidecar in SIDECARS {
+        let from = format!("{}.{}", dtf_fname, sidecar);
+        if Path::new(&from).exists() {
+            fs::rename(&from, format!("{}.{}", partition, sidecar))?;
+        }
+    }
+    epoch::vacuum(dtf_fname)?;
+    Ok(partition)
+}
+
+/// the folder of the mirror of `partition` in `mirror_folder`, laid out like dtf_folder
+fn mirror_of(partition: &str, mirror_folder: &str) -> String {
+    let stem = Path::new(partition).parent()
+        .and_then(|folder| folder.file_name())
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(".");
+    utils::join(&utils::join(mirror_folder, "partitions"), stem)
+}
+
+/// After a partition was sealed or rewritten
+pub fn mirror_written(partition: &str, mirror_folder: &str) -> io::Result<()> {
+    let folder = mirror_of(partition, mirror_folder);
+    fs::create_dir_all(&folder)?;
+    mirror::written(partition, &folder)
+}
+
+/// After a partition was removed
+pub fn mirror_removed(partition: &str, mirror_folder: &str) -> io::Result<()> {
+    mirror::removed(partition, &mirror_of(partition, mirror_folder))
+}
+
+/// Remove a partition and its sidecars
+pub fn remove(partition: &str) -> io::Result<()> {
+    for sidecar in SIDECARS {
+        let fname = format!("{}.{}", partition, sidecar);
+        if Path::new(&fname).exists() {
+            fs::remove_file(&fname)?;
+        }
+    }
+    utils::remove_file(partition)
+}
+
+/// Give back its file to every store sealed right before the server stopped,
+/// a store without a file in dtf_folder is not opened
+pub fn restore(dtf_folder: &str) {
+    let entries = match fs::read_dir(utils::join(dtf_folder, "partitions")) {
+        Ok(entries) => entries,
+        Err(_) => return
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue
+        };
+        let dtf_fname = utils::dtf_fname(dtf_folder, &name);
+        if Path::new(&dtf_fname).exists() {
+            continue;
+        }
+        if let Some(last) = list(&dtf_fname).last() {
+            let symbol = dtf::read_meta(last).symbol;
+            dtf::encode(&dtf_fname, &symbol, &[]);
+            warn!("Restored the file of {} after its last partition {}", symbol, last);
+        }
+    }
+}
@@ -0,0 +1,58 @@
+/// leader election of an HA pair
+///
+/// With `--ha_lease [file]`, renews the lease of this server, or takes it
+/// once the other server let it expire, every third of `--ha_lease_secs`,
+/// and switches between primary and standby accordingly. Announces
+/// `--ha_addr` along with it for `TOPOLOGY`. See `lease`.
+
+use std::{thread, time};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use state::SharedState;
+use lease::{self, Role};
+
+pub fn run(global: Arc<RwLock<SharedState>> ) {
+    let (fname, node, addr, secs) = {
+        let rdr = global.read().unwrap();
+        match rdr.settings.ha_lease {
+            Some(ref fname) => (fname.clone(), rdr.settings.ha_node.clone(),
+                rdr.settings.ha_addr.clone(), rdr.settings.ha_lease_secs),
+            None => return
+        }
+    };
+    thread::spawn(move || {
+        let dur = time::Duration::from_millis(secs * 1000 / 3);
+        loop {
+            if let Err(e) = lease::announce(&fname, &node, &addr) {
+                warn!("Unable to announce {} next to the lease {}: {}", addr, fname, e);
+            }
+            elect(&global, &fname, &node, secs);
+            thread::sleep(dur);
+        }
+    });
+}
+
+fn elect(global: &Arc<RwLock<SharedState>>, fname: &str, node: &str, secs: u64) {
+    let started = Instant::now();
+    let lease = match lease::acquire(fname, node, secs) {
+        Ok(lease) => lease,
+        Err(e) => {
+            // a primary stops accepting writes when its lease runs out
+            error!("Unable to renew the lease {}: {}", fname, e);
+            return;
+        }
+    };
+    let mut wtr = global.write().unwrap();
+    let role = if lease.node == node {
+        if !wtr.role.accepts_writes() {
+            warn!("Primary of term {}, accepting writes", lease.term);
+        }
+        Role::Primary { term: lease.term, until: started + Duration::from_millis(secs * 1000 * 4 / 5) }
+    } else {
+        if wtr.role.accepts_writes() {
+            warn!("Standby of {} in term {}, refusing writes", lease.node, lease.term);
+        }
+        Role::Standby { term: lease.term, primary: lease.node }
+    };
+    wtr.role = role;
+}
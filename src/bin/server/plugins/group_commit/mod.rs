@@ -0,0 +1,61 @@
+/// group commit
+///
+/// With `--group_commit [ms]`, a store due for an autoflush is only marked dirty.
+/// Every `ms` the dirty stores are flushed, each dtf file (and its header) written
+/// once however many autoflushes it had, and the files are synced to disk in one pass.
+/// The delay can be changed with `CONFIG SET group_commit [ms]`.
+
+use std::{thread, time};
+use std::collections::HashMap;
+use std::fs::File;
+use std::mem;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use state::{SharedState, Store};
+use utils;
+
+/// ms between two checks while group commit is off
+static IDLE_INTERVAL : u64 = 1000;
+
+pub fn run(global: Arc<RwLock<SharedState>> ) {
+    thread::spawn(move || {
+        loop {
+            let delay = global.read().unwrap().settings.group_commit;
+            let delay = if delay == 0 { IDLE_INTERVAL } else { delay };
+            thread::sleep(time::Duration::from_millis(delay));
+            // stores left dirty when group commit was turned off are flushed too
+            commit(&global);
+        }
+    });
+}
+
+/// flush and sync the stores marked dirty since the last group commit
+pub fn commit(global: &Arc<RwLock<SharedState>>) {
+    let (dirty, folder) = {
+        let mut wtr = global.write().unwrap();
+        (mem::replace(&mut wtr.dirty, HashMap::new()), wtr.settings.dtf_folder.clone())
+    };
+    if dirty.is_empty() {
+        return;
+    }
+
+    let mut written = Vec::new();
+    for (name, fname) in dirty {
+        let mut store = Store {
+            name,
+            fname: fname.clone(),
+            global: global.clone(),
+        };
+        if store.flush() == Some(true) {
+            written.push(utils::dtf_fname(&folder, &fname));
+        }
+    }
+
+    for fname in written.iter().filter(|fname| Path::new(fname).exists()) {
+        if let Err(e) = File::open(fname).and_then(|f| f.sync_all()) {
+            error!("Unable to sync {}: {:?}", fname, e);
+            global.write().unwrap().flush_errors += 1;
+        }
+    }
+    debug!("Group commit of {} files", written.len());
+}
@@ -8,6 +8,27 @@ pub mod gstorage;
 // history plugin
 pub mod history;
 
+// retention worker
+pub mod retention;
+
+// moves old rows to cold storage
+pub mod tiering;
+
+// writes and syncs autoflushes together
+pub mod group_commit;
+
+// alerts on stores that stopped receiving rows
+pub mod watchdog;
+
+// drops the rows of idle stores from memory
+pub mod unload;
+
+// primary and standby of an HA pair
+pub mod election;
+
+// starts admin jobs on a schedule
+pub mod scheduler;
+
 // global state
 use std::sync::{Arc, RwLock};
 use state::SharedState;
@@ -18,6 +39,20 @@ pub fn run_plugins(global: Arc<RwLock<SharedState>>) {
 
     history::run(global.clone());
 
+    retention::run(global.clone());
+
+    tiering::run(global.clone());
+
+    group_commit::run(global.clone());
+
+    watchdog::run(global.clone());
+
+    unload::run(global.clone());
+
+    election::run(global.clone());
+
+    scheduler::run(global.clone());
+
     #[cfg(feature = "gcs")]
     gstorage::run(global.clone());
 
@@ -0,0 +1,154 @@
+/// retention worker
+///
+/// Prunes the rows of stores created with `CREATE [db] TTL [duration]`
+/// once they are older than the TTL, from memory and from the dtf file and
+/// its partitions. A partition left without rows is removed.
+///
+/// The TTL of a store is kept next to its file in `[fname].dtf.ttl`.
+/// `RETAIN [db] [duration|OFF]` sets or removes the TTL of an existing store.
+
+use std::{thread, time};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use dtf::{self, Update};
+use state::{SharedState, Ttl};
+use cdc::Event;
+use epoch;
+use partition;
+use filestats;
+use zonemap;
+use utils;
+
+/// seconds between two passes
+static RETENTION_INTERVAL : u64 = 60;
+
+fn ttl_fname(dtf_fname: &str) -> String {
+    format!("{}.ttl", dtf_fname)
+}
+
+/// TTL in ms of a dtf file
+pub fn read_ttl(dtf_fname: &str) -> Option<u64> {
+    let mut s = String::new();
+    File::open(ttl_fname(dtf_fname)).ok()?.read_to_string(&mut s).ok()?;
+    s.trim().parse().ok()
+}
+
+pub fn write_ttl(dtf_fname: &str, ms: u64) -> Result<(), io::Error> {
+    let mut file = File::create(ttl_fname(dtf_fname))?;
+    writeln!(file, "{}", ms)
+}
+
+pub fn remove_ttl(dtf_fname: &str) -> Result<(), io::Error> {
+    let fname = ttl_fname(dtf_fname);
+    if Path::new(&fname).exists() {
+        utils::remove_file(&fname)?;
+    }
+    Ok(())
+}
+
+pub fn run(global: Arc<RwLock<SharedState>> ) {
+    thread::spawn(move || {
+        let dur = time::Duration::from_secs(RETENTION_INTERVAL);
+        loop {
+            prune(&global);
+            thread::sleep(dur);
+        }
+    });
+}
+
+/// prune every store with a TTL once, returns the rows removed. Also run by `JOB PRUNE`
+pub fn prune(global: &Arc<RwLock<SharedState>>) -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let now = now.as_secs() * 1000 + u64::from(now.subsec_nanos()) / 1_000_000;
+
+    let mut total = 0;
+    let mut wtr = global.write().unwrap();
+    let ttls : Vec<(String, Ttl)> = wtr.ttls.iter()
+        .map(|(name, ttl)| (name.clone(), ttl.clone()))
+        .collect();
+
+    for (name, ttl) in ttls {
+        // FLUSHALL is writing the file
+        if wtr.flushing.contains(&name) {
+            continue;
+        }
+        let cutoff = now.saturating_sub(ttl.ms);
+        let mut removed = 0;
+
+        for partition in partition::list(&ttl.fname) {
+            if dtf::read_meta(&partition).min_ts >= cutoff {
+                break;
+            }
+            let ups = dtf::decode(&partition, None);
+            let kept : Vec<Update> = ups.iter().filter(|up| up.ts >= cutoff).cloned().collect();
+            let result = if kept.is_empty() {
+                partition::remove(&partition)
+            } else {
+                let tmp = format!("{}.retention", partition);
+                dtf::encode(&tmp, &name, &kept);
+                utils::replace_file(&tmp, &partition).map(|_| {
+                    filestats::written(&partition, &kept);
+                    zonemap::written(&partition);
+                })
+            };
+            match result {
+                Ok(()) => {
+                    removed += (ups.len() - kept.len()) as u64;
+                    if kept.is_empty() {
+                        wtr.mirror_partition_removed(&partition);
+                    } else {
+                        wtr.mirror_partition_written(&partition);
+                    }
+                },
+                Err(e) => error!("Unable to prune {}: {:?}", partition, e)
+            }
+            wtr.stats.remove(&name);
+        }
+
+        if Path::new(&ttl.fname).exists() && dtf::read_meta(&ttl.fname).min_ts < cutoff {
+            let ups = dtf::decode(&ttl.fname, None);
+            let kept : Vec<Update> = ups.iter().filter(|up| up.ts >= cutoff).cloned().collect();
+            removed += (ups.len() - kept.len()) as u64;
+
+            let result = if kept.is_empty() {
+                utils::remove_file(&ttl.fname)
+            } else {
+                let tmp = format!("{}.retention", ttl.fname);
+                dtf::encode(&tmp, &name, &kept);
+                utils::replace_file(&tmp, &ttl.fname)
+            };
+            if let Err(e) = result {
+                error!("Unable to prune {}: {:?}", name, e);
+                continue;
+            }
+            let _ = epoch::vacuum(&ttl.fname);
+            if kept.is_empty() {
+                wtr.mirror_removed(&ttl.fname);
+            } else {
+                filestats::written(&ttl.fname, &kept);
+                zonemap::written(&ttl.fname);
+                wtr.mirror_written(&ttl.fname);
+            }
+            wtr.stats.remove(&name);
+            wtr.watermark_mut(&name).disk = kept.last().map(|up| (up.ts, up.seq));
+        }
+
+        if let Some(vecs) = wtr.vec_store.get_mut(&name) {
+            let before = vecs.0.len();
+            vecs.0.retain(|up| up.ts >= cutoff);
+            removed += (before - vecs.0.len()) as u64;
+            vecs.1 = vecs.1.saturating_sub(removed);
+        }
+
+        if removed > 0 {
+            wtr.result_cache.invalidate(&name, None);
+            wtr.cdc.push(&Event::Prune { store: name.clone(), before: cutoff, removed });
+            info!("Pruned {} rows older than {} from {}", removed, cutoff, name);
+        }
+        total += removed;
+    }
+    total
+}
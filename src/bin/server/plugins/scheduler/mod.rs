@@ -0,0 +1,52 @@
+/// job scheduler
+///
+/// Starts the admin jobs of `--schedule` and `CONFIG SET schedule.[name]`
+/// in the minutes their crontab fields match, see `job::Cron`. A schedule
+/// whose previous job is still queued or running is skipped that minute, and
+/// minutes missed while the server was down are not made up for.
+
+use std::{thread, time};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use state::{SharedState, State};
+use job;
+
+/// seconds between two checks of the clock
+static SCHEDULER_INTERVAL : u64 = 5;
+
+pub fn run(global: Arc<RwLock<SharedState>> ) {
+    thread::spawn(move || {
+        let dur = time::Duration::from_secs(SCHEDULER_INTERVAL);
+        let mut last_minute = now_minute();
+        loop {
+            thread::sleep(dur);
+            let minute = now_minute();
+            if minute == last_minute {
+                continue;
+            }
+            last_minute = minute;
+            for (name, kind) in due(&global, minute * 60) {
+                let id = State::new(&global).start_job(kind);
+                info!("Schedule {} started job {}", name, id);
+            }
+        }
+    });
+}
+
+fn now_minute() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() / 60
+}
+
+/// the jobs of the schedules matching `secs`, but not already pending
+fn due(global: &Arc<RwLock<SharedState>>, secs: u64) -> Vec<(String, job::Kind)> {
+    let rdr = global.read().unwrap();
+    let mut due = Vec::new();
+    for (name, schedule) in rdr.settings.schedules.iter().filter(|&(_, schedule)| schedule.cron.matches(secs)) {
+        if rdr.jobs.is_pending(&schedule.job) {
+            warn!("Skipping schedule {}, its last job is still pending", name);
+            continue;
+        }
+        due.push((name.clone(), schedule.job.clone()));
+    }
+    due
+}
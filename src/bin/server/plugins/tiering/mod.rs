@@ -0,0 +1,111 @@
+/// tiering worker
+///
+/// Moves the rows of stores configured with `TIER [db] COLD [folder] AFTER [duration]`
+/// from the dtf file in dtf_folder (hot, e.g. NVMe) to a dtf file of the same
+/// name in the cold folder (e.g. HDD or NFS) once they are older than the duration.
+/// Reads go through both files, cold first.
+///
+/// The tier of a store is kept next to its file in `[fname].dtf.tier`.
+
+use std::{thread, time};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use dtf::{self, Update};
+use state::{SharedState, Tier};
+use epoch;
+use filestats;
+use zonemap;
+use utils;
+
+/// seconds between two passes
+static TIERING_INTERVAL : u64 = 60;
+
+fn tier_fname(dtf_fname: &str) -> String {
+    format!("{}.tier", dtf_fname)
+}
+
+/// the file in `cold_folder` that holds the old rows of `dtf_fname`
+pub fn cold_fname(cold_folder: &str, dtf_fname: &str) -> String {
+    let basename = Path::new(dtf_fname).file_name().unwrap().to_str().unwrap();
+    utils::join(cold_folder, basename)
+}
+
+/// (cold folder, age in ms) of a dtf file
+pub fn read_tier(dtf_fname: &str) -> Option<(String, u64)> {
+    let mut s = String::new();
+    File::open(tier_fname(dtf_fname)).ok()?.read_to_string(&mut s).ok()?;
+    let mut parts = s.trim().splitn(2, '\t');
+    let ms = parts.next()?.parse().ok()?;
+    Some((parts.next()?.to_owned(), ms))
+}
+
+pub fn write_tier(dtf_fname: &str, cold_folder: &str, ms: u64) -> Result<(), io::Error> {
+    let mut file = File::create(tier_fname(dtf_fname))?;
+    writeln!(file, "{}\t{}", ms, cold_folder)
+}
+
+pub fn run(global: Arc<RwLock<SharedState>> ) {
+    thread::spawn(move || {
+        let dur = time::Duration::from_secs(TIERING_INTERVAL);
+        loop {
+            move_cold(&global);
+            thread::sleep(dur);
+        }
+    });
+}
+
+fn move_cold(global: &Arc<RwLock<SharedState>>) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let now = now.as_secs() * 1000 + u64::from(now.subsec_nanos()) / 1_000_000;
+
+    let mut wtr = global.write().unwrap();
+    let tiers : Vec<(String, Tier)> = wtr.tiers.iter()
+        .map(|(name, tier)| (name.clone(), tier.clone()))
+        .collect();
+
+    for (name, tier) in tiers {
+        // FLUSHALL is writing the file
+        if wtr.flushing.contains(&name) {
+            continue;
+        }
+        let cutoff = now.saturating_sub(tier.ms);
+        if !Path::new(&tier.fname).exists() || dtf::read_meta(&tier.fname).min_ts >= cutoff {
+            continue;
+        }
+
+        // the newest rows stay hot so the file keeps the max ts later flushes append after
+        let max_ts = dtf::read_meta(&tier.fname).max_ts;
+        let ups = dtf::decode(&tier.fname, None);
+        let (cold, hot) : (Vec<Update>, Vec<Update>) = ups.into_iter()
+            .partition(|up| up.ts < cutoff && up.ts < max_ts);
+        if cold.is_empty() {
+            continue;
+        }
+
+        // rows are only removed from the hot file once they are in the cold one
+        let cold_fname = cold_fname(&tier.cold_folder, &tier.fname);
+        if Path::new(&cold_fname).exists() {
+            dtf::append(&cold_fname, &cold);
+        } else {
+            dtf::encode(&cold_fname, &name, &cold);
+        }
+
+        let tmp = format!("{}.tiering", tier.fname);
+        dtf::encode(&tmp, &name, &hot);
+        if let Err(e) = utils::replace_file(&tmp, &tier.fname) {
+            error!("Unable to move cold rows of {}: {:?}", name, e);
+            continue;
+        }
+        let _ = epoch::vacuum(&tier.fname);
+        filestats::written(&tier.fname, &hot);
+        zonemap::written(&tier.fname);
+        wtr.mirror_written(&tier.fname);
+        wtr.stats.remove(&name);
+        wtr.result_cache.invalidate(&name, None);
+
+        info!("Moved {} rows older than {} of {} to {}", cold.len(), cutoff, name, cold_fname);
+    }
+}
@@ -0,0 +1,72 @@
+/// idle store unloader
+///
+/// With `--unload_idle [minutes]`, a store without reads or inserts for
+/// `minutes` drops the rows it holds in memory, like with `CLEAR`, and keeps
+/// its count and metadata. A server that loaded thousands of stores then
+/// only holds the ones in use. Stores with rows not flushed yet, `MEMORY`
+/// stores and materialized stores are never unloaded. Reads of an unloaded
+/// store go to its files until it is loaded again.
+
+use std::{thread, time};
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use state::SharedState;
+
+/// seconds between two passes
+static UNLOAD_INTERVAL : u64 = 30;
+
+pub fn run(global: Arc<RwLock<SharedState>> ) {
+    thread::spawn(move || {
+        let dur = time::Duration::from_secs(UNLOAD_INTERVAL);
+        loop {
+            thread::sleep(dur);
+            unload(&global);
+        }
+    });
+}
+
+fn unload(global: &Arc<RwLock<SharedState>>) {
+    let mut wtr = global.write().unwrap();
+    let minutes = wtr.settings.unload_idle;
+    if minutes == 0 {
+        return;
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    for store_name in wtr.unload_idle(now, minutes * 60) {
+        info!("Unloaded {}, idle for {} minutes", store_name, minutes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use state::tests::{add, connect, global, rows, run};
+
+    #[test]
+    fn should_unload_idle_stores_with_all_rows_on_disk() {
+        let global = global();
+        let mut state = connect(&global);
+        for store in &["btc_usdt", "eth_usdt"] {
+            run(&mut state, &format!("CREATE {}", store));
+            run(&mut state, &format!("USE {}", store));
+            add(&mut state, 0);
+            add(&mut state, 1);
+            run(&mut state, "FLUSH");
+            run(&mut state, &format!("USE {}", store));
+        }
+        // eth_usdt holds a row not flushed yet
+        add(&mut state, 2);
+        run(&mut state, "USE btc_usdt");
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert!(global.write().unwrap().unload_idle(now, 60).is_empty());
+        assert_eq!(global.write().unwrap().unload_idle(now + 3600, 60), vec!["btc_usdt".to_owned()]);
+        assert!(global.read().unwrap().vec_store["btc_usdt"].0.is_empty());
+        assert_eq!(global.read().unwrap().vec_store["eth_usdt"].0.len(), 3);
+
+        // the next command loads the store in use again
+        state.start_command("GET ALL AS JSON");
+        assert_eq!(rows(&mut state), 2);
+        assert_eq!(global.read().unwrap().vec_store["btc_usdt"].0.len(), 2);
+    }
+}
@@ -0,0 +1,226 @@
+/// alert watchdog
+///
+/// Raises the alerts of the rules added with `ALERT`, and with `--silence_alert [secs]`
+/// a `silent` alert when any store that received rows since the server started
+/// has not received any for `secs`, which usually means its collector died.
+///
+/// An alert is raised once until its condition clears, e.g. until rows arrive again.
+
+use std::{thread, time};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use dtf::Update;
+use state::SharedState;
+use alert::{self, Condition};
+
+/// seconds between two checks
+static WATCHDOG_INTERVAL : u64 = 1;
+
+/// checks between two measures of the disk usage
+static DISK_INTERVAL : u64 = 30;
+
+/// what the alerts raised so far were raised for
+#[derive(Default)]
+struct Fired {
+    /// store name -> last write, of `--silence_alert`
+    silent: HashMap<String, u64>,
+    /// rule -> last write of silent rules, flush errors of flush error rules
+    rules: HashMap<String, u64>,
+    /// bytes in dtf_folder
+    disk_usage: u64,
+}
+
+pub fn run(global: Arc<RwLock<SharedState>> ) {
+    thread::spawn(move || {
+        let dur = time::Duration::from_secs(WATCHDOG_INTERVAL);
+        let mut fired = Fired::default();
+        let mut ticks = 0;
+        loop {
+            if ticks % DISK_INTERVAL == 0 {
+                let dtf_folder = global.read().unwrap().settings.dtf_folder.clone();
+                fired.disk_usage = disk_usage(Path::new(&dtf_folder));
+            }
+            check_silence(&global, &mut fired);
+            check_rules(&global, &mut fired);
+            ticks += 1;
+            thread::sleep(dur);
+        }
+    });
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn check_silence(global: &Arc<RwLock<SharedState>>, fired: &mut Fired) {
+    let rdr = global.read().unwrap();
+    let silence = rdr.settings.silence_alert;
+    if silence == 0 {
+        return;
+    }
+    let now = now();
+    let webhook = rdr.settings.webhook.as_ref().map(|w| w.as_str());
+    for (name, &last_write) in rdr.last_write.iter() {
+        match fired.silent.get(name).cloned() {
+            Some(fired_for) if fired_for == last_write => continue,
+            Some(_) => {
+                info!("{} receives rows again", name);
+                fired.silent.remove(name);
+            }
+            None => ()
+        }
+        if now.saturating_sub(last_write) >= silence {
+            alert::raise(webhook, "silent", name,
+                &format!("no rows for {}s, last at {}", now - last_write, last_write));
+            fired.silent.insert(name.to_owned(), last_write);
+        }
+    }
+}
+
+fn check_rules(global: &Arc<RwLock<SharedState>>, fired: &mut Fired) {
+    let now = now();
+    // (rule number, INTO store, measured value, threshold)
+    let mut rows : Vec<(usize, String, f32, f32)> = Vec::new();
+    {
+        let rdr = global.read().unwrap();
+        let webhook = rdr.settings.webhook.as_ref().map(|w| w.as_str());
+        for (i, rule) in rdr.alerts.iter().enumerate() {
+            let key = rule.to_string();
+            let (store_name, value, threshold, message) = match rule.condition {
+                Condition::Silent(ref store_name, ms) => {
+                    let last_write = match rdr.last_write.get(store_name) {
+                        Some(&last_write) => last_write,
+                        None => continue
+                    };
+                    let silent_for = now.saturating_sub(last_write);
+                    if silent_for < ms / 1000 || fired.rules.get(&key) == Some(&last_write) {
+                        continue;
+                    }
+                    fired.rules.insert(key, last_write);
+                    (store_name.as_str(), silent_for, ms / 1000,
+                        format!("no rows for {}s, last at {}", silent_for, last_write))
+                }
+                Condition::DiskAbove(bytes) => {
+                    if fired.disk_usage <= bytes {
+                        fired.rules.remove(&key);
+                        continue;
+                    }
+                    if fired.rules.contains_key(&key) {
+                        continue;
+                    }
+                    fired.rules.insert(key, fired.disk_usage);
+                    ("", fired.disk_usage, bytes,
+                        format!("{} bytes in {}, above {}", fired.disk_usage, rdr.settings.dtf_folder, bytes))
+                }
+                Condition::FlushErrors => {
+                    let seen = fired.rules.get(&key).cloned().unwrap_or(0);
+                    if rdr.flush_errors <= seen {
+                        continue;
+                    }
+                    fired.rules.insert(key, rdr.flush_errors);
+                    ("", rdr.flush_errors, seen,
+                        format!("{} flush errors, {} new", rdr.flush_errors, rdr.flush_errors - seen))
+                }
+            };
+            alert::raise(webhook, rule.alert(), store_name, &message);
+            if let Some(ref into) = rule.into {
+                rows.push((i + 1, into.clone(), value as f32, threshold as f32));
+            }
+        }
+    }
+
+    if rows.is_empty() {
+        return;
+    }
+    let mut wtr = global.write().unwrap();
+    for (n, into, value, threshold) in rows {
+        if !wtr.vec_store.contains_key(&into) {
+            error!("Unable to insert alert of rule {}, no db named `{}`", n, into);
+            continue;
+        }
+        wtr.push(&into, Update {
+            ts: now * 1000,
+            seq: n as u32,
+            is_trade: false,
+            is_bid: false,
+            price: value,
+            size: threshold,
+            flags: 0,
+        });
+    }
+}
+
+/// bytes of the files under `folder`
+fn disk_usage(folder: &Path) -> u64 {
+    let entries = match fs::read_dir(folder) {
+        Ok(entries) => entries,
+        Err(_) => return 0
+    };
+    entries.filter_map(|entry| entry.ok())
+        .map(|entry| match entry.metadata() {
+            Ok(ref meta) if meta.is_dir() => disk_usage(&entry.path()),
+            Ok(meta) => meta.len(),
+            Err(_) => 0
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{self, Value};
+    use state::tests::{add, connect, global, run};
+
+    #[test]
+    fn should_alert_once_on_a_silent_store() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "USE btc_usdt");
+        add(&mut state, 0);
+        let info = serde_json::from_str::<Value>(&run(&mut state, "INFO")).unwrap();
+        let store = info["dbs"].as_array().unwrap().iter().find(|store| store["name"] == "btc_usdt").cloned().unwrap();
+        assert_eq!(store["silent_for"], 0);
+
+        let mut fired = Fired::default();
+        global.write().unwrap().settings.silence_alert = 5;
+        check_silence(&global, &mut fired);
+        assert!(fired.silent.is_empty());
+
+        // the collector stopped 10s ago
+        let last_write = now() - 10;
+        global.write().unwrap().last_write.insert("btc_usdt".to_owned(), last_write);
+        check_silence(&global, &mut fired);
+        assert_eq!(fired.silent.get("btc_usdt"), Some(&last_write));
+        check_silence(&global, &mut fired);
+        assert_eq!(fired.silent.len(), 1);
+
+        // rows arrive again
+        add(&mut state, 1);
+        check_silence(&global, &mut fired);
+        assert!(fired.silent.is_empty());
+    }
+
+    #[test]
+    fn should_insert_the_alerts_of_a_rule_into_its_store() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "CREATE alerts");
+        run(&mut state, "ALERT SILENT btc_usdt AFTER 30s INTO alerts");
+        run(&mut state, "USE btc_usdt");
+        add(&mut state, 0);
+        global.write().unwrap().last_write.insert("btc_usdt".to_owned(), now() - 60);
+
+        let mut fired = Fired::default();
+        check_rules(&global, &mut fired);
+        check_rules(&global, &mut fired);
+        let rows = &global.read().unwrap().vec_store["alerts"].0;
+        assert_eq!(rows.len(), 1);
+        assert_eq!((rows[0].seq, rows[0].size), (1, 30.));
+        assert!(rows[0].price >= 60.);
+    }
+}
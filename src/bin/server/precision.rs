@@ -0,0 +1,51 @@
+/// JSON precision
+///
+/// `PRECISION [db] [n]` rounds price and size in the JSON responses of a
+/// store to n decimal places, `PRECISION [db] OFF` goes back to the shortest
+/// exact representation. A query can override it with `PRECISION n`.
+///
+/// Precisions are kept in `[dtf_folder]/precision`, one `db <TAB> n|off`
+/// line each. A later line for the same store wins.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use utils;
+
+/// most decimal places, more than an f32 has
+pub static MAX_DECIMALS : usize = 17;
+
+/// store name -> decimal places
+pub type Precisions = HashMap<String, usize>;
+
+fn precision_fname(dtf_folder: &str) -> String {
+    utils::join(dtf_folder, "precision")
+}
+
+/// all precisions kept in the folder
+pub fn read(dtf_folder: &str) -> Precisions {
+    let mut precisions = HashMap::new();
+    let file = match File::open(precision_fname(dtf_folder)) {
+        Ok(f) => f,
+        Err(_) => return precisions
+    };
+    for line in BufReader::new(file).lines().filter_map(|line| line.ok()) {
+        let parts : Vec<&str> = line.split('\t').collect();
+        if parts.len() != 2 {
+            continue;
+        }
+        match parts[1].parse::<usize>() {
+            Ok(decimals) => { precisions.insert(parts[0].to_owned(), decimals); }
+            Err(_) => { precisions.remove(parts[0]); }
+        }
+    }
+    precisions
+}
+
+pub fn record(dtf_folder: &str, store_name: &str, decimals: Option<usize>) -> Result<(), io::Error> {
+    let mut file = OpenOptions::new().create(true).append(true).open(precision_fname(dtf_folder))?;
+    match decimals {
+        Some(decimals) => writeln!(file, "{}\t{}", store_name, decimals),
+        None => writeln!(file, "{}\toff", store_name)
+    }
+}
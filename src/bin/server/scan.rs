@@ -0,0 +1,160 @@
+/// Parallel range scans
+///
+/// A read over a long range, e.g. a week of 1-minute candles, decodes
+/// thousands of batches. The batches of the store's files within the range
+/// (see `zonemap`) are split into at most `--scan_threads` runs of
+/// consecutive batches, each decoded by a worker of a pool, and the runs are
+/// concatenated in file order, so the rows come out as a sequential scan
+/// returns them. `fold` hands each decoded batch to an accumulator of its
+/// worker instead, e.g. the heap of `TOPN`, so the range is never held in
+/// memory. Workers stop between batches once the command is cancelled or
+/// times out.
+
+use std::fs::File;
+use std::io::{BufReader, Seek, SeekFrom};
+use std::sync::{mpsc, Arc};
+use std::time::Instant;
+use connection::Connection;
+use dtf::{self, Update};
+use threadpool::ThreadPool;
+use zonemap;
+
+/// fewer batches per worker are decoded on the calling thread
+static MIN_BATCHES_PER_THREAD : usize = 64;
+
+/// what stops the workers of a scan, see `State::interrupted`
+#[derive(Clone)]
+pub struct Interrupt {
+    pub connection: Arc<Connection>,
+    pub deadline: Option<Instant>,
+}
+
+impl Interrupt {
+    fn is_set(&self) -> bool {
+        self.connection.is_cancelled() || self.deadline.map_or(false, |deadline| Instant::now() >= deadline)
+    }
+}
+
+/// Rows of `files`, oldest first, within [min_ts, max_ts] (in ms), decoded by up to `threads` workers
+pub fn range(files: &[String], min_ts: u64, max_ts: u64, threads: usize, interrupt: &Interrupt) -> Result<Vec<Update>, String> {
+    let mut runs = fold(files, min_ts, max_ts, threads, interrupt, Vec::new, |ups: &mut Vec<Update>, batch| ups.extend(batch))?;
+    let mut ups = if runs.is_empty() { Vec::new() } else { runs.remove(0) };
+    for run in runs {
+        ups.extend(run);
+    }
+    Ok(ups)
+}
+
+/// `range` folded into an accumulator per worker one batch at a time, so
+/// the rows of the range are never all in memory. The accumulators come
+/// back in file order, each over a run of consecutive batches.
+pub fn fold<A, I, F>(files: &[String], min_ts: u64, max_ts: u64, threads: usize, interrupt: &Interrupt, init: I, fold: F)
+    -> Result<Vec<A>, String>
+    where A: Send + 'static, I: Fn() -> A, F: Fn(&mut A, Vec<Update>) + Send + Sync + 'static
+{
+    let mut batches = Vec::new();
+    for fname in files {
+        batches.extend(zonemap::get(fname).into_iter()
+            .filter(|zone| zone.batch.max_ts >= min_ts && zone.batch.min_ts <= max_ts)
+            .map(|zone| (fname.clone(), zone.batch.offset)));
+    }
+
+    let threads = threads.min(batches.len() / MIN_BATCHES_PER_THREAD).max(1);
+    if threads == 1 {
+        let mut acc = init();
+        fold_batches(&batches, min_ts, max_ts, interrupt, &mut acc, &fold)?;
+        return Ok(vec![acc]);
+    }
+
+    let per_thread = (batches.len() + threads - 1) / threads;
+    let fold = Arc::new(fold);
+    let (tx, rx) = mpsc::channel();
+    {
+        let pool = ThreadPool::new(threads);
+        for (i, run) in batches.chunks(per_thread).enumerate() {
+            let run = run.to_vec();
+            let interrupt = interrupt.clone();
+            let fold = fold.clone();
+            let mut acc = init();
+            let tx = tx.clone();
+            pool.execute(move || {
+                let folded = fold_batches(&run, min_ts, max_ts, &interrupt, &mut acc, &*fold).map(|_| acc);
+                tx.send((i, folded)).unwrap();
+            });
+        }
+        // dropping the pool waits for every worker
+    }
+    drop(tx);
+
+    let mut runs : Vec<(usize, Result<A, String>)> = rx.iter().collect();
+    runs.sort_by_key(|&(i, _)| i);
+    runs.into_iter().map(|(_, run)| run).collect()
+}
+
+/// fold the rows within [min_ts, max_ts] of the batches at (fname, offset) into `acc`
+fn fold_batches<A, F: Fn(&mut A, Vec<Update>)>(batches: &[(String, u64)], min_ts: u64, max_ts: u64, interrupt: &Interrupt,
+        acc: &mut A, fold: &F) -> Result<(), String> {
+    let mut rdr : Option<(&str, BufReader<File>)> = None;
+    for &(ref fname, offset) in batches {
+        if interrupt.is_set() {
+            break;
+        }
+        if rdr.as_ref().map_or(true, |&(open, _)| open != fname.as_str()) {
+            let file = File::open(fname).map_err(|e| format!("{:?}", e))?;
+            rdr = Some((fname, BufReader::new(file)));
+        }
+        let file = &mut rdr.as_mut().unwrap().1;
+        file.seek(SeekFrom::Start(offset)).map_err(|e| format!("{:?}", e))?;
+        fold(acc, dtf::try_read_one_batch_range(file, min_ts, max_ts)
+            .map_err(|e| format!("{}: {}", fname, e))?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use uuid::Uuid;
+    use utils;
+
+    /// a dtf file of `n` rows a second apart, in batches of 15 rows
+    fn file(n: u64) -> (String, Vec<Update>) {
+        let folder = format!("{}/tectonic_{}", env::temp_dir().display(), Uuid::new_v4());
+        utils::create_dir_if_not_exist(&folder);
+        let ups : Vec<Update> = (0..n)
+            .map(|i| Update::new(1_505_177_459_000 + i * 1000, i as u32, false, true, 0.07, 1.))
+            .collect();
+        let fname = utils::dtf_fname(&folder, "btc_usdt");
+        dtf::encode(&fname, "btc_usdt", &ups);
+        (fname, ups)
+    }
+
+    fn interrupt() -> Interrupt {
+        Interrupt { connection: Arc::new(Connection::new(1, "test")), deadline: None }
+    }
+
+    #[test]
+    fn should_return_the_rows_of_a_sequential_scan() {
+        let (fname, ups) = file(6000);
+        let files = [fname];
+        let (min_ts, max_ts) = (ups[100].ts, ups[5900].ts);
+        let expected : Vec<Update> = ups[100..5901].to_vec();
+        assert_eq!(range(&files, min_ts, max_ts, 1, &interrupt()).unwrap(), expected);
+        assert_eq!(range(&files, min_ts, max_ts, 4, &interrupt()).unwrap(), expected);
+
+        // one accumulator per worker, in file order
+        let counts = fold(&files, min_ts, max_ts, 4, &interrupt(), || 0, |n: &mut usize, batch| *n += batch.len()).unwrap();
+        assert_eq!(counts.len(), 4);
+        assert_eq!(counts.iter().sum::<usize>(), expected.len());
+    }
+
+    #[test]
+    fn should_stop_once_the_command_is_cancelled() {
+        let (fname, ups) = file(6000);
+        let interrupt = interrupt();
+        interrupt.connection.start("GET ALL");
+        interrupt.connection.cancel();
+        assert!(range(&[fname], ups[0].ts, ups[5999].ts, 4, &interrupt).unwrap().is_empty());
+    }
+}
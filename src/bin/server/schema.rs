@@ -0,0 +1,288 @@
+/// Schema of the protocol
+///
+/// `SCHEMA` describes the commands, how their responses are written and the
+/// stores as one JSON document, so clients can be generated from it and
+/// third-party client libraries checked against it:
+///
+///     {"version": "0.1.7", "protocol": {...}, "formats": {...}, "row": {...},
+///      "commands": [{"command": "ADD", "syntax": "ADD [ts],...", "response": "text", "read_only": false}, ...],
+///      "stores": {"btc": {"memory": false, "precision": 4, "tags": {"exchange": "binance"}, "flags": ["synthetic"], ...}}}
+///
+/// `row` is the JSON Schema of a row of `GET ... AS JSON`, the same for every
+/// store. `read_only` tells whether a listener with `allow=read` accepts the
+/// command. The server only speaks its line protocol, there is no HTTP API
+/// for an OpenAPI description.
+///
+/// A command added to the handler is added to COMMANDS too, the commands of
+/// extensions are listed with their `help`.
+
+use std::collections::BTreeMap;
+use serde_json::{self, Value};
+use settings::Allow;
+use extension::Extensions;
+
+pub static VERSION : &str = env!("CARGO_PKG_VERSION");
+
+/// JSON Schema of a row of `GET ... AS JSON`
+static ROW : &str = r#"{
+    "type": "object",
+    "properties": {
+        "ts": {"type": "number", "description": "secs since epoch, ms as decimals"},
+        "seq": {"type": "integer", "minimum": 0, "maximum": 4294967295},
+        "is_trade": {"type": "boolean"},
+        "is_bid": {"type": "boolean"},
+        "price": {"type": "number", "description": "f32, rounded with PRECISION"},
+        "size": {"type": "number", "description": "f32, rounded with PRECISION"},
+        "flags": {"type": "integer", "minimum": 1, "maximum": 63, "description": "bits named by the `flags` of the store, left out if none is set"}
+    },
+    "required": ["ts", "seq", "is_trade", "is_bid", "price", "size"]
+}"#;
+
+static PROTOCOL : &[(&str, &str)] = &[
+    ("request", "one command per line over TCP, ending with \\n"),
+    ("response", "success byte (1, or 0 for an error), body length as u64 big endian, body; \
+                  an error body is `ERR: [message]`. Some commands write their response as described by `formats` instead"),
+];
+
+/// name -> how a response is written
+static FORMATS : &[(&str, &str)] = &[
+    ("text", "a response whose body is text, e.g. `1` or a message"),
+    ("json", "a response whose body is a JSON document"),
+    ("rows", "a response whose body holds rows in the FORMAT of the connection: dtf batches by default, \
+              a JSON array of `row` with AS JSON, one `row` per line with AS JSONL"),
+    ("stream", "success byte, then dtf batches as they are read, then a 0 byte"),
+    ("frames", "success byte, then frames of `offset,...` lines, each after its length as u64 big endian, \
+                until the subscriber disconnects; an empty frame ends a cancelled subscription"),
+    ("dtf", "success byte, then a dtf file"),
+];
+
+/// (syntax, response format)
+static COMMANDS : &[(&str, &str)] = &[
+    ("PING", "text"),
+    ("HELP", "text"),
+    ("INFO", "json"),
+    ("SCHEMA", "json"),
+    ("USE [db]", "text"),
+    ("CREATE [db]", "text"),
+    ("CREATE [db] MEMORY", "text"),
+    ("CREATE [db] TTL [30s|15m|24h|7d]", "text"),
+    ("CREATE [db] LIKE [db|template]", "text"),
+    ("CREATE [db] LIKE [funding|open_interest]", "text"),
+    ("CREATE MATERIALIZED [db] FROM [db] INTERVAL [1m|1h|1d]", "text"),
+    ("CREATE VIRTUAL [db] AS [expression] [INTERVAL 1s] [MATERIALIZED]", "text"),
+    ("CREATE IF NOT EXISTS [db]", "json"),
+    ("EXISTS [db]", "text"),
+    ("EXISTS [db] AS JSON", "json"),
+    ("ADD [ts],[seq],[is_trade],[is_bid],[price],[size][,flags]; [INTO db]", "text"),
+    ("ADD CORRECTION [ts],[seq],[is_trade],[is_bid],[price],[size]; [INTO db]", "text"),
+    ("BULKADD [INTO db] [COUNT n [CRC crc32]]", "text"),
+    ("DDAKLUB", "text"),
+    ("BEGIN", "text"),
+    ("COMMIT", "text"),
+    ("ROLLBACK", "text"),
+    ("VALIDATE [row]", "json"),
+    ("STRICT [ON|OFF]", "text"),
+    ("FLUSH", "text"),
+    ("FLUSHALL", "text"),
+    ("CLEAR", "text"),
+    ("CLOSE [db]", "text"),
+    ("COUNT", "text"),
+    ("COUNT ALL", "text"),
+    ("GETALL", "rows"),
+    ("GET [count|ALL] [FROM ts TO ts] [AS JSON|JSONL [PRECISION n]] [LIMIT n [CURSOR token]]", "rows"),
+    ("GET [count|ALL] [FROM ts TO ts] STREAM [WINDOW n]", "stream"),
+    ("GET [count|ALL] [FROM ts TO ts] [AS JSON|JSONL] ASOF EPOCH [n]", "rows"),
+    ("GET ... AFTER [offset] [TIMEOUT ms]", "rows"),
+    ("EPOCHS [db]", "json"),
+    ("VACUUM [db]", "text"),
+    ("EXPLAIN GET ...", "json"),
+    ("EXPLAIN COPY ...", "json"),
+    ("FORMAT [DTF|FLAT|MSGPACK]", "text"),
+    ("HELLO [protocol=n] [store=db] [format=binary|flat|msgpack]", "json"),
+    ("LATEST [db]", "json"),
+    ("STATS [db]", "json"),
+    ("PERF", "json"),
+    ("PERF LATENCY [RESET]", "json"),
+    ("SLOWLOG GET [n]", "json"),
+    ("SLOWLOG RESET", "text"),
+    ("CONNECTIONS", "json"),
+    ("CANCEL [id]", "text"),
+    ("FLUSH ALL ASYNC", "json"),
+    ("CLEAR ALL ASYNC", "json"),
+    ("JOB STATUS [id]", "json"),
+    ("JOB CANCEL [id]", "text"),
+    ("JOB COMPACT [db]", "json"),
+    ("JOB PRUNE", "json"),
+    ("JOB INTEGRITY [fast|deep]", "json"),
+    ("JOB BACKUP TO [folder] [INCREMENTAL]", "json"),
+    ("JOBS", "json"),
+    ("TIMEOUT [ms]", "text"),
+    ("DELETE FROM [db] WHERE ts BETWEEN [ts] AND [ts]", "text"),
+    ("COMPACT [db]", "text"),
+    ("COPY [db] TO [db] [FROM ts TO ts] [WHERE ...]", "text"),
+    ("REPLAY [db] [FROM ts TO ts] [SPEED 10x]", "stream"),
+    ("DOWNLOAD [db] [FROM ts TO ts]", "dtf"),
+    ("UPLOAD INTO [db] [bytes]", "text"),
+    ("SYNC FROM [host:port] STORES [pattern] [SINCE ts] [VERIFY]", "json"),
+    ("CHECKSUMS [db] [FROM ts TO ts]", "json"),
+    ("JOIN [trades db] ASOF [quotes|series db] [FROM ts TO ts] [TOLERANCE ms]", "json"),
+    ("SERIES [db] [FROM ts TO ts] INTERVAL [1h|8h|1d]", "json"),
+    ("APPROX [COUNT|VWAP] [db] [FROM ts TO ts] [ERROR 1%]", "json"),
+    ("TOPN [db] [n] BY [size|notional|spread] [FROM ts TO ts]", "json"),
+    ("HIST [db] FIELD [price|size|notional] BUCKETS [n] [FROM ts TO ts] [WHERE ...]", "json"),
+    ("SUBSCRIBE [db] [FROM OFFSET n] [WHERE ...]", "frames"),
+    ("SUBSCRIBE GROUP [name] [FROM OFFSET n] [WHERE ...]", "frames"),
+    ("SUBSCRIBE __cdc__ [FROM OFFSET n]", "frames"),
+    ("ALIAS [alias] FOR [db]", "text"),
+    ("GROUP [name] = [pattern]", "text"),
+    ("GROUP DROP [name]", "text"),
+    ("GROUPS", "text"),
+    ("FLUSH GROUP [name]", "text"),
+    ("CLEAR GROUP [name]", "text"),
+    ("RETAIN [db] [30s|15m|24h|7d|OFF]", "text"),
+    ("RETAIN GROUP [name] [30s|15m|24h|7d|OFF]", "text"),
+    ("TIER [db] COLD [folder] AFTER [30s|15m|24h|7d]", "text"),
+    ("BACKUP TO [folder] [INCREMENTAL]", "text"),
+    ("HA", "json"),
+    ("TOPOLOGY", "json"),
+    ("MULTICAST [db] [OFF]", "text"),
+    ("PRECISION [db] [n|OFF]", "text"),
+    ("FLAGS [db] [name ...|OFF]", "json"),
+    ("META [db] [tag=value ...]", "json"),
+    ("FIND [tag=value|pattern ...]", "json"),
+    ("ALERT SILENT [db] AFTER [30s|15m|24h|7d] [INTO db]", "text"),
+    ("ALERT DISK ABOVE [500MB|2GB] [INTO db]", "text"),
+    ("ALERT FLUSH ERRORS [INTO db]", "text"),
+    ("ALERTS", "text"),
+    ("ALERT DROP [n]", "text"),
+    ("CONFIG GET [setting]", "text"),
+    ("CONFIG SET [setting] [value]", "text"),
+];
+
+#[derive(Serialize)]
+struct Command<'a> {
+    command: String,
+    syntax: &'a str,
+    response: &'static str,
+    read_only: bool,
+}
+
+/// What a store adds to the row schema
+#[derive(Serialize)]
+pub struct Store {
+    /// rows are only kept in memory
+    pub memory: bool,
+    /// store the candles are built from, see `CREATE MATERIALIZED`
+    pub materialized_from: Option<String>,
+    pub ttl_ms: Option<u64>,
+    /// decimal places of price and size in JSON
+    pub precision: Option<usize>,
+    pub aliases: Vec<String>,
+    pub tags: BTreeMap<String, String>,
+    /// names of the bits of `flags`, from the lowest
+    pub flags: Vec<String>,
+    /// `funding` or `open_interest`, see `SERIES`
+    pub series: Option<String>,
+    /// what a virtual store is derived from, see `CREATE VIRTUAL`
+    pub expression: Option<String>,
+}
+
+/// A store as `EXISTS [db] AS JSON` and `CREATE IF NOT EXISTS` answer
+#[derive(Serialize)]
+struct Described<'a> {
+    store: &'a str,
+    exists: bool,
+    /// by this `CREATE IF NOT EXISTS`
+    created: bool,
+    metadata: Option<Store>,
+}
+
+#[derive(Serialize)]
+struct Schema<'a> {
+    version: &'static str,
+    protocol: BTreeMap<&'static str, &'static str>,
+    formats: BTreeMap<&'static str, &'static str>,
+    row: Value,
+    commands: Vec<Command<'a>>,
+    stores: BTreeMap<String, Store>,
+}
+
+/// the schema of the server with `stores` and the commands of `extensions`, as JSON
+pub fn to_json(stores: BTreeMap<String, Store>, extensions: &Extensions) -> String {
+    let read_only = Allow::read_only();
+    let mut commands : Vec<Command> = COMMANDS.iter().map(|&(syntax, response)| Command {
+        // the keywords before the first argument
+        command: syntax.split(' ')
+            .take_while(|word| word.chars().all(|c| c.is_ascii_uppercase() || c == '_'))
+            .collect::<Vec<_>>()
+            .join(" "),
+        syntax,
+        response,
+        read_only: read_only.permits(syntax),
+    }).collect();
+    // extensions answer with text or an error
+    commands.extend(extensions.usages().into_iter().map(|(keyword, syntax)| Command {
+        command: keyword.to_owned(),
+        syntax,
+        response: "text",
+        read_only: read_only.permits(syntax),
+    }));
+    let schema = Schema {
+        version: VERSION,
+        protocol: PROTOCOL.iter().cloned().collect(),
+        formats: FORMATS.iter().cloned().collect(),
+        row: serde_json::from_str(ROW).expect("ROW is valid JSON"),
+        commands,
+        stores,
+    };
+    serde_json::to_string(&schema).unwrap_or_default()
+}
+
+/// `store_name` with its `metadata`, None if it does not exist, as JSON:
+///
+///     {"store": "btc", "exists": true, "created": false, "metadata": {"memory": false, ...}}
+pub fn store_to_json(store_name: &str, created: bool, metadata: Option<Store>) -> String {
+    let described = Described {
+        store: store_name,
+        exists: metadata.is_some(),
+        created,
+        metadata,
+    };
+    serde_json::to_string(&described).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use state::tests::{connect, global, run};
+    use serde_json::{self, Value};
+
+    #[test]
+    fn should_describe_the_commands_and_the_stores() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "CREATE scratch MEMORY");
+        run(&mut state, "PRECISION btc_usdt 4");
+        run(&mut state, "ALIAS btc FOR btc_usdt");
+        let schema : Value = serde_json::from_str(&run(&mut state, "SCHEMA")).unwrap();
+
+        assert_eq!(schema["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(schema["row"]["required"].as_array().unwrap().len(), 6);
+        let command = |syntax: &str| schema["commands"].as_array().unwrap().iter()
+            .find(|command| command["syntax"] == syntax)
+            .cloned()
+            .unwrap_or_else(|| panic!("{} is not described", syntax));
+        let create = command("CREATE [db] MEMORY");
+        assert_eq!((&create["command"], &create["response"], &create["read_only"]),
+            (&Value::from("CREATE"), &Value::from("text"), &Value::from(false)));
+        let exists = command("EXISTS [db] AS JSON");
+        assert_eq!((&exists["command"], &exists["response"], &exists["read_only"]),
+            (&Value::from("EXISTS"), &Value::from("json"), &Value::from(true)));
+
+        let stores = &schema["stores"];
+        assert_eq!(stores["btc_usdt"]["precision"], 4);
+        assert_eq!(stores["btc_usdt"]["aliases"], Value::from(vec!["btc"]));
+        assert_eq!(stores["btc_usdt"]["memory"], false);
+        assert_eq!(stores["scratch"]["memory"], true);
+    }
+}
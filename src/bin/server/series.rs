@@ -0,0 +1,127 @@
+/// Auxiliary series
+///
+/// Funding rates and open interest change every few seconds to hours, they
+/// are kept as stores of their own next to the tick stores, one row per value
+/// in the price field:
+///
+///     1505177459.658, 0, f, f, 0.0001, 0;
+///
+/// `CREATE btc_funding LIKE funding` and `CREATE btc_oi LIKE open_interest`
+/// create them from the built in templates, a template of the config with the
+/// same name wins. `SERIES [db] [FROM ts TO ts] INTERVAL 8h` aggregates them:
+///
+///     [{"ts": 1505174400, "rate": 0.0001, "accrued": 0.0003, "mean": 0.0001, "count": 3}, ...]
+///     [{"ts": 1505174400, "open": 9800, "high": 9950, "low": 9700, "close": 9900, "change": 100, "count": 96}, ...]
+///
+/// and `JOIN [trades db] ASOF [series db]` gives every trade the latest value.
+///
+/// The kind of a series is kept in `[dtf_folder]/series`, one `db <TAB> kind`
+/// line each.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use dtf::Update;
+use dtf::series::Bucket;
+use settings::Template;
+use utils;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// rate paid per funding interval, e.g. 0.0001
+    Funding,
+    /// contracts outstanding
+    OpenInterest,
+}
+
+impl Kind {
+    pub fn parse(name: &str) -> Option<Kind> {
+        match name {
+            "funding" => Some(Kind::Funding),
+            "open_interest" => Some(Kind::OpenInterest),
+            _ => None
+        }
+    }
+
+    /// key of the value in the rows of `JOIN`
+    fn field(self) -> &'static str {
+        match self {
+            Kind::Funding => "funding_rate",
+            Kind::OpenInterest => "open_interest",
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Kind::Funding => write!(f, "funding"),
+            Kind::OpenInterest => write!(f, "open_interest"),
+        }
+    }
+}
+
+/// store name -> kind of series
+pub type Kinds = HashMap<String, Kind>;
+
+/// The built in template named `name`, see `CREATE [db] LIKE`
+pub fn template(name: &str) -> Option<Template> {
+    let series = Kind::parse(name)?;
+    Some(Template {
+        series: Some(series),
+        // rates are small fractions
+        precision: if series == Kind::Funding { Some(8) } else { None },
+        ..Template::default()
+    })
+}
+
+fn series_fname(dtf_folder: &str) -> String {
+    utils::join(dtf_folder, "series")
+}
+
+/// all kinds kept in the folder
+pub fn read(dtf_folder: &str) -> Kinds {
+    let mut kinds = HashMap::new();
+    let file = match File::open(series_fname(dtf_folder)) {
+        Ok(f) => f,
+        Err(_) => return kinds
+    };
+    for line in BufReader::new(file).lines().filter_map(|line| line.ok()) {
+        let parts : Vec<&str> = line.split('\t').collect();
+        if parts.len() != 2 {
+            continue;
+        }
+        if let Some(kind) = Kind::parse(parts[1]) {
+            kinds.insert(parts[0].to_owned(), kind);
+        }
+    }
+    kinds
+}
+
+pub fn record(dtf_folder: &str, store_name: &str, kind: Kind) -> Result<(), io::Error> {
+    let mut file = OpenOptions::new().create(true).append(true).open(series_fname(dtf_folder))?;
+    writeln!(file, "{}\t{}", store_name, kind)
+}
+
+/// the buckets of `SERIES` as a JSON array
+pub fn to_json(kind: Kind, buckets: &[Bucket]) -> String {
+    let rows : Vec<String> = buckets.iter().map(|bucket| match kind {
+        Kind::Funding => format!(r#"{{"ts":{},"rate":{},"accrued":{},"mean":{},"count":{}}}"#,
+            bucket.ts / 1000, bucket.last, bucket.sum as f32, bucket.mean() as f32, bucket.count),
+        Kind::OpenInterest => format!(r#"{{"ts":{},"open":{},"high":{},"low":{},"close":{},"change":{},"count":{}}}"#,
+            bucket.ts / 1000, bucket.first, bucket.max, bucket.min, bucket.last, bucket.last - bucket.first, bucket.count),
+    }).collect();
+    format!("[{}]\n", rows.join(","))
+}
+
+/// trades with the latest value of a series of `kind` as a JSON array,
+/// null where there is none
+pub fn join_to_json(kind: Kind, joined: &[(Update, Option<Update>)]) -> String {
+    let rows : Vec<String> = joined.iter().map(|&(ref trade, ref value)| {
+        format!(r#"{{"ts":{},"seq":{},"is_bid":{},"price":{},"size":{},"{}":{}}}"#,
+            (trade.ts as f64) / 1000_f64, trade.seq, trade.is_bid, trade.price, trade.size,
+            kind.field(), value.as_ref().map_or("null".to_owned(), |value| value.price.to_string()))
+    }).collect();
+    format!("[{}]\n", rows.join(","))
+}
@@ -3,104 +3,363 @@
 /// List of commands:
 /// -------------------------------------------
 
-use byteorder::{WriteBytesExt, NetworkEndian, /*ReadBytesExt*/ };
+use byteorder::{ByteOrder, NetworkEndian, /*ReadBytesExt*/ };
 
 use std::str;
 use std::error::Error;
-use std::io::{Read, Write};
+use std::cmp;
+use std::io::{self, IoSlice, Read, Write};
 use std::net::TcpListener;
-use std::net::TcpStream;
+use std::sync::mpsc;
 
 use state::*;
 use handler::ReturnType;
-use utils;
-use handler;
-use settings::Settings;
+use parser;
+use settings::{Allow, Listen, Settings};
+use extension::Extensions;
+use engine::Engine;
+use daemon;
+use tls;
 use threadpool::ThreadPool;
 use std::sync::{Arc, RwLock};
 
-use plugins::run_plugins;
+use dtf;
+use std::thread;
+use std::time::Duration;
 
-fn respond(mut stream: &TcpStream, mut state: &mut State, line: &str) {
-    let resp = handler::gen_response(&line, &mut state);
+/// largest dtf file accepted by UPLOAD
+pub static MAX_UPLOAD_BYTES : u64 = 1 << 30;
+
+/// ms between two checks for new rows of a subscribed store
+static SUBSCRIBE_POLL_MS : u64 = 10;
+
+/// most parts `send` writes at once, the header included
+const MAX_PARTS : usize = 4;
+
+/// Write `parts` one after the other, in one `writev` on a socket unless
+/// it only takes part of them
+fn send<S: Write>(stream: &mut S, parts: &[&[u8]]) -> io::Result<()> {
+    assert!(parts.len() <= MAX_PARTS);
+    // the part being written and how much of it is written
+    let (mut part, mut written) = (0, 0);
+    while part < parts.len() {
+        let mut slices = [IoSlice::new(&[]); MAX_PARTS];
+        slices[0] = IoSlice::new(&parts[part][written..]);
+        for (slice, rest) in slices[1..].iter_mut().zip(&parts[(part + 1)..]) {
+            *slice = IoSlice::new(rest);
+        }
+        let mut n = match stream.write_vectored(&slices[..(parts.len() - part)]) {
+            Ok(0) if parts[part..].iter().any(|rest| !rest.is_empty()) =>
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write the response")),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        };
+        while part < parts.len() && n >= parts[part].len() - written {
+            n -= parts[part].len() - written;
+            part += 1;
+            written = 0;
+        }
+        written += n;
+    }
+    Ok(())
+}
+
+/// success byte and length of a response
+fn header(success: bool, len: usize) -> [u8; 9] {
+    let mut header = [0; 9];
+    header[0] = if success { 0x1 } else { 0x0 };
+    NetworkEndian::write_u64(&mut header[1..], len as u64);
+    header
+}
+
+/// Send the response to `line`. `buf` is the connection's buffer for the
+/// chunks of streams, replays and subscriptions, reused from one to the next.
+fn respond<S: Write>(stream: &mut S, state: &mut State, line: &str, resp: ReturnType, buf: &mut Vec<u8>) {
     match resp {
         ReturnType::Bytes(bytes)  => {
-            stream.write_u8(0x1).unwrap();
-            stream.write(&bytes).unwrap()
+            send(stream, &[&[0x1], &bytes]).unwrap();
+        }
+        ReturnType::Stream(query) => {
+            // one chunk at a time: the store lock is only held while copying a
+            // chunk out and blocking writes apply the socket's backpressure
+            send(stream, &[&[0x1]]).unwrap();
+            let mut cursor = None;
+            // a cancelled or timed out stream just ends early
+            while !state.interrupted() {
+                let (chunk, next_cursor) = state.get_page(query.count, query.range, query.window, cursor);
+                if !chunk.is_empty() {
+                    buf.clear();
+                    dtf::write_batches(buf, &chunk);
+                    send(stream, &[&buf[..]]).unwrap();
+                }
+                match next_cursor {
+                    Some(c) => cursor = Some(c),
+                    None => break
+                }
+            }
+            // end of stream
+            send(stream, &[&[0x0]]).unwrap();
+        }
+        ReturnType::Replay(query) => {
+            // rows of the same ts go out together, after the original gap
+            send(stream, &[&[0x1]]).unwrap();
+            let mut start = 0;
+            while start < query.ups.len() && !state.connection.is_cancelled() {
+                let ts = query.ups[start].ts;
+                let end = start + query.ups[start..].iter().take_while(|up| up.ts == ts).count();
+                if start > 0 {
+                    let gap = ts.saturating_sub(query.ups[start - 1].ts) as f64 / query.speed;
+                    thread::sleep(Duration::from_millis(gap as u64));
+                }
+                buf.clear();
+                dtf::write_batches(buf, &query.ups[start..end]);
+                if send(stream, &[&buf[..]]).is_err() {
+                    // the subscriber went away
+                    return;
+                }
+                start = end;
+            }
+            // end of replay
+            send(stream, &[&[0x0]]).unwrap();
+        }
+        ReturnType::Subscribe(query) => {
+            // frames of `offset,ts,seq,is_trade,is_bid,price,size` lines, or
+            // `offset,store,ts,...` for a group, the offset of the last line
+            // is where to resume after a disconnect
+            send(stream, &[&[0x1]]).unwrap();
+            let mut offset = query.offset;
+            while !state.connection.is_cancelled() {
+                let rows = match state.feed_since(&query.stores, offset) {
+                    Some(rows) => rows,
+                    None => return
+                };
+                if rows.is_empty() {
+                    thread::sleep(Duration::from_millis(SUBSCRIBE_POLL_MS));
+                    continue;
+                }
+                offset = rows.last().unwrap().0;
+                buf.clear();
+                for &(row_offset, i, ref up) in rows.iter().filter(|&&(_, _, ref up)| query.filter.matches(up)) {
+                    let _ = if query.group {
+                        writeln!(buf, "{},{},{}", row_offset, query.stores[i], up.to_csv())
+                    } else {
+                        writeln!(buf, "{},{}", row_offset, up.to_csv())
+                    };
+                }
+                if buf.is_empty() {
+                    continue;
+                }
+                let mut len = [0; 8];
+                NetworkEndian::write_u64(&mut len, buf.len() as u64);
+                if send(stream, &[&len, &buf[..]]).is_err() {
+                    // the subscriber went away
+                    return;
+                }
+            }
+            // an empty frame ends a cancelled subscription
+            let _ = send(stream, &[&[0; 8]]);
+        }
+        ReturnType::Cdc(mut offset) => {
+            // frames of `offset,json` lines, like a subscription to a store
+            send(stream, &[&[0x1]]).unwrap();
+            while !state.connection.is_cancelled() {
+                let events = state.cdc_since(offset);
+                if events.is_empty() {
+                    thread::sleep(Duration::from_millis(SUBSCRIBE_POLL_MS));
+                    continue;
+                }
+                offset = events.last().unwrap().0;
+                buf.clear();
+                for &(event_offset, ref json) in &events {
+                    let _ = writeln!(buf, "{},{}", event_offset, json);
+                }
+                let mut len = [0; 8];
+                NetworkEndian::write_u64(&mut len, buf.len() as u64);
+                if send(stream, &[&len, &buf[..]]).is_err() {
+                    // the subscriber went away
+                    return;
+                }
+            }
+            // an empty frame ends a cancelled subscription
+            let _ = send(stream, &[&[0; 8]]);
         }
         ReturnType::String(str_resp) => {
-            stream.write_u8(0x1).unwrap();
-            stream.write_u64::<NetworkEndian>(str_resp.len() as u64).unwrap();
-            stream.write(str_resp.as_bytes()).unwrap()
+            send(stream, &[&header(true, str_resp.len()), str_resp.as_bytes()]).unwrap();
         },
         ReturnType::Error(errmsg) => {
             error!("Req: `{}`", line);
             error!("Err: `{}`", errmsg.clone());
 
-            stream.write_u8(0x0).unwrap();
-            let ret = format!("ERR: {}\n", errmsg);
-            stream.write_u64::<NetworkEndian>(ret.len() as u64).unwrap();
-            stream.write(ret.as_bytes()).unwrap()
+            let len = "ERR: ".len() + errmsg.len() + "\n".len();
+            send(stream, &[&header(false, len), b"ERR: ", errmsg.as_bytes(), b"\n"]).unwrap();
         }
     };
 }
 
-fn handle_client(mut stream: TcpStream, global: &LockedGlobal) {
-    let settings = {
-        let shared_state = global.read().unwrap();
-        &shared_state.settings.clone()
-    };
-    let dtf_folder = &settings.dtf_folder;
-    utils::create_dir_if_not_exist(&dtf_folder);
-
-    let mut state = State::new(global);
-    utils::init_dbs(&mut state);
+fn handle_client<S: Read + Write>(mut stream: S, peer: &str, allow: &Allow, engine: &Engine) {
+    let mut session = engine.session(peer);
 
     let mut buf = [0; 2048];
+    let mut out = Vec::new();
     loop {
         let bytes_read = stream.read(&mut buf).unwrap();
         if bytes_read == 0 { break }
-        let req = str::from_utf8(&buf[..(bytes_read-1)]).unwrap();
-        for line in req.split('\n') {
+        let mut rest : &[u8] = &buf[..bytes_read];
+        while !rest.is_empty() {
+            let end = rest.iter().position(|&b| b == b'\n').unwrap_or(rest.len());
+            let line = str::from_utf8(&rest[..end]).unwrap();
+            rest = if end < rest.len() { &rest[(end + 1)..] } else { &[] };
             // println!("[DEBUG] Received:\t{:?}", line);
-            respond(&stream, &mut state, &line);
+            // rows sent after BULKADD were allowed with it
+            let allowed = session.state.is_adding || allow.permits(line);
+            let resp = match parser::parse_upload(line) {
+                // the dtf file follows the command
+                Some((_, len)) => match read_body(&mut stream, &mut rest, len) {
+                    Ok(Some(_)) if !allowed => not_allowed(line),
+                    Ok(Some(file)) => session.upload(line, &file),
+                    Ok(None) => ReturnType::Error(format!("UPLOAD is limited to {} bytes.", MAX_UPLOAD_BYTES)),
+                    Err(e) => { error!("Unable to read upload: {:?}", e); return }
+                },
+                None if !allowed => not_allowed(line),
+                None => session.execute(line)
+            };
+            respond(&mut stream, &mut session.state, line, resp, &mut out);
+            session.state.connection.finish();
         }
     }
 }
 
-pub fn run_server(host : &str, port : &str, settings: &Settings) {
-    let addr = format!("{}:{}", host, port);
+fn not_allowed(line: &str) -> ReturnType {
+    let keyword = line.split(' ').next().unwrap_or(line);
+    ReturnType::Error(format!("`{}` is not allowed on this listener.", keyword))
+}
+
+/// Read `len` bytes sent after a command, starting with the ones already
+/// read into `rest`. None if the body is too long, it is skipped then.
+fn read_body<S: Read>(stream: &mut S, rest: &mut &[u8], len: u64) -> io::Result<Option<Vec<u8>>> {
+    let buffered = cmp::min(len, rest.len() as u64) as usize;
+    let (head, tail) = rest.split_at(buffered);
+    *rest = tail;
+    if len > MAX_UPLOAD_BYTES {
+        io::copy(&mut stream.take(len - buffered as u64), &mut io::sink())?;
+        return Ok(None);
+    }
+    let mut body = vec![0; len as usize];
+    body[..buffered].copy_from_slice(head);
+    stream.read_exact(&mut body[buffered..])?;
+    Ok(Some(body))
+}
+
+pub fn run_server(host : &str, port : &str, settings: &Settings, extensions: Extensions) {
+    let listeners = bind(host, port, settings);
+    // opening the engine runs the integrity scan, clients wait in the backlog meanwhile
+    let engine = Arc::new(Engine::open(settings, extensions));
+    daemon::ready(settings, &local_addrs(&listeners));
+    serve(listeners, settings, engine);
+}
+
+/// A bound socket of `--listen`
+pub struct Listener {
+    socket: TcpListener,
+    tls: Option<tls::Acceptor>,
+    allow: Allow,
+}
+
+/// The sockets of `--listen`, or `host:port` without it. With `--daemon`,
+/// the sockets passed by the service manager are used in the same order.
+pub fn bind(host : &str, port : &str, settings: &Settings) -> Vec<Listener> {
+    let default = [Listen { addr: format!("{}:{}", host, port), tls: None, allow: Allow::All }];
+    let listens = if settings.listeners.is_empty() { &default[..] } else { &settings.listeners[..] };
 
-    info!("Trying to bind to addr: {}", addr);
+    let mut activated = if settings.daemon { daemon::activated_listeners() } else { Vec::new() };
+    if activated.len() > listens.len() {
+        warn!("{} sockets were passed for {} listeners, the rest is unused", activated.len(), listens.len());
+    }
+    activated.reverse();
+
+    listens.iter().map(|listen| {
+        let socket = match activated.pop() {
+            Some(socket) => {
+                info!("Listening on the socket passed by the service manager for {}", listen.addr);
+                socket
+            }
+            None => {
+                info!("Trying to bind to addr: {}", listen.addr);
+                let socket = match TcpListener::bind(&listen.addr) {
+                    Ok(l) => l,
+                    Err(e) => panic!(format!("{:?}", e.description()))
+                };
+                info!("Listening on addr: {}", listen.addr);
+                socket
+            }
+        };
+        let tls = listen.tls.as_ref().map(|fname| match tls::acceptor(fname) {
+            Ok(acceptor) => acceptor,
+            Err(e) => panic!("{}", e)
+        });
+        Listener { socket, tls, allow: listen.allow.clone() }
+    }).collect()
+}
+
+/// addresses of the listeners, for logs and status messages
+pub fn local_addrs(listeners: &[Listener]) -> String {
+    listeners.iter()
+        .map(|listener| listener.socket.local_addr().map(|addr| addr.to_string()).unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Accept connections to `engine`, one session per connection, until the process exits
+pub fn serve(listeners: Vec<Listener>, settings: &Settings, engine: Arc<Engine>) {
     if !settings.autoflush {
         warn!("Autoflush is off!");
     }
     debug!("Autoflush is {}: every {} inserts.", settings.autoflush, settings.flush_interval);
     debug!("Maximum connection: {}.", settings.threads);
     debug!("History granularity: {}.", settings.hist_granularity);
-
-    let listener = match TcpListener::bind(&addr) {
-        Ok(l) => l,
-        Err(e) => panic!(format!("{:?}", e.description()))
-    };
-
-    info!("Listening on addr: {}", addr);
     info!("-----------------initiated-----------------");
 
     let pool = ThreadPool::new(settings.threads);
-    let global = Arc::new(RwLock::new(SharedState::new(settings.clone()))); 
-
 
-    run_plugins(global.clone());
+    // one thread accepting per listener, the connections share the pool
+    let (tx, rx) = mpsc::channel();
+    for listener in listeners {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            let Listener { socket, tls, allow } = listener;
+            let config = Arc::new((tls, allow));
+            for stream in socket.incoming() {
+                let stream = stream.unwrap();
+                if tx.send((stream, config.clone())).is_err() {
+                    break;
+                }
+            }
+        });
+    }
 
     // main loop
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        let global_copy = global.clone();
+    for (stream, config) in rx {
+        // checked on every connection, the lists can be changed with CONFIG SET
+        if let Ok(addr) = stream.peer_addr() {
+            if !engine.global().read().unwrap().settings.permits_ip(addr.ip()) {
+                warn!("Refused connection from {}.", addr);
+                continue;
+            }
+        }
+        let engine = engine.clone();
         pool.execute(move || {
-            on_connect(&global_copy);
-            handle_client(stream, &global_copy);
-            on_disconnect(&global_copy);
+            let (ref tls, ref allow) = *config;
+            let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+            on_connect(engine.global());
+            match *tls {
+                Some(ref acceptor) => match tls::accept(acceptor, stream) {
+                    Ok(stream) => handle_client(stream, &peer, allow, &engine),
+                    Err(e) => error!("TLS handshake with {} failed: {}", peer, e)
+                },
+                None => handle_client(stream, &peer, allow, &engine)
+            }
+            on_disconnect(engine.global());
         });
     }
 }
@@ -124,4 +383,67 @@ fn on_disconnect(global: &LockedGlobal) {
 
     let rdr = global.read().unwrap();
     info!("Client connection disconnected. Current: {}.", rdr.n_cxns);
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use state::tests::{connect, global};
+
+    /// a socket taking at most `max` bytes per write
+    struct Trickle {
+        out: Vec<u8>,
+        max: usize,
+        writes: usize,
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+            self.writes += 1;
+            let mut n = 0;
+            for buf in bufs {
+                let take = cmp::min(buf.len(), self.max - n);
+                self.out.extend_from_slice(&buf[..take]);
+                n += take;
+            }
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn should_send_every_part_of_partial_writes() {
+        let mut socket = Trickle { out: Vec::new(), max: 1 << 20, writes: 0 };
+        send(&mut socket, &[b"ab", b"", b"cde"]).unwrap();
+        assert_eq!((&socket.out[..], socket.writes), (&b"abcde"[..], 1));
+
+        let mut socket = Trickle { out: Vec::new(), max: 3, writes: 0 };
+        send(&mut socket, &[b"ab", b"cdefg", b"h", b"ij"]).unwrap();
+        assert_eq!(socket.out, b"abcdefghij");
+        assert_eq!(socket.writes, 4);
+
+        let mut socket = Trickle { out: Vec::new(), max: 0, writes: 0 };
+        assert_eq!(send(&mut socket, &[b"ab"]).unwrap_err().kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn should_frame_strings_and_errors() {
+        let global = global();
+        let mut state = connect(&global);
+        let mut buf = Vec::new();
+        let mut socket = Trickle { out: Vec::new(), max: 4, writes: 0 };
+        respond(&mut socket, &mut state, "PING", ReturnType::String("PONG\n".to_owned()), &mut buf);
+        assert_eq!(socket.out, b"\x01\0\0\0\0\0\0\0\x05PONG\n");
+
+        let mut socket = Trickle { out: Vec::new(), max: 1 << 20, writes: 0 };
+        respond(&mut socket, &mut state, "FOO", ReturnType::Error("Unknown command.".to_owned()), &mut buf);
+        assert_eq!(socket.out, &b"\x00\0\0\0\0\0\0\0\x16ERR: Unknown command.\n"[..]);
+        assert_eq!(socket.writes, 1);
+    }
+}
@@ -0,0 +1,25 @@
+/// Windows service
+///
+/// `tectonic-server --service [NAME] [options]` runs the server under the
+/// service control manager of Windows, e.g. on a collector box. Register it
+/// with absolute paths, a service starts in `C:\Windows\System32`:
+///
+///     sc.exe create tectonicdb start= auto binPath= "C:\tectonic\tectonic-server.exe --service tectonicdb -f C:\tectonic\db -l C:\tectonic\tectonic.log"
+///
+/// Stopping the service, or shutting Windows down, flushes every store
+/// before the process exits.
+
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+pub use self::windows::run;
+
+#[cfg(not(windows))]
+use extension::Extensions;
+#[cfg(not(windows))]
+use settings::Settings;
+
+#[cfg(not(windows))]
+pub fn run(_name: &str, _host: &str, _port: &str, _settings: &Settings, _extensions: Extensions) {
+    panic!("--service is only supported on Windows");
+}
@@ -0,0 +1,147 @@
+use std::ffi::OsStr;
+use std::io;
+use std::os::raw::c_void;
+use std::os::windows::ffi::OsStrExt;
+use std::process;
+use std::ptr;
+use std::sync::Arc;
+use std::thread;
+use daemon;
+use engine::Engine;
+use extension::Extensions;
+use server;
+use settings::Settings;
+
+const SERVICE_WIN32_OWN_PROCESS : u32 = 0x10;
+const SERVICE_STOPPED : u32 = 1;
+const SERVICE_STOP_PENDING : u32 = 3;
+const SERVICE_RUNNING : u32 = 4;
+const SERVICE_ACCEPT_STOP : u32 = 0x1;
+const SERVICE_ACCEPT_SHUTDOWN : u32 = 0x4;
+const SERVICE_CONTROL_STOP : u32 = 1;
+const SERVICE_CONTROL_INTERROGATE : u32 = 4;
+const SERVICE_CONTROL_SHUTDOWN : u32 = 5;
+const NO_ERROR : u32 = 0;
+const ERROR_CALL_NOT_IMPLEMENTED : u32 = 120;
+
+/// ms the service control manager waits for the stores to be flushed
+const STOP_WAIT_HINT : u32 = 60_000;
+
+#[repr(C)]
+struct ServiceStatus {
+    service_type: u32,
+    current_state: u32,
+    controls_accepted: u32,
+    win32_exit_code: u32,
+    service_specific_exit_code: u32,
+    check_point: u32,
+    wait_hint: u32,
+}
+
+#[repr(C)]
+struct ServiceTableEntry {
+    name: *const u16,
+    service_main: Option<extern "system" fn(u32, *mut *mut u16)>,
+}
+
+type HandlerEx = extern "system" fn(u32, u32, *mut c_void, *mut c_void) -> u32;
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn StartServiceCtrlDispatcherW(table: *const ServiceTableEntry) -> i32;
+    fn RegisterServiceCtrlHandlerExW(name: *const u16, handler: HandlerEx, context: *mut c_void) -> *mut c_void;
+    fn SetServiceStatus(handle: *mut c_void, status: *const ServiceStatus) -> i32;
+}
+
+/// the server run by `service_main`, set before the dispatcher starts
+struct Service {
+    name: Vec<u16>,
+    host: String,
+    port: String,
+    settings: Settings,
+    engine: Arc<Engine>,
+    handle: *mut c_void,
+}
+
+static mut SERVICE : *mut Service = 0 as *mut Service;
+
+fn to_wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(Some(0)).collect()
+}
+
+fn set_status(handle: *mut c_void, state: u32, wait_hint: u32) {
+    let status = ServiceStatus {
+        service_type: SERVICE_WIN32_OWN_PROCESS,
+        current_state: state,
+        controls_accepted: if state == SERVICE_RUNNING { SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN } else { 0 },
+        win32_exit_code: NO_ERROR,
+        service_specific_exit_code: 0,
+        check_point: 0,
+        wait_hint,
+    };
+    if unsafe { SetServiceStatus(handle, &status) } == 0 {
+        error!("Unable to set the service status: {}", io::Error::last_os_error());
+    }
+}
+
+/// Run the server as the service `name`, returns when the service is stopped
+pub fn run(name: &str, host: &str, port: &str, settings: &Settings, extensions: Extensions) {
+    let engine = Arc::new(Engine::open(settings, extensions));
+    let wide_name = to_wide(name);
+    unsafe {
+        SERVICE = Box::into_raw(Box::new(Service {
+            name: wide_name.clone(),
+            host: host.to_owned(),
+            port: port.to_owned(),
+            settings: settings.clone(),
+            engine,
+            handle: ptr::null_mut(),
+        }));
+    }
+    let table = [
+        ServiceTableEntry { name: wide_name.as_ptr(), service_main: Some(service_main) },
+        ServiceTableEntry { name: ptr::null(), service_main: None },
+    ];
+    if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+        panic!("Unable to start the service, --service only works when started by the service control manager: {}",
+            io::Error::last_os_error());
+    }
+}
+
+extern "system" fn service_main(_argc: u32, _argv: *mut *mut u16) {
+    let service = unsafe {
+        if SERVICE.is_null() { return; }
+        &mut *SERVICE
+    };
+    let handle = unsafe { RegisterServiceCtrlHandlerExW(service.name.as_ptr(), control_handler, ptr::null_mut()) };
+    if handle.is_null() {
+        error!("Unable to register the service control handler: {}", io::Error::last_os_error());
+        return;
+    }
+    service.handle = handle;
+
+    let (host, port, settings, engine) = (service.host.clone(), service.port.clone(), service.settings.clone(), service.engine.clone());
+    thread::spawn(move || {
+        let listeners = server::bind(&host, &port, &settings);
+        daemon::ready(&settings, &server::local_addrs(&listeners));
+        server::serve(listeners, &settings, engine)
+    });
+    set_status(handle, SERVICE_RUNNING, 0);
+    info!("Running as a Windows service");
+}
+
+extern "system" fn control_handler(control: u32, _event_type: u32, _event_data: *mut c_void, _context: *mut c_void) -> u32 {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            if let Some(service) = unsafe { SERVICE.as_ref() } {
+                set_status(service.handle, SERVICE_STOP_PENDING, STOP_WAIT_HINT);
+                info!("Stopping the service, flushing every store");
+                service.engine.session("service").flush_all();
+                set_status(service.handle, SERVICE_STOPPED, 0);
+            }
+            process::exit(0);
+        },
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED
+    }
+}
@@ -1,12 +1,522 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::fmt;
+use std::net::IpAddr;
+use parser;
+use series::Kind;
+use job::{self, Cron};
+use utils;
+
 /// autoflush: boolean. Flush everything to disk at some interval.
 /// dtf_folder: string. folder to save .dtf files
+/// dtf_folder_mirror: Option<String>. folder every flushed dtf file is copied to.
 /// flush_interval: u32. flush at some regular interval.
+/// storage: Storage. where flushed rows are kept.
+/// journal: Option<String>. file to record every received command to, for tectonic-replay.
+/// normalize_names: bool. lowercase store names and replace `-` with `_`.
+/// autocreate_stores: bool. create the store named by ADD INTO if it does not exist.
+/// default_store: Option<String>. store of a connection until USE, None refuses ADD without INTO until then.
+/// group_commit: u64. ms autoflushes wait to be written and synced together, 0 flushes right away.
+/// multicast: Option<String>. group:port the rows of stores selected with MULTICAST are published to.
+/// silence_alert: u64. secs without inserts after which a store raises an alert, 0 is off.
+/// webhook: Option<String>. url alerts are POSTed to.
+/// replay_window: usize. rows the feed of a subscribed store keeps for resuming subscribers.
+/// max_open_files: usize. files kept open between writes, the least recently used is closed first.
+/// integrity_scan: IntegrityScan. how the dtf files are checked on startup.
+/// daemon: bool. notify the service manager when ready and listen on the socket it passes.
+/// pid_file: Option<String>. file the pid is written to once the server is ready.
+/// listeners: Vec<Listen>. sockets to accept connections on, `--host:--port` if empty.
+/// allow_ips: Vec<Cidr>. networks connections are accepted from, any if empty.
+/// deny_ips: Vec<Cidr>. networks connections are refused from, even if allowed.
+/// slowlog_threshold: u64. ms after which a command is recorded in the slow log, 0 is off.
+/// slowlog_file: String. file the slow log is appended to.
+/// query_timeout: u64. ms after which a read is interrupted, 0 is never. See `TIMEOUT`.
+/// shed_reads_above: u64. ms of ingest lag from which full scans are refused, 0 is off. See `parser::is_full_scan`.
+/// result_cache: u64. bytes of responses kept for repeated reads, 0 is off. See `cache`.
+/// scan_threads: usize. threads decoding the batches of a range read, see `scan`.
+/// direct_io: u64. bytes from which a flush or compaction bypasses the page cache, 0 is off. See `direct`.
+/// compaction_throttle: u64. ms an insert into a store being compacted waits at most, 0 is off. See `throttle`.
+/// max_partition_bytes: u64. bytes from which the file of a store is sealed as a partition, 0 is off. See `partition`.
+/// ha_lease: Option<String>. lease file shared by an HA pair, see `lease`.
+/// ha_lease_secs: u64. secs the lease of the primary lasts without renewal.
+/// ha_node: String. name of this server in the lease.
+/// ha_addr: String. host:port clients reach this server at, listed by `TOPOLOGY`.
+/// unload_idle: u64. minutes without reads or inserts after which the flushed rows of a store are dropped from memory, 0 is off. See `unload`.
+/// templates: BTreeMap<String, Template>. settings of the stores created with `CREATE [db] LIKE [template]`.
+/// max_memory: u64. bytes of rows kept in memory before MEMORY stores refuse inserts, 0 is unlimited.
+/// flush_memory: u64. bytes of unflushed rows of all stores from which the largest stores are flushed, 0 is off.
+/// max_jobs: usize. admin jobs run at once, the others are queued. See `job`.
+/// schedules: BTreeMap<String, Schedule>. admin jobs started by the scheduler plugin.
+///
+/// The settings in RUNTIME_SETTINGS can be changed with `CONFIG SET`, and
+/// so can the templates, as `template.[name]`, and the schedules, as
+/// `schedule.[name]`.
 
 #[derive(Clone, Debug)]
 pub struct Settings {
     pub autoflush: bool,
     pub dtf_folder: String,
+    pub dtf_folder_mirror: Option<String>,
     pub flush_interval: u32,
     pub threads: usize,
     pub hist_granularity: u64,
+    pub storage: Storage,
+    pub max_memory: u64,
+    pub flush_memory: u64,
+    pub journal: Option<String>,
+    pub normalize_names: bool,
+    pub autocreate_stores: bool,
+    pub default_store: Option<String>,
+    pub group_commit: u64,
+    pub multicast: Option<String>,
+    pub silence_alert: u64,
+    pub webhook: Option<String>,
+    pub replay_window: usize,
+    pub max_open_files: usize,
+    pub integrity_scan: IntegrityScan,
+    pub daemon: bool,
+    pub pid_file: Option<String>,
+    pub listeners: Vec<Listen>,
+    pub allow_ips: Vec<Cidr>,
+    pub deny_ips: Vec<Cidr>,
+    pub slowlog_threshold: u64,
+    pub slowlog_file: String,
+    pub query_timeout: u64,
+    pub shed_reads_above: u64,
+    pub result_cache: u64,
+    pub scan_threads: usize,
+    pub direct_io: u64,
+    pub compaction_throttle: u64,
+    pub max_partition_bytes: u64,
+    pub unload_idle: u64,
+    pub ha_lease: Option<String>,
+    pub ha_lease_secs: u64,
+    pub ha_node: String,
+    pub ha_addr: String,
+    pub templates: BTreeMap<String, Template>,
+    pub max_jobs: usize,
+    pub schedules: BTreeMap<String, Schedule>,
+}
+
+/// prefix of the config keys of templates, e.g. `template.binance`
+pub static TEMPLATE_PREFIX : &str = "template.";
+
+/// prefix of the config keys of schedules, e.g. `schedule.nightly`
+pub static SCHEDULE_PREFIX : &str = "schedule.";
+
+/// replaced by the address of the client in `--default_store`
+pub static PEER_PLACEHOLDER : &str = "{peer}";
+
+/// settings `CONFIG SET` can change while the server runs
+pub static RUNTIME_SETTINGS : &[&str] = &[
+    "autoflush",
+    "flush_interval",
+    "max_memory",
+    "flush_memory",
+    "normalize_names",
+    "autocreate_stores",
+    "default_store",
+    "group_commit",
+    "silence_alert",
+    "webhook",
+    "allow_ips",
+    "deny_ips",
+    "slowlog_threshold",
+    "query_timeout",
+    "shed_reads_above",
+    "result_cache",
+    "scan_threads",
+    "direct_io",
+    "compaction_throttle",
+    "max_partition_bytes",
+    "unload_idle",
+    "max_jobs",
+];
+
+impl Settings {
+    /// value of a runtime setting, max_memory, flush_memory, result_cache and direct_io are in MB like on the command line
+    pub fn get(&self, key: &str) -> Option<String> {
+        Some(match key {
+            "autoflush" => self.autoflush.to_string(),
+            "flush_interval" => self.flush_interval.to_string(),
+            "max_memory" => (self.max_memory / 1024 / 1024).to_string(),
+            "flush_memory" => (self.flush_memory / 1024 / 1024).to_string(),
+            "normalize_names" => self.normalize_names.to_string(),
+            "autocreate_stores" => self.autocreate_stores.to_string(),
+            "default_store" => self.default_store.clone().unwrap_or_else(|| "none".to_owned()),
+            "group_commit" => self.group_commit.to_string(),
+            "silence_alert" => self.silence_alert.to_string(),
+            "webhook" => self.webhook.clone().unwrap_or_else(|| "none".to_owned()),
+            "allow_ips" => format_cidrs(&self.allow_ips),
+            "deny_ips" => format_cidrs(&self.deny_ips),
+            "slowlog_threshold" => self.slowlog_threshold.to_string(),
+            "query_timeout" => self.query_timeout.to_string(),
+            "shed_reads_above" => self.shed_reads_above.to_string(),
+            "result_cache" => (self.result_cache / 1024 / 1024).to_string(),
+            "scan_threads" => self.scan_threads.to_string(),
+            "direct_io" => (self.direct_io / 1024 / 1024).to_string(),
+            "compaction_throttle" => self.compaction_throttle.to_string(),
+            "max_partition_bytes" => self.max_partition_bytes.to_string(),
+            "unload_idle" => self.unload_idle.to_string(),
+            "max_jobs" => self.max_jobs.to_string(),
+            key if key.starts_with(TEMPLATE_PREFIX) => self.templates.get(&key[TEMPLATE_PREFIX.len()..])?.to_string(),
+            key if key.starts_with(SCHEDULE_PREFIX) => self.schedules.get(&key[SCHEDULE_PREFIX.len()..])?.to_string(),
+            _ => return None
+        })
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let invalid = || format!("Invalid value `{}` for {}", value, key);
+        match key {
+            "autoflush" => self.autoflush = value.parse().map_err(|_| invalid())?,
+            "flush_interval" => {
+                self.flush_interval = match value.parse() {
+                    Ok(0) | Err(_) => return Err(invalid()),
+                    Ok(n) => n
+                }
+            }
+            "max_memory" => self.max_memory = value.parse::<u64>().map_err(|_| invalid())? * 1024 * 1024,
+            "flush_memory" => self.flush_memory = value.parse::<u64>().map_err(|_| invalid())? * 1024 * 1024,
+            "normalize_names" => self.normalize_names = value.parse().map_err(|_| invalid())?,
+            "autocreate_stores" => self.autocreate_stores = value.parse().map_err(|_| invalid())?,
+            "default_store" => {
+                self.default_store = if value == "none" {
+                    None
+                } else {
+                    parser::validate_name(&value.replace(PEER_PLACEHOLDER, "peer"))?;
+                    Some(value.to_owned())
+                }
+            }
+            "group_commit" => self.group_commit = value.parse().map_err(|_| invalid())?,
+            "silence_alert" => self.silence_alert = value.parse().map_err(|_| invalid())?,
+            "webhook" => self.webhook = if value == "none" { None } else { Some(value.to_owned()) },
+            "allow_ips" => self.allow_ips = parser::parse_cidrs(value).ok_or_else(invalid)?,
+            "deny_ips" => self.deny_ips = parser::parse_cidrs(value).ok_or_else(invalid)?,
+            "slowlog_threshold" => self.slowlog_threshold = value.parse().map_err(|_| invalid())?,
+            "query_timeout" => self.query_timeout = value.parse().map_err(|_| invalid())?,
+            "shed_reads_above" => self.shed_reads_above = value.parse().map_err(|_| invalid())?,
+            "result_cache" => self.result_cache = value.parse::<u64>().map_err(|_| invalid())? * 1024 * 1024,
+            "scan_threads" => {
+                self.scan_threads = match value.parse() {
+                    Ok(0) | Err(_) => return Err(invalid()),
+                    Ok(n) => n
+                }
+            }
+            "direct_io" => self.direct_io = value.parse::<u64>().map_err(|_| invalid())? * 1024 * 1024,
+            "compaction_throttle" => self.compaction_throttle = value.parse().map_err(|_| invalid())?,
+            "max_partition_bytes" => {
+                let bytes = value.parse().map_err(|_| invalid())?;
+                if bytes > 0 && self.storage != Storage::DTF {
+                    return Err(format!("{} is only supported with --storage dtf", key));
+                }
+                self.max_partition_bytes = bytes;
+            }
+            "unload_idle" => self.unload_idle = value.parse().map_err(|_| invalid())?,
+            "max_jobs" => {
+                self.max_jobs = match value.parse() {
+                    Ok(0) | Err(_) => return Err(invalid()),
+                    Ok(n) => n
+                }
+            }
+            key if key.starts_with(TEMPLATE_PREFIX) => {
+                let name = &key[TEMPLATE_PREFIX.len()..];
+                parser::validate_name(name)?;
+                if value == "none" {
+                    self.templates.remove(name);
+                } else {
+                    self.templates.insert(name.to_owned(), parser::parse_template(value).ok_or_else(invalid)?);
+                }
+            }
+            key if key.starts_with(SCHEDULE_PREFIX) => {
+                let name = &key[SCHEDULE_PREFIX.len()..];
+                parser::validate_name(name)?;
+                if value == "none" {
+                    self.schedules.remove(name);
+                } else {
+                    self.schedules.insert(name.to_owned(), parser::parse_schedule(value).ok_or_else(invalid)?);
+                }
+            }
+            _ => return Err(format!("No runtime setting named `{}`", key))
+        }
+        Ok(())
+    }
+
+    /// the store a connection from `peer` adds to until `USE`, see `--default_store`
+    pub fn default_store_of(&self, peer: &str) -> Option<String> {
+        let name = self.default_store.as_ref()?;
+        // the host of host:port, without the brackets of an IPv6 address
+        let host = match peer.rfind(':') {
+            Some(i) if peer[i + 1..].chars().all(|c| c.is_ascii_digit()) => &peer[..i],
+            _ => peer
+        };
+        let host : String = host.trim_matches(|c| c == '[' || c == ']').chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .collect();
+        Some(name.replace(PEER_PLACEHOLDER, &host))
+    }
+
+    /// Options flushing through a storage backend would skip: a backend
+    /// keeps no flush epochs and writes no mirror or partitions, even
+    /// `--storage uring` which keeps the dtf files.
+    pub fn check(&self) -> Result<(), String> {
+        if self.storage == Storage::DTF {
+            return Ok(());
+        }
+        if self.dtf_folder_mirror.is_some() {
+            return Err("--dtf_folder_mirror is only supported with --storage dtf".to_owned());
+        }
+        if self.max_partition_bytes > 0 {
+            return Err("--max_partition_bytes is only supported with --storage dtf".to_owned());
+        }
+        Ok(())
+    }
+
+    /// whether connections from `ip` are accepted, see `--allow_ips` and `--deny_ips`
+    pub fn permits_ip(&self, ip: IpAddr) -> bool {
+        (self.allow_ips.is_empty() || self.allow_ips.iter().any(|cidr| cidr.contains(ip)))
+            && !self.deny_ips.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    fn config_fname(&self) -> String {
+        utils::join(&self.dtf_folder, "config")
+    }
+
+    /// settings changed with `CONFIG SET`, kept in `[dtf_folder]/config` as `key value` lines
+    fn read_config(&self) -> BTreeMap<String, String> {
+        let mut config = BTreeMap::new();
+        let file = match File::open(self.config_fname()) {
+            Ok(f) => f,
+            Err(_) => return config
+        };
+        for line in BufReader::new(file).lines().filter_map(|line| line.ok()) {
+            let mut parts = line.splitn(2, ' ');
+            if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+                config.insert(key.to_owned(), value.to_owned());
+            }
+        }
+        config
+    }
+
+    /// apply the settings changed with `CONFIG SET` before the last restart,
+    /// they take precedence over the command line
+    pub fn load_config(&mut self) {
+        for (key, value) in self.read_config() {
+            if let Err(e) = self.set(&key, &value) {
+                warn!("Ignoring config: {}", e);
+            }
+        }
+    }
+
+    /// keep the current value of `key` across restarts
+    pub fn persist(&self, key: &str) -> Result<(), io::Error> {
+        let mut config = self.read_config();
+        match self.get(key) {
+            Some(value) => { config.insert(key.to_owned(), value); },
+            // a dropped template or schedule
+            None => { config.remove(key); }
+        }
+        let mut file = File::create(self.config_fname())?;
+        for (key, value) in config {
+            writeln!(file, "{} {}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// DTF: one dtf file per store in dtf_folder
+/// RocksDB: a RocksDB database in dtf_folder/rocksdb, requires the `rocksdb` feature
+/// S3: closed partitions in an S3 bucket, dtf_folder only buffers and caches. Requires the `s3` feature
+/// Uring: the dtf files, read and appended through io_uring. Requires the `uring` feature and Linux, else DTF
+#[derive(Clone, Debug, PartialEq)]
+pub enum Storage {
+    DTF,
+    RocksDB,
+    S3(String),
+    Uring,
+}
+
+/// Settings a store created with `CREATE [db] LIKE [template]` starts with,
+/// copied from another store or named in the config with `--template` or
+/// `CONFIG SET template.[name]`. Written as comma separated options, e.g.
+/// `precision=8,ttl=30d`, or `default` for none:
+///
+/// memory: rows are only kept in memory, like `CREATE [db] MEMORY`.
+/// ttl: rows older than the duration are pruned, like `RETAIN`.
+/// tier: `folder:duration`, older rows are moved to folder, like `TIER`.
+/// precision: decimal places of JSON responses, like `PRECISION`.
+/// multicast: inserted rows are published, like `MULTICAST`.
+/// series: the store holds a `funding` or `open_interest` series, see `series`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Template {
+    pub memory: bool,
+    pub ttl: Option<u64>,
+    pub tier: Option<(String, u64)>,
+    pub precision: Option<usize>,
+    pub multicast: bool,
+    pub series: Option<Kind>,
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut options = Vec::new();
+        if self.memory {
+            options.push("memory".to_owned());
+        }
+        if let Some(ms) = self.ttl {
+            options.push(format!("ttl={}", format_duration(ms)));
+        }
+        if let Some((ref cold_folder, ms)) = self.tier {
+            options.push(format!("tier={}:{}", cold_folder, format_duration(ms)));
+        }
+        if let Some(decimals) = self.precision {
+            options.push(format!("precision={}", decimals));
+        }
+        if self.multicast {
+            options.push("multicast".to_owned());
+        }
+        if let Some(series) = self.series {
+            options.push(format!("series={}", series));
+        }
+        if options.is_empty() {
+            write!(f, "default")
+        } else {
+            write!(f, "{}", options.join(","))
+        }
+    }
+}
+
+/// a duration in ms as `parser::parse_duration` reads it, in its largest whole unit
+fn format_duration(ms: u64) -> String {
+    let units = [("d", 24 * 60 * 60 * 1000), ("h", 60 * 60 * 1000), ("m", 60 * 1000)];
+    match units.iter().find(|&&(_, unit)| ms % unit == 0) {
+        Some(&(suffix, unit)) => format!("{}{}", ms / unit, suffix),
+        None => format!("{}s", ms / 1000)
+    }
+}
+
+/// A job the scheduler plugin starts, named in the config with `--schedule`
+/// or `CONFIG SET schedule.[name]`. Written as the five time fields of a
+/// crontab line, see `job::Cron`, and the command of `JOB [command]`, e.g.
+/// `0 3 * * * COMPACT btc_usdt`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    pub cron: Cron,
+    pub job: job::Kind,
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.cron, self.job)
+    }
+}
+
+/// Off: files are not checked
+/// Fast: the header and batch headers of every file, and its last row
+/// Deep: also every row, and the checksum of the stats sidecar
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IntegrityScan {
+    Off,
+    Fast,
+    Deep,
+}
+
+/// A socket of `--listen`
+///
+/// addr: host:port to bind.
+/// tls: Option<String>. PKCS#12 identity, clients connect with TLS if set.
+/// allow: Allow. commands accepted on the socket.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Listen {
+    pub addr: String,
+    pub tls: Option<String>,
+    pub allow: Allow,
+}
+
+/// commands that only read, e.g. for a listener open to the public
+pub static READ_COMMANDS : &[&str] = &[
+    "PING", "HELP", "HELLO", "INFO", "PERF", "USE", "EXISTS", "EXPLAIN", "COUNT", "GET", "FORMAT",
+    "STRICT", "VALIDATE", "LATEST", "STATS", "EPOCHS", "REPLAY", "DOWNLOAD", "CHECKSUMS", "JOIN",
+    "SUBSCRIBE", "ALERTS", "GROUPS", "CONFIG GET", "TIMEOUT", "HA", "TOPOLOGY", "FIND", "SCHEMA",
+    "SERIES", "APPROX", "TOPN", "HIST", "JOB STATUS", "JOBS",
+];
+
+/// commands a standby accepts besides READ_COMMANDS, see `lease`
+pub static STANDBY_COMMANDS : &[&str] = &["SYNC"];
+
+/// All: every command
+/// Only: the commands starting with one of the keywords, e.g. `GET` or `CONFIG GET`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Allow {
+    All,
+    Only(Vec<String>),
+}
+
+impl Allow {
+    pub fn read_only() -> Allow {
+        Allow::Only(READ_COMMANDS.iter().map(|&keyword| keyword.to_owned()).collect())
+    }
+
+    pub fn standby() -> Allow {
+        Allow::Only(READ_COMMANDS.iter().chain(STANDBY_COMMANDS).map(|&keyword| keyword.to_owned()).collect())
+    }
+
+    pub fn permits(&self, command: &str) -> bool {
+        match *self {
+            Allow::All => true,
+            Allow::Only(ref keywords) => command.is_empty() || keywords.iter().any(|keyword| {
+                command.starts_with(keyword.as_str())
+                    && (command.len() == keyword.len() || command[keyword.len()..].starts_with(' '))
+            })
+        }
+    }
+}
+
+/// A network, e.g. `10.0.0.0/8` or `2001:db8::/32`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (net, ip) = match (self.addr, unmap(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (net.octets().to_vec(), ip.octets().to_vec()),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (net.octets().to_vec(), ip.octets().to_vec()),
+            _ => return false
+        };
+        let bytes = (self.prefix / 8) as usize;
+        let bits = self.prefix % 8;
+        net[..bytes] == ip[..bytes]
+            && (bits == 0 || (net[bytes] ^ ip[bytes]) & (0xff << (8 - bits)) == 0)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// `::ffff:10.0.0.1`, an IPv4 client of an IPv6 socket, is `10.0.0.1`
+fn unmap(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => {
+            let segments = v6.segments();
+            if segments[..5].iter().all(|&s| s == 0) && segments[5] == 0xffff {
+                v6.to_ipv4().map(IpAddr::V4).unwrap_or(ip)
+            } else {
+                ip
+            }
+        }
+        IpAddr::V4(_) => ip
+    }
+}
+
+fn format_cidrs(cidrs: &[Cidr]) -> String {
+    if cidrs.is_empty() {
+        return "none".to_owned();
+    }
+    cidrs.iter().map(|cidr| cidr.to_string()).collect::<Vec<_>>().join(",")
 }
@@ -0,0 +1,164 @@
+/// Slow query log
+///
+/// A command taking longer than `--slowlog_threshold` ms is recorded with
+/// its store, the length of its range, the rows it returned and where the
+/// time went, appended to `--slowlog_file` as JSON Lines and kept among the
+/// last SLOWLOG_LEN entries for `SLOWLOG GET [n]`.
+///
+/// The breakdown covers reads: `lock_wait_ms` is the time waiting for the
+/// shared state, `disk_ms` reading dtf files and `serialize_ms` encoding the
+/// response. The rest of `total_ms` goes elsewhere, e.g. filtering rows.
+
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde_json;
+
+/// entries kept for SLOWLOG GET
+const SLOWLOG_LEN : usize = 128;
+
+/// longest command recorded, longer ones are cut
+const MAX_COMMAND_LEN : usize = 1024;
+
+/// what the current command of a connection did, filled in by the reads
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    pub store: Option<String>,
+    pub range: Option<(u32, u32)>,
+    pub rows: Option<usize>,
+    pub lock_wait: Duration,
+    pub disk: Duration,
+    pub serialize: Duration,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Entry {
+    pub id: u64,
+    /// secs since epoch when the command finished
+    pub ts: u64,
+    pub client: String,
+    pub command: String,
+    pub store: String,
+    /// secs between FROM and TO
+    pub range_secs: Option<u32>,
+    pub rows: Option<usize>,
+    pub total_ms: f64,
+    pub lock_wait_ms: f64,
+    pub disk_ms: f64,
+    pub serialize_ms: f64,
+}
+
+fn as_ms(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000. + f64::from(duration.subsec_nanos()) / 1e6
+}
+
+impl Entry {
+    pub fn new(client: &str, command: &str, store: &str, profile: &Profile, total: Duration) -> Entry {
+        Entry {
+            id: 0,
+            ts: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            client: client.to_owned(),
+            command: command.chars().take(MAX_COMMAND_LEN).collect(),
+            store: profile.store.clone().unwrap_or_else(|| store.to_owned()),
+            range_secs: profile.range.map(|(min, max)| max.saturating_sub(min)),
+            rows: profile.rows,
+            total_ms: as_ms(total),
+            lock_wait_ms: as_ms(profile.lock_wait),
+            disk_ms: as_ms(profile.disk),
+            serialize_ms: as_ms(profile.serialize),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SlowLog {
+    entries: VecDeque<Entry>,
+    next_id: u64,
+}
+
+impl SlowLog {
+    /// keep `entry`, returns it as a JSON line
+    pub fn record(&mut self, mut entry: Entry) -> String {
+        entry.id = self.next_id;
+        self.next_id += 1;
+        let line = serde_json::to_string(&entry).unwrap_or_default();
+        if self.entries.len() == SLOWLOG_LEN {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+        line
+    }
+
+    /// the last `n` entries as a JSON array, newest first
+    pub fn get(&self, n: usize) -> String {
+        let entries : Vec<&Entry> = self.entries.iter().rev().take(n).collect();
+        serde_json::to_string(&entries).unwrap_or_default()
+    }
+
+    pub fn reset(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// append a line of `SlowLog::record` to the slow log file
+pub fn append(fname: &str, line: &str) -> Result<(), io::Error> {
+    let mut file = OpenOptions::new().create(true).append(true).open(fname)?;
+    writeln!(file, "{}", line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use state::tests::{connect, global, run};
+
+    fn entry(command: &str) -> Entry {
+        let profile = Profile {
+            store: Some("btc_usdt".to_owned()),
+            range: Some((1505177459, 1505177519)),
+            rows: Some(42),
+            lock_wait: Duration::from_millis(2),
+            disk: Duration::from_millis(30),
+            serialize: Duration::from_millis(5),
+        };
+        Entry::new("127.0.0.1:4000", command, "default", &profile, Duration::from_millis(40))
+    }
+
+    #[test]
+    fn should_record_the_breakdown_of_slow_commands() {
+        let mut slowlog = SlowLog::default();
+        let line : Value = serde_json::from_str(&slowlog.record(entry("GET ALL FROM 1505177459 TO 1505177519"))).unwrap();
+        assert_eq!(line["id"], 0);
+        assert_eq!(line["store"], "btc_usdt");
+        assert_eq!(line["range_secs"], 60);
+        assert_eq!(line["rows"], 42);
+        assert_eq!(line["total_ms"], 40.);
+        assert_eq!(line["disk_ms"], 30.);
+
+        // only the last SLOWLOG_LEN entries are kept
+        for _ in 0..SLOWLOG_LEN {
+            slowlog.record(entry("GET ALL"));
+        }
+        let entries : Value = serde_json::from_str(&slowlog.get(SLOWLOG_LEN + 1)).unwrap();
+        assert_eq!(entries.as_array().unwrap().len(), SLOWLOG_LEN);
+        assert_eq!(entries[0]["id"], SLOWLOG_LEN as u64);
+        assert_eq!(entries[SLOWLOG_LEN - 1]["id"], 1);
+    }
+
+    #[test]
+    fn should_get_the_newest_entries() {
+        let global = global();
+        let mut state = connect(&global);
+        for command in &["GET 1", "GET 2", "GET 3"] {
+            global.write().unwrap().slowlog.record(entry(command));
+        }
+        let entries : Value = serde_json::from_str(&run(&mut state, "SLOWLOG GET 2")).unwrap();
+        assert_eq!(entries.as_array().unwrap().len(), 2);
+        assert_eq!(entries[0]["command"], "GET 3");
+        assert_eq!(entries[1]["command"], "GET 2");
+
+        run(&mut state, "SLOWLOG RESET");
+        assert_eq!(run(&mut state, "SLOWLOG GET"), "[]\n");
+    }
+}
@@ -1,15 +1,74 @@
 use dtf;
 use dtf::update::Update;
-use std::collections::HashMap;
+use dtf::candle::Candles;
+use dtf::summary::Summary;
+use dtf::asof;
+use dtf::distribution::Distribution;
+use dtf::client::Journal;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::borrow::Cow;
+use std::cmp;
+use std::mem;
+use std::slice;
+use byteorder::{BigEndian, WriteBytesExt};
 use utils;
+use epoch;
+use filestats;
+use zonemap;
+use mirror;
+use direct;
+use partition;
+use tombstone::{self, Tombstone};
+use correction::{self, Corrections};
+use alias::{self, Aliases};
+use group::{self, Groups};
+use tag::{self, Tags};
+use schema;
+use catalog::{self, Catalog};
+use backup;
+use lease::{self, Role};
+use precision::{self, Precisions};
+use flag;
+use series::{self, Kind};
+use derived::{self, Derived, Expr};
+use materialized;
+use approx::{self, Aggregate, FileSample};
+use topn::{By, Ranking};
+use hist;
+use job::{self, Jobs};
+use throttle::Throttle;
+use hello::{self, Hello};
+use extension::Extensions;
+use multicast::Multicast;
+use feed::{self, Feed};
+use cdc::{Cdc, Event};
+use handles::FileHandles;
+use sync::{self, Remote};
+use integrity::{self, Report};
+use latency::{IngestLag, Latencies};
+use slowlog::{Profile, SlowLog};
+use connection::{Connection, CHECK_EVERY};
+use cache::{self, ResultCache};
+use pool::BufferPool;
+use alert::{self, Condition, Rule};
+use backend::{self, StorageBackend};
+use plugins::{retention, tiering};
+use parser::{self, BulkDeclaration, RowFilter};
+use std::io::{self, BufReader, Seek, SeekFrom};
+use std::fs::File;
 use std::path::Path;
-use settings::Settings;
-use std::sync::{Arc, RwLock};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::fs;
+use settings::{self, Allow, Settings, Template};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::cell::{Cell, RefCell};
+use std::thread;
+use std::sync::mpsc;
+use threadpool::ThreadPool;
+use scan;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
 /// name: *should* be the filename
-/// in_memory: are the updates read into memory?
 /// size: true number of items
 /// v: vector of updates
 ///
@@ -31,7 +90,6 @@ use uuid::Uuid;
 pub struct Store {
     pub name: String,
     pub fname: String,
-    pub in_memory: bool,
     pub global: Global
 }
 
@@ -43,34 +101,34 @@ impl Store {
 
     /// push a new `update` into the vec
     pub fn add(&mut self, new_vec: Update) {
-        let is_autoflush = {
+        let (is_autoflush, delay) = {
             let mut wtr = self.global.write().unwrap();
-            let is_autoflush = wtr.settings.autoflush;
-            let flush_interval = wtr.settings.flush_interval;
-            let _folder = wtr.settings.dtf_folder.to_owned();
-            let vecs = wtr.vec_store.get_mut(&self.name).expect("KEY IS NOT IN HASHMAP");
-
-            vecs.0.push(new_vec);
-            vecs.1 += 1;
-
-            // Saves current store into disk after n items is inserted.
-            let size = vecs.0.len(); // using the raw len so won't have race condition with load_size_from_file
-            let is_autoflush = is_autoflush
-                && size != 0
-                && (size as u32) % flush_interval == 0;
-
-            if is_autoflush {
-                debug!("AUTOFLUSHING {}! Size: {} Last: {:?}", self.name, vecs.1, vecs.0.last().clone().unwrap());
-            }
-
-            is_autoflush
+            let is_autoflush = wtr.push(&self.name, new_vec);
+            (is_autoflush, wtr.throttle(&self.name))
         };
+        if let Some(delay) = delay {
+            thread::sleep(delay);
+        }
 
         if is_autoflush {
-            self.flush();
+            self.autoflush();
+        }
+    }
+
+    /// flush now, or leave it to the next group commit, see `--group_commit`
+    fn autoflush(&mut self) {
+        {
+            let mut wtr = self.global.write().unwrap();
+            if wtr.settings.group_commit > 0 {
+                wtr.dirty.insert(self.name.to_owned(), self.fname.to_owned());
+                return;
+            }
         }
+        self.flush();
     }
 
+    /// The rows hidden by a `DELETE` are counted until `compact` removes
+    /// them, so COUNT stays a lookup instead of a scan of the store.
     pub fn count(&self) -> u64 {
         let rdr = self.global.read().unwrap();
         let vecs = rdr.vec_store.get(&self.name).expect("KEY IS NOT IN HASHMAP");
@@ -81,57 +139,250 @@ impl Store {
     /// If file exists, use append which only appends a filtered set of updates whose timestamp is larger than the old timestamp
     /// If file doesn't exists, simply encode.
     ///
+    /// The rows are taken under a short write lock and written to the dtf file
+    /// without holding it, so reads and inserts go on while the file is written.
     pub fn flush(&mut self) -> Option<bool> {
-        {
-            let mut rdr = self.global.write().unwrap(); // use a write lock to block write in client processes
+        let snapshot = {
+            let mut rdr = self.global.write().unwrap();
             let folder = rdr.settings.dtf_folder.to_owned();
-            let vecs = rdr.vec_store.get_mut(&self.name).expect("KEY IS NOT IN HASHMAP");
-            let fullfname = format!("{}/{}.dtf", &folder, self.fname);
+            let fullfname = utils::dtf_fname(&folder, &self.fname);
             utils::create_dir_if_not_exist(&folder);
+            rdr.dirty.remove(&self.name);
 
-            let fpath = Path::new(&fullfname);
-            if fpath.exists() {
-                dtf::append(&fullfname, &vecs.0);
-            } else {
-                dtf::encode(&fullfname, &self.name, &vecs.0);
+            // MEMORY stores never touch the disk, and `flushall` may be writing the file
+            if rdr.ephemeral.contains(&self.name) || rdr.flushing.contains(&self.name) {
+                return Some(false);
             }
 
-            // clear
-            vecs.0.clear();
+            // the last candle of a materialized store keeps changing, so the
+            // (small) file is rewritten and the candles stay in memory
+            if let Some(view) = rdr.materialized.get(&self.name) {
+                let candles = view.candles.to_updates();
+                dtf::encode(&fullfname, &self.name, &candles);
+                let _ = epoch::vacuum(&fullfname);
+                filestats::written(&fullfname, &candles);
+                zonemap::written(&fullfname);
+                rdr.mirror_written(&fullfname);
+                rdr.result_cache.invalidate(&self.name, None);
+                return Some(true);
+            }
+
+            if rdr.backend.is_some() {
+                // a compaction is replacing the rows of the store
+                if rdr.flushing.contains(&self.name) {
+                    return Some(false);
+                }
+                {
+                    let SharedState { ref mut vec_store, ref mut stats, ref backend, ref mut flush_errors, .. } = *rdr;
+                    let vecs = vec_store.get_mut(&self.name).expect("KEY IS NOT IN HASHMAP");
+                    if let Some(ref backend) = *backend {
+                        if let Err(e) = backend.append(&self.name, &vecs.0) {
+                            error!("Unable to flush {}: {}", self.name, e);
+                            *flush_errors += 1;
+                            return Some(false);
+                        }
+                    }
+                    // the rows flushed before are summarized on first use, see `stats`
+                    if let Some(summary) = stats.get_mut(&self.name) {
+                        summary.add(&vecs.0);
+                    }
+                    vecs.0.clear();
+                }
+                *rdr.flush_count.entry(self.name.to_owned()).or_insert(0) += 1;
+                rdr.forget_unflushed(&self.name, usize::max_value());
+                rdr.unsorted.remove(&self.name);
+                rdr.result_cache.invalidate(&self.name, None);
+                // flush epochs are a property of dtf files
+                rdr.watermark_mut(&self.name).flush();
+                return Some(true);
+            }
+
+            self.snapshot(&mut rdr).expect("NOT FLUSHED THROUGH A SNAPSHOT")
+        };
+
+        let written = write_snapshot(&snapshot);
+        Some(flushed(&mut self.global.write().unwrap(), snapshot, written))
+    }
+
+    /// Flush once another connection is done flushing the store, so the rows
+    /// inserted before are in its file on return, see `FLUSH`. A store being
+    /// compacted is flushed when the compaction is done.
+    pub fn flush_synced(&mut self) -> Result<(), String> {
+        loop {
+            if self.flush() != Some(false) {
+                return Ok(());
+            }
+            {
+                let rdr = self.global.read().unwrap();
+                if rdr.compacting.contains_key(&self.name) {
+                    return Err(format!("`{}` is being compacted, try again.", self.name));
+                }
+                if !rdr.flushing.contains(&self.name) {
+                    return Ok(());
+                }
+            }
+            thread::sleep(Duration::from_millis(FLUSH_POLL_MS));
         }
-        // continue clear
-        self.in_memory = false;
-        Some(true)
     }
 
-    /// load items from dtf file
-    fn load(&mut self) {
-        let folder = self.global.read().unwrap().settings.dtf_folder.to_owned();
-        let fname = format!("{}/{}.dtf", &folder, self.name);
-        if Path::new(&fname).exists() && !self.in_memory {
-            // let file_item_count = dtf::read_meta(&fname).nums;
-            // // when we have more items in memory, don't load
-            // if file_item_count < self.count() {
-            //     warn!("There are more items in memory than in file. Cannot load from file.");
-            //     return;
-            // }
-            let mut ups = dtf::decode(&fname, None);
+    /// Take the rows to write to the dtf file without holding the lock.
+    /// None if the store is flushed some other way or is being flushed already.
+    fn snapshot(&self, global: &mut SharedState) -> Option<FlushSnapshot> {
+        if global.backend.is_some()
+            || global.ephemeral.contains(&self.name)
+            || global.materialized.contains_key(&self.name)
+            || global.flushing.contains(&self.name) {
+            return None;
+        }
+        global.flushing.insert(self.name.to_owned());
+        let fname = utils::dtf_fname(&global.settings.dtf_folder, &self.fname);
+        // reads go on with the rows of the file before the flush and those in memory
+        global.flushing_from.insert(self.name.to_owned(), file_len(&fname));
+        let seal = partition::is_full(&fname, global.settings.max_partition_bytes);
+        Some(FlushSnapshot {
+            guard: FlushGuard { global: self.global.clone(), name: self.name.to_owned(), armed: true },
+            name: self.name.to_owned(),
+            fname,
+            rows: global.buffer_pool.copy(&global.vec_store.get(&self.name).expect("KEY IS NOT IN HASHMAP").0),
+            clears: global.clears.get(&self.name).cloned().unwrap_or(0),
+            mirror: global.settings.dtf_folder_mirror.clone(),
+            direct_io: global.settings.direct_io,
+            seal,
+        })
+    }
+
+    /// Read the rows of the files of the store, or of the storage backend,
+    /// into memory, once for every connection, see `SharedState::is_loaded`.
+    /// Left to the next read while a flush writes the file, see `load_locked`.
+    /// false if the rows could not be read.
+    fn load(&self) -> bool {
+        loop {
+            let (flushes, len) = {
+                let rdr = self.global.read().unwrap();
+                if rdr.is_loaded(&self.name) || rdr.flushing_from.contains_key(&self.name) {
+                    return true;
+                }
+                // a flush starting meanwhile writes past `len`
+                (rdr.flushes(&self.name), file_len(&utils::dtf_fname(&rdr.settings.dtf_folder, &self.fname)))
+            };
+            let mut ups = match self.read_files(len) {
+                Ok(ups) => ups,
+                Err(e) => {
+                    error!("Unable to load {}: {}", self.name, e);
+                    return false;
+                }
+            };
             let mut wtr = self.global.write().unwrap();
-            // let size = ups.len() as u64;
-            let vecs = wtr.vec_store.get_mut(&self.name).unwrap();
-            vecs.0.append(&mut ups);
-            // wtr.vec_store.insert(self.name.to_owned(), (ups, size));
-            self.in_memory = true;
+            if wtr.is_loaded(&self.name) {
+                return true;
+            }
+            // a flush got in the way, the files read may miss rows or hold some twice
+            if wtr.flushes(&self.name) != flushes || wtr.flushing_from.contains_key(&self.name) {
+                continue;
+            }
+            {
+                let watermark = wtr.watermark_mut(&self.name);
+                watermark.memory = cmp::max(watermark.memory, watermark.disk);
+            }
+            wtr.result_cache.invalidate(&self.name, None);
+            wtr.record_read(&self.name);
+            if !is_sorted(&ups) || !follows(&wtr.vec_store[&self.name].0, &ups) {
+                wtr.unsorted.insert(self.name.to_owned());
+            }
+            wtr.vec_store.get_mut(&self.name).unwrap().0.append(&mut ups);
+            wtr.loaded.insert(self.name.to_owned(), flushes);
+            return true;
+        }
+    }
+
+    /// Lock the shared state for a read of all the rows of the store. They
+    /// are the rows in memory once loaded, see `load`. While a flush writes
+    /// the file they are also the rows the file had before the flush,
+    /// returned along, as the rows of the flush stay in memory until written.
+    /// `global` is the store's, the lock outlives the borrow of the store.
+    fn load_locked<'a>(&self, global: &'a Global) -> (RwLockReadGuard<'a, SharedState>, Option<Vec<Update>>) {
+        loop {
+            let loaded = self.load();
+            let (flushes, from) = {
+                let rdr = global.read().unwrap();
+                // a store loaded before the flush holds the rows of its file already
+                if rdr.is_loaded(&self.name) {
+                    return (rdr, None);
+                }
+                match rdr.flushing_from.get(&self.name) {
+                    Some(&from) => (rdr.flushes(&self.name), from),
+                    // unreadable files leave the rows in memory
+                    None if !loaded => return (rdr, None),
+                    // a flush ended since `load`
+                    None => continue
+                }
+            };
+            let ups = match self.read_files(from) {
+                Ok(ups) => ups,
+                Err(e) => {
+                    error!("Unable to read {}: {}", self.name, e);
+                    Vec::new()
+                }
+            };
+            let rdr = global.read().unwrap();
+            if rdr.flushes(&self.name) == flushes && rdr.flushing_from.get(&self.name) == Some(&from) {
+                return (rdr, Some(ups));
+            }
         }
     }
 
+    /// The rows of the files of the store, those of the backend if it has
+    /// one. `len` is the length of its dtf file to read, the rest is being
+    /// written by a flush or may be meanwhile.
+    fn read_files(&self, len: u64) -> Result<Vec<Update>, String> {
+        let rdr = self.global.read().unwrap();
+        if let Some(backend) = rdr.backend.clone() {
+            drop(rdr);
+            return backend.read(&self.name, None);
+        }
+        let fname = utils::dtf_fname(&rdr.settings.dtf_folder, &self.fname);
+        // older rows of a tiered store are in its cold file
+        let mut files : Vec<String> = rdr.tiers.get(&self.name)
+            .map(|tier| tiering::cold_fname(&tier.cold_folder, &tier.fname))
+            .into_iter()
+            .collect();
+        files.extend(partition::list(&fname));
+        drop(rdr);
+        let mut ups : Vec<Update> = files.iter()
+            .filter(|fname| Path::new(fname).exists())
+            .flat_map(|fname| dtf::decode(fname, None))
+            .collect();
+        // a new file is not read before it is written, a sealed one was renamed
+        if len > 0 && Path::new(&fname).exists() {
+            ups.extend(dtf::decode_prefix(&fname, len));
+        }
+        Ok(ups)
+    }
+
     /// load size from file
     pub fn load_size_from_file(&mut self) {
         let header_size = {
             let rdr = self.global.read().unwrap();
-            let folder = rdr.settings.dtf_folder.to_owned();
-            let fname = format!("{}/{}.dtf", &folder, self.name);
-            dtf::get_size(&fname)
+            match rdr.backend.clone() {
+                // an object store is listed without holding the shared state
+                Some(backend) => {
+                    drop(rdr);
+                    backend.count(&self.name)
+                },
+                None => {
+                    let folder = rdr.settings.dtf_folder.to_owned();
+                    let fname = utils::dtf_fname(&folder, &self.fname);
+                    let cold_size = match rdr.tiers.get(&self.name) {
+                        Some(tier) => {
+                            let cold_fname = tiering::cold_fname(&tier.cold_folder, &tier.fname);
+                            if Path::new(&cold_fname).exists() { dtf::get_size(&cold_fname) } else { 0 }
+                        },
+                        None => 0
+                    };
+                    let size = if Path::new(&fname).exists() { dtf::get_size(&fname) } else { 0 };
+                    size + partition::count(&fname) + cold_size
+                }
+            }
         };
 
         let mut wtr = self.global.write().unwrap();
@@ -141,19 +392,41 @@ impl Store {
             .1 = header_size;
     }
 
-    /// clear the vector. toggle in_memory. update size
+    /// clear the vector, the rows are read from the files again. update size
     pub fn clear(&mut self) {
+        self.clear_rows(&mut self.global.write().unwrap());
+        self.load_size_from_file();
+    }
+
+    /// drop the rows in memory, under the lock of the caller
+    fn clear_rows(&self, rdr: &mut SharedState) {
         {
-            let mut rdr = self.global.write().unwrap();
-            let vecs = (*rdr).vec_store.get_mut(&self.name).expect("KEY IS NOT IN HASHMAP");
+            let vecs = rdr.vec_store.get_mut(&self.name).expect("KEY IS NOT IN HASHMAP");
             vecs.0.clear();
             // vecs.1 = 0;
         }
-        self.in_memory = false;
-        self.load_size_from_file();
+        rdr.watermark_mut(&self.name).memory = None;
+        rdr.forget_unflushed(&self.name, usize::max_value());
+        rdr.loaded.remove(&self.name);
+        rdr.unsorted.remove(&self.name);
+        *rdr.clears.entry(self.name.to_owned()).or_insert(0) += 1;
+        rdr.result_cache.invalidate(&self.name, None);
     }
 }
 
+/// Encoding of binary responses, negotiated per connection using `FORMAT`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    /// DTF batches, same as the file format
+    DTF,
+    /// fixed-width records readable in place, see `dtf::flat`. Only the
+    /// rows of `GET` and `GET ... FROM ... TO` are flat, pages of `LIMIT`
+    /// and chunks of `STREAM` are DTF batches
+    Flat,
+    /// MessagePack, see `dtf::msgpack`, also the encoding of BULKADD rows
+    Msgpack,
+}
+
 /// Each client gets its own State
 pub struct State {
     /// Is inside a BULKADD operation?
@@ -162,12 +435,42 @@ pub struct State {
     /// Current selected db using `BULKADD INTO [db]`
     pub bulkadd_db: Option<String>,
 
+    /// rows of a `BULKADD ... COUNT n` waiting for DDAKLUB
+    pub bulkadd_pending: Option<PendingBulkAdd>,
+
+    /// (store, row) inserted after `BEGIN`, applied on `COMMIT`
+    pub transaction: Option<Vec<(String, Update)>>,
+
     /// mapping store_name -> Store
     pub store: HashMap<String, Store>,
 
     /// the current STORE client is using
     pub current_store_name: String,
 
+    /// ADD and BULKADD without INTO are refused until `USE`, see `--default_store`
+    pub require_use: bool,
+
+    /// encoding of binary GET responses and BULKADD rows
+    pub encoding: Encoding,
+
+    /// reject malformed rows with a diagnostic, see `STRICT ON`
+    pub strict: bool,
+
+    /// what the current command did, see `slowlog`
+    pub profile: RefCell<Profile>,
+
+    /// this connection in the registry of `CONNECTIONS`
+    pub connection: Arc<Connection>,
+
+    /// ms after which reads are interrupted, see `TIMEOUT`, `--query_timeout` if None
+    pub timeout: Option<u64>,
+
+    /// when the running command times out
+    deadline: Cell<Option<Instant>>,
+
+    /// why the running command was interrupted
+    interruption: Cell<Option<&'static str>>,
+
     /// shared data
     pub global: Global
 }
@@ -191,6 +494,7 @@ impl State {
     /// }
     pub fn info(&self) -> String {
         let rdr = self.global.read().unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
         let info_vec : Vec<String> = rdr.vec_store.iter().map(|i| {
             let (key, value) = i;
             let vecs = &value.0;
@@ -198,10 +502,22 @@ impl State {
             format!(r#"{{
     "name": "{}",
     "in_memory": {},
+    "ephemeral": {},
+    "aliases": [{}],
+    "silent_for": {},
     "count": {}
   }}"#,
                         key,
                         !vecs.is_empty(),
+                        rdr.ephemeral.contains(key),
+                        rdr.aliases.iter()
+                            .filter(|&(_, store)| store == key)
+                            .map(|(alias, _)| format!("\"{}\"", alias))
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        rdr.last_write.get(key)
+                            .map(|&last| now.saturating_sub(last).to_string())
+                            .unwrap_or_else(|| "null".to_owned()),
                         size
                    )
         }).collect();
@@ -214,6 +530,14 @@ impl State {
     "autoflush_enabled": {},
     "autoflush_interval": {},
     "dtf_folder": "{}",
+    "offset": {},
+    "memory_used": {},
+    "memory_budget": {},
+    "open_files": {},
+    "integrity": {},
+    "jobs": {},
+    "result_cache": {},
+    "buffer_pool": {},
     "total_count": {}
   }}"#,
 
@@ -226,6 +550,14 @@ impl State {
                 rdr.settings.autoflush,
                 rdr.settings.flush_interval,
                 rdr.settings.dtf_folder,
+                rdr.offset,
+                rdr.memory_used(),
+                rdr.settings.max_memory,
+                rdr.handles.count(),
+                rdr.integrity.to_json(),
+                rdr.jobs.metrics(),
+                rdr.result_cache.to_json(),
+                rdr.buffer_pool.to_json(),
                 rdr.vec_store.iter().fold(0, |acc, (_name, tup)| acc + tup.1)
             );
         let mut ret = format!(r#"{{
@@ -252,195 +584,4494 @@ impl State {
         format!("[{}]\n", objs.join(", "))
     }
 
-    /// Insert a row into store
-    pub fn insert(&mut self, up: Update, store_name : &str) -> Option<()> {
-        match self.store.get_mut(store_name) {
-            Some(store) => {
-                store.add(up);
-                Some(())
-            }
-            None => None
+    /// Latency histograms of the commands since start or the last reset
+    pub fn perf_latency(&self, reset: bool) -> String {
+        let mut wtr = self.global.write().unwrap();
+        let json = wtr.latencies.to_json();
+        if reset {
+            wtr.latencies.reset();
         }
+        json
     }
 
-    /// Check if a table exists
-    pub fn exists(&mut self, store_name : &str) -> bool {
-        self.store.contains_key(store_name)
+    /// How `GET` reads the current store, see `EXPLAIN`
+    ///
+    /// {"store": "btc", "range": [1505177459, 1505177460], "estimated_rows": 12, "sources": [
+    ///   {"source": "memory", "read": true, "scan": "linear", "rows": 100, "rows_in_range": 12},
+    ///   {"source": "disk", "fname": "db/btc.dtf", "read": false, "index": "batch headers",
+    ///    "batches": 4, "batches_read": 1, "rows": 400, "rows_in_batches_read": 100}]}
+    ///
+    /// GET serves the rows in memory. The files are listed with what a read
+    /// of the range from disk, e.g. by DOWNLOAD or JOIN, decodes: batches
+    /// whose header rules out the range are skipped.
+    pub fn explain_get(&self, count: Option<u32>, range: Option<(u32, u32)>, limit: Option<u32>) -> String {
+        let store_name = &self.current_store_name;
+        let files = self.store_files(store_name);
+        let rdr = self.global.read().unwrap();
+
+        let mut sources = Vec::new();
+        let vecs = &rdr.vec_store.get(store_name).expect("KEY IS NOT IN HASHMAP").0;
+        let amendments = rdr.amendments_of(store_name);
+        let in_range_rows = vecs.iter()
+            .filter(|up| in_range(up, range))
+            .filter(|up| amendments.apply(up).is_some())
+            .count();
+        sources.push(format!(r#"{{"source": "memory", "read": true, "scan": "linear", "rows": {}, "rows_in_range": {}}}"#,
+            vecs.len(), in_range_rows));
+
+        if rdr.backend.is_some() {
+            sources.push(r#"{"source": "backend", "read": false}"#.to_owned());
+        }
+        let (min_ts, max_ts) = match range {
+            Some((min, max)) => (u64::from(min) * 1000, u64::from(max) * 1000),
+            None => (0, u64::max_value())
+        };
+        for fname in files {
+            let batches = dtf::read_batch_ranges(&fname);
+            // the test of `dtf::range`, every row of a batch is within 0xFFFF ms of its ref_ts
+            let read : Vec<&dtf::BatchRange> = batches.iter()
+                .filter(|b| b.min_ts <= max_ts && b.min_ts.saturating_add(0xFFFF) > min_ts)
+                .collect();
+            sources.push(format!(r#"{{"source": "disk", "fname": "{}", "read": false, "index": "{}", "batches": {}, "batches_read": {}, "rows": {}, "rows_in_batches_read": {}}}"#,
+                fname.replace('\\', "\\\\"),
+                if range.is_some() { "batch headers" } else { "none" },
+                batches.len(),
+                read.len(),
+                batches.iter().map(|b| u64::from(b.count)).sum::<u64>(),
+                read.iter().map(|b| u64::from(b.count)).sum::<u64>()));
+        }
+
+        let estimated_rows = [count, limit].iter()
+            .filter_map(|&n| n.map(|n| n as usize))
+            .fold(in_range_rows, cmp::min);
+        let range = match range {
+            Some((min, max)) => format!("[{}, {}]", min, max),
+            None => "null".to_owned()
+        };
+        format!(r#"{{"store": "{}", "range": {}, "estimated_rows": {}, "sources": [{}]}}"#,
+            store_name, range, estimated_rows, sources.join(", "))
     }
 
-    /// Insert a row into current store.
-    pub fn add(&mut self, up: Update) {
-        let current_store = self.get_current_store();
-        current_store.add(up);
+    /// How `COPY` reads `src`, see `EXPLAIN`
+    ///
+    /// {"store": "btc", "range": null, "sources": [
+    ///   {"source": "memory", "read": true, "scan": "linear", "rows": 100, "rows_matching": 3},
+    ///   {"source": "disk", "fname": "db/btc.dtf", "read": true, "index": "zone maps",
+    ///    "batches": 4, "batches_skipped_by_range": 0, "batches_skipped_by_zone_maps": 3,
+    ///    "batches_read": 1, "rows": 400, "rows_in_batches_read": 100}]}
+    ///
+    /// The files of a store loaded with USE are not read, its rows are in memory.
+    pub fn explain_copy(&self, src: &str, range: Option<(u32, u32)>, filter: &RowFilter) -> Result<String, String> {
+        if !self.store.contains_key(src) {
+            return Err(format!("No db named `{}`", src));
+        }
+        let in_memory = self.is_loaded(src);
+        let files = self.store_files(src);
+        let rdr = self.global.read().unwrap();
+
+        let mut sources = Vec::new();
+        let vecs = &rdr.vec_store.get(src).expect("KEY IS NOT IN HASHMAP").0;
+        let amendments = rdr.amendments_of(src);
+        let matching_rows = vecs.iter()
+            .filter(|up| in_range(up, range) && filter.matches(up))
+            .filter(|up| amendments.apply(up).is_some())
+            .count();
+        sources.push(format!(r#"{{"source": "memory", "read": true, "scan": "linear", "rows": {}, "rows_matching": {}}}"#,
+            vecs.len(), matching_rows));
+
+        let (min_ts, max_ts) = match range {
+            Some((min, max)) => (u64::from(min) * 1000, u64::from(max) * 1000),
+            None => (0, u64::max_value())
+        };
+        for fname in files {
+            let zones = zonemap::get(&fname);
+            let in_range : Vec<&dtf::BatchZone> = zones.iter()
+                .filter(|z| z.batch.max_ts >= min_ts && z.batch.min_ts <= max_ts)
+                .collect();
+            let read : Vec<&&dtf::BatchZone> = in_range.iter().filter(|z| filter.may_match(z)).collect();
+            sources.push(format!(r#"{{"source": "disk", "fname": "{}", "read": {}, "index": "{}", "batches": {}, "batches_skipped_by_range": {}, "batches_skipped_by_zone_maps": {}, "batches_read": {}, "rows": {}, "rows_in_batches_read": {}}}"#,
+                fname.replace('\\', "\\\\"),
+                !in_memory,
+                if filter.has_ranges() { "zone maps" } else if range.is_some() { "batch headers" } else { "none" },
+                zones.len(),
+                zones.len() - in_range.len(),
+                in_range.len() - read.len(),
+                read.len(),
+                zones.iter().map(|z| u64::from(z.batch.count)).sum::<u64>(),
+                read.iter().map(|z| u64::from(z.batch.count)).sum::<u64>()));
+        }
+
+        let range = match range {
+            Some((min, max)) => format!("[{}, {}]", min, max),
+            None => "null".to_owned()
+        };
+        Ok(format!(r#"{{"store": "{}", "range": {}, "sources": [{}]}}"#, src, range, sources.join(", ")))
     }
 
+    /// The cached response of a repeated read, or the write generation of
+    /// its store to pass to `cache`
+    pub fn cached(&self, key: &cache::Key) -> Result<cache::Response, u64> {
+        let rdr = self.global.read().unwrap();
+        if rdr.settings.result_cache == 0 {
+            return Err(0);
+        }
+        rdr.result_cache.get(key)
+    }
 
-    /// Create a new store
-    pub fn create(&mut self, store_name: &str) {
-        // insert a vector into shared hashmap
-        {
-            let mut global = self.global.write().unwrap();
-            global.vec_store.insert(store_name.to_owned(), (Vec::new(), 0));
+    /// Keep the response of a read for when it is repeated, unless it was
+    /// interrupted or a write touched the store since `generation`
+    pub fn cache(&self, key: cache::Key, generation: u64, response: cache::Response) {
+        if self.interruption().is_some() {
+            return;
         }
-        // insert a store into client state hashmap
-        self.store.insert(store_name.to_owned(), Store {
-            name: store_name.to_owned(),
-            fname: format!("{}--{}", Uuid::new_v4(), store_name),
-            in_memory: false,
-            global: self.global.clone()
-        });
+        let rdr = self.global.read().unwrap();
+        let capacity = rdr.settings.result_cache as usize;
+        rdr.result_cache.insert(key, response, capacity, generation);
     }
 
-    /// load a datastore file into memory
-    pub fn use_db(&mut self, store_name: &str) -> Option<()> {
-        if self.store.contains_key(store_name) {
-            self.current_store_name = store_name.to_owned();
-            let current_store = self.get_current_store();
-            current_store.load();
-            Some(())
+    /// Mark the start of `command`, which times out after `--query_timeout`
+    /// or the timeout set with `TIMEOUT`
+    pub fn start_command(&mut self, command: &str) {
+        let timeout = self.timeout.unwrap_or(self.global.read().unwrap().settings.query_timeout);
+        self.connection.start(command);
+        self.deadline.set(if timeout == 0 { None } else { Some(Instant::now() + Duration::from_millis(timeout)) });
+        self.interruption.set(None);
+    }
+
+    /// Whether the running command was cancelled or timed out, see `connection`
+    pub fn interrupted(&self) -> bool {
+        if self.interruption.get().is_some() {
+            return true;
+        }
+        let interruption = if self.connection.is_cancelled() {
+            Some("Query cancelled.")
+        } else if self.deadline.get().map_or(false, |deadline| Instant::now() >= deadline) {
+            Some("Query timed out.")
         } else {
             None
-        }
+        };
+        self.interruption.set(interruption);
+        interruption.is_some()
     }
 
-    /// return the count of the current store
-    pub fn count(&mut self) -> u64 {
-        let store = self.get_current_store();
-        store.count() 
+    /// why the running command was interrupted, if it was
+    pub fn interruption(&self) -> Option<&'static str> {
+        self.interruption.get()
     }
 
-    /// Returns the total count of every item in memory
-    pub fn countall(&self) -> u64 {
+    /// The open connections and what they run, as JSON
+    pub fn connections(&self) -> String {
         let rdr = self.global.read().unwrap();
-        rdr.vec_store.iter().fold(0, |acc, (_name, tup)| acc + tup.1)
+        let connections : Vec<String> = rdr.connections.values().map(|c| c.to_json()).collect();
+        format!("[{}]", connections.join(", "))
     }
 
-    /// remove everything in the current store
-    pub fn clear(&mut self) {
-        self.get_current_store().clear();
+    /// Interrupt the command running on connection `id`
+    pub fn cancel(&self, id: u64) -> Result<(), String> {
+        let rdr = self.global.read().unwrap();
+        match rdr.connections.get(&id) {
+            Some(connection) if connection.cancel() => Ok(()),
+            Some(_) => Err(format!("No command is running on connection {}.", id)),
+            None => Err(format!("No connection {}.", id))
+        }
     }
 
-    /// remove everything in every store
-    pub fn clearall(&mut self) {
-        for store in self.store.values_mut() {
-            store.clear();
-        }
+    /// `JOB [command]`, `FLUSH ALL ASYNC` or `CLEAR ALL ASYNC`, runs in a
+    /// thread of its own once fewer than `--max_jobs` jobs run, see `job`.
+    /// Returns the id of the job.
+    pub fn start_job(&mut self, kind: job::Kind) -> u64 {
+        let (job, slots, names) = {
+            let mut wtr = self.global.write().unwrap();
+            let mut names : Vec<String> = if kind.is_chunked() { wtr.vec_store.keys().cloned().collect() } else { Vec::new() };
+            names.sort();
+            let total = if kind.is_chunked() { names.len() } else { 1 };
+            (wtr.jobs.start(kind, total), wtr.jobs.slots.clone(), names)
+        };
+        let id = job.id;
+        let global = self.global.clone();
+        thread::spawn(move || {
+            let max_jobs = || global.read().unwrap().settings.max_jobs;
+            let result = if slots.acquire(&job, max_jobs) {
+                job.begin();
+                let result = run_job(&global, &job, &names);
+                slots.release();
+                result
+            } else {
+                Ok(String::new())
+            };
+            if let Err(ref e) = result {
+                error!("Job {} ({}) failed: {}", job.id, job.kind, e);
+            }
+            job.finish(result);
+            global.write().unwrap().jobs.finished(&job);
+            info!("Job {} finished: {}", job.id, job.to_json());
+        });
+        id
     }
 
-    /// save current store to file
-    pub fn flush(&mut self) {
-        self.get_current_store().flush();
+    /// `JOBS`
+    pub fn jobs(&self) -> String {
+        self.global.read().unwrap().jobs.to_json()
     }
 
-    /// save all stores to corresponding files
-    pub fn flushall(&mut self) {
-        for store in self.store.values_mut() {
-            store.flush();
+    /// `JOB STATUS [id]`
+    pub fn job_status(&self, id: u64) -> Result<String, String> {
+        match self.global.read().unwrap().jobs.get(id) {
+            Some(job) => Ok(job.to_json()),
+            None => Err(format!("No job {}.", id))
         }
     }
 
-    /// returns the current store as a mutable reference
-    fn get_current_store(&mut self) -> &mut Store {
-        self.store.get_mut(&self.current_store_name).expect("KEY IS NOT IN HASHMAP")
+    /// `JOB CANCEL [id]`
+    pub fn job_cancel(&self, id: u64) -> Result<(), String> {
+        match self.global.read().unwrap().jobs.get(id) {
+            Some(job) => job.cancel(),
+            None => Err(format!("No job {}.", id))
+        }
     }
 
-    /// get n items in memory as JSON
-    pub fn get_n_as_json(&mut self, count: Option<u32>) -> Option<String> {
-        match self.get_aux(count) {
-            Some(vecs) => Some(format!("[{}]\n", dtf::update_vec_to_json(&vecs))),
-            None => None
-        }
+    /// The last `n` entries of the slow log, newest first
+    pub fn slowlog_get(&self, n: usize) -> String {
+        self.global.read().unwrap().slowlog.get(n)
     }
 
-    fn get_aux(&mut self, count: Option<u32>) -> Option<Vec<Update>> {
-        let shared_state = self.global.read().unwrap();
-        let &(ref vecs, ref size) = 
-            shared_state.vec_store
-                    .get(&self.current_store_name)
-                    .expect("Key is not in vec_store");
-        match count {
-            Some(count) => {
-                if (*size as u32) < count || *size == 0 {
-                    return None
-                }
-                Some(vecs[..count as usize].to_vec())
-            },
-            None => Some(vecs.clone()) // XXX: very inefficient, ok with small n
+    pub fn slowlog_reset(&self) {
+        self.global.write().unwrap().slowlog.reset();
+    }
+
+    /// Insert a row into store
+    pub fn insert(&mut self, up: Update, store_name : &str) -> Option<()> {
+        match self.store.get_mut(store_name) {
+            Some(store) => store.add(up),
+            None => return None
         }
+        self.relieve_memory();
+        Some(())
     }
 
-    /// get `count` items from the current store
-    pub fn get(&mut self, count: Option<u32>) -> Option<Vec<u8>> {
-        let mut bytes : Vec<u8> = Vec::new();
-        match self.get_aux(count) {
-            Some(vecs) => { dtf::write_batches(&mut bytes, &vecs); Some(bytes) },
-            None => None
+    /// Returns the newest timestamp and seq of a store as JSON
+    ///
+    /// {"memory": {"ts": 1505177459.658, "seq": 139010}, "disk": null}
+    pub fn latest(&self, store_name: &str) -> Option<String> {
+        if !self.store.contains_key(store_name) {
+            return None;
         }
+        let rdr = self.global.read().unwrap();
+        let watermark = rdr.watermarks.get(store_name).cloned().unwrap_or_default();
+        let to_json = |w: Option<(u64, u32)>| match w {
+            Some((ts, seq)) => format!(r#"{{"ts": {}, "seq": {}}}"#, (ts as f64) / 1000_f64, seq),
+            None => "null".to_owned()
+        };
+        Some(format!(r#"{{"memory": {}, "disk": {}}}"#, to_json(watermark.memory), to_json(watermark.disk)) + "\n")
     }
 
-    /// create a new store
-    pub fn new(global: &Global) -> State {
-        let dtf_folder: &str = &global.read().unwrap().settings.dtf_folder;
-        let mut state = State {
-            current_store_name: "default".to_owned(),
-            bulkadd_db: None,
-            is_adding: false,
-            store: HashMap::new(),
-            global: global.clone()
+    /// Returns statistics of a store as JSON.
+    ///
+    /// Statistics of the flushed rows are cached and updated on flush; rows
+    /// still in memory are added on the fly. A file flushed before the server
+    /// started is summarized from its stats sidecar on first use, the rows of
+    /// a storage backend from the backend.
+    pub fn stats(&mut self, store_name: &str) -> Option<String> {
+        let fname = self.store_fname(store_name)?;
+        let file_exists = Path::new(&fname).exists();
+
+        let (is_cached, backend) = {
+            let rdr = self.global.read().unwrap();
+            (rdr.stats.contains_key(store_name), rdr.backend.clone())
+        };
+        if !is_cached {
+            let summary = match backend {
+                Some(backend) => match backend.summary(store_name) {
+                    Ok(summary) => Some(summary),
+                    Err(e) => {
+                        error!("Unable to summarize {}: {}", store_name, e);
+                        None
+                    }
+                },
+                None if file_exists => {
+                    let mut summary = Summary::new();
+                    for partition in partition::list(&fname) {
+                        summary.merge(&filestats::get(&partition).summary);
+                    }
+                    summary.merge(&filestats::get(&fname).summary);
+                    Some(summary)
+                },
+                None => None
+            };
+            if let Some(summary) = summary {
+                self.global.write().unwrap().stats.insert(store_name.to_owned(), summary);
+            }
+        }
+
+        let rdr = self.global.read().unwrap();
+        let disk = rdr.stats.get(store_name).cloned().unwrap_or_default();
+        let is_materialized = rdr.materialized.contains_key(store_name);
+        // a loaded store holds the rows of its files in memory too
+        let mut summary = if rdr.is_loaded(store_name) && !is_materialized { Summary::new() } else { disk.clone() };
+        if !is_materialized {
+            summary.add(&rdr.vec_store.get(store_name).expect("KEY IS NOT IN HASHMAP").0);
+        }
+
+        let file_size = partition::list(&fname).iter().chain(Some(&fname))
+            .filter_map(|fname| fs::metadata(fname).ok())
+            .map(|m| m.len())
+            .sum::<u64>();
+        // relative to fixed-width uncompressed records
+        let compression_ratio = if file_size == 0 {
+            "null".to_owned()
+        } else {
+            format!("{}", (disk.count * dtf::FLAT_RECORD_LEN as u64) as f64 / file_size as f64)
+        };
+        let checksum = match filestats::read(&fname) {
+            Some(stats) => format!(r#""{:08x}""#, stats.checksum),
+            None => "null".to_owned()
         };
 
-        // insert default first, if there is a copy in memory this will be replaced
-        let default_file = format!("{}/default.dtf", dtf_folder);
-        let default_in_memory = !Path::new(&default_file).exists();
-        state.store.insert("default".to_owned(), Store {
-            name: "default".to_owned(),
-            fname: format!("{}--default", Uuid::new_v4()),
-            in_memory: default_in_memory,
-            global: global.clone()
-        });
+        let throttle = rdr.compacting.get(store_name).map_or("null".to_owned(), |throttle| throttle.to_json());
 
-        let rdr = global.read().unwrap();
-        for (store_name, _vec) in &rdr.vec_store {
-            let fname = format!("{}/{}.dtf", dtf_folder, store_name);
-            let in_memory = !Path::new(&fname).exists();
-            state.store.insert(store_name.to_owned(), Store {
-                name: store_name.to_owned(),
-                fname: format!("{}--{}", Uuid::new_v4(), store_name),
-                in_memory: in_memory,
-                global: global.clone()
-            });
+        Some(format!(r#"{{"name": "{}", "file_size": {}, "compression_ratio": {}, "checksum": {}, "throttle": {}, "stats": {}}}"#,
+            store_name, file_size, compression_ratio, checksum, throttle, summary.to_json()) + "\n")
+    }
+
+    /// path of the dtf file backing a store
+    fn store_fname(&self, store_name: &str) -> Option<String> {
+        let store = self.store.get(store_name)?;
+        let folder = self.global.read().unwrap().settings.dtf_folder.to_owned();
+        Some(utils::dtf_fname(&folder, &store.fname))
+    }
+
+    /// existing dtf files of a store, oldest rows first: the cold file of
+    /// a tiered store, its sealed partitions, then the file in dtf_folder
+    fn store_files(&self, store_name: &str) -> Vec<String> {
+        let fname = match self.store_fname(store_name) {
+            Some(fname) => fname,
+            None => return Vec::new()
+        };
+        let mut files = Vec::new();
+        if let Some(tier) = self.global.read().unwrap().tiers.get(store_name) {
+            files.push(tiering::cold_fname(&tier.cold_folder, &tier.fname));
         }
-        state
+        files.extend(partition::list(&fname));
+        files.push(fname);
+        files.into_iter().filter(|fname| Path::new(fname).exists()).collect()
     }
-}
 
-/// (updates, count)
-pub type VecStore = (Vec<Update>, u64);
+    /// get `count` items of the current store as it was at flush `epoch`
+    pub fn get_asof(&mut self, count: Option<u32>, range: Option<(u32, u32)>, asof: u32) -> Result<Vec<Update>, String> {
+        let store_name = self.current_store_name.clone();
+        let fname = self.store_fname(&store_name).expect("KEY IS NOT IN HASHMAP");
+        if self.global.read().unwrap().backend.is_some() {
+            return Err(format!("`{}` is kept in a storage backend, which keeps no flush epochs.", store_name));
+        }
+        if !Path::new(&fname).exists() {
+            return Err(format!("`{}` has not been flushed.", store_name));
+        }
+        let tombstones = tombstone::read(&fname);
+        let corrections = correction::read(&fname);
+        let amendments = Amendments { tombstones: &tombstones, corrections: Some(&corrections) };
+        match epoch::read_asof(&fname, asof) {
+            Some(ups) => {
+                let filtered = ups.iter()
+                    .filter(|up| in_range(up, range))
+                    .filter_map(|up| amendments.apply(up));
+                Ok(match count {
+                    Some(count) => filtered.take(count as usize).collect(),
+                    None => filtered.collect()
+                })
+            },
+            None => Err(format!("No epoch {} in `{}`.", asof, store_name))
+        }
+    }
 
-/// key: btc_neo
-///      btc_eth
-///      ..
-///      total
-pub type History = HashMap<String, Vec<(SystemTime, u64)>>;
+    /// Returns the flush epochs of a store as JSON
+    pub fn epochs(&self, store_name: &str) -> Option<String> {
+        let fname = self.store_fname(store_name)?;
+        let epochs : Vec<String> = epoch::read(&fname).iter().map(|e| e.to_json()).collect();
+        Some(format!("[{}]\n", epochs.join(", ")))
+    }
 
+    /// forget the flush epochs of a store
+    pub fn vacuum(&self, store_name: &str) -> Option<Result<(), String>> {
+        let fname = self.store_fname(store_name)?;
+        self.global.write().unwrap().result_cache.invalidate(store_name, None);
+        Some(epoch::vacuum(&fname).map_err(|e| format!("{:?}", e)))
+    }
 
-#[derive(Debug)]
-pub struct SharedState {
-    pub n_cxns: u16,
-    pub settings: Settings,
-    pub vec_store: HashMap<String, VecStore>,
-    pub history: History,
-}
+    /// Hide the rows of a store within [min_ts, max_ts] (in ms) from reads.
+    /// The rows are removed from disk by `compact`.
+    pub fn delete(&mut self, store_name: &str, min_ts: u64, max_ts: u64) -> Result<(), String> {
+        let fname = match self.store_fname(store_name) {
+            Some(fname) => fname,
+            None => return Err(format!("No db named `{}`", store_name))
+        };
+        // under the lock, so a compaction rewriting the tombstones keeps it
+        let mut wtr = self.global.write().unwrap();
+        // rows newer than the store are kept. A store without rows has none
+        // to hide, though the newest row of a backend store is unknown after a restart
+        let newest = {
+            let watermark = wtr.watermark_mut(store_name);
+            cmp::max(watermark.memory, watermark.disk)
+        };
+        let tombstone = match newest {
+            Some(newest) => Tombstone { newest, ..Tombstone::new(min_ts, max_ts) },
+            None if wtr.backend.is_none() => Tombstone { newest: (0, 0), ..Tombstone::new(min_ts, max_ts) },
+            None => Tombstone::new(min_ts, max_ts)
+        };
+        tombstone::record(&fname, tombstone).map_err(|e| format!("{:?}", e))?;
+        wtr.tombstones.entry(store_name.to_owned())
+            .or_insert_with(Vec::new)
+            .push(tombstone);
+        wtr.result_cache.invalidate(store_name, Some((min_ts, max_ts)));
+        wtr.cdc.push(&Event::Delete { store: store_name.to_owned(), from: min_ts, to: max_ts });
+        Ok(())
+    }
 
-impl SharedState {
-    pub fn new(settings: Settings) -> SharedState {
-        let mut hashmap = HashMap::new();
-        hashmap.insert("default".to_owned(), (Vec::new(),0) );
-        SharedState {
-            n_cxns: 0,
-            settings,
-            vec_store: hashmap,
-            history: HashMap::new(),
-        }
+    /// Supersede the row of the same (ts, seq) in a store.
+    /// The file is rewritten with the correction by `compact`.
+    pub fn correct(&mut self, store_name: &str, up: Update) -> Result<(), String> {
+        let fname = match self.store_fname(store_name) {
+            Some(fname) => fname,
+            None => return Err(format!("No db named `{}`", store_name))
+        };
+        let mut wtr = self.global.write().unwrap();
+        correction::record(&fname, &up).map_err(|e| format!("{:?}", e))?;
+        wtr.result_cache.invalidate(store_name, Some((up.ts, up.ts)));
+        wtr.corrections.entry(store_name.to_owned())
+            .or_insert_with(HashMap::new)
+            .insert((up.ts, up.seq), up);
+        Ok(())
+    }
+
+    /// Rewrite the file of a store without its deleted rows and with its
+    /// corrections applied, or its rows in the storage backend, and do the
+    /// same in memory. Inserts into the store
+    /// go on meanwhile, throttled, see `throttle`.
+    /// Returns the number of rows removed.
+    pub fn compact(&mut self, store_name: &str) -> Result<u64, String> {
+        let fname = match self.store_fname(store_name) {
+            Some(fname) => fname,
+            None => return Err(format!("No db named `{}`", store_name))
+        };
+
+        let (tombstones, corrections, files, direct_io, backend) = {
+            let mut wtr = self.global.write().unwrap();
+            if wtr.compacting.contains_key(store_name) {
+                return Err(format!("`{}` is being compacted.", store_name));
+            }
+            if wtr.flushing.contains(store_name) {
+                return Err(format!("`{}` is being flushed, try again.", store_name));
+            }
+            // reads still apply them until the files are rewritten
+            let tombstones = wtr.tombstones.get(store_name).cloned().unwrap_or_default();
+            let corrections = wtr.corrections.get(store_name).cloned().unwrap_or_default();
+            if tombstones.is_empty() && corrections.is_empty() {
+                return Ok(0);
+            }
+            let backend = wtr.backend.clone();
+            let mut files = partition::list(&fname);
+            files.push(fname.clone());
+            files.retain(|file| backend.is_none() && Path::new(file).exists());
+            // flushes, retention and tiering stay off the files, inserts are throttled, see `throttle`
+            wtr.flushing.insert(store_name.to_owned());
+            let rewrites = if backend.is_some() { 1 } else { files.len() };
+            wtr.compacting.insert(store_name.to_owned(), Throttle::new(rewrites));
+            (tombstones, corrections, files, wtr.settings.direct_io, backend)
+        };
+        let amendments = Amendments { tombstones: &tombstones, corrections: Some(&corrections) };
+
+        let rewritten = match backend {
+            Some(ref backend) => self.rewrite_backend(store_name, &**backend, &amendments),
+            None => self.rewrite_files(store_name, &fname, &files, &amendments, direct_io)
+        };
+
+        let (removed, is_autoflush) = {
+            let mut wtr = self.global.write().unwrap();
+            wtr.flushing.remove(store_name);
+            wtr.compacting.remove(store_name);
+            let mut removed = rewritten?;
+            if wtr.backend.is_some() {
+                wtr.stats.remove(store_name);
+            } else if Path::new(&fname).exists() {
+                wtr.stats.remove(store_name);
+                wtr.watermark_mut(store_name).disk = dtf::read_newest(&fname).or_else(|| partition::newest(&fname));
+            }
+
+            {
+                // the rows of a loaded store in memory include those of its files
+                let is_loaded = wtr.is_loaded(store_name);
+                let vecs = wtr.vec_store.get_mut(store_name).expect("KEY IS NOT IN HASHMAP");
+                let before = vecs.0.len();
+                vecs.0 = vecs.0.iter().filter_map(|up| amendments.apply(up)).collect();
+                let in_memory = (before - vecs.0.len()) as u64;
+                removed = if is_loaded { in_memory } else { removed + in_memory };
+                vecs.1 = vecs.1.saturating_sub(removed);
+            }
+            wtr.result_cache.invalidate(store_name, None);
+
+            // deletes and corrections received meanwhile are kept for the next compaction
+            let kept_tombstones : Vec<Tombstone> = wtr.tombstones.remove(store_name).unwrap_or_default()
+                .into_iter()
+                .filter(|tombstone| !tombstones.contains(tombstone))
+                .collect();
+            let kept_corrections : Corrections = wtr.corrections.remove(store_name).unwrap_or_default()
+                .into_iter()
+                .filter(|&(ref key, ref up)| corrections.get(key) != Some(up))
+                .collect();
+            tombstone::clear(&fname).map_err(|e| format!("{:?}", e))?;
+            correction::clear(&fname).map_err(|e| format!("{:?}", e))?;
+            for &tombstone in &kept_tombstones {
+                tombstone::record(&fname, tombstone).map_err(|e| format!("{:?}", e))?;
+            }
+            for up in kept_corrections.values() {
+                correction::record(&fname, up).map_err(|e| format!("{:?}", e))?;
+            }
+            if !kept_tombstones.is_empty() {
+                wtr.tombstones.insert(store_name.to_owned(), kept_tombstones);
+            }
+            if !kept_corrections.is_empty() {
+                wtr.corrections.insert(store_name.to_owned(), kept_corrections);
+            }
+            wtr.cdc.push(&Event::Compact { store: store_name.to_owned(), removed });
+            // the rows inserted meanwhile were not flushed
+            let is_autoflush = wtr.settings.autoflush && wtr.unflushed.get(store_name).map_or(false, |&rows| rows > 0);
+            (removed, is_autoflush)
+        };
+        if is_autoflush {
+            if let Some(store) = self.store.get_mut(store_name) {
+                store.autoflush();
+            }
+        }
+        Ok(removed)
+    }
+
+    /// replace the rows of a store in the storage backend with those not
+    /// deleted, corrected. Returns the rows removed.
+    fn rewrite_backend(&self, store_name: &str, backend: &StorageBackend, amendments: &Amendments) -> Result<u64, String> {
+        let ups = backend.read(store_name, None)?;
+        let kept : Vec<Update> = ups.iter()
+            .filter_map(|up| amendments.apply(up))
+            .collect();
+        backend.replace(store_name, &kept)?;
+
+        let mut wtr = self.global.write().unwrap();
+        wtr.result_cache.invalidate(store_name, None);
+        if let Some(throttle) = wtr.compacting.get_mut(store_name) {
+            throttle.files_done += 1;
+        }
+        Ok((ups.len() - kept.len()) as u64)
+    }
+
+    /// rewrite the files of a store without the deleted rows and with the
+    /// corrected ones, holding the shared state only to swap each file in.
+    /// Returns the rows removed.
+    fn rewrite_files(&self, store_name: &str, fname: &str, files: &[String], amendments: &Amendments,
+                     direct_io: u64) -> Result<u64, String> {
+        let mut removed = 0;
+        for file in files {
+            let ups = dtf::decode(file, None);
+            let kept : Vec<Update> = ups.iter()
+                .filter_map(|up| amendments.apply(up))
+                .collect();
+            removed += (ups.len() - kept.len()) as u64;
+
+            let tmp = format!("{}.compact", file);
+            if let Err(e) = direct::encode(&tmp, store_name, &kept, direct_io) {
+                let _ = fs::remove_file(&tmp);
+                return Err(format!("{:?}", e));
+            }
+            filestats::written(&tmp, &kept);
+            zonemap::written(&tmp);
+
+            let mut wtr = self.global.write().unwrap();
+            utils::replace_file(&tmp, file).map_err(|e| format!("{:?}", e))?;
+            for sidecar in partition::SIDECARS {
+                let from = format!("{}.{}", tmp, sidecar);
+                if Path::new(&from).exists() {
+                    utils::replace_file(&from, &format!("{}.{}", file, sidecar)).map_err(|e| format!("{:?}", e))?;
+                }
+            }
+            // the file was rewritten
+            let _ = epoch::vacuum(file);
+            if file == fname {
+                wtr.mirror_written(file);
+            } else {
+                wtr.mirror_partition_written(file);
+            }
+            direct::evict(file, 0, kept.len(), direct_io);
+            wtr.result_cache.invalidate(store_name, None);
+            if let Some(throttle) = wtr.compacting.get_mut(store_name) {
+                throttle.files_done += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Merge the rows of an uploaded dtf file into the file of a store,
+    /// creating it if needed. The rows of the store still in memory are
+    /// written with them, sorted by (ts, seq). Rows the store already has are
+    /// skipped, so uploading the same file twice adds nothing.
+    /// Returns the number of rows added.
+    pub fn upload(&mut self, store_name: &str, file: &[u8]) -> Result<u64, String> {
+        let ups = dtf::decode_buffer(file).map_err(|e| format!("Invalid dtf file: {}", e))?;
+        if !self.exists(store_name) {
+            self.create(store_name);
+        }
+        let fname = self.store_fname(store_name).expect("KEY IS NOT IN HASHMAP");
+
+        let mut wtr = self.global.write().unwrap(); // block writes while merging
+        if wtr.backend.is_some() || wtr.ephemeral.contains(store_name) || wtr.materialized.contains_key(store_name) {
+            return Err(format!("`{}` is not kept in a dtf file, use BULKADD.", store_name));
+        }
+        if wtr.flushing.contains(store_name) {
+            return Err(format!("`{}` is being flushed, try again.", store_name));
+        }
+
+        let mut rows = if Path::new(&fname).exists() { dtf::decode(&fname, None) } else { Vec::new() };
+        // a loaded store holds the rows of its file too, they are dropped as duplicates
+        rows.extend(wtr.vec_store.get(store_name).expect("KEY IS NOT IN HASHMAP").0.iter().cloned());
+        let mut merged = sorted_unique(rows);
+        let before = merged.len();
+        merged.extend(ups);
+        let merged = sorted_unique(merged);
+        let added = (merged.len() - before) as u64;
+
+        let tmp = format!("{}.upload", fname);
+        dtf::encode(&tmp, store_name, &merged);
+        utils::replace_file(&tmp, &fname).map_err(|e| format!("{:?}", e))?;
+
+        // the file was rewritten
+        let _ = epoch::vacuum(&fname);
+        filestats::written(&fname, &merged);
+        zonemap::written(&fname);
+        wtr.mirror_written(&fname);
+        wtr.stats.remove(store_name);
+        {
+            let vecs = wtr.vec_store.get_mut(store_name).expect("KEY IS NOT IN HASHMAP");
+            vecs.0.clear();
+            vecs.1 += added;
+        }
+        wtr.forget_unflushed(store_name, usize::max_value());
+        wtr.loaded.remove(store_name);
+        wtr.unsorted.remove(store_name);
+        wtr.result_cache.invalidate(store_name, None);
+        let newest = merged.last().map(|up| (up.ts, up.seq));
+        wtr.watermark_mut(store_name).flush_upto(newest, false);
+        wtr.cdc.push(&Event::Upload { store: store_name.to_owned(), added });
+        drop(wtr);
+
+        Ok(added)
+    }
+
+    /// Pull the rows missing here of the stores of another server matching
+    /// `pattern`, see `sync`. Returns the rows added to each store as JSON.
+    pub fn sync(&mut self, addr: &str, pattern: &str, since: Option<u32>, verify: bool) -> Result<String, String> {
+        let mut remote = Remote::connect(addr)?;
+        let mut added = Vec::new();
+        for remote_name in remote.stores()? {
+            if !parser::matches_pattern(pattern, &remote_name) {
+                continue;
+            }
+            let remote_newest = match remote.newest(&remote_name)? {
+                Some(ts) => ts,
+                None => continue
+            };
+            let store_name = self.new_store_name(&remote_name)?;
+            if verify {
+                let n = self.sync_divergent(&mut remote, &remote_name, &store_name, since.unwrap_or(0))?;
+                if n > 0 {
+                    info!("Synced {} rows of `{}` from {} after verifying", n, remote_name, addr);
+                }
+                added.push(format!(r#""{}": {}"#, store_name, n));
+                continue;
+            }
+            let local_newest = {
+                let rdr = self.global.read().unwrap();
+                rdr.watermarks.get(&store_name)
+                    .and_then(|watermark| cmp::max(watermark.memory, watermark.disk))
+                    .map(|(ts, _)| ts)
+            };
+            if local_newest.map_or(false, |ts| ts >= remote_newest) {
+                continue;
+            }
+            // rows of the second of the local watermark are merged as duplicates
+            let from = cmp::max(since.unwrap_or(0), local_newest.map_or(0, |ts| (ts / 1000) as u32));
+            let to = (remote_newest / 1000) as u32 + 1;
+            if from > to {
+                continue;
+            }
+            let file = remote.download(&remote_name, (from, to))?;
+            let n = self.upload(&store_name, &file)?;
+            info!("Synced {} rows of `{}` from {}", n, remote_name, addr);
+            added.push(format!(r#""{}": {}"#, store_name, n));
+        }
+        Ok(format!("{{{}}}\n", added.join(", ")))
+    }
+
+    /// Fetch the hours of a remote store whose checksums differ from the
+    /// local ones, from `since` in seconds. Returns the rows added.
+    fn sync_divergent(&mut self, remote: &mut Remote, remote_name: &str, store_name: &str, since: u32) -> Result<u64, String> {
+        let range = (since, u32::max_value());
+        let local = if self.exists(store_name) {
+            sync::checksums(&sorted_unique(self.read_store(store_name, Some(range))?))
+        } else {
+            Vec::new()
+        };
+        let mut added = 0;
+        for range in sync::divergent(&remote.checksums(remote_name, range)?, &local) {
+            let file = remote.download(remote_name, range)?;
+            added += self.upload(store_name, &file)?;
+        }
+        Ok(added)
+    }
+
+    /// Checksums of the rows of a store within `range` (in seconds) by hour,
+    /// as a JSON array, see `sync`
+    pub fn checksums(&self, store_name: &str, range: Option<(u32, u32)>) -> Result<String, String> {
+        let ups = sorted_unique(self.read_store(store_name, range)?);
+        let json : Vec<String> = sync::checksums(&ups).iter().map(|checksum| checksum.to_json()).collect();
+        Ok(format!("[{}]\n", json.join(",")))
+    }
+
+    /// Copy the rows of `src` within `range` (in seconds) matching `filter`
+    /// into `dst`, creating it if needed. The file of `src` is read one batch
+    /// at a time, skipping the batches its zone maps rule out, and rows go
+    /// through the regular insert path of `dst`.
+    /// Returns the number of rows copied.
+    pub fn copy(&mut self, src: &str, dst: &str, range: Option<(u32, u32)>, filter: &RowFilter) -> Result<u64, String> {
+        if src == dst {
+            return Err("Cannot COPY a db into itself.".to_owned());
+        }
+        if !self.store.contains_key(src) {
+            return Err(format!("No db named `{}`", src));
+        }
+        let in_memory = self.is_loaded(src);
+        if !self.exists(dst) {
+            self.create(dst);
+        }
+
+        let mut copied = 0;
+        let select = |global: &SharedState, ups: &[Update]| -> Vec<Update> {
+            let amendments = global.amendments_of(src);
+            ups.iter()
+                .filter(|up| in_range(up, range) && filter.matches(up))
+                .filter_map(|up| amendments.apply(up))
+                .collect()
+        };
+
+        // a loaded store already holds the rows of its files
+        let files = if in_memory { Vec::new() } else { self.store_files(src) };
+        for fname in files {
+            let (min_ts, max_ts) = match range {
+                Some((min, max)) => (u64::from(min) * 1000, u64::from(max) * 1000),
+                None => (0, u64::max_value())
+            };
+            let file = File::open(&fname).map_err(|e| format!("{:?}", e))?;
+            let mut rdr = BufReader::new(file);
+            for zone in zonemap::get(&fname) {
+                let batch = &zone.batch;
+                if batch.max_ts < min_ts || batch.min_ts > max_ts || !filter.may_match(&zone) {
+                    continue;
+                }
+                let ups = {
+                    let rdr_global = self.global.read().unwrap();
+                    rdr.seek(SeekFrom::Start(batch.offset)).map_err(|e| format!("{:?}", e))?;
+                    select(&rdr_global, &dtf::read_one_batch(&mut rdr))
+                };
+                copied += self.insert_all(ups, dst);
+            }
+        }
+
+        let ups = {
+            let rdr_global = self.global.read().unwrap();
+            let vecs = rdr_global.vec_store.get(src).expect("KEY IS NOT IN HASHMAP");
+            select(&rdr_global, &vecs.0)
+        };
+        copied += self.insert_all(ups, dst);
+
+        Ok(copied)
+    }
+
+    /// Rows of a store within `range` (in seconds), from its file and from memory
+    pub fn read_store(&self, store_name: &str, range: Option<(u32, u32)>) -> Result<Vec<Update>, String> {
+        let store = match self.store.get(store_name) {
+            Some(store) => store,
+            None => return Err(format!("No db named `{}`", store_name))
+        };
+
+        let (mut ups, rdr) = loop {
+            let seen = {
+                let rdr = self.read_global();
+                (rdr.is_loaded(store_name), rdr.flushes(store_name), rdr.flushing_from.get(store_name).cloned())
+            };
+            let start = Instant::now();
+            let ups = match seen {
+                // a loaded store already holds the rows of its files
+                (true, _, _) => Vec::new(),
+                // the rows a flush is writing are still in memory
+                (false, _, Some(from)) => store.read_files(from)?
+                    .into_iter()
+                    .filter(|up| in_range(up, range))
+                    .collect(),
+                (false, _, None) => {
+                    let (min_ts, max_ts) = match range {
+                        Some((min, max)) => (u64::from(min) * 1000, u64::from(max) * 1000),
+                        None => (0, u64::max_value())
+                    };
+                    let backend = self.global.read().unwrap().backend.clone();
+                    match backend {
+                        Some(backend) => backend.read(store_name, Some((min_ts, max_ts)))?,
+                        None => {
+                            let interrupt = scan::Interrupt { connection: self.connection.clone(), deadline: self.deadline.get() };
+                            let threads = self.global.read().unwrap().settings.scan_threads;
+                            let ups = scan::range(&self.store_files(store_name), min_ts, max_ts, threads, &interrupt)?;
+                            self.interrupted();
+                            ups
+                        }
+                    }
+                }
+            };
+            self.profile.borrow_mut().disk += start.elapsed();
+            let rdr = self.read_global();
+            // a flush dropped rows from memory that were not in the files read
+            if (rdr.is_loaded(store_name), rdr.flushes(store_name), rdr.flushing_from.get(store_name).cloned()) == seen {
+                break (ups, rdr);
+            }
+        };
+        let vecs = rdr.vec_store.get(store_name).expect("KEY IS NOT IN HASHMAP");
+        ups.extend(vecs.0.iter().filter(|up| in_range(up, range)).cloned());
+
+        let amendments = rdr.amendments_of(store_name);
+        let ups : Vec<Update> = ups.iter().filter_map(|up| amendments.apply(up)).collect();
+        self.profile_read(store_name, range, ups.len());
+        Ok(ups)
+    }
+
+    /// the shared state, the wait is counted as lock wait of the current command
+    fn read_global(&self) -> RwLockReadGuard<SharedState> {
+        let start = Instant::now();
+        let rdr = self.global.read().unwrap();
+        self.profile.borrow_mut().lock_wait += start.elapsed();
+        rdr
+    }
+
+    /// whether the rows of the files of a store are in memory
+    fn is_loaded(&self, store_name: &str) -> bool {
+        self.global.read().unwrap().is_loaded(store_name)
+    }
+
+    /// `encode` timed as serialization of the current command
+    fn serialize<T, F: FnOnce() -> T>(&self, encode: F) -> T {
+        let start = Instant::now();
+        let encoded = encode();
+        self.profile.borrow_mut().serialize += start.elapsed();
+        encoded
+    }
+
+    /// record what the current command read, for the slow log
+    fn profile_read(&self, store_name: &str, range: Option<(u32, u32)>, rows: usize) {
+        let mut profile = self.profile.borrow_mut();
+        profile.store = Some(store_name.to_owned());
+        profile.range = range;
+        profile.rows = Some(profile.rows.unwrap_or(0) + rows);
+    }
+
+    /// Make sure `store_name` exists before inserting into it, creating it
+    /// if `autocreate_stores` is set
+    pub fn ensure_store(&mut self, store_name: &str) -> Result<(), String> {
+        if self.exists(store_name) {
+            return Ok(());
+        }
+        if !self.global.read().unwrap().settings.autocreate_stores {
+            return Err(format!("No db named `{}`", store_name));
+        }
+        self.create(store_name);
+        Ok(())
+    }
+
+    /// Verify the rows of a `BULKADD ... COUNT n` against what was declared
+    /// and insert all of them, or none.
+    pub fn commit_bulkadd(&mut self, pending: PendingBulkAdd, store_name: &str) -> Result<u64, String> {
+        pending.verify()?;
+        if let Some(ref mut transaction) = self.transaction {
+            let rows = pending.rows.len() as u64;
+            transaction.extend(pending.rows.into_iter().map(|up| (store_name.to_owned(), up)));
+            return Ok(rows);
+        }
+        self.ensure_store(store_name)?;
+        if self.over_budget(store_name) {
+            return Err(format!("Memory budget exceeded, `{}` cannot be flushed.", store_name));
+        }
+        Ok(self.insert_all(pending.rows, store_name))
+    }
+
+    /// Start buffering inserts until `commit`
+    pub fn begin(&mut self) -> Result<(), String> {
+        if self.transaction.is_some() {
+            return Err("Already in a transaction, COMMIT or ROLLBACK first.".to_owned());
+        }
+        self.transaction = Some(Vec::new());
+        Ok(())
+    }
+
+    /// Insert every row buffered since `begin`, across stores, under one
+    /// lock so readers see either none or all of them. Returns the commit offset.
+    /// A commit that fails keeps the transaction open, to retry or `ROLLBACK`.
+    pub fn commit(&mut self) -> Result<u64, String> {
+        let mut store_names : Vec<String> = match self.transaction {
+            Some(ref rows) => rows.iter().map(|&(ref name, _)| name.clone()).collect(),
+            None => return Err("Not in a transaction, BEGIN first.".to_owned())
+        };
+        store_names.sort();
+        store_names.dedup();
+        for store_name in &store_names {
+            self.ensure_store(store_name)?;
+            if self.over_budget(store_name) {
+                return Err(format!("Memory budget exceeded, `{}` cannot be flushed.", store_name));
+            }
+        }
+        let rows = self.transaction.take().expect("NOT IN A TRANSACTION");
+
+        let (offset, mut to_flush, delay) = {
+            let mut wtr = self.global.write().unwrap();
+            let mut delay = None;
+            let to_flush = rows.into_iter()
+                .filter(|&(ref store_name, ref up)| {
+                    let is_autoflush = wtr.push(store_name, up.clone());
+                    delay = cmp::max(delay, wtr.throttle(store_name));
+                    is_autoflush
+                })
+                .map(|(store_name, _)| store_name)
+                .collect::<Vec<String>>();
+            (wtr.offset, to_flush, delay)
+        };
+        if let Some(delay) = delay {
+            thread::sleep(delay);
+        }
+        to_flush.sort();
+        to_flush.dedup();
+        for store_name in to_flush {
+            self.store.get_mut(&store_name).expect("KEY IS NOT IN HASHMAP").autoflush();
+        }
+        self.relieve_memory();
+        Ok(offset)
+    }
+
+    /// commit offset of the last write
+    pub fn offset(&self) -> u64 {
+        self.global.read().unwrap().offset
+    }
+
+    /// Block until writes up to commit `offset` are applied, or `timeout` ms passed
+    pub fn wait_for_offset(&self, offset: u64, timeout: u64) -> Result<(), String> {
+        let start = Instant::now();
+        let timeout = Duration::from_millis(timeout);
+        while self.offset() < offset {
+            if start.elapsed() >= timeout {
+                return Err(format!("Timed out waiting for offset {}, at {}.", offset, self.offset()));
+            }
+            thread::sleep(Duration::from_millis(OFFSET_POLL_MS));
+        }
+        Ok(())
+    }
+
+    /// Discard every row buffered since `begin`
+    pub fn rollback(&mut self) -> Result<u64, String> {
+        match self.transaction.take() {
+            Some(rows) => Ok(rows.len() as u64),
+            None => Err("Not in a transaction, BEGIN first.".to_owned())
+        }
+    }
+
+    /// Insert rows into a store, returns how many
+    fn insert_all(&mut self, ups: Vec<Update>, store_name: &str) -> u64 {
+        let store = self.store.get_mut(store_name).expect("KEY IS NOT IN HASHMAP");
+        let n = ups.len() as u64;
+        for up in ups {
+            store.add(up);
+        }
+        self.relieve_memory();
+        n
+    }
+
+    /// Was the insert with this idempotency key already made?
+    pub fn has_idempotency_key(&self, key: &str) -> bool {
+        let rdr = self.global.read().unwrap();
+        rdr.recent_keys.contains(key)
+    }
+
+    /// Record the idempotency key of an insert that succeeded
+    pub fn record_idempotency_key(&mut self, key: &str) {
+        let mut wtr = self.global.write().unwrap();
+        wtr.recent_keys.insert(key);
+    }
+
+    /// Check if a table exists
+    pub fn exists(&mut self, store_name : &str) -> bool {
+        self.store.contains_key(store_name)
+    }
+
+    /// Insert a row into current store.
+    pub fn add(&mut self, up: Update) {
+        self.get_current_store().add(up);
+        self.relieve_memory();
+    }
+
+    /// Flush the stores with the most unflushed rows once all stores hold
+    /// more than `--flush_memory`, see `SharedState::flush_candidates`
+    fn relieve_memory(&mut self) {
+        let candidates = self.global.read().unwrap().flush_candidates();
+        for store_name in candidates {
+            if let Some(store) = self.store.get_mut(&store_name) {
+                debug!("Flushing {} under memory pressure", store_name);
+                store.flush();
+            }
+        }
+    }
+
+
+    /// Create a new store
+    pub fn create(&mut self, store_name: &str) {
+        let fname = self.global.read().unwrap().catalog_fname(store_name);
+        self.create_as(store_name, &fname);
+    }
+
+    /// `create`, flushing the store to `fname` unless another client
+    /// created it already
+    fn create_as(&mut self, store_name: &str, fname: &str) {
+        // insert a vector into shared hashmap, keeping the rows of a store
+        // another client already created
+        let fname = {
+            let mut global = self.global.write().unwrap();
+            global.vec_store.entry(store_name.to_owned()).or_insert_with(|| (Vec::new(), 0));
+            global.catalog_entry_as(store_name, fname, false).fname
+        };
+        // insert a store into client state hashmap
+        self.store.insert(store_name.to_owned(), Store {
+            name: store_name.to_owned(),
+            fname,
+            global: self.global.clone()
+        });
+    }
+
+    /// Create a store that is never flushed to disk
+    pub fn create_ephemeral(&mut self, store_name: &str) {
+        self.create(store_name);
+        let mut wtr = self.global.write().unwrap();
+        wtr.catalog_entry(store_name, true);
+        wtr.ephemeral.insert(store_name.to_owned());
+    }
+
+    /// Create a store with the settings of another store, or of a template
+    /// in the config, see `settings::Template`
+    pub fn create_like(&mut self, store_name: &str, like: &str) -> Result<(), String> {
+        if self.exists(store_name) {
+            return Err(format!("DB `{}` already exists", store_name));
+        }
+        let like_store = self.resolve(like);
+        let template = if self.exists(&like_store) {
+            self.template_of(&like_store)?
+        } else {
+            let configured = self.global.read().unwrap().settings.templates.get(like).cloned();
+            match configured.or_else(|| series::template(like)) {
+                Some(template) => template,
+                None => return Err(format!("No db or template named `{}`", like))
+            }
+        };
+        if template.multicast && self.global.read().unwrap().multicast.is_none() {
+            return Err("Multicast is not enabled, see --multicast.".to_owned());
+        }
+        if template.memory {
+            self.create_ephemeral(store_name);
+        } else {
+            self.create(store_name);
+        }
+        let stores = [store_name.to_owned()];
+        if template.ttl.is_some() {
+            self.retain(&stores, template.ttl)?;
+        }
+        if let Some((ref cold_folder, ms)) = template.tier {
+            self.set_tier(store_name, cold_folder, ms)?;
+        }
+        if template.precision.is_some() {
+            self.set_precision(store_name, template.precision)?;
+        }
+        if template.multicast {
+            self.set_multicast(store_name, true)?;
+        }
+        if let Some(kind) = template.series {
+            let dtf_folder = self.global.read().unwrap().settings.dtf_folder.clone();
+            utils::create_dir_if_not_exist(&dtf_folder);
+            series::record(&dtf_folder, store_name, kind).map_err(|e| format!("{:?}", e))?;
+            self.global.write().unwrap().series.insert(store_name.to_owned(), kind);
+        }
+        Ok(())
+    }
+
+    /// the settings of a store as a template
+    fn template_of(&self, store_name: &str) -> Result<Template, String> {
+        let rdr = self.global.read().unwrap();
+        if rdr.materialized.contains_key(store_name) {
+            return Err(format!("`{}` is a materialized store, use CREATE MATERIALIZED.", store_name));
+        }
+        if rdr.virtuals.contains_key(store_name) {
+            return Err(format!("`{}` is a virtual store, use CREATE VIRTUAL.", store_name));
+        }
+        Ok(Template {
+            memory: rdr.ephemeral.contains(store_name),
+            ttl: rdr.ttls.get(store_name).map(|ttl| ttl.ms),
+            tier: rdr.tiers.get(store_name).map(|tier| (tier.cold_folder.clone(), tier.ms)),
+            precision: rdr.precisions.get(store_name).cloned(),
+            multicast: rdr.multicast.as_ref().map_or(false, |multicast| multicast.stores.contains(store_name)),
+            series: rdr.series.get(store_name).cloned(),
+        })
+    }
+
+    /// Create a store whose rows are pruned by the retention worker once
+    /// they are older than `ttl` ms
+    pub fn create_ttl(&mut self, store_name: &str, ttl: u64) -> Result<(), String> {
+        if self.exists(store_name) {
+            return Err(format!("DB `{}` already exists", store_name));
+        }
+        // the TTL is written before the store is created, so it is never
+        // kept without it
+        let (name, fname) = {
+            let rdr = self.global.read().unwrap();
+            let name = rdr.catalog_fname(store_name);
+            utils::create_dir_if_not_exist(&rdr.settings.dtf_folder);
+            let fname = utils::dtf_fname(&rdr.settings.dtf_folder, &name);
+            (name, fname)
+        };
+        retention::write_ttl(&fname, ttl).map_err(|e| format!("{:?}", e))?;
+        self.create_as(store_name, &name);
+        let mut wtr = self.global.write().unwrap();
+        wtr.ttls.insert(store_name.to_owned(), Ttl { ms: ttl, fname });
+        Ok(())
+    }
+
+    /// Why `command` is refused, if this server is the standby of an HA pair, see `lease`
+    pub fn fenced(&self, command: &str) -> Option<String> {
+        let rdr = self.global.read().unwrap();
+        if rdr.role.accepts_writes() || Allow::standby().permits(command) {
+            return None;
+        }
+        Some(match rdr.role {
+            Role::Standby { term, ref primary } if !primary.is_empty() =>
+                format!("Standby of {} in term {}, writes go to the primary.", primary, term),
+            _ => "Not the primary, writes are refused until this server holds the lease.".to_owned()
+        })
+    }
+
+    /// End a BULKADD without inserting the rows held back for its end
+    pub fn abort_bulkadd(&mut self) {
+        self.is_adding = false;
+        self.bulkadd_db = None;
+        self.bulkadd_pending = None;
+    }
+
+    /// Refuses a full scan while inserts lag, see `--shed_reads_above`
+    pub fn shed(&self, command: &str) -> Option<String> {
+        let rdr = self.global.read().unwrap();
+        let threshold = rdr.settings.shed_reads_above;
+        if threshold == 0 || !parser::is_full_scan(command) {
+            return None;
+        }
+        let lag = rdr.ingest_lag.ms();
+        if lag <= threshold {
+            return None;
+        }
+        Some(format!("Inserts lag by {}ms, above {}ms, full scans are refused. Read a range or a page, or retry after {}s.",
+            lag, threshold, SHED_RETRY_SECS))
+    }
+
+    /// `EXISTS [db] AS JSON`: whether `store_name` exists, with its schema
+    pub fn describe(&self, store_name: &str, created: bool) -> String {
+        let rdr = self.global.read().unwrap();
+        let store = if self.store.contains_key(store_name) { Some(rdr.schema_store(store_name)) } else { None };
+        schema::store_to_json(store_name, created, store)
+    }
+
+    /// `CREATE IF NOT EXISTS`: create `store_name` like `CREATE` unless
+    /// another connection did, and describe it
+    pub fn create_if_not_exists(&mut self, store_name: &str) -> String {
+        let (created, fname) = {
+            let mut global = self.global.write().unwrap();
+            let created = !global.vec_store.contains_key(store_name);
+            if created {
+                global.vec_store.insert(store_name.to_owned(), (Vec::new(), 0));
+            }
+            (created, global.catalog_entry(store_name, false).fname)
+        };
+        // a store the connection already has is left as it is
+        if !self.store.contains_key(store_name) {
+            self.store.insert(store_name.to_owned(), Store {
+                name: store_name.to_owned(),
+                fname,
+                global: self.global.clone()
+            });
+        }
+        self.describe(store_name, created)
+    }
+
+    /// role in an HA pair as JSON
+    pub fn ha(&self) -> String {
+        let rdr = self.global.read().unwrap();
+        format!("{}\n", rdr.role.to_json(&rdr.settings.ha_node))
+    }
+
+    /// The commands, response formats and stores as JSON, see `schema`
+    pub fn schema(&self) -> String {
+        let rdr = self.global.read().unwrap();
+        let stores = rdr.vec_store.keys()
+            .map(|store_name| (store_name.clone(), rdr.schema_store(store_name)))
+            .collect();
+        schema::to_json(stores, &rdr.extensions)
+    }
+
+    /// Where clients find the primary and its standbys, as JSON
+    ///
+    /// {"term": 2, "primary": "10.0.0.2:9001", "replicas": ["10.0.0.1:9001"]}
+    ///
+    /// The primary is empty while no server holds the lease. A standby that
+    /// has not announced itself for three leases is left out.
+    pub fn topology(&self) -> String {
+        let rdr = self.global.read().unwrap();
+        let settings = &rdr.settings;
+        let fname = match settings.ha_lease {
+            Some(ref fname) => fname,
+            None => return format!("{{\"term\": 0, \"primary\": \"{}\", \"replicas\": []}}\n", settings.ha_addr)
+        };
+        let (term, primary) = match rdr.role {
+            Role::Primary { term, .. } if rdr.role.accepts_writes() => (term, settings.ha_node.as_str()),
+            Role::Primary { term, .. } => (term, ""),
+            Role::Standby { term, ref primary } => (term, primary.as_str()),
+            Role::Single => (0, ""),
+        };
+        let seen = lease::now_ms().saturating_sub(settings.ha_lease_secs * 1000 * 3);
+        let members = lease::members(fname);
+        let addr = members.iter().find(|member| member.node == primary)
+            .map_or("", |member| member.addr.as_str());
+        let replicas : Vec<String> = members.iter()
+            .filter(|member| member.node != primary && member.seen >= seen)
+            .map(|member| format!("\"{}\"", member.addr))
+            .collect();
+        format!("{{\"term\": {}, \"primary\": \"{}\", \"replicas\": [{}]}}\n", term, addr, replicas.join(", "))
+    }
+
+    /// Copy the flushed files to a new backup in `folder`, see `backup`
+    pub fn backup(&self, folder: &str, incremental: bool) -> Result<String, String> {
+        let (dtf_folder, journal) = {
+            let rdr = self.global.read().unwrap();
+            (rdr.settings.dtf_folder.clone(), rdr.settings.journal.clone())
+        };
+        let report = backup::run(&dtf_folder, journal.as_ref().map(|journal| journal.as_str()), folder, incremental)
+            .map_err(|e| format!("Unable to back up to {}: {}", folder, e))?;
+        info!("Backup {} to {}: {} files copied, {} unchanged", report.backup, folder, report.copied, report.unchanged);
+        Ok(format!("Backup {}: {} files ({} bytes) copied, {} unchanged.",
+            report.backup, report.copied, report.bytes, report.unchanged))
+    }
+
+    /// Move rows of a store older than `ms` to `cold_folder`, see `tiering`
+    pub fn set_tier(&mut self, store_name: &str, cold_folder: &str, ms: u64) -> Result<(), String> {
+        let fname = match self.store_fname(store_name) {
+            Some(fname) => fname,
+            None => return Err(format!("No db named `{}`", store_name))
+        };
+        utils::create_dir_if_not_exist(&self.global.read().unwrap().settings.dtf_folder);
+        fs::create_dir_all(cold_folder).map_err(|e| format!("{:?}", e))?;
+        tiering::write_tier(&fname, cold_folder, ms).map_err(|e| format!("{:?}", e))?;
+        let mut wtr = self.global.write().unwrap();
+        wtr.tiers.insert(store_name.to_owned(), Tier { ms, cold_folder: cold_folder.to_owned(), fname });
+        Ok(())
+    }
+
+    /// Whether an insert into a MEMORY store would go over the memory budget.
+    /// Other stores can always be flushed to make room.
+    pub fn over_budget(&self, store_name: &str) -> bool {
+        let rdr = self.global.read().unwrap();
+        rdr.settings.max_memory != 0
+            && rdr.ephemeral.contains(store_name)
+            && rdr.memory_used() >= rdr.settings.max_memory
+    }
+
+    /// Create a store of `minutes` candles continuously derived from the trades of `source`.
+    /// Trades already in memory are folded in immediately.
+    pub fn create_materialized(&mut self, name: &str, source: &str, minutes: u16) -> Result<(), String> {
+        if !self.exists(source) {
+            return Err(format!("No db named `{}`", source));
+        }
+        if self.exists(name) {
+            return Err(format!("DB `{}` already exists", name));
+        }
+
+        // recorded before the store is created, so it is never kept as a plain store
+        let dtf_folder = self.global.read().unwrap().settings.dtf_folder.clone();
+        utils::create_dir_if_not_exist(&dtf_folder);
+        materialized::record(&dtf_folder, name, source, minutes).map_err(|e| format!("{:?}", e))?;
+        self.create(name);
+
+        let mut wtr = self.global.write().unwrap();
+        let mut candles = Candles::new(BTreeMap::new(), minutes);
+        for up in wtr.vec_store.get(source).expect("KEY IS NOT IN HASHMAP").0.iter() {
+            candles.add(up);
+        }
+        let ups = candles.to_updates();
+        let size = ups.len() as u64;
+        wtr.vec_store.insert(name.to_owned(), (ups, size));
+        wtr.materialized.insert(name.to_owned(), Materialized {
+            source: source.to_owned(),
+            candles,
+        });
+        Ok(())
+    }
+
+    /// Create a store derived from the rows inserted into the stores `expr` reads, see `derived`.
+    /// Rows of the sources in memory are folded in immediately.
+    pub fn create_virtual(&mut self, name: &str, mut expr: Expr, interval: Option<u64>, materialized: bool) -> Result<(), String> {
+        if self.exists(name) {
+            return Err(format!("DB `{}` already exists", name));
+        }
+        expr.rename(&|store_name| self.resolve(store_name));
+        let sources = expr.stores();
+        if sources.is_empty() {
+            return Err("A virtual store reads at least one store, e.g. mid(db).".to_owned());
+        }
+        if let Some(source) = sources.iter().find(|source| !self.exists(source)) {
+            return Err(format!("No db named `{}`", source));
+        }
+
+        // recorded before the store is created, so it is never kept as a plain store
+        let mut derived = Derived::new(expr, interval, materialized);
+        let dtf_folder = self.global.read().unwrap().settings.dtf_folder.clone();
+        utils::create_dir_if_not_exist(&dtf_folder);
+        derived::record(&dtf_folder, name, &derived).map_err(|e| format!("{:?}", e))?;
+        if materialized {
+            self.create(name);
+        } else {
+            self.create_ephemeral(name);
+        }
+
+        let mut wtr = self.global.write().unwrap();
+        let mut rows : Vec<(&String, Update)> = sources.iter()
+            .flat_map(|source| wtr.vec_store.get(source).expect("KEY IS NOT IN HASHMAP").0.iter()
+                .map(move |up| (source, up.clone())))
+            .collect();
+        // stable, rows of the same ms keep the order of the sources
+        rows.sort_by_key(|&(_, ref up)| up.ts);
+        for (source, up) in rows {
+            if let Some(row) = derived.add(source, &up) {
+                wtr.push(name, row);
+            }
+        }
+        wtr.virtuals.insert(name.to_owned(), derived);
+        Ok(())
+    }
+
+    /// The store a name refers to, following the naming policy and aliases
+    pub fn resolve(&self, name: &str) -> String {
+        let rdr = self.global.read().unwrap();
+        let name = if rdr.settings.normalize_names {
+            parser::normalize_name(name)
+        } else {
+            name.to_owned()
+        };
+        rdr.aliases.get(&name).cloned().unwrap_or(name)
+    }
+
+    /// A store name given by a client for creating or inserting, rejected
+    /// if it breaks the naming rules
+    pub fn new_store_name(&self, name: &str) -> Result<String, String> {
+        parser::validate_name(name)?;
+        Ok(self.resolve(name))
+    }
+
+    /// Make `store_name` reachable as `alias`
+    pub fn create_alias(&mut self, alias: &str, store_name: &str) -> Result<(), String> {
+        parser::validate_name(alias)?;
+        let alias = if self.global.read().unwrap().settings.normalize_names {
+            parser::normalize_name(alias)
+        } else {
+            alias.to_owned()
+        };
+        let store_name = self.resolve(store_name);
+        if !self.exists(&store_name) {
+            return Err(format!("No db named `{}`", store_name));
+        }
+        if self.exists(&alias) {
+            return Err(format!("DB `{}` already exists", alias));
+        }
+        let dtf_folder = self.global.read().unwrap().settings.dtf_folder.clone();
+        utils::create_dir_if_not_exist(&dtf_folder);
+        alias::record(&dtf_folder, &alias, &store_name).map_err(|e| format!("{:?}", e))?;
+        let mut wtr = self.global.write().unwrap();
+        wtr.aliases.insert(alias, store_name);
+        Ok(())
+    }
+
+    /// Name the stores matching `pattern` as `group_name`, see `group`
+    pub fn create_group(&mut self, group_name: &str, pattern: &str) -> Result<(), String> {
+        parser::validate_name(group_name)?;
+        let dtf_folder = self.global.read().unwrap().settings.dtf_folder.clone();
+        utils::create_dir_if_not_exist(&dtf_folder);
+        group::record(&dtf_folder, group_name, pattern).map_err(|e| format!("{:?}", e))?;
+        let mut wtr = self.global.write().unwrap();
+        wtr.groups.insert(group_name.to_owned(), pattern.to_owned());
+        Ok(())
+    }
+
+    pub fn drop_group(&mut self, group_name: &str) -> Result<(), String> {
+        let dtf_folder = self.global.read().unwrap().settings.dtf_folder.clone();
+        if !self.global.read().unwrap().groups.contains_key(group_name) {
+            return Err(format!("No group named `{}`", group_name));
+        }
+        group::record(&dtf_folder, group_name, "").map_err(|e| format!("{:?}", e))?;
+        let mut wtr = self.global.write().unwrap();
+        wtr.groups.remove(group_name);
+        Ok(())
+    }
+
+    /// `group = pattern (n stores)` lines, by group
+    pub fn groups(&self) -> String {
+        let groups : BTreeMap<String, String> = self.global.read().unwrap().groups.clone().into_iter().collect();
+        if groups.is_empty() {
+            return "No groups.\n".to_owned();
+        }
+        let mut ret = String::new();
+        for (group_name, pattern) in groups {
+            let stores = self.store.keys().filter(|name| parser::matches_pattern(&pattern, name)).count();
+            ret.push_str(&format!("{} = {} ({} stores)\n", group_name, pattern, stores));
+        }
+        ret
+    }
+
+    /// the stores of a group, by name
+    pub fn group_stores(&self, group_name: &str) -> Result<Vec<String>, String> {
+        let pattern = match self.global.read().unwrap().groups.get(group_name) {
+            Some(pattern) => pattern.clone(),
+            None => return Err(format!("No group named `{}`", group_name))
+        };
+        let mut stores : Vec<String> = self.store.keys()
+            .filter(|name| parser::matches_pattern(&pattern, name))
+            .cloned()
+            .collect();
+        stores.sort();
+        Ok(stores)
+    }
+
+    /// Flush the stores of a group, their rows are taken at once. Returns the number of stores.
+    pub fn flush_group(&mut self, group_name: &str) -> Result<usize, String> {
+        let stores = self.group_stores(group_name)?;
+        self.flush_where(|name| stores.iter().any(|store_name| store_name == name));
+        Ok(stores.len())
+    }
+
+    /// Clear the stores of a group at once. Returns the number of stores.
+    pub fn clear_group(&mut self, group_name: &str) -> Result<usize, String> {
+        let stores = self.group_stores(group_name)?;
+        {
+            let mut wtr = self.global.write().unwrap();
+            for store_name in &stores {
+                self.store[store_name].clear_rows(&mut wtr);
+            }
+        }
+        for store_name in &stores {
+            self.store.get_mut(store_name).expect("KEY IS NOT IN HASHMAP").load_size_from_file();
+        }
+        Ok(stores.len())
+    }
+
+    /// Prune the rows of `stores` older than `ttl` ms, or stop pruning them with None
+    pub fn retain(&mut self, stores: &[String], ttl: Option<u64>) -> Result<(), String> {
+        let mut fnames = Vec::new();
+        for store_name in stores {
+            match self.store_fname(store_name) {
+                Some(fname) => fnames.push(fname),
+                None => return Err(format!("No db named `{}`", store_name))
+            }
+        }
+        utils::create_dir_if_not_exist(&self.global.read().unwrap().settings.dtf_folder);
+        for fname in &fnames {
+            match ttl {
+                Some(ms) => retention::write_ttl(fname, ms),
+                None => retention::remove_ttl(fname)
+            }.map_err(|e| format!("{:?}", e))?;
+        }
+        let mut wtr = self.global.write().unwrap();
+        for (store_name, fname) in stores.iter().zip(fnames) {
+            match ttl {
+                Some(ms) => { wtr.ttls.insert(store_name.to_owned(), Ttl { ms, fname }); },
+                None => { wtr.ttls.remove(store_name); }
+            }
+        }
+        Ok(())
+    }
+
+    /// The rows of `store_name` in `range` as a standalone dtf file,
+    /// prefixed with its length in bytes
+    pub fn download(&self, store_name: &str, range: Option<(u32, u32)>) -> Result<Vec<u8>, String> {
+        let ups = self.read_store(store_name, range)?;
+        // the symbol field of the header is fixed width
+        let symbol : String = store_name.chars().take(dtf::SYMBOL_LEN).collect();
+        let file = self.serialize(|| dtf::encode_buffer(&symbol, &ups));
+        let mut bytes : Vec<u8> = Vec::with_capacity(8 + file.len());
+        let _ = bytes.write_u64::<BigEndian>(file.len() as u64);
+        bytes.extend(file);
+        Ok(bytes)
+    }
+
+    /// The trades of `trades` in `range` with the best bid and ask of the
+    /// order book of `quotes` at their timestamp, as a JSON array.
+    /// Books without a quote in the last `tolerance` ms are left out.
+    ///
+    /// If `quotes` is an auxiliary series, the trades get its latest value instead.
+    pub fn join_quotes(&self, trades: &str, quotes: &str, range: Option<(u32, u32)>, tolerance: Option<u64>) -> Result<String, String> {
+        let kind = self.global.read().unwrap().series.get(quotes).cloned();
+        if let Some(kind) = kind {
+            return self.join_series(trades, quotes, kind, range, tolerance);
+        }
+        let mut trade_rows = self.read_store(trades, range)?;
+        // the book at the first trade needs every quote before it
+        let mut quote_rows = self.read_store(quotes, range.map(|(_, to)| (0, to)))?;
+        trade_rows.sort();
+        quote_rows.sort();
+        let joined = asof::join_quotes(&trade_rows, &quote_rows, tolerance);
+        let json : Vec<String> = joined.iter().map(|row| row.to_json()).collect();
+        Ok(format!("[{}]\n", json.join(",")))
+    }
+
+    fn join_series(&self, trades: &str, series_name: &str, kind: Kind, range: Option<(u32, u32)>, tolerance: Option<u64>) -> Result<String, String> {
+        let mut trade_rows = self.read_store(trades, range)?;
+        let mut series_rows = self.read_store(series_name, range.map(|(_, to)| (0, to)))?;
+        trade_rows.retain(|up| up.is_trade);
+        trade_rows.sort();
+        series_rows.sort();
+        let joined : Vec<(Update, Option<Update>)> = asof::asof_join(trade_rows, series_rows, tolerance).collect();
+        Ok(series::join_to_json(kind, &joined))
+    }
+
+    /// The rows of the auxiliary series `store_name` in `range` aggregated
+    /// over intervals of `interval` ms, as a JSON array, see `series`
+    pub fn aggregate_series(&self, store_name: &str, range: Option<(u32, u32)>, interval: u64) -> Result<String, String> {
+        let kind = match self.global.read().unwrap().series.get(store_name) {
+            Some(&kind) => kind,
+            None if self.store.contains_key(store_name) =>
+                return Err(format!("`{}` is not a series, create one LIKE funding or open_interest.", store_name)),
+            None => return Err(format!("No db named `{}`", store_name))
+        };
+        let mut rows = self.read_store(store_name, range)?;
+        rows.sort();
+        Ok(series::to_json(kind, &dtf::series::buckets(&rows, interval)))
+    }
+
+    /// `APPROX`, the estimate of an aggregate from the stats and samples of the files,
+    /// exact from the rows if it is less precise than `max_error`
+    pub fn approx(&self, aggregate: Aggregate, store_name: &str, range: Option<(u32, u32)>, max_error: Option<f64>) -> Result<String, String> {
+        if !self.store.contains_key(store_name) {
+            return Err(format!("No db named `{}`", store_name));
+        }
+        let in_memory = self.is_loaded(store_name);
+        if in_memory {
+            return Ok(approx::exact(aggregate, &self.read_store(store_name, range)?).to_json());
+        }
+        let (min_ts, max_ts) = match range {
+            Some((min, max)) => (u64::from(min) * 1000, u64::from(max) * 1000),
+            None => (0, u64::max_value())
+        };
+        let start = Instant::now();
+        let files : Vec<FileSample> = self.store_files(store_name).iter()
+            .map(|fname| FileSample { summary: filestats::get(fname).summary, sample: filestats::sample(fname) })
+            .collect();
+        self.profile.borrow_mut().disk += start.elapsed();
+        let estimate = {
+            let rdr = self.read_global();
+            let memory = &rdr.vec_store.get(store_name).expect("KEY IS NOT IN HASHMAP").0;
+            match aggregate {
+                Aggregate::Count => approx::count(&files, memory, min_ts, max_ts),
+                Aggregate::Vwap => approx::vwap(&files, memory, min_ts, max_ts),
+            }
+        };
+        match max_error {
+            Some(max_error) if estimate.error > max_error =>
+                Ok(approx::exact(aggregate, &self.read_store(store_name, range)?).to_json()),
+            _ => Ok(estimate.to_json())
+        }
+    }
+
+    /// `TOPN`, the `n` rows of the range ranked highest `by`, folded over the scan without reading the range into memory
+    pub fn topn(&self, store_name: &str, n: usize, by: By, range: Option<(u32, u32)>) -> Result<String, String> {
+        if !self.store.contains_key(store_name) {
+            return Err(format!("No db named `{}`", store_name));
+        }
+        let in_memory = self.is_loaded(store_name);
+        let mut ranking = Ranking::new(by, n);
+        if !in_memory {
+            let (min_ts, max_ts) = match range {
+                Some((min, max)) => (u64::from(min) * 1000, u64::from(max) * 1000),
+                None => (0, u64::max_value())
+            };
+            let (tombstones, corrections, threads) = {
+                let rdr = self.read_global();
+                let threads = if by.is_sequential() { 1 } else { rdr.settings.scan_threads };
+                (rdr.tombstones_of(store_name).to_vec(), rdr.corrections.get(store_name).cloned(), threads)
+            };
+            let start = Instant::now();
+            let interrupt = scan::Interrupt { connection: self.connection.clone(), deadline: self.deadline.get() };
+            let runs = scan::fold(&self.store_files(store_name), min_ts, max_ts, threads, &interrupt,
+                || Ranking::new(by, n),
+                move |ranking: &mut Ranking, batch| {
+                    let amendments = Amendments { tombstones: &tombstones, corrections: corrections.as_ref() };
+                    let batch : Vec<Update> = batch.iter().filter_map(|up| amendments.apply(up)).collect();
+                    ranking.add(&batch);
+                })?;
+            self.interrupted();
+            self.profile.borrow_mut().disk += start.elapsed();
+            // a sequential ranking is a single run whose book the rows in memory continue
+            let mut runs = runs.into_iter();
+            if let Some(first) = runs.next() {
+                ranking = first;
+            }
+            for run in runs {
+                ranking.merge(run);
+            }
+        }
+        let mut ups : Vec<Update> = {
+            let rdr = self.read_global();
+            let amendments = rdr.amendments_of(store_name);
+            rdr.vec_store.get(store_name).expect("KEY IS NOT IN HASHMAP").0.iter()
+                .filter(|up| in_range(up, range))
+                .filter_map(|up| amendments.apply(up))
+                .collect()
+        };
+        ups.sort();
+        ranking.add(&ups);
+        self.profile_read(store_name, range, n);
+        Ok(self.serialize(|| ranking.to_json()))
+    }
+
+    /// `HIST`, the rows of the range that pass `filter` counted by the value of `field` in `buckets` buckets
+    pub fn hist(&self, store_name: &str, field: hist::Field, buckets: usize, range: Option<(u32, u32)>, filter: RowFilter) -> Result<String, String> {
+        if !self.store.contains_key(store_name) {
+            return Err(format!("No db named `{}`", store_name));
+        }
+        let in_memory = self.is_loaded(store_name);
+        let mut distribution = Distribution::new();
+        if !in_memory {
+            let (min_ts, max_ts) = match range {
+                Some((min, max)) => (u64::from(min) * 1000, u64::from(max) * 1000),
+                None => (0, u64::max_value())
+            };
+            let (tombstones, corrections, threads) = {
+                let rdr = self.read_global();
+                (rdr.tombstones_of(store_name).to_vec(), rdr.corrections.get(store_name).cloned(), rdr.settings.scan_threads)
+            };
+            let filter = filter.clone();
+            let start = Instant::now();
+            let interrupt = scan::Interrupt { connection: self.connection.clone(), deadline: self.deadline.get() };
+            let runs = scan::fold(&self.store_files(store_name), min_ts, max_ts, threads, &interrupt, Distribution::new,
+                move |distribution: &mut Distribution, batch| {
+                    let amendments = Amendments { tombstones: &tombstones, corrections: corrections.as_ref() };
+                    for up in batch.iter().filter_map(|up| amendments.apply(up)).filter(|up| filter.matches(up)) {
+                        distribution.add(field.value(&up));
+                    }
+                })?;
+            self.interrupted();
+            self.profile.borrow_mut().disk += start.elapsed();
+            for run in runs {
+                distribution.merge(run);
+            }
+        }
+        {
+            let rdr = self.read_global();
+            let amendments = rdr.amendments_of(store_name);
+            let vecs = rdr.vec_store.get(store_name).expect("KEY IS NOT IN HASHMAP");
+            for up in vecs.0.iter().filter(|up| in_range(up, range)).filter_map(|up| amendments.apply(up)).filter(|up| filter.matches(up)) {
+                distribution.add(field.value(&up));
+            }
+        }
+        self.profile_read(store_name, range, distribution.count() as usize);
+        Ok(self.serialize(|| hist::to_json(&distribution.bins(buckets))))
+    }
+
+    /// Release the memory and open files of a store that is no longer written to.
+    /// Its rows are flushed first, reads load them from the file again.
+    pub fn close(&mut self, store_name: &str) -> Result<(), String> {
+        if !self.exists(store_name) {
+            return Err(format!("No db named `{}`", store_name));
+        }
+        if self.global.read().unwrap().ephemeral.contains(store_name) {
+            return Err(format!("`{}` is a MEMORY store, its rows would be lost.", store_name));
+        }
+        if self.store.get_mut(store_name).unwrap().flush() != Some(true) {
+            return Err(format!("`{}` is being flushed, try again.", store_name));
+        }
+
+        let mut wtr = self.global.write().unwrap();
+        // rows inserted since the flush stay
+        wtr.vec_store.get_mut(store_name).expect("KEY IS NOT IN HASHMAP").0.shrink_to_fit();
+        wtr.stats.remove(store_name);
+        // an archived store is expected to be silent
+        wtr.last_write.remove(store_name);
+        let feed_fname = wtr.feeds.get(store_name).map(|feed| feed.fname.clone());
+        if let Some(fname) = feed_fname {
+            wtr.handles.close(&fname);
+        }
+        info!("Closed {}", store_name);
+        Ok(())
+    }
+
+    /// Start a subscription to `stores`, returns the offset it continues after.
+    /// Resuming after `from` fails if rows after it are no longer in a feed.
+    pub fn subscribe(&mut self, stores: &[String], from: Option<u64>) -> Result<u64, String> {
+        if let Some(store_name) = stores.iter().find(|&store_name| !self.exists(store_name)) {
+            return Err(format!("No db named `{}`", store_name));
+        }
+        let mut wtr = self.global.write().unwrap();
+        let offset = wtr.offset;
+        for store_name in stores {
+            if !wtr.feeds.contains_key(store_name) {
+                let mut feed = Feed::open(&wtr.settings.dtf_folder, store_name, wtr.settings.replay_window)
+                    .map_err(|e| format!("{:?}", e))?;
+                // the feed starts now
+                feed.gap_upto = cmp::max(feed.gap_upto, offset);
+                wtr.feeds.insert(store_name.to_owned(), feed);
+            }
+        }
+        let from = match from {
+            Some(from) => from,
+            None => return Ok(offset)
+        };
+        if from > offset {
+            return Err(format!("Offset {} is ahead of the current offset {}", from, offset));
+        }
+        let gap_upto = stores.iter().map(|store_name| wtr.feeds[store_name].gap_upto).max().unwrap_or(0);
+        if from < gap_upto {
+            return Err(format!("Offset {} is older than the replay window, which starts after {}", from, gap_upto));
+        }
+        Ok(from)
+    }
+
+    /// Start a subscription to the changes to the stores, returns the offset
+    /// it continues after, see `cdc`
+    pub fn subscribe_cdc(&self, from: Option<u64>) -> Result<u64, String> {
+        let rdr = self.global.read().unwrap();
+        let offset = rdr.cdc.offset;
+        let from = match from {
+            Some(from) => from,
+            None => return Ok(offset)
+        };
+        if from > offset {
+            return Err(format!("Offset {} is ahead of the current event offset {}", from, offset));
+        }
+        if from < rdr.cdc.gap_upto() {
+            return Err(format!("Offset {} is older than the replay window, which starts after {}", from, rdr.cdc.gap_upto()));
+        }
+        Ok(from)
+    }
+
+    /// (offset, json) of the changes to the stores after event `offset`
+    pub fn cdc_since(&self, offset: u64) -> Vec<(u64, String)> {
+        self.global.read().unwrap().cdc.since(offset)
+    }
+
+    /// (offset, index in `stores`, row) of the rows inserted into `stores`
+    /// after commit `offset`, by offset
+    pub fn feed_since(&self, stores: &[String], offset: u64) -> Option<Vec<(u64, usize, Update)>> {
+        let rdr = self.global.read().unwrap();
+        let mut rows = Vec::new();
+        for (i, store_name) in stores.iter().enumerate() {
+            let feed = rdr.feeds.get(store_name)?;
+            rows.extend(feed.since(offset).into_iter().map(|(offset, up)| (offset, i, up)));
+        }
+        rows.sort_by_key(|&(offset, _, _)| offset);
+        Some(rows)
+    }
+
+    /// `key value` lines of the runtime settings, or of `key`
+    pub fn config_get(&self, key: Option<&str>) -> Result<String, String> {
+        let rdr = self.global.read().unwrap();
+        let keys = match key {
+            Some(key) => vec![key.to_owned()],
+            None => settings::RUNTIME_SETTINGS.iter()
+                .map(|&key| key.to_owned())
+                .chain(rdr.settings.templates.keys().map(|name| format!("{}{}", settings::TEMPLATE_PREFIX, name)))
+                .chain(rdr.settings.schedules.keys().map(|name| format!("{}{}", settings::SCHEDULE_PREFIX, name)))
+                .collect()
+        };
+        let mut ret = String::new();
+        for key in keys {
+            match rdr.settings.get(&key) {
+                Some(value) => ret.push_str(&format!("{} {}\n", key, value)),
+                None => return Err(format!("No runtime setting named `{}`", key))
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Change a runtime setting for every connection and keep it across restarts
+    pub fn config_set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let mut wtr = self.global.write().unwrap();
+        wtr.settings.set(key, value)?;
+        utils::create_dir_if_not_exist(&wtr.settings.dtf_folder);
+        wtr.settings.persist(key).map_err(|e| format!("{:?}", e))?;
+        info!("CONFIG SET {} {}", key, value);
+        Ok(())
+    }
+
+    /// Add an alert rule, returns its number
+    pub fn add_alert(&mut self, mut rule: Rule) -> Result<usize, String> {
+        if let Condition::Silent(ref mut store_name, _) = rule.condition {
+            *store_name = self.resolve(store_name);
+            if !self.exists(store_name) {
+                return Err(format!("No db named `{}`", store_name));
+            }
+        }
+        if let Some(ref mut into) = rule.into {
+            *into = self.resolve(into);
+            if !self.exists(into) {
+                return Err(format!("No db named `{}`", into));
+            }
+        }
+        let mut wtr = self.global.write().unwrap();
+        wtr.alerts.push(rule);
+        utils::create_dir_if_not_exist(&wtr.settings.dtf_folder);
+        alert::write_rules(&wtr.settings.dtf_folder, &wtr.alerts).map_err(|e| format!("{:?}", e))?;
+        Ok(wtr.alerts.len())
+    }
+
+    /// Remove alert rule number `n`
+    pub fn drop_alert(&mut self, n: usize) -> Result<(), String> {
+        let mut wtr = self.global.write().unwrap();
+        if n == 0 || n > wtr.alerts.len() {
+            return Err(format!("No alert rule {}", n));
+        }
+        wtr.alerts.remove(n - 1);
+        alert::write_rules(&wtr.settings.dtf_folder, &wtr.alerts).map_err(|e| format!("{:?}", e))
+    }
+
+    /// alert rules, one `[n]: [command]` line each
+    pub fn alerts(&self) -> String {
+        let rdr = self.global.read().unwrap();
+        if rdr.alerts.is_empty() {
+            return "No alert rules.\n".to_owned();
+        }
+        let mut ret = String::new();
+        for (i, rule) in rdr.alerts.iter().enumerate() {
+            ret.push_str(&format!("{}: {}\n", i + 1, rule));
+        }
+        ret
+    }
+
+    /// Start or stop publishing the rows inserted into `store_name`, see `--multicast`
+    pub fn set_multicast(&mut self, store_name: &str, on: bool) -> Result<(), String> {
+        if !self.exists(store_name) {
+            return Err(format!("No db named `{}`", store_name));
+        }
+        let mut wtr = self.global.write().unwrap();
+        let multicast = match wtr.multicast {
+            Some(ref mut multicast) => multicast,
+            None => return Err("Multicast is not enabled, see --multicast.".to_owned())
+        };
+        if on {
+            multicast.stores.insert(store_name.to_owned());
+        } else {
+            multicast.stores.remove(store_name);
+        }
+        Ok(())
+    }
+
+    /// Round price and size in the JSON responses of `store_name` to `decimals` places
+    pub fn set_precision(&mut self, store_name: &str, decimals: Option<usize>) -> Result<(), String> {
+        if !self.exists(store_name) {
+            return Err(format!("No db named `{}`", store_name));
+        }
+        let dtf_folder = self.global.read().unwrap().settings.dtf_folder.clone();
+        utils::create_dir_if_not_exist(&dtf_folder);
+        precision::record(&dtf_folder, store_name, decimals).map_err(|e| format!("{:?}", e))?;
+        let mut wtr = self.global.write().unwrap();
+        match decimals {
+            Some(decimals) => wtr.precisions.insert(store_name.to_owned(), decimals),
+            None => wtr.precisions.remove(store_name)
+        };
+        Ok(())
+    }
+
+    /// Set the tags of a store, drop those with an empty value, see `tag`.
+    /// Returns the tags of the store afterwards as JSON.
+    pub fn set_tags(&mut self, store_name: &str, tags: &[(String, String)]) -> Result<String, String> {
+        if !self.exists(store_name) {
+            return Err(format!("No db named `{}`", store_name));
+        }
+        if !tags.is_empty() {
+            let dtf_folder = self.global.read().unwrap().settings.dtf_folder.clone();
+            utils::create_dir_if_not_exist(&dtf_folder);
+            tag::record(&dtf_folder, store_name, tags).map_err(|e| format!("{:?}", e))?;
+        }
+        let mut wtr = self.global.write().unwrap();
+        let json = {
+            let store_tags = wtr.tags.entry(store_name.to_owned()).or_insert_with(BTreeMap::new);
+            for &(ref tag, ref value) in tags {
+                if value.is_empty() {
+                    store_tags.remove(tag);
+                } else {
+                    store_tags.insert(tag.clone(), value.clone());
+                }
+            }
+            tag::to_json(store_tags)
+        };
+        if wtr.tags.get(store_name).map_or(false, |store_tags| store_tags.is_empty()) {
+            wtr.tags.remove(store_name);
+        }
+        Ok(format!("{}\n", json))
+    }
+
+    /// Name the flags of a store, forget them with no names, see `flag`.
+    /// Returns the names afterwards as JSON, the current ones without `names`.
+    pub fn set_flags(&mut self, store_name: &str, names: Option<Vec<String>>) -> Result<String, String> {
+        if !self.exists(store_name) {
+            return Err(format!("No db named `{}`", store_name));
+        }
+        if let Some(names) = names {
+            flag::validate(&names)?;
+            let dtf_folder = self.global.read().unwrap().settings.dtf_folder.clone();
+            utils::create_dir_if_not_exist(&dtf_folder);
+            flag::record(&dtf_folder, store_name, &names).map_err(|e| format!("{:?}", e))?;
+            let mut wtr = self.global.write().unwrap();
+            if names.is_empty() {
+                wtr.flags.remove(store_name);
+            } else {
+                wtr.flags.insert(store_name.to_owned(), names);
+            }
+        }
+        let rdr = self.global.read().unwrap();
+        Ok(format!("{}\n", flag::to_json(rdr.flags.get(store_name).map_or(&[], |names| names.as_slice()))))
+    }
+
+    /// `filter` with the bits of the flags it tests, which every store of `stores` names alike
+    pub fn resolve_flags(&self, stores: &[String], filter: RowFilter) -> Result<RowFilter, String> {
+        if filter.flags.is_empty() {
+            return Ok(filter);
+        }
+        let rdr = self.global.read().unwrap();
+        let mut resolved : Option<RowFilter> = None;
+        for store_name in stores {
+            let mut store_filter = filter.clone();
+            store_filter.resolve_flags(rdr.flags.get(store_name).map_or(&[], |names| names.as_slice()))?;
+            if resolved.as_ref().map_or(false, |resolved| *resolved != store_filter) {
+                return Err(format!("`{}` numbers the flags differently than the other stores, see FLAGS.", store_name));
+            }
+            resolved = Some(store_filter);
+        }
+        Ok(resolved.unwrap_or(filter))
+    }
+
+    /// the stores with every tag, whose value matches the pattern, as a JSON array
+    pub fn find(&self, tags: &[(String, String)]) -> String {
+        let rdr = self.global.read().unwrap();
+        let mut stores : Vec<&String> = rdr.tags.iter()
+            .filter(|&(_, store_tags)| tags.iter().all(|&(ref tag, ref pattern)| {
+                store_tags.get(tag).map_or(false, |value| parser::matches_pattern(pattern, value))
+            }))
+            .map(|(store_name, _)| store_name)
+            .collect();
+        stores.sort();
+        let names : Vec<String> = stores.iter().map(|store_name| format!("\"{}\"", store_name)).collect();
+        format!("[{}]\n", names.join(", "))
+    }
+
+    /// decimal places of a JSON response, of the query or else of the current store
+    pub fn decimals(&self, query: Option<usize>) -> Option<usize> {
+        query.or_else(|| self.global.read().unwrap().precisions.get(&self.current_store_name).cloned())
+    }
+
+    /// load a datastore file into memory
+    pub fn use_db(&mut self, store_name: &str) -> Option<()> {
+        if self.store.contains_key(store_name) {
+            self.current_store_name = store_name.to_owned();
+            self.require_use = false;
+            let current_store = self.get_current_store();
+            current_store.load();
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    /// return the count of the current store
+    pub fn count(&mut self) -> u64 {
+        let store = self.get_current_store();
+        store.count() 
+    }
+
+    /// Returns the total count of every item in memory
+    pub fn countall(&self) -> u64 {
+        let rdr = self.global.read().unwrap();
+        rdr.vec_store.iter().fold(0, |acc, (_name, tup)| acc + tup.1)
+    }
+
+    /// remove everything in the current store
+    pub fn clear(&mut self) {
+        self.get_current_store().clear();
+    }
+
+    /// remove everything in every store
+    pub fn clearall(&mut self) {
+        for store in self.store.values_mut() {
+            store.clear();
+        }
+    }
+
+    /// save current store to file, a GET afterwards reads its rows from the file
+    pub fn flush(&mut self) -> Result<(), String> {
+        self.get_current_store().flush_synced()
+    }
+
+    /// save all stores to corresponding files
+    /// Flush every store. The dtf files are written by FLUSH_THREADS workers
+    /// in parallel while inserts and reads continue, only taking and
+    /// dropping the flushed rows holds the lock.
+    pub fn flushall(&mut self) {
+        self.flush_where(|_| true);
+    }
+
+    /// Flush the stores whose name is `selected`, see `flushall`
+    fn flush_where<F: Fn(&str) -> bool>(&mut self, selected: F) {
+        let mut snapshots = Vec::new();
+        {
+            let mut wtr = self.global.write().unwrap();
+            utils::create_dir_if_not_exist(&wtr.settings.dtf_folder);
+            for store in self.store.values_mut().filter(|store| selected(&store.name)) {
+                if let Some(snapshot) = store.snapshot(&mut wtr) {
+                    snapshots.push(snapshot);
+                }
+            }
+        }
+
+        // ephemeral, materialized and backend stores, and those another connection is flushing
+        for store in self.store.values_mut().filter(|store| selected(&store.name)) {
+            if !snapshots.iter().any(|snapshot| snapshot.name == store.name) {
+                let _ = store.flush_synced();
+            }
+        }
+
+        let (tx, rx) = mpsc::channel();
+        {
+            let pool = ThreadPool::new(FLUSH_THREADS);
+            for snapshot in snapshots {
+                let tx = tx.clone();
+                pool.execute(move || {
+                    let written = write_snapshot(&snapshot);
+                    tx.send((snapshot, written)).unwrap();
+                });
+            }
+            // dropping the pool waits for every worker
+        }
+        drop(tx);
+
+        let mut wtr = self.global.write().unwrap();
+        for (snapshot, written) in rx {
+            flushed(&mut wtr, snapshot, written);
+        }
+    }
+
+    /// Apply the settings of a `HELLO`, all of them or none, see `hello`
+    pub fn hello(&mut self, hello: Hello) -> Result<String, String> {
+        if let Some(ref store_name) = hello.store {
+            let store_name = self.new_store_name(store_name)?;
+            self.ensure_store(&store_name)?;
+            self.use_db(&store_name);
+        }
+        if let Some(encoding) = hello.format {
+            self.encoding = encoding;
+        }
+        if let Some(protocol) = hello.protocol {
+            self.connection.set_protocol(hello::negotiate(protocol));
+        }
+        let store_name = if self.require_use { None } else { Some(self.current_store_name.as_str()) };
+        let global = self.global.read().unwrap();
+        Ok(hello::to_json(self.connection.protocol(), store_name, self.encoding, &global.settings))
+    }
+
+    /// Start a connection from `peer` on the store of `--default_store`,
+    /// created like `CREATE`. The `default` store keeps its rows in a file
+    /// of each connection, a named one in the file of the store.
+    pub fn use_default_store(&mut self, peer: &str) {
+        let store_name = match self.global.read().unwrap().settings.default_store_of(peer) {
+            Some(store_name) => store_name,
+            None => {
+                self.require_use = true;
+                return;
+            }
+        };
+        if store_name == "default" {
+            return;
+        }
+        if let Err(e) = parser::validate_name(&store_name) {
+            warn!("Refusing ADD without INTO from {}: {}", peer, e);
+            self.require_use = true;
+            return;
+        }
+        if !self.exists(&store_name) {
+            self.create(&store_name);
+        }
+        self.use_db(&store_name);
+    }
+
+    /// returns the current store as a mutable reference
+    fn get_current_store(&mut self) -> &mut Store {
+        self.store.get_mut(&self.current_store_name).expect("KEY IS NOT IN HASHMAP")
+    }
+
+    /// get n items in memory as JSON
+    pub fn get_n_as_json(&mut self, count: Option<u32>, range: Option<(u32, u32)>,
+                         decimals: Option<usize>) -> Option<String> {
+        let decimals = self.decimals(decimals);
+        match self.get_aux(count, range) {
+            Some(vecs) => Some(self.serialize(|| format!("[{}]\n", self.encode_json(&vecs, decimals, ", ")))),
+            None => None
+        }
+    }
+
+    /// get n items in memory as JSON Lines, one update per line
+    pub fn get_n_as_jsonl(&mut self, count: Option<u32>, range: Option<(u32, u32)>,
+                          decimals: Option<usize>) -> Option<String> {
+        let decimals = self.decimals(decimals);
+        match self.get_aux(count, range) {
+            Some(vecs) => Some(self.serialize(|| format!("{}\n", self.encode_json(&vecs, decimals, "\n")))),
+            None => None
+        }
+    }
+
+    /// rows as JSON objects joined by `separator`, encoded in chunks to
+    /// stop early when the command is interrupted
+    fn encode_json(&self, ups: &[Update], decimals: Option<usize>, separator: &str) -> String {
+        let mut objects = Vec::with_capacity(ups.len());
+        for chunk in ups.chunks(CHECK_EVERY) {
+            if self.interrupted() {
+                break;
+            }
+            objects.extend(chunk.iter().map(|up| up.to_json_with(decimals)));
+        }
+        objects.join(separator)
+    }
+
+    fn get_aux(&mut self, count: Option<u32>, range: Option<(u32, u32)>) -> Option<Vec<Update>> {
+        let ups = {
+            // rows dropped from memory by a flush are read from the file again, as `USE` does
+            let (shared_state, files) = self.store.get(&self.current_store_name).expect("KEY IS NOT IN HASHMAP")
+                .load_locked(&self.global);
+            let (vecs, _) = current_rows(&shared_state, &self.current_store_name, files);
+            self.get_aux_unprofiled(&vecs, &shared_state.amendments_of(&self.current_store_name), count, range)
+        };
+        let rows = ups.as_ref().map_or(0, |ups| ups.len());
+        self.profile_read(&self.current_store_name, range, rows);
+        ups
+    }
+
+    fn get_aux_unprofiled(&self, vecs: &[Update], amendments: &Amendments, count: Option<u32>, range: Option<(u32, u32)>) -> Option<Vec<Update>> {
+        if range.is_some() || !amendments.is_empty() {
+            let filtered = vecs.iter()
+                .enumerate()
+                .take_while(|&(i, _)| i % CHECK_EVERY != 0 || !self.interrupted())
+                .map(|(_, up)| up)
+                .filter(|up| in_range(up, range))
+                .filter_map(|up| amendments.apply(up));
+            return match count {
+                Some(count) => Some(filtered.take(count as usize).collect()),
+                None => Some(filtered.collect())
+            };
+        }
+
+        match count {
+            Some(count) => {
+                if (vecs.len() as u32) < count || vecs.is_empty() {
+                    return None
+                }
+                Some(vecs[..count as usize].to_vec())
+            },
+            None => Some(vecs.to_vec()) // XXX: very inefficient, ok with small n
+        }
+    }
+
+    /// get `count` items from the current store
+    pub fn get(&mut self, count: Option<u32>, range: Option<(u32, u32)>) -> Option<Vec<u8>> {
+        let mut bytes : Vec<u8> = Vec::new();
+        let encoding = self.encoding;
+        match self.get_aux(count, range) {
+            Some(ref vecs) if encoding == Encoding::Flat => Some(self.serialize(|| dtf::encode_flat(vecs))),
+            Some(ref vecs) if encoding == Encoding::Msgpack => Some(self.serialize(|| dtf::encode_msgpack(vecs))),
+            Some(ref vecs) if vecs.is_empty() => None,
+            Some(vecs) => { self.serialize(|| dtf::write_batches(&mut bytes, &vecs)); Some(bytes) },
+            None => None
+        }
+    }
+
+    /// get at most `limit` items after `cursor`, the (ts, seq) of the last
+    /// row of the previous page
+    ///
+    /// Pages are in (ts, seq) order, so rows inserted meanwhile don't shift
+    /// the next page. Only the requested page is copied out of the store.
+    /// Returns the page and the cursor of the next page, which is `None` on
+    /// the last page.
+    pub fn get_page(&mut self, count: Option<u32>, range: Option<(u32, u32)>,
+                    limit: u32, cursor: Option<(u64, u32)>) -> (Vec<Update>, Option<(u64, u32)>) {
+        let (page, next_cursor) = {
+            let (shared_state, files) = self.store.get(&self.current_store_name).expect("KEY IS NOT IN HASHMAP")
+                .load_locked(&self.global);
+            let (vecs, sorted) = current_rows(&shared_state, &self.current_store_name, files);
+            let amendments = shared_state.amendments_of(&self.current_store_name);
+            self.get_page_unprofiled(&vecs, sorted, &amendments, count, range, limit, cursor)
+        };
+        self.profile_read(&self.current_store_name, range, page.len());
+        (page, next_cursor)
+    }
+
+    fn get_page_unprofiled(&self, vecs: &[Update], sorted: bool, amendments: &Amendments, count: Option<u32>,
+                           range: Option<(u32, u32)>, limit: u32, cursor: Option<(u64, u32)>) -> (Vec<Update>, Option<(u64, u32)>) {
+        let is_after = |up: &Update| cursor.map_or(true, |cursor| key(up) > cursor);
+        let is_unfiltered = range.is_none() && amendments.is_empty();
+        // rows in order are found from the cursor on by binary search
+        let start = if sorted { vecs.partition_point(|up| !is_after(up)) } else { 0 };
+
+        // the rows of the previous pages count towards `count`
+        let total = count.map_or(u64::max_value(), u64::from);
+        let returned = match (count, cursor) {
+            (None, _) | (_, None) => 0,
+            (Some(_), Some(_)) if sorted && is_unfiltered => start as u64,
+            (Some(_), Some(_)) => vecs.iter()
+                .filter(|up| !is_after(up) && in_range(up, range))
+                .filter(|up| amendments.apply(up).is_some())
+                .count() as u64,
+        };
+        if returned >= total {
+            return (Vec::new(), None);
+        }
+
+        let page_len = cmp::min(u64::from(limit), total - returned) as usize;
+        let rows = vecs[start..].iter()
+            .enumerate()
+            .take_while(|&(i, _)| i % CHECK_EVERY != 0 || !self.interrupted())
+            .map(|(_, up)| up)
+            .filter(|up| is_after(up) && in_range(up, range))
+            .filter_map(|up| amendments.apply(up));
+        // peek one more to know if there is a next page
+        let mut page : Vec<Update> = if sorted {
+            rows.take(page_len + 1).collect()
+        } else {
+            lowest(rows, page_len + 1)
+        };
+
+        let has_more = page.len() > page_len && returned + (page_len as u64) < total;
+        page.truncate(page_len);
+        let next_cursor = if has_more { page.last().map(key) } else { None };
+
+        (page, next_cursor)
+    }
+
+    /// get a page as JSON
+    ///
+    /// {"cursor": "1505177459685:139010", "data": [...]}
+    ///
+    /// cursor is null on the last page.
+    pub fn get_page_as_json(&mut self, count: Option<u32>, range: Option<(u32, u32)>,
+                            limit: u32, cursor: Option<(u64, u32)>, decimals: Option<usize>) -> String {
+        let decimals = self.decimals(decimals);
+        let (page, next_cursor) = self.get_page(count, range, limit, cursor);
+        let next_cursor = match next_cursor {
+            Some((ts, seq)) => format!("\"{}:{}\"", ts, seq),
+            None => "null".to_owned()
+        };
+        let data = self.serialize(|| dtf::update_vec_to_json_with(&page, decimals));
+        format!(r#"{{"cursor": {}, "data": [{}]}}"#, next_cursor, data) + "\n"
+    }
+
+    /// get a page as JSON Lines
+    ///
+    /// One update per line, the last line is {"cursor": "1505177459685:139010"}, null on the last page.
+    pub fn get_page_as_jsonl(&mut self, count: Option<u32>, range: Option<(u32, u32)>,
+                             limit: u32, cursor: Option<(u64, u32)>, decimals: Option<usize>) -> String {
+        let decimals = self.decimals(decimals);
+        let (page, next_cursor) = self.get_page(count, range, limit, cursor);
+        let next_cursor = match next_cursor {
+            Some((ts, seq)) => format!("\"{}:{}\"", ts, seq),
+            None => "null".to_owned()
+        };
+        let mut lines = self.serialize(|| dtf::update_vec_to_jsonl_with(&page, decimals));
+        if !lines.is_empty() {
+            lines.push('\n');
+        }
+        lines + &format!(r#"{{"cursor": {}}}"#, next_cursor) + "\n"
+    }
+
+    /// get a page as bytes
+    ///
+    /// The ts and seq of the next cursor are written as a u64 and a u32 (both
+    /// 0 on the last page), followed by one batch. An empty page is a single
+    /// 0x0 byte. With `FORMAT MSGPACK` the cursor is followed by the page as a
+    /// MessagePack array instead.
+    pub fn get_page_as_bytes(&mut self, count: Option<u32>, range: Option<(u32, u32)>,
+                             limit: u32, cursor: Option<(u64, u32)>) -> Vec<u8> {
+        let (page, next_cursor) = self.get_page(count, range, limit, cursor);
+        let mut bytes : Vec<u8> = Vec::new();
+        let (ts, seq) = next_cursor.unwrap_or((0, 0));
+        let _ = bytes.write_u64::<BigEndian>(ts);
+        let _ = bytes.write_u32::<BigEndian>(seq);
+        if self.encoding == Encoding::Msgpack {
+            bytes.extend(self.serialize(|| dtf::encode_msgpack(&page)));
+        } else if page.is_empty() {
+            let _ = bytes.write_u8(0x0);
+        } else {
+            self.serialize(|| dtf::write_batches(&mut bytes, &page));
+        }
+        bytes
+    }
+
+    /// create a new store
+    pub fn new(global: &Global) -> State {
+        let mut state = State {
+            current_store_name: "default".to_owned(),
+            require_use: false,
+            encoding: Encoding::DTF,
+            strict: false,
+            profile: RefCell::new(Profile::default()),
+            connection: Arc::new(Connection::new(0, "")),
+            timeout: None,
+            deadline: Cell::new(None),
+            interruption: Cell::new(None),
+            bulkadd_db: None,
+            bulkadd_pending: None,
+            transaction: None,
+            is_adding: false,
+            store: HashMap::new(),
+            global: global.clone()
+        };
+
+        // insert default first, if there is a copy in memory this will be replaced
+        state.store.insert("default".to_owned(), Store {
+            name: "default".to_owned(),
+            fname: format!("{}--default", Uuid::new_v4()),
+            global: global.clone()
+        });
+
+        let rdr = global.read().unwrap();
+        for (store_name, _vec) in &rdr.vec_store {
+            state.store.insert(store_name.to_owned(), Store {
+                name: store_name.to_owned(),
+                fname: rdr.catalog.get(store_name)
+                    .map(|entry| entry.fname.clone())
+                    .unwrap_or_else(|| format!("{}--{}", Uuid::new_v4(), store_name)),
+                global: global.clone()
+            });
+        }
+        state
+    }
+}
+
+/// the work of a job, `names` are the stores a chunked job goes through
+fn run_job(global: &Global, job: &job::Job, names: &[String]) -> Result<String, String> {
+    let mut state = State::new(global);
+    // the stores of the files without a catalog entry, as for a connection
+    utils::init_dbs(&mut state);
+    match job.kind {
+        job::Kind::FlushAll | job::Kind::ClearAll => {
+            for chunk in names.chunks(job::JOB_CHUNK) {
+                if job.is_cancelled() {
+                    break;
+                }
+                let chunk : HashSet<&str> = chunk.iter().map(|name| name.as_str()).collect();
+                if job.kind == job::Kind::FlushAll {
+                    state.flush_where(|name| chunk.contains(name));
+                } else {
+                    for store in state.store.values_mut().filter(|store| chunk.contains(store.name.as_str())) {
+                        store.clear();
+                    }
+                }
+                job.progress(chunk.len());
+            }
+            Ok(format!("{} stores.", job.done()))
+        },
+        job::Kind::Compact(ref store) => {
+            let removed = state.compact(store)?;
+            job.progress(1);
+            Ok(format!("Removed {} rows.", removed))
+        },
+        job::Kind::Prune => {
+            let removed = retention::prune(global);
+            job.progress(1);
+            Ok(format!("Pruned {} rows.", removed))
+        },
+        job::Kind::Integrity(mode) => {
+            let dtf_folder = global.read().unwrap().settings.dtf_folder.clone();
+            let report = integrity::scan(&dtf_folder, mode, false);
+            let message = format!("{} files, {} corrupt.", report.scanned, report.corrupt.len());
+            global.write().unwrap().integrity = report;
+            job.progress(1);
+            Ok(message)
+        },
+        job::Kind::Backup(ref folder, incremental) => {
+            let message = state.backup(folder, incremental)?;
+            job.progress(1);
+            Ok(message)
+        },
+    }
+}
+
+/// true if `up` falls within `range` (inclusive, in seconds)
+fn in_range(up: &Update, range: Option<(u32, u32)>) -> bool {
+    match range {
+        Some((min, max)) => up.ts >= u64::from(min) * 1000 && up.ts <= u64::from(max) * 1000,
+        None => true
+    }
+}
+
+/// (updates, count)
+pub type VecStore = (Vec<Update>, u64);
+
+/// key: btc_neo
+///      btc_eth
+///      ..
+///      total
+pub type History = HashMap<String, Vec<(SystemTime, u64)>>;
+
+
+/// Rows of a `BULKADD ... COUNT n [CRC x]` held back until DDAKLUB
+/// so a truncated or corrupted upload is rejected as a whole.
+#[derive(Debug)]
+pub struct PendingBulkAdd {
+    pub declared: BulkDeclaration,
+    pub rows: Vec<Update>,
+    /// CRC-32 of the row lines received so far
+    pub crc: u32,
+    /// row lines that could not be parsed
+    pub malformed: u64,
+}
+
+impl PendingBulkAdd {
+    pub fn new(declared: BulkDeclaration) -> PendingBulkAdd {
+        PendingBulkAdd {
+            declared,
+            rows: Vec::new(),
+            crc: 0,
+            malformed: 0,
+        }
+    }
+
+    /// account for one row line as received
+    pub fn push(&mut self, line: &str, up: Option<Update>) {
+        self.crc = dtf::crc32_update(self.crc, line.as_bytes());
+        self.crc = dtf::crc32_update(self.crc, b"\n");
+        match up {
+            Some(up) => self.rows.push(up),
+            None => self.malformed += 1,
+        }
+    }
+
+    pub fn verify(&self) -> Result<(), String> {
+        if self.malformed > 0 {
+            return Err(format!("BULKADD rejected, {} malformed rows.", self.malformed));
+        }
+        let received = self.rows.len() as u64;
+        if received != self.declared.count {
+            return Err(format!("BULKADD rejected, declared {} rows, received {}.", self.declared.count, received));
+        }
+        match self.declared.crc {
+            Some(crc) if crc != self.crc =>
+                Err(format!("BULKADD rejected, declared CRC {:08x}, received {:08x}.", crc, self.crc)),
+            _ => Ok(())
+        }
+    }
+}
+
+/// how often `GET ... AFTER` checks the commit offset
+static OFFSET_POLL_MS : u64 = 5;
+
+/// secs a refused full scan is told to wait, the lag is averaged over the last inserts
+static SHED_RETRY_SECS : u64 = 1;
+
+/// how many idempotency keys are remembered
+static MAX_RECENT_KEYS : usize = 100_000;
+
+/// Bounded set of the most recent idempotency keys.
+/// The oldest key is forgotten when the set is full.
+#[derive(Debug)]
+pub struct RecentKeys {
+    set: HashSet<String>,
+    order: VecDeque<String>,
+    capacity: usize,
+}
+
+impl RecentKeys {
+    pub fn new(capacity: usize) -> RecentKeys {
+        RecentKeys {
+            set: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.set.contains(key)
+    }
+
+    /// returns false if the key was already seen
+    pub fn insert(&mut self, key: &str) -> bool {
+        if self.set.contains(key) {
+            return false;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.set.remove(&oldest);
+            }
+        }
+        self.set.insert(key.to_owned());
+        self.order.push_back(key.to_owned());
+        true
+    }
+}
+
+/// Newest (ts, seq) of a store, maintained on insert, flush and clear
+#[derive(Debug, Default, Clone)]
+pub struct Watermark {
+    pub memory: Option<(u64, u32)>,
+    pub disk: Option<(u64, u32)>,
+}
+
+impl Watermark {
+    pub fn add(&mut self, up: &Update) {
+        self.memory = cmp::max(self.memory, Some((up.ts, up.seq)));
+    }
+
+    /// everything in memory is now on disk
+    pub fn flush(&mut self) {
+        self.disk = cmp::max(self.disk, self.memory);
+        self.memory = None;
+    }
+
+    /// rows up to `upto` are now on disk, newer rows may still be in memory
+    pub fn flush_upto(&mut self, upto: Option<(u64, u32)>, in_memory: bool) {
+        self.disk = cmp::max(self.disk, upto);
+        if !in_memory {
+            self.memory = None;
+        }
+    }
+}
+
+/// Rows sorted by (ts, seq) without exact duplicates. Rows of an order book
+/// snapshot share (ts, seq), so only equal rows count as duplicates.
+fn sorted_unique(mut ups: Vec<Update>) -> Vec<Update> {
+    ups.sort_by_key(|up| (up.ts, up.seq));
+    let mut unique : Vec<Update> = Vec::with_capacity(ups.len());
+    for up in ups {
+        let seen = unique.iter().rev()
+            .take_while(|kept| (kept.ts, kept.seq) == (up.ts, up.seq))
+            .any(|kept| *kept == up);
+        if !seen {
+            unique.push(up);
+        }
+    }
+    unique
+}
+
+/// number of threads writing dtf files in `flushall`
+static FLUSH_THREADS : usize = 8;
+
+/// ms between two looks at a store another connection is flushing, see `Store::flush_synced`
+static FLUSH_POLL_MS : u64 = 5;
+
+/// length of a file, 0 if there is none
+fn file_len(fname: &str) -> u64 {
+    fs::metadata(fname).map(|m| m.len()).unwrap_or(0)
+}
+
+/// The rows of a store in memory, after those `Store::load_locked` read
+/// from the file while a flush writes it, and whether they are in (ts, seq)
+/// order, see `SharedState::unsorted`.
+fn current_rows<'a>(shared_state: &'a SharedState, store_name: &str, files: Option<Vec<Update>>) -> (Cow<'a, [Update]>, bool) {
+    let vecs = &shared_state.vec_store.get(store_name).expect("Key is not in vec_store").0;
+    let sorted = !shared_state.unsorted.contains(store_name);
+    match files {
+        Some(mut files) => {
+            let sorted = sorted && is_sorted(&files) && follows(&files, vecs);
+            files.extend(vecs.iter().cloned());
+            (Cow::Owned(files), sorted)
+        }
+        None => (Cow::Borrowed(vecs), sorted)
+    }
+}
+
+/// (ts, seq) of a row, the order of pages, see `State::get_page`
+fn key(up: &Update) -> (u64, u32) {
+    (up.ts, up.seq)
+}
+
+fn is_sorted(ups: &[Update]) -> bool {
+    ups.windows(2).all(|pair| key(&pair[0]) <= key(&pair[1]))
+}
+
+/// whether `next` may follow `ups` in (ts, seq) order
+fn follows(ups: &[Update], next: &[Update]) -> bool {
+    match (ups.last(), next.first()) {
+        (Some(last), Some(first)) => key(last) <= key(first),
+        _ => true
+    }
+}
+
+/// The `n` rows with the lowest (ts, seq), in order, of rows in any order.
+/// Holds at most 2n of them at once.
+fn lowest<I: Iterator<Item = Update>>(rows: I, n: usize) -> Vec<Update> {
+    let mut lowest = Vec::with_capacity(2 * n);
+    for up in rows {
+        lowest.push(up);
+        if lowest.len() >= 2 * n {
+            lowest.sort_by_key(key);
+            lowest.truncate(n);
+        }
+    }
+    lowest.sort_by_key(key);
+    lowest.truncate(n);
+    lowest
+}
+
+/// Ends the flush of a store that did not finish, e.g. writing its file
+/// panicked, so the store is flushed again later, see `SharedState::flush_failed`
+#[derive(Debug)]
+struct FlushGuard {
+    global: Global,
+    name: String,
+    /// until `flushed`
+    armed: bool,
+}
+
+impl Drop for FlushGuard {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        // a lock poisoned by the panic is left as it is
+        if let Ok(mut wtr) = self.global.write() {
+            wtr.flush_failed(&self.name);
+        }
+    }
+}
+
+/// Rows of a store taken by `flush` or `flushall`, written to `fname` outside the lock
+#[derive(Debug)]
+struct FlushSnapshot {
+    guard: FlushGuard,
+    name: String,
+    fname: String,
+    rows: Vec<Update>,
+    /// `SharedState::clears` of the store when its rows were taken
+    clears: u64,
+    /// dtf_folder_mirror
+    mirror: Option<String>,
+    /// bytes from which the rows are written with O_DIRECT, see `direct`
+    direct_io: u64,
+    /// whether the file is sealed as a partition first, see `seal`
+    seal: bool,
+}
+
+/// Drop the rows of a written snapshot from memory, see `write_snapshot`,
+/// and give its buffer back to the pool. The rows stay in memory if the file
+/// could not be written. Returns whether it was.
+fn flushed(wtr: &mut SharedState, mut snapshot: FlushSnapshot, written: io::Result<Written>) -> bool {
+    snapshot.guard.armed = false;
+    let (is_new_file, appended, recorded, mirrored) = match written {
+        Ok(written) => written,
+        Err(e) => {
+            error!("Unable to flush {}: {:?}", snapshot.name, e);
+            wtr.flush_failed(&snapshot.name);
+            wtr.buffer_pool.give(snapshot.rows);
+            return false;
+        }
+    };
+    if recorded.is_none() || !mirrored {
+        wtr.flush_errors += 1;
+    }
+    if let Some(epoch) = recorded {
+        wtr.cdc.push(&Event::Flush { store: snapshot.name.clone(), epoch: epoch.epoch, count: epoch.count });
+    }
+    *wtr.flush_count.entry(snapshot.name.to_owned()).or_insert(0) += 1;
+    // the rows in memory were all inserted after a CLEAR while writing
+    let cleared = wtr.clears.get(&snapshot.name).cloned().unwrap_or(0) != snapshot.clears;
+    {
+        let SharedState { ref mut vec_store, ref mut stats, .. } = *wtr;
+        let vecs = vec_store.get_mut(&snapshot.name).expect("KEY IS NOT IN HASHMAP");
+        // rows inserted while writing stay in memory
+        let flushed = if cleared { 0 } else { cmp::min(snapshot.rows.len(), vecs.0.len()) };
+        vecs.0.drain(..flushed);
+        if is_new_file {
+            let mut summary = Summary::new();
+            summary.add(&snapshot.rows);
+            stats.insert(snapshot.name.to_owned(), summary);
+        } else if let Some(summary) = stats.get_mut(&snapshot.name) {
+            summary.add(&appended);
+        }
+    }
+    // a loaded store flushes the rows of its file too, the rows inserted while writing are left
+    if !cleared {
+        wtr.forget_unflushed(&snapshot.name, snapshot.rows.len());
+    }
+    let in_memory = !wtr.vec_store.get(&snapshot.name).expect("KEY IS NOT IN HASHMAP").0.is_empty();
+    if !in_memory {
+        wtr.unsorted.remove(&snapshot.name);
+    }
+    let upto = snapshot.rows.iter().map(|up| (up.ts, up.seq)).max();
+    wtr.watermark_mut(&snapshot.name).flush_upto(upto, in_memory);
+    wtr.flushing.remove(&snapshot.name);
+    wtr.flushing_from.remove(&snapshot.name);
+    wtr.result_cache.invalidate(&snapshot.name, None);
+    wtr.buffer_pool.give(snapshot.rows);
+    true
+}
+
+/// Whether the file is the first of the store, the rows written otherwise
+/// that were not on disk yet, the flush epoch and whether the mirror was
+/// written, see `write_snapshot`
+type Written = (bool, Vec<Update>, Option<epoch::Epoch>, bool);
+
+/// Write a snapshot to its dtf file, record the flush epoch and mirror the file.
+fn write_snapshot(snapshot: &FlushSnapshot) -> io::Result<Written> {
+    if snapshot.seal {
+        seal(snapshot);
+    }
+    // a loaded store holds the rows of its sealed partitions too
+    let unsealed = partition::unsealed(&snapshot.fname, &snapshot.rows);
+    let rows = unsealed.as_ref().map_or(&snapshot.rows[..], |rows| &rows[..]);
+    let (is_new_file, appended, old_len) = if Path::new(&snapshot.fname).exists() {
+        // append only writes what's newer than the file
+        let old_len = fs::metadata(&snapshot.fname).map(|m| m.len()).unwrap_or(0);
+        let appended = direct::append(&snapshot.fname, rows, snapshot.direct_io)?;
+        filestats::appended(&snapshot.fname, old_len, &appended);
+        zonemap::appended(&snapshot.fname, old_len);
+        (false, appended, old_len)
+    } else {
+        direct::encode(&snapshot.fname, &snapshot.name, rows, snapshot.direct_io)?;
+        filestats::written(&snapshot.fname, rows);
+        zonemap::written(&snapshot.fname);
+        (true, Vec::new(), 0)
+    };
+    let recorded = match epoch::record(&snapshot.fname) {
+        Ok(epoch) => Some(epoch),
+        Err(e) => {
+            error!("Unable to record flush epoch of {}: {:?}", snapshot.name, e);
+            None
+        }
+    };
+    let mirrored = match snapshot.mirror {
+        Some(ref mirror_folder) => {
+            let result = if is_new_file {
+                mirror::written(&snapshot.fname, mirror_folder)
+            } else {
+                mirror::appended(&snapshot.fname, old_len, mirror_folder)
+            };
+            result.map_err(|e| error!("Unable to mirror {}: {:?}", snapshot.name, e)).is_ok()
+        },
+        None => true
+    };
+    direct::evict(&snapshot.fname, old_len, rows.len(), snapshot.direct_io);
+    Ok(match unsealed {
+        // the file after a partition, its rows are added to the stats of the store
+        Some(unsealed) if is_new_file => (false, unsealed, recorded, mirrored),
+        _ => (is_new_file, appended, recorded, mirrored)
+    })
+}
+
+/// Seal the file of a store being flushed as a partition, without holding
+/// the shared state. Reads meanwhile take the rows of the file up to
+/// `flushing_from`, or of the partition once the file is moved, and are
+/// read again once `flushing_from` drops to the empty file.
+fn seal(snapshot: &FlushSnapshot) {
+    let sealed = match partition::seal(&snapshot.fname) {
+        Ok(sealed) => sealed,
+        Err(e) => {
+            error!("Unable to seal the file of {}: {:?}", snapshot.name, e);
+            return;
+        }
+    };
+    info!("Sealed the file of {} as {}", snapshot.name, sealed);
+    let mirrored = match snapshot.mirror {
+        Some(ref mirror_folder) => partition::mirror_written(&sealed, mirror_folder)
+            .and_then(|_| mirror::removed(&snapshot.fname, mirror_folder))
+            .map_err(|e| error!("Unable to mirror {}: {:?}", sealed, e))
+            .is_ok(),
+        None => true
+    };
+    let mut wtr = snapshot.guard.global.write().unwrap();
+    wtr.flushing_from.insert(snapshot.name.to_owned(), 0);
+    if !mirrored {
+        wtr.flush_errors += 1;
+    }
+}
+
+/// Rows of a store older than `ms` are pruned from memory and from `fname`
+#[derive(Clone, Debug)]
+pub struct Ttl {
+    pub ms: u64,
+    pub fname: String,
+}
+
+/// Rows of a store older than `ms` are moved from `fname` to the same file in `cold_folder`
+#[derive(Clone, Debug)]
+pub struct Tier {
+    pub ms: u64,
+    pub cold_folder: String,
+    pub fname: String,
+}
+
+/// A store of candles continuously derived from the trades of `source`
+#[derive(Debug)]
+pub struct Materialized {
+    pub source: String,
+    pub candles: Candles,
+}
+
+#[derive(Debug)]
+pub struct SharedState {
+    pub n_cxns: u16,
+    pub settings: Settings,
+    pub vec_store: HashMap<String, VecStore>,
+    pub history: History,
+    pub recent_keys: RecentKeys,
+    /// materialized store name -> definition
+    pub materialized: HashMap<String, Materialized>,
+    /// virtual store name -> definition, see `derived`
+    pub virtuals: derived::Virtuals,
+    /// store name -> newest (ts, seq)
+    pub watermarks: HashMap<String, Watermark>,
+    /// store name -> statistics of flushed rows
+    pub stats: HashMap<String, Summary>,
+    /// store name -> deleted ranges not yet compacted
+    pub tombstones: HashMap<String, Vec<Tombstone>>,
+    /// store name -> corrected rows not yet compacted
+    pub corrections: HashMap<String, Corrections>,
+    /// where flushed rows go instead of dtf files
+    pub backend: Option<Arc<StorageBackend>>,
+    /// stores created with `CREATE [db] MEMORY`
+    pub ephemeral: HashSet<String>,
+    /// store name -> retention of stores created with `CREATE [db] TTL`
+    pub ttls: HashMap<String, Ttl>,
+    /// store name -> cold folder of stores configured with `TIER`
+    pub tiers: HashMap<String, Tier>,
+    /// stores whose dtf file `flushall` is writing, or which are being compacted
+    pub flushing: HashSet<String>,
+    /// store name -> length of its dtf file when the running flush took its rows, see `Store::load_locked`
+    pub flushing_from: HashMap<String, u64>,
+    /// store name -> flushes that dropped its rows from memory since start, see `flushes`
+    pub flush_count: HashMap<String, u64>,
+    /// store name -> `flushes` when the rows of its files were read into memory, see `is_loaded`
+    pub loaded: HashMap<String, u64>,
+    /// store name -> times its rows were cleared from memory, see `FlushSnapshot::clears`
+    pub clears: HashMap<String, u64>,
+    /// stores whose rows in memory may not be in (ts, seq) order, see `State::get_page`
+    pub unsorted: HashSet<String>,
+    /// store name -> throttle of the inserts of stores being compacted
+    pub compacting: HashMap<String, Throttle>,
+    /// store name -> fname of stores due for the next group commit
+    pub dirty: HashMap<String, String>,
+    /// records received commands, see `--journal`
+    pub journal: Option<Journal>,
+    /// alternative names of stores, see `alias`
+    pub aliases: Aliases,
+    /// group -> pattern of its stores, see `group`
+    pub groups: Groups,
+    /// every created store, see `catalog`
+    pub catalog: Catalog,
+    /// store name -> decimal places of JSON responses, see `PRECISION`
+    pub precisions: Precisions,
+    /// store name -> tags, see `META`
+    pub tags: Tags,
+    /// store name -> names of the flags of its rows, see `FLAGS`
+    pub flags: flag::Names,
+    /// store name -> kind of the auxiliary series it holds, see `series`
+    pub series: series::Kinds,
+    /// rows applied since start, the commit offset returned by write acks
+    pub offset: u64,
+    /// publisher of the stores selected with `MULTICAST`
+    pub multicast: Option<Multicast>,
+    /// store name -> secs since epoch of the last insert, see `--silence_alert`
+    pub last_write: HashMap<String, u64>,
+    /// store name -> secs since epoch of the last read or load, see `--unload_idle`
+    pub last_read: HashMap<String, u64>,
+    /// store name -> rows inserted and not flushed yet, see `--flush_memory`
+    pub unflushed: HashMap<String, usize>,
+    /// sum of `unflushed`
+    pub unflushed_rows: usize,
+    /// primary or standby of an HA pair, see `lease`
+    pub role: Role,
+    /// rules added with `ALERT`
+    pub alerts: Vec<Rule>,
+    /// flushes that failed since start
+    pub flush_errors: u64,
+    /// store name -> recent rows of subscribed stores, see `SUBSCRIBE`
+    pub feeds: HashMap<String, Feed>,
+    /// changes to the stores, see `SUBSCRIBE __cdc__`
+    pub cdc: Cdc,
+    /// files kept open between writes, see `--max_open_files`
+    pub handles: FileHandles,
+    /// findings of `--integrity_scan`
+    pub integrity: Report,
+    /// commands added by embedding code, see `extension`
+    pub extensions: Arc<Extensions>,
+    /// command type -> handler latencies, see `PERF LATENCY`
+    pub latencies: Latencies,
+    /// average latency of inserts, see `--shed_reads_above`
+    pub ingest_lag: IngestLag,
+    /// commands slower than `--slowlog_threshold`, see `SLOWLOG GET`
+    pub slowlog: SlowLog,
+    /// id -> open connection, see `CONNECTIONS`
+    pub connections: BTreeMap<u64, Arc<Connection>>,
+    /// id of the last connection
+    pub last_connection_id: u64,
+    /// responses of repeated reads, see `cache`
+    pub result_cache: ResultCache,
+    /// buffers of flush snapshots, see `pool`
+    pub buffer_pool: BufferPool,
+    /// `FLUSH ALL ASYNC` and `CLEAR ALL ASYNC`, see `job`
+    pub jobs: Jobs,
+}
+
+/// Deletions and corrections of a store that reads must apply
+pub struct Amendments<'a> {
+    pub tombstones: &'a [Tombstone],
+    pub corrections: Option<&'a Corrections>,
+}
+
+impl<'a> Amendments<'a> {
+    pub fn is_empty(&self) -> bool {
+        self.tombstones.is_empty() && self.corrections.map_or(true, |c| c.is_empty())
+    }
+
+    /// the row as it should be read, or None if it was deleted
+    pub fn apply(&self, up: &Update) -> Option<Update> {
+        if tombstone::is_deleted(up, self.tombstones) {
+            return None;
+        }
+        match self.corrections.and_then(|c| c.get(&(up.ts, up.seq))) {
+            Some(corrected) => Some(corrected.clone()),
+            None => Some(up.clone())
+        }
+    }
+}
+
+impl SharedState {
+    pub fn new(settings: Settings) -> SharedState {
+        let mut hashmap = HashMap::new();
+        hashmap.insert("default".to_owned(), (Vec::new(),0) );
+        let backend = backend::open(&settings);
+        let journal = settings.journal.as_ref().map(|path| {
+            Journal::open(path).expect("Unable to open journal")
+        });
+        // corrupt files are moved away before anything reads them
+        let integrity = integrity::scan(&settings.dtf_folder, settings.integrity_scan, true);
+        let aliases = alias::read(&settings.dtf_folder);
+        let groups = group::read(&settings.dtf_folder);
+        let catalog = catalog::read(&settings.dtf_folder);
+        let precisions = precision::read(&settings.dtf_folder);
+        let tags = tag::read(&settings.dtf_folder);
+        let flags = flag::read(&settings.dtf_folder);
+        let series = series::read(&settings.dtf_folder);
+        let virtuals = derived::read(&settings.dtf_folder);
+        // the candles of materialized stores are in their dtf files
+        let materialized = materialized::read(&settings.dtf_folder).into_iter()
+            .map(|(name, (source, minutes))| {
+                let ups = match catalog.get(&name) {
+                    Some(entry) => {
+                        let fname = utils::dtf_fname(&settings.dtf_folder, &entry.fname);
+                        if Path::new(&fname).exists() { dtf::decode(&fname, None) } else { Vec::new() }
+                    },
+                    None => Vec::new()
+                };
+                let candles = Candles::from_updates(&ups, minutes);
+                let size = ups.len() as u64;
+                hashmap.insert(name.to_owned(), (ups, size));
+                (name, Materialized { source, candles })
+            })
+            .collect();
+        let alerts = alert::read_rules(&settings.dtf_folder);
+        let feeds = feed::open_all(&settings.dtf_folder, settings.replay_window);
+        let cdc = Cdc::open(&settings.dtf_folder, settings.replay_window);
+        // offsets keep growing across restarts so subscribers can resume
+        let offset = feeds.values().filter_map(|feed| feed.newest()).max().unwrap_or(0);
+        let handles = FileHandles::new(settings.max_open_files);
+        let multicast = settings.multicast.as_ref().map(|group| {
+            Multicast::open(group).expect("Unable to open multicast publisher")
+        });
+        // a node of an HA pair accepts writes once it holds the lease
+        let role = match settings.ha_lease {
+            Some(_) => Role::Standby { term: 0, primary: String::new() },
+            None => Role::Single
+        };
+        SharedState {
+            n_cxns: 0,
+            settings,
+            vec_store: hashmap,
+            history: HashMap::new(),
+            recent_keys: RecentKeys::new(MAX_RECENT_KEYS),
+            materialized,
+            virtuals,
+            watermarks: HashMap::new(),
+            stats: HashMap::new(),
+            tombstones: HashMap::new(),
+            corrections: HashMap::new(),
+            backend,
+            ephemeral: HashSet::new(),
+            ttls: HashMap::new(),
+            tiers: HashMap::new(),
+            flushing: HashSet::new(),
+            flushing_from: HashMap::new(),
+            flush_count: HashMap::new(),
+            loaded: HashMap::new(),
+            clears: HashMap::new(),
+            unsorted: HashSet::new(),
+            compacting: HashMap::new(),
+            dirty: HashMap::new(),
+            journal,
+            aliases,
+            groups,
+            catalog,
+            precisions,
+            tags,
+            flags,
+            series,
+            offset,
+            multicast,
+            last_write: HashMap::new(),
+            last_read: HashMap::new(),
+            unflushed: HashMap::new(),
+            unflushed_rows: 0,
+            role,
+            alerts,
+            flush_errors: 0,
+            feeds,
+            cdc,
+            handles,
+            integrity,
+            extensions: Arc::new(Extensions::new()),
+            latencies: Latencies::default(),
+            ingest_lag: IngestLag::default(),
+            slowlog: SlowLog::default(),
+            connections: BTreeMap::new(),
+            last_connection_id: 0,
+            result_cache: ResultCache::default(),
+            buffer_pool: BufferPool::default(),
+            jobs: Jobs::default(),
+        }
+    }
+
+    /// bytes of rows held in memory by all stores
+    pub fn memory_used(&self) -> u64 {
+        let rows : usize = self.vec_store.values().map(|vecs| vecs.0.len()).sum();
+        (rows * mem::size_of::<Update>()) as u64
+    }
+
+    /// `store_name` was just read, see `--unload_idle`
+    pub fn record_read(&mut self, store_name: &str) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.last_read.insert(store_name.to_owned(), now);
+    }
+
+    /// `rows` of the unflushed rows of `store_name` were flushed or dropped
+    pub fn forget_unflushed(&mut self, store_name: &str, rows: usize) {
+        let left = match self.unflushed.get_mut(store_name) {
+            Some(unflushed) => {
+                let rows = cmp::min(rows, *unflushed);
+                *unflushed -= rows;
+                self.unflushed_rows -= rows;
+                *unflushed
+            }
+            None => return
+        };
+        if left == 0 {
+            self.unflushed.remove(store_name);
+        }
+    }
+
+    /// What `store_name` adds to the row schema, see `SCHEMA`
+    pub fn schema_store(&self, store_name: &str) -> schema::Store {
+        schema::Store {
+            memory: self.ephemeral.contains(store_name),
+            materialized_from: self.materialized.get(store_name).map(|materialized| materialized.source.clone()),
+            ttl_ms: self.ttls.get(store_name).map(|ttl| ttl.ms),
+            precision: self.precisions.get(store_name).cloned(),
+            aliases: {
+                let mut aliases : Vec<String> = self.aliases.iter()
+                    .filter(|&(_, store)| store == store_name)
+                    .map(|(alias, _)| alias.clone())
+                    .collect();
+                aliases.sort();
+                aliases
+            },
+            tags: self.tags.get(store_name).cloned().unwrap_or_default(),
+            flags: self.flags.get(store_name).cloned().unwrap_or_default(),
+            series: self.series.get(store_name).map(|kind| kind.to_string()),
+            expression: self.virtuals.get(store_name).map(|derived| derived.expr.to_string()),
+        }
+    }
+
+    /// The flushes of a store done since start. Reading the files and then
+    /// the memory of a store sees every row once when this is the same
+    /// before and after, and no flush was writing the file meanwhile: a
+    /// flush drops the rows from memory only once they are in the file. A
+    /// compaction keeps the rows in memory.
+    pub fn flushes(&self, store_name: &str) -> u64 {
+        self.flush_count.get(store_name).cloned().unwrap_or(0)
+    }
+
+    /// Whether the rows in memory of a store include those of its files, so
+    /// reads leave the files alone. A flush drops the rows from memory, a
+    /// `CLEAR` or an unload drops them all, they are read again by the next
+    /// read of any connection, see `Store::load`.
+    pub fn is_loaded(&self, store_name: &str) -> bool {
+        self.ephemeral.contains(store_name)
+            || self.materialized.contains_key(store_name)
+            || self.loaded.get(store_name) == Some(&self.flushes(store_name))
+    }
+
+    /// End a flush of `store_name` that did not write its file, the rows stay in memory
+    fn flush_failed(&mut self, store_name: &str) {
+        let was_loaded = self.is_loaded(store_name);
+        self.flushing.remove(store_name);
+        self.flushing_from.remove(store_name);
+        self.flush_errors += 1;
+        // the file may hold some of the rows now, reads that went on with the file are done again
+        let flushes = self.flush_count.entry(store_name.to_owned()).or_insert(0);
+        *flushes += 1;
+        // the rows in memory are still all the rows of the store
+        if was_loaded {
+            let flushes = *flushes;
+            self.loaded.insert(store_name.to_owned(), flushes);
+        }
+        self.result_cache.invalidate(store_name, None);
+    }
+
+    /// The stores to flush once the unflushed rows of all stores take more
+    /// than `--flush_memory`, those with the most unflushed rows first, until
+    /// the others take less than half of it. Empty under `--flush_memory`.
+    pub fn flush_candidates(&self) -> Vec<String> {
+        let limit = self.settings.flush_memory;
+        let row_bytes = mem::size_of::<Update>() as u64;
+        let mut total = self.unflushed_rows as u64 * row_bytes;
+        if limit == 0 || total < limit {
+            return Vec::new();
+        }
+        let mut stores : Vec<(&String, &usize)> = self.unflushed.iter()
+            .filter(|&(name, _)| !self.flushing.contains(name) && !self.ephemeral.contains(name))
+            .collect();
+        stores.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let mut candidates = Vec::new();
+        for (name, &rows) in stores {
+            if total < limit / 2 {
+                break;
+            }
+            total = total.saturating_sub(rows as u64 * row_bytes);
+            candidates.push(name.to_owned());
+        }
+        candidates
+    }
+
+    /// Drop the rows in memory of the stores without reads or inserts for
+    /// `idle` secs, as `CLEAR` does, and free their memory. Stores holding
+    /// rows that are not on disk yet are kept. Returns the unloaded stores.
+    pub fn unload_idle(&mut self, now: u64, idle: u64) -> Vec<String> {
+        let idle_stores : Vec<String> = self.vec_store.iter()
+            .filter(|&(name, vecs)| !vecs.0.is_empty() && self.is_unloadable(name))
+            .filter(|&(name, _)| {
+                let last = cmp::max(self.last_read.get(name), self.last_write.get(name));
+                now.saturating_sub(last.cloned().unwrap_or(0)) >= idle
+            })
+            .map(|(name, _)| name.to_owned())
+            .collect();
+        for name in &idle_stores {
+            self.vec_store.get_mut(name).expect("KEY IS NOT IN HASHMAP").0 = Vec::new();
+            self.watermark_mut(name).memory = None;
+            self.loaded.remove(name);
+            self.unsorted.remove(name);
+            self.result_cache.invalidate(name, None);
+        }
+        idle_stores
+    }
+
+    /// whether the rows in memory of `store_name` can be read back from disk
+    fn is_unloadable(&self, store_name: &str) -> bool {
+        let watermark = self.watermarks.get(store_name).cloned().unwrap_or_default();
+        !self.ephemeral.contains(store_name)
+            && !self.materialized.contains_key(store_name)
+            && !self.flushing.contains(store_name)
+            && !self.dirty.contains_key(store_name)
+            && watermark.memory <= watermark.disk
+    }
+
+    pub fn amendments_of(&self, store_name: &str) -> Amendments {
+        Amendments {
+            tombstones: self.tombstones_of(store_name),
+            corrections: self.corrections.get(store_name),
+        }
+    }
+
+    pub fn tombstones_of(&self, store_name: &str) -> &[Tombstone] {
+        match self.tombstones.get(store_name) {
+            Some(tombstones) => tombstones,
+            None => &[]
+        }
+    }
+
+    /// how long an insert into `store_name` waits while it is compacted, see `throttle`
+    pub fn throttle(&mut self, store_name: &str) -> Option<Duration> {
+        let max_ms = self.settings.compaction_throttle;
+        let delay = self.compacting.get_mut(store_name)?.delay(max_ms);
+        if delay > Duration::from_secs(0) { Some(delay) } else { None }
+    }
+
+    /// push a new `update` into the vec of `store_name`.
+    /// Returns true when the store is due for an autoflush.
+    pub fn push(&mut self, store_name: &str, new_vec: Update) -> bool {
+        let is_ephemeral = self.ephemeral.contains(store_name);
+        if new_vec.is_trade {
+            self.update_materialized(store_name, &new_vec);
+        }
+        let derived = self.derive(store_name, &new_vec);
+        self.watermark_mut(store_name).add(&new_vec);
+        self.result_cache.invalidate(store_name, Some((new_vec.ts, new_vec.ts)));
+        if let Some(ref mut multicast) = self.multicast {
+            multicast.publish(store_name, &new_vec);
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        self.last_write.insert(store_name.to_owned(), now);
+        if !is_ephemeral && !self.materialized.contains_key(store_name) {
+            *self.unflushed.entry(store_name.to_owned()).or_insert(0) += 1;
+            self.unflushed_rows += 1;
+        }
+        let is_autoflush = self.settings.autoflush;
+        let flush_interval = self.settings.flush_interval;
+        let out_of_order = self.vec_store.get(store_name)
+            .map_or(false, |vecs| !follows(&vecs.0, slice::from_ref(&new_vec)));
+        if out_of_order {
+            self.unsorted.insert(store_name.to_owned());
+        }
+        let vecs = self.vec_store.get_mut(store_name).expect("KEY IS NOT IN HASHMAP");
+
+        vecs.0.push(new_vec);
+        vecs.1 += 1;
+        self.offset += 1;
+        if let Some(feed) = self.feeds.get_mut(store_name) {
+            feed.push(self.offset, vecs.0.last().unwrap(), &mut self.handles);
+        }
+
+        // Saves current store into disk after n items is inserted.
+        let size = vecs.0.len(); // using the raw len so won't have race condition with load_size_from_file
+        let is_autoflush = is_autoflush
+            && !is_ephemeral
+            && size != 0
+            && (size as u32) % flush_interval == 0;
+
+        if is_autoflush {
+            debug!("AUTOFLUSHING {}! Size: {} Last: {:?}", store_name, vecs.1, vecs.0.last().clone().unwrap());
+        }
+
+        // after the row, so subscribers see the sources first
+        for (name, row) in derived {
+            self.push(&name, row);
+        }
+
+        is_autoflush
+    }
+
+    /// the rows the virtual stores reading `source` add after `up` is inserted into it
+    fn derive(&mut self, source: &str, up: &Update) -> Vec<(String, Update)> {
+        self.virtuals.iter_mut()
+            .filter_map(|(name, derived)| derived.add(source, up).map(|row| (name.clone(), row)))
+            .collect()
+    }
+
+    /// copy a rewritten dtf file to dtf_folder_mirror, see `mirror`
+    pub fn mirror_written(&mut self, dtf_fname: &str) {
+        if let Some(ref mirror_folder) = self.settings.dtf_folder_mirror {
+            if let Err(e) = mirror::written(dtf_fname, mirror_folder) {
+                error!("Unable to mirror {}: {:?}", dtf_fname, e);
+                self.flush_errors += 1;
+            }
+        }
+    }
+
+    /// The name of the dtf file of a store in the catalog, a new one if it
+    /// is not there yet
+    pub fn catalog_fname(&self, store_name: &str) -> String {
+        match self.catalog.get(store_name) {
+            Some(entry) => entry.fname.clone(),
+            None => format!("{}--{}", Uuid::new_v4(), store_name)
+        }
+    }
+
+    /// The catalog entry of a store, recorded if it is new or becomes a
+    /// MEMORY store
+    pub fn catalog_entry(&mut self, store_name: &str, memory: bool) -> catalog::Entry {
+        let fname = self.catalog_fname(store_name);
+        self.catalog_entry_as(store_name, &fname, memory)
+    }
+
+    /// `catalog_entry`, flushing the store to `fname` if it is new
+    pub fn catalog_entry_as(&mut self, store_name: &str, fname: &str, memory: bool) -> catalog::Entry {
+        let entry = match self.catalog.get(store_name) {
+            Some(entry) if entry.memory || !memory => return entry.clone(),
+            Some(entry) => catalog::Entry { fname: entry.fname.clone(), memory },
+            None => catalog::Entry { fname: fname.to_owned(), memory }
+        };
+        if let Err(e) = catalog::record(&self.settings.dtf_folder, store_name, &entry) {
+            error!("Unable to record {} in the catalog: {:?}", store_name, e);
+        }
+        if !self.catalog.contains_key(store_name) {
+            self.cdc.push(&Event::Create { store: store_name.to_owned() });
+        }
+        self.catalog.insert(store_name.to_owned(), entry.clone());
+        entry
+    }
+
+    /// copy a sealed or rewritten partition to dtf_folder_mirror, see `partition`
+    pub fn mirror_partition_written(&mut self, partition: &str) {
+        if let Some(ref mirror_folder) = self.settings.dtf_folder_mirror {
+            if let Err(e) = partition::mirror_written(partition, mirror_folder) {
+                error!("Unable to mirror {}: {:?}", partition, e);
+                self.flush_errors += 1;
+            }
+        }
+    }
+
+    /// remove the copy of a removed partition from dtf_folder_mirror
+    pub fn mirror_partition_removed(&mut self, partition: &str) {
+        if let Some(ref mirror_folder) = self.settings.dtf_folder_mirror {
+            if let Err(e) = partition::mirror_removed(partition, mirror_folder) {
+                error!("Unable to remove the mirror of {}: {:?}", partition, e);
+                self.flush_errors += 1;
+            }
+        }
+    }
+
+    /// remove the copy of a removed dtf file from dtf_folder_mirror
+    pub fn mirror_removed(&mut self, dtf_fname: &str) {
+        if let Some(ref mirror_folder) = self.settings.dtf_folder_mirror {
+            if let Err(e) = mirror::removed(dtf_fname, mirror_folder) {
+                error!("Unable to remove the mirror of {}: {:?}", dtf_fname, e);
+                self.flush_errors += 1;
+            }
+        }
+    }
+
+    pub fn watermark_mut(&mut self, store_name: &str) -> &mut Watermark {
+        self.watermarks.entry(store_name.to_owned()).or_insert_with(Watermark::default)
+    }
+
+    /// fold a trade inserted into `source` into every store materialized from it
+    pub fn update_materialized(&mut self, source: &str, trade: &Update) {
+        for (name, view) in self.materialized.iter_mut() {
+            if view.source != source {
+                continue;
+            }
+            let epoch = match view.candles.add(trade) {
+                Some(epoch) => epoch,
+                None => continue
+            };
+            let candle = view.candles.get(epoch).unwrap().to_updates(epoch);
+            let ts = candle[0].ts;
+            self.result_cache.invalidate(name, Some((ts, ts)));
+
+            let vecs = self.vec_store.get_mut(name).expect("KEY IS NOT IN HASHMAP");
+            let n = vecs.0.len();
+            if n >= candle.len() && vecs.0[n - candle.len()].ts == ts {
+                // the last candle changed
+                vecs.0.truncate(n - candle.len());
+                vecs.0.extend(candle);
+            } else if n == 0 || vecs.0[n - 1].ts < ts {
+                // a new candle
+                vecs.0.extend(candle);
+            } else {
+                // an older candle changed
+                vecs.0 = view.candles.to_updates();
+            }
+            vecs.1 = vecs.0.len() as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use backend::memory::MemoryBackend;
+    use engine::{Engine, Session};
+    use extension::Extensions;
+    use plugins::group_commit;
+    use handler::{self, ReturnType};
+    use settings::{Allow, IntegrityScan, Listen, Storage};
+    use serde_json::{self, Value};
+    use std::env;
+
+    /// the shared state of a server on a fresh dtf folder, without plugins
+    pub fn global() -> Global {
+        let dtf_folder = format!("{}/tectonic_{}", env::temp_dir().display(), Uuid::new_v4());
+        utils::create_dir_if_not_exist(&dtf_folder);
+        Arc::new(RwLock::new(SharedState::new(Settings {
+            autoflush: false,
+            dtf_folder,
+            dtf_folder_mirror: None,
+            flush_interval: 1000,
+            threads: 100,
+            hist_granularity: 30,
+            storage: Storage::DTF,
+            max_memory: 0,
+            flush_memory: 0,
+            journal: None,
+            normalize_names: false,
+            autocreate_stores: false,
+            default_store: Some("default".to_owned()),
+            group_commit: 0,
+            multicast: None,
+            silence_alert: 0,
+            webhook: None,
+            replay_window: 100,
+            max_open_files: 16,
+            integrity_scan: IntegrityScan::Off,
+            daemon: false,
+            pid_file: None,
+            listeners: Vec::new(),
+            allow_ips: Vec::new(),
+            deny_ips: Vec::new(),
+            slowlog_threshold: 0,
+            slowlog_file: "slow.log".to_owned(),
+            query_timeout: 0,
+            shed_reads_above: 0,
+            result_cache: 64 * 1024 * 1024,
+            scan_threads: 2,
+            direct_io: 0,
+            compaction_throttle: 1,
+            max_partition_bytes: 0,
+            unload_idle: 0,
+            ha_lease: None,
+            ha_lease_secs: 10,
+            ha_node: "test".to_owned(),
+            ha_addr: "127.0.0.1:9001".to_owned(),
+            templates: BTreeMap::new(),
+            max_jobs: 2,
+            schedules: BTreeMap::new(),
+        })))
+    }
+
+    /// the shared state of a server restarted on the dtf folder of `global`
+    pub fn restart(global: &Global) -> Global {
+        let settings = global.read().unwrap().settings.clone();
+        Arc::new(RwLock::new(SharedState::new(settings)))
+    }
+
+    /// a new connection, with the stores created so far
+    pub fn connect(global: &Global) -> State {
+        let mut state = State::new(global);
+        utils::init_dbs(&mut state);
+        state
+    }
+
+    pub fn run(state: &mut State, command: &str) -> String {
+        match handler::gen_response(command, state) {
+            ReturnType::String(resp) => resp,
+            ReturnType::Error(e) => panic!("{}: {}", command, e),
+            _ => panic!("{}: not a string", command)
+        }
+    }
+
+    pub fn run_err(state: &mut State, command: &str) -> String {
+        match handler::gen_response(command, state) {
+            ReturnType::Error(e) => e,
+            _ => panic!("{}: not an error", command)
+        }
+    }
+
+    /// add the i-th row of a store
+    pub fn add(state: &mut State, i: u64) {
+        run(state, &format!("ADD {}.685, {}, t, f, 0.0703620, 7.65064240;", 1505177459 + i, 139010 + i));
+    }
+
+    /// rows of GET ALL AS JSON
+    pub fn rows(state: &mut State) -> usize {
+        run(state, "GET ALL AS JSON").matches("\"ts\"").count()
+    }
+
+    #[test]
+    fn should_take_the_idempotency_key_of_an_insert_that_succeeded() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "USE btc_usdt");
+
+        // a row that doesn't parse leaves its key to the replay of the fixed row
+        run_err(&mut state, "ADD 1505177459.685, 139010, t, f, 0.0703620, x; KEY spool:0");
+        run(&mut state, "ADD 1505177459.685, 139010, t, f, 0.0703620, 7.65064240; KEY spool:0");
+        assert_eq!(rows(&mut state), 1);
+
+        // replayed again, it is not inserted twice
+        run(&mut state, "ADD 1505177459.685, 139010, t, f, 0.0703620, 7.65064240; KEY spool:0");
+        assert_eq!(rows(&mut state), 1);
+    }
+
+    #[test]
+    fn should_keep_materialized_stores_across_restarts() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "CREATE MATERIALIZED btc_usdt_1m FROM btc_usdt INTERVAL 1m");
+        run(&mut state, "ADD 1505177460.000, 1, t, f, 2.0, 1.0; INTO btc_usdt");
+        run(&mut state, "ADD 1505177461.000, 2, t, f, 3.0, 1.0; INTO btc_usdt");
+        run(&mut state, "FLUSH ALL");
+
+        let global = restart(&global);
+        let mut state = connect(&global);
+        assert_eq!(global.read().unwrap().materialized["btc_usdt_1m"].source, "btc_usdt");
+        // the candle flushed before the restart is updated, not started over
+        run(&mut state, "ADD 1505177462.000, 3, t, f, 1.0, 1.0; INTO btc_usdt");
+        run(&mut state, "USE btc_usdt_1m");
+        let candle : Vec<f32> = serde_json::from_str::<Value>(&run(&mut state, "GET ALL AS JSON")).unwrap()
+            .as_array().unwrap().iter()
+            .map(|row| row["price"].as_f64().unwrap() as f32)
+            .collect();
+        assert_eq!(candle, vec![2.0, 3.0, 1.0, 1.0]);
+        let volume = serde_json::from_str::<Value>(&run(&mut state, "GET ALL AS JSON")).unwrap()[3]["size"].as_f64().unwrap();
+        assert_eq!(volume, 3.0);
+
+        // and flushed again with the candles of before the restart
+        run(&mut state, "FLUSH ALL");
+        let global = restart(&global);
+        let mut state = connect(&global);
+        run(&mut state, "USE btc_usdt_1m");
+        assert_eq!(rows(&mut state), 4);
+    }
+
+    #[test]
+    fn should_keep_rows_inserted_after_a_delete() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "USE btc_usdt");
+        for i in 0..5 {
+            add(&mut state, i);
+        }
+        run(&mut state, "FLUSH");
+        for i in 5..8 {
+            add(&mut state, i);
+        }
+
+        // the range reaches past the newest row
+        run(&mut state, "DELETE FROM btc_usdt WHERE ts BETWEEN 1505177461 AND 1505177559");
+        assert_eq!(rows(&mut state), 2);
+        add(&mut state, 8);
+        add(&mut state, 9);
+        assert_eq!(rows(&mut state), 4);
+
+        // the tombstone is read back with the newest row of the DELETE
+        let mut state = connect(&restart(&global));
+        run(&mut state, "USE btc_usdt");
+        assert_eq!(rows(&mut state), 2);
+
+        let mut state = connect(&global);
+        run(&mut state, "USE btc_usdt");
+        run(&mut state, "COMPACT btc_usdt");
+        assert_eq!(rows(&mut state), 4);
+        run(&mut state, "FLUSH");
+        let mut state = connect(&restart(&global));
+        run(&mut state, "USE btc_usdt");
+        assert_eq!(rows(&mut state), 4);
+    }
+
+    #[test]
+    fn should_read_stats_and_compact_through_the_backend() {
+        let global = global();
+        let backend = Arc::new(MemoryBackend::default());
+        global.write().unwrap().backend = Some(backend.clone());
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "USE btc_usdt");
+        for i in 0..5 {
+            add(&mut state, i);
+        }
+        run(&mut state, "FLUSH");
+        assert_eq!(backend.count("btc_usdt"), 5);
+
+        // nothing is left in memory or in dtf_folder after a restart
+        let global = restart(&global);
+        global.write().unwrap().backend = Some(backend.clone());
+        let mut state = connect(&global);
+        run(&mut state, "USE btc_usdt");
+        assert_eq!(run(&mut state, "GET ALL FROM 1505177460 TO 1505177462 AS JSON").matches("\"ts\"").count(), 2);
+        let stats = serde_json::from_str::<Value>(&run(&mut state, "STATS btc_usdt")).unwrap();
+        assert_eq!(stats["stats"]["count"], 5);
+
+        run(&mut state, "DELETE FROM btc_usdt WHERE ts BETWEEN 1505177460 AND 1505177461");
+        run(&mut state, "COMPACT btc_usdt");
+        assert_eq!(backend.count("btc_usdt"), 4);
+        assert!(backend.read("btc_usdt", None).unwrap().iter().all(|up| up.ts != 1505177460685));
+        let stats = serde_json::from_str::<Value>(&run(&mut state, "STATS btc_usdt")).unwrap();
+        assert_eq!(stats["stats"]["count"], 4);
+        assert_eq!(rows(&mut state), 4);
+    }
+
+    #[test]
+    fn should_refuse_what_a_backend_skips() {
+        let global = global();
+        global.write().unwrap().backend = Some(Arc::new(MemoryBackend::default()));
+        global.write().unwrap().settings.storage = Storage::Uring;
+        let mut settings = global.read().unwrap().settings.clone();
+        assert!(settings.check().is_ok());
+        settings.dtf_folder_mirror = Some("mirror".to_owned());
+        assert!(settings.check().unwrap_err().contains("dtf_folder_mirror"));
+
+        let mut state = connect(&global);
+        assert!(run_err(&mut state, "CONFIG SET max_partition_bytes 1000").contains("--storage dtf"));
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "USE btc_usdt");
+        add(&mut state, 0);
+        run(&mut state, "FLUSH");
+        assert!(run_err(&mut state, "GET ALL AS JSON ASOF EPOCH 1").contains("no flush epochs"));
+    }
+
+    #[test]
+    fn should_mirror_sealed_partitions() {
+        let global = global();
+        let (dtf_folder, mirror_folder) = {
+            let mut wtr = global.write().unwrap();
+            let mirror_folder = format!("{}-mirror", wtr.settings.dtf_folder);
+            utils::create_dir_if_not_exist(&mirror_folder);
+            wtr.settings.dtf_folder_mirror = Some(mirror_folder.clone());
+            wtr.settings.max_partition_bytes = 1;
+            (wtr.settings.dtf_folder.clone(), mirror_folder)
+        };
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "USE btc_usdt");
+        for i in 0..3 {
+            add(&mut state, i);
+        }
+        run(&mut state, "FLUSH");
+        add(&mut state, 3);
+        run(&mut state, "FLUSH");
+
+        let fname = state.store["btc_usdt"].fname.clone();
+        let partition = format!("{}/partitions/{}/000000.dtf", dtf_folder, fname);
+        let mirrored = format!("{}/partitions/{}/000000.dtf", mirror_folder, fname);
+        assert_eq!(dtf::decode(&partition, None).len(), 3);
+        assert_eq!(fs::read(&mirrored).unwrap(), fs::read(&partition).unwrap());
+        // the mirror of the file holds the rows after the partition
+        assert_eq!(dtf::decode(&utils::dtf_fname(&mirror_folder, &fname), None).len(), 1);
+        assert_eq!(rows(&mut state), 4);
+
+        run(&mut state, "DELETE FROM btc_usdt WHERE ts BETWEEN 1505177459 AND 1505177460");
+        run(&mut state, "COMPACT btc_usdt");
+        assert_eq!(dtf::decode(&mirrored, None).len(), 2);
+        assert_eq!(global.read().unwrap().flush_errors, 0);
+        let _ = fs::remove_dir_all(&mirror_folder);
+    }
+
+    #[test]
+    fn should_fence_every_row_of_a_bulkadd() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "USE btc_usdt");
+        global.write().unwrap().role = Role::Primary { term: 1, until: Instant::now() + Duration::from_secs(10) };
+        let mut session = Session::new(state, "test");
+        let row = |i: u64| format!("{}.685, {}, t, f, 0.0703620, 7.65064240;", 1505177459 + i, 139010 + i);
+        let is_err = |resp: ReturnType| match resp { ReturnType::Error(_) => true, _ => false };
+
+        // the lease is lost in the middle of a BULKADD
+        assert!(!is_err(session.execute("BULKADD")));
+        assert!(!is_err(session.execute(&row(0))));
+        global.write().unwrap().role = Role::Primary { term: 1, until: Instant::now() };
+        assert!(is_err(session.execute(&row(1))));
+        assert!(is_err(session.execute("DDAKLUB")));
+        assert!(!session.state.is_adding);
+
+        // rows held back for the end of a declared BULKADD are dropped
+        global.write().unwrap().role = Role::Primary { term: 1, until: Instant::now() + Duration::from_secs(10) };
+        assert!(!is_err(session.execute("BULKADD COUNT 1")));
+        assert!(!is_err(session.execute(&row(2))));
+        global.write().unwrap().role = Role::Standby { term: 2, primary: "other".to_owned() };
+        assert!(is_err(session.execute("DDAKLUB")));
+        assert!(!session.state.is_adding && session.state.bulkadd_pending.is_none());
+
+        global.write().unwrap().role = Role::Single;
+        assert_eq!(rows(&mut session.state), 1);
+    }
+
+    #[test]
+    fn should_apply_corrections_until_compacted() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "USE btc_usdt");
+        for i in 0..3 {
+            add(&mut state, i);
+        }
+        run(&mut state, "FLUSH");
+        let fname = utils::dtf_fname(&global.read().unwrap().settings.dtf_folder, &state.store["btc_usdt"].fname);
+
+        run(&mut state, "ADD CORRECTION 1505177460.685, 139011, t, f, 0.08, 1.5; INTO btc_usdt");
+        let price_of_second = |state: &mut State| {
+            let rows = serde_json::from_str::<Value>(&run(state, "GET ALL AS JSON")).unwrap();
+            rows[1]["price"].as_f64().unwrap() as f32
+        };
+        assert_eq!(price_of_second(&mut state), 0.08);
+        assert!(dtf::decode(&fname, None).iter().all(|up| up.price != 0.08));
+
+        // corrections survive a restart, and COMPACT writes them into the file
+        let global = restart(&global);
+        let mut state = connect(&global);
+        run(&mut state, "USE btc_usdt");
+        assert_eq!(price_of_second(&mut state), 0.08);
+        run(&mut state, "COMPACT btc_usdt");
+        assert_eq!(dtf::decode(&fname, None)[1].price, 0.08);
+        assert_eq!(dtf::decode(&fname, None).len(), 3);
+        assert!(correction::read(&fname).is_empty());
+        assert_eq!(price_of_second(&mut state), 0.08);
+    }
+
+    #[test]
+    fn should_never_flush_memory_stores() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE scratch MEMORY");
+        run(&mut state, "CREATE btc_usdt");
+        for store in &["scratch", "btc_usdt"] {
+            run(&mut state, &format!("USE {}", store));
+            add(&mut state, 0);
+            add(&mut state, 1);
+        }
+        run(&mut state, "FLUSH ALL");
+        let dtf_folder = global.read().unwrap().settings.dtf_folder.clone();
+        let file_of = |state: &State, store: &str| utils::dtf_fname(&dtf_folder, &state.store[store].fname);
+        assert!(Path::new(&file_of(&state, "btc_usdt")).exists());
+        assert!(!Path::new(&file_of(&state, "scratch")).exists());
+        run(&mut state, "USE scratch");
+        assert_eq!(rows(&mut state), 2);
+
+        // only the MEMORY store counts against the budget, the other can be flushed
+        global.write().unwrap().settings.max_memory = 1;
+        assert!(run_err(&mut state, "ADD 1505177461.685, 139012, t, f, 0.0703620, 7.65064240;").starts_with("Memory budget exceeded"));
+        run(&mut state, "ADD 1505177461.685, 139012, t, f, 0.0703620, 7.65064240; INTO btc_usdt");
+
+        // the store is still there after a restart, its rows are not
+        let global = restart(&global);
+        let mut state = connect(&global);
+        assert!(global.read().unwrap().ephemeral.contains("scratch"));
+        run(&mut state, "USE scratch");
+        assert_eq!(rows(&mut state), 0);
+    }
+
+    #[test]
+    fn should_create_the_store_of_an_insert_with_autocreate_stores() {
+        let global = global();
+        let mut state = connect(&global);
+        let insert = "ADD 1505177459.685, 139010, t, f, 0.0703620, 7.65064240; INTO new_listing";
+        assert!(run_err(&mut state, insert).starts_with("No db named `new_listing`"));
+        assert!(!state.exists("new_listing"));
+
+        global.write().unwrap().settings.autocreate_stores = true;
+        run(&mut state, insert);
+        run(&mut state, "ADD 1505177460.685, 139011, t, f, 0.0703620, 7.65064240; INTO new_listing");
+        run(&mut state, "USE new_listing");
+        assert_eq!(rows(&mut state), 2);
+
+        // another connection sees the rows of the store it did not create
+        let mut other = connect(&global);
+        run(&mut other, "USE new_listing");
+        assert_eq!(rows(&mut other), 2);
+    }
+
+    #[test]
+    fn should_wait_for_the_commit_offset_of_a_write() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        let insert = |state: &mut State, i: u64| -> u64 {
+            run(state, &format!("ADD {}.685, {}, t, f, 0.0703620, 7.65064240; INTO btc_usdt", 1505177459 + i, 139010 + i))
+                .trim().parse().unwrap()
+        };
+        let offset = insert(&mut state, 0);
+        assert_eq!(insert(&mut state, 1), offset + 1);
+        run(&mut state, "USE btc_usdt");
+        assert_eq!(run(&mut state, &format!("GET ALL AS JSON AFTER {}", offset + 1)).matches("\"ts\"").count(), 2);
+        assert!(run_err(&mut state, &format!("GET ALL AS JSON AFTER {} TIMEOUT 20", offset + 2)).starts_with("Timed out"));
+
+        // a read waits for the write of another connection
+        let writer = {
+            let global = global.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                insert(&mut connect(&global), 2)
+            })
+        };
+        let rows = run(&mut state, &format!("GET ALL AS JSON AFTER {} TIMEOUT 5000", offset + 2));
+        assert_eq!(writer.join().unwrap(), offset + 2);
+        assert_eq!(rows.matches("\"ts\"").count(), 3);
+    }
+
+    #[test]
+    fn should_leave_autoflushes_to_the_group_commit() {
+        let global = global();
+        {
+            let mut wtr = global.write().unwrap();
+            wtr.settings.autoflush = true;
+            wtr.settings.flush_interval = 2;
+            wtr.settings.group_commit = 1000;
+        }
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "CREATE eth_usdt");
+        for store in &["btc_usdt", "eth_usdt"] {
+            run(&mut state, &format!("USE {}", store));
+            for i in 0..3 {
+                add(&mut state, i);
+            }
+        }
+        let dtf_folder = global.read().unwrap().settings.dtf_folder.clone();
+        let files : Vec<String> = ["btc_usdt", "eth_usdt"].iter()
+            .map(|store| utils::dtf_fname(&dtf_folder, &state.store[*store].fname))
+            .collect();
+        assert_eq!(global.read().unwrap().dirty.len(), 2);
+        assert!(files.iter().all(|fname| !Path::new(fname).exists()));
+
+        // both files are written by one group commit
+        group_commit::commit(&global);
+        assert!(global.read().unwrap().dirty.is_empty());
+        assert!(files.iter().all(|fname| dtf::get_size(fname) == 3));
+        assert_eq!(rows(&mut state), 3);
+    }
+
+    #[test]
+    fn should_close_a_store_and_read_it_back() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "CREATE scratch MEMORY");
+        run(&mut state, "USE btc_usdt");
+        add(&mut state, 0);
+        add(&mut state, 1);
+
+        assert_eq!(run(&mut state, "CLOSE btc_usdt"), "1\n");
+        let fname = utils::dtf_fname(&global.read().unwrap().settings.dtf_folder, &state.store["btc_usdt"].fname);
+        assert_eq!(dtf::get_size(&fname), 2);
+        assert!(!global.read().unwrap().last_write.contains_key("btc_usdt"));
+        // rows are read back from the file
+        assert_eq!(rows(&mut state), 2);
+
+        assert!(run_err(&mut state, "CLOSE scratch").starts_with("`scratch` is a MEMORY store"));
+        assert!(run_err(&mut state, "CLOSE nope").starts_with("No db named `nope`"));
+    }
+
+    #[test]
+    fn should_mirror_every_flush() {
+        let global = global();
+        let mirror_folder = format!("{}.mirror", global.read().unwrap().settings.dtf_folder);
+        utils::create_dir_if_not_exist(&mirror_folder);
+        global.write().unwrap().settings.dtf_folder_mirror = Some(mirror_folder.clone());
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "USE btc_usdt");
+        let fname = utils::dtf_fname(&global.read().unwrap().settings.dtf_folder, &state.store["btc_usdt"].fname);
+        let mirror = mirror::mirror_fname(&mirror_folder, &fname);
+        let same = || fs::read(&fname).unwrap() == fs::read(&mirror).unwrap();
+
+        add(&mut state, 0);
+        run(&mut state, "FLUSH");
+        assert!(same());
+        // only the appended rows are copied
+        add(&mut state, 1);
+        run(&mut state, "FLUSH");
+        assert!(same());
+        assert_eq!(dtf::get_size(&mirror), 2);
+
+        // a mirror that missed a write is copied whole
+        fs::write(&mirror, b"").unwrap();
+        add(&mut state, 2);
+        run(&mut state, "FLUSH");
+        assert!(same());
+        assert_eq!(dtf::get_size(&mirror), 3);
+    }
+
+    #[test]
+    fn should_interrupt_a_cancelled_or_timed_out_read() {
+        let settings = global().read().unwrap().settings.clone();
+        let engine = Engine::open(&settings, Extensions::new());
+        let mut reader = engine.session("reader");
+        let mut admin = engine.session("admin");
+        run(&mut reader.state, "CREATE btc_usdt");
+        run(&mut reader.state, "USE btc_usdt");
+        add(&mut reader.state, 0);
+        let id = reader.state.connection.id;
+        assert_eq!(run_err(&mut admin.state, &format!("CANCEL {}", id)), format!("No command is running on connection {}.\n", id));
+
+        // the command stays running while admin cancels it
+        reader.state.start_command("GET ALL AS JSON");
+        assert!(run(&mut admin.state, "CONNECTIONS").contains(r#""command": "GET ALL AS JSON""#));
+        assert_eq!(run(&mut admin.state, &format!("CANCEL {}", id)), "1\n");
+        handler::gen_response("GET ALL AS JSON", &mut reader.state);
+        assert_eq!(reader.state.interruption(), Some("Query cancelled."));
+
+        run(&mut reader.state, "TIMEOUT 1");
+        reader.state.start_command("GET ALL AS JSON");
+        assert_eq!(reader.state.interruption(), None);
+        thread::sleep(Duration::from_millis(5));
+        handler::gen_response("GET ALL AS JSON", &mut reader.state);
+        assert_eq!(reader.state.interruption(), Some("Query timed out."));
+
+        // the next command starts over
+        run(&mut reader.state, "TIMEOUT 0");
+        match reader.execute("GET ALL AS JSON") {
+            ReturnType::String(json) => assert_eq!(serde_json::from_str::<Value>(&json).unwrap().as_array().unwrap().len(), 1),
+            _ => panic!("GET ALL AS JSON was interrupted")
+        }
+    }
+
+    #[test]
+    fn should_flush_the_largest_stores_under_memory_pressure() {
+        let global = global();
+        global.write().unwrap().settings.flush_memory = 6 * mem::size_of::<Update>() as u64;
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "CREATE eth_usdt");
+        run(&mut state, "USE btc_usdt");
+        for i in 0..4 {
+            add(&mut state, i);
+        }
+        run(&mut state, "USE eth_usdt");
+        add(&mut state, 0);
+        assert_eq!(global.read().unwrap().unflushed_rows, 5);
+
+        // only the largest store is flushed, the other takes less than half of the limit
+        add(&mut state, 1);
+        let dtf_folder = global.read().unwrap().settings.dtf_folder.clone();
+        let file_of = |state: &State, store: &str| utils::dtf_fname(&dtf_folder, &state.store[store].fname);
+        assert_eq!(dtf::get_size(&file_of(&state, "btc_usdt")), 4);
+        assert!(!Path::new(&file_of(&state, "eth_usdt")).exists());
+        let rdr = global.read().unwrap();
+        assert_eq!(rdr.unflushed_rows, 2);
+        assert_eq!(rdr.unflushed.keys().collect::<Vec<_>>(), vec!["eth_usdt"]);
+    }
+
+    #[test]
+    fn should_count_the_rows_compacted_from_a_loaded_store_once() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "USE btc_usdt");
+        for i in 0..5 {
+            add(&mut state, i);
+        }
+        run(&mut state, "FLUSH");
+        // loads the rows of the file into memory
+        assert_eq!(rows(&mut state), 5);
+
+        run(&mut state, "DELETE FROM btc_usdt WHERE ts BETWEEN 1505177459 AND 1505177460");
+        assert_eq!(rows(&mut state), 4);
+        // counted until compacted
+        assert_eq!(run(&mut state, "COUNT"), "5\n");
+
+        assert_eq!(run(&mut state, "COMPACT btc_usdt"), "1\n");
+        assert_eq!(run(&mut state, "COUNT"), "4\n");
+        assert_eq!(rows(&mut state), 4);
+    }
+
+    #[test]
+    fn should_not_create_a_ttl_store_without_its_ttl() {
+        let global = global();
+        let mut state = connect(&global);
+        let dtf_folder = global.read().unwrap().settings.dtf_folder.clone();
+        utils::create_dir_if_not_exist(&dtf_folder);
+        // a file in place of the folder refuses the TTL
+        let blocked = utils::join(&dtf_folder, "blocked");
+        fs::write(&blocked, b"").unwrap();
+        global.write().unwrap().settings.dtf_folder = blocked;
+        run_err(&mut state, "CREATE btc_usdt TTL 7d");
+        assert!(!state.exists("btc_usdt"));
+        assert!(!global.read().unwrap().catalog.contains_key("btc_usdt"));
+
+        global.write().unwrap().settings.dtf_folder = dtf_folder;
+        run(&mut state, "CREATE btc_usdt TTL 7d");
+        assert_eq!(global.read().unwrap().ttls["btc_usdt"].ms, 7 * 24 * 3600 * 1000);
+    }
+
+    #[test]
+    fn should_not_create_a_materialized_store_it_could_not_record() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        let dtf_folder = global.read().unwrap().settings.dtf_folder.clone();
+        // a folder in place of the file refuses the record
+        let blocked = utils::join(&dtf_folder, "materialized");
+        fs::create_dir_all(&blocked).unwrap();
+        run_err(&mut state, "CREATE MATERIALIZED btc_usdt_1m FROM btc_usdt INTERVAL 1m");
+        assert!(!state.exists("btc_usdt_1m"));
+        assert!(!global.read().unwrap().catalog.contains_key("btc_usdt_1m"));
+
+        fs::remove_dir(&blocked).unwrap();
+        run(&mut state, "CREATE MATERIALIZED btc_usdt_1m FROM btc_usdt INTERVAL 1m");
+        assert!(global.read().unwrap().materialized.contains_key("btc_usdt_1m"));
+    }
+
+    #[test]
+    fn should_not_create_a_virtual_store_it_could_not_record() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        let dtf_folder = global.read().unwrap().settings.dtf_folder.clone();
+        // a folder in place of the file refuses the record
+        let blocked = utils::join(&dtf_folder, "virtual");
+        fs::create_dir_all(&blocked).unwrap();
+        run_err(&mut state, "CREATE VIRTUAL btc_mid AS mid(btc_usdt)");
+        assert!(!state.exists("btc_mid"));
+        assert!(!global.read().unwrap().catalog.contains_key("btc_mid"));
+
+        fs::remove_dir(&blocked).unwrap();
+        run(&mut state, "CREATE VIRTUAL btc_mid AS mid(btc_usdt)");
+        assert!(global.read().unwrap().virtuals.contains_key("btc_mid"));
+    }
+
+    #[test]
+    fn should_get_rows_after_flush() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "USE btc_usdt");
+        add(&mut state, 0);
+        add(&mut state, 1);
+        assert_eq!(run(&mut state, "FLUSH"), "1\n");
+        assert_eq!(rows(&mut state), 2);
+        add(&mut state, 2);
+        assert_eq!(run(&mut state, "FLUSH"), "1\n");
+        assert_eq!(rows(&mut state), 3);
+        assert_eq!(run(&mut state, "GET 2 AS JSON").matches("\"ts\"").count(), 2);
+    }
+
+    #[test]
+    fn should_get_rows_flushed_by_another_connection() {
+        let global = global();
+        let mut first = connect(&global);
+        run(&mut first, "CREATE btc_usdt");
+        run(&mut first, "USE btc_usdt");
+        add(&mut first, 0);
+        assert_eq!(rows(&mut first), 1);
+
+        let mut second = connect(&global);
+        run(&mut second, "USE btc_usdt");
+        add(&mut second, 1);
+        run(&mut second, "FLUSH ALL");
+        // the rows `first` had loaded were dropped from memory by the flush
+        assert_eq!(rows(&mut first), 2);
+        assert_eq!(rows(&mut second), 2);
+    }
+
+    #[test]
+    fn should_get_every_row_while_another_connection_flushes() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "USE btc_usdt");
+
+        let done = Arc::new(RwLock::new(false));
+        let flusher = {
+            // connected before the first file is written, which `init_dbs` reads
+            let (mut state, done) = (connect(&global), done.clone());
+            thread::spawn(move || {
+                while !*done.read().unwrap() {
+                    run(&mut state, "FLUSH ALL");
+                }
+            })
+        };
+        for i in 0..200 {
+            add(&mut state, i);
+            assert_eq!(run(&mut state, "FLUSH"), "1\n");
+            assert_eq!(rows(&mut state), i as usize + 1);
+        }
+        *done.write().unwrap() = true;
+        flusher.join().unwrap();
+    }
+
+    #[test]
+    fn should_load_flushed_rows_once_for_every_connection() {
+        let global = global();
+        let mut first = connect(&global);
+        run(&mut first, "CREATE btc_usdt");
+        run(&mut first, "USE btc_usdt");
+        add(&mut first, 0);
+        add(&mut first, 1);
+        assert_eq!(run(&mut first, "FLUSH"), "1\n");
+
+        let mut second = connect(&global);
+        run(&mut second, "USE btc_usdt");
+        // read past the result cache, which would hide rows loaded twice
+        assert_eq!(first.get_aux(None, None).unwrap().len(), 2);
+        assert_eq!(second.get_aux(None, None).unwrap().len(), 2);
+        assert_eq!(first.get_aux(None, None).unwrap().len(), 2);
+        assert_eq!(global.read().unwrap().vec_store["btc_usdt"].0.len(), 2);
+    }
+
+    #[test]
+    fn should_get_rows_while_a_flush_writes_the_file() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "USE btc_usdt");
+        add(&mut state, 0);
+        assert_eq!(run(&mut state, "FLUSH"), "1\n");
+        add(&mut state, 1);
+
+        let snapshot = state.store["btc_usdt"].snapshot(&mut global.write().unwrap()).unwrap();
+        add(&mut state, 2);
+        assert_eq!(state.get_aux(None, None).unwrap().len(), 3);
+        assert_eq!(state.read_store("btc_usdt", None).unwrap().len(), 3);
+
+        // as if the thread writing the snapshot panicked
+        drop(snapshot);
+        assert!(!global.read().unwrap().flushing.contains("btc_usdt"));
+        assert_eq!(run(&mut state, "FLUSH"), "1\n");
+        assert_eq!(rows(&mut state), 3);
+    }
+
+    /// take the rows of the current store as FLUSH does
+    fn snapshot(global: &Global, state: &State) -> FlushSnapshot {
+        state.store[&state.current_store_name].snapshot(&mut global.write().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn should_keep_rows_inserted_while_a_snapshot_is_written() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "USE btc_usdt");
+        add(&mut state, 0);
+        add(&mut state, 1);
+        let snapshot = snapshot(&global, &state);
+        add(&mut state, 2);
+        let written = write_snapshot(&snapshot);
+        assert!(flushed(&mut global.write().unwrap(), snapshot, written));
+        assert_eq!(global.read().unwrap().vec_store["btc_usdt"].0.len(), 1);
+        assert_eq!(rows(&mut state), 3);
+    }
+
+    #[test]
+    fn should_keep_rows_inserted_after_a_clear_while_a_snapshot_is_written() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "USE btc_usdt");
+        add(&mut state, 0);
+        add(&mut state, 1);
+        let snapshot = snapshot(&global, &state);
+        run(&mut state, "CLEAR");
+        add(&mut state, 2);
+        let written = write_snapshot(&snapshot);
+        assert!(flushed(&mut global.write().unwrap(), snapshot, written));
+        assert_eq!(global.read().unwrap().vec_store["btc_usdt"].0.len(), 1);
+        assert_eq!(rows(&mut state), 3);
+    }
+
+    #[test]
+    fn should_keep_rows_of_a_snapshot_that_could_not_be_written() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "USE btc_usdt");
+        add(&mut state, 0);
+        let snapshot = snapshot(&global, &state);
+        let written = Err(io::Error::new(io::ErrorKind::Other, "disk full"));
+        assert!(!flushed(&mut global.write().unwrap(), snapshot, written));
+        assert!(!global.read().unwrap().flushing.contains("btc_usdt"));
+        assert_eq!(rows(&mut state), 1);
+    }
+
+    #[test]
+    fn should_keep_rows_inserted_while_flush_all_writes_the_files() {
+        let global = global();
+        let mut state = connect(&global);
+        let names = ["btc_usdt", "eth_usdt", "ltc_usdt"];
+        for name in &names {
+            run(&mut state, &format!("CREATE {}", name));
+        }
+
+        let done = Arc::new(RwLock::new(false));
+        let flusher = {
+            let (global, done) = (global.clone(), done.clone());
+            thread::spawn(move || {
+                let mut state = connect(&global);
+                while !*done.read().unwrap() {
+                    run(&mut state, "FLUSH ALL");
+                }
+            })
+        };
+        for i in 0..100 {
+            for name in &names {
+                run(&mut state, &format!("ADD {}.685, {}, t, f, 0.0703620, 7.65064240; INTO {}", 1505177459 + i, 139010 + i, name));
+            }
+        }
+        *done.write().unwrap() = true;
+        flusher.join().unwrap();
+
+        run(&mut state, "FLUSH ALL");
+        for name in &names {
+            run(&mut state, &format!("USE {}", name));
+            assert_eq!(rows(&mut state), 100);
+        }
+    }
+
+    /// JOB STATUS of the job started by `command` once it finished
+    fn run_job(state: &mut State, command: &str) -> Value {
+        let started : Value = serde_json::from_str(&run(state, command)).unwrap();
+        let id = started["job"].as_u64().unwrap();
+        for _ in 0..1000 {
+            let status : Value = serde_json::from_str(&run(state, &format!("JOB STATUS {}", id))).unwrap();
+            if status["status"] != "queued" && status["status"] != "running" {
+                return status;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        panic!("{}: job {} did not finish", command, id);
+    }
+
+    #[test]
+    fn should_report_the_progress_of_flush_all_async() {
+        let global = global();
+        let mut state = connect(&global);
+        for name in &["btc_usdt", "eth_usdt"] {
+            run(&mut state, &format!("CREATE {}", name));
+            run(&mut state, &format!("USE {}", name));
+            add(&mut state, 0);
+            add(&mut state, 1);
+        }
+        let stores = global.read().unwrap().vec_store.len() as u64;
+
+        let status = run_job(&mut state, "FLUSH ALL ASYNC");
+        assert_eq!(status["status"], "done");
+        assert_eq!(status["command"], "FLUSH ALL");
+        assert_eq!(status["done"], stores);
+        assert_eq!(status["total"], stores);
+        assert_eq!(status["result"], format!("{} stores.", stores));
+        assert!(global.read().unwrap().vec_store["eth_usdt"].0.is_empty());
+        // the flushed rows are read back from the file
+        assert_eq!(rows(&mut state), 2);
+
+        let status = run_job(&mut state, "CLEAR ALL ASYNC");
+        assert_eq!(status["status"], "done");
+        assert_eq!(status["done"], stores);
+        assert_eq!(run(&mut state, "JOBS").matches("\"id\"").count(), 2);
+        assert!(run_err(&mut state, "JOB STATUS 100").starts_with("No job 100"));
+    }
+
+    #[test]
+    fn should_commit_a_transaction_across_stores() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "CREATE eth_usdt");
+        run(&mut state, "BEGIN");
+        run(&mut state, "ADD 1505177459.685, 139010, t, f, 0.0703620, 7.65064240; INTO btc_usdt");
+        run(&mut state, "ADD 1505177459.686, 139011, t, f, 0.0703620, 7.65064240; INTO eth_usdt");
+        assert_eq!(run(&mut state, "COUNT ALL"), "0\n");
+        run(&mut state, "COMMIT");
+        assert_eq!(run(&mut state, "COUNT ALL"), "2\n");
+        assert!(run_err(&mut state, "COMMIT").starts_with("Not in a transaction"));
+    }
+
+    #[test]
+    fn should_keep_the_transaction_of_a_failed_commit() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt MEMORY");
+        run(&mut state, "ADD 1505177459.684, 139009, t, f, 0.0703620, 7.65064240; INTO btc_usdt");
+        run(&mut state, "BEGIN");
+        run(&mut state, "ADD 1505177459.685, 139010, t, f, 0.0703620, 7.65064240; INTO btc_usdt");
+        global.write().unwrap().settings.max_memory = 1;
+        assert!(run_err(&mut state, "COMMIT").starts_with("Memory budget exceeded"));
+        assert_eq!(run(&mut state, "COUNT ALL"), "1\n");
+
+        // the rows are still there to commit once there is room
+        global.write().unwrap().settings.max_memory = 0;
+        run(&mut state, "COMMIT");
+        assert_eq!(run(&mut state, "COUNT ALL"), "2\n");
+    }
+
+    /// seqs of every page of `GET ... LIMIT limit`, adding the row of
+    /// `insert(page)` before reading the next page
+    fn pages<F: FnMut(&mut State, usize)>(state: &mut State, get: &str, limit: u32, mut insert: F) -> Vec<Vec<u64>> {
+        let mut pages = Vec::new();
+        let mut cursor : Option<String> = None;
+        loop {
+            let command = match cursor {
+                Some(ref cursor) => format!("{} LIMIT {} CURSOR {}", get, limit, cursor),
+                None => format!("{} LIMIT {}", get, limit)
+            };
+            let page : Value = serde_json::from_str(&run(state, &command)).unwrap();
+            pages.push(page["data"].as_array().unwrap().iter().map(|up| up["seq"].as_u64().unwrap()).collect());
+            match page["cursor"].as_str() {
+                Some(next) => cursor = Some(next.to_owned()),
+                None => return pages
+            }
+            insert(state, pages.len());
+        }
+    }
+
+    #[test]
+    fn should_page_from_the_last_row_of_the_previous_page() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "USE btc_usdt");
+        for i in 0..10 {
+            add(&mut state, i);
+        }
+        // rows inserted meanwhile don't shift the next page
+        let pages = pages(&mut state, "GET ALL AS JSON", 4, |state, page| add(state, 100 + page as u64));
+        assert_eq!(pages, vec![
+            vec![139010, 139011, 139012, 139013],
+            vec![139014, 139015, 139016, 139017],
+            vec![139018, 139019, 139111, 139112],
+        ]);
+        let pages = pages_of_count(&mut state, 5, 2);
+        assert_eq!(pages, vec![vec![139010, 139011], vec![139012, 139013], vec![139014]]);
+        assert!(run_err(&mut state, "GET ALL AS JSON LIMIT 2 CURSOR 4").starts_with("Unable to parse LIMIT/CURSOR"));
+    }
+
+    fn pages_of_count(state: &mut State, count: u32, limit: u32) -> Vec<Vec<u64>> {
+        pages(state, &format!("GET {} AS JSON", count), limit, |_, _| ())
+    }
+
+    #[test]
+    fn should_page_rows_in_order_of_ts_and_seq() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "USE btc_usdt");
+        for i in 5..10 {
+            add(&mut state, i);
+        }
+        run(&mut state, "FLUSH");
+        // the rows in memory are before the rows of the file once loaded
+        for &i in &[3, 0, 4, 2, 1] {
+            add(&mut state, i);
+        }
+        let seqs : Vec<u64> = pages(&mut state, "GET ALL AS JSON", 3, |_, _| ()).concat();
+        assert_eq!(seqs, (139010..139020).collect::<Vec<u64>>());
+        let seqs : Vec<u64> = pages(&mut state, "GET ALL FROM 1505177461 TO 1505177466 AS JSON", 2, |_, _| ()).concat();
+        assert_eq!(seqs, (139012..139017).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn should_refuse_add_without_use() {
+        let global = global();
+        global.write().unwrap().settings.default_store = None;
+        let mut state = connect(&global);
+        state.use_default_store("10.0.0.5:6000");
+        let row = "ADD 1505177459.685, 139010, t, f, 0.0703620, 7.65064240;";
+        assert!(run_err(&mut state, row).starts_with("No db in use"));
+        assert!(run_err(&mut state, "BULKADD").starts_with("No db in use"));
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, &format!("{} INTO btc_usdt", row));
+        run(&mut state, "USE btc_usdt");
+        run(&mut state, row);
+        assert_eq!(run(&mut state, "COUNT"), "2\n");
+        assert_eq!(run(&mut state, "COUNT ALL"), "2\n");
+    }
+
+    #[test]
+    fn should_add_to_the_default_store_of_the_peer() {
+        let global = global();
+        global.write().unwrap().settings.default_store = Some("unrouted_{peer}".to_owned());
+        let mut state = connect(&global);
+        state.use_default_store("10.0.0.5:6000");
+        assert_eq!(state.current_store_name, "unrouted_10_0_0_5");
+        add(&mut state, 0);
+        run(&mut state, "FLUSH");
+        // a named default store is in the catalog, the next connection of the host finds its rows
+        let mut state = connect(&global);
+        state.use_default_store("10.0.0.5:6001");
+        assert!(global.read().unwrap().catalog.contains_key("unrouted_10_0_0_5"));
+        assert_eq!(rows(&mut state), 1);
+    }
+
+    #[test]
+    fn should_negotiate_the_protocol_in_hello() {
+        let global = global();
+        let mut state = connect(&global);
+        let hello = |state: &mut State, command: &str| -> Value {
+            serde_json::from_str(&run(state, command)).unwrap()
+        };
+        run(&mut state, "CREATE btc_usdt");
+        let welcome = hello(&mut state, "HELLO protocol=7 store=btc_usdt format=flat");
+        assert_eq!(welcome["protocol"], hello::PROTOCOL_VERSION);
+        assert_eq!(welcome["store"], "btc_usdt");
+        assert_eq!(welcome["format"], "flat");
+        assert_eq!(welcome["features"]["compression"], false);
+        assert_eq!(welcome["features"]["tls"], false);
+        assert_eq!(welcome["limits"]["max_name_len"], 64);
+        assert_eq!(welcome["limits"]["max_flags"], 6);
+        assert_eq!(welcome["limits"]["max_upload_bytes"], ::server::MAX_UPLOAD_BYTES);
+        assert_eq!(welcome["formats"].to_string(), r#"["binary","flat","msgpack"]"#);
+        assert_eq!(state.connection.protocol(), hello::PROTOCOL_VERSION);
+        // a bare HELLO keeps the agreed version
+        assert_eq!(hello(&mut state, "HELLO")["protocol"], hello::PROTOCOL_VERSION);
+        assert!(run_err(&mut state, "HELLO protocol=0").starts_with("Unable to parse HELLO"));
+
+        global.write().unwrap().settings.listeners.push(Listen {
+            addr: "127.0.0.1:9002".to_owned(),
+            tls: Some("identity.p12".to_owned()),
+            allow: Allow::All,
+        });
+        assert_eq!(hello(&mut state, "HELLO")["features"]["tls"], true);
+    }
+
+    #[test]
+    fn should_create_if_not_exists() {
+        let global = global();
+        let mut state = connect(&global);
+        let json = |state: &mut State, command: &str| -> Value {
+            serde_json::from_str(&run(state, command)).unwrap()
+        };
+        let missing = json(&mut state, "EXISTS btc_usdt AS JSON");
+        assert_eq!(missing["exists"], false);
+        assert!(missing["metadata"].is_null());
+
+        let created = json(&mut state, "CREATE IF NOT EXISTS btc_usdt");
+        assert_eq!(created["exists"], true);
+        assert_eq!(created["created"], true);
+        run(&mut state, "USE btc_usdt");
+        add(&mut state, 0);
+        run(&mut state, "PRECISION btc_usdt 4");
+
+        // a restarted collector finds the store with its rows and settings
+        let mut state = connect(&global);
+        let existing = json(&mut state, "CREATE IF NOT EXISTS btc_usdt");
+        assert_eq!(existing["created"], false);
+        assert_eq!(existing["metadata"]["precision"], 4);
+        assert_eq!(json(&mut state, "EXISTS btc_usdt AS JSON")["exists"], true);
+        run(&mut state, "USE btc_usdt");
+        assert_eq!(rows(&mut state), 1);
+        assert!(run_err(&mut state, "CREATE IF NOT EXISTS btc usdt").starts_with("Unable to parse"));
+        assert!(run_err(&mut state, "EXISTS AS JSON").starts_with("Unable to parse"));
+        assert!(run_err(&mut state, "CREATE MEMORY").starts_with("Unable to parse"));
+    }
+
+    #[test]
+    fn should_create_if_not_exists_once_across_connections() {
+        let global = global();
+        let creators : Vec<_> = (0..8).map(|_| {
+            let global = global.clone();
+            thread::spawn(move || {
+                let mut state = connect(&global);
+                let created : Value = serde_json::from_str(&run(&mut state, "CREATE IF NOT EXISTS btc_usdt")).unwrap();
+                created["created"] == true
+            })
+        }).collect();
+        let created = creators.into_iter().map(|creator| creator.join().unwrap()).filter(|&created| created).count();
+        assert_eq!(created, 1);
+
+        // an existing store is left as it is, its flushed rows are not loaded twice
+        let mut state = connect(&global);
+        run(&mut state, "USE btc_usdt");
+        add(&mut state, 0);
+        run(&mut state, "FLUSH");
+        assert_eq!(rows(&mut state), 1);
+        run(&mut state, "CREATE IF NOT EXISTS btc_usdt");
+        assert_eq!(state.get_aux(None, None).unwrap().len(), 1);
     }
 }
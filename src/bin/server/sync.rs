@@ -0,0 +1,228 @@
+/// Inter-server sync
+///
+/// `SYNC FROM [host:port] STORES [pattern] [SINCE ts]` consolidates the
+/// stores of another server, e.g. a regional collector, into this one. For
+/// every remote store matching the pattern, the rows newer than the local
+/// watermark (see `LATEST`), and not older than `SINCE` (in seconds), are
+/// fetched with `DOWNLOAD` and merged like an `UPLOAD`. Running it again
+/// only fetches what is missing.
+///
+/// Rows missing from older ranges, e.g. after the local server was down
+/// while another one backfilled, are past the watermark. With `VERIFY`, both
+/// servers instead checksum the rows of every store by hour (see
+/// `CHECKSUMS`), and only the hours whose row count or CRC-32 differ are
+/// fetched, adjacent hours in one `DOWNLOAD`.
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::str;
+use std::time::Duration;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use serde_json::{self, Value};
+use dtf::Update;
+use dtf::utils::crc32_update;
+use server::MAX_UPLOAD_BYTES;
+
+/// seconds to wait for a response of the remote server
+static TIMEOUT_SECS : u64 = 60;
+
+/// seconds of the ranges compared by `VERIFY`
+pub static CHECKSUM_SECS : u32 = 3600;
+
+/// Rows of a store in the range of CHECKSUM_SECS starting at `from`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeChecksum {
+    pub from: u32,
+    pub rows: u64,
+    pub crc: u32,
+}
+
+impl RangeChecksum {
+    pub fn to_json(&self) -> String {
+        format!(r#"{{"from":{},"rows":{},"crc":{}}}"#, self.from, self.rows, self.crc)
+    }
+}
+
+/// checksums of `ups`, sorted by (ts, seq) without duplicates, by range
+pub fn checksums(ups: &[Update]) -> Vec<RangeChecksum> {
+    let mut checksums : Vec<RangeChecksum> = Vec::new();
+    let mut bytes = Vec::with_capacity(23);
+    for up in ups {
+        let from = (up.ts / 1000) as u32 / CHECKSUM_SECS * CHECKSUM_SECS;
+        if checksums.last().map_or(true, |last| last.from != from) {
+            checksums.push(RangeChecksum { from, rows: 0, crc: 0 });
+        }
+        bytes.clear();
+        let _ = bytes.write_u64::<BigEndian>(up.ts);
+        let _ = bytes.write_u32::<BigEndian>(up.seq);
+        bytes.push(up.is_trade as u8);
+        bytes.push(up.is_bid as u8);
+        let _ = bytes.write_u32::<BigEndian>(up.price.to_bits());
+        let _ = bytes.write_u32::<BigEndian>(up.size.to_bits());
+        bytes.push(up.flags);
+        let last = checksums.last_mut().unwrap();
+        last.rows += 1;
+        last.crc = crc32_update(last.crc, &bytes);
+    }
+    checksums
+}
+
+/// ranges (in seconds) of the `remote` checksums that differ from the
+/// `local` ones, adjacent ranges merged
+pub fn divergent(remote: &[RangeChecksum], local: &[RangeChecksum]) -> Vec<(u32, u32)> {
+    let mut ranges : Vec<(u32, u32)> = Vec::new();
+    for checksum in remote.iter().filter(|checksum| !local.contains(checksum)) {
+        let to = checksum.from.saturating_add(CHECKSUM_SECS);
+        match ranges.last_mut() {
+            Some(last) if last.1 == checksum.from => last.1 = to,
+            _ => ranges.push((checksum.from, to))
+        }
+    }
+    ranges
+}
+
+/// connection to the server synced from
+pub struct Remote {
+    stream: TcpStream,
+}
+
+impl Remote {
+    pub fn connect(addr: &str) -> Result<Remote, String> {
+        let stream = TcpStream::connect(addr).map_err(|e| format!("Unable to connect to {}: {}", addr, e))?;
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS)));
+        Ok(Remote { stream })
+    }
+
+    /// body of the response to `command`, or the error of the remote server
+    fn send(&mut self, command: &str) -> Result<Vec<u8>, String> {
+        self.request(command).map_err(|e| format!("Connection to the remote server failed: {}", e))?
+    }
+
+    fn request(&mut self, command: &str) -> io::Result<Result<Vec<u8>, String>> {
+        self.stream.write_all(format!("{}\n", command).as_bytes())?;
+        let success = self.stream.read_u8()? == 0x1;
+        let size = self.stream.read_u64::<BigEndian>()?;
+        if size > MAX_UPLOAD_BYTES {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("a response of {} bytes is over the limit of {}", size, MAX_UPLOAD_BYTES)));
+        }
+        let mut body = vec![0; size as usize];
+        self.stream.read_exact(&mut body)?;
+        if success {
+            Ok(Ok(body))
+        } else {
+            Ok(Err(format!("`{}` failed on the remote server: {}", command, String::from_utf8_lossy(&body).trim())))
+        }
+    }
+
+    fn send_json(&mut self, command: &str) -> Result<Value, String> {
+        let body = self.send(command)?;
+        serde_json::from_slice(&body).map_err(|e| format!("Unable to parse the response to `{}`: {}", command, e))
+    }
+
+    /// names of the remote stores, from `INFO`
+    pub fn stores(&mut self) -> Result<Vec<String>, String> {
+        let info = self.send_json("INFO")?;
+        Ok(info["dbs"].as_array()
+            .map(|dbs| dbs.iter().filter_map(|db| db["name"].as_str().map(str::to_owned)).collect())
+            .unwrap_or_default())
+    }
+
+    /// ts in ms of the newest row of a remote store, in memory or on disk
+    pub fn newest(&mut self, store_name: &str) -> Result<Option<u64>, String> {
+        let latest = self.send_json(&format!("LATEST {}", store_name))?;
+        let ts = |watermark: &Value| watermark["ts"].as_f64().map(|ts| (ts * 1000.).round() as u64);
+        Ok(cmp::max(ts(&latest["memory"]), ts(&latest["disk"])))
+    }
+
+    /// checksums of the rows of a remote store in `range` (in seconds)
+    pub fn checksums(&mut self, store_name: &str, range: (u32, u32)) -> Result<Vec<RangeChecksum>, String> {
+        let json = self.send_json(&format!("CHECKSUMS {} FROM {} TO {}", store_name, range.0, range.1))?;
+        Ok(json.as_array().map(|checksums| checksums.iter().filter_map(|checksum| Some(RangeChecksum {
+            from: checksum["from"].as_u64()? as u32,
+            rows: checksum["rows"].as_u64()?,
+            crc: checksum["crc"].as_u64()? as u32,
+        })).collect()).unwrap_or_default())
+    }
+
+    /// rows of a remote store in `range` (in seconds) as a dtf file
+    pub fn download(&mut self, store_name: &str, range: (u32, u32)) -> Result<Vec<u8>, String> {
+        self.send(&format!("DOWNLOAD {} FROM {} TO {}", store_name, range.0, range.1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use state::tests::{connect, global, run};
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn checksums_of(json: &str) -> Vec<RangeChecksum> {
+        let json : Value = serde_json::from_str(json).unwrap();
+        json.as_array().unwrap().iter().map(|checksum| RangeChecksum {
+            from: checksum["from"].as_u64().unwrap() as u32,
+            rows: checksum["rows"].as_u64().unwrap(),
+            crc: checksum["crc"].as_u64().unwrap() as u32,
+        }).collect()
+    }
+
+    #[test]
+    fn should_fetch_only_the_hours_that_differ() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE primary");
+        run(&mut state, "CREATE replica");
+        // rows in 4 hours from 1505174400, the replica misses one of the second
+        for (i, hour) in [0, 1, 1, 2, 3].iter().enumerate() {
+            let ts = 1505174400 + hour * 3600 + i as u32;
+            for store in &["primary", "replica"] {
+                if *store == "replica" && i == 2 {
+                    continue;
+                }
+                run(&mut state, &format!("ADD {}.000, {}, f, t, 0.07, 1.0; INTO {}", ts, i, store));
+            }
+        }
+        let remote = checksums_of(&run(&mut state, "CHECKSUMS primary"));
+        let local = checksums_of(&run(&mut state, "CHECKSUMS replica"));
+        assert_eq!(remote.len(), 4);
+        assert_eq!(remote.iter().map(|checksum| checksum.rows).collect::<Vec<_>>(), vec![1, 2, 1, 1]);
+        assert_eq!(divergent(&remote, &local), vec![(1505178000, 1505181600)]);
+        assert!(divergent(&remote, &remote).is_empty());
+        // a range of the checksums only covers its hours
+        assert_eq!(checksums_of(&run(&mut state, "CHECKSUMS primary FROM 1505178000 TO 1505181599")), &remote[1..2]);
+    }
+
+    #[test]
+    fn should_checksum_the_flags() {
+        let up = Update { ts: 1505174400000, seq: 1, is_trade: false, is_bid: true, price: 0.07, size: 1.0, flags: 0 };
+        let flagged = Update { flags: 1, ..up };
+        assert_ne!(checksums(&[up]), checksums(&[flagged]));
+    }
+
+    #[test]
+    fn should_refuse_a_response_over_the_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut line = [0; 5];
+            stream.read_exact(&mut line).unwrap();
+            stream.write_u8(0x1).unwrap();
+            stream.write_u64::<BigEndian>(MAX_UPLOAD_BYTES + 1).unwrap();
+        });
+        let mut remote = Remote::connect(&addr).unwrap();
+        let err = remote.send("INFO").unwrap_err();
+        assert!(err.contains("over the limit"), "{}", err);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn should_merge_adjacent_hours() {
+        let checksum = |from, crc| RangeChecksum { from, rows: 1, crc };
+        let remote = [checksum(0, 1), checksum(3600, 2), checksum(7200, 3), checksum(14400, 4)];
+        let local = [checksum(0, 1), checksum(3600, 0), checksum(14400, 0)];
+        assert_eq!(divergent(&remote, &local), vec![(3600, 10800), (14400, 18000)]);
+    }
+}
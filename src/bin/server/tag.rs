@@ -0,0 +1,64 @@
+/// Store tags
+///
+/// `META [db] exchange=binance asset_class=spot` tags a store, `META [db]
+/// asset_class=` removes a tag and `META [db]` returns the tags of a store as
+/// JSON. `FIND exchange=binance asset_class=spot` returns the stores with all
+/// of the tags, a value may be a pattern, e.g. `FIND exchange=*`, so programs
+/// can enumerate datasets without parsing store names.
+///
+/// Tags are kept in `[dtf_folder]/tags`, one `db <TAB> tag <TAB> value` line
+/// each. A later line for the same tag of a store wins, an empty value drops it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use utils;
+
+/// store name -> tag -> value
+pub type Tags = HashMap<String, BTreeMap<String, String>>;
+
+fn tags_fname(dtf_folder: &str) -> String {
+    utils::join(dtf_folder, "tags")
+}
+
+/// all tags kept in the folder
+pub fn read(dtf_folder: &str) -> Tags {
+    let mut tags : Tags = HashMap::new();
+    let file = match File::open(tags_fname(dtf_folder)) {
+        Ok(f) => f,
+        Err(_) => return tags
+    };
+    for line in BufReader::new(file).lines().filter_map(|line| line.ok()) {
+        let parts : Vec<&str> = line.split('\t').collect();
+        if parts.len() != 3 {
+            continue;
+        }
+        let store_tags = tags.entry(parts[0].to_owned()).or_insert_with(BTreeMap::new);
+        if parts[2].is_empty() {
+            store_tags.remove(parts[1]);
+        } else {
+            store_tags.insert(parts[1].to_owned(), parts[2].to_owned());
+        }
+    }
+    tags.retain(|_, store_tags| !store_tags.is_empty());
+    tags
+}
+
+/// Keep the tags of `store_name`, drop those with an empty value
+pub fn record(dtf_folder: &str, store_name: &str, tags: &[(String, String)]) -> Result<(), io::Error> {
+    let mut file = OpenOptions::new().create(true).append(true).open(tags_fname(dtf_folder))?;
+    let mut lines = String::new();
+    for &(ref tag, ref value) in tags {
+        lines.push_str(&format!("{}\t{}\t{}\n", store_name, tag, value));
+    }
+    // one write, a crash never keeps half of the tags
+    file.write_all(lines.as_bytes())
+}
+
+/// {"exchange": "binance", "asset_class": "spot"}
+pub fn to_json(tags: &BTreeMap<String, String>) -> String {
+    let fields : Vec<String> = tags.iter()
+        .map(|(tag, value)| format!("\"{}\": \"{}\"", tag, value))
+        .collect();
+    format!("{{{}}}", fields.join(", "))
+}
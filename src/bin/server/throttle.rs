@@ -0,0 +1,95 @@
+/// Write throttling during compaction
+///
+/// `COMPACT [db]` rewrites the files of a store one at a time and only
+/// holds the shared state while it swaps a rewritten file in, so inserts go
+/// on meanwhile. The store is not flushed until the compaction is done, its
+/// new rows pile up in memory, and so inserts into it are delayed, the more
+/// the more rows were inserted since the compaction started, by up to
+/// `--compaction_throttle` ms a row once THROTTLE_BACKLOG rows wait. `STATS
+/// [db]` shows the throttle of a store being compacted:
+///
+///     "throttle": {"compacting_ms": 5210, "files": 12, "files_done": 7, "inserted": 48200, "delay_us": 482, "delayed_ms": 11620}
+
+use std::time::{Duration, Instant};
+
+/// rows inserted during a compaction from which inserts wait `--compaction_throttle` ms
+static THROTTLE_BACKLOG : u64 = 100_000;
+
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    started: Instant,
+    /// files to rewrite
+    pub files: usize,
+    pub files_done: usize,
+    /// rows inserted since the compaction started
+    inserted: u64,
+    /// µs of the last delay
+    delay_us: u64,
+    /// µs inserts were delayed in total
+    delayed_us: u64,
+}
+
+impl Throttle {
+    pub fn new(files: usize) -> Throttle {
+        Throttle { started: Instant::now(), files, files_done: 0, inserted: 0, delay_us: 0, delayed_us: 0 }
+    }
+
+    /// how long the insert of one more row waits, at most `max_ms`
+    pub fn delay(&mut self, max_ms: u64) -> Duration {
+        self.inserted += 1;
+        self.delay_us = max_ms * 1000 * self.inserted.min(THROTTLE_BACKLOG) / THROTTLE_BACKLOG;
+        self.delayed_us += self.delay_us;
+        Duration::from_micros(self.delay_us)
+    }
+
+    pub fn to_json(&self) -> String {
+        let elapsed = self.started.elapsed();
+        format!(r#"{{"compacting_ms": {}, "files": {}, "files_done": {}, "inserted": {}, "delay_us": {}, "delayed_ms": {}}}"#,
+            elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis()), self.files, self.files_done,
+            self.inserted, self.delay_us, self.delayed_us / 1000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{self, Value};
+    use state::State;
+    use state::tests::{add, connect, global, run, run_err};
+
+    #[test]
+    fn should_delay_inserts_more_as_the_backlog_grows() {
+        let mut throttle = Throttle::new(3);
+        assert_eq!(throttle.delay(10), Duration::from_micros(0));
+        throttle.inserted = THROTTLE_BACKLOG / 2 - 1;
+        assert_eq!(throttle.delay(10), Duration::from_millis(5));
+        throttle.inserted = THROTTLE_BACKLOG * 3;
+        assert_eq!(throttle.delay(10), Duration::from_millis(10));
+        throttle.files_done = 1;
+        let json : Value = serde_json::from_str(&throttle.to_json()).unwrap();
+        assert_eq!((&json["files"], &json["files_done"]), (&Value::from(3), &Value::from(1)));
+        assert_eq!((&json["delay_us"], &json["delayed_ms"]), (&Value::from(10_000), &Value::from(15)));
+    }
+
+    #[test]
+    fn should_show_the_throttle_of_a_store_being_compacted() {
+        let global = global();
+        let mut state = connect(&global);
+        run(&mut state, "CREATE btc_usdt");
+        run(&mut state, "USE btc_usdt");
+        let stats = |state: &mut State| serde_json::from_str::<Value>(&run(state, "STATS btc_usdt")).unwrap();
+        assert!(stats(&mut state)["throttle"].is_null());
+
+        // inserts go on while the store is compacted, counted by its throttle
+        global.write().unwrap().compacting.insert("btc_usdt".to_owned(), Throttle::new(2));
+        add(&mut state, 0);
+        add(&mut state, 1);
+        let throttle = &stats(&mut state)["throttle"];
+        assert_eq!((&throttle["files"], &throttle["inserted"]), (&Value::from(2), &Value::from(2)));
+        assert!(run_err(&mut state, "COMPACT btc_usdt").starts_with("`btc_usdt` is being compacted."));
+        assert!(global.write().unwrap().throttle("eth_usdt").is_none());
+
+        global.write().unwrap().compacting.remove("btc_usdt");
+        assert!(stats(&mut state)["throttle"].is_null());
+    }
+}
@@ -0,0 +1,57 @@
+/// TLS listeners
+///
+/// A listener of `--listen ADDR,tls=IDENTITY` accepts TLS connections only,
+/// e.g. for collectors reaching the server over the internet. IDENTITY is a
+/// PKCS#12 archive of the certificate and its private key:
+///
+///     openssl pkcs12 -export -in cert.pem -inkey key.pem -out identity.p12
+///
+/// Its password is read from `TECTONIC_TLS_PASSWORD` rather than the command
+/// line, which other users see in `ps`. Requires the `tls` feature.
+
+#[cfg(feature = "tls")]
+use std::env;
+use std::net::TcpStream;
+
+/// environment variable with the password of the identities
+#[cfg(feature = "tls")]
+static PASSWORD_VAR : &str = "TECTONIC_TLS_PASSWORD";
+
+#[cfg(feature = "tls")]
+pub use native_tls::TlsAcceptor as Acceptor;
+
+#[cfg(feature = "tls")]
+pub fn acceptor(fname: &str) -> Result<Acceptor, String> {
+    use std::fs::File;
+    use std::io::Read;
+    use native_tls::Identity;
+
+    let mut der = Vec::new();
+    File::open(fname)
+        .and_then(|mut file| file.read_to_end(&mut der))
+        .map_err(|e| format!("Unable to read the TLS identity {}: {}", fname, e))?;
+    let password = env::var(PASSWORD_VAR).unwrap_or_default();
+    let identity = Identity::from_pkcs12(&der, &password)
+        .map_err(|e| format!("Unable to load the TLS identity {}: {}", fname, e))?;
+    Acceptor::new(identity).map_err(|e| format!("Unable to set up TLS with {}: {}", fname, e))
+}
+
+/// TLS handshake of a connection accepted by a TLS listener
+#[cfg(feature = "tls")]
+pub fn accept(acceptor: &Acceptor, stream: TcpStream) -> Result<::native_tls::TlsStream<TcpStream>, String> {
+    acceptor.accept(stream).map_err(|e| e.to_string())
+}
+
+/// no TLS listener can be set up without the `tls` feature
+#[cfg(not(feature = "tls"))]
+pub enum Acceptor {}
+
+#[cfg(not(feature = "tls"))]
+pub fn acceptor(fname: &str) -> Result<Acceptor, String> {
+    Err(format!("Unable to use the TLS identity {}, build with `--features tls` for TLS listeners", fname))
+}
+
+#[cfg(not(feature = "tls"))]
+pub fn accept(acceptor: &Acceptor, _stream: TcpStream) -> Result<TcpStream, String> {
+    match *acceptor {}
+}
@@ -0,0 +1,80 @@
+/// Tombstones
+///
+/// `DELETE FROM [db] WHERE ts BETWEEN a AND b` does not touch the dtf file.
+/// It records a tombstone covering [a, b] (in ms) that hides the rows from
+/// every read until COMPACT rewrites the file without them.
+///
+/// A tombstone also keeps the newest (ts, seq) of the store at the DELETE,
+/// and only hides rows up to it, so rows inserted afterwards, e.g. while
+/// COMPACT runs or into a range reaching into the future, are kept.
+///
+/// Tombstones are recorded next to the dtf file in `[fname].dtf.tombstones`,
+/// one `min_ts <TAB> max_ts <TAB> ts <TAB> seq` line each. Lines without the
+/// newest row, written before it was kept, hide every row of their range.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use dtf::Update;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tombstone {
+    /// deleted range in ms, inclusive
+    pub min_ts: u64,
+    pub max_ts: u64,
+    /// the newest (ts, seq) of the store when the range was deleted
+    pub newest: (u64, u32),
+}
+
+impl Tombstone {
+    /// a tombstone hiding every row of [min_ts, max_ts]
+    pub fn new(min_ts: u64, max_ts: u64) -> Tombstone {
+        Tombstone { min_ts, max_ts, newest: (u64::max_value(), u32::max_value()) }
+    }
+
+    pub fn hides(&self, up: &Update) -> bool {
+        up.ts >= self.min_ts && up.ts <= self.max_ts && (up.ts, up.seq) <= self.newest
+    }
+}
+
+fn tombstones_fname(dtf_fname: &str) -> String {
+    format!("{}.tombstones", dtf_fname)
+}
+
+/// all tombstones of a dtf file
+pub fn read(dtf_fname: &str) -> Vec<Tombstone> {
+    let file = match File::open(tombstones_fname(dtf_fname)) {
+        Ok(f) => f,
+        Err(_) => return Vec::new()
+    };
+    BufReader::new(file).lines()
+        .filter_map(|line| line.ok())
+        .filter_map(|line| {
+            let parts : Vec<&str> = line.split('\t').collect();
+            let tombstone = Tombstone::new(parts.get(0)?.parse().ok()?, parts.get(1)?.parse().ok()?);
+            match parts.len() {
+                2 => Some(tombstone),
+                4 => Some(Tombstone { newest: (parts[2].parse().ok()?, parts[3].parse().ok()?), ..tombstone }),
+                _ => None
+            }
+        })
+        .collect()
+}
+
+pub fn record(dtf_fname: &str, tombstone: Tombstone) -> Result<(), io::Error> {
+    let mut file = OpenOptions::new().create(true).append(true).open(tombstones_fname(dtf_fname))?;
+    writeln!(file, "{}\t{}\t{}\t{}", tombstone.min_ts, tombstone.max_ts, tombstone.newest.0, tombstone.newest.1)
+}
+
+/// remove every tombstone, once they are merged out of the file
+pub fn clear(dtf_fname: &str) -> Result<(), io::Error> {
+    let fname = tombstones_fname(dtf_fname);
+    if Path::new(&fname).exists() {
+        fs::remove_file(fname)?;
+    }
+    Ok(())
+}
+
+pub fn is_deleted(up: &Update, tombstones: &[Tombstone]) -> bool {
+    tombstones.iter().any(|tombstone| tombstone.hides(up))
+}
@@ -0,0 +1,98 @@
+/// Top-N queries
+///
+/// `TOPN [db] [n] BY [size|notional|spread] [FROM ts TO ts]` ranks the rows
+/// of a range on the server and returns only the `n` highest as a JSON array,
+/// the largest first:
+///
+///     TOPN btc_usdt 10 BY size FROM 1505174400 TO 1505260800
+///
+/// `size` and `notional` (price * size) rank the trades. `spread` ranks the
+/// quotes after which the best ask minus the best bid of the book built from
+/// the quotes of the range was widest, each row with its `"spread"`, one row
+/// per change of the spread. Every worker of the scan (see `scan::fold`)
+/// keeps a heap of `n` rows, a spread ranking is folded by one worker as the
+/// book is built in order.
+
+use dtf::Update;
+use dtf::topn::TopN;
+use derived::{Field, Top};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum By {
+    Size,
+    Notional,
+    Spread,
+}
+
+impl By {
+    pub fn parse(name: &str) -> Option<By> {
+        match name {
+            "size" => Some(By::Size),
+            "notional" => Some(By::Notional),
+            "spread" => Some(By::Spread),
+            _ => None
+        }
+    }
+
+    /// the rows have to be folded in order
+    pub fn is_sequential(self) -> bool {
+        self == By::Spread
+    }
+}
+
+/// The rows folded so far ranked by `by`
+pub struct Ranking {
+    by: By,
+    top: TopN,
+    book: Top,
+    spread: Option<f32>,
+}
+
+impl Ranking {
+    pub fn new(by: By, n: usize) -> Ranking {
+        Ranking { by, top: TopN::new(n), book: Top::default(), spread: None }
+    }
+
+    pub fn add(&mut self, ups: &[Update]) {
+        for up in ups.iter() {
+            match self.by {
+                By::Size => if up.is_trade {
+                    self.top.push(f64::from(up.size), up);
+                },
+                By::Notional => if up.is_trade {
+                    self.top.push(f64::from(up.price) * f64::from(up.size), up);
+                },
+                By::Spread => if !up.is_trade {
+                    self.book.add(up);
+                    let spread = match (self.book.get(Field::Ask), self.book.get(Field::Bid)) {
+                        (Some(ask), Some(bid)) => Some(ask - bid),
+                        _ => None
+                    };
+                    if let Some(spread) = spread {
+                        if self.spread != Some(spread) {
+                            self.top.push(f64::from(spread), up);
+                        }
+                    }
+                    self.spread = spread;
+                },
+            }
+        }
+    }
+
+    /// rank the rows of both, `other` folded the later rows
+    pub fn merge(&mut self, other: Ranking) {
+        self.top.merge(other.top);
+    }
+
+    pub fn to_json(self) -> String {
+        let by = self.by;
+        let rows : Vec<String> = self.top.into_sorted().into_iter().map(|(key, row)| match by {
+            By::Spread => {
+                let json = row.to_json();
+                format!(r#"{},"spread":{}}}"#, &json[..json.len() - 1], key as f32)
+            },
+            _ => row.to_json()
+        }).collect();
+        format!("[{}]\n", rows.join(","))
+    }
+}
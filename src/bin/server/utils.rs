@@ -1,7 +1,77 @@
 use std::path::Path;
 use std::fs;
+use std::io;
+#[cfg(windows)]
+use std::thread;
+#[cfg(windows)]
+use std::time::Duration;
 use state::*;
 use dtf;
+use filestats;
+use tombstone;
+use correction;
+use partition;
+use plugins::{retention, tiering};
+
+/// attempts to replace or remove a file on Windows, see `retry`
+#[cfg(windows)]
+static FILE_RETRIES : u32 = 20;
+#[cfg(windows)]
+static FILE_RETRY_MS : u64 = 50;
+
+/// `folder/name` with the separator of the platform
+pub fn join(folder: &str, name: &str) -> String {
+    Path::new(folder).join(name).to_string_lossy().into_owned()
+}
+
+/// the dtf file `[folder]/[fname].dtf` of a store
+pub fn dtf_fname(folder: &str, fname: &str) -> String {
+    join(folder, &format!("{}.dtf", fname))
+}
+
+/// Windows refuses to replace or remove a file while another process, e.g.
+/// a virus scanner or a backup agent, has it open without sharing it, so
+/// these operations are retried for a while there.
+#[cfg(windows)]
+fn retry<F: FnMut() -> io::Result<()>>(mut op: F) -> io::Result<()> {
+    for _ in 0..(FILE_RETRIES - 1) {
+        match op() {
+            Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied =>
+                thread::sleep(Duration::from_millis(FILE_RETRY_MS)),
+            result => return result
+        }
+    }
+    op()
+}
+
+#[cfg(not(windows))]
+fn retry<F: FnMut() -> io::Result<()>>(mut op: F) -> io::Result<()> {
+    op()
+}
+
+/// Atomically replace `to` with `from`, like `fs::rename`. The batch index
+/// of a dtf file, see `dtf::batch_index_fname`, goes along with it.
+pub fn replace_file(from: &str, to: &str) -> io::Result<()> {
+    retry(|| fs::rename(from, to))?;
+    if to.ends_with(".dtf") {
+        let index = dtf::batch_index_fname(from);
+        if Path::new(&index).exists() {
+            retry(|| fs::rename(&index, dtf::batch_index_fname(to)))?;
+        } else {
+            dtf::remove_batch_index(to);
+        }
+    }
+    Ok(())
+}
+
+/// Remove a file, like `fs::remove_file`, and the batch index of a dtf file
+pub fn remove_file(fname: &str) -> io::Result<()> {
+    retry(|| fs::remove_file(fname))?;
+    if fname.ends_with(".dtf") {
+        dtf::remove_batch_index(fname);
+    }
+    Ok(())
+}
 
 pub fn create_dir_if_not_exist(dtf_folder : &str) {
     if !Path::new(dtf_folder).exists() {
@@ -25,30 +95,117 @@ pub fn init_dbs(state: &mut State) {
                        .unwrap()
                        .to_str()
                        .unwrap(); // sldjf-lks-djflk-sfsd--something
-            let full_path = &format!("{}/{}", dtf_folder, stem);
-            let header_size = dtf::get_size(full_path);
+            let full_path = &join(&dtf_folder, stem);
+            let tier = tiering::read_tier(full_path);
+            let cold_size = match tier {
+                Some((ref cold_folder, _)) => {
+                    let cold_fname = tiering::cold_fname(cold_folder, full_path);
+                    if Path::new(&cold_fname).exists() { dtf::get_size(&cold_fname) } else { 0 }
+                },
+                None => 0
+            };
+            // the row count is in the stats sidecar, unless it is stale
+            let size = filestats::read(full_path)
+                .map(|stats| stats.summary.count)
+                .unwrap_or_else(|| dtf::get_size(full_path));
+            let header_size = size + partition::count(full_path) + cold_size;
             let symbol = dtf::read_meta(full_path).symbol;
+            let last = dtf::read_newest(full_path).or_else(|| partition::newest(full_path));
 
-            {
-                let rdr = state.global.read().unwrap();
-                if rdr.vec_store.contains_key(&symbol) {
-                    return;
-                }
-            }
-
-            // insert a vector into shared hashmap
-            {
+            // insert a vector into shared hashmap, unless an earlier client did
+            let is_loaded = state.global.read().unwrap().vec_store.contains_key(&symbol);
+            if !is_loaded {
                 let mut global = state.global.write().unwrap();
                 global.vec_store.insert(symbol.to_owned(), (Vec::new(), header_size));
+                global.watermark_mut(&symbol).disk = last;
+                let tombstones = tombstone::read(full_path);
+                if !tombstones.is_empty() {
+                    global.tombstones.insert(symbol.to_owned(), tombstones);
+                }
+                if let Some((cold_folder, ms)) = tier {
+                    global.tiers.insert(symbol.to_owned(), Tier { ms, cold_folder, fname: full_path.to_owned() });
+                }
+                if let Some(ms) = retention::read_ttl(full_path) {
+                    global.ttls.insert(symbol.to_owned(), Ttl { ms, fname: full_path.to_owned() });
+                }
+                let corrections = correction::read(full_path);
+                if !corrections.is_empty() {
+                    global.corrections.insert(symbol.to_owned(), corrections);
+                }
             }
 
             // insert a db store into user state
             state.store.insert(symbol.to_owned(), Store {
                 name: symbol.to_owned(),
                 fname: basename.to_owned(),
-                in_memory: false,
                 global: state.global.clone()
             });
         }
     }
-}
\ No newline at end of file
+
+    // stores created but never flushed, see `catalog`
+    let catalog = state.global.read().unwrap().catalog.clone();
+    for (name, entry) in catalog {
+        if state.store.contains_key(&name) {
+            continue;
+        }
+        {
+            let mut global = state.global.write().unwrap();
+            global.vec_store.entry(name.to_owned()).or_insert_with(|| (Vec::new(), 0));
+            if entry.memory {
+                global.ephemeral.insert(name.to_owned());
+            }
+        }
+        state.store.insert(name.to_owned(), Store {
+            name: name.to_owned(),
+            fname: entry.fname,
+            global: state.global.clone()
+        });
+    }
+
+    // stores kept in a storage backend
+    let backend = match state.global.read().unwrap().backend {
+        Some(ref backend) => backend.clone(),
+        None => return
+    };
+    for name in backend.stores() {
+        if state.store.contains_key(&name) {
+            continue;
+        }
+        // counted without holding the shared state, an object store is listed
+        let count = backend.count(&name);
+        state.global.write().unwrap().vec_store.entry(name.to_owned()).or_insert_with(|| (Vec::new(), count));
+        state.store.insert(name.to_owned(), Store {
+            name: name.to_owned(),
+            fname: name.to_owned(),
+            global: state.global.clone()
+        });
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::MAIN_SEPARATOR;
+
+    #[test]
+    fn should_join_paths_with_the_separator_of_the_platform() {
+        assert_eq!(join("db", "btc_usdt.dtf"), format!("db{}btc_usdt.dtf", MAIN_SEPARATOR));
+        assert_eq!(dtf_fname("db", "btc_usdt"), join("db", "btc_usdt.dtf"));
+        // a trailing separator is not doubled
+        assert_eq!(join(&format!("db{}", MAIN_SEPARATOR), "btc_usdt.dtf"), join("db", "btc_usdt.dtf"));
+    }
+
+    #[test]
+    fn should_replace_and_remove_files() {
+        let (from, to) = ("test-utils-replace.tmp", "test-utils-replace.dtf");
+        fs::write(from, b"new").unwrap();
+        fs::write(to, b"old").unwrap();
+        replace_file(from, to).unwrap();
+        assert!(!Path::new(from).exists());
+        assert_eq!(fs::read(to).unwrap(), b"new");
+
+        remove_file(to).unwrap();
+        assert!(!Path::new(to).exists());
+        assert_eq!(remove_file(to).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,107 @@
+/// Zone maps
+///
+/// Every write of a dtf file leaves `[fname].dtf.zones` next to it, with
+/// the ranges of each batch:
+///
+///     len <file length (bytes)>
+///     <offset> <min ts> <max ts> <rows> <min price> <max price> <min size> <max size>
+///     ...
+///
+/// so `COPY ... WHERE price > X` skips the batches none of whose rows can
+/// match without decoding them, see `RowFilter::may_match`. An append only
+/// reads the new batches. A sidecar whose length is not the length of the
+/// file is rebuilt from the file.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use dtf::{self, BatchRange, BatchZone};
+use utils;
+
+fn zones_fname(dtf_fname: &str) -> String {
+    format!("{}.zones", dtf_fname)
+}
+
+fn file_len(dtf_fname: &str) -> u64 {
+    fs::metadata(dtf_fname).map(|m| m.len()).unwrap_or(0)
+}
+
+/// the zone maps of a dtf file, unless the sidecar is missing or stale
+pub fn read(dtf_fname: &str) -> Option<Vec<BatchZone>> {
+    read_at(dtf_fname, file_len(dtf_fname))
+}
+
+/// the zone maps, if the sidecar describes the file when it was `len` bytes long
+fn read_at(dtf_fname: &str, len: u64) -> Option<Vec<BatchZone>> {
+    let mut text = String::new();
+    File::open(zones_fname(dtf_fname)).ok()?.read_to_string(&mut text).ok()?;
+    let mut lines = text.lines();
+    let line = lines.next()?;
+    if !line.starts_with("len ") || line[4..].parse::<u64>().ok()? != len {
+        return None;
+    }
+    lines.map(parse_zone).collect()
+}
+
+fn parse_zone(line: &str) -> Option<BatchZone> {
+    let fields : Vec<&str> = line.split(' ').collect();
+    if fields.len() != 8 {
+        return None;
+    }
+    Some(BatchZone {
+        batch: BatchRange {
+            offset: fields[0].parse().ok()?,
+            min_ts: fields[1].parse().ok()?,
+            max_ts: fields[2].parse().ok()?,
+            count: fields[3].parse().ok()?,
+        },
+        min_price: fields[4].parse().ok()?,
+        max_price: fields[5].parse().ok()?,
+        min_size: fields[6].parse().ok()?,
+        max_size: fields[7].parse().ok()?,
+    })
+}
+
+fn write(dtf_fname: &str, zones: &[BatchZone]) -> Result<(), io::Error> {
+    let tmp = format!("{}.tmp", zones_fname(dtf_fname));
+    {
+        let mut file = File::create(&tmp)?;
+        writeln!(file, "len {}", file_len(dtf_fname))?;
+        for zone in zones {
+            writeln!(file, "{} {} {} {} {} {} {} {}", zone.batch.offset, zone.batch.min_ts, zone.batch.max_ts,
+                zone.batch.count, zone.min_price, zone.max_price, zone.min_size, zone.max_size)?;
+        }
+    }
+    utils::replace_file(&tmp, &zones_fname(dtf_fname))
+}
+
+fn save(dtf_fname: &str, zones: &[BatchZone]) {
+    if let Err(e) = write(dtf_fname, zones) {
+        error!("Unable to write zone maps of {}: {:?}", dtf_fname, e);
+    }
+}
+
+/// zone maps of the batches of a dtf file, from the sidecar or rebuilt from the file
+pub fn get(dtf_fname: &str) -> Vec<BatchZone> {
+    match read(dtf_fname) {
+        Some(zones) => zones,
+        None => written(dtf_fname)
+    }
+}
+
+/// After the dtf file was (re)written
+pub fn written(dtf_fname: &str) -> Vec<BatchZone> {
+    let zones = dtf::read_batch_zones(dtf_fname, 0);
+    save(dtf_fname, &zones);
+    zones
+}
+
+/// After batches were appended to the dtf file, which was `old_len` bytes long
+pub fn appended(dtf_fname: &str, old_len: u64) {
+    match read_at(dtf_fname, old_len) {
+        Some(mut zones) => {
+            zones.extend(dtf::read_batch_zones(dtf_fname, old_len));
+            save(dtf_fname, &zones);
+        }
+        None => { written(dtf_fname); }
+    }
+}
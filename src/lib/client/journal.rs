@@ -0,0 +1,93 @@
+/// Journal of the commands received by a server
+///
+/// A server started with `--journal [file]` appends every command it
+/// receives as one `ms since epoch <TAB> peer address <TAB> command` line,
+/// and `tectonic-replay` sends a journal back to a server, one connection
+/// per peer, paced like the original traffic.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, PartialEq)]
+pub struct JournalEntry {
+    pub ts: u64,
+    pub peer: String,
+    pub command: String,
+}
+
+impl JournalEntry {
+    pub fn to_line(&self) -> String {
+        format!("{}\t{}\t{}\n", self.ts, self.peer, self.command)
+    }
+
+    pub fn parse(line: &str) -> Option<JournalEntry> {
+        let mut parts = line.splitn(3, '\t');
+        Some(JournalEntry {
+            ts: parts.next()?.parse().ok()?,
+            peer: parts.next()?.to_owned(),
+            command: parts.next()?.trim_right_matches('\n').to_owned(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    file: Mutex<File>,
+}
+
+impl Journal {
+    pub fn open(path: &str) -> Result<Journal, io::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Journal { file: Mutex::new(file) })
+    }
+
+    /// append a command, written in one piece so it survives a crash of the server
+    pub fn record(&self, peer: &str, command: &str) -> Result<(), io::Error> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let entry = JournalEntry {
+            ts: now.as_secs() * 1000 + u64::from(now.subsec_nanos()) / 1_000_000,
+            peer: peer.to_owned(),
+            command: command.to_owned(),
+        };
+        let mut file = self.file.lock().unwrap();
+        file.write_all(entry.to_line().as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Read;
+
+    #[test]
+    fn should_parse_journal_line() {
+        let entry = JournalEntry {
+            ts: 1505177459658,
+            peer: "127.0.0.1:52000".to_owned(),
+            command: "ADD 1505177459.658, 139010, t, f, 0.07, 7.6; INTO btc".to_owned(),
+        };
+        assert_eq!(JournalEntry::parse(&entry.to_line()), Some(entry));
+        assert_eq!(JournalEntry::parse("abc\tpeer\tPING"), None);
+        assert_eq!(JournalEntry::parse("1505177459658\tpeer"), None);
+    }
+
+    #[test]
+    fn should_record_commands() {
+        let fname = "test-journal.log";
+        let _ = fs::remove_file(fname);
+        {
+            let journal = Journal::open(fname).unwrap();
+            journal.record("a", "PING").unwrap();
+            journal.record("b", "INFO").unwrap();
+        }
+        let mut s = String::new();
+        File::open(fname).unwrap().read_to_string(&mut s).unwrap();
+        let entries : Vec<JournalEntry> = s.lines().filter_map(JournalEntry::parse).collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[1].peer.as_str(), entries[1].command.as_str()), ("b", "INFO"));
+        fs::remove_file(fname).unwrap();
+    }
+}
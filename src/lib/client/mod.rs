@@ -0,0 +1,291 @@
+/// Client for tectonic-server
+///
+/// When created with a spool, every insert is first written to the on-disk
+/// spool and then delivered. If the server is unreachable the inserts stay
+/// spooled and are replayed, in order, on the next successful connection.
+/// Replayed inserts carry an idempotency key so the server drops the ones
+/// it already received.
+///
+/// Connected to a server of an HA pair, the client asks for its `TOPOLOGY`
+/// and moves to the primary. When the primary becomes unreachable, or a
+/// server refuses a write because it is no longer the primary, the client
+/// asks the servers of the pair it knows for the new primary, so a
+/// collector only needs the address of one of them.
+
+pub mod spool;
+pub use self::spool::*;
+
+pub mod journal;
+pub use self::journal::*;
+
+pub mod topology;
+pub use self::topology::*;
+
+use update::Update;
+use utils::crc32_update;
+use byteorder::{BigEndian, ReadBytesExt};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::iter;
+use std::str;
+
+/// errors of a server of an HA pair that is not the primary
+static NOT_PRIMARY : &[&str] = &["Standby of", "Not the primary"];
+
+pub struct Client {
+    addr: String,
+    /// servers of the HA pair, learned from `TOPOLOGY`
+    nodes: Vec<String>,
+    stream: Option<TcpStream>,
+    spool: Option<Spool>,
+}
+
+impl Client {
+    /// a client without local buffering. Connects lazily.
+    pub fn new(addr: &str) -> Client {
+        Client {
+            addr: addr.to_owned(),
+            nodes: vec![addr.to_owned()],
+            stream: None,
+            spool: None,
+        }
+    }
+
+    /// a client that buffers inserts in the spool file at `spool_path`
+    pub fn with_spool(addr: &str, spool_path: &str) -> Result<Client, io::Error> {
+        Ok(Client {
+            addr: addr.to_owned(),
+            nodes: vec![addr.to_owned()],
+            stream: None,
+            spool: Some(Spool::open(spool_path)?),
+        })
+    }
+
+    fn connect(&mut self) -> Result<(), io::Error> {
+        if self.stream.is_none() {
+            let stream = match open(&self.addr) {
+                Ok(stream) => stream,
+                Err(e) => self.failover().ok_or(e)?
+            };
+            self.stream = Some(stream);
+            self.follow();
+        }
+        Ok(())
+    }
+
+    /// Move to the primary the connected server knows, if it is another one
+    fn follow(&mut self) {
+        let topology = match self.stream.as_mut().and_then(topology) {
+            Some(topology) => topology,
+            None => return
+        };
+        if topology.primary != self.addr {
+            match open(&topology.primary) {
+                Ok(stream) => {
+                    self.stream = Some(stream);
+                    self.addr = topology.primary.clone();
+                }
+                // not reachable from here, stay
+                Err(_) => (),
+            }
+        }
+        self.learn(topology);
+    }
+
+    /// Connect to the primary, asking every other known server for it
+    fn failover(&mut self) -> Option<TcpStream> {
+        for node in self.nodes.clone() {
+            if node == self.addr {
+                continue;
+            }
+            let mut stream = match open(&node) {
+                Ok(stream) => stream,
+                Err(_) => continue
+            };
+            let topology = match topology(&mut stream) {
+                Some(topology) => topology,
+                None => continue
+            };
+            let stream = if topology.primary == node { Ok(stream) } else { open(&topology.primary) };
+            if let Ok(stream) = stream {
+                self.addr = topology.primary.clone();
+                self.learn(topology);
+                return Some(stream);
+            }
+        }
+        None
+    }
+
+    fn learn(&mut self, topology: Topology) {
+        for node in iter::once(topology.primary).chain(topology.replicas) {
+            if !self.nodes.contains(&node) {
+                self.nodes.push(node);
+            }
+        }
+    }
+
+    /// the primary and its standbys, if connected to an HA pair
+    pub fn topology(&mut self) -> Result<Option<Topology>, io::Error> {
+        self.connect()?;
+        Ok(self.stream.as_mut().and_then(topology))
+    }
+
+    /// send a command that returns a string. Server errors are returned as `io::ErrorKind::Other`.
+    ///
+    /// A write refused because the server is no longer the primary is sent
+    /// again to the new one. While the pair has no primary it is returned as
+    /// `io::ErrorKind::NotConnected`, so spooled inserts are kept.
+    pub fn cmd(&mut self, command: &str) -> Result<String, io::Error> {
+        match self.send(command) {
+            // the pair failed over
+            Err(ref e) if is_not_primary(e) => self.follow(),
+            ret => return ret
+        }
+        match self.send(command) {
+            Err(ref e) if is_not_primary(e) => {
+                self.stream = None;
+                Err(io::Error::new(io::ErrorKind::NotConnected, e.to_string()))
+            }
+            ret => ret
+        }
+    }
+
+    fn send(&mut self, command: &str) -> Result<String, io::Error> {
+        self.connect()?;
+        let ret = {
+            let stream = self.stream.as_mut().unwrap();
+            send(stream, command)
+        };
+        if let Err(ref e) = ret {
+            if e.kind() != io::ErrorKind::Other {
+                // drop the broken connection, reconnect next time
+                self.stream = None;
+            }
+        }
+        ret
+    }
+
+    /// insert an update into `store`
+    ///
+    /// With a spool this never fails because the server is down: the update
+    /// is kept on disk until it can be delivered.
+    pub fn insert(&mut self, up: &Update, store: &str) -> Result<(), io::Error> {
+        let command = format!("ADD {} INTO {}", to_add_line(up), store);
+        if self.spool.is_some() {
+            self.spool.as_mut().unwrap().push(&command)?;
+            let _ = self.replay();
+            Ok(())
+        } else {
+            self.cmd(&command).map(|_| ())
+        }
+    }
+
+    /// insert `ups` into `store` with one `BULKADD ... COUNT n CRC x`.
+    /// The server inserts either every row or, if any was lost, none.
+    pub fn bulkadd(&mut self, ups: &[Update], store: &str) -> Result<(), io::Error> {
+        let lines : Vec<String> = ups.iter().map(to_add_line).collect();
+        let crc = lines.iter().fold(0, |crc, line| {
+            crc32_update(crc32_update(crc, line.as_bytes()), b"\n")
+        });
+        self.cmd(&format!("BULKADD INTO {} COUNT {} CRC {:08x}", store, lines.len(), crc))?;
+        for line in &lines {
+            self.cmd(line)?;
+        }
+        self.cmd("DDAKLUB").map(|_| ())
+    }
+
+    /// deliver spooled commands. Returns how many were delivered.
+    pub fn replay(&mut self) -> Result<usize, io::Error> {
+        let pending = match self.spool {
+            Some(ref spool) => spool.pending()?,
+            None => return Ok(0),
+        };
+
+        let mut delivered = 0;
+        let mut ret = Ok(());
+        for &(ref key, ref command) in pending.iter() {
+            match self.cmd(&format!("{} KEY {}", command, key)) {
+                // rejected by the server, retrying will not help
+                Err(ref e) if e.kind() == io::ErrorKind::Other => delivered += 1,
+                Err(e) => { ret = Err(e); break; },
+                Ok(_) => delivered += 1,
+            }
+        }
+
+        if delivered > 0 {
+            self.spool.as_mut().unwrap().ack(delivered)?;
+        }
+        ret.map(|_| delivered)
+    }
+
+    /// number of spooled commands waiting for delivery
+    pub fn pending(&self) -> usize {
+        match self.spool {
+            Some(ref spool) => spool.pending().map(|v| v.len()).unwrap_or(0),
+            None => 0,
+        }
+    }
+}
+
+fn open(addr: &str) -> Result<TcpStream, io::Error> {
+    let stream = TcpStream::connect(addr)?;
+    // requests are small and wait on their response
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// the topology of the HA pair of the server, `None` outside of one
+fn topology(stream: &mut TcpStream) -> Option<Topology> {
+    send(stream, "TOPOLOGY").ok()
+        .and_then(|json| Topology::parse(&json))
+        .filter(|topology| topology.has_primary())
+}
+
+fn is_not_primary(e: &io::Error) -> bool {
+    let body = e.to_string();
+    e.kind() == io::ErrorKind::Other
+        && NOT_PRIMARY.iter().any(|prefix| body.starts_with(&format!("ERR: {}", prefix)))
+}
+
+fn send(stream: &mut TcpStream, command: &str) -> Result<String, io::Error> {
+    // one write: the server treats every read as whole lines
+    stream.write_all(format!("{}\n", command).as_bytes())?;
+    let success = stream.read_u8()? == 0x1;
+    let size = stream.read_u64::<BigEndian>()?;
+    let mut buf = vec![0; size as usize];
+    stream.read_exact(&mut buf)?;
+    let body = str::from_utf8(&buf)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        .to_owned();
+    if success {
+        Ok(body)
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other, body))
+    }
+}
+
+/// format an update the way `ADD` expects it
+///
+/// 1505177459.658, 139010, t, f, 0.0703629, 7.65064249;
+///
+/// with the flags as a 7th field if any is set
+fn to_add_line(up: &Update) -> String {
+    let line = format!("{}.{:03}, {}, {}, {}, {}, {}",
+        up.ts / 1000, up.ts % 1000, up.seq,
+        if up.is_trade { "t" } else { "f" },
+        if up.is_bid { "t" } else { "f" },
+        up.price, up.size);
+    if up.flags == 0 { format!("{};", line) } else { format!("{}, {};", line, up.flags) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_format_add_line() {
+        let up = Update { ts: 1505177459050, seq: 139010, is_trade: true, is_bid: false, price: 0.0703629, size: 7.5, flags: 0 };
+        assert_eq!(to_add_line(&up), "1505177459.050, 139010, t, f, 0.0703629, 7.5;");
+        assert_eq!(to_add_line(&Update { flags: 5, ..up }), "1505177459.050, 139010, t, f, 0.0703629, 7.5, 5;");
+    }
+}
@@ -0,0 +1,252 @@
+/// Durable on-disk queue of commands that could not be delivered.
+///
+/// Each line of the spool file is `<key>\t<command>`. Keys are increasing
+/// and are sent along with the command as an idempotency key so a replayed
+/// command that already reached the server is not inserted twice.
+///
+/// Commands are appended to the spool, acknowledged ones are counted in a
+/// `.state` file next to it, along with the key prefix and the next key. The
+/// spool is only rewritten without the acknowledged commands once all of them,
+/// or more than `COMPACT_AFTER`, are acknowledged.
+
+use std::collections::hash_map::RandomState;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::SystemTime;
+
+/// acknowledged commands kept in the spool before it is compacted
+const COMPACT_AFTER : usize = 1024;
+
+pub struct Spool {
+    path: PathBuf,
+    /// idempotency key prefix, random per spool so collectors don't share keys
+    prefix: String,
+    next_key: u64,
+    /// commands at the start of the spool already delivered
+    acked: usize,
+}
+
+impl Spool {
+    /// opens (or creates) the spool at `path`
+    pub fn open(path: &str) -> Result<Spool, io::Error> {
+        let path = PathBuf::from(path);
+        if !path.exists() {
+            File::create(&path)?;
+        }
+        let mut spool = match read_state(&state_path(&path))? {
+            Some((prefix, next_key, acked)) => Spool { path, prefix, next_key, acked },
+            None => Spool { path, prefix: random_prefix(), next_key: 0, acked: 0 },
+        };
+        // commands pushed since the state was last written
+        let last_key = spool.lines()?.last().and_then(|&(ref key, _)| spool.parse_key(key));
+        if let Some(key) = last_key {
+            spool.next_key = spool.next_key.max(key + 1);
+        }
+        spool.write_state()?;
+        Ok(spool)
+    }
+
+    fn parse_key(&self, key: &str) -> Option<u64> {
+        let (prefix, n) = key.split_at(key.rfind(':')?);
+        if prefix != self.prefix {
+            return None;
+        }
+        n[1..].parse::<u64>().ok()
+    }
+
+    /// append a command, returns its idempotency key
+    pub fn push(&mut self, command: &str) -> Result<String, io::Error> {
+        let key = format!("{}:{}", self.prefix, self.next_key);
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        writeln!(file, "{}\t{}", key, command)?;
+        file.sync_data()?;
+        self.next_key += 1;
+        Ok(key)
+    }
+
+    /// every line of the spool, acknowledged or not
+    fn lines(&self) -> Result<Vec<(String, String)>, io::Error> {
+        let rdr = BufReader::new(File::open(&self.path)?);
+        let mut v = Vec::new();
+        for line in rdr.lines() {
+            let line = line?;
+            let mut parts = line.splitn(2, '\t');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(cmd)) => v.push((key.to_owned(), cmd.to_owned())),
+                _ => continue, // torn write
+            }
+        }
+        Ok(v)
+    }
+
+    /// every command not yet acknowledged, oldest first
+    pub fn pending(&self) -> Result<Vec<(String, String)>, io::Error> {
+        Ok(self.lines()?.into_iter().skip(self.acked).collect())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending().map(|v| v.is_empty()).unwrap_or(true)
+    }
+
+    /// drop the first `n` pending commands
+    pub fn ack(&mut self, n: usize) -> Result<(), io::Error> {
+        let lines = self.lines()?;
+        self.acked = (self.acked + n).min(lines.len());
+        if self.acked < lines.len() && self.acked <= COMPACT_AFTER {
+            return self.write_state();
+        }
+
+        // the state is written first: if the spool is not rewritten after all,
+        // acknowledged commands are replayed and dropped by their key
+        let rest = &lines[self.acked..];
+        self.acked = 0;
+        self.write_state()?;
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            for &(ref key, ref cmd) in rest.iter() {
+                writeln!(file, "{}\t{}", key, cmd)?;
+            }
+            file.sync_data()?;
+        }
+        fs::rename(&tmp, &self.path)
+    }
+
+    fn write_state(&self) -> Result<(), io::Error> {
+        let path = state_path(&self.path);
+        let tmp = path.with_extension("state.tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            writeln!(file, "{}\t{}\t{}", self.prefix, self.next_key, self.acked)?;
+            file.sync_data()?;
+        }
+        fs::rename(&tmp, &path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+fn state_path(path: &Path) -> PathBuf {
+    path.with_extension("state")
+}
+
+/// (prefix, next key, acknowledged commands), None without a state file
+fn read_state(path: &Path) -> Result<Option<(String, u64, usize)>, io::Error> {
+    let mut contents = String::new();
+    match File::open(path) {
+        Ok(mut file) => file.read_to_string(&mut contents)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let parts = contents.trim_end().split('\t').collect::<Vec<_>>();
+    match (parts.get(0), parts.get(1).and_then(|n| n.parse().ok()), parts.get(2).and_then(|n| n.parse().ok())) {
+        (Some(prefix), Some(next_key), Some(acked)) if !prefix.is_empty() =>
+            Ok(Some((prefix.to_string(), next_key, acked))),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid spool state {}", path.display()))),
+    }
+}
+
+/// a key prefix unlikely to be picked by another spool, on this host or another
+fn random_prefix() -> String {
+    // `RandomState` is seeded randomly by the OS
+    let mut hasher = RandomState::new().build_hasher();
+    process::id().hash(&mut hasher);
+    SystemTime::now().hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remove(fname: &str) {
+        let _ = fs::remove_file(fname);
+        let _ = fs::remove_file(state_path(Path::new(fname)));
+    }
+
+    #[test]
+    fn should_push_and_ack() {
+        let fname = "test-spool.spool";
+        remove(fname);
+
+        let mut spool = Spool::open(fname).unwrap();
+        assert!(spool.is_empty());
+        let key = spool.push("ADD 1, 1, f, t, 1.0, 1.0; INTO a").unwrap();
+        assert!(key.ends_with(":0"));
+        let prefix = &key[..(key.len() - 2)];
+        assert_eq!(spool.push("ADD 2, 2, f, t, 1.0, 1.0; INTO a").unwrap(), format!("{}:1", prefix));
+
+        // keys keep increasing after reopening
+        let mut spool = Spool::open(fname).unwrap();
+        assert_eq!(spool.pending().unwrap().len(), 2);
+        spool.ack(1).unwrap();
+        let pending = spool.pending().unwrap();
+        assert_eq!(pending, vec![(format!("{}:1", prefix), "ADD 2, 2, f, t, 1.0, 1.0; INTO a".to_owned())]);
+        assert_eq!(spool.push("PING").unwrap(), format!("{}:2", prefix));
+
+        // the acknowledged command stays in the spool until all are
+        assert_eq!(spool.lines().unwrap().len(), 3);
+        let spool = Spool::open(fname).unwrap();
+        assert_eq!(spool.pending().unwrap().len(), 2);
+
+        remove(fname);
+    }
+
+    #[test]
+    fn should_not_reuse_keys_once_all_are_acked() {
+        let fname = "test-spool-acked.spool";
+        remove(fname);
+
+        let mut spool = Spool::open(fname).unwrap();
+        spool.push("PING").unwrap();
+        let key = spool.push("PING").unwrap();
+        spool.ack(2).unwrap();
+        assert_eq!(spool.lines().unwrap().len(), 0);
+
+        let mut spool = Spool::open(fname).unwrap();
+        assert!(spool.is_empty());
+        let next = spool.push("PING").unwrap();
+        assert_ne!(next, key);
+        assert!(next.ends_with(":2"));
+
+        remove(fname);
+    }
+
+    #[test]
+    fn should_compact_after_many_acks() {
+        let fname = "test-spool-compact.spool";
+        remove(fname);
+
+        let mut spool = Spool::open(fname).unwrap();
+        for _ in 0..(COMPACT_AFTER + 2) {
+            spool.push("PING").unwrap();
+        }
+        spool.ack(COMPACT_AFTER).unwrap();
+        assert_eq!(spool.lines().unwrap().len(), COMPACT_AFTER + 2);
+        spool.ack(1).unwrap();
+        assert_eq!(spool.lines().unwrap().len(), 1);
+        assert_eq!(spool.pending().unwrap().len(), 1);
+        assert!(spool.pending().unwrap()[0].0.ends_with(&format!(":{}", COMPACT_AFTER + 1)));
+
+        remove(fname);
+    }
+
+    #[test]
+    fn should_pick_a_prefix_per_spool() {
+        let (a, b) = ("test-spool-a/orders.spool", "test-spool-b/orders.spool");
+        let _ = fs::create_dir_all("test-spool-a");
+        let _ = fs::create_dir_all("test-spool-b");
+
+        let key_a = Spool::open(a).unwrap().push("PING").unwrap();
+        let key_b = Spool::open(b).unwrap().push("PING").unwrap();
+        assert_ne!(key_a, key_b);
+
+        let _ = fs::remove_dir_all("test-spool-a");
+        let _ = fs::remove_dir_all("test-spool-b");
+    }
+}
@@ -0,0 +1,53 @@
+/// Where the servers of an HA pair are
+///
+/// `TOPOLOGY` returns the address of the primary and of its standbys, e.g.
+///
+/// {"term": 2, "primary": "10.0.0.2:9001", "replicas": ["10.0.0.1:9001"]}
+///
+/// A server outside of an HA pair returns term 0 and its own address, which
+/// may be `0.0.0.0`, so a client only follows the topology of a pair.
+
+use serde_json::{self, Value};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topology {
+    /// incremented by every change of primary, 0 outside of an HA pair
+    pub term: u64,
+    /// host:port of the primary, empty while no server holds the lease
+    pub primary: String,
+    pub replicas: Vec<String>,
+}
+
+impl Topology {
+    pub fn parse(json: &str) -> Option<Topology> {
+        let value : Value = serde_json::from_str(json).ok()?;
+        Some(Topology {
+            term: value.get("term")?.as_u64()?,
+            primary: value.get("primary")?.as_str()?.to_owned(),
+            replicas: value.get("replicas")?.as_array()?.iter()
+                .filter_map(|addr| addr.as_str())
+                .map(|addr| addr.to_owned())
+                .collect(),
+        })
+    }
+
+    /// of an HA pair with a primary
+    pub fn has_primary(&self) -> bool {
+        self.term > 0 && !self.primary.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_topology() {
+        let topology = Topology::parse(r#"{"term": 2, "primary": "b:9001", "replicas": ["a:9001"]}"#).unwrap();
+        assert_eq!(topology, Topology { term: 2, primary: "b:9001".to_owned(), replicas: vec!["a:9001".to_owned()] });
+        assert!(topology.has_primary());
+        assert!(!Topology::parse(r#"{"term": 0, "primary": "0.0.0.0:9001", "replicas": []}"#).unwrap().has_primary());
+        assert!(!Topology::parse(r#"{"term": 3, "primary": "", "replicas": ["a:9001"]}"#).unwrap().has_primary());
+        assert_eq!(Topology::parse("ERR: unknown command"), None);
+    }
+}
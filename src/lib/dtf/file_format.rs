@@ -6,46 +6,66 @@
 /// Offset 00: ([u8; 5]) magic value 0x4454469001
 /// Offset 05: ([u8; 20]) Symbol
 /// Offset 25: (u64) number of records
-/// Offset 33: (u32) max ts
+/// Offset 33: (u64) max ts
+/// Offset 41: (u64) min ts, 0 in files written before it was added
+/// Offset 49: (u32) seq of the newest row, the row with the largest (ts, seq)
+/// Offset 53: (u8) 1 if offset 49 is set, 0 in files written before it was added
 /// Offset 80: -- records - see below --
 /// 
 /// 
 /// Record Spec:
-/// Offset 81: bool for `is_snapshot`
-/// 1. if is true
+/// Offset 80: (u8) 1 for a batch, 2 for a batch whose rows may carry user
+///     defined `flags`, which readers before them stop at
+/// 1. if is a batch
 ///        4 bytes (u32): reference ts
 ///        2 bytes (u32): reference seq
 ///        2 bytes (u16): how many records between this snapshot and the next snapshot
 /// 2. record
 ///        dts (u16): $ts - reference ts$, 2^16 = 65536 - ~65 seconds
 ///        dseq (u8) $seq - reference seq$ , 2^8 = 256
-///        `is_trade & is_bid`: (u8): bitwise and to store two bools in one byte
+///        `is_trade & is_bid`: (u8): bitwise and to store two bools in one byte,
+///            the 6 bits above them hold the user defined `flags` in a batch marked 2
 ///        price: (f32)
 ///        size: (f32)
 
 
 
 use update::*;
+use kernels;
 use std::str;
 use std::fs;
 use std::fs::File;
 use std::fmt;
+use std::cmp;
 use byteorder::{BigEndian, WriteBytesExt, ReadBytesExt};
 use std::io::{
+    self,
     Write,
     Read,
     Seek,
     BufWriter,
     BufReader,
+    Cursor,
     SeekFrom
 };
 
 static MAGIC_VALUE : &[u8] = &[0x44, 0x54, 0x46, 0x90, 0x01]; // DTF9001
-const SYMBOL_LEN : usize = 20;
+/// marker byte of a batch
+static BATCH : u8 = 0x1;
+/// marker byte of a batch whose rows may carry user defined flags
+static BATCH_WITH_FLAGS : u8 = 0x2;
+pub const SYMBOL_LEN : usize = 20;
 static SYMBOL_OFFSET : u64 = 5;
 static LEN_OFFSET : u64 = 25;
 static MAX_TS_OFFSET : u64 = 33;
-static MAIN_OFFSET : u64 = 80; // main section start at 80
+static MIN_TS_OFFSET : u64 = 41;
+static LAST_SEQ_OFFSET : u64 = 49;
+pub const MAIN_OFFSET : u64 = 80; // main section start at 80
+/// indicator byte, ref ts, ref seq and row count of a batch
+static BATCH_HEADER_LEN : u64 = 15;
+/// file length and the offset, min ts, max ts and rows of each batch
+static INDEX_HEADER_LEN : usize = 8;
+static INDEX_ENTRY_LEN : usize = 26;
 // static ITEM_OFFSET : u64 = 13; // each item has 13 bytes
 
 
@@ -60,9 +80,36 @@ pub struct Metadata {
 pub struct BatchMetadata {
     pub ref_ts: u64,
     pub ref_seq: u32,
+    pub count: u16,
+    /// marked BATCH_WITH_FLAGS, other bits of the flags byte than is_bid and is_trade are corrupt otherwise
+    pub user_flags: bool
+}
+
+/// whether `marker` starts a batch
+fn is_batch(marker: u8) -> bool {
+    marker == BATCH || marker == BATCH_WITH_FLAGS
+}
+
+/// time range covered by one batch in a file
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchRange {
+    /// offset of the batch's indicator byte
+    pub offset: u64,
+    pub min_ts: u64,
+    pub max_ts: u64,
     pub count: u16
 }
 
+/// time, price and size ranges of one batch in a file, see `read_batch_zones`
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchZone {
+    pub batch: BatchRange,
+    pub min_price: f32,
+    pub max_price: f32,
+    pub min_size: f32,
+    pub max_size: f32,
+}
+
 impl fmt::Display for Metadata {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, r#"{{
@@ -86,10 +133,25 @@ pub fn update_vec_to_csv(vecs: &[Update]) -> String {
 }
 
 pub fn update_vec_to_json(vecs: &[Update]) -> String {
-    let objects : Vec<String> = vecs.into_iter().map(|up| up.to_json()).collect();
+    update_vec_to_json_with(vecs, None)
+}
+
+/// `update_vec_to_json` with price and size rounded to `decimals` places
+pub fn update_vec_to_json_with(vecs: &[Update], decimals: Option<usize>) -> String {
+    let objects : Vec<String> = vecs.into_iter().map(|up| up.to_json_with(decimals)).collect();
     objects.join(", ")
 }
 
+/// one JSON object per line
+pub fn update_vec_to_jsonl(vecs: &[Update]) -> String {
+    update_vec_to_jsonl_with(vecs, None)
+}
+
+pub fn update_vec_to_jsonl_with(vecs: &[Update], decimals: Option<usize>) -> String {
+    let objects : Vec<String> = vecs.into_iter().map(|up| up.to_json_with(decimals)).collect();
+    objects.join("\n")
+}
+
 pub fn get_max_ts(updates : &[Update]) -> u64 {
     let mut max = 0;
     for update in updates.iter() {
@@ -122,69 +184,123 @@ fn write_symbol(wtr: &mut Write, symbol : &str) {
     let _ = wtr.write(padded_symbol.as_bytes());
 }
 
-fn write_len(wtr: &mut BufWriter<File>, len : u64) {
+fn write_len<W: Write + Seek>(wtr: &mut W, len : u64) {
     let _ = wtr.seek(SeekFrom::Start(LEN_OFFSET));
     wtr.write_u64::<BigEndian>(len).expect("length of records");
 }
 
-fn write_max_ts(wtr: &mut BufWriter<File>, max_ts : u64) {
+fn write_max_ts<W: Write + Seek>(wtr: &mut W, max_ts : u64) {
     let _ = wtr.seek(SeekFrom::Start(MAX_TS_OFFSET));
     wtr.write_u64::<BigEndian>(max_ts).expect("maximum timestamp");
 }
 
-fn write_metadata(wtr: &mut BufWriter<File>, ups : &[Update]) {
+pub fn get_min_ts(updates : &[Update]) -> u64 {
+    updates.iter().map(|up| up.ts).min().unwrap_or(0)
+}
+
+fn write_min_ts<W: Write + Seek>(wtr: &mut W, min_ts : u64) {
+    let _ = wtr.seek(SeekFrom::Start(MIN_TS_OFFSET));
+    wtr.write_u64::<BigEndian>(min_ts).expect("minimum timestamp");
+}
+
+/// (ts, seq) of the newest row
+fn get_newest(updates : &[Update]) -> Option<(u64, u32)> {
+    updates.iter().map(|up| (up.ts, up.seq)).max()
+}
+
+fn write_last_seq<W: Write + Seek>(wtr: &mut W, last_seq : u32) {
+    let _ = wtr.seek(SeekFrom::Start(LAST_SEQ_OFFSET));
+    wtr.write_u32::<BigEndian>(last_seq).expect("last seq");
+    wtr.write_u8(1).expect("last seq");
+}
+
+fn write_metadata<W: Write + Seek>(wtr: &mut W, ups : &[Update]) {
     write_len(wtr, ups.len() as u64);
     write_max_ts(wtr, get_max_ts(ups));
+    write_min_ts(wtr, get_min_ts(ups));
+    if let Some((_, last_seq)) = get_newest(ups) {
+        write_last_seq(wtr, last_seq);
+    }
 }
 
-fn write_reference(wtr: &mut Write, ref_ts: u64, ref_seq: u32, len: u16) {
-    let _ = wtr.write_u8(true as u8);
+fn write_reference(wtr: &mut Write, ref_ts: u64, ref_seq: u32, len: u16, user_flags: bool) {
+    // batches without flags stay readable by readers before them
+    let _ = wtr.write_u8(if user_flags { BATCH_WITH_FLAGS } else { BATCH });
     let _ = wtr.write_u64::<BigEndian>(ref_ts);
     let _ = wtr.write_u32::<BigEndian>(ref_seq);
     let _ = wtr.write_u16::<BigEndian>(len);
 }
 
+/// whether `elem` can't be written relative to the first row of a batch
+fn starts_batch(elem: &Update, ref_ts: u64, ref_seq: u32) -> bool {
+    elem.ts >= ref_ts + 0xFFFF // if still addressable (ref_ts is 4 bytes)
+    || elem.seq >= ref_seq + 0xF // ref_seq is 1 byte
+    || elem.seq < ref_seq // sometimes the data is scrambled, just write that line down
+    || elem.ts < ref_ts // ^
+}
+
 pub fn write_batches(mut wtr: &mut Write, ups : &[Update]) {
     let mut buf : Vec<u8> = Vec::new();
     let mut ref_ts = ups[0].ts;
     let mut ref_seq = ups[0].seq;
     let mut count = 0;
+    let mut user_flags = false;
 
     for elem in ups.iter() {
         if count != 0 // if we got things to write
-        && (
-             elem.ts >= ref_ts + 0xFFFF // if still addressable (ref_ts is 4 bytes)
-          || elem.seq >= ref_seq + 0xF // ref_seq is 1 byte
-          || elem.seq < ref_seq // sometimes the data is scrambled, just write that line down
-          || elem.ts < ref_ts // ^
-         ) {
-            write_reference(&mut wtr, ref_ts, ref_seq, count);
+        && starts_batch(elem, ref_ts, ref_seq) {
+            write_reference(&mut wtr, ref_ts, ref_seq, count, user_flags);
             let _ = wtr.write(buf.as_slice());
             buf.clear();
 
             ref_ts = elem.ts;
             ref_seq = elem.seq;
             count = 0;
+            user_flags = false;
         }
 
-        let serialized = elem.serialize(ref_ts, ref_seq);
-        let _ = buf.write(serialized.as_slice());
+        elem.serialize_into(&mut buf, ref_ts, ref_seq);
 
         count += 1;
+        user_flags |= elem.flags != 0;
     }
 
-    write_reference(&mut wtr, ref_ts, ref_seq, count);
+    write_reference(&mut wtr, ref_ts, ref_seq, count, user_flags);
     wtr.write_all(buf.as_slice()).unwrap();
 }
 
-fn write_main(wtr: &mut BufWriter<File>, ups : &[Update]) {
+fn write_main<W: Write + Seek>(wtr: &mut W, ups : &[Update]) {
     let _ = wtr.seek(SeekFrom::Start(MAIN_OFFSET));
     if !ups.is_empty() {
         write_batches(wtr, ups);
     }
 }
 
+/// The batches `write_batches` writes for `ups` from `offset`
+pub fn batch_ranges_of(ups: &[Update], mut offset: u64) -> Vec<BatchRange> {
+    let mut v : Vec<BatchRange> = Vec::new();
+    let mut ref_seq = 0;
+    for up in ups {
+        let is_new = match v.last() {
+            Some(batch) => starts_batch(up, batch.min_ts, ref_seq),
+            None => true
+        };
+        if is_new {
+            if let Some(batch) = v.last() {
+                offset += BATCH_HEADER_LEN + u64::from(batch.count) * kernels::ROW_LEN as u64;
+            }
+            v.push(BatchRange { offset, min_ts: up.ts, max_ts: up.ts, count: 0 });
+            ref_seq = up.seq;
+        }
+        let batch = v.last_mut().unwrap();
+        batch.max_ts = cmp::max(batch.max_ts, up.ts);
+        batch.count += 1;
+    }
+    v
+}
+
 pub fn encode(fname : &str, symbol : &str, ups : &[Update]) {
+    remove_batch_index(fname);
     let mut wtr = file_writer(fname, true);
 
     write_magic_value(&mut wtr);
@@ -193,6 +309,24 @@ pub fn encode(fname : &str, symbol : &str, ups : &[Update]) {
     write_main(&mut wtr, ups);
 
     wtr.flush().expect("FAILURE TO FLUSH");
+    batch_index_written(fname, ups);
+}
+
+/// a whole dtf file held in memory, e.g. to send it over the network
+pub fn encode_buffer(symbol : &str, ups : &[Update]) -> Vec<u8> {
+    let mut wtr = Cursor::new(Vec::new());
+
+    write_magic_value(&mut wtr);
+    write_symbol(&mut wtr, symbol);
+    write_metadata(&mut wtr, ups);
+    write_main(&mut wtr, ups);
+
+    let mut buf = wtr.into_inner();
+    // the file of an empty store ends with its header
+    if buf.len() < MAIN_OFFSET as usize {
+        buf.resize(MAIN_OFFSET as usize, 0);
+    }
+    buf
 }
 
 pub fn is_dtf(fname: &str) -> bool {
@@ -237,7 +371,12 @@ fn read_len(rdr : &mut BufReader<File>) -> u64 {
 }
 
 fn read_min_ts(mut rdr: &mut BufReader<File>) -> u64 {
-    read_first(&mut rdr).ts
+    let _ = rdr.seek(SeekFrom::Start(MIN_TS_OFFSET));
+    match rdr.read_u64::<BigEndian>() {
+        Ok(min_ts) if min_ts != 0 => min_ts,
+        // written before min ts was in the header
+        _ => read_first(&mut rdr).ts
+    }
 }
 
 fn read_max_ts(rdr : &mut BufReader<File>) -> u64 {
@@ -245,19 +384,38 @@ fn read_max_ts(rdr : &mut BufReader<File>) -> u64 {
     rdr.read_u64::<BigEndian>().expect("maximum timestamp")
 }
 
-pub fn read_one_batch_meta(rdr: &mut Read) -> BatchMetadata {
-    let ref_ts = rdr.read_u64::<BigEndian>().unwrap();
-    let ref_seq = rdr.read_u32::<BigEndian>().unwrap();
-    let count = rdr.read_u16::<BigEndian>().unwrap();
+/// None in files written before the last seq was in the header
+fn read_last_seq(rdr : &mut BufReader<File>) -> Option<u32> {
+    let _ = rdr.seek(SeekFrom::Start(LAST_SEQ_OFFSET));
+    let last_seq = rdr.read_u32::<BigEndian>().ok()?;
+    match rdr.read_u8() {
+        Ok(1) => Some(last_seq),
+        _ => None
+    }
+}
+
+/// the header of a batch after its `marker` byte
+pub fn read_one_batch_meta(rdr: &mut Read, marker: u8) -> BatchMetadata {
+    try_read_one_batch_meta(rdr, marker).unwrap()
+}
+
+fn try_read_one_batch_meta(rdr: &mut Read, marker: u8) -> Result<BatchMetadata, io::Error> {
+    let ref_ts = rdr.read_u64::<BigEndian>()?;
+    let ref_seq = rdr.read_u32::<BigEndian>()?;
+    let count = rdr.read_u16::<BigEndian>()?;
 
-    BatchMetadata {
+    Ok(BatchMetadata {
         ref_ts,
         ref_seq,
-        count
-    }
+        count,
+        user_flags: marker == BATCH_WITH_FLAGS
+    })
 }
 
-/// reads a vector of Update over some time interval (min_ts, max_ts) from file.
+/// reads a vector of Update over some time interval [min_ts, max_ts] (in seconds) from file.
+///
+/// Every row of a batch is within [ref_ts, ref_ts + 0xFFFF), so batches
+/// outside of the interval are skipped without decoding them.
 pub fn range(rdr: &mut BufReader<File>, min_ts: f64, max_ts: f64) -> Vec<Update> {
     // convert ts to match the dtf file format (in ms)
     let min_ts = (min_ts * 1000.) as u64;
@@ -271,114 +429,94 @@ pub fn range(rdr: &mut BufReader<File>, min_ts: f64, max_ts: f64) -> Vec<Update>
 
     loop {
         // read marker byte
-        match rdr.read_u8() {
-            Ok(byte) => { if byte != 0x1 { return v; } },   // 0x1 indicates a batch
-            Err(_e) => { return v; }                        // EOF
+        let marker = match rdr.read_u8() {
+            Ok(marker) if is_batch(marker) => marker,
+            _ => return v           // EOF
         };
 
-        // read the metadata of the current batch
-        let current_meta = read_one_batch_meta(rdr);
-        let current_ref_ts = current_meta.ref_ts;
-        let current_count = current_meta.count;
-
-        // skip a few bytes and read the next metadata
-        let bytes_to_skip = current_count * 12 /* 12 bytes per row */;
-        rdr.seek(SeekFrom::Current(bytes_to_skip as i64)).expect(&format!("Skipping {} rows", current_count));
-
-        // must be a batch
-        match rdr.read_u8() {
-            Ok(byte) => { if byte != 0x1 { return v; } },   // is a batch
-            Err(_e) => { return v; }                        // EOF
+        let meta = match try_read_one_batch_meta(rdr, marker) {
+            Ok(meta) => meta,
+            Err(_) => return v
         };
-        let next_meta = read_one_batch_meta(rdr);
-        let next_ref_ts = next_meta.ref_ts;
 
-        // legend:
-        // `|`: meta data
-        // `1`: indicator byte
-        // `-`: updates
-
-        //     |1-----|1*---      <- we are here
+        if meta.ref_ts > max_ts || meta.ref_ts.saturating_add(0xFFFF) <= min_ts {
+            let bytes_to_skip = i64::from(meta.count) * 12 /* 12 bytes per row */;
+            if rdr.seek(SeekFrom::Current(bytes_to_skip)).is_err() {
+                return v;
+            }
+            continue;
+        }
 
-        //  [ ]                   <- requested
-        //     |1-----|1---
-        // 
-        if min_ts <= current_ref_ts && max_ts <= current_ref_ts {
+        if try_read_batch_rows(rdr, &meta, min_ts, max_ts, &mut v).is_err() {
             return v;
-        } else
-
-        // [    ]
-        //   |1*-----|1---
-        //
-        // or 
-        //
-        //         [     ]
-        //         [          ]
-        //   |1*-----|1----|1---
-        //
-        if (min_ts <= current_ref_ts && max_ts <= next_ref_ts )
-            || (min_ts < next_ref_ts && max_ts >= next_ref_ts) {
-            // seek back
-            let bytes_to_scrollback = - (bytes_to_skip as i64) - 14 /* metadata */ - 1 /* indicator byte */ ;
-            rdr.seek(SeekFrom::Current( bytes_to_scrollback )).expect("scrolling back");
-            //   |1*------|1--          <- we are here
-            // read and filter current batch
-            let filtered = {
-                let batch = read_one_batch_main(rdr, current_meta);
-                if min_ts <= current_ref_ts && max_ts >= next_ref_ts {
-                    batch
-                } else {
-                    batch.into_iter()
-                        .filter(|up| up.ts <= max_ts && up.ts >= min_ts)
-                        .collect::<Vec<Update>>()
-                }
-            };
-            v.extend(filtered);
-        
-        //               [      ]
-        // |1----|1---|1----
-        //
-        } else if min_ts >= next_ref_ts {
-            // simply skip back to the beginning of the second batch
-            // |1----*|1---|1---
-            let bytes_to_scrollback = - 14 /* metadata */ - 1 /* indicator byte */ ;
-            rdr.seek(SeekFrom::Current( bytes_to_scrollback )).expect("SKIPPING n ROWS");
-        } else {
-            panic!("Should have cover all the cases.");
         }
     }
 }
 
 pub fn read_one_batch(rdr: &mut Read) -> Vec<Update> {
-    let is_ref = rdr.read_u8().expect("is_ref") == 0x1;
-    if !is_ref {
+    let marker = rdr.read_u8().expect("is_ref");
+    if !is_batch(marker) {
         Vec::new()
     } else {
-        let meta = read_one_batch_meta(rdr);
-        read_one_batch_main(rdr, meta)
+        let meta = read_one_batch_meta(rdr, marker);
+        try_read_one_batch_main(rdr, &meta).expect("reading batch")
+    }
+}
+
+/// like `read_one_batch` but a truncated or corrupt batch is an error
+pub fn try_read_one_batch(rdr: &mut Read) -> Result<Vec<Update>, io::Error> {
+    let marker = rdr.read_u8()?;
+    if !is_batch(marker) {
+        return Ok(Vec::new());
     }
+    let meta = try_read_one_batch_meta(rdr, marker)?;
+    try_read_one_batch_main(rdr, &meta)
 }
 
-fn read_one_batch_main(rdr: &mut Read, meta: BatchMetadata) -> Vec<Update> {
+/// like `try_read_one_batch` from a file position, only the rows within [min_ts, max_ts] (in ms)
+pub fn try_read_one_batch_range(rdr: &mut Read, min_ts: u64, max_ts: u64) -> Result<Vec<Update>, io::Error> {
     let mut v : Vec<Update> = Vec::new();
-    for _i in 0..meta.count {
-        let up = read_one_update(rdr, &meta);
-        v.push(up);
+    let marker = rdr.read_u8()?;
+    if is_batch(marker) {
+        let meta = try_read_one_batch_meta(rdr, marker)?;
+        try_read_batch_rows(rdr, &meta, min_ts, max_ts, &mut v)?;
     }
-    v
+    Ok(v)
+}
+
+fn try_read_one_batch_main(rdr: &mut Read, meta: &BatchMetadata) -> Result<Vec<Update>, io::Error> {
+    let mut v : Vec<Update> = Vec::new();
+    try_read_batch_rows(rdr, meta, 0, u64::max_value(), &mut v)?;
+    Ok(v)
+}
+
+/// append the rows of the batch `meta` within [min_ts, max_ts] to `v`, see `kernels`
+fn try_read_batch_rows(rdr: &mut Read, meta: &BatchMetadata, min_ts: u64, max_ts: u64, v: &mut Vec<Update>) -> Result<(), io::Error> {
+    // at most 65535 rows, whatever `count` says
+    let mut buf = vec![0; usize::from(meta.count) * kernels::ROW_LEN];
+    rdr.read_exact(&mut buf)?;
+    kernels::decode_rows(&buf, meta, min_ts, max_ts, v)
 }
 
 fn read_one_update(rdr: &mut Read, meta: &BatchMetadata) -> Update {
-    let ts = u64::from(rdr.read_u16::<BigEndian>().expect("ts")) + meta.ref_ts;
-    let seq = u32::from(rdr.read_u8().expect("seq")) + meta.ref_seq;
-    let flags = rdr.read_u8().expect("is_trade and is_bid");
-    let is_trade = (Flags::from_bits(flags).unwrap() & Flags::FLAG_IS_TRADE).to_bool();
-    let is_bid = (Flags::from_bits(flags).unwrap() & Flags::FLAG_IS_BID).to_bool();
-    let price = rdr.read_f32::<BigEndian>().expect("price");
-    let size = rdr.read_f32::<BigEndian>().expect("size");
-    Update {
-        ts, seq, is_trade, is_bid, price, size
+    try_read_one_update(rdr, meta).expect("reading update")
+}
+
+fn try_read_one_update(rdr: &mut Read, meta: &BatchMetadata) -> Result<Update, io::Error> {
+    let corrupt = |what| io::Error::new(io::ErrorKind::InvalidData, what);
+    let ts = meta.ref_ts.checked_add(u64::from(rdr.read_u16::<BigEndian>()?))
+        .ok_or_else(|| corrupt("ts overflows"))?;
+    let seq = meta.ref_seq.checked_add(u32::from(rdr.read_u8()?))
+        .ok_or_else(|| corrupt("seq overflows"))?;
+    let flags = rdr.read_u8()?;
+    if !meta.user_flags && flags & Flags::FLAG_USER.bits() != 0 {
+        return Err(corrupt("unknown flags"));
     }
+    let price = rdr.read_f32::<BigEndian>()?;
+    let size = rdr.read_f32::<BigEndian>()?;
+    Ok(Update {
+        ts, seq, is_trade: false, is_bid: false, price, size, flags: 0
+    }.with_flags_byte(flags))
 }
 
 fn read_first_batch(mut rdr: &mut BufReader<File>) -> Vec<Update> {
@@ -391,6 +529,275 @@ fn read_first(mut rdr: &mut BufReader<File>) -> Update {
     batch[0].clone()
 }
 
+/// reads the last update of the file by hopping from batch header to
+/// batch header, without decoding the rows in between
+pub fn read_last(fname: &str) -> Option<Update> {
+    let mut rdr = file_reader(fname);
+    if read_len(&mut rdr) == 0 {
+        return None;
+    }
+    rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
+
+    // (metadata, offset of its first row)
+    let mut last : Option<(BatchMetadata, u64)> = None;
+    loop {
+        let marker = match rdr.read_u8() {
+            Ok(marker) if is_batch(marker) => marker,
+            _ => break
+        };
+        let meta = read_one_batch_meta(&mut rdr, marker);
+        let pos = rdr.seek(SeekFrom::Current(0)).expect("SEEKING");
+        rdr.seek(SeekFrom::Current(i64::from(meta.count) * 12)).expect("SKIPPING BATCH");
+        last = Some((meta, pos));
+    }
+
+    let (meta, pos) = last?;
+    if meta.count == 0 {
+        return None;
+    }
+    rdr.seek(SeekFrom::Start(pos + (u64::from(meta.count) - 1) * 12)).expect("SEEKING");
+    Some(read_one_update(&mut rdr, &meta))
+}
+
+/// (ts, seq) of the newest row, from the header. Files written before the
+/// last seq was in the header fall back to `read_last`.
+pub fn read_newest(fname: &str) -> Option<(u64, u32)> {
+    let mut rdr = file_reader(fname);
+    if read_len(&mut rdr) == 0 {
+        return None;
+    }
+    let max_ts = read_max_ts(&mut rdr);
+    match read_last_seq(&mut rdr) {
+        Some(last_seq) => Some((max_ts, last_seq)),
+        None => read_last(fname).map(|up| (up.ts, up.seq))
+    }
+}
+
+/// Batch index
+///
+/// `encode` and `append` leave `[fname].batches` next to the file, with the
+/// range of each batch, so `read_batch_ranges` reads O(batches) bytes:
+///
+/// Offset 00: (u64) length of the file it describes
+/// Offset 08: -- one entry per batch --
+///     offset (u64), min ts (u64), max ts (u64), rows (u16)
+///
+/// An index whose length is not the length of the file is ignored.
+pub fn batch_index_fname(fname: &str) -> String {
+    format!("{}.batches", fname)
+}
+
+/// forget the batch index, before the file is written over
+pub fn remove_batch_index(fname: &str) {
+    let _ = fs::remove_file(batch_index_fname(fname));
+}
+
+fn write_batch_index(fname: &str, ranges: &[BatchRange]) -> Result<(), io::Error> {
+    let len = fs::metadata(fname)?.len();
+    let index = batch_index_fname(fname);
+    let tmp = format!("{}.tmp", index);
+    {
+        let mut wtr = BufWriter::new(File::create(&tmp)?);
+        wtr.write_u64::<BigEndian>(len)?;
+        for batch in ranges {
+            wtr.write_u64::<BigEndian>(batch.offset)?;
+            wtr.write_u64::<BigEndian>(batch.min_ts)?;
+            wtr.write_u64::<BigEndian>(batch.max_ts)?;
+            wtr.write_u16::<BigEndian>(batch.count)?;
+        }
+        wtr.flush()?;
+    }
+    fs::rename(&tmp, &index)
+}
+
+fn save_batch_index(fname: &str, ranges: &[BatchRange]) {
+    if write_batch_index(fname, ranges).is_err() {
+        remove_batch_index(fname);
+    }
+}
+
+/// the batch index, if it describes the file when it was `len` bytes long
+fn read_batch_index(fname: &str, len: u64) -> Option<Vec<BatchRange>> {
+    let mut buf = Vec::new();
+    File::open(batch_index_fname(fname)).ok()?.read_to_end(&mut buf).ok()?;
+    if buf.len() < INDEX_HEADER_LEN || (buf.len() - INDEX_HEADER_LEN) % INDEX_ENTRY_LEN != 0 {
+        return None;
+    }
+    let mut rdr = Cursor::new(&buf[..]);
+    if rdr.read_u64::<BigEndian>().ok()? != len {
+        return None;
+    }
+    (0..((buf.len() - INDEX_HEADER_LEN) / INDEX_ENTRY_LEN))
+        .map(|_| Some(BatchRange {
+            offset: rdr.read_u64::<BigEndian>().ok()?,
+            min_ts: rdr.read_u64::<BigEndian>().ok()?,
+            max_ts: rdr.read_u64::<BigEndian>().ok()?,
+            count: rdr.read_u16::<BigEndian>().ok()?,
+        }))
+        .collect()
+}
+
+/// index a file `encode_buffer(.., ups)` was just written to
+pub fn batch_index_written(fname: &str, ups: &[Update]) {
+    save_batch_index(fname, &batch_ranges_of(ups, MAIN_OFFSET));
+}
+
+/// index the batches of `plan` appended to a file that was `old_len` bytes long
+pub fn batch_index_appended(fname: &str, old_len: u64, plan: &AppendPlan) {
+    let (offset, old) = if plan.at_main_offset {
+        (MAIN_OFFSET, Some(Vec::new()))
+    } else {
+        (old_len, read_batch_index(fname, old_len))
+    };
+    let ranges = match old {
+        Some(mut ranges) => {
+            ranges.extend(batch_ranges_of(&plan.ups, offset));
+            ranges
+        },
+        // no index of the file before the append
+        None => scan_batch_ranges(fname)
+    };
+    save_batch_index(fname, &ranges);
+}
+
+/// Returns the time range of every batch in the file, from its batch index
+/// unless that is missing or stale.
+pub fn read_batch_ranges(fname: &str) -> Vec<BatchRange> {
+    let len = fs::metadata(fname).map(|m| m.len()).unwrap_or(0);
+    match read_batch_index(fname, len) {
+        Some(ranges) => ranges,
+        None => scan_batch_ranges(fname)
+    }
+}
+
+/// Only the batch headers and the 2-byte ts delta of each row are read.
+fn scan_batch_ranges(fname: &str) -> Vec<BatchRange> {
+    let mut rdr = file_reader(fname);
+    rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
+
+    let mut v = Vec::new();
+    loop {
+        let offset = rdr.seek(SeekFrom::Current(0)).expect("SEEKING");
+        let marker = match rdr.read_u8() {
+            Ok(marker) if is_batch(marker) => marker,
+            _ => break
+        };
+        let meta = read_one_batch_meta(&mut rdr, marker);
+        let mut max_dts = 0;
+        for _i in 0..meta.count {
+            max_dts = cmp::max(max_dts, rdr.read_u16::<BigEndian>().expect("ts"));
+            rdr.seek(SeekFrom::Current(10)).expect("SKIPPING ROW");
+        }
+        v.push(BatchRange {
+            offset,
+            min_ts: meta.ref_ts,
+            max_ts: meta.ref_ts + u64::from(max_dts),
+            count: meta.count
+        });
+    }
+    v
+}
+
+/// Zone maps of the batches of a file starting at `offset`, e.g. the length
+/// of the file before an append, or the start of the main section if it is
+/// before. Every row is decoded.
+pub fn read_batch_zones(fname: &str, offset: u64) -> Vec<BatchZone> {
+    let mut rdr = file_reader(fname);
+    rdr.seek(SeekFrom::Start(cmp::max(offset, MAIN_OFFSET))).expect("SEEKING");
+
+    let mut v = Vec::new();
+    loop {
+        let offset = rdr.seek(SeekFrom::Current(0)).expect("SEEKING");
+        let ups = match try_read_one_batch(&mut rdr) {
+            Ok(ref ups) if ups.is_empty() => break,
+            Ok(ups) => ups,
+            Err(_) => break
+        };
+        let mut zone = BatchZone {
+            batch: BatchRange { offset, min_ts: ups[0].ts, max_ts: ups[0].ts, count: ups.len() as u16 },
+            min_price: ups[0].price,
+            max_price: ups[0].price,
+            min_size: ups[0].size,
+            max_size: ups[0].size,
+        };
+        for up in &ups[1..] {
+            zone.batch.max_ts = cmp::max(zone.batch.max_ts, up.ts);
+            zone.min_price = zone.min_price.min(up.price);
+            zone.max_price = zone.max_price.max(up.price);
+            zone.min_size = zone.min_size.min(up.size);
+            zone.max_size = zone.max_size.max(up.size);
+        }
+        v.push(zone);
+    }
+    v
+}
+
+/// Is there any update within [min_ts, max_ts] (in ms)?
+/// Answered from the header and batch ranges without decoding.
+pub fn has_range(fname: &str, min_ts: u64, max_ts: u64) -> bool {
+    let meta = read_meta(fname);
+    if meta.nums == 0 || max_ts < meta.min_ts || min_ts > meta.max_ts {
+        return false;
+    }
+    read_batch_ranges(fname).iter()
+        .any(|b| b.min_ts <= max_ts && b.max_ts >= min_ts)
+}
+
+/// Checks the structure of a dtf file without decoding its rows, and without
+/// panicking on a corrupt one: the header, the batch headers, which must add
+/// up to the row count of the header and end at the end of the file, and the
+/// last row, which must not be newer than the max ts of the header.
+pub fn verify(fname: &str) -> Result<(), String> {
+    let file = File::open(fname).map_err(|e| e.to_string())?;
+    let file_len = file.metadata().map_err(|e| e.to_string())?.len();
+    let mut rdr = BufReader::new(file);
+    if !read_magic_value(&mut rdr) {
+        return Err("magic value incorrect".to_owned());
+    }
+    if file_len < MIN_TS_OFFSET {
+        return Err(format!("header truncated at {} bytes", file_len));
+    }
+    let _ = rdr.seek(SeekFrom::Start(LEN_OFFSET));
+    let nums = rdr.read_u64::<BigEndian>().map_err(|e| e.to_string())?;
+    let max_ts = rdr.read_u64::<BigEndian>().map_err(|e| e.to_string())?;
+
+    // (metadata, offset of its first row)
+    let mut last : Option<(BatchMetadata, u64)> = None;
+    let mut rows = 0;
+    let mut pos = MAIN_OFFSET;
+    let _ = rdr.seek(SeekFrom::Start(pos));
+    while pos < file_len {
+        let marker = match rdr.read_u8() {
+            Ok(marker) if is_batch(marker) => marker,
+            _ => return Err(format!("no batch at byte {}", pos))
+        };
+        let meta = try_read_one_batch_meta(&mut rdr, marker)
+            .map_err(|_| format!("batch header truncated at byte {}", pos))?;
+        let start = pos + 15;
+        pos = start + u64::from(meta.count) * 12;
+        if pos > file_len {
+            return Err(format!("batch at byte {} truncated, {} bytes missing", start - 15, pos - file_len));
+        }
+        rdr.seek(SeekFrom::Start(pos)).map_err(|e| e.to_string())?;
+        rows += u64::from(meta.count);
+        last = Some((meta, start));
+    }
+    if rows != nums {
+        return Err(format!("header counts {} rows, the batches hold {}", nums, rows));
+    }
+
+    if let Some((meta, start)) = last {
+        if meta.count > 0 {
+            rdr.seek(SeekFrom::Start(start + (u64::from(meta.count) - 1) * 12)).map_err(|e| e.to_string())?;
+            let up = try_read_one_update(&mut rdr, &meta).map_err(|e| format!("last row: {}", e))?;
+            if up.ts > max_ts {
+                return Err(format!("last row at {} is newer than the max ts {} of the header", up.ts, max_ts));
+            }
+        }
+    }
+    Ok(())
+}
+
 pub fn get_size(fname: &str) -> u64 {
     let mut rdr = file_reader(fname);
     read_len(&mut rdr)
@@ -429,9 +836,12 @@ pub fn decode(fname: &str, num_rows : Option<u32>) -> Vec<Update> {
             while let Ok(is_ref) = rdr.read_u8() {
                 if count > num_rows { break; }
 
-                if is_ref == 0x1 {
+                if is_batch(is_ref) {
                     rdr.seek(SeekFrom::Current(-1)).expect("ROLLBACK ONE BYTE");
-                    v.extend(read_one_batch(&mut rdr));
+                    match try_read_one_batch(&mut rdr) {
+                        Ok(batch) => v.extend(batch),
+                        Err(_) => break // truncated
+                    }
                 }
 
                 count += 1;
@@ -439,9 +849,12 @@ pub fn decode(fname: &str, num_rows : Option<u32>) -> Vec<Update> {
         },
         None => {
             while let Ok(is_ref) = rdr.read_u8() {
-                if is_ref == 0x1 {
+                if is_batch(is_ref) {
                     rdr.seek(SeekFrom::Current(-1)).expect("ROLLBACK ONE BYTE");
-                    v.extend(read_one_batch(&mut rdr));
+                    match try_read_one_batch(&mut rdr) {
+                        Ok(batch) => v.extend(batch),
+                        Err(_) => break // truncated
+                    }
                 }
             }
         }
@@ -451,51 +864,230 @@ pub fn decode(fname: &str, num_rows : Option<u32>) -> Vec<Update> {
     v
 }
 
-pub fn append(fname: &str, ups : &[Update]) {
+/// Iterator over the batches of a dtf file, oldest first.
+/// A truncated or corrupt batch ends it, like it ends `decode`.
+pub struct Batches {
+    rdr: BufReader<File>,
+    failed: bool,
+}
 
-    let (ups, new_max_ts, cur_len) = {
-        let mut rdr = file_reader(fname);
-        let _symbol = read_symbol(&mut rdr);
+impl Iterator for Batches {
+    type Item = Result<Vec<Update>, io::Error>;
 
-        let old_max_ts = read_max_ts(&mut rdr);
+    fn next(&mut self) -> Option<Result<Vec<Update>, io::Error>> {
+        if self.failed {
+            return None;
+        }
+        loop {
+            let marker = match self.rdr.read_u8() {
+                Ok(marker) if is_batch(marker) => marker,
+                Ok(_) => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            };
+            let batch = try_read_one_batch_meta(&mut self.rdr, marker)
+                .and_then(|meta| try_read_one_batch_main(&mut self.rdr, &meta));
+            match batch {
+                Ok(ref batch) if batch.is_empty() => continue,
+                Ok(batch) => return Some(Ok(batch)),
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// the batches of a dtf file, without panicking on a file that is not one.
+/// A batch cut short or corrupt is an error, which ends the iteration.
+pub fn batches(fname: &str) -> Result<Batches, io::Error> {
+    let mut rdr = BufReader::new(File::open(fname)?);
+    if !read_magic_value(&mut rdr) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a dtf file"));
+    }
+    rdr.seek(SeekFrom::Start(MAIN_OFFSET))?;
+    Ok(Batches { rdr, failed: false })
+}
+
+/// decode a whole dtf file held in memory
+///
+/// Unlike the file based readers this never panics: corrupt or truncated
+/// input is an `InvalidData` or `UnexpectedEof` error.
+pub fn decode_buffer(buf: &[u8]) -> Result<Vec<Update>, io::Error> {
+    if !has_magic_value(buf) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a dtf file"));
+    }
+    let mut rdr = Cursor::new(buf);
+    rdr.set_position(LEN_OFFSET);
+    let len = rdr.read_u64::<BigEndian>()?;
+    // the file of an empty store ends with its header
+    if buf.len() <= MAIN_OFFSET as usize {
+        return if len == 0 {
+            Ok(Vec::new())
+        } else {
+            Err(io::Error::new(io::ErrorKind::UnexpectedEof, "no records after the header"))
+        };
+    }
 
-        let ups : Vec<Update> = ups.into_iter()
-                                    .filter(|up| up.ts > old_max_ts)
-                                    .cloned()
-                                    .collect();
-        if ups.is_empty() {
-            return;
+    let v = decode_batches_buffer(&buf[(MAIN_OFFSET as usize)..])?;
+    if v.len() as u64 != len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "number of records does not match the header"));
+    }
+    Ok(v)
+}
+
+/// number of rows in the header of a dtf file held in memory
+pub fn header_len(header: &[u8]) -> Result<u64, io::Error> {
+    if !has_magic_value(header) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a dtf file"));
+    }
+    let mut rdr = Cursor::new(header);
+    rdr.set_position(LEN_OFFSET);
+    rdr.read_u64::<BigEndian>()
+}
+
+/// whether `buf` starts like a dtf file, rather than with its batches
+pub fn has_magic_value(buf: &[u8]) -> bool {
+    buf.len() >= MAGIC_VALUE.len() && &buf[..MAGIC_VALUE.len()] == MAGIC_VALUE
+}
+
+/// decode batches held in memory as written by `write_batches`, e.g. a
+/// GET response of the server. Never panics, like `decode_buffer`.
+pub fn decode_batches_buffer(buf: &[u8]) -> Result<Vec<Update>, io::Error> {
+    let mut v : Vec<Update> = Vec::new();
+    let mut rdr = Cursor::new(buf);
+    while let Ok(is_ref) = rdr.read_u8() {
+        if is_batch(is_ref) {
+            let meta = try_read_one_batch_meta(&mut rdr, is_ref)?;
+            v.extend(try_read_one_batch_main(&mut rdr, &meta)?);
         }
+    }
+    Ok(v)
+}
 
-        let new_min_ts = ups[0].ts;
-        let new_max_ts = ups[ups.len()-1].ts;
+/// decode the batches that start before byte offset `end`,
+/// i.e. the file as it was when it was `end` bytes long
+pub fn decode_prefix(fname: &str, end: u64) -> Vec<Update> {
+    let mut v : Vec<Update> = Vec::new();
+
+    let mut rdr = file_reader(fname);
+    rdr.seek(SeekFrom::Start(MAIN_OFFSET)).expect("SEEKING");
 
-        if new_min_ts <= old_max_ts {
-            panic!("Cannot append data!(not implemented)");
+    while rdr.seek(SeekFrom::Current(0)).expect("SEEKING") < end {
+        match rdr.read_u8() {
+            Ok(marker) if is_batch(marker) => {
+                rdr.seek(SeekFrom::Current(-1)).expect("ROLLBACK ONE BYTE");
+                v.extend(read_one_batch(&mut rdr));
+            },
+            _ => break
         }
+    }
+
+    v
+}
+
+/// What `append` writes to a file, see `plan_append`
+pub struct AppendPlan {
+    /// the new header, written over the first MAIN_OFFSET bytes of the file
+    pub header: Vec<u8>,
+    /// the batches of the new rows
+    pub batches: Vec<u8>,
+    /// whether the batches go at MAIN_OFFSET, the file had no rows, or at its end
+    pub at_main_offset: bool,
+    /// the rows newer than the newest row of the file
+    pub ups: Vec<Update>,
+}
 
-        let cur_len = read_len(&mut rdr);
-        (ups, new_max_ts, cur_len)
+/// The writes appending `ups` to a file whose first MAIN_OFFSET bytes are
+/// `header`, without doing them. None if no row is newer than the file.
+pub fn plan_append(header: &[u8], ups: &[Update]) -> Result<Option<AppendPlan>, io::Error> {
+    if !has_magic_value(header) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a dtf file"));
+    }
+    // files without rows end before MAIN_OFFSET
+    let mut header = header[..header.len().min(MAIN_OFFSET as usize)].to_vec();
+    header.resize(MAIN_OFFSET as usize, 0);
+    let cur_len = header_len(&header)?;
+    let mut rdr = Cursor::new(&header[..]);
+    rdr.set_position(MAX_TS_OFFSET);
+    let old_max_ts = rdr.read_u64::<BigEndian>()?;
+    rdr.set_position(LAST_SEQ_OFFSET);
+    // None in files written before the last seq was in the header
+    let last_seq = match (rdr.read_u32::<BigEndian>()?, rdr.read_u8()?) {
+        (last_seq, 1) => Some(last_seq),
+        _ => None
     };
 
+    let ups : Vec<Update> = ups.into_iter()
+                                .filter(|up| match (cur_len, last_seq) {
+                                    (0, _) => true,
+                                    (_, Some(last_seq)) => (up.ts, up.seq) > (old_max_ts, last_seq),
+                                    // written before the last seq was in the header
+                                    (_, None) => up.ts > old_max_ts,
+                                })
+                                .cloned()
+                                .collect();
+    if ups.is_empty() {
+        return Ok(None);
+    }
+
     let new_len = cur_len + ups.len() as u64;
+    let (new_max_ts, new_last_seq) = get_newest(&ups).unwrap();
 
-    let mut wtr = file_writer(fname, false);
+    let mut wtr = Cursor::new(header);
     write_len(&mut wtr, new_len);
     write_max_ts(&mut wtr, new_max_ts);
-
+    write_last_seq(&mut wtr, new_last_seq);
     if cur_len == 0 {
+        write_min_ts(&mut wtr, get_min_ts(&ups));
+    }
+
+    let mut batches = Vec::new();
+    write_batches(&mut batches, &ups);
+    Ok(Some(AppendPlan { header: wtr.into_inner(), batches, at_main_offset: cur_len == 0, ups }))
+}
+
+/// Appends the rows newer than the newest row of the file and updates the
+/// header in place. Only the header is read, never the rows already in the file.
+/// Returns the appended rows.
+pub fn append(fname: &str, ups : &[Update]) -> Vec<Update> {
+    let mut header = Vec::new();
+    let file = File::open(fname).expect("OPENING FILE");
+    let old_len = file.metadata().expect("READING LENGTH").len();
+    file.take(MAIN_OFFSET)
+        .read_to_end(&mut header)
+        .expect("READING HEADER");
+    let plan = match plan_append(&header, ups).expect("MAGIC VALUE INCORRECT") {
+        Some(plan) => plan,
+        None => return Vec::new()
+    };
+
+    let mut wtr = file_writer(fname, false);
+    wtr.write_all(&plan.header).unwrap();
+    if plan.at_main_offset {
         wtr.seek(SeekFrom::Start(MAIN_OFFSET)).unwrap();
     } else {
         wtr.seek(SeekFrom::End(0)).unwrap();
     }
-    write_batches(&mut wtr, &ups);
+    wtr.write_all(&plan.batches).unwrap();
     wtr.flush().unwrap();
+    batch_index_appended(fname, old_len, &plan);
+    plan.ups
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    /// remove a test file and its batch index
+    fn remove(fname: &str) {
+        let _ = fs::remove_file(fname);
+        remove_batch_index(fname);
+    }
     fn sample_data() -> Vec<Update> {
         let mut ts : Vec<Update> = vec![];
         let t = Update {
@@ -505,6 +1097,7 @@ mod tests {
             is_bid: false,
             price: 5100.01,
             size: 1.14564564645,
+            flags: 0,
         };
         let t1 = Update {
             ts: 101,
@@ -513,6 +1106,7 @@ mod tests {
             is_bid: false,
             price: 5100.01,
             size: 2.14564564645,
+            flags: 0,
         };
         let t2 = Update {
             ts: 1000000,
@@ -521,6 +1115,7 @@ mod tests {
             is_bid: false,
             price: 5100.01,
             size: 1.123465,
+            flags: 0,
         };
         ts.push(t);
         ts.push(t1);
@@ -539,6 +1134,7 @@ mod tests {
             is_bid: false,
             price: 5100.01,
             size: 1.14564564645,
+            flags: 63,
         };
         ts.push(t);
 
@@ -555,6 +1151,7 @@ mod tests {
             is_bid: false,
             price: 5100.01,
             size: 1.14564564645,
+            flags: 0,
         };
         let t1 = Update {
             ts: 20000001,
@@ -563,6 +1160,7 @@ mod tests {
             is_bid: false,
             price: 5100.01,
             size: 1.14564564645,
+            flags: 0,
         };
         let t = Update {
             ts: 20000000,
@@ -571,6 +1169,7 @@ mod tests {
             is_bid: false,
             price: 5100.01,
             size: 1.14564564645,
+            flags: 0,
         };
         ts.push(t);
         ts.push(t1);
@@ -615,6 +1214,7 @@ mod tests {
         encode(fname, symbol, &ts);
         let decoded_updates = decode(fname, None);
         assert_eq!(decoded_updates, ts);
+        remove_batch_index(fname);
     }
 
     #[test]
@@ -623,6 +1223,23 @@ mod tests {
         let fname = "test.dtf";
         let decoded_updates = decode(fname, None);
         assert_eq!(decoded_updates, ts);
+        remove_batch_index(fname);
+    }
+
+    #[test]
+    fn should_encode_into_buffer() {
+        let ups = init();
+        let fname = "test_buffer.dtf";
+        encode(fname, "NEO_BTC", &ups);
+        let mut file = Vec::new();
+        File::open(fname).unwrap().read_to_end(&mut file).unwrap();
+        remove(fname);
+
+        let buf = encode_buffer("NEO_BTC", &ups);
+        assert_eq!(buf, file);
+        assert_eq!(decode_buffer(&buf).unwrap(), ups);
+        assert_eq!(decode_buffer(&encode_buffer("NEO_BTC", &[])).unwrap(), vec![]);
+        remove_batch_index("test.dtf");
     }
 
     #[test]
@@ -638,7 +1255,8 @@ mod tests {
                         price: 0.,
                         size: 0.,
                         is_bid: false,
-                        is_trade: false
+                        is_trade: false,
+                        flags: 0
                     })
                 .collect::<Vec<Update>>();
 
@@ -653,9 +1271,11 @@ mod tests {
                         price: 0.,
                         size: 0.,
                         is_bid: false,
-                        is_trade: false
+                        is_trade: false,
+                        flags: 0
                     })
                 .collect::<Vec<Update>>(), range(&mut rdr, 10., 20.));
+        remove_batch_index(fname);
     }
 
     #[test]
@@ -671,7 +1291,8 @@ mod tests {
                         price: 0.,
                         size: 0.,
                         is_bid: false,
-                        is_trade: false
+                        is_trade: false,
+                        flags: 0
                     })
                 .collect::<Vec<Update>>();
 
@@ -679,16 +1300,19 @@ mod tests {
         }
         
         let mut rdr = file_reader(fname);
-        assert_eq!((1..999).map(|i| 
+        // the last batch is in range too
+        assert_eq!((1..1000).map(|i| 
                     Update {
                         ts: i*1000 as u64,
                         seq: i as u32 % 500 * 500,
                         price: 0.,
                         size: 0.,
                         is_bid: false,
-                        is_trade: false
+                        is_trade: false,
+                        flags: 0
                     })
-                .collect::<Vec<Update>>(), range(&mut rdr, 1., 999.)); // ???
+                .collect::<Vec<Update>>(), range(&mut rdr, 1., 999.));
+        remove_batch_index(fname);
     }
 
     #[test]
@@ -717,6 +1341,7 @@ mod tests {
         let mut rdr = file_reader(fname);
         let sym = read_symbol(&mut rdr);
         assert_eq!(sym, "NEO_BTC");
+        remove_batch_index(fname);
     }
 
     #[test]
@@ -726,6 +1351,213 @@ mod tests {
         let mut rdr = file_reader(fname);
         let v = read_first(&mut rdr);
         assert_eq!(vs[0], v);
+        remove_batch_index(fname);
+    }
+
+    #[test]
+    fn should_read_last_record() {
+        let fname = "test_last.dtf";
+        let ups = (1..1000).map(|i| Update {
+                ts: i * 1000,
+                seq: i as u32,
+                is_trade: false,
+                is_bid: false,
+                price: 0.,
+                size: 0.,
+                flags: 0,
+            })
+            .collect::<Vec<Update>>();
+        encode(fname, "test", &ups);
+        assert_eq!(read_last(fname), ups.last().cloned());
+
+        encode(fname, "test", &[]);
+        assert_eq!(read_last(fname), None);
+        remove(fname);
+    }
+
+    #[test]
+    fn should_append_rows_sharing_the_last_ts() {
+        let fname = "test_append_seq.dtf";
+        let ups = (0..10).map(|i| Update {
+                ts: 1000 + i / 5,
+                seq: i as u32,
+                is_trade: false,
+                is_bid: false,
+                price: 0.,
+                size: 0.,
+                flags: 0,
+            })
+            .collect::<Vec<Update>>();
+        encode(fname, "test", &ups[..7]);
+        assert_eq!(read_newest(fname), Some((1001, 6)));
+
+        // rows up to (1001, 6) are already in the file
+        let appended = append(fname, &ups[5..]);
+        assert_eq!(appended, ups[7..].to_vec());
+        assert_eq!(read_newest(fname), Some((1001, 9)));
+        assert_eq!(decode(fname, None), ups);
+        remove(fname);
+    }
+
+    #[test]
+    fn should_read_min_ts_from_header() {
+        let fname = "test_min_ts.dtf";
+        let mut ups = sample_data();
+        encode(fname, "test", &ups);
+        assert_eq!(read_meta(fname).min_ts, 100);
+
+        // appending keeps the min ts
+        ups.extend(sample_data_append().into_iter().filter(|up| up.ts > 1000000));
+        append(fname, &ups);
+        let meta = read_meta(fname);
+        assert_eq!(meta.min_ts, 100);
+        assert_eq!(meta.max_ts, 20000001);
+        remove(fname);
+    }
+
+    #[test]
+    fn should_read_batch_ranges() {
+        let fname = "test_batch_ranges.dtf";
+        encode(fname, "test", &sample_data());
+        let ranges = read_batch_ranges(fname);
+        assert_eq!(ranges.len(), 2);
+        assert_eq!((ranges[0].min_ts, ranges[0].max_ts, ranges[0].count), (100, 101, 2));
+        assert_eq!((ranges[1].min_ts, ranges[1].max_ts, ranges[1].count), (1000000, 1000000, 1));
+
+        assert!(has_range(fname, 0, 100));
+        assert!(!has_range(fname, 102, 999999));
+        assert!(!has_range(fname, 1000001, 2000000));
+        remove(fname);
+    }
+
+    #[test]
+    fn should_index_many_batches() {
+        let fname = "test_batch_index.dtf";
+        // every row is a batch of its own, 0xFFFF ms apart
+        let rows = |from: u64, to: u64| (from..to).map(|i| Update {
+            ts: 100 + i * 0x10000, seq: i as u32, is_trade: false, is_bid: true, price: 1., size: i as f32, flags: 0,
+        }).collect::<Vec<_>>();
+        encode(fname, "test", &rows(0, 5000));
+        let indexed = read_batch_ranges(fname);
+        assert_eq!(indexed.len(), 5000);
+        assert_eq!(indexed, scan_batch_ranges(fname));
+
+        append(fname, &rows(5000, 8000));
+        let indexed = read_batch_ranges(fname);
+        assert_eq!(indexed.len(), 8000);
+        assert_eq!(indexed, scan_batch_ranges(fname));
+        assert!(has_range(fname, 100 + 7999 * 0x10000, u64::max_value()));
+        assert!(!has_range(fname, 101, 100 + 0x10000 - 1));
+
+        // an index of another length of the file is not used
+        let len = fs::metadata(fname).unwrap().len();
+        File::create(fname).unwrap().write_all(&encode_buffer("test", &rows(0, 10))).unwrap();
+        assert!(read_batch_index(fname, len).is_some());
+        assert_eq!(read_batch_ranges(fname), scan_batch_ranges(fname));
+        assert_eq!(read_batch_ranges(fname).len(), 10);
+
+        // nor is one of a file that was appended to without it
+        remove_batch_index(fname);
+        append(fname, &rows(10, 20));
+        assert_eq!(read_batch_index(fname, fs::metadata(fname).unwrap().len()).map(|r| r.len()), Some(20));
+        remove(fname);
+    }
+
+    #[test]
+    fn should_read_batch_zones() {
+        let fname = "test_batch_zones.dtf";
+        encode(fname, "test", &sample_data());
+        let zones = read_batch_zones(fname, 0);
+        assert_eq!(zones.iter().map(|z| z.batch.clone()).collect::<Vec<_>>(), read_batch_ranges(fname));
+        assert_eq!((zones[0].min_price, zones[0].max_price), (5100.01, 5100.01));
+        assert_eq!((zones[0].min_size, zones[0].max_size), (1.14564564645, 2.14564564645));
+        assert_eq!((zones[1].min_size, zones[1].max_size), (1.123465, 1.123465));
+
+        // only the appended batches
+        let len = fs::metadata(fname).unwrap().len();
+        append(fname, &sample_data_append());
+        let appended = read_batch_zones(fname, len);
+        assert_eq!(appended.len() + zones.len(), read_batch_ranges(fname).len());
+        assert_eq!(appended[0].batch.offset, len);
+        remove(fname);
+    }
+
+    #[test]
+    fn should_verify() {
+        let fname = "test_verify.dtf";
+        encode(fname, "test", &sample_data());
+        assert_eq!(verify(fname), Ok(()));
+        encode(fname, "test", &[]);
+        assert_eq!(verify(fname), Ok(()));
+
+        encode(fname, "test", &sample_data());
+        let len = fs::metadata(fname).unwrap().len();
+        fs::OpenOptions::new().write(true).open(fname).unwrap().set_len(len - 5).unwrap();
+        assert!(verify(fname).unwrap_err().contains("truncated"));
+
+        encode(fname, "test", &sample_data());
+        let mut wtr = file_writer(fname, false);
+        write_len(&mut wtr, 4);
+        wtr.flush().unwrap();
+        drop(wtr);
+        assert_eq!(verify(fname), Err("header counts 4 rows, the batches hold 3".to_owned()));
+        remove(fname);
+    }
+
+    #[test]
+    fn should_iterate_batches() {
+        let fname = "test_batches.dtf";
+        encode(fname, "test", &sample_data());
+        let read : Vec<Vec<Update>> = batches(fname).unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read.concat(), decode(fname, None));
+
+        // a batch cut short is an error, not the end of the file
+        let len = fs::metadata(fname).unwrap().len();
+        fs::OpenOptions::new().write(true).open(fname).unwrap().set_len(len - 5).unwrap();
+        let read : Vec<Result<Vec<Update>, io::Error>> = batches(fname).unwrap().collect();
+        assert_eq!(read.len(), 2);
+        assert!(read[0].is_ok() && read[1].is_err());
+        remove(fname);
+        assert!(batches("test_no_such.dtf").is_err());
+    }
+
+    #[test]
+    fn should_mark_the_batches_with_flags() {
+        let ups = sample_data();
+        let mut buf = Vec::new();
+        write_batches(&mut buf, &ups);
+        // rows without flags are written as before them
+        assert_eq!(buf[0], BATCH);
+
+        let flagged : Vec<Update> = ups.iter().map(|up| up.clone().with_flags(5)).collect();
+        let mut buf = Vec::new();
+        write_batches(&mut buf, &flagged);
+        assert_eq!(buf[0], BATCH_WITH_FLAGS);
+        assert_eq!(decode_batches_buffer(&buf).unwrap(), flagged);
+
+        // the same bits in a batch written without flags are corrupt
+        buf[0] = BATCH;
+        assert_eq!(decode_batches_buffer(&buf).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let meta = BatchMetadata { ref_ts: 0, ref_seq: 0, count: 1, user_flags: false };
+        let row = flagged[0].serialize(flagged[0].ts, flagged[0].seq);
+        assert!(try_read_one_update(&mut Cursor::new(&row), &meta).is_err());
+        let meta = BatchMetadata { user_flags: true, ..meta };
+        assert_eq!(try_read_one_update(&mut Cursor::new(&row), &meta).unwrap().flags, 5);
+    }
+
+    #[test]
+    fn should_decode_prefix() {
+        let fname = "test_prefix.dtf";
+        let ups = sample_data();
+        encode(fname, "test", &ups);
+        let len = fs::metadata(fname).unwrap().len();
+        append(fname, &sample_data_append().into_iter().filter(|up| up.ts > 1000000).collect::<Vec<_>>());
+
+        assert_eq!(decode_prefix(fname, len), ups);
+        assert_eq!(decode_prefix(fname, MAIN_OFFSET), vec![]);
+        assert_eq!(decode_prefix(fname, fs::metadata(fname).unwrap().len()), decode(fname, None));
+        remove(fname);
     }
 
     #[test]
@@ -735,6 +1567,7 @@ mod tests {
         let mut rdr = file_reader(fname);
         let len = read_len(&mut rdr);
         assert_eq!(vs.len() as u64, len);
+        remove_batch_index(fname);
     }
 
     #[test]
@@ -744,6 +1577,7 @@ mod tests {
         let mut rdr = file_reader(fname);
         let max_ts = read_max_ts(&mut rdr);
         assert_eq!(max_ts, get_max_ts(&vs));
+        remove_batch_index(fname);
     }
 
     // #[cfg(test)]
@@ -800,7 +1634,7 @@ mod tests {
         all_the_data.sort();
         let decoded = decode(&fname, None);
         assert_eq!(all_the_data, decoded);
-        
+        remove_batch_index(fname);
     }
 
     #[test]
@@ -812,7 +1646,27 @@ mod tests {
             is_bid: false,
             price: 5100.01,
             size: 1.14564564645,
+            flags: 0,
         };
         assert_eq!(r#"{"ts":20000.001,"seq":113,"is_trade":false,"is_bid":false,"price":5100.01,"size":1.1456456}"#, t1.to_json());
+        assert_eq!(t1.to_json_with(None), t1.to_json());
+        assert_eq!(r#"{"ts":20000.001,"seq":113,"is_trade":false,"is_bid":false,"price":5100.01,"size":1.15}"#, t1.to_json_with(Some(2)));
+
+        let tiny = Update { size: 0.0000001, ..t1 };
+        assert!(tiny.to_json().ends_with(r#""size":0.0000001}"#));
+        assert!(tiny.to_json_with(Some(8)).ends_with(r#""size":0.00000010}"#));
+
+        let flagged = Update { flags: 5, ..t1 };
+        assert!(flagged.to_json_with(Some(2)).ends_with(r#""size":1.15,"flags":5}"#));
+        assert_eq!(flagged.to_csv(), "20000.001,113,false,false,5100.01,1.1456456,5");
+    }
+
+    #[test]
+    fn should_speak_jsonl() {
+        let ups = init();
+        let jsonl = update_vec_to_jsonl(&ups[..2]);
+        assert_eq!(jsonl, format!("{}\n{}", ups[0].to_json(), ups[1].to_json()));
+        assert_eq!(update_vec_to_jsonl(&[]), "");
+        remove_batch_index("test.dtf");
     }
 }
\ No newline at end of file
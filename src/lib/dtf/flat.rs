@@ -0,0 +1,165 @@
+/// Flat, zero-copy response encoding
+///
+/// Updates are laid out as fixed-width little-endian records so a consumer
+/// can read any field directly out of the received buffer without decoding
+/// the whole message first.
+///
+/// Layout:
+/// Offset 00: (u64) number of records
+/// Offset 08: -- records - see below --
+///
+/// Record Spec (24 bytes):
+/// Offset 00: ts (u64)
+/// Offset 08: seq (u32)
+/// Offset 12: flags (u8), same bits as `Update::flags_byte`
+/// Offset 13: padding ([u8; 3])
+/// Offset 16: price (f32)
+/// Offset 20: size (f32)
+
+use update::*;
+use std::convert::TryFrom;
+use byteorder::{LittleEndian, WriteBytesExt, ByteOrder};
+
+pub const FLAT_HEADER_LEN : usize = 8;
+pub const FLAT_RECORD_LEN : usize = 24;
+
+/// encode updates into the flat layout
+pub fn encode_flat(ups: &[Update]) -> Vec<u8> {
+    let mut buf : Vec<u8> = Vec::with_capacity(FLAT_HEADER_LEN + ups.len() * FLAT_RECORD_LEN);
+    let _ = buf.write_u64::<LittleEndian>(ups.len() as u64);
+    for up in ups.iter() {
+        let _ = buf.write_u64::<LittleEndian>(up.ts);
+        let _ = buf.write_u32::<LittleEndian>(up.seq);
+        let _ = buf.write_u8(up.flags_byte());
+        buf.extend_from_slice(&[0u8; 3]);
+        let _ = buf.write_f32::<LittleEndian>(up.price);
+        let _ = buf.write_f32::<LittleEndian>(up.size);
+    }
+    buf
+}
+
+/// Read-only view over a flat encoded buffer. Fields are read in place.
+pub struct FlatView<'a> {
+    buf: &'a [u8],
+    len: usize,
+}
+
+impl<'a> FlatView<'a> {
+    /// returns None if the buffer is shorter than its header claims
+    pub fn new(buf: &'a [u8]) -> Option<FlatView<'a>> {
+        if buf.len() < FLAT_HEADER_LEN {
+            return None;
+        }
+        let len = LittleEndian::read_u64(&buf[..FLAT_HEADER_LEN]);
+        // a corrupt header can claim more records than fit in a usize
+        let len = usize::try_from(len).ok()?;
+        let size = len.checked_mul(FLAT_RECORD_LEN)?.checked_add(FLAT_HEADER_LEN)?;
+        if buf.len() < size {
+            return None;
+        }
+        Some(FlatView { buf, len })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn record(&self, i: usize) -> &'a [u8] {
+        assert!(i < self.len, "index out of bounds");
+        let start = FLAT_HEADER_LEN + i * FLAT_RECORD_LEN;
+        &self.buf[start..(start + FLAT_RECORD_LEN)]
+    }
+
+    pub fn ts(&self, i: usize) -> u64 {
+        LittleEndian::read_u64(&self.record(i)[0..8])
+    }
+
+    pub fn seq(&self, i: usize) -> u32 {
+        LittleEndian::read_u32(&self.record(i)[8..12])
+    }
+
+    pub fn is_bid(&self, i: usize) -> bool {
+        self.record(i)[12] & Flags::FLAG_IS_BID.bits() != 0
+    }
+
+    pub fn is_trade(&self, i: usize) -> bool {
+        self.record(i)[12] & Flags::FLAG_IS_TRADE.bits() != 0
+    }
+
+    /// the user defined flags, see `Update::flags`
+    pub fn flags(&self, i: usize) -> u8 {
+        self.record(i)[12] >> USER_FLAGS_SHIFT
+    }
+
+    pub fn price(&self, i: usize) -> f32 {
+        LittleEndian::read_f32(&self.record(i)[16..20])
+    }
+
+    pub fn size(&self, i: usize) -> f32 {
+        LittleEndian::read_f32(&self.record(i)[20..24])
+    }
+
+    /// materialize record `i`
+    pub fn get(&self, i: usize) -> Update {
+        Update {
+            ts: self.ts(i),
+            seq: self.seq(i),
+            is_trade: self.is_trade(i),
+            is_bid: self.is_bid(i),
+            price: self.price(i),
+            size: self.size(i),
+            flags: self.flags(i),
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<Update> {
+        (0..self.len).map(|i| self.get(i)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_data() -> Vec<Update> {
+        vec![
+            Update { ts: 1505177459658, seq: 139010, is_trade: false, is_bid: true, price: 0.0703629, size: 7.65064249, flags: 0 },
+            Update { ts: 1505177459660, seq: 139011, is_trade: true, is_bid: false, price: 0.0703620, size: 1.5, flags: 5 },
+        ]
+    }
+
+    #[test]
+    fn should_read_fields_in_place() {
+        let ups = sample_data();
+        let buf = encode_flat(&ups);
+        assert_eq!(buf.len(), FLAT_HEADER_LEN + 2 * FLAT_RECORD_LEN);
+
+        let view = FlatView::new(&buf).unwrap();
+        assert_eq!(view.len(), 2);
+        assert_eq!(view.ts(1), 1505177459660);
+        assert!(view.is_trade(1));
+        assert!(view.is_bid(0));
+        assert_eq!(view.flags(1), 5);
+        assert_eq!(view.to_vec(), ups);
+    }
+
+    #[test]
+    fn should_reject_truncated_buffer() {
+        let buf = encode_flat(&sample_data());
+        assert!(FlatView::new(&buf[..buf.len() - 1]).is_none());
+        assert!(FlatView::new(&buf[..4]).is_none());
+    }
+
+    #[test]
+    fn should_reject_header_overflowing_the_length() {
+        let mut buf = encode_flat(&sample_data());
+        LittleEndian::write_u64(&mut buf[..FLAT_HEADER_LEN], u64::max_value());
+        assert!(FlatView::new(&buf).is_none());
+        LittleEndian::write_u64(&mut buf[..FLAT_HEADER_LEN], u64::max_value() / FLAT_RECORD_LEN as u64);
+        assert!(FlatView::new(&buf).is_none());
+    }
+}
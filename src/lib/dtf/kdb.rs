@@ -0,0 +1,125 @@
+/// kdb+ splayed table export
+///
+/// Writes updates as a splayed table, a directory with one file per column
+/// and `.d` listing the columns, which q loads with ``get `:/path/to/table/``.
+///
+/// Every file is a simple list as written by `set`:
+/// Offset 00: ([u8; 2]) 0xff 0x01
+/// Offset 02: (i8) type
+/// Offset 03: (u8) attribute, 0
+/// Offset 04: (i32) number of items
+/// Offset 08: -- items, little endian --
+///
+/// Columns:
+/// ts: timestamp, ns since 2000.01.01
+/// seq: long
+/// is_trade, is_bid: boolean
+/// price, size: real
+/// flags: byte, see `Update::flags`
+///
+/// There is no symbol column, so the table needs no `sym` file.
+
+use update::*;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use byteorder::{LittleEndian, WriteBytesExt};
+
+static KDB_BOOLEAN : i8 = 1;
+static KDB_BYTE : i8 = 4;
+static KDB_LONG : i8 = 7;
+static KDB_REAL : i8 = 8;
+static KDB_SYMBOL : i8 = 11;
+static KDB_TIMESTAMP : i8 = 12;
+
+/// ms between 1970.01.01 and 2000.01.01, the kdb+ epoch
+static KDB_EPOCH_MS : i64 = 946_684_800_000;
+
+static COLUMNS : &[&str] = &["ts", "seq", "is_trade", "is_bid", "price", "size", "flags"];
+
+fn write_header(wtr: &mut Write, kdb_type: i8, len: usize) -> Result<(), io::Error> {
+    if len > i32::max_value() as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many rows for a kdb+ list"));
+    }
+    wtr.write_all(&[0xff, 0x01])?;
+    wtr.write_i8(kdb_type)?;
+    wtr.write_u8(0)?;
+    wtr.write_i32::<LittleEndian>(len as i32)
+}
+
+fn write_column<F>(fname: &Path, ups: &[Update], kdb_type: i8, write: F) -> Result<(), io::Error>
+        where F: Fn(&mut BufWriter<File>, &Update) -> Result<(), io::Error> {
+    let mut wtr = BufWriter::new(File::create(fname)?);
+    write_header(&mut wtr, kdb_type, ups.len())?;
+    for up in ups.iter() {
+        write(&mut wtr, up)?;
+    }
+    wtr.flush()
+}
+
+/// kdb+ timestamp of a ts in ms
+pub fn to_kdb_timestamp(ts: u64) -> i64 {
+    (ts as i64 - KDB_EPOCH_MS) * 1_000_000
+}
+
+/// write `ups` as a splayed table into the folder `dir`, created if needed
+pub fn write_splayed(dir: &str, ups: &[Update]) -> Result<(), io::Error> {
+    let dir = Path::new(dir);
+    fs::create_dir_all(dir)?;
+
+    write_column(&dir.join("ts"), ups, KDB_TIMESTAMP,
+        |wtr, up| wtr.write_i64::<LittleEndian>(to_kdb_timestamp(up.ts)))?;
+    write_column(&dir.join("seq"), ups, KDB_LONG,
+        |wtr, up| wtr.write_i64::<LittleEndian>(i64::from(up.seq)))?;
+    write_column(&dir.join("is_trade"), ups, KDB_BOOLEAN,
+        |wtr, up| wtr.write_u8(up.is_trade as u8))?;
+    write_column(&dir.join("is_bid"), ups, KDB_BOOLEAN,
+        |wtr, up| wtr.write_u8(up.is_bid as u8))?;
+    write_column(&dir.join("price"), ups, KDB_REAL,
+        |wtr, up| wtr.write_f32::<LittleEndian>(up.price))?;
+    write_column(&dir.join("size"), ups, KDB_REAL,
+        |wtr, up| wtr.write_f32::<LittleEndian>(up.size))?;
+    write_column(&dir.join("flags"), ups, KDB_BYTE,
+        |wtr, up| wtr.write_u8(up.flags))?;
+
+    // the column names, a symbol list
+    let mut wtr = BufWriter::new(File::create(dir.join(".d"))?);
+    write_header(&mut wtr, KDB_SYMBOL, COLUMNS.len())?;
+    for column in COLUMNS.iter() {
+        wtr.write_all(column.as_bytes())?;
+        wtr.write_u8(0)?;
+    }
+    wtr.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn read(fname: &str) -> Vec<u8> {
+        let mut buf = Vec::new();
+        File::open(fname).unwrap().read_to_end(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn should_write_splayed_table() {
+        let dir = "test_kdb_splayed";
+        let ups = vec![
+            Update { ts: 946_684_800_001, seq: 7, is_trade: true, is_bid: false, price: 1.5, size: 2., flags: 0 },
+            Update { ts: 946_684_800_002, seq: 8, is_trade: false, is_bid: true, price: 1.25, size: 3., flags: 5 },
+        ];
+        write_splayed(dir, &ups).unwrap();
+
+        assert_eq!(read(&format!("{}/.d", dir)),
+            b"\xff\x01\x0b\x00\x07\x00\x00\x00ts\0seq\0is_trade\0is_bid\0price\0size\0flags\0".to_vec());
+        let ts = read(&format!("{}/ts", dir));
+        assert_eq!(&ts[..8], &[0xff, 0x01, 12, 0, 2, 0, 0, 0]);
+        assert_eq!(&ts[8..16], &[0x40, 0x42, 0x0f, 0, 0, 0, 0, 0]); // 1ms
+        assert_eq!(read(&format!("{}/is_bid", dir)), vec![0xff, 0x01, 1, 0, 2, 0, 0, 0, 0, 1]);
+        assert_eq!(read(&format!("{}/price", dir)).len(), 8 + 2 * 4);
+        assert_eq!(read(&format!("{}/flags", dir)), vec![0xff, 0x01, 4, 0, 2, 0, 0, 0, 0, 5]);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
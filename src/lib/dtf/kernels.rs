@@ -0,0 +1,251 @@
+/// Batch decode kernels
+///
+/// The rows of a batch are 12 bytes each, big endian: the ts (u16) and seq
+/// (u8) deltas to the batch reference, the flags (u8), price (f32) and size
+/// (f32). `decode_rows` turns them into `Update`s and keeps those within a ts
+/// range in one pass. On x86_64 CPUs with SSSE3 (detected at runtime), 4
+/// rows at a time are shuffled into columns, byte swapped, checked and
+/// compared to the range with SSE instructions. The other rows, other CPUs
+/// and batches whose ts or seq could overflow are decoded one row at a
+/// time by `decode_rows_scalar`. Both give the same rows and errors, see
+/// `benches/decode.rs` for how they compare.
+
+use std::io;
+use byteorder::{BigEndian, ByteOrder};
+use update::{Flags, Update};
+use file_format::BatchMetadata;
+
+/// bytes per row of a batch
+pub const ROW_LEN : usize = 12;
+
+/// bits of the flags byte other than is_bid and is_trade, only set in a
+/// batch whose rows may carry user defined flags
+fn unknown_flags(meta: &BatchMetadata) -> u8 {
+    if meta.user_flags { 0 } else { Flags::FLAG_USER.bits() }
+}
+
+fn corrupt(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+/// Append the rows of `buf`, the rows of the batch `meta`, whose ts is in
+/// [min_ts, max_ts] (in ms) to `out`. Nothing is appended if a row is corrupt.
+pub fn decode_rows(buf: &[u8], meta: &BatchMetadata, min_ts: u64, max_ts: u64, out: &mut Vec<Update>) -> Result<(), io::Error> {
+    let start = out.len();
+    let decoded = decode_rows_fast(buf, meta, min_ts, max_ts, out);
+    if decoded.is_err() {
+        out.truncate(start);
+    }
+    decoded
+}
+
+#[cfg(target_arch = "x86_64")]
+fn decode_rows_fast(buf: &[u8], meta: &BatchMetadata, min_ts: u64, max_ts: u64, out: &mut Vec<Update>) -> Result<(), io::Error> {
+    let overflows = meta.ref_ts.checked_add(0xFFFF).is_none() || meta.ref_seq.checked_add(0xFF).is_none();
+    if overflows || !is_x86_feature_detected!("ssse3") {
+        return decode_rows_scalar(buf, meta, min_ts, max_ts, out);
+    }
+    let simd_len = buf.len() / (4 * ROW_LEN) * 4 * ROW_LEN;
+    unsafe { ssse3::decode_rows(&buf[..simd_len], meta, min_ts, max_ts, out)? };
+    decode_rows_scalar(&buf[simd_len..], meta, min_ts, max_ts, out)
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn decode_rows_fast(buf: &[u8], meta: &BatchMetadata, min_ts: u64, max_ts: u64, out: &mut Vec<Update>) -> Result<(), io::Error> {
+    decode_rows_scalar(buf, meta, min_ts, max_ts, out)
+}
+
+/// `decode_rows` one row at a time, rows appended before a corrupt row are kept
+pub fn decode_rows_scalar(buf: &[u8], meta: &BatchMetadata, min_ts: u64, max_ts: u64, out: &mut Vec<Update>) -> Result<(), io::Error> {
+    if buf.len() % ROW_LEN != 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "partial row"));
+    }
+    let unknown_flags = unknown_flags(meta);
+    for row in buf.chunks(ROW_LEN) {
+        let ts = meta.ref_ts.checked_add(u64::from(BigEndian::read_u16(&row[0..2])))
+            .ok_or_else(|| corrupt("ts overflows"))?;
+        let seq = meta.ref_seq.checked_add(u32::from(row[2]))
+            .ok_or_else(|| corrupt("seq overflows"))?;
+        if row[3] & unknown_flags != 0 {
+            return Err(corrupt("unknown flags"));
+        }
+        if ts < min_ts || ts > max_ts {
+            continue;
+        }
+        out.push(Update {
+            ts,
+            seq,
+            is_trade: false,
+            is_bid: false,
+            price: BigEndian::read_f32(&row[4..8]),
+            size: BigEndian::read_f32(&row[8..12]),
+            flags: 0,
+        }.with_flags_byte(row[3]));
+    }
+    Ok(())
+}
+
+#[cfg(target_arch = "x86_64")]
+mod ssse3 {
+    use std::arch::x86_64::*;
+    use std::io;
+    use update::Update;
+    use file_format::BatchMetadata;
+    use super::{corrupt, ROW_LEN};
+
+    /// lane of -1 is zeroed by `_mm_shuffle_epi8`
+    const Z : i8 = -1;
+
+    type Blocks = (__m128i, __m128i, __m128i);
+
+    /// one field of 4 rows: the bytes picked from each block by `masks`
+    #[inline]
+    #[target_feature(enable = "ssse3")]
+    unsafe fn gather(blocks: Blocks, masks: Blocks) -> __m128i {
+        _mm_or_si128(_mm_or_si128(_mm_shuffle_epi8(blocks.0, masks.0), _mm_shuffle_epi8(blocks.1, masks.1)),
+            _mm_shuffle_epi8(blocks.2, masks.2))
+    }
+
+    /// Rows of `buf`, a multiple of 4 rows, in [min_ts, max_ts]. The
+    /// caller checked that ts and seq cannot overflow.
+    #[target_feature(enable = "ssse3")]
+    pub unsafe fn decode_rows(buf: &[u8], meta: &BatchMetadata, min_ts: u64, max_ts: u64, out: &mut Vec<Update>) -> Result<(), io::Error> {
+        // ts in [min_ts, max_ts] <=> dts in [lo, hi], all dts are within [0, 0xFFFF]
+        let (lo, hi) = if max_ts < meta.ref_ts || min_ts > meta.ref_ts + 0xFFFF {
+            (1, 0)
+        } else {
+            (min_ts.saturating_sub(meta.ref_ts) as i32, (max_ts - meta.ref_ts).min(0xFFFF) as i32)
+        };
+        let lo = _mm_set1_epi32(lo);
+        let hi = _mm_set1_epi32(hi);
+        let dts_mask = _mm_set1_epi32(0xFFFF);
+        let unknown_flags = _mm_set1_epi32((u32::from(super::unknown_flags(meta)) << 24) as i32);
+
+        // [dts (u16), dseq, flags] of each row, from the 3 blocks of 16 bytes holding 4 rows
+        let head = (
+            _mm_setr_epi8(1, 0, 2, 3, 13, 12, 14, 15, Z, Z, Z, Z, Z, Z, Z, Z),
+            _mm_setr_epi8(Z, Z, Z, Z, Z, Z, Z, Z, 9, 8, 10, 11, Z, Z, Z, Z),
+            _mm_setr_epi8(Z, Z, Z, Z, Z, Z, Z, Z, Z, Z, Z, Z, 5, 4, 6, 7),
+        );
+        let price = (
+            _mm_setr_epi8(7, 6, 5, 4, Z, Z, Z, Z, Z, Z, Z, Z, Z, Z, Z, Z),
+            _mm_setr_epi8(Z, Z, Z, Z, 3, 2, 1, 0, 15, 14, 13, 12, Z, Z, Z, Z),
+            _mm_setr_epi8(Z, Z, Z, Z, Z, Z, Z, Z, Z, Z, Z, Z, 11, 10, 9, 8),
+        );
+        let size = (
+            _mm_setr_epi8(11, 10, 9, 8, Z, Z, Z, Z, Z, Z, Z, Z, Z, Z, Z, Z),
+            _mm_setr_epi8(Z, Z, Z, Z, 7, 6, 5, 4, Z, Z, Z, Z, Z, Z, Z, Z),
+            _mm_setr_epi8(Z, Z, Z, Z, Z, Z, Z, Z, 3, 2, 1, 0, 15, 14, 13, 12),
+        );
+
+        let mut heads = [0u32; 4];
+        let mut prices = [0f32; 4];
+        let mut sizes = [0f32; 4];
+        for rows in buf.chunks(4 * ROW_LEN) {
+            let ptr = rows.as_ptr() as *const __m128i;
+            let blocks = (_mm_loadu_si128(ptr), _mm_loadu_si128(ptr.offset(1)), _mm_loadu_si128(ptr.offset(2)));
+            let head_lanes = gather(blocks, head);
+            if _mm_movemask_epi8(_mm_cmpeq_epi32(_mm_and_si128(head_lanes, unknown_flags), _mm_setzero_si128())) != 0xFFFF {
+                return Err(corrupt("unknown flags"));
+            }
+            let dts = _mm_and_si128(head_lanes, dts_mask);
+            let outside = _mm_or_si128(_mm_cmplt_epi32(dts, lo), _mm_cmpgt_epi32(dts, hi));
+            let outside = _mm_movemask_ps(_mm_castsi128_ps(outside));
+            if outside == 0b1111 {
+                continue;
+            }
+            _mm_storeu_si128(heads.as_mut_ptr() as *mut __m128i, head_lanes);
+            _mm_storeu_ps(prices.as_mut_ptr(), _mm_castsi128_ps(gather(blocks, price)));
+            _mm_storeu_ps(sizes.as_mut_ptr(), _mm_castsi128_ps(gather(blocks, size)));
+            for i in 0..4 {
+                if outside & (1 << i) != 0 {
+                    continue;
+                }
+                out.push(Update {
+                    ts: meta.ref_ts + u64::from(heads[i] & 0xFFFF),
+                    seq: meta.ref_seq + ((heads[i] >> 16) & 0xFF),
+                    is_trade: false,
+                    is_bid: false,
+                    price: prices[i],
+                    size: sizes[i],
+                    flags: 0,
+                }.with_flags_byte((heads[i] >> 24) as u8));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// rows with every flag, price sign and ts delta edge
+    fn rows(count: usize) -> Vec<u8> {
+        let mut buf = vec![0; count * ROW_LEN];
+        for (i, row) in buf.chunks_mut(ROW_LEN).enumerate() {
+            let dts = if i % 7 == 0 { 0xFFFF } else { (i * 997 % 0x10000) as u16 };
+            BigEndian::write_u16(&mut row[0..2], dts);
+            row[2] = (i * 31 % 256) as u8;
+            row[3] = (i * 7 % 256) as u8;
+            BigEndian::write_f32(&mut row[4..8], i as f32 * -0.37 + 5.);
+            BigEndian::write_f32(&mut row[8..12], i as f32 * 1.5e-3);
+        }
+        buf
+    }
+
+    fn decoded(buf: &[u8], meta: &BatchMetadata, min_ts: u64, max_ts: u64) -> (Result<Vec<Update>, String>, Result<Vec<Update>, String>) {
+        let mut fast = Vec::new();
+        let mut scalar = Vec::new();
+        let fast_result = decode_rows(buf, meta, min_ts, max_ts, &mut fast);
+        let scalar_result = decode_rows_scalar(buf, meta, min_ts, max_ts, &mut scalar);
+        (fast_result.map(|_| fast).map_err(|e| e.to_string()), scalar_result.map(|_| scalar).map_err(|e| e.to_string()))
+    }
+
+    #[test]
+    fn should_decode_like_the_scalar_kernel() {
+        let meta = BatchMetadata { ref_ts: 1_505_177_459_000, ref_seq: 100, count: 0, user_flags: true };
+        for &count in &[0, 1, 3, 4, 5, 8, 67, 1000] {
+            let buf = rows(count);
+            for &(min_ts, max_ts) in &[(0, u64::max_value()), (meta.ref_ts + 1000, meta.ref_ts + 30_000),
+                                       (meta.ref_ts + 0xFFFF, meta.ref_ts + 0xFFFF), (0, meta.ref_ts - 1),
+                                       (meta.ref_ts + 0x10000, u64::max_value()), (meta.ref_ts + 10, meta.ref_ts + 5)] {
+                let (fast, scalar) = decoded(&buf, &meta, min_ts, max_ts);
+                assert_eq!(fast, scalar);
+                assert!(fast.unwrap().iter().all(|up| up.ts >= min_ts && up.ts <= max_ts));
+            }
+        }
+    }
+
+    #[test]
+    fn should_reject_corrupt_rows() {
+        let meta = BatchMetadata { ref_ts: 1_505_177_459_000, ref_seq: 100, count: 0, user_flags: true };
+        let mut buf = rows(9);
+        buf.pop();
+        let mut out = vec![Update::new(1, 1, false, false, 1., 1.)];
+        assert!(decode_rows(&buf, &meta, 0, u64::max_value(), &mut out).is_err());
+        assert_eq!(out.len(), 1);
+
+        // a batch written without user flags has none
+        let old = BatchMetadata { user_flags: false, ..meta.clone() };
+        let mut buf = rows(9);
+        for row in buf.chunks_mut(ROW_LEN) {
+            row[3] &= 0b11;
+        }
+        assert_eq!(decoded(&buf, &old, 0, u64::max_value()).0.unwrap().len(), 9);
+        buf[5 * ROW_LEN + 3] = 0b100;
+        let (fast, scalar) = decoded(&buf, &old, 0, u64::max_value());
+        assert_eq!(fast, Err("unknown flags".to_owned()));
+        assert_eq!(fast, scalar);
+        assert!(decode_rows(&buf, &old, 0, u64::max_value(), &mut out).is_err());
+        assert_eq!(out.len(), 1);
+
+        let (fast, scalar) = decoded(&rows(8), &BatchMetadata { ref_ts: u64::max_value() - 10, ref_seq: 0, count: 0, user_flags: true }, 0, u64::max_value());
+        assert_eq!(fast, Err("ts overflows".to_owned()));
+        assert_eq!(fast, scalar);
+        let (fast, scalar) = decoded(&rows(8), &BatchMetadata { ref_ts: 0, ref_seq: u32::max_value(), count: 0, user_flags: true }, 0, u64::max_value());
+        assert_eq!(fast, Err("seq overflows".to_owned()));
+        assert_eq!(fast, scalar);
+        assert!(decode_rows(&rows(2)[..20], &meta, 0, u64::max_value(), &mut Vec::new()).is_err());
+    }
+}
@@ -1,8 +1,19 @@
 pub mod file_format;
 pub use file_format::*;
 
+pub mod kernels;
+
 pub mod update;
 pub use update::*;
 
 pub mod symbol;
-pub use symbol::*;
\ No newline at end of file
+pub use symbol::*;
+
+pub mod flat;
+pub use flat::*;
+
+pub mod kdb;
+pub use kdb::*;
+
+pub mod msgpack;
+pub use msgpack::*;
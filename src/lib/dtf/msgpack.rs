@@ -0,0 +1,327 @@
+/// MessagePack encoding
+///
+/// A compact, self-describing alternative to JSON, negotiated per connection
+/// with `FORMAT MSGPACK`. Updates are encoded as an array of maps with the
+/// keys of `Update::to_json`:
+///
+/// {"ts": uint, "seq": uint, "is_trade": bool, "is_bid": bool, "price": float32, "size": float32}
+///
+/// except that ts is in ms, so it round trips exactly. A row with user
+/// defined flags has a 7th key, "flags": uint.
+///
+/// A row is decoded from either such a map or an array
+/// [ts, seq, is_trade, is_bid, price, size, (flags)], an integer ts is in ms
+/// and a float ts in seconds like in JSON.
+
+use update::*;
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+
+static KEYS : &[&str] = &["ts", "seq", "is_trade", "is_bid", "price", "size"];
+
+/// a decoded MessagePack value
+#[derive(Debug, Clone, PartialEq)]
+pub enum MsgpackValue {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Str(String),
+    Bin(Vec<u8>),
+    Array(Vec<MsgpackValue>),
+    Map(Vec<(MsgpackValue, MsgpackValue)>),
+}
+
+fn write_array_len(buf: &mut Vec<u8>, len: usize) {
+    if len < 16 {
+        buf.push(0x90 | len as u8);
+    } else if len <= u16::max_value() as usize {
+        buf.push(0xdc);
+        let _ = buf.write_u16::<BigEndian>(len as u16);
+    } else {
+        buf.push(0xdd);
+        let _ = buf.write_u32::<BigEndian>(len as u32);
+    }
+}
+
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+    // keys only, always shorter than 32 bytes
+    buf.push(0xa0 | s.len() as u8);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn write_uint(buf: &mut Vec<u8>, n: u64) {
+    if n < 128 {
+        buf.push(n as u8);
+    } else if n <= u64::from(u32::max_value()) {
+        buf.push(0xce);
+        let _ = buf.write_u32::<BigEndian>(n as u32);
+    } else {
+        buf.push(0xcf);
+        let _ = buf.write_u64::<BigEndian>(n);
+    }
+}
+
+fn write_bool(buf: &mut Vec<u8>, b: bool) {
+    buf.push(if b { 0xc3 } else { 0xc2 });
+}
+
+fn write_f32(buf: &mut Vec<u8>, f: f32) {
+    buf.push(0xca);
+    let _ = buf.write_f32::<BigEndian>(f);
+}
+
+/// encode updates as a MessagePack array of maps
+pub fn encode_msgpack(ups: &[Update]) -> Vec<u8> {
+    let mut buf : Vec<u8> = Vec::with_capacity(5 + ups.len() * 64);
+    write_array_len(&mut buf, ups.len());
+    for up in ups.iter() {
+        buf.push(0x80 | (KEYS.len() + if up.flags == 0 { 0 } else { 1 }) as u8);
+        write_str(&mut buf, "ts");
+        write_uint(&mut buf, up.ts);
+        write_str(&mut buf, "seq");
+        write_uint(&mut buf, u64::from(up.seq));
+        write_str(&mut buf, "is_trade");
+        write_bool(&mut buf, up.is_trade);
+        write_str(&mut buf, "is_bid");
+        write_bool(&mut buf, up.is_bid);
+        write_str(&mut buf, "price");
+        write_f32(&mut buf, up.price);
+        write_str(&mut buf, "size");
+        write_f32(&mut buf, up.size);
+        if up.flags != 0 {
+            write_str(&mut buf, "flags");
+            write_uint(&mut buf, u64::from(up.flags));
+        }
+    }
+    buf
+}
+
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Result<&'a [u8], String> {
+    if buf.len() < n {
+        return Err("unexpected end of msgpack".to_owned());
+    }
+    let (head, tail) = buf.split_at(n);
+    *buf = tail;
+    Ok(head)
+}
+
+fn read_len(buf: &mut &[u8], width: usize) -> Result<usize, String> {
+    let bytes = take(buf, width)?;
+    Ok(match width {
+        1 => bytes[0] as usize,
+        2 => BigEndian::read_u16(bytes) as usize,
+        _ => BigEndian::read_u32(bytes) as usize,
+    })
+}
+
+fn read_str(buf: &mut &[u8], len: usize) -> Result<MsgpackValue, String> {
+    let bytes = take(buf, len)?;
+    String::from_utf8(bytes.to_vec())
+        .map(MsgpackValue::Str)
+        .map_err(|_| "msgpack string is not utf-8".to_owned())
+}
+
+fn read_array(buf: &mut &[u8], len: usize) -> Result<MsgpackValue, String> {
+    let mut items = Vec::with_capacity(len.min(buf.len()));
+    for _ in 0..len {
+        items.push(read_value(buf)?);
+    }
+    Ok(MsgpackValue::Array(items))
+}
+
+fn read_map(buf: &mut &[u8], len: usize) -> Result<MsgpackValue, String> {
+    let mut entries = Vec::with_capacity(len.min(buf.len()));
+    for _ in 0..len {
+        let key = read_value(buf)?;
+        let value = read_value(buf)?;
+        entries.push((key, value));
+    }
+    Ok(MsgpackValue::Map(entries))
+}
+
+/// read one value off the front of `buf`
+pub fn read_value(buf: &mut &[u8]) -> Result<MsgpackValue, String> {
+    use self::MsgpackValue::*;
+    let marker = take(buf, 1)?[0];
+    match marker {
+        0x00..=0x7f => Ok(UInt(u64::from(marker))),
+        0x80..=0x8f => read_map(buf, (marker & 0x0f) as usize),
+        0x90..=0x9f => read_array(buf, (marker & 0x0f) as usize),
+        0xa0..=0xbf => read_str(buf, (marker & 0x1f) as usize),
+        0xc0 => Ok(Nil),
+        0xc2 => Ok(Bool(false)),
+        0xc3 => Ok(Bool(true)),
+        0xc4 | 0xc5 | 0xc6 => {
+            let len = read_len(buf, 1 << (marker - 0xc4))?;
+            Ok(Bin(take(buf, len)?.to_vec()))
+        }
+        0xca => Ok(Float(f64::from(BigEndian::read_f32(take(buf, 4)?)))),
+        0xcb => Ok(Float(BigEndian::read_f64(take(buf, 8)?))),
+        0xcc => Ok(UInt(u64::from(take(buf, 1)?[0]))),
+        0xcd => Ok(UInt(u64::from(BigEndian::read_u16(take(buf, 2)?)))),
+        0xce => Ok(UInt(u64::from(BigEndian::read_u32(take(buf, 4)?)))),
+        0xcf => Ok(UInt(BigEndian::read_u64(take(buf, 8)?))),
+        0xd0 => Ok(Int(i64::from(take(buf, 1)?[0] as i8))),
+        0xd1 => Ok(Int(i64::from(BigEndian::read_i16(take(buf, 2)?)))),
+        0xd2 => Ok(Int(i64::from(BigEndian::read_i32(take(buf, 4)?)))),
+        0xd3 => Ok(Int(BigEndian::read_i64(take(buf, 8)?))),
+        0xd9 | 0xda | 0xdb => {
+            let len = read_len(buf, 1 << (marker - 0xd9))?;
+            read_str(buf, len)
+        }
+        0xdc | 0xdd => {
+            let len = read_len(buf, 2 << (marker - 0xdc))?;
+            read_array(buf, len)
+        }
+        0xde | 0xdf => {
+            let len = read_len(buf, 2 << (marker - 0xde))?;
+            read_map(buf, len)
+        }
+        0xe0..=0xff => Ok(Int(i64::from(marker as i8))),
+        _ => Err(format!("unsupported msgpack type 0x{:02x}", marker))
+    }
+}
+
+fn as_uint(value: &MsgpackValue, key: &str) -> Result<u64, String> {
+    match *value {
+        MsgpackValue::UInt(n) => Ok(n),
+        MsgpackValue::Int(n) if n >= 0 => Ok(n as u64),
+        _ => Err(format!("`{}` is not an unsigned integer", key))
+    }
+}
+
+fn as_bool(value: &MsgpackValue, key: &str) -> Result<bool, String> {
+    match *value {
+        MsgpackValue::Bool(b) => Ok(b),
+        MsgpackValue::UInt(n) if n <= 1 => Ok(n == 1),
+        _ => Err(format!("`{}` is not a boolean", key))
+    }
+}
+
+fn as_float(value: &MsgpackValue, key: &str) -> Result<f64, String> {
+    match *value {
+        MsgpackValue::Float(f) => Ok(f),
+        MsgpackValue::UInt(n) => Ok(n as f64),
+        MsgpackValue::Int(n) => Ok(n as f64),
+        _ => Err(format!("`{}` is not a number", key))
+    }
+}
+
+/// the 6 fields of KEYS, then the flags if any
+fn to_update(fields: &[&MsgpackValue]) -> Result<Update, String> {
+    let ts = match *fields[0] {
+        MsgpackValue::Float(secs) if secs >= 0. => (secs * 1000.).round() as u64,
+        ref ts => as_uint(ts, "ts")?
+    };
+    let seq = as_uint(fields[1], "seq")?;
+    if seq > u64::from(u32::max_value()) {
+        return Err("`seq` is out of range".to_owned());
+    }
+    let flags = match fields.get(KEYS.len()) {
+        Some(flags) => as_uint(flags, "flags")?,
+        None => 0
+    };
+    if flags > u64::from(MAX_USER_FLAGS) {
+        return Err("`flags` is out of range".to_owned());
+    }
+    Ok(Update {
+        ts,
+        seq: seq as u32,
+        is_trade: as_bool(fields[2], "is_trade")?,
+        is_bid: as_bool(fields[3], "is_bid")?,
+        price: as_float(fields[4], "price")? as f32,
+        size: as_float(fields[5], "size")? as f32,
+        flags: flags as u8,
+    })
+}
+
+/// decode one row, a map or an array of the six fields and the flags
+pub fn decode_msgpack_row(buf: &[u8]) -> Result<Update, String> {
+    let mut rest = buf;
+    let value = read_value(&mut rest)?;
+    if !rest.is_empty() {
+        return Err("trailing bytes after msgpack row".to_owned());
+    }
+    match value {
+        MsgpackValue::Array(ref items) if items.len() == KEYS.len() || items.len() == KEYS.len() + 1 => {
+            let fields : Vec<&MsgpackValue> = items.iter().collect();
+            to_update(&fields)
+        }
+        MsgpackValue::Map(ref entries) => {
+            let mut fields = Vec::with_capacity(KEYS.len());
+            for key in KEYS.iter() {
+                let value = entries.iter()
+                    .find(|&&(ref k, _)| *k == MsgpackValue::Str((*key).to_owned()))
+                    .map(|&(_, ref v)| v)
+                    .ok_or_else(|| format!("missing `{}`", key))?;
+                fields.push(value);
+            }
+            let flags = MsgpackValue::Str("flags".to_owned());
+            if let Some(&(_, ref value)) = entries.iter().find(|&&(ref k, _)| *k == flags) {
+                fields.push(value);
+            }
+            to_update(&fields)
+        }
+        _ => Err("a row is a map or an array of 6 or 7 fields".to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ups() -> Vec<Update> {
+        vec![
+            Update { ts: 1_505_177_459_658, seq: 139_010, is_trade: true, is_bid: false, price: 0.0703629, size: 7.65064, flags: 0 },
+            Update { ts: 1_505_177_459_685, seq: 5, is_trade: false, is_bid: true, price: 0.0703620, size: 0., flags: 3 },
+        ]
+    }
+
+    #[test]
+    fn should_encode_msgpack() {
+        let ups = ups();
+        let buf = encode_msgpack(&ups);
+        assert_eq!(buf[0], 0x92);
+        let mut rest = &buf[..];
+        match read_value(&mut rest).unwrap() {
+            MsgpackValue::Array(rows) => {
+                assert_eq!(rows.len(), 2);
+                match rows[1] {
+                    MsgpackValue::Map(ref entries) => {
+                        assert_eq!(entries[0], (MsgpackValue::Str("ts".to_owned()), MsgpackValue::UInt(1_505_177_459_685)));
+                        assert_eq!(entries[3], (MsgpackValue::Str("is_bid".to_owned()), MsgpackValue::Bool(true)));
+                    }
+                    ref other => panic!("not a map: {:?}", other)
+                }
+            }
+            other => panic!("not an array: {:?}", other)
+        }
+        assert!(rest.is_empty());
+        assert_eq!(encode_msgpack(&[]), vec![0x90]);
+    }
+
+    #[test]
+    fn should_decode_msgpack_row() {
+        let ups = ups();
+        // a row of the encoded array
+        let buf = encode_msgpack(&ups[..1]);
+        assert_eq!(decode_msgpack_row(&buf[1..]), Ok(ups[0].clone()));
+
+        // [1505177459.685, 5, false, true, 0.070362, 0, 3] with a float64 ts
+        let mut row = vec![0x97, 0xcb];
+        let _ = row.write_f64::<BigEndian>(1_505_177_459.685);
+        row.extend_from_slice(&[0x05, 0xc2, 0xc3, 0xca]);
+        let _ = row.write_f32::<BigEndian>(0.0703620);
+        row.extend_from_slice(&[0x00, 0x03]);
+        assert_eq!(decode_msgpack_row(&row), Ok(ups[1].clone()));
+        assert_eq!(decode_msgpack_row(&encode_msgpack(&ups[1..])[1..]), Ok(ups[1].clone()));
+
+        assert!(decode_msgpack_row(&row[..row.len() - 1]).is_err());
+        assert!(decode_msgpack_row(&[0x93, 0x01, 0x02, 0x03]).is_err());
+        let last = row.len() - 1;
+        row[last] = 0x40;
+        assert_eq!(decode_msgpack_row(&row), Err("`flags` is out of range".to_owned()));
+        assert!(decode_msgpack_row(&[0x81, 0xa2, b't', b's', 0x01]).is_err());
+    }
+}
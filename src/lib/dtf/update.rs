@@ -9,40 +9,90 @@ pub struct Update {
 	pub is_bid: bool,
 	pub price: f32,
 	pub size: f32,
+	/// user defined bits from 0 to 63, e.g. synthetic or snapshot rows, named per store with `FLAGS`
+	pub flags: u8,
 }
 
 
 impl Update {
 
+	/// A row without user defined flags. Prefer it to a struct literal, which
+	/// stops compiling whenever a field is added, as it did with `flags`.
+	pub fn new(ts: u64, seq: u32, is_trade: bool, is_bid: bool, price: f32, size: f32) -> Update {
+		Update { ts, seq, is_trade, is_bid, price, size, flags: 0 }
+	}
+
+	/// the row with the user defined `flags`, only the 6 lowest bits are kept
+	pub fn with_flags(mut self, flags: u8) -> Update {
+		self.flags = flags & MAX_USER_FLAGS;
+		self
+	}
+
 	pub fn serialize(&self, ref_ts : u64, ref_seq : u32) -> Vec<u8> {
+		let mut buf : Vec<u8> = Vec::with_capacity(12);
+		self.serialize_into(&mut buf, ref_ts, ref_seq);
+		buf
+	}
+
+	/// append the row, relative to the batch reference, to `buf`
+	pub fn serialize_into(&self, buf: &mut Vec<u8>, ref_ts : u64, ref_seq : u32) {
 		if self.seq < ref_seq {
 			println!("{:?}", ref_seq);
 			println!("{:?}", self);
 			panic!("TODO: ???");
 			/* TODO */
 		}
-		let mut buf : Vec<u8> = Vec::new();
 		let _ = buf.write_u16::<BigEndian>((self.ts- ref_ts) as u16);
 		let _ = buf.write_u8((self.seq - ref_seq) as u8);
 
-		let mut flags = Flags::FLAG_EMPTY;
-		if self.is_bid { flags |= Flags::FLAG_IS_BID; }
-		if self.is_trade { flags |= Flags::FLAG_IS_TRADE; }
-		let _ = buf.write_u8(flags.bits());
+		let _ = buf.write_u8(self.flags_byte());
 
 		let _ = buf.write_f32::<BigEndian>(self.price);
 		let _ = buf.write_f32::<BigEndian>(self.size);
-		buf
+	}
+
+	/// is_bid and is_trade in the low bits, the user defined flags above them
+	pub fn flags_byte(&self) -> u8 {
+		let mut flags = Flags::from_bits_truncate(self.flags << USER_FLAGS_SHIFT);
+		if self.is_bid { flags |= Flags::FLAG_IS_BID; }
+		if self.is_trade { flags |= Flags::FLAG_IS_TRADE; }
+		flags.bits()
+	}
+
+	/// the row with the bits of `flags_byte`
+	pub fn with_flags_byte(mut self, flags: u8) -> Update {
+		self.is_trade = flags & Flags::FLAG_IS_TRADE.bits() != 0;
+		self.is_bid = flags & Flags::FLAG_IS_BID.bits() != 0;
+		self.flags = flags >> USER_FLAGS_SHIFT;
+		self
+	}
+
+	/// `,"flags":5` or nothing, so rows without flags are written as before
+	fn flags_json(&self) -> String {
+		if self.flags == 0 { String::new() } else { format!(r#","flags":{}"#, self.flags) }
 	}
 
 	pub fn to_json(&self) -> String {
-		format!(r#"{{"ts":{},"seq":{},"is_trade":{},"is_bid":{},"price":{},"size":{}}}"#,
-				  (self.ts as f64) / 1000_f64, self.seq, self.is_trade, self.is_bid, self.price, self.size)
+		format!(r#"{{"ts":{},"seq":{},"is_trade":{},"is_bid":{},"price":{},"size":{}{}}}"#,
+				  (self.ts as f64) / 1000_f64, self.seq, self.is_trade, self.is_bid, self.price, self.size, self.flags_json())
 	}
 
+	/// `to_json` with price and size rounded to `decimals` places.
+	/// Floats are never written in exponent notation, `1e-7` is `0.0000001`.
+	pub fn to_json_with(&self, decimals: Option<usize>) -> String {
+		match decimals {
+			Some(decimals) => format!(r#"{{"ts":{},"seq":{},"is_trade":{},"is_bid":{},"price":{:.*},"size":{:.*}{}}}"#,
+				  (self.ts as f64) / 1000_f64, self.seq, self.is_trade, self.is_bid, decimals, self.price, decimals, self.size,
+				  self.flags_json()),
+			None => self.to_json()
+		}
+	}
+
+	/// the fields of an ADD row, flags only if any is set
 	pub fn to_csv(&self) -> String {
-		format!(r#"{},{},{},{},{},{}"#,
-				  (self.ts as f64) / 1000_f64, self.seq, self.is_trade, self.is_bid, self.price, self.size)
+		let csv = format!(r#"{},{},{},{},{},{}"#,
+				  (self.ts as f64) / 1000_f64, self.seq, self.is_trade, self.is_bid, self.price, self.size);
+		if self.flags == 0 { csv } else { format!("{},{}", csv, self.flags) }
 	}
 }
 
@@ -69,11 +119,18 @@ impl Ord for Update {
 impl Eq for Update {}
 
 
+/// the user defined flags are kept above is_bid and is_trade
+pub const USER_FLAGS_SHIFT : u8 = 2;
+
+/// highest value of `Update::flags`, 6 bits
+pub const MAX_USER_FLAGS : u8 = 0b0011_1111;
+
 bitflags! {
 	pub struct Flags: u8 {
 		const FLAG_EMPTY   = 0b0000_0000;
 		const FLAG_IS_BID   = 0b0000_0001;
 		const FLAG_IS_TRADE = 0b0000_0010;
+		const FLAG_USER     = 0b1111_1100;
 	}
 }
 
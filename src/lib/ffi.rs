@@ -0,0 +1,464 @@
+/// C ABI of the dtf reader and writer
+///
+/// Lets a DuckDB or SQLite extension or a C++/Java trading stack read and
+/// write dtf files directly, see `include/dtf.h`. `dtf_encode` writes rows,
+/// `dtf_decode_range` reads the rows of a time range at once and a scan hands
+/// out the rows of a file in chunks of at most `DTF_SCAN_CHUNK` rows, one
+/// array per column:
+///
+/// ```c
+/// DtfScan *scan = dtf_scan_open("db/bnc_btc_eth.dtf", 0, UINT64_MAX);
+/// DtfChunk chunk;
+/// while (dtf_scan_next(scan, &chunk) > 0) {
+///     /* chunk.ts[0 .. chunk.len], chunk.price[0 .. chunk.len], ... */
+/// }
+/// dtf_scan_close(scan);
+/// ```
+///
+/// The arrays of a chunk are owned by the scan and valid until the next
+/// `dtf_scan_next` or `dtf_scan_close`.
+
+use std::ffi::CStr;
+use std::fs;
+use std::io;
+use std::os::raw::c_char;
+use std::panic;
+use std::ptr;
+use std::slice;
+use dtf::{self, Batches, Update, MAX_USER_FLAGS, SYMBOL_LEN};
+
+/// rows per chunk, the vector size of DuckDB
+pub const DTF_SCAN_CHUNK : usize = 2048;
+
+/// A chunk of rows, one array of `len` values per column
+#[repr(C)]
+pub struct DtfChunk {
+    pub len: usize,
+    pub ts: *const u64,
+    pub seq: *const u32,
+    pub is_trade: *const u8,
+    pub is_bid: *const u8,
+    pub price: *const f32,
+    pub size: *const f32,
+    /// the user defined flags, see `Update::flags`
+    pub flags: *const u8,
+}
+
+/// A row, 24 bytes like a record of `dtf::flat` but with is_trade, is_bid
+/// and the user defined flags in a byte each: ts at offset 0, seq at 8,
+/// is_trade at 12, is_bid at 13, flags at 14, price at 16 and size at 20,
+/// see `DtfRow` in `include/dtf.h`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DtfRow {
+    pub ts: u64,
+    pub seq: u32,
+    pub is_trade: u8,
+    pub is_bid: u8,
+    pub flags: u8,
+    pub price: f32,
+    pub size: f32,
+}
+
+impl<'a> From<&'a Update> for DtfRow {
+    fn from(up: &Update) -> DtfRow {
+        DtfRow {
+            ts: up.ts,
+            seq: up.seq,
+            is_trade: up.is_trade as u8,
+            is_bid: up.is_bid as u8,
+            flags: up.flags,
+            price: up.price,
+            size: up.size,
+        }
+    }
+}
+
+impl<'a> From<&'a DtfRow> for Update {
+    fn from(row: &DtfRow) -> Update {
+        Update::new(row.ts, row.seq, row.is_trade != 0, row.is_bid != 0, row.price, row.size)
+            .with_flags(row.flags)
+    }
+}
+
+unsafe fn to_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+/// whether rows can be written as they are: in ts order, with is_trade and
+/// is_bid 0 or 1 and flags up to 63
+fn is_valid(rows: &[DtfRow]) -> bool {
+    rows.iter().all(|row| row.is_trade <= 1 && row.is_bid <= 1 && row.flags <= MAX_USER_FLAGS)
+        && rows.windows(2).all(|pair| pair[0].ts <= pair[1].ts)
+}
+
+/// move the dtf file `from` and its batch index to `to`
+fn rename(from: &str, to: &str) -> Result<(), io::Error> {
+    dtf::remove_batch_index(to);
+    fs::rename(from, to)?;
+    let index = dtf::batch_index_fname(from);
+    if fs::metadata(&index).is_ok() {
+        fs::rename(&index, dtf::batch_index_fname(to))?;
+    }
+    Ok(())
+}
+
+/// Write `len` rows, in ts order, to a new dtf file at `path`.
+/// The rows are checked before anything is written, and the file is written
+/// next to `path` then renamed, so `path` is never left half written.
+/// Returns the number of rows written, -1 on a bad argument or if the file cannot be written.
+#[no_mangle]
+pub unsafe extern "C" fn dtf_encode(path: *const c_char, symbol: *const c_char, rows: *const DtfRow, len: usize) -> i64 {
+    let (path, symbol) = match (to_str(path), to_str(symbol)) {
+        (Some(path), Some(symbol)) if symbol.len() <= SYMBOL_LEN => (path, symbol),
+        _ => return -1
+    };
+    if rows.is_null() && len > 0 {
+        return -1;
+    }
+    let rows = if len == 0 { &[][..] } else { slice::from_raw_parts(rows, len) };
+    if !is_valid(rows) {
+        return -1;
+    }
+    let ups : Vec<Update> = rows.iter().map(Update::from).collect();
+
+    let tmp = format!("{}.tmp", path);
+    // the writer panics on io errors, which must not unwind into C
+    let written = panic::catch_unwind(|| dtf::encode(&tmp, symbol, &ups)).is_ok()
+        && rename(&tmp, path).is_ok();
+    if !written {
+        let _ = fs::remove_file(&tmp);
+        dtf::remove_batch_index(&tmp);
+        return -1;
+    }
+    len as i64
+}
+
+/// Read the rows of the dtf file at `path` with min_ts <= ts <= max_ts (in ms) into
+/// `*rows`, to be freed with `dtf_rows_free`. Returns the number of rows, -1 if the
+/// file cannot be read, is not a dtf file or is corrupt.
+#[no_mangle]
+pub unsafe extern "C" fn dtf_decode_range(path: *const c_char, min_ts: u64, max_ts: u64, rows: *mut *mut DtfRow) -> i64 {
+    let path = match to_str(path) {
+        Some(path) if !rows.is_null() => path,
+        _ => return -1
+    };
+    let batches = match dtf::batches(path) {
+        Ok(batches) => batches,
+        Err(_) => return -1
+    };
+    let mut decoded = Vec::new();
+    for batch in batches {
+        match batch {
+            Ok(batch) => decoded.extend(batch.iter()
+                .filter(|up| up.ts >= min_ts && up.ts <= max_ts)
+                .map(DtfRow::from)),
+            Err(_) => return -1
+        }
+    }
+    let decoded = decoded.into_boxed_slice();
+    let len = decoded.len();
+    *rows = Box::into_raw(decoded) as *mut DtfRow;
+    len as i64
+}
+
+/// Free the `len` rows returned by `dtf_decode_range`
+#[no_mangle]
+pub unsafe extern "C" fn dtf_rows_free(rows: *mut DtfRow, len: usize) {
+    if !rows.is_null() {
+        drop(Box::from_raw(slice::from_raw_parts_mut(rows, len) as *mut [DtfRow]));
+    }
+}
+
+/// An open scan, opaque to C
+pub struct DtfScan {
+    batches: Batches,
+    min_ts: u64,
+    max_ts: u64,
+    /// rows of the current batch not handed out yet
+    pending: Vec<Update>,
+    pos: usize,
+    ts: Vec<u64>,
+    seq: Vec<u32>,
+    is_trade: Vec<u8>,
+    is_bid: Vec<u8>,
+    price: Vec<f32>,
+    size: Vec<f32>,
+    flags: Vec<u8>,
+}
+
+impl DtfScan {
+    fn clear(&mut self) {
+        self.ts.clear();
+        self.seq.clear();
+        self.is_trade.clear();
+        self.is_bid.clear();
+        self.price.clear();
+        self.size.clear();
+        self.flags.clear();
+    }
+
+    fn push(&mut self, up: &Update) {
+        self.ts.push(up.ts);
+        self.seq.push(up.seq);
+        self.is_trade.push(up.is_trade as u8);
+        self.is_bid.push(up.is_bid as u8);
+        self.price.push(up.price);
+        self.size.push(up.size);
+        self.flags.push(up.flags);
+    }
+
+    /// fill the columns with the next chunk, returns its length
+    fn fill(&mut self) -> Result<usize, io::Error> {
+        self.clear();
+        while self.ts.len() < DTF_SCAN_CHUNK {
+            if self.pos == self.pending.len() {
+                match self.batches.next() {
+                    Some(batch) => {
+                        self.pending = batch?;
+                        self.pos = 0;
+                    }
+                    None => break
+                }
+            }
+            let up = self.pending[self.pos].clone();
+            self.pos += 1;
+            if up.ts >= self.min_ts && up.ts <= self.max_ts {
+                self.push(&up);
+            }
+        }
+        Ok(self.ts.len())
+    }
+}
+
+/// Start a scan of the rows of the dtf file at `path` with min_ts <= ts <= max_ts (in ms).
+/// Returns null if the file cannot be opened or is not a dtf file.
+#[no_mangle]
+pub unsafe extern "C" fn dtf_scan_open(path: *const c_char, min_ts: u64, max_ts: u64) -> *mut DtfScan {
+    let path = match to_str(path) {
+        Some(path) => path,
+        None => return ptr::null_mut()
+    };
+    let batches = match dtf::batches(path) {
+        Ok(batches) => batches,
+        Err(_) => return ptr::null_mut()
+    };
+    Box::into_raw(Box::new(DtfScan {
+        batches,
+        min_ts,
+        max_ts,
+        pending: Vec::new(),
+        pos: 0,
+        ts: Vec::with_capacity(DTF_SCAN_CHUNK),
+        seq: Vec::with_capacity(DTF_SCAN_CHUNK),
+        is_trade: Vec::with_capacity(DTF_SCAN_CHUNK),
+        is_bid: Vec::with_capacity(DTF_SCAN_CHUNK),
+        price: Vec::with_capacity(DTF_SCAN_CHUNK),
+        size: Vec::with_capacity(DTF_SCAN_CHUNK),
+        flags: Vec::with_capacity(DTF_SCAN_CHUNK),
+    }))
+}
+
+/// Fill `chunk` with the next rows of the scan.
+/// Returns the number of rows, 0 once the scan is done, -1 on a null argument
+/// or a corrupt file.
+#[no_mangle]
+pub unsafe extern "C" fn dtf_scan_next(scan: *mut DtfScan, chunk: *mut DtfChunk) -> i64 {
+    if scan.is_null() || chunk.is_null() {
+        return -1;
+    }
+    let scan = &mut *scan;
+    let len = match scan.fill() {
+        Ok(len) => len,
+        Err(_) => return -1
+    };
+    *chunk = DtfChunk {
+        len,
+        ts: scan.ts.as_ptr(),
+        seq: scan.seq.as_ptr(),
+        is_trade: scan.is_trade.as_ptr(),
+        is_bid: scan.is_bid.as_ptr(),
+        price: scan.price.as_ptr(),
+        size: scan.size.as_ptr(),
+        flags: scan.flags.as_ptr(),
+    };
+    len as i64
+}
+
+/// End a scan, freeing its chunks
+#[no_mangle]
+pub unsafe extern "C" fn dtf_scan_close(scan: *mut DtfScan) {
+    if !scan.is_null() {
+        drop(Box::from_raw(scan));
+    }
+}
+
+/// Row count in the header of the dtf file at `path`, for planning a scan.
+/// Returns -1 if the file cannot be opened or is not a dtf file.
+#[no_mangle]
+pub unsafe extern "C" fn dtf_row_count(path: *const c_char) -> i64 {
+    match to_str(path) {
+        Some(path) if dtf::verify(path).is_ok() => dtf::get_size(path) as i64,
+        _ => -1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::mem;
+    static FNAME : &str = "test-data/bt_btcnav.dtf";
+
+    #[test]
+    fn should_scan_in_chunks() {
+        let ups = dtf::decode(FNAME, None);
+        let path = CString::new(FNAME).unwrap();
+        unsafe {
+            assert_eq!(dtf_row_count(path.as_ptr()), ups.len() as i64);
+
+            let scan = dtf_scan_open(path.as_ptr(), 0, u64::max_value());
+            assert!(!scan.is_null());
+            let mut chunk = DtfChunk { len: 0, ts: ptr::null(), seq: ptr::null(), is_trade: ptr::null(),
+                is_bid: ptr::null(), price: ptr::null(), size: ptr::null(), flags: ptr::null() };
+            let mut scanned = Vec::new();
+            loop {
+                let n = dtf_scan_next(scan, &mut chunk);
+                assert!(n >= 0 && n as usize <= DTF_SCAN_CHUNK);
+                if n == 0 { break; }
+                let ts = slice::from_raw_parts(chunk.ts, chunk.len);
+                let price = slice::from_raw_parts(chunk.price, chunk.len);
+                scanned.extend(ts.iter().cloned().zip(price.iter().cloned()));
+            }
+            dtf_scan_close(scan);
+            assert_eq!(scanned, ups.iter().map(|up| (up.ts, up.price)).collect::<Vec<_>>());
+
+            let scan = dtf_scan_open(path.as_ptr(), ups[10].ts, ups[10].ts);
+            assert!(dtf_scan_next(scan, &mut chunk) > 0);
+            assert_eq!(*chunk.ts, ups[10].ts);
+            dtf_scan_close(scan);
+
+            let missing = CString::new("test-data/no_such.dtf").unwrap();
+            assert!(dtf_scan_open(missing.as_ptr(), 0, 0).is_null());
+            assert_eq!(dtf_row_count(missing.as_ptr()), -1);
+        }
+    }
+
+    #[test]
+    fn should_encode_and_decode_range() {
+        // the rows of the sample file are not all in ts order
+        let mut ups = dtf::decode(FNAME, None)[..100].to_vec();
+        ups.sort_by_key(|up| up.ts);
+        let rows : Vec<DtfRow> = ups.iter().map(DtfRow::from).collect();
+        let fname = "test_ffi_encode.dtf";
+        let path = CString::new(fname).unwrap();
+        let symbol = CString::new("NEO_BTC").unwrap();
+        unsafe {
+            assert_eq!(dtf_encode(path.as_ptr(), symbol.as_ptr(), rows.as_ptr(), rows.len()), 100);
+            assert_eq!(dtf::decode(fname, None), ups);
+
+            let mut decoded : *mut DtfRow = ptr::null_mut();
+            let n = dtf_decode_range(path.as_ptr(), ups[10].ts, ups[20].ts, &mut decoded);
+            let expected : Vec<DtfRow> = rows.iter().filter(|row| row.ts >= ups[10].ts && row.ts <= ups[20].ts).cloned().collect();
+            assert_eq!(slice::from_raw_parts(decoded, n as usize), &expected[..]);
+            dtf_rows_free(decoded, n as usize);
+
+            let too_long = CString::new("A_SYMBOL_LONGER_THAN_TWENTY_BYTES").unwrap();
+            assert_eq!(dtf_encode(path.as_ptr(), too_long.as_ptr(), rows.as_ptr(), rows.len()), -1);
+
+            // rows out of order are refused, the file written before is left as it was
+            let mut unordered = rows.clone();
+            unordered.swap(0, 50);
+            assert_eq!(dtf_encode(path.as_ptr(), symbol.as_ptr(), unordered.as_ptr(), unordered.len()), -1);
+            let mut bad_flag = rows.clone();
+            bad_flag[3].is_bid = 2;
+            assert_eq!(dtf_encode(path.as_ptr(), symbol.as_ptr(), bad_flag.as_ptr(), bad_flag.len()), -1);
+            let mut bad_flags = rows.clone();
+            bad_flags[3].flags = 64;
+            assert_eq!(dtf_encode(path.as_ptr(), symbol.as_ptr(), bad_flags.as_ptr(), bad_flags.len()), -1);
+            assert_eq!(dtf::decode(fname, None), ups);
+            assert!(fs::metadata(format!("{}.tmp", fname)).is_err());
+
+            let missing = CString::new("test-data/no_such.dtf").unwrap();
+            assert_eq!(dtf_decode_range(missing.as_ptr(), 0, 0, &mut decoded), -1);
+        }
+        let _ = fs::remove_file(fname);
+        dtf::remove_batch_index(fname);
+        dtf::remove_batch_index(fname);
+    }
+
+    #[test]
+    fn should_keep_the_flags_of_rows() {
+        let mut ups = dtf::decode(FNAME, None)[..100].to_vec();
+        ups.sort_by_key(|up| up.ts);
+        let ups : Vec<Update> = ups.into_iter().enumerate().map(|(i, up)| up.with_flags((i % 64) as u8)).collect();
+        let rows : Vec<DtfRow> = ups.iter().map(DtfRow::from).collect();
+        let fname = "test_ffi_flags.dtf";
+        let path = CString::new(fname).unwrap();
+        let symbol = CString::new("NEO_BTC").unwrap();
+        unsafe {
+            assert_eq!(dtf_encode(path.as_ptr(), symbol.as_ptr(), rows.as_ptr(), rows.len()), 100);
+            assert_eq!(dtf::decode(fname, None), ups);
+
+            let mut decoded : *mut DtfRow = ptr::null_mut();
+            let n = dtf_decode_range(path.as_ptr(), 0, u64::max_value(), &mut decoded);
+            assert_eq!(slice::from_raw_parts(decoded, n as usize), &rows[..]);
+            dtf_rows_free(decoded, n as usize);
+
+            let scan = dtf_scan_open(path.as_ptr(), 0, u64::max_value());
+            let mut chunk = DtfChunk { len: 0, ts: ptr::null(), seq: ptr::null(), is_trade: ptr::null(),
+                is_bid: ptr::null(), price: ptr::null(), size: ptr::null(), flags: ptr::null() };
+            assert_eq!(dtf_scan_next(scan, &mut chunk), 100);
+            assert_eq!(slice::from_raw_parts(chunk.flags, chunk.len), &rows.iter().map(|row| row.flags).collect::<Vec<_>>()[..]);
+            dtf_scan_close(scan);
+        }
+        let _ = fs::remove_file(fname);
+        dtf::remove_batch_index(fname);
+        dtf::remove_batch_index(fname);
+    }
+
+    #[test]
+    fn should_lay_out_rows_like_dtf_h() {
+        let row = DtfRow { ts: 0, seq: 0, is_trade: 0, is_bid: 0, flags: 0, price: 0., size: 0. };
+        let base = &row as *const DtfRow as usize;
+        let offset = |field: usize| field - base;
+        assert_eq!(mem::size_of::<DtfRow>(), 24);
+        assert_eq!(offset(&row.seq as *const u32 as usize), 8);
+        assert_eq!(offset(&row.is_trade as *const u8 as usize), 12);
+        assert_eq!(offset(&row.is_bid as *const u8 as usize), 13);
+        assert_eq!(offset(&row.flags as *const u8 as usize), 14);
+        assert_eq!(offset(&row.price as *const f32 as usize), 16);
+        assert_eq!(offset(&row.size as *const f32 as usize), 20);
+    }
+
+    #[test]
+    fn should_fail_a_scan_of_a_truncated_file() {
+        let fname = "test_ffi_truncated.dtf";
+        let ups = dtf::decode(FNAME, None);
+        dtf::encode(fname, "NEO_BTC", &ups);
+        let len = fs::metadata(fname).unwrap().len();
+        fs::OpenOptions::new().write(true).open(fname).unwrap().set_len(len - 5).unwrap();
+        let path = CString::new(fname).unwrap();
+        unsafe {
+            let mut decoded : *mut DtfRow = ptr::null_mut();
+            assert_eq!(dtf_decode_range(path.as_ptr(), 0, u64::max_value(), &mut decoded), -1);
+
+            let scan = dtf_scan_open(path.as_ptr(), 0, u64::max_value());
+            let mut chunk = DtfChunk { len: 0, ts: ptr::null(), seq: ptr::null(), is_trade: ptr::null(),
+                is_bid: ptr::null(), price: ptr::null(), size: ptr::null(), flags: ptr::null() };
+            let mut n;
+            loop {
+                n = dtf_scan_next(scan, &mut chunk);
+                if n <= 0 { break; }
+            }
+            assert_eq!(n, -1);
+            dtf_scan_close(scan);
+        }
+        let _ = fs::remove_file(fname);
+        dtf::remove_batch_index(fname);
+        dtf::remove_batch_index(fname);
+    }
+}
@@ -13,6 +13,9 @@ pub mod postprocessing;
 pub mod storage;
 pub mod utils;
 pub mod dtf;
+pub mod client;
+pub mod ffi;
+pub mod wasm;
 
 pub use update::*;
 pub use storage::*;
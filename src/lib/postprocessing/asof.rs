@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::iter::Peekable;
+use dtf::{self, Update};
+
+/// Iterator of `asof_join`
+pub struct AsofJoin<L: Iterator<Item=Update>, R: Iterator<Item=Update>> {
+    left: L,
+    right: Peekable<R>,
+    /// latest right row so far
+    last: Option<Update>,
+    tolerance: Option<u64>,
+}
+
+impl<L: Iterator<Item=Update>, R: Iterator<Item=Update>> Iterator for AsofJoin<L, R> {
+    type Item = (Update, Option<Update>);
+
+    fn next(&mut self) -> Option<(Update, Option<Update>)> {
+        let left = self.left.next()?;
+        while self.right.peek().map_or(false, |right| right.ts <= left.ts) {
+            self.last = self.right.next();
+        }
+        let matched = match (self.last.as_ref(), self.tolerance) {
+            (Some(right), Some(tolerance)) if left.ts.saturating_sub(right.ts) > tolerance => None,
+            (last, _) => last.cloned(),
+        };
+        Some((left, matched))
+    }
+}
+
+/// As-of join: every row of `left` with the latest row of `right` at or before
+/// its timestamp, or None if there is none or it is more than `tolerance` ms older.
+///
+/// Both sides are expected in time order, e.g. decoded dtf files.
+pub fn asof_join<L, R>(left: L, right: R, tolerance: Option<u64>) -> AsofJoin<L::IntoIter, R::IntoIter>
+        where L: IntoIterator<Item=Update>, R: IntoIterator<Item=Update> {
+    AsofJoin {
+        left: left.into_iter(),
+        right: right.into_iter().peekable(),
+        last: None,
+        tolerance,
+    }
+}
+
+/// `asof_join` of two dtf files, whose rows are put in time order first
+pub fn asof_join_files(left: &str, right: &str, tolerance: Option<u64>) -> Vec<(Update, Option<Update>)> {
+    let mut left = dtf::decode(left, None);
+    let mut right = dtf::decode(right, None);
+    left.sort();
+    right.sort();
+    asof_join(left, right, tolerance).collect()
+}
+
+/// price (as bits, which sort like positive prices) -> size
+type BookSide = BTreeMap<u32, f32>;
+
+/// A trade with the best bid and ask (price, size) prevailing when it happened
+#[derive(Debug, Clone, PartialEq)]
+pub struct TradeQuote {
+    pub trade: Update,
+    pub bid: Option<(f32, f32)>,
+    pub ask: Option<(f32, f32)>,
+}
+
+impl TradeQuote {
+    pub fn to_json(&self) -> String {
+        fn level(side: Option<(f32, f32)>) -> (String, String) {
+            match side {
+                Some((price, size)) => (price.to_string(), size.to_string()),
+                None => ("null".to_owned(), "null".to_owned())
+            }
+        }
+        let (bid, bid_size) = level(self.bid);
+        let (ask, ask_size) = level(self.ask);
+        format!(r#"{{"ts":{},"seq":{},"is_bid":{},"price":{},"size":{},"bid":{},"bid_size":{},"ask":{},"ask_size":{}}}"#,
+            (self.trade.ts as f64) / 1000_f64, self.trade.seq, self.trade.is_bid, self.trade.price, self.trade.size,
+            bid, bid_size, ask, ask_size)
+    }
+}
+
+/// As-of join of the trades in `trades` with the order book built from the
+/// quotes in `quotes`: every trade gets the best bid and ask after all quotes
+/// up to its timestamp. Quotes set the size of a price level, size 0 removes it.
+/// A book whose last quote is more than `tolerance` ms older than the trade is stale,
+/// the trade then gets no bid and ask.
+///
+/// Both slices are expected in time order.
+pub fn join_quotes(trades: &[Update], quotes: &[Update], tolerance: Option<u64>) -> Vec<TradeQuote> {
+    let mut bids = BookSide::new();
+    let mut asks = BookSide::new();
+    let trades : Vec<&Update> = trades.iter().filter(|up| up.is_trade).collect();
+    let quotes : Vec<&Update> = quotes.iter().filter(|up| !up.is_trade).collect();
+    let fresh : Vec<bool> = asof_join(trades.iter().map(|&up| up.clone()), quotes.iter().map(|&up| up.clone()), tolerance)
+        .map(|(_, last_quote)| last_quote.is_some())
+        .collect();
+    let mut quotes = quotes.into_iter().peekable();
+
+    let mut ret = Vec::new();
+    for (trade, fresh) in trades.into_iter().zip(fresh) {
+        while quotes.peek().map_or(false, |quote| quote.ts <= trade.ts) {
+            let quote = quotes.next().unwrap();
+            let side = if quote.is_bid { &mut bids } else { &mut asks };
+            if quote.size == 0. {
+                side.remove(&quote.price.to_bits());
+            } else {
+                side.insert(quote.price.to_bits(), quote.size);
+            }
+        }
+        if !fresh {
+            ret.push(TradeQuote { trade: trade.clone(), bid: None, ask: None });
+            continue;
+        }
+        ret.push(TradeQuote {
+            trade: trade.clone(),
+            bid: bids.iter().next_back().map(|(&price, &size)| (f32::from_bits(price), size)),
+            ask: asks.iter().next().map(|(&price, &size)| (f32::from_bits(price), size)),
+        });
+    }
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    static FNAME : &str = "test-data/bt_btcnav.dtf";
+
+    fn up(ts: u64, is_trade: bool, is_bid: bool, price: f32, size: f32) -> Update {
+        Update { ts, seq: 0, is_trade, is_bid, price, size, flags: 0 }
+    }
+
+    #[test]
+    fn should_join_trades_with_prevailing_quotes() {
+        let quotes = vec![
+            up(1, false, true, 9., 1.),
+            up(1, false, true, 9.5, 2.),
+            up(1, false, false, 10., 3.),
+            up(3, false, true, 9.5, 0.),
+            up(5, false, false, 9.8, 1.),
+        ];
+        let trades = vec![
+            up(0, true, false, 9., 1.),
+            up(2, true, false, 10., 1.),
+            up(3, true, true, 9.5, 1.),
+            up(4, false, true, 1., 1.),
+            up(6, true, true, 9.8, 1.),
+        ];
+
+        let joined = join_quotes(&trades, &quotes, None);
+        assert_eq!(joined.len(), 4);
+        assert_eq!((joined[0].bid, joined[0].ask), (None, None));
+        assert_eq!((joined[1].bid, joined[1].ask), (Some((9.5, 2.)), Some((10., 3.))));
+        // quotes of the trade's timestamp are applied first
+        assert_eq!((joined[2].bid, joined[2].ask), (Some((9., 1.)), Some((10., 3.))));
+        assert_eq!((joined[3].bid, joined[3].ask), (Some((9., 1.)), Some((9.8, 1.))));
+        assert_eq!(joined[3].trade, trades[4]);
+
+        // the book is stale 3ms after the quote at 3
+        let joined = join_quotes(&trades, &quotes, Some(2));
+        assert_eq!((joined[1].bid, joined[1].ask), (Some((9.5, 2.)), Some((10., 3.))));
+        assert_eq!((joined[3].bid, joined[3].ask), (Some((9., 1.)), Some((9.8, 1.))));
+        let joined = join_quotes(&trades, &quotes, Some(0));
+        assert_eq!((joined[1].bid, joined[1].ask), (None, None));
+    }
+
+    #[test]
+    fn should_asof_join_within_tolerance() {
+        let left = vec![up(1, true, false, 1., 1.), up(5, true, false, 2., 1.), up(20, true, false, 3., 1.)];
+        let right = vec![up(2, false, true, 10., 1.), up(5, false, true, 11., 1.), up(6, false, true, 12., 1.)];
+
+        let joined : Vec<_> = asof_join(left.clone(), right.clone(), None).collect();
+        assert_eq!(joined[0], (left[0].clone(), None));
+        assert_eq!(joined[1], (left[1].clone(), Some(right[1].clone())));
+        assert_eq!(joined[2], (left[2].clone(), Some(right[2].clone())));
+
+        let joined : Vec<_> = asof_join(left.clone(), right, Some(10)).collect();
+        assert_eq!(joined[2], (left[2].clone(), None));
+    }
+
+    #[test]
+    fn should_asof_join_files() {
+        let joined = asof_join_files(FNAME, FNAME, Some(0));
+        assert_eq!(joined.len(), dtf::decode(FNAME, None).len());
+        assert!(joined.iter().all(|&(ref left, ref right)| right.as_ref().map(|r| r.ts) == Some(left.ts)));
+    }
+}
@@ -154,6 +154,52 @@ impl Candles {
     }
 
 
+    /// fold a trade into the candle it falls into.
+    /// Returns the epoch of that candle, None if `trade` is not a trade.
+    pub fn add(&mut self, trade: &Update) -> Option<Time> {
+        if !trade.is_trade { return None; }
+        let width = self.scale as u64 * 60;
+        let ts = (fill_digits(trade.ts) / 1000 / width * width) as Time;
+
+        let candle = match self.v.get(&ts) {
+            Some(c) => Candle {
+                volume: c.volume + trade.size,
+                high: if trade.price >= c.high { trade.price } else { c.high },
+                low: if trade.price <= c.low { trade.price } else { c.low },
+                close: trade.price,
+                open: c.open
+            },
+            None => Candle {
+                volume: trade.size,
+                high: trade.price,
+                low: trade.price,
+                close: trade.price,
+                open: trade.price
+            }
+        };
+        self.v.insert(ts, candle);
+        Some(ts)
+    }
+
+    /// the candle starting at epoch `ts`
+    pub fn get(&self, ts: Time) -> Option<&Candle> {
+        self.v.get(&ts)
+    }
+
+    /// encode candles as trades so they can be stored in a dtf file.
+    /// See `Candle::to_updates`.
+    pub fn to_updates(&self) -> Vec<Update> {
+        self.v.iter()
+            .flat_map(|(&ts, candle)| candle.to_updates(ts))
+            .collect()
+    }
+
+    /// the `scale` minute candles encoded by `to_updates`, e.g. read back
+    /// from a dtf file
+    pub fn from_updates(ups: &[Update], scale: u16) -> Candles {
+        Candles { scale, ..Candles::from(ups) }
+    }
+
     /// create new Candles object
     pub fn new(v: BTreeMap<Time, Candle>, scale: u16) -> Candles {
         let ret = Candles {
@@ -281,6 +327,22 @@ impl Candle {
                 self.open, self.high, self.low, self.close, self.volume)
     }
 
+    /// encode as 4 trades at `ts`: open, high, low and close.
+    /// The volume is carried by the close so that `Candles::from`
+    /// turns the trades back into the same candle.
+    pub fn to_updates(&self, ts: Time) -> Vec<Update> {
+        let prices = [self.open, self.high, self.low, self.close];
+        prices.iter().enumerate().map(|(i, &price)| Update {
+            ts: u64::from(ts) * 1000,
+            seq: i as u32,
+            is_trade: true,
+            is_bid: false,
+            price,
+            size: if i == 3 { self.volume } else { 0. },
+            flags: 0,
+        }).collect()
+    }
+
 }
 
 /// Check a list of sequence
@@ -560,6 +622,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn should_add_trades_incrementally() {
+        let mut candles = Candles::new(BTreeMap::new(), 1);
+        let base : u64 = 1_505_177_400; // on a minute mark
+        let trade = |ts: u64, price, size| Update { ts: base * 1000 + ts, seq: 0, is_trade: true, is_bid: false, price, size, flags: 0 };
+        assert_eq!(candles.add(&trade(0, 10., 1.)), Some(base as Time));
+        assert_eq!(candles.add(&trade(1_000, 12., 1.)), Some(base as Time));
+        assert_eq!(candles.add(&trade(2_000, 9., 1.)), Some(base as Time));
+        assert_eq!(candles.add(&trade(60_000, 11., 2.)), Some(base as Time + 60));
+        assert_eq!(candles.add(&Update { is_trade: false, ..trade(70_000, 1., 1.) }), None);
+
+        assert_eq!(candles.get(base as Time), Some(&Candle { open: 10., high: 12., low: 9., close: 9., volume: 3. }));
+
+        // round trip through updates
+        let ups = candles.to_updates();
+        assert_eq!(ups.len(), 8);
+        assert_eq!(Candles::from(ups.as_slice()), candles);
+    }
 }
 
 // ────────────────────────────────────────────────────────────────────────────────
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+/// Rows with values within [lower, upper), the last bucket includes `upper`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bin {
+    pub lower: f64,
+    pub upper: f64,
+    pub count: u64,
+}
+
+/// The number of rows of each value of a field. Prices and sizes take few
+/// distinct values, so the counts of a range are small and are merged across
+/// the workers of a scan before the range of the values, and so the width of
+/// the buckets, is known.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Distribution {
+    /// bits of the value -> rows
+    counts: HashMap<u32, u64>,
+}
+
+impl Distribution {
+    pub fn new() -> Distribution {
+        Distribution::default()
+    }
+
+    /// NaN and infinite values are ignored
+    pub fn add(&mut self, value: f32) {
+        if !value.is_finite() {
+            return;
+        }
+        // -0 and 0 are one value
+        let value = if value == 0. { 0. } else { value };
+        *self.counts.entry(value.to_bits()).or_insert(0) += 1;
+    }
+
+    pub fn merge(&mut self, other: Distribution) {
+        for (value, count) in other.counts {
+            *self.counts.entry(value).or_insert(0) += count;
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    /// `bins` buckets of equal width from the smallest to the largest value,
+    /// a single bucket if all values are equal, none without values
+    pub fn bins(&self, bins: usize) -> Vec<Bin> {
+        let values = || self.counts.keys().map(|&bits| f64::from(f32::from_bits(bits)));
+        let (min, max) = match (values().fold(None, min_of), values().fold(None, max_of)) {
+            (Some(min), Some(max)) => (min, max),
+            _ => return Vec::new()
+        };
+        if min == max || bins < 2 {
+            return vec![Bin { lower: min, upper: max, count: self.count() }];
+        }
+        let width = (max - min) / bins as f64;
+        let mut ret : Vec<Bin> = (0..bins)
+            .map(|i| Bin {
+                lower: min + i as f64 * width,
+                upper: if i + 1 == bins { max } else { min + (i + 1) as f64 * width },
+                count: 0,
+            })
+            .collect();
+        for (&bits, &count) in self.counts.iter() {
+            let i = ((f64::from(f32::from_bits(bits)) - min) / width) as usize;
+            ret[i.min(bins - 1)].count += count;
+        }
+        ret
+    }
+}
+
+fn min_of(min: Option<f64>, value: f64) -> Option<f64> {
+    Some(min.map_or(value, |min| min.min(value)))
+}
+
+fn max_of(max: Option<f64>, value: f64) -> Option<f64> {
+    Some(max.map_or(value, |max| max.max(value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_bin_evenly() {
+        let mut first = Distribution::new();
+        let mut second = Distribution::new();
+        for i in 0..50 {
+            first.add(i as f32);
+        }
+        for i in 50..101 {
+            second.add(i as f32);
+        }
+        second.add(::std::f32::NAN);
+        first.merge(second);
+        assert_eq!(first.count(), 101);
+        let bins = first.bins(4);
+        let counts : Vec<u64> = bins.iter().map(|bin| bin.count).collect();
+        // 100 falls in the last bucket
+        assert_eq!(counts, vec![25, 25, 25, 26]);
+        assert_eq!((bins[1].lower, bins[1].upper), (25., 50.));
+        assert_eq!(bins[3].upper, 100.);
+    }
+
+    #[test]
+    fn should_bin_a_single_value() {
+        let mut distribution = Distribution::new();
+        assert_eq!(distribution.bins(10), vec![]);
+        distribution.add(-0.);
+        distribution.add(0.);
+        assert_eq!(distribution.bins(10), vec![Bin { lower: 0., upper: 0., count: 2 }]);
+    }
+}
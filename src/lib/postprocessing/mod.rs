@@ -3,5 +3,12 @@ pub mod orderbook;
 pub mod level;
 pub mod event;
 pub mod histogram;
+pub mod summary;
+pub mod asof;
+pub mod series;
+pub mod sample;
+pub mod topn;
+pub mod distribution;
 
-pub use self::orderbook::*;
\ No newline at end of file
+pub use self::orderbook::*;
+pub use self::asof::{asof_join, asof_join_files};
\ No newline at end of file
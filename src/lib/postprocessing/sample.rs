@@ -0,0 +1,132 @@
+use dtf::Update;
+
+/// A uniform sample of at most `capacity` rows of a store that can be
+/// updated incrementally, one batch of updates at a time (reservoir
+/// sampling, algorithm R).
+///
+/// The random numbers come from a xorshift generator whose state is kept
+/// with the sample, so a sample continued after `parse` is the same as one
+/// that was never serialized.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reservoir {
+    pub capacity: usize,
+    /// rows offered so far
+    pub seen: u64,
+    pub rows: Vec<Update>,
+    state: u64,
+}
+
+impl Reservoir {
+    pub fn new(capacity: usize) -> Reservoir {
+        Reservoir { capacity, seen: 0, rows: Vec::new(), state: 0x9E37_79B9_7F4A_7C15 }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    pub fn add(&mut self, ups: &[Update]) {
+        for up in ups.iter() {
+            self.seen += 1;
+            if self.rows.len() < self.capacity {
+                self.rows.push(up.clone());
+                continue;
+            }
+            let i = (self.next_random() % self.seen) as usize;
+            if i < self.capacity {
+                self.rows[i] = up.clone();
+            }
+        }
+    }
+
+    /// rows of the store each sampled row stands for
+    pub fn weight(&self) -> f64 {
+        if self.rows.is_empty() { 0. } else { self.seen as f64 / self.rows.len() as f64 }
+    }
+
+    /// The sample as a `capacity seen state` line and a
+    /// `ts seq is_trade is_bid price size flags` line per row, read back by `parse`
+    pub fn serialize(&self) -> String {
+        let mut ret = format!("{} {} {}\n", self.capacity, self.seen, self.state);
+        for up in self.rows.iter() {
+            ret.push_str(&format!("{} {} {} {} {} {} {}\n",
+                up.ts, up.seq, up.is_trade as u8, up.is_bid as u8, up.price, up.size, up.flags));
+        }
+        ret
+    }
+
+    pub fn parse(text: &str) -> Option<Reservoir> {
+        let mut lines = text.lines();
+        let header : Vec<&str> = lines.next()?.split(' ').collect();
+        if header.len() != 3 {
+            return None;
+        }
+        let mut reservoir = Reservoir {
+            capacity: header[0].parse().ok()?,
+            seen: header[1].parse().ok()?,
+            rows: Vec::new(),
+            state: header[2].parse().ok()?,
+        };
+        for line in lines {
+            let parts : Vec<&str> = line.split(' ').collect();
+            if parts.len() != 7 {
+                return None;
+            }
+            reservoir.rows.push(Update {
+                ts: parts[0].parse().ok()?,
+                seq: parts[1].parse().ok()?,
+                is_trade: parts[2] == "1",
+                is_bid: parts[3] == "1",
+                price: parts[4].parse().ok()?,
+                size: parts[5].parse().ok()?,
+                flags: parts[6].parse().ok()?,
+            });
+        }
+        if reservoir.rows.len() > reservoir.capacity || reservoir.rows.len() as u64 > reservoir.seen {
+            return None;
+        }
+        Some(reservoir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn up(ts: u64) -> Update {
+        Update { ts, seq: 0, is_trade: ts > 5_000, is_bid: false, price: ts as f32 / 4., size: 1.5, flags: 0 }
+    }
+
+    #[test]
+    fn should_sample_uniformly() {
+        let rows : Vec<Update> = (0..10_000).map(up).collect();
+        let mut reservoir = Reservoir::new(1_000);
+        reservoir.add(&rows[..10]);
+        assert_eq!(reservoir.rows, &rows[..10]);
+        reservoir.add(&rows[10..]);
+        assert_eq!(reservoir.rows.len(), 1_000);
+        assert_eq!(reservoir.seen, 10_000);
+        assert_eq!(reservoir.weight(), 10.);
+        // about a tenth of the sample falls in each tenth of the rows
+        let first_tenth = reservoir.rows.iter().filter(|up| up.ts < 1_000).count();
+        assert!(first_tenth > 60 && first_tenth < 140, "{}", first_tenth);
+    }
+
+    #[test]
+    fn should_continue_after_parse() {
+        let rows : Vec<Update> = (0..3_000).map(up).collect();
+        let mut whole = Reservoir::new(100);
+        whole.add(&rows);
+        let mut first = Reservoir::new(100);
+        first.add(&rows[..1_500]);
+        let mut continued = Reservoir::parse(&first.serialize()).unwrap();
+        assert_eq!(continued, first);
+        continued.add(&rows[1_500..]);
+        assert_eq!(continued, whole);
+        assert_eq!(Reservoir::parse("100 3"), None);
+        assert_eq!(Reservoir::parse(&Reservoir::new(1).serialize()), Some(Reservoir::new(1)));
+    }
+}
@@ -0,0 +1,70 @@
+use dtf::Update;
+
+/// The values of a low-frequency series, e.g. funding rates or open interest,
+/// within one interval. The value of a row is its price.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bucket {
+    /// start of the interval in ms
+    pub ts: u64,
+    pub count: u64,
+    pub first: f32,
+    pub last: f32,
+    pub min: f32,
+    pub max: f32,
+    pub sum: f64,
+}
+
+impl Bucket {
+    fn new(ts: u64, value: f32) -> Bucket {
+        Bucket { ts, count: 1, first: value, last: value, min: value, max: value, sum: f64::from(value) }
+    }
+
+    fn add(&mut self, value: f32) {
+        self.count += 1;
+        self.last = value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += f64::from(value);
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+}
+
+/// Fold the rows of a series into buckets of `interval` ms, aligned on the
+/// epoch. Intervals without a row are left out.
+///
+/// The rows are expected in time order.
+pub fn buckets(ups: &[Update], interval: u64) -> Vec<Bucket> {
+    let mut ret : Vec<Bucket> = Vec::new();
+    for up in ups.iter() {
+        let ts = up.ts / interval * interval;
+        if let Some(bucket) = ret.last_mut().filter(|bucket| bucket.ts == ts) {
+            bucket.add(up.price);
+            continue;
+        }
+        ret.push(Bucket::new(ts, up.price));
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn up(ts: u64, price: f32) -> Update {
+        Update { ts, seq: 0, is_trade: false, is_bid: false, price, size: 0., flags: 0 }
+    }
+
+    #[test]
+    fn should_fold_into_buckets() {
+        let rows = [up(0, 0.5), up(1_000, 0.25), up(1_500, 1.), up(3_999, 2.)];
+        let buckets = buckets(&rows, 2_000);
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0], Bucket { ts: 0, count: 3, first: 0.5, last: 1., min: 0.25, max: 1., sum: 1.75 });
+        assert_eq!(buckets[1], Bucket { ts: 2_000, count: 1, first: 2., last: 2., min: 2., max: 2., sum: 2. });
+        assert_eq!(buckets[1].mean(), 2.);
+        assert!(super::buckets(&[], 2_000).is_empty());
+    }
+}
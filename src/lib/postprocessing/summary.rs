@@ -0,0 +1,247 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use dtf::Update;
+
+/// ms in a day
+static DAY : u64 = 86_400_000;
+
+/// Summary statistics of a store that can be updated incrementally,
+/// one batch of updates at a time.
+///
+/// The spread is approximated by the distance between the most recent bid
+/// quote and the most recent ask quote, sampled on every quote.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Summary {
+    pub count: u64,
+    pub min_ts: Option<u64>,
+    pub max_ts: Option<u64>,
+    pub min_price: Option<f32>,
+    pub max_price: Option<f32>,
+    /// total size of the trades
+    pub volume: f64,
+    /// day (epoch in seconds) -> number of rows
+    pub rows_per_day: BTreeMap<u64, u64>,
+    pub trades: u64,
+    pub quotes: u64,
+
+    spread_sum: f64,
+    spread_samples: u64,
+    last_bid: Option<f32>,
+    last_ask: Option<f32>,
+}
+
+impl Summary {
+    pub fn new() -> Summary {
+        Default::default()
+    }
+
+    /// fold updates into the summary
+    pub fn add(&mut self, ups: &[Update]) {
+        for up in ups.iter() {
+            self.count += 1;
+            self.min_ts = Some(self.min_ts.map_or(up.ts, |ts| if up.ts < ts { up.ts } else { ts }));
+            self.max_ts = Some(self.max_ts.map_or(up.ts, |ts| if up.ts > ts { up.ts } else { ts }));
+            self.min_price = Some(self.min_price.map_or(up.price, |price| price.min(up.price)));
+            self.max_price = Some(self.max_price.map_or(up.price, |price| price.max(up.price)));
+            *self.rows_per_day.entry(up.ts / DAY * DAY / 1000).or_insert(0) += 1;
+
+            if up.is_trade {
+                self.trades += 1;
+                self.volume += f64::from(up.size);
+                continue;
+            }
+
+            self.quotes += 1;
+            if up.is_bid {
+                self.last_bid = Some(up.price);
+            } else {
+                self.last_ask = Some(up.price);
+            }
+            if let (Some(bid), Some(ask)) = (self.last_bid, self.last_ask) {
+                if ask > bid {
+                    self.spread_sum += f64::from(ask - bid);
+                    self.spread_samples += 1;
+                }
+            }
+        }
+    }
+
+    /// fold in the summary of the updates that came after those of this one
+    pub fn merge(&mut self, later: &Summary) {
+        fn pick<T: Copy>(a: Option<T>, b: Option<T>, f: fn(T, T) -> T) -> Option<T> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(f(a, b)),
+                (a, b) => a.or(b)
+            }
+        }
+        self.count += later.count;
+        self.min_ts = pick(self.min_ts, later.min_ts, ::std::cmp::min);
+        self.max_ts = pick(self.max_ts, later.max_ts, ::std::cmp::max);
+        self.min_price = pick(self.min_price, later.min_price, f32::min);
+        self.max_price = pick(self.max_price, later.max_price, f32::max);
+        self.volume += later.volume;
+        for (day, n) in later.rows_per_day.iter() {
+            *self.rows_per_day.entry(*day).or_insert(0) += n;
+        }
+        self.trades += later.trades;
+        self.quotes += later.quotes;
+        self.spread_sum += later.spread_sum;
+        self.spread_samples += later.spread_samples;
+        self.last_bid = later.last_bid.or(self.last_bid);
+        self.last_ask = later.last_ask.or(self.last_ask);
+    }
+
+    pub fn avg_spread(&self) -> Option<f64> {
+        if self.spread_samples == 0 {
+            None
+        } else {
+            Some(self.spread_sum / self.spread_samples as f64)
+        }
+    }
+
+    pub fn trade_quote_ratio(&self) -> Option<f64> {
+        if self.quotes == 0 {
+            None
+        } else {
+            Some(self.trades as f64 / self.quotes as f64)
+        }
+    }
+
+    /// time span in seconds
+    pub fn span(&self) -> f64 {
+        match (self.min_ts, self.max_ts) {
+            (Some(min), Some(max)) => (max - min) as f64 / 1000_f64,
+            _ => 0.
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let opt = |v: Option<f64>| match v {
+            Some(v) => format!("{}", v),
+            None => "null".to_owned()
+        };
+        let days : Vec<String> = self.rows_per_day.iter()
+            .map(|(day, n)| format!(r#""{}":{}"#, day, n))
+            .collect();
+        format!(r#"{{"count":{},"min_ts":{},"max_ts":{},"span":{},"min_price":{},"max_price":{},"volume":{},"rows_per_day":{{{}}},"avg_spread":{},"trades":{},"quotes":{},"trade_quote_ratio":{}}}"#,
+            self.count,
+            opt(self.min_ts.map(|ts| ts as f64 / 1000_f64)),
+            opt(self.max_ts.map(|ts| ts as f64 / 1000_f64)),
+            self.span(),
+            opt(self.min_price.map(f64::from)),
+            opt(self.max_price.map(f64::from)),
+            self.volume,
+            days.join(","),
+            opt(self.avg_spread()),
+            self.trades,
+            self.quotes,
+            opt(self.trade_quote_ratio()))
+    }
+
+    /// The summary as `key value` lines, read back by `parse`
+    pub fn serialize(&self) -> String {
+        fn opt<T: ToString>(v: Option<T>) -> String {
+            v.map_or("-".to_owned(), |v| v.to_string())
+        }
+        let mut ret = format!("count {}\nmin_ts {}\nmax_ts {}\nmin_price {}\nmax_price {}\nvolume {}\ntrades {}\nquotes {}\nspread_sum {}\nspread_samples {}\nlast_bid {}\nlast_ask {}\n",
+            self.count, opt(self.min_ts), opt(self.max_ts), opt(self.min_price), opt(self.max_price),
+            self.volume, self.trades, self.quotes, self.spread_sum, self.spread_samples,
+            opt(self.last_bid), opt(self.last_ask));
+        for (day, n) in self.rows_per_day.iter() {
+            ret.push_str(&format!("day {} {}\n", day, n));
+        }
+        ret
+    }
+
+    /// Summary of `serialize`d lines, unknown keys are skipped
+    pub fn parse(text: &str) -> Option<Summary> {
+        fn opt<T: FromStr>(v: &str) -> Option<Option<T>> {
+            if v == "-" { Some(None) } else { v.parse().ok().map(Some) }
+        }
+        let mut summary = Summary::new();
+        for line in text.lines() {
+            let parts : Vec<&str> = line.split(' ').collect();
+            match (parts[0], parts.get(1).cloned().unwrap_or("")) {
+                ("count", v) => summary.count = v.parse().ok()?,
+                ("min_ts", v) => summary.min_ts = opt(v)?,
+                ("max_ts", v) => summary.max_ts = opt(v)?,
+                ("min_price", v) => summary.min_price = opt(v)?,
+                ("max_price", v) => summary.max_price = opt(v)?,
+                ("volume", v) => summary.volume = v.parse().ok()?,
+                ("trades", v) => summary.trades = v.parse().ok()?,
+                ("quotes", v) => summary.quotes = v.parse().ok()?,
+                ("spread_sum", v) => summary.spread_sum = v.parse().ok()?,
+                ("spread_samples", v) => summary.spread_samples = v.parse().ok()?,
+                ("last_bid", v) => summary.last_bid = opt(v)?,
+                ("last_ask", v) => summary.last_ask = opt(v)?,
+                ("day", day) => {
+                    summary.rows_per_day.insert(day.parse().ok()?, parts.get(2)?.parse().ok()?);
+                }
+                _ => ()
+            }
+        }
+        Some(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn up(ts: u64, is_trade: bool, is_bid: bool, price: f32) -> Update {
+        Update { ts, seq: 0, is_trade, is_bid, price, size: 1., flags: 0 }
+    }
+
+    #[test]
+    fn should_summarize_incrementally() {
+        let mut summary = Summary::new();
+        summary.add(&[up(1_510_185_600_000, false, true, 10.), up(1_510_185_601_000, false, false, 12.)]);
+        summary.add(&[up(1_510_185_602_000, true, false, 11.), up(1_510_272_000_000, false, false, 11.)]);
+
+        assert_eq!(summary.count, 4);
+        assert_eq!(summary.min_ts, Some(1_510_185_600_000));
+        assert_eq!(summary.max_ts, Some(1_510_272_000_000));
+        assert_eq!(summary.trades, 1);
+        assert_eq!(summary.quotes, 3);
+        assert_eq!(summary.avg_spread(), Some(1.5));
+        assert_eq!(summary.rows_per_day.get(&1_510_185_600), Some(&3));
+        assert_eq!(summary.rows_per_day.get(&1_510_272_000), Some(&1));
+        assert_eq!((summary.min_price, summary.max_price), (Some(10.), Some(12.)));
+        assert_eq!(summary.volume, 1.);
+    }
+
+    #[test]
+    fn should_merge_like_adding() {
+        let rows = [up(1_510_185_600_000, false, true, 10.), up(1_510_185_601_000, false, false, 12.),
+                    up(1_510_185_602_000, true, false, 11.), up(1_510_272_000_000, false, false, 11.5)];
+        let mut whole = Summary::new();
+        whole.add(&rows);
+        let (mut first, mut second) = (Summary::new(), Summary::new());
+        first.add(&rows[..1]);
+        second.add(&rows[1..]);
+        first.merge(&second);
+        first.merge(&Summary::new());
+        assert_eq!(first.count, whole.count);
+        assert_eq!((first.min_ts, first.max_ts), (whole.min_ts, whole.max_ts));
+        assert_eq!((first.min_price, first.max_price), (whole.min_price, whole.max_price));
+        assert_eq!(first.rows_per_day, whole.rows_per_day);
+        assert_eq!((first.trades, first.quotes, first.volume), (whole.trades, whole.quotes, whole.volume));
+    }
+
+    #[test]
+    fn should_serialize() {
+        let mut summary = Summary::new();
+        assert_eq!(Summary::parse(&summary.serialize()), Some(summary.clone()));
+        summary.add(&[up(1_510_185_600_000, false, true, 10.25), up(1_510_185_601_000, false, false, 12.)]);
+        summary.add(&[up(1_510_272_000_000, true, false, 11.)]);
+        assert_eq!(Summary::parse(&summary.serialize()), Some(summary));
+        assert_eq!(Summary::parse("count x"), None);
+    }
+
+    #[test]
+    fn should_speak_json() {
+        let summary = Summary::new();
+        assert_eq!(summary.to_json(),
+            r#"{"count":0,"min_ts":null,"max_ts":null,"span":0,"min_price":null,"max_price":null,"volume":0,"rows_per_day":{},"avg_spread":null,"trades":0,"quotes":0,"trade_quote_ratio":null}"#);
+    }
+}
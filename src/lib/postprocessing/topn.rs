@@ -0,0 +1,110 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use dtf::Update;
+
+/// A row and the value it is ranked by
+#[derive(Debug, Clone)]
+struct Ranked {
+    key: f64,
+    row: Update,
+}
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Ranked) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ranked {}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Ranked) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// by key, of equal keys the earlier row ranks higher
+impl Ord for Ranked {
+    fn cmp(&self, other: &Ranked) -> Ordering {
+        self.key.partial_cmp(&other.key).unwrap_or(Ordering::Equal)
+            .then_with(|| other.row.ts.cmp(&self.row.ts))
+            .then_with(|| other.row.seq.cmp(&self.row.seq))
+    }
+}
+
+/// The `n` rows with the largest keys of those pushed, kept in a min-heap
+/// of `n` rows so a range of any length is ranked in constant memory.
+#[derive(Debug, Clone)]
+pub struct TopN {
+    n: usize,
+    heap: BinaryHeap<Reverse<Ranked>>,
+}
+
+impl TopN {
+    pub fn new(n: usize) -> TopN {
+        TopN { n, heap: BinaryHeap::new() }
+    }
+
+    /// NaN keys are ignored
+    pub fn push(&mut self, key: f64, row: &Update) {
+        if self.n == 0 || key.is_nan() {
+            return;
+        }
+        let ranked = Ranked { key, row: row.clone() };
+        if self.heap.len() == self.n && ranked <= self.heap.peek().unwrap().0 {
+            return;
+        }
+        self.heap.push(Reverse(ranked));
+        if self.heap.len() > self.n {
+            self.heap.pop();
+        }
+    }
+
+    /// rank the rows of both
+    pub fn merge(&mut self, other: TopN) {
+        for Reverse(ranked) in other.heap {
+            self.push(ranked.key, &ranked.row);
+        }
+    }
+
+    /// (key, row), the largest first
+    pub fn into_sorted(self) -> Vec<(f64, Update)> {
+        self.heap.into_sorted_vec().into_iter().map(|Reverse(ranked)| (ranked.key, ranked.row)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn up(ts: u64, size: f32) -> Update {
+        Update { ts, seq: 0, is_trade: true, is_bid: false, price: 1., size, flags: 0 }
+    }
+
+    #[test]
+    fn should_keep_the_largest() {
+        let rows : Vec<Update> = (0..1_000).map(|i| up(i, ((i * 7919) % 1_000) as f32)).collect();
+        let mut first = TopN::new(3);
+        let mut second = TopN::new(3);
+        for row in &rows[..500] {
+            first.push(f64::from(row.size), row);
+        }
+        for row in &rows[500..] {
+            second.push(f64::from(row.size), row);
+        }
+        first.merge(second);
+        let keys : Vec<f64> = first.into_sorted().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![999., 998., 997.]);
+    }
+
+    #[test]
+    fn should_prefer_earlier_rows_of_equal_keys() {
+        let mut top = TopN::new(2);
+        for ts in 0..5 {
+            top.push(1., &up(ts, 1.));
+        }
+        top.push(::std::f64::NAN, &up(9, 1.));
+        let ts : Vec<u64> = top.into_sorted().into_iter().map(|(_, row)| row.ts).collect();
+        assert_eq!(ts, vec![0, 1]);
+    }
+}
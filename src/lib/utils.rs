@@ -17,6 +17,24 @@ pub fn bigram<T: Copy>(a: &[T]) -> Vec<(T,T)> {
         .collect::<Vec<(_, _)>>()
 }
 
+/// CRC-32 (IEEE) of `bytes` continued from `crc`, the CRC of everything before.
+///     crc32_update(crc32(b"ab"), b"c") == crc32(b"abc")
+pub fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// CRC-32 (IEEE) of `bytes`
+pub fn crc32(bytes: &[u8]) -> u32 {
+    crc32_update(0, bytes)
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -28,4 +46,11 @@ mod tests {
         assert_eq!(bigram(&a), vec![(1,2), (2,3)]);
     }
 
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32_update(crc32(b"12345"), b"6789"), crc32(b"123456789"));
+    }
+
 }
\ No newline at end of file
@@ -0,0 +1,130 @@
+/// WebAssembly entry points of the dtf decoder
+///
+/// Lets a browser dashboard decode downloaded dtf payloads client-side. The
+/// decoder reads from memory only, so the crate builds for the browser:
+///
+/// ```sh
+/// cargo build --lib --release --target wasm32-unknown-unknown
+/// ```
+///
+/// The host copies the payload, a whole dtf file or the batches of a GET
+/// response, into memory from `dtf_alloc` and calls `dtf_decode_flat`:
+///
+/// ```js
+/// const ptr = dtf.dtf_alloc(payload.length);
+/// new Uint8Array(dtf.memory.buffer, ptr, payload.length).set(payload);
+/// const out = dtf.dtf_decode_flat(ptr, payload.length);
+/// dtf.dtf_free(ptr, payload.length);
+/// const count = Number(new DataView(dtf.memory.buffer, out).getBigUint64(0, true));
+/// /* rows at out + 8 + 24 * i, see `dtf::flat`: bit 0 of the byte at offset
+///    12 is is_bid, bit 1 is_trade and the user defined flags are above them */
+/// dtf.dtf_free(out, 8 + 24 * count);
+/// ```
+
+use std::mem;
+use std::ptr;
+use std::slice;
+use dtf::{self, FLAT_HEADER_LEN, FLAT_RECORD_LEN};
+
+/// Allocate `len` bytes for the host to write a payload into
+#[no_mangle]
+pub extern "C" fn dtf_alloc(len: usize) -> *mut u8 {
+    let mut buf : Vec<u8> = Vec::with_capacity(len);
+    let ptr = buf.as_mut_ptr();
+    mem::forget(buf);
+    ptr
+}
+
+/// Free `len` bytes from `dtf_alloc` or `dtf_decode_flat`
+#[no_mangle]
+pub unsafe extern "C" fn dtf_free(ptr: *mut u8, len: usize) {
+    if !ptr.is_null() {
+        drop(Vec::from_raw_parts(ptr, 0, len));
+    }
+}
+
+/// Decode the `len` bytes at `buf` into the flat layout of `dtf::flat`,
+/// `8 + 24 * count` bytes to be freed with `dtf_free`, or null if the
+/// payload is corrupt
+#[no_mangle]
+pub unsafe extern "C" fn dtf_decode_flat(buf: *const u8, len: usize) -> *mut u8 {
+    if buf.is_null() {
+        return ptr::null_mut();
+    }
+    let buf = slice::from_raw_parts(buf, len);
+    let decoded = if dtf::has_magic_value(buf) {
+        dtf::decode_buffer(buf)
+    } else {
+        dtf::decode_batches_buffer(buf)
+    };
+    match decoded {
+        Ok(ups) => {
+            let mut flat = dtf::encode_flat(&ups);
+            debug_assert_eq!(flat.len(), FLAT_HEADER_LEN + ups.len() * FLAT_RECORD_LEN);
+            flat.shrink_to_fit();
+            let ptr = flat.as_mut_ptr();
+            mem::forget(flat);
+            ptr
+        }
+        Err(_) => ptr::null_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Read;
+    use byteorder::{ByteOrder, LittleEndian};
+    use dtf::FlatView;
+
+    static FNAME : &str = "test-data/bt_btcnav.dtf";
+
+    unsafe fn decode_flat(payload: &[u8]) -> Option<Vec<u8>> {
+        let ptr = dtf_alloc(payload.len());
+        ptr::copy_nonoverlapping(payload.as_ptr(), ptr, payload.len());
+        let out = dtf_decode_flat(ptr, payload.len());
+        dtf_free(ptr, payload.len());
+        if out.is_null() {
+            return None;
+        }
+        let count = LittleEndian::read_u64(slice::from_raw_parts(out, FLAT_HEADER_LEN)) as usize;
+        let len = FLAT_HEADER_LEN + count * FLAT_RECORD_LEN;
+        let flat = slice::from_raw_parts(out, len).to_vec();
+        dtf_free(out, len);
+        Some(flat)
+    }
+
+    #[test]
+    fn should_decode_files_and_batches() {
+        let ups = dtf::decode(FNAME, None);
+        let mut file = Vec::new();
+        File::open(FNAME).unwrap().read_to_end(&mut file).unwrap();
+
+        let mut batches = Vec::new();
+        dtf::write_batches(&mut batches, &ups[..100]);
+        // a GET response ends with a null byte
+        batches.push(0x0);
+
+        unsafe {
+            assert_eq!(decode_flat(&file), Some(dtf::encode_flat(&ups)));
+            assert_eq!(decode_flat(&batches), Some(dtf::encode_flat(&ups[..100])));
+            assert_eq!(decode_flat(&file[..file.len() - 10]), None);
+            assert_eq!(dtf_decode_flat(ptr::null(), 0), ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn should_decode_the_flags_of_rows() {
+        let ups : Vec<_> = dtf::decode(FNAME, None)[..100].iter().enumerate()
+            .map(|(i, up)| up.clone().with_flags((i % 64) as u8))
+            .collect();
+        let mut batches = Vec::new();
+        dtf::write_batches(&mut batches, &ups);
+
+        let flat = unsafe { decode_flat(&batches) }.unwrap();
+        let view = FlatView::new(&flat).unwrap();
+        assert_eq!((0..view.len()).map(|i| view.get(i)).collect::<Vec<_>>(), ups);
+        assert_eq!(view.flags(63), 63);
+    }
+}
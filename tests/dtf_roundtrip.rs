@@ -0,0 +1,143 @@
+/// Property based round-trip tests of the dtf file format
+///
+/// Arbitrary update sequences must survive encode -> append -> decode,
+/// range queries must agree with filtering the decoded rows, and the
+/// in-memory decoder must never panic, whatever the input bytes.
+
+extern crate dtf;
+#[macro_use] extern crate proptest;
+
+use dtf::Update;
+use proptest::prelude::*;
+use std::fs;
+use std::io::{BufReader, Read};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+static FILE_ID : AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// a fresh file name, test cases run in parallel
+fn tmp_fname() -> String {
+    let id = FILE_ID.fetch_add(1, Ordering::SeqCst);
+    let dir = std::env::temp_dir();
+    format!("{}/dtf_roundtrip_{}.dtf", dir.display(), id)
+}
+
+/// remove a test file and its batch index
+fn remove(fname: &str) {
+    let _ = fs::remove_file(fname);
+    dtf::remove_batch_index(fname);
+}
+
+fn read_file(fname: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    fs::File::open(fname).unwrap().read_to_end(&mut bytes).unwrap();
+    bytes
+}
+
+/// rows with strictly increasing ts, like a store flushes them.
+/// Gaps span several batches; seq may go back, which also starts a batch.
+fn arb_updates(max_len: usize) -> BoxedStrategy<Vec<Update>> {
+    let row = (1u64..100_000, 0u32..1000, any::<bool>(), any::<bool>(), -1e6f32..1e6f32, 0f32..1e6f32);
+    (1_500_000_000_000u64..1_600_000_000_000, prop::collection::vec(row, 1..max_len))
+        .prop_map(|(start, rows)| {
+            let mut ts = start;
+            rows.into_iter().map(|(dts, seq, is_trade, is_bid, price, size)| {
+                ts += dts;
+                Update { ts, seq, is_trade, is_bid, price, size, flags: 0 }
+            }).collect()
+        })
+        .boxed()
+}
+
+proptest! {
+    #[test]
+    fn encode_decode_roundtrip(ref ups in arb_updates(500)) {
+        let fname = tmp_fname();
+        dtf::encode(&fname, "prop", ups);
+        let decoded = dtf::decode(&fname, None);
+        let meta = dtf::read_meta(&fname);
+        remove(&fname);
+
+        prop_assert_eq!(&decoded, ups);
+        prop_assert_eq!(meta.nums, ups.len() as u64);
+        prop_assert_eq!(meta.min_ts, ups[0].ts);
+        prop_assert_eq!(meta.max_ts, ups[ups.len() - 1].ts);
+    }
+
+    #[test]
+    fn encode_append_decode_roundtrip(ref ups in arb_updates(500), split in 0usize..500) {
+        let split = split % ups.len();
+        let fname = tmp_fname();
+        if split == 0 {
+            // appending to an empty file
+            dtf::encode(&fname, "prop", &[]);
+        } else {
+            dtf::encode(&fname, "prop", &ups[..split]);
+        }
+        dtf::append(&fname, &ups[split..]);
+        let decoded = dtf::decode(&fname, None);
+        let meta = dtf::read_meta(&fname);
+        let last = dtf::read_last(&fname);
+        remove(&fname);
+
+        prop_assert_eq!(&decoded, ups);
+        prop_assert_eq!(meta.nums, ups.len() as u64);
+        prop_assert_eq!(meta.min_ts, ups[0].ts);
+        prop_assert_eq!(last.as_ref(), ups.last());
+    }
+
+    #[test]
+    fn range_matches_filter(ref ups in arb_updates(500), a in 0f64..1.0, b in 0f64..1.0) {
+        let first = ups[0].ts as f64 / 1000.;
+        let last = ups[ups.len() - 1].ts as f64 / 1000.;
+        let min_ts = (first + (last - first) * a.min(b)).floor();
+        let max_ts = (first + (last - first) * a.max(b)).ceil();
+
+        let fname = tmp_fname();
+        dtf::encode(&fname, "prop", ups);
+        let ranged = {
+            let mut rdr = BufReader::new(fs::File::open(&fname).unwrap());
+            dtf::range(&mut rdr, min_ts, max_ts)
+        };
+        remove(&fname);
+
+        let expected : Vec<Update> = ups.iter()
+            .filter(|up| up.ts >= (min_ts * 1000.) as u64 && up.ts <= (max_ts * 1000.) as u64)
+            .cloned()
+            .collect();
+        prop_assert_eq!(ranged, expected);
+    }
+
+    #[test]
+    fn batches_roundtrip_in_memory(ref ups in arb_updates(500)) {
+        let fname = tmp_fname();
+        dtf::encode(&fname, "prop", ups);
+        let bytes = read_file(&fname);
+        remove(&fname);
+
+        prop_assert_eq!(&dtf::decode_buffer(&bytes).unwrap(), ups);
+        // the delta encoding never takes more than a batch header per row on top of 12 bytes a row
+        prop_assert!(bytes.len() <= 80 + ups.len() * (12 + 15));
+    }
+
+    #[test]
+    fn decode_buffer_never_panics(ref bytes in prop::collection::vec(any::<u8>(), 0..2048)) {
+        let _ = dtf::decode_buffer(bytes);
+    }
+
+    #[test]
+    fn decode_buffer_rejects_or_truncates(ref ups in arb_updates(100), cut in 0usize..4096, flip in any::<u8>()) {
+        let fname = tmp_fname();
+        dtf::encode(&fname, "prop", ups);
+        let mut bytes = read_file(&fname);
+        remove(&fname);
+
+        // corrupt one byte and cut the tail off
+        let i = cut % bytes.len();
+        bytes[i] ^= flip;
+        bytes.truncate(cut.max(i + 1));
+        if let Ok(decoded) = dtf::decode_buffer(&bytes) {
+            prop_assert!(decoded.len() <= ups.len() + bytes.len() / 12);
+        }
+    }
+}